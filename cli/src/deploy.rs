@@ -7,7 +7,7 @@ use hyper::Uri;
 use log::debug;
 use owo_colors::OwoColorize;
 use reqwest::multipart::{Form, Part};
-use reqwest::{Body, Client};
+use reqwest::{Body, Client, StatusCode};
 use std::borrow::Cow;
 use std::env::var;
 use std::ffi::OsStr;
@@ -20,6 +20,8 @@ pub async fn deploy(
   auth_token: Option<Uuid>,
   path: PathBuf,
   mode: UploadMode,
+  weight: u8,
+  force: bool,
 ) -> anyhow::Result<()> {
   let path = fs::canonicalize(path).await?;
   let server = server.map(Ok).unwrap_or_else(|| {
@@ -30,7 +32,14 @@ pub async fn deploy(
   })?;
   let name = path.file_stem().context("no filename found")?;
   let name = name.to_str().context("filename contains non-UTF-8 bytes")?;
-  let server = format!("{server}/services/{name}?mode={mode}");
+  let service_url = format!("{server}/services/{name}");
+  let mut server = format!("{service_url}?mode={mode}");
+  if mode == UploadMode::Canary {
+    server += &format!("&weight={weight}");
+  }
+  if force {
+    server += "&force=true";
+  }
 
   let auth_token = auth_token
     .map(|x| Ok(Some(x)))
@@ -74,10 +83,25 @@ pub async fn deploy(
     Form::new().part(kind, Part::stream_with_length(file, metadata.len()))
   };
 
-  let mut builder = Client::new().put(server);
+  let client = Client::new();
+
+  // Updating an existing service requires its current ETag
+  let mut builder = client.get(service_url);
+  if let Some(x) = &auth_token {
+    builder = builder.header("authorization", x.clone());
+  }
+  let resp = builder.send().await?;
+  let etag = (resp.status().is_success())
+    .then(|| resp.headers().get("etag").cloned())
+    .flatten();
+
+  let mut builder = client.put(server);
   if let Some(x) = auth_token {
     builder = builder.header("authorization", x);
   }
+  if let Some(x) = etag {
+    builder = builder.header("if-match", x);
+  }
   let resp = builder.multipart(form).send().await?;
 
   let status = resp.status();
@@ -86,6 +110,10 @@ pub async fn deploy(
       .json()
       .await
       .context("failed to read JSON from response body")?;
+    if status == StatusCode::CONFLICT && error == "service unchanged" {
+      println!("Service '{name}' unchanged");
+      return Ok(());
+    }
     if let Some(detail) = detail {
       let detail = serde_json::to_string_pretty(&detail)?;
       bail!("server responded with error '{error}' ({status})\n\nDetail: {detail}");
@@ -95,11 +123,15 @@ pub async fn deploy(
   }
 
   let resp: HttpUploadResponse = resp.json().await?;
-  let prefix = resp
-    .replaced_service
-    .is_some()
-    .then_some("Updated")
-    .unwrap_or("Created");
+  let prefix = if mode == UploadMode::Canary {
+    "Deployed canary of"
+  } else {
+    resp
+      .replaced_service
+      .is_some()
+      .then_some("Updated")
+      .unwrap_or("Created")
+  };
   let suffix = resp
     .errors
     .is_empty()
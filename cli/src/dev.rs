@@ -1,4 +1,4 @@
-use crate::server::metadata::Metadata;
+use crate::server::metadata::{Metadata, MetadataStore};
 use crate::server::upload::{log_result, upload_local, UploadMode};
 use crate::server::ServerState;
 use crate::SourceKind;
@@ -23,6 +23,7 @@ pub async fn save_services_from_paths(
   services_path: &Path,
 ) -> anyhow::Result<Vec<(SourceKind, String)>> {
   let mut kinds_and_names = Vec::with_capacity(services.len());
+  let store = MetadataStore::new(services_path);
   for path in services {
     let path = fs::canonicalize(path).await?;
     let fs_metadata = fs::metadata(&path).await?;
@@ -64,12 +65,20 @@ pub async fn save_services_from_paths(
     kinds_and_names.push((kind, name));
 
     fs::create_dir(&service_path).await?;
-    Metadata {
+    let metadata = Metadata {
       uuid: Uuid::new_v4(),
       started: true,
-    }
-    .write(&service_path.join("metadata.json"))
-    .await?;
+      remote: None,
+      granted: None,
+      pending_approval: false,
+      hash: None,
+      deployed_at: None,
+      revision: 0,
+      owner: None,
+      created_at: None,
+      history: Vec::new(),
+    };
+    store.put(&name, &metadata).await?;
 
     match kind {
       SourceKind::Single => {
@@ -112,14 +121,15 @@ pub fn init_watcher(
               if &event_path == path || *kind == SourceKind::Multi && event_path.starts_with(path) {
                 let result = rt.block_on(async {
                   const MODE: UploadMode = UploadMode::Hot; // FIXME: is hot update okay?
+                  let lock = state.abel.lock_deploy(name, true).await?;
                   let resp = match kind {
                     SourceKind::Single => {
                       let stream = ReaderStream::new(File::open(&path).await?);
-                      upload_local(&state, name.clone(), MODE, *kind, stream).await?
+                      upload_local(&state, &lock, MODE, true, *kind, stream).await?
                     }
                     SourceKind::Multi => {
                       let stream = pack_dir_into_stream(&path).await?;
-                      upload_local(&state, name.clone(), MODE, *kind, stream).await?
+                      upload_local(&state, &lock, MODE, true, *kind, stream).await?
                     }
                   };
                   log_result(&resp);
@@ -14,8 +14,8 @@ use log::{info, warn};
 use owo_colors::OwoColorize;
 use resolve::resolve_dep;
 use server::config::{Config, ConfigArgs, ServerArgs, HALF_NUM_CPUS};
-use server::upload::UploadMode;
-use server::{init_logger, init_state, init_state_with_stored_config, load_saved_services};
+use server::upload::{UploadMode, DEFAULT_CANARY_WEIGHT};
+use server::{init_state, init_state_with_stored_config, load_saved_services};
 use std::path::PathBuf;
 use std::sync::Arc;
 use tempfile::tempdir;
@@ -49,6 +49,12 @@ enum Command {
     path: PathBuf,
     #[clap(short, long, value_enum, default_value_t)]
     mode: UploadMode,
+    /// Percentage of traffic routed to the canary in canary mode.
+    #[clap(short, long, default_value_t = DEFAULT_CANARY_WEIGHT)]
+    weight: u8,
+    /// Updates the service even if its source is unchanged.
+    #[clap(short, long)]
+    force: bool,
   },
   Resolve {
     path: PathBuf,
@@ -67,8 +73,9 @@ fn main() -> anyhow::Result<()> {
 
   match args.command {
     Command::Server { args } => {
-      init_logger();
+      server::logging::init();
       info!("Starting abel-server v{ver}");
+      server::hardening::apply(&args.abel_path)?;
       block_on(async {
         let (abel_path, config, state) = init_state_with_stored_config(args).await?;
         info!("Abel working path: {}", abel_path.display().underline());
@@ -79,12 +86,12 @@ fn main() -> anyhow::Result<()> {
           warn!("No authentication token set. Don't do this in production environment!");
         }
 
-        load_saved_services(&state, &abel_path.join("services")).await?;
+        load_saved_services(&state, &abel_path.join("services"), &config).await?;
         server::run(config, state).await
       })
     }
     Command::Dev { config, services } => {
-      init_logger();
+      server::logging::init();
       info!("Starting abel-server v{ver} (dev mode)");
 
       let abel_path = tempdir()?;
@@ -111,7 +118,7 @@ fn main() -> anyhow::Result<()> {
         let services_path = abel_path.path().join("services");
         let kinds_and_names = save_services_from_paths(&services, &services_path).await?;
 
-        load_saved_services(&state, &services_path).await?;
+        load_saved_services(&state, &services_path, &config).await?;
         let server_handle = tokio::spawn(server::run(config, state.clone()));
         let _watcher = init_watcher(state, kinds_and_names, services)?;
         server_handle.await?
@@ -122,8 +129,10 @@ fn main() -> anyhow::Result<()> {
       auth_token,
       path,
       mode,
+      weight,
+      force,
     } => {
-      if let Err(error) = block_on(deploy(server, auth_token, path, mode)) {
+      if let Err(error) = block_on(deploy(server, auth_token, path, mode, weight, force)) {
         println!("{} {error:?}", "error:".red().bold());
         std::process::exit(1);
       }
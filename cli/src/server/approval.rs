@@ -0,0 +1,121 @@
+use super::types::{ServiceStatus, ServiceWithStatus};
+use super::{json_response, layout, Result, ServerState};
+use abel_core::Permissions;
+use hyper::{Body, Request, Response, StatusCode};
+use log::info;
+use serde_json::{json, Value};
+use std::borrow::Cow;
+use tokio::io;
+
+/// Permissions a new version of the service may request without approval.
+pub async fn granted(state: &ServerState, name: &str) -> Result<Permissions> {
+  let metadata = match state.metadata.get(name).await {
+    Ok(metadata) => metadata,
+    Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(Permissions::default()),
+    Err(error) => return Err(error.into()),
+  };
+  match metadata.granted {
+    Some(granted) => Ok(granted),
+    // Services deployed without approval keep what they currently have
+    None => Ok(match state.abel.get_service(name) {
+      Ok(service) => service.upgrade().permissions().clone(),
+      Err(_) => Permissions::default(),
+    }),
+  }
+}
+
+/// Approves the permissions requested by a held service, and starts it. A held
+/// update replaces the version serving until now.
+pub async fn approve(state: &ServerState, name: &str) -> Result<Response<Body>> {
+  let lock = state.abel.lock_deploy(name, false).await?;
+  let (service, _replaced) = state.abel.approve_service(&lock).await?;
+  let approved = service.permissions().clone();
+  drop(service);
+  layout::approve_held(&state.abel_path.join("services"), name).await?;
+  (state.metadata)
+    .modify(name, &mut |m| {
+      m.granted = Some(approved.clone());
+      m.started = true;
+      m.pending_approval = false;
+    })
+    .await?;
+  info!("Approved permissions of service '{name}': {approved:?}");
+
+  let service = state.abel.start_service(&lock).await?;
+  let guard = service.upgrade();
+  json_response(
+    StatusCode::OK,
+    json!({
+      "approved": approved,
+      "service": ServiceWithStatus {
+        status: ServiceStatus::Running,
+        service: Cow::Borrowed(guard.info()),
+        metrics: guard.metrics().snapshot(),
+        uptime: Some(guard.metrics().uptime().as_secs()),
+        canary: None,
+      },
+    }),
+  )
+}
+
+/// Permissions requested by the service, granted to it, and what it actually
+/// runs with.
+///
+/// If an update is held for approval, what it requests is shown, while the
+/// effective permissions are still those of the version serving.
+pub async fn get_permissions(state: &ServerState, name: &str) -> Result<Response<Body>> {
+  let service = state.abel.get_service(name)?;
+  let guard = service.upgrade();
+  let held = state.abel.get_held_service(name);
+  let requested = held.as_deref().unwrap_or(&*guard);
+  json_response(
+    StatusCode::OK,
+    json!({
+      "requested": requested.permissions(),
+      "granted": granted(state, name).await?,
+      "effective": guard.effective_permissions(),
+      "pending_approval": requested.pending_approval(),
+    }),
+  )
+}
+
+/// Grants or revokes permissions at runtime, replacing the top-level fields
+/// present in the request body.
+///
+/// A running service keeps running with the new permissions; services held
+/// for approval still need to be approved.
+pub async fn update_permissions(
+  state: &ServerState,
+  name: &str,
+  req: Request<Body>,
+) -> Result<Response<Body>> {
+  let lock = state.abel.lock_deploy(name, false).await?;
+  state.abel.get_service(name)?;
+  let body = hyper::body::to_bytes(req.into_body())
+    .await
+    .map_err(|error| (400, "failed to read request body", error.to_string()))?;
+  let patch: serde_json::Map<String, Value> =
+    serde_json::from_slice(&body).map_err(|error| ("invalid permissions", error.to_string()))?;
+  let mut granted = serde_json::to_value(granted(state, name).await?)?;
+  granted.as_object_mut().unwrap().extend(patch);
+  let granted: Permissions =
+    serde_json::from_value(granted).map_err(|error| ("invalid permissions", error.to_string()))?;
+
+  (state.metadata)
+    .modify(name, &mut |m| m.granted = Some(granted.clone()))
+    .await?;
+  let service = state.abel.grant_permissions(&lock, Some(granted.clone()))?;
+  let guard = service.upgrade();
+  info!("Updated permissions granted to service '{name}': {granted:?}");
+  let held = state.abel.get_held_service(name);
+  let requested = held.as_deref().unwrap_or(&*guard);
+  json_response(
+    StatusCode::OK,
+    json!({
+      "requested": requested.permissions(),
+      "granted": granted,
+      "effective": guard.effective_permissions(),
+      "pending_approval": requested.pending_approval(),
+    }),
+  )
+}
@@ -0,0 +1,242 @@
+use super::{json_response, Result, ServerState};
+use data_encoding::HEXLOWER;
+use futures::Future;
+use hyper::{Body, Request, Response, StatusCode};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::fs::{self, OpenOptions};
+use tokio::io::{self, AsyncWriteExt};
+use tokio::sync::Mutex;
+use uuid::Uuid;
+
+/// Seconds before another denied request from the same address to the same
+/// service is recorded.
+const DENIAL_INTERVAL: u64 = 60;
+/// Most denied addresses remembered within [`DENIAL_INTERVAL`]. Denials beyond
+/// are not recorded.
+const MAX_DENIALS: usize = 1024;
+
+/// Append-only log of management operations, stored as JSON lines.
+pub struct AuditLog {
+  path: PathBuf,
+  /// Identifies the authentication token without revealing it.
+  identity: String,
+  lock: Mutex<()>,
+  /// When denied requests were last recorded, by service and address.
+  denials: std::sync::Mutex<HashMap<(String, IpAddr), u64>>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AuditEntry {
+  /// Unix time in seconds.
+  pub time: u64,
+  pub identity: String,
+  /// Whether the operation was replicated from another node in the cluster.
+  #[serde(default, skip_serializing_if = "is_false")]
+  pub forwarded: bool,
+  pub operation: String,
+  pub service: String,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub uuid: Option<Uuid>,
+  pub status: u16,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub error: Option<String>,
+}
+
+fn is_false(x: &bool) -> bool {
+  !x
+}
+
+/// Who requested an operation, taken from the request before it is consumed.
+#[derive(Clone)]
+pub struct Actor {
+  forwarded: bool,
+}
+
+impl Actor {
+  pub fn of(state: &ServerState, req: &Request<Body>) -> Self {
+    Self {
+      forwarded: state.cluster.is_forwarded(req.headers()),
+    }
+  }
+}
+
+impl AuditLog {
+  pub fn new(path: PathBuf, auth_token: Option<Uuid>) -> Self {
+    let identity = match auth_token {
+      Some(token) => {
+        let digest = Sha256::digest(token.to_string().as_bytes());
+        format!("token:{}", &HEXLOWER.encode(&digest)[..16])
+      }
+      None => "anonymous".into(),
+    };
+    Self {
+      path,
+      identity,
+      lock: Mutex::new(()),
+      denials: Default::default(),
+    }
+  }
+
+  /// Whether a denied request is due to be recorded, so that denied clients
+  /// cannot flood the log with synced writes.
+  fn denial_due(&self, service: &str, ip: IpAddr) -> bool {
+    let now = now();
+    let mut denials = self.denials.lock().unwrap();
+    denials.retain(|_, time| now < *time + DENIAL_INTERVAL);
+    if denials.len() >= MAX_DENIALS {
+      return false;
+    }
+    match denials.entry((service.into(), ip)) {
+      Entry::Occupied(_) => false,
+      Entry::Vacant(entry) => {
+        entry.insert(now);
+        true
+      }
+    }
+  }
+
+  async fn append(&self, entry: &AuditEntry) -> io::Result<()> {
+    let mut line = serde_json::to_vec(entry)?;
+    line.push(b'\n');
+    let _guard = self.lock.lock().await;
+    let mut file = (OpenOptions::new().create(true).append(true))
+      .open(&self.path)
+      .await?;
+    file.write_all(&line).await?;
+    file.sync_data().await
+  }
+
+  pub async fn query(
+    &self,
+    service: Option<&str>,
+    since: Option<u64>,
+  ) -> io::Result<Vec<AuditEntry>> {
+    let content = match fs::read_to_string(&self.path).await {
+      Ok(content) => content,
+      Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+      Err(error) => return Err(error),
+    };
+    let entries = (content.lines())
+      .filter_map(|x| serde_json::from_str::<AuditEntry>(x).ok())
+      .filter(|x| service.map_or(true, |s| x.service == s))
+      .filter(|x| since.map_or(true, |t| x.time >= t))
+      .collect();
+    Ok(entries)
+  }
+}
+
+fn now() -> u64 {
+  (SystemTime::now().duration_since(UNIX_EPOCH))
+    .map(|x| x.as_secs())
+    .unwrap_or(0)
+}
+
+fn service_uuid(state: &ServerState, name: &str) -> Option<Uuid> {
+  (state.abel.get_service(name).ok()).map(|x| x.upgrade().uuid())
+}
+
+/// Runs a management operation and records its outcome.
+///
+/// Operations accepted to run as jobs are recorded by [`audited_job`] once
+/// done, not when accepted.
+pub async fn audited(
+  state: &ServerState,
+  actor: Actor,
+  operation: &str,
+  service: &str,
+  f: impl Future<Output = Result<Response<Body>>>,
+) -> Result<Response<Body>> {
+  let uuid_before = service_uuid(state, service);
+  let result = f.await;
+  let (status, error) = match &result {
+    Ok(resp) if resp.status() == StatusCode::ACCEPTED => return result,
+    Ok(resp) => (resp.status(), None),
+    Err(error) => (error.kind().status(), Some(error.to_string())),
+  };
+  record(state, actor, operation, service, uuid_before, status, error).await;
+  result
+}
+
+/// Runs a management operation as a job and records its outcome.
+pub async fn audited_job(
+  state: &ServerState,
+  actor: Actor,
+  operation: &str,
+  service: &str,
+  f: impl Future<Output = Result<serde_json::Value>>,
+) -> Result<serde_json::Value> {
+  let uuid_before = service_uuid(state, service);
+  let result = f.await;
+  let (status, error) = match &result {
+    Ok(_) => (StatusCode::OK, None),
+    Err(error) => (error.kind().status(), Some(error.to_string())),
+  };
+  record(state, actor, operation, service, uuid_before, status, error).await;
+  result
+}
+
+async fn record(
+  state: &ServerState,
+  actor: Actor,
+  operation: &str,
+  service: &str,
+  uuid_before: Option<Uuid>,
+  status: StatusCode,
+  error: Option<String>,
+) {
+  let entry = AuditEntry {
+    time: now(),
+    identity: state.audit.identity.clone(),
+    forwarded: actor.forwarded,
+    operation: operation.into(),
+    service: service.into(),
+    uuid: service_uuid(state, service).or(uuid_before),
+    status: status.as_u16(),
+    error,
+  };
+  if let Err(error) = state.audit.append(&entry).await {
+    warn!("failed to write audit log: {error}");
+  }
+}
+
+/// Records a request rejected by the service's `allow_ips` or `deny_ips`.
+///
+/// Each address is recorded at most once every [`DENIAL_INTERVAL`] seconds
+/// per service.
+pub async fn record_ip_denied(state: &ServerState, service: &str, ip: IpAddr) {
+  if !state.audit.denial_due(service, ip) {
+    return;
+  }
+  let entry = AuditEntry {
+    time: now(),
+    identity: format!("ip:{ip}"),
+    forwarded: false,
+    operation: "deny_ip".into(),
+    service: service.into(),
+    uuid: service_uuid(state, service),
+    status: StatusCode::FORBIDDEN.as_u16(),
+    error: None,
+  };
+  if let Err(error) = state.audit.append(&entry).await {
+    warn!("failed to write audit log: {error}");
+  }
+}
+
+pub async fn list(state: &ServerState, query: &str) -> Result<Response<Body>> {
+  #[derive(Deserialize)]
+  struct Query {
+    service: Option<String>,
+    since: Option<u64>,
+  }
+
+  let Query { service, since } = serde_qs::from_str(query)?;
+  let entries = state.audit.query(service.as_deref(), since).await?;
+  json_response(StatusCode::OK, entries)
+}
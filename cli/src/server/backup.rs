@@ -0,0 +1,373 @@
+use super::{json_response, Result, ServerState};
+use abel_core::{encode_s3_key, S3Credentials};
+use hive_asar::Archive;
+use hyper::{Body, Method, Response, StatusCode};
+use log::{info, warn};
+use reqwest::{Client, RequestBuilder};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::fs;
+use uuid::Uuid;
+
+/// Attempts at archiving storage before giving up, if files keep changing.
+const MAX_SNAPSHOT_ATTEMPTS: usize = 5;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BackupConfig {
+  /// Local directory, or base URL of an object storage bucket (`http://` or
+  /// `https://`) accepting `GET`, `PUT` and `DELETE`.
+  pub destination: String,
+  /// Credentials signing requests to the bucket. Requests are sent unsigned
+  /// if not set.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub credentials: Option<S3Credentials>,
+  /// Seconds between backups.
+  #[serde(default = "default_interval")]
+  pub interval: u64,
+  /// Number of backups kept for each service.
+  #[serde(default = "default_keep")]
+  pub keep: usize,
+}
+
+impl BackupConfig {
+  /// Directory backups are stored in, unless stored in object storage.
+  pub fn local_dir(&self) -> Option<&Path> {
+    if Destination::is_bucket(&self.destination) {
+      None
+    } else {
+      Some(Path::new(&self.destination))
+    }
+  }
+}
+
+fn default_interval() -> u64 {
+  24 * 60 * 60
+}
+
+fn default_keep() -> usize {
+  7
+}
+
+/// Where backups are stored.
+///
+/// Each service's backups are kept under `<destination>/<name>/` as asar
+/// archives named by their Unix time in seconds, along with an `index.json`
+/// listing them from oldest to newest.
+enum Destination {
+  Dir(PathBuf),
+  Bucket {
+    base: String,
+    client: Client,
+    credentials: Option<S3Credentials>,
+  },
+}
+
+fn bucket_error(error: reqwest::Error) -> io::Error {
+  match error.status() {
+    Some(reqwest::StatusCode::NOT_FOUND) => io::ErrorKind::NotFound.into(),
+    _ => io::Error::new(io::ErrorKind::Other, error),
+  }
+}
+
+impl Destination {
+  fn is_bucket(destination: &str) -> bool {
+    destination.starts_with("http://") || destination.starts_with("https://")
+  }
+
+  fn new(destination: &str, credentials: Option<S3Credentials>) -> Self {
+    if Self::is_bucket(destination) {
+      Self::Bucket {
+        base: destination.trim_end_matches('/').into(),
+        client: Client::new(),
+        credentials,
+      }
+    } else {
+      Self::Dir(destination.into())
+    }
+  }
+
+  /// Builds a request to the object at `path` in the bucket, signed if
+  /// credentials are set.
+  fn request(
+    base: &str,
+    client: &Client,
+    credentials: &Option<S3Credentials>,
+    method: Method,
+    path: &str,
+  ) -> io::Result<RequestBuilder> {
+    let url = format!("{base}/{}", encode_s3_key(path));
+    let mut req = client.request(method.clone(), &url);
+    if let Some(credentials) = credentials {
+      let uri = (url.parse::<hyper::Uri>())
+        .map_err(|error| io::Error::new(io::ErrorKind::InvalidInput, error))?;
+      let headers = (credentials.authorize(&method, &uri))
+        .map_err(|error| io::Error::new(io::ErrorKind::InvalidInput, error))?;
+      req = req.headers(headers);
+    }
+    Ok(req)
+  }
+
+  async fn read(&self, path: &str) -> io::Result<Vec<u8>> {
+    match self {
+      Self::Dir(dir) => fs::read(dir.join(path)).await,
+      Self::Bucket {
+        base,
+        client,
+        credentials,
+      } => {
+        let req = Self::request(base, client, credentials, Method::GET, path)?;
+        let resp = (req.send().await)
+          .and_then(|x| x.error_for_status())
+          .map_err(bucket_error)?;
+        Ok(resp.bytes().await.map_err(bucket_error)?.to_vec())
+      }
+    }
+  }
+
+  async fn write(&self, path: &str, content: Vec<u8>) -> io::Result<()> {
+    match self {
+      Self::Dir(dir) => {
+        let path = dir.join(path);
+        if let Some(parent) = path.parent() {
+          fs::create_dir_all(parent).await?;
+        }
+        fs::write(path, content).await
+      }
+      Self::Bucket {
+        base,
+        client,
+        credentials,
+      } => {
+        let req = Self::request(base, client, credentials, Method::PUT, path)?;
+        (req.body(content).send().await)
+          .and_then(|x| x.error_for_status())
+          .map_err(bucket_error)?;
+        Ok(())
+      }
+    }
+  }
+
+  async fn remove(&self, path: &str) -> io::Result<()> {
+    let result = match self {
+      Self::Dir(dir) => fs::remove_file(dir.join(path)).await,
+      Self::Bucket {
+        base,
+        client,
+        credentials,
+      } => {
+        let req = Self::request(base, client, credentials, Method::DELETE, path)?;
+        (req.send().await)
+          .and_then(|x| x.error_for_status())
+          .map(|_| ())
+          .map_err(bucket_error)
+      }
+    };
+    match result {
+      Err(error) if error.kind() != io::ErrorKind::NotFound => Err(error),
+      _ => Ok(()),
+    }
+  }
+
+  async fn index(&self, name: &str) -> io::Result<Vec<u64>> {
+    match self.read(&format!("{name}/index.json")).await {
+      Ok(content) => Ok(serde_json::from_slice(&content)?),
+      Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+      Err(error) => Err(error),
+    }
+  }
+}
+
+/// Periodic backups of services' local storage.
+pub struct Backups {
+  destination: Destination,
+  interval: Duration,
+  keep: usize,
+}
+
+impl Backups {
+  pub fn new(config: &BackupConfig) -> Self {
+    Self {
+      destination: Destination::new(&config.destination, config.credentials.clone()),
+      interval: Duration::from_secs(config.interval.max(1)),
+      keep: config.keep.max(1),
+    }
+  }
+
+  /// Archives the service's local storage, then removes backups beyond
+  /// retention. Returns the new backup's ID.
+  ///
+  /// The service keeps running, so the archive is taken again if any file
+  /// changed while being packed, ensuring that it is a consistent snapshot.
+  async fn back_up(&self, abel_path: &Path, name: &str) -> io::Result<u64> {
+    let storage_path = abel_path.join("storage").join(name);
+    let archive = snapshot(&storage_path).await?;
+
+    let id = (SystemTime::now().duration_since(UNIX_EPOCH))
+      .map(|x| x.as_secs())
+      .unwrap_or(0);
+    (self.destination)
+      .write(&format!("{name}/{id}.asar"), archive)
+      .await?;
+
+    let mut index = self.destination.index(name).await?;
+    index.retain(|x| *x != id);
+    index.push(id);
+    let expired = index.len().saturating_sub(self.keep);
+    let expired = index.drain(..expired).collect::<Vec<_>>();
+    (self.destination)
+      .write(&format!("{name}/index.json"), serde_json::to_vec(&index)?)
+      .await?;
+    for id in expired {
+      (self.destination)
+        .remove(&format!("{name}/{id}.asar"))
+        .await?;
+    }
+    Ok(id)
+  }
+}
+
+/// Packs the directory into an asar archive, retrying until no file changes
+/// in the meantime.
+async fn snapshot(path: &Path) -> io::Result<Vec<u8>> {
+  for _ in 0..MAX_SNAPSHOT_ATTEMPTS {
+    let before = file_states(path).await?;
+    let mut archive = Vec::new();
+    hive_asar::pack_dir(path, &mut archive).await?;
+    if file_states(path).await? == before {
+      return Ok(archive);
+    }
+  }
+  Err(io::Error::new(
+    io::ErrorKind::Other,
+    "storage kept changing while being backed up",
+  ))
+}
+
+/// Paths, sizes and modification times of all files under the directory.
+async fn file_states(path: &Path) -> io::Result<Vec<(PathBuf, u64, SystemTime)>> {
+  let mut states = Vec::new();
+  let mut dirs = vec![path.to_owned()];
+  while let Some(dir) = dirs.pop() {
+    let mut entries = fs::read_dir(&dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+      let metadata = entry.metadata().await?;
+      if metadata.is_dir() {
+        dirs.push(entry.path());
+      } else {
+        states.push((entry.path(), metadata.len(), metadata.modified()?));
+      }
+    }
+  }
+  states.sort();
+  Ok(states)
+}
+
+/// Backs up all services' local storage periodically. Never returns.
+pub async fn run(state: Arc<ServerState>) {
+  let backups = match &state.backups {
+    Some(x) => x,
+    None => return,
+  };
+  let mut interval = tokio::time::interval(backups.interval);
+  // The first tick completes immediately
+  interval.tick().await;
+  loop {
+    interval.tick().await;
+    let names = (state.abel.list_services())
+      .map(|x| x.upgrade().name().to_owned())
+      .collect::<Vec<_>>();
+    for name in names {
+      // Services never started have no storage yet
+      if !state.abel_path.join("storage").join(&name).exists() {
+        continue;
+      }
+      match backups.back_up(&state.abel_path, &name).await {
+        Ok(id) => info!("Backed up storage of service '{name}' ({id})"),
+        Err(error) => warn!("failed to back up storage of service '{name}': {error}"),
+      }
+    }
+  }
+}
+
+fn backups(state: &ServerState) -> Result<&Backups> {
+  (state.backups.as_ref())
+    .ok_or_else(|| (404, "backup not configured", serde_json::Value::Null).into())
+}
+
+pub async fn list(state: &ServerState, name: &str) -> Result<Response<Body>> {
+  state.abel.get_service(name)?;
+  let index = backups(state)?.destination.index(name).await?;
+  json_response(StatusCode::OK, json!({ "service": name, "backups": index }))
+}
+
+/// Replaces the local storage of a stopped service with a backup.
+pub async fn restore(state: &ServerState, name: &str, query: &str) -> Result<Response<Body>> {
+  #[derive(Deserialize)]
+  struct Query {
+    /// Latest if not set.
+    backup: Option<u64>,
+  }
+
+  let Query { backup } = serde_qs::from_str(query)?;
+  let backups = backups(state)?;
+  let _lock = state.abel.lock_deploy(name, false).await?;
+  if state.abel.get_running_service(name).is_ok() {
+    return Err(From::from((
+      409,
+      "service is running",
+      json!({ "msg": "stop the service before restoring its storage", "name": name }),
+    )));
+  }
+  state.abel.get_service(name)?;
+
+  let index = backups.destination.index(name).await?;
+  let id = match backup {
+    Some(id) if index.contains(&id) => id,
+    None if !index.is_empty() => *index.last().unwrap(),
+    _ => {
+      return Err(From::from((
+        404,
+        "backup not found",
+        json!({ "name": name, "backup": backup }),
+      )))
+    }
+  };
+  let archive = (backups.destination)
+    .read(&format!("{name}/{id}.asar"))
+    .await?;
+
+  let temp_path = state.abel_path.join(format!("tmp/{}", Uuid::new_v4()));
+  let archive_path = temp_path.with_extension("asar");
+  fs::write(&archive_path, archive).await?;
+  let result = async {
+    let mut archive = Archive::new_from_file(&archive_path).await?;
+    fs::create_dir(&temp_path).await?;
+    archive.extract(&temp_path).await?;
+
+    // Swaps the directories, so that a failed extraction leaves the current
+    // storage untouched
+    let storage_path = state.abel_path.join("storage").join(name);
+    let old_path = temp_path.with_extension("old");
+    if storage_path.exists() {
+      fs::rename(&storage_path, &old_path).await?;
+    }
+    fs::rename(&temp_path, &storage_path).await?;
+    if old_path.exists() {
+      fs::remove_dir_all(&old_path).await?;
+    }
+    io::Result::Ok(())
+  }
+  .await;
+  let _ = fs::remove_file(&archive_path).await;
+  if result.is_err() && temp_path.exists() {
+    let _ = fs::remove_dir_all(&temp_path).await;
+  }
+  result?;
+
+  info!("Restored storage of service '{name}' from backup {id}");
+  json_response(StatusCode::OK, json!({ "service": name, "backup": id }))
+}
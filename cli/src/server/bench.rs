@@ -0,0 +1,234 @@
+//! Load testing services from inside the process.
+
+use super::upload::check_size;
+use super::{json_response, Result, ServerState};
+use bytes::BytesMut;
+use futures::{stream, StreamExt, TryStreamExt};
+use hyper::header::{HeaderName, HeaderValue};
+use hyper::{Body, Method, Request, Response, StatusCode};
+use log::info;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::{BTreeMap, HashMap};
+use std::time::{Duration, Instant};
+
+const MAX_REQUESTS: usize = 10000;
+const MAX_CONCURRENCY: usize = 100;
+/// Maximum size of the options, including the body of each request.
+const MAX_OPTIONS_SIZE: u64 = 1024 * 1024;
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct BenchOptions {
+  /// Path in the service, with query if any.
+  #[serde(default = "default_path")]
+  path: String,
+  #[serde(default = "default_method")]
+  method: String,
+  #[serde(default)]
+  headers: HashMap<String, String>,
+  #[serde(default)]
+  body: String,
+  #[serde(default = "default_requests")]
+  requests: usize,
+  /// Number of requests in flight at a time.
+  #[serde(default = "default_concurrency")]
+  concurrency: usize,
+}
+
+fn default_path() -> String {
+  "/".into()
+}
+
+fn default_method() -> String {
+  "GET".into()
+}
+
+fn default_requests() -> usize {
+  100
+}
+
+fn default_concurrency() -> usize {
+  1
+}
+
+/// Latencies in milliseconds.
+#[derive(Serialize)]
+struct Latencies {
+  min: f64,
+  p50: f64,
+  p95: f64,
+  p99: f64,
+  max: f64,
+}
+
+impl Latencies {
+  fn new(mut samples: Vec<Duration>) -> Option<Self> {
+    samples.sort_unstable();
+    let ms = |x: Duration| x.as_secs_f64() * 1000.;
+    // Nearest rank
+    let percentile = |p: usize| {
+      let rank = (samples.len() * p + 99) / 100;
+      ms(samples[rank.max(1) - 1])
+    };
+    Some(Self {
+      min: ms(*samples.first()?),
+      p50: percentile(50),
+      p95: percentile(95),
+      p99: percentile(99),
+      max: ms(*samples.last()?),
+    })
+  }
+}
+
+/// Sends synthetic requests to a running service, bypassing the network, and
+/// reports their latencies.
+///
+/// Requests go directly to the service, skipping maintenance, response caching
+/// and request capturing. They are not counted in the service's metrics, and
+/// their failures are not reported.
+pub async fn bench(state: &ServerState, name: &str, req: Request<Body>) -> Result<Response<Body>> {
+  state.abel.get_running_service(name)?;
+  let mut body = BytesMut::new();
+  let mut req_body = req.into_body();
+  while let Some(chunk) = req_body
+    .try_next()
+    .await
+    .map_err(|error| (400, "failed to read request body", error.to_string()))?
+  {
+    body.extend(chunk);
+    check_size("bench options", body.len() as _, MAX_OPTIONS_SIZE)?;
+  }
+  let body = if body.is_empty() {
+    &b"{}"[..]
+  } else {
+    &body[..]
+  };
+  let options: BenchOptions =
+    serde_json::from_slice(body).map_err(|error| ("invalid bench options", error.to_string()))?;
+
+  if !options.path.starts_with('/') {
+    return Err("path must start with '/'".into());
+  }
+  if options.requests == 0 || options.requests > MAX_REQUESTS {
+    return Err(From::from((
+      "invalid bench options",
+      json!({ "msg": "number of requests out of range", "max": MAX_REQUESTS }),
+    )));
+  }
+  if options.concurrency == 0 || options.concurrency > MAX_CONCURRENCY {
+    return Err(From::from((
+      "invalid bench options",
+      json!({ "msg": "concurrency out of range", "max": MAX_CONCURRENCY }),
+    )));
+  }
+  let method = Method::from_bytes(options.method.as_bytes())
+    .map_err(|_| ("invalid bench options", json!({ "msg": "invalid method" })))?;
+  let mut headers = Vec::with_capacity(options.headers.len());
+  for (k, v) in &options.headers {
+    let header = (HeaderName::from_bytes(k.as_bytes()).ok())
+      .zip(HeaderValue::from_str(v).ok())
+      .ok_or_else(|| {
+        (
+          "invalid bench options",
+          json!({ "msg": "invalid header", "name": k }),
+        )
+      })?;
+    headers.push(header);
+  }
+  let (sub_path, query) = match options.path.split_once('?') {
+    Some((path, query)) => (path.to_owned(), Some(query)),
+    None => (options.path.clone(), None),
+  };
+  let uri = match query {
+    Some(query) => format!("/{name}{sub_path}?{query}"),
+    None => format!("/{name}{sub_path}"),
+  };
+
+  let make_request = || {
+    let mut builder = Request::builder().method(method.clone()).uri(&uri);
+    for (k, v) in &headers {
+      builder = builder.header(k, v);
+    }
+    builder.body(Body::from(options.body.clone()))
+  };
+  // Fail early on URIs the service would never see
+  make_request().map_err(|error| ("invalid bench options", error.to_string()))?;
+
+  let started_at = Instant::now();
+  let results = stream::iter(0..options.requests)
+    .map(|_| async {
+      let service = state.abel.get_running_service(name)?;
+      let req = make_request().unwrap();
+      let start = Instant::now();
+      let resp = (state.abel)
+        .run_synthetic(service, sub_path.clone(), req)
+        .await?;
+      let status = resp.status();
+      // Responses are read through, but not kept
+      let mut resp_body = resp.into_body();
+      while resp_body
+        .try_next()
+        .await
+        .map_err(|error| (502, "failed to read service response", error.to_string()))?
+        .is_some()
+      {}
+      Result::Ok((status, start.elapsed()))
+    })
+    .buffer_unordered(options.concurrency)
+    .collect::<Vec<_>>()
+    .await;
+  let elapsed = started_at.elapsed();
+
+  let mut latencies = Vec::with_capacity(results.len());
+  let mut statuses = BTreeMap::<u16, usize>::new();
+  let mut errors = BTreeMap::<String, usize>::new();
+  for result in results {
+    match result {
+      Ok((status, latency)) => {
+        latencies.push(latency);
+        *statuses.entry(status.as_u16()).or_default() += 1;
+      }
+      Err(error) => *errors.entry(error.to_string()).or_default() += 1,
+    }
+  }
+
+  info!(
+    "Benchmarked service '{name}' with {} requests in {elapsed:.2?}",
+    options.requests
+  );
+  json_response(
+    StatusCode::OK,
+    json!({
+      "service": name,
+      "requests": options.requests,
+      "concurrency": options.concurrency,
+      "elapsed": elapsed.as_secs_f64() * 1000.,
+      "rps": options.requests as f64 / elapsed.as_secs_f64(),
+      "latency": Latencies::new(latencies),
+      "statuses": statuses,
+      "errors": errors,
+    }),
+  )
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_latencies() {
+    assert!(Latencies::new(Vec::new()).is_none());
+
+    let one = Latencies::new(vec![Duration::from_millis(5)]).unwrap();
+    assert_eq!((one.min, one.p50, one.p99, one.max), (5., 5., 5., 5.));
+
+    let samples = (1..=100).rev().map(Duration::from_millis).collect();
+    let latencies = Latencies::new(samples).unwrap();
+    assert_eq!(latencies.min, 1.);
+    assert_eq!(latencies.p50, 50.);
+    assert_eq!(latencies.p95, 95.);
+    assert_eq!(latencies.p99, 99.);
+    assert_eq!(latencies.max, 100.);
+  }
+}
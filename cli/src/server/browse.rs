@@ -0,0 +1,106 @@
+//! Browsing the deployed source of services.
+
+use super::error::Error;
+use super::{json_response, Result, ServerState};
+use abel_core::source::{Metadata, Source};
+use hyper::{Body, Response, StatusCode};
+use serde::Serialize;
+use serde_json::json;
+use tokio::io::{self, AsyncReadExt};
+
+#[derive(Serialize)]
+struct SourceEntry {
+  path: String,
+  #[serde(rename = "type")]
+  kind: &'static str,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  size: Option<u64>,
+}
+
+fn source_of(state: &ServerState, name: &str) -> Result<Source> {
+  let service = state.abel.get_service(name)?;
+  let source = service.upgrade().source().clone();
+  Ok(source)
+}
+
+/// Lists all entries in a service's source.
+pub async fn tree(state: &ServerState, name: &str) -> Result<Response<Body>> {
+  let source = source_of(state, name)?;
+  let mut entries = Vec::new();
+  let mut dirs = vec![String::new()];
+  while let Some(dir) = dirs.pop() {
+    let names = match source.read_dir(&dir).await {
+      Ok(names) => names,
+      Err(error) if error.kind() == io::ErrorKind::Unsupported => {
+        return Err(From::from((
+          "listing not supported",
+          json!({ "msg": error.to_string(), "name": name }),
+        )))
+      }
+      Err(error) => return Err(error.into()),
+    };
+    for entry_name in names {
+      let path = if dir.is_empty() {
+        entry_name
+      } else {
+        format!("{dir}/{entry_name}")
+      };
+      let entry = match source.metadata(&path).await? {
+        Metadata::Dir => {
+          dirs.push(path.clone());
+          SourceEntry {
+            path,
+            kind: "dir",
+            size: None,
+          }
+        }
+        Metadata::File { size } => SourceEntry {
+          path,
+          kind: "file",
+          size: Some(size),
+        },
+      };
+      entries.push(entry);
+    }
+  }
+  entries.sort_unstable_by(|a, b| a.path.cmp(&b.path));
+  json_response(StatusCode::OK, json!({ "name": name, "entries": entries }))
+}
+
+/// Returns the content of a file in a service's source.
+pub async fn file(state: &ServerState, name: &str, path: &str) -> Result<Response<Body>> {
+  let source = source_of(state, name)?;
+  let not_found = || Error::from((404, "file not found", json!({ "name": name, "path": path })));
+  match source.metadata(path).await {
+    Ok(Metadata::File { .. }) => {}
+    Ok(Metadata::Dir) => return Err(not_found()),
+    Err(error) if error.kind() == io::ErrorKind::NotFound => return Err(not_found()),
+    Err(error) => return Err(error.into()),
+  }
+
+  let mut content = Vec::new();
+  source.get(path).await?.read_to_end(&mut content).await?;
+  let resp = Response::builder()
+    .header("content-type", content_type(path, &content))
+    .body(content.into())
+    .unwrap();
+  Ok(resp)
+}
+
+/// Guesses a file's content type from its extension, falling back to whether
+/// it is valid UTF-8.
+pub fn content_type(path: &str, content: &[u8]) -> &'static str {
+  match path.rsplit_once('.').map(|x| x.1) {
+    Some("html" | "htm") => "text/html; charset=utf-8",
+    Some("json") => "application/json",
+    Some("lua") => "text/x-lua; charset=utf-8",
+    Some("css") => "text/css; charset=utf-8",
+    Some("js") => "text/javascript; charset=utf-8",
+    Some("svg") => "image/svg+xml",
+    Some("png") => "image/png",
+    Some("jpg" | "jpeg") => "image/jpeg",
+    Some("gif") => "image/gif",
+    _ if std::str::from_utf8(content).is_ok() => "text/plain; charset=utf-8",
+    _ => "application/octet-stream",
+  }
+}
@@ -0,0 +1,200 @@
+use abel_core::net::CacheTtl;
+use bytes::Bytes;
+use hyper::body::HttpBody;
+use hyper::header::{HeaderName, HeaderValue, AGE, AUTHORIZATION, CACHE_CONTROL, SET_COOKIE, VARY};
+use hyper::{Body, HeaderMap, Method, Request, Response, StatusCode};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+/// Largest response body kept in the cache.
+const MAX_BODY_SIZE: u64 = 1024 * 1024;
+
+/// Responses are cached per version of a service, so that updating it
+/// invalidates them.
+#[derive(Hash, PartialEq, Eq)]
+struct Key {
+  uuid: Uuid,
+  method: Method,
+  path_and_query: String,
+}
+
+struct Entry {
+  /// Request headers named in the response's `Vary`, and their values.
+  vary: Vec<(HeaderName, Option<HeaderValue>)>,
+  stored: Instant,
+  expires: Instant,
+  status: StatusCode,
+  headers: HeaderMap,
+  body: Bytes,
+}
+
+impl Entry {
+  fn matches(&self, headers: &HeaderMap) -> bool {
+    (self.vary.iter()).all(|(name, value)| headers.get(name) == value.as_ref())
+  }
+
+  fn to_response(&self) -> Response<Body> {
+    let mut resp = Response::new(Body::from(self.body.clone()));
+    *resp.status_mut() = self.status;
+    *resp.headers_mut() = self.headers.clone();
+    resp
+      .headers_mut()
+      .insert(AGE, self.stored.elapsed().as_secs().into());
+    resp
+  }
+}
+
+/// Request eligible for caching, kept for looking up and storing its response.
+pub struct Lookup {
+  key: Key,
+  headers: HeaderMap,
+}
+
+/// Cache of service responses, keyed by method, path and the request headers
+/// named in `Vary`.
+///
+/// Only responses with a TTL, set by the service with `cache_ttl` or with
+/// `max-age` in `Cache-Control`, are cached.
+pub struct ResponseCache {
+  max_entries: usize,
+  entries: Mutex<HashMap<Key, Vec<Entry>>>,
+}
+
+impl ResponseCache {
+  pub fn new(max_entries: usize) -> Self {
+    Self {
+      max_entries,
+      entries: Default::default(),
+    }
+  }
+
+  /// Returns `None` if the request cannot be served from cache.
+  pub fn lookup(&self, uuid: Uuid, req: &Request<Body>) -> Option<Lookup> {
+    if !matches!(*req.method(), Method::GET | Method::HEAD)
+      || req.headers().contains_key(AUTHORIZATION)
+    {
+      return None;
+    }
+    let path_and_query = (req.uri().path_and_query())
+      .map(|x| x.as_str())
+      .unwrap_or("/");
+    Some(Lookup {
+      key: Key {
+        uuid,
+        method: req.method().clone(),
+        path_and_query: path_and_query.into(),
+      },
+      headers: req.headers().clone(),
+    })
+  }
+
+  pub fn get(&self, lookup: &Lookup) -> Option<Response<Body>> {
+    let entries = self.entries.lock().unwrap();
+    let now = Instant::now();
+    (entries.get(&lookup.key)?.iter())
+      .find(|x| x.expires > now && x.matches(&lookup.headers))
+      .map(Entry::to_response)
+  }
+
+  /// Stores the response if it is cacheable, returning it intact.
+  pub async fn store(&self, lookup: Lookup, resp: Response<Body>) -> hyper::Result<Response<Body>> {
+    let ttl = match cache_ttl(&resp) {
+      Some(ttl) if is_cacheable(&resp) => ttl,
+      _ => return Ok(resp),
+    };
+    let vary = (resp.headers().get_all(VARY).iter())
+      .filter_map(|x| x.to_str().ok())
+      .flat_map(|x| x.split(','))
+      .filter_map(|x| HeaderName::from_bytes(x.trim().as_bytes()).ok())
+      .map(|name| {
+        let value = lookup.headers.get(&name).cloned();
+        (name, value)
+      })
+      .collect();
+
+    let (parts, body) = resp.into_parts();
+    let body = hyper::body::to_bytes(body).await?;
+    let now = Instant::now();
+    let entry = Entry {
+      vary,
+      stored: now,
+      expires: now + ttl,
+      status: parts.status,
+      headers: parts.headers.clone(),
+      body: body.clone(),
+    };
+
+    let mut entries = self.entries.lock().unwrap();
+    let variants = entries.entry(lookup.key).or_default();
+    variants.retain(|x| x.expires > now && x.vary != entry.vary);
+    variants.push(entry);
+    evict(&mut entries, self.max_entries, now);
+
+    Ok(Response::from_parts(parts, body.into()))
+  }
+}
+
+/// Removes expired entries, and then those expiring soonest, until at most
+/// `max_entries` are left.
+fn evict(entries: &mut HashMap<Key, Vec<Entry>>, max_entries: usize, now: Instant) {
+  let len = |entries: &HashMap<Key, Vec<Entry>>| entries.values().map(Vec::len).sum::<usize>();
+  if len(entries) <= max_entries {
+    return;
+  }
+  entries
+    .values_mut()
+    .for_each(|x| x.retain(|x| x.expires > now));
+  entries.retain(|_, x| !x.is_empty());
+  let mut expires = (entries.values().flatten())
+    .map(|x| x.expires)
+    .collect::<Vec<_>>();
+  if expires.len() <= max_entries {
+    return;
+  }
+  expires.sort_unstable();
+  let threshold = expires[expires.len() - max_entries];
+  entries
+    .values_mut()
+    .for_each(|x| x.retain(|x| x.expires >= threshold));
+  entries.retain(|_, x| !x.is_empty());
+}
+
+fn cache_ttl(resp: &Response<Body>) -> Option<Duration> {
+  let directives = (resp.headers().get_all(CACHE_CONTROL).iter())
+    .filter_map(|x| x.to_str().ok())
+    .flat_map(|x| x.split(','))
+    .map(|x| x.trim().to_ascii_lowercase())
+    .collect::<Vec<_>>();
+  if (directives.iter()).any(|x| matches!(&**x, "no-store" | "no-cache" | "private")) {
+    return None;
+  }
+  let max_age = |name: &str| {
+    (directives.iter())
+      .filter_map(|x| x.split_once('='))
+      .find(|(k, _)| *k == name)
+      .and_then(|(_, v)| v.trim_matches('"').parse().ok())
+      .map(Duration::from_secs)
+  };
+  let ttl = (resp.extensions().get::<CacheTtl>())
+    .map(|x| x.0)
+    .or_else(|| max_age("s-maxage"))
+    .or_else(|| max_age("max-age"))?;
+  if ttl.is_zero() {
+    None
+  } else {
+    Some(ttl)
+  }
+}
+
+fn is_cacheable(resp: &Response<Body>) -> bool {
+  let headers = resp.headers();
+  matches!(resp.status().as_u16(), 200 | 203 | 204 | 301 | 404 | 410)
+    && !headers.contains_key(SET_COOKIE)
+    && !(headers.get_all(VARY).iter())
+      .filter_map(|x| x.to_str().ok())
+      .flat_map(|x| x.split(','))
+      .any(|x| x.trim() == "*")
+    && (resp.body().size_hint().upper()).map_or(false, |x| x <= MAX_BODY_SIZE)
+}
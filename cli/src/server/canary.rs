@@ -0,0 +1,122 @@
+use super::types::{CanaryStatus, ServiceStatus, ServiceWithStatus};
+use super::{json_response, layout, redirect, Result, ServerState};
+use abel_core::DeployGuard;
+use abel_core::ErrorKind::CanaryNotFound;
+use hyper::{Body, Response, StatusCode};
+use log::{info, warn};
+use owo_colors::OwoColorize;
+use serde_json::json;
+use std::borrow::Cow;
+use std::path::Path;
+use tokio::{fs, io};
+
+const SOURCE_FILES: [(&str, &str); 2] =
+  [("canary.lua", "source.lua"), ("canary.asar", "source.asar")];
+
+pub fn status(state: &ServerState, name: &str) -> Option<CanaryStatus> {
+  let (service, weight) = state.abel.get_canary(name)?;
+  let guard = service.try_upgrade().ok()?;
+  Some(CanaryStatus {
+    uuid: guard.uuid(),
+    weight,
+    metrics: guard.metrics().snapshot(),
+  })
+}
+
+/// Promotes the canary of the service locked by `lock`.
+///
+/// Its source replaces the current one on disk before it does in memory.
+pub async fn promote(state: &ServerState, lock: &DeployGuard) -> Result<Response<Body>> {
+  let name = lock.name();
+  if state.abel.get_canary(name).is_none() {
+    return Err(CanaryNotFound { name: name.into() }.into());
+  }
+
+  let services_path = state.abel_path.join("services");
+  let staging = layout::stage_copy(&services_path, name).await?;
+  let result = async {
+    for (canary, source) in SOURCE_FILES {
+      if staging.join(canary).exists() {
+        for (_, x) in SOURCE_FILES {
+          remove_if_exists(&staging.join(x)).await?;
+        }
+        fs::rename(staging.join(canary), staging.join(source)).await?;
+      }
+    }
+    // The promoted version brings its own redirect map
+    redirect::remove_override(&staging).await
+  };
+  if let Err(error) = result.await {
+    layout::discard(&services_path, name).await?;
+    return Err(error.into());
+  }
+  layout::commit(&services_path, name).await?;
+
+  let (service, replaced, errors) = state.abel.promote_canary(lock).await?;
+  let guard = service.try_upgrade()?;
+  (state.metadata)
+    .modify(name, &mut |m| {
+      m.uuid = guard.uuid();
+      m.started = true;
+      m.remote = None;
+      m.hash = guard.content_hash().map(Into::into);
+      m.deployed_at = guard.deployed_at();
+    })
+    .await?;
+
+  if let Some(replaced) = replaced {
+    info!(
+      "Promoted canary of service '{name}' {}",
+      format!("({} -> {})", replaced.uuid(), guard.uuid()).dimmed(),
+    );
+  }
+  if !errors.is_empty() {
+    warn!("errors: {errors:?}");
+  }
+
+  json_response(StatusCode::OK, ServiceWithStatus {
+    status: ServiceStatus::Running,
+    service: Cow::Borrowed(guard.info()),
+    metrics: guard.metrics().snapshot(),
+    uptime: Some(guard.metrics().uptime().as_secs()),
+    canary: None,
+  })
+}
+
+/// Aborts the canary of the service locked by `lock`.
+pub async fn abort(state: &ServerState, lock: &DeployGuard) -> Result<Response<Body>> {
+  let name = lock.name();
+  let (aborted, errors) = state.abel.abort_canary(lock).await?;
+  remove_files(&state.abel_path.join("services").join(name)).await?;
+
+  info!(
+    "Aborted canary of service '{name}' {}",
+    format!("({})", aborted.uuid()).dimmed(),
+  );
+  if !errors.is_empty() {
+    warn!("errors: {errors:?}");
+  }
+
+  json_response(
+    StatusCode::OK,
+    json!({ "aborted": aborted.info(), "metrics": aborted.metrics().snapshot() }),
+  )
+}
+
+/// Removes stored canary sources of a service.
+///
+/// Canaries are not restored across restarts, so this is also used to clean up
+/// leftovers when loading saved services.
+pub async fn remove_files(service_path: &Path) -> io::Result<()> {
+  for (canary, _) in SOURCE_FILES {
+    remove_if_exists(&service_path.join(canary)).await?;
+  }
+  Ok(())
+}
+
+async fn remove_if_exists(path: &Path) -> io::Result<()> {
+  match fs::remove_file(path).await {
+    Err(error) if error.kind() != io::ErrorKind::NotFound => Err(error),
+    _ => Ok(()),
+  }
+}
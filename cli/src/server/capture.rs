@@ -0,0 +1,214 @@
+use super::{json_response, Result, ServerState};
+use abel_core::ErrorKind::ServiceNotFound;
+use abel_core::MAX_CAPTURE_REQUESTS;
+use bytes::{Bytes, BytesMut};
+use futures::{future, stream, StreamExt};
+use hyper::body::HttpBody;
+use hyper::header::{HeaderName, HeaderValue, AUTHORIZATION, COOKIE, PROXY_AUTHORIZATION};
+use hyper::{Body, HeaderMap, Method, Request, Response, StatusCode, Uri};
+use serde::{Serialize, Serializer};
+use serde_json::json;
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+/// Maximum bytes of request body kept in a capture.
+const MAX_CAPTURED_BODY: usize = 64 * 1024;
+
+/// Headers whose values are replaced before being kept in a capture.
+const REDACTED_HEADERS: [HeaderName; 3] = [AUTHORIZATION, COOKIE, PROXY_AUTHORIZATION];
+
+/// Recently captured requests of services with `capture_requests` enabled.
+#[derive(Default)]
+pub struct Captures {
+  services: Mutex<HashMap<String, VecDeque<CapturedRequest>>>,
+  next_id: AtomicU64,
+}
+
+#[derive(Clone, Serialize)]
+pub struct CapturedRequest {
+  id: u64,
+  time: SystemTime,
+  #[serde(serialize_with = "serialize_method")]
+  method: Method,
+  #[serde(serialize_with = "serialize_uri")]
+  uri: Uri,
+  /// Path inside the service, as seen by the handler.
+  sub_path: String,
+  #[serde(serialize_with = "serialize_headers")]
+  headers: HeaderMap,
+  #[serde(serialize_with = "serialize_body")]
+  body: Bytes,
+  body_truncated: bool,
+}
+
+impl CapturedRequest {
+  /// Rebuilds the request for replaying. Redacted headers are left out
+  /// instead of sending their placeholders.
+  fn to_request(&self) -> Request<Body> {
+    let mut builder = Request::builder()
+      .method(self.method.clone())
+      .uri(self.uri.clone());
+    let headers = builder.headers_mut().unwrap();
+    *headers = self.headers.clone();
+    for name in &REDACTED_HEADERS {
+      headers.remove(name);
+    }
+    builder.body(self.body.clone().into()).unwrap()
+  }
+}
+
+impl Captures {
+  /// Records the request and returns an equivalent one to be passed on.
+  ///
+  /// Only the first [`MAX_CAPTURED_BODY`] bytes of the body are read before
+  /// passing it on; the rest is streamed as usual.
+  pub async fn capture(
+    &self,
+    service_name: &str,
+    limit: usize,
+    sub_path: &str,
+    req: Request<Body>,
+  ) -> Result<Request<Body>> {
+    let (parts, mut body) = req.into_parts();
+    let mut prefix = BytesMut::new();
+    while prefix.len() <= MAX_CAPTURED_BODY {
+      match body.data().await {
+        Some(chunk) => prefix.extend_from_slice(
+          &chunk.map_err(|error| (400, "failed to read request body", error.to_string()))?,
+        ),
+        None => break,
+      }
+    }
+    let prefix = prefix.freeze();
+
+    let mut headers = parts.headers.clone();
+    for name in &REDACTED_HEADERS {
+      if headers.contains_key(name) {
+        headers.insert(name, HeaderValue::from_static("[redacted]"));
+      }
+    }
+
+    let body_truncated = prefix.len() > MAX_CAPTURED_BODY;
+    let captured = CapturedRequest {
+      id: self.next_id.fetch_add(1, Ordering::Relaxed),
+      time: SystemTime::now(),
+      method: parts.method.clone(),
+      uri: parts.uri.clone(),
+      sub_path: sub_path.into(),
+      headers,
+      body: prefix.slice(..prefix.len().min(MAX_CAPTURED_BODY)),
+      body_truncated,
+    };
+
+    let mut services = self.services.lock().unwrap();
+    let captures = services.entry(service_name.into()).or_default();
+    captures.push_back(captured);
+    // Bounded even for services saved before the limit was checked
+    while captures.len() > limit.min(MAX_CAPTURE_REQUESTS) {
+      captures.pop_front();
+    }
+    drop(services);
+
+    let body = stream::once(future::ready(Ok::<_, hyper::Error>(prefix))).chain(body);
+    Ok(Request::from_parts(parts, Body::wrap_stream(body)))
+  }
+
+  pub fn list(&self, service_name: &str) -> Vec<CapturedRequest> {
+    (self.services.lock().unwrap())
+      .get(service_name)
+      .map(|x| x.iter().cloned().collect())
+      .unwrap_or_default()
+  }
+
+  fn get(&self, service_name: &str, id: u64) -> Option<CapturedRequest> {
+    (self.services.lock().unwrap())
+      .get(service_name)
+      .and_then(|x| x.iter().find(|x| x.id == id).cloned())
+  }
+
+  pub fn remove_service(&self, service_name: &str) {
+    self.services.lock().unwrap().remove(service_name);
+  }
+}
+
+pub fn list(state: &ServerState, name: &str) -> Result<Response<Body>> {
+  state.abel.get_service(name)?;
+  json_response(StatusCode::OK, state.captures.list(name))
+}
+
+pub async fn replay(state: &ServerState, name: &str, id: &str) -> Result<Response<Body>> {
+  let service = state.abel.get_running_service(name)?;
+  let id: u64 = id
+    .parse()
+    .map_err(|_| ("invalid capture ID", json!({ "id": id })))?;
+  let captured = state.captures.get(name, id).ok_or_else(|| {
+    (
+      404,
+      "capture not found",
+      json!({ "service": name, "id": id }),
+    )
+  })?;
+  if captured.body_truncated {
+    return Err(From::from((
+      "capture body truncated",
+      json!({ "msg": "cannot replay a request whose body was truncated", "id": id }),
+    )));
+  }
+
+  let resp = (state.abel)
+    .run_service(service, captured.sub_path.clone(), captured.to_request())
+    .await
+    .map_err(|error| match error.kind() {
+      abel_core::ErrorKind::ServiceDropped => ServiceNotFound { name: name.into() }.into(),
+      _ => error,
+    })?;
+  Ok(resp)
+}
+
+fn serialize_method<S: Serializer>(method: &Method, ser: S) -> Result<S::Ok, S::Error> {
+  ser.serialize_str(method.as_str())
+}
+
+fn serialize_uri<S: Serializer>(uri: &Uri, ser: S) -> Result<S::Ok, S::Error> {
+  ser.collect_str(uri)
+}
+
+fn serialize_headers<S: Serializer>(headers: &HeaderMap, ser: S) -> Result<S::Ok, S::Error> {
+  ser.collect_seq(
+    headers
+      .iter()
+      .map(|(k, v)| (k.as_str(), String::from_utf8_lossy(v.as_bytes()))),
+  )
+}
+
+fn serialize_body<S: Serializer>(body: &Bytes, ser: S) -> Result<S::Ok, S::Error> {
+  ser.serialize_str(&String::from_utf8_lossy(body))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[tokio::test]
+  async fn test_redacted() -> Result<()> {
+    let captures = Captures::default();
+    let req = Request::post("/svc/x")
+      .header(AUTHORIZATION, "Bearer secret")
+      .header(COOKIE, "session=secret")
+      .header("x-kept", "1")
+      .body(Body::from("body"))
+      .unwrap();
+    let req = captures.capture("svc", 1, "/x", req).await?;
+    assert_eq!(req.headers()[AUTHORIZATION], "Bearer secret");
+
+    let captured = captures.list("svc").pop().unwrap();
+    assert_eq!(captured.headers[COOKIE], "[redacted]");
+    let replayed = captured.to_request();
+    assert!(!replayed.headers().contains_key(AUTHORIZATION));
+    assert!(!replayed.headers().contains_key(COOKIE));
+    assert_eq!(replayed.headers()["x-kept"], "1");
+    Ok(())
+  }
+}
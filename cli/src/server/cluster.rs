@@ -0,0 +1,287 @@
+use super::types::{ServiceStatus, ServiceWithStatus};
+use super::upload::UploadMode;
+use super::Result;
+use abel_core::net::{remove_hop_by_hop, ClientAddr};
+use abel_core::{constant_time_eq, hmac_sha256};
+use anyhow::bail;
+use data_encoding::HEXLOWER;
+use hyper::header::{HeaderValue, CONTENT_LENGTH, HOST};
+use hyper::{Body, HeaderMap, Request, Response};
+use log::{debug, warn};
+use reqwest::multipart::{Form, Part};
+use reqwest::Client;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::fs::File;
+use uuid::Uuid;
+
+/// Marks requests sent by another node, which must not be forwarded or
+/// replicated again. See [`forwarded_value`].
+pub const FORWARDED_HEADER: &str = "abel-forwarded";
+
+/// Client address of a forwarded request, trusted only in cluster mode along
+/// with an authentic [`FORWARDED_HEADER`].
+pub const CLIENT_IP_HEADER: &str = "abel-client-ip";
+
+const SYNC_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Value of [`FORWARDED_HEADER`] sent by nodes, derived from the shared
+/// authentication token so that clients cannot pass their requests off as
+/// forwarded ones.
+///
+/// Without a token there is nothing to derive it from, but then every client
+/// may manage services anyway. Cluster mode requires a token, so that clients
+/// cannot pass off their addresses as well.
+pub fn forwarded_value(auth_token: Option<Uuid>) -> HeaderValue {
+  let value = match auth_token {
+    Some(token) => HEXLOWER.encode(&hmac_sha256(token.as_bytes(), FORWARDED_HEADER.as_bytes())),
+    None => "1".into(),
+  };
+  let mut value = HeaderValue::try_from(value).unwrap();
+  value.set_sensitive(true);
+  value
+}
+
+/// Static cluster of Abel nodes.
+///
+/// Each node periodically fetches the service lists of its peers, forwards
+/// requests for services it does not host to a peer that does, and replicates
+/// uploaded and removed services to all peers. Nodes in a cluster must share
+/// the same authentication token, which is required if there are peers.
+#[derive(Clone)]
+pub struct Cluster {
+  peers: Arc<[String]>,
+  client: Client,
+  auth: Option<HeaderValue>,
+  forwarded: HeaderValue,
+  /// Running services of peers, mapped to the first peer hosting them.
+  remote_services: Arc<RwLock<HashMap<String, Arc<str>>>>,
+}
+
+impl Cluster {
+  pub fn new(peers: Vec<String>, auth_token: Option<Uuid>) -> anyhow::Result<Self> {
+    if !peers.is_empty() && auth_token.is_none() {
+      bail!("cluster mode requires an authentication token");
+    }
+    let peers = (peers.into_iter())
+      .map(|x| x.trim_end_matches('/').to_owned())
+      .collect();
+    let auth = auth_token.map(|x| {
+      let mut x = HeaderValue::try_from(format!("Abel {x}")).unwrap();
+      x.set_sensitive(true);
+      x
+    });
+    Ok(Self {
+      peers,
+      client: Client::new(),
+      auth,
+      forwarded: forwarded_value(auth_token),
+      remote_services: Default::default(),
+    })
+  }
+
+  pub fn is_enabled(&self) -> bool {
+    !self.peers.is_empty()
+  }
+
+  /// Checks if a request is sent by another node of the cluster.
+  pub fn is_forwarded(&self, headers: &HeaderMap) -> bool {
+    (headers.get(FORWARDED_HEADER))
+      .map(|x| constant_time_eq(x.as_bytes(), self.forwarded.as_bytes()))
+      .unwrap_or(false)
+  }
+
+  /// Client address of a request forwarded by a peer.
+  ///
+  /// Only trusted in cluster mode with an authentication token, as the value
+  /// of [`FORWARDED_HEADER`] is otherwise known to every client.
+  pub fn forwarded_client_ip(&self, headers: &HeaderMap) -> Option<IpAddr> {
+    if !self.is_enabled() || self.auth.is_none() || !self.is_forwarded(headers) {
+      return None;
+    }
+    headers.get(CLIENT_IP_HEADER)?.to_str().ok()?.parse().ok()
+  }
+
+  fn request(&self, method: reqwest::Method, url: String) -> reqwest::RequestBuilder {
+    let mut builder = (self.client)
+      .request(method, url)
+      .header(FORWARDED_HEADER, self.forwarded.clone());
+    if let Some(auth) = &self.auth {
+      builder = builder.header("authorization", auth.clone());
+    }
+    builder
+  }
+
+  /// Keeps the service lists of peers up to date. Never returns.
+  pub async fn sync(self) {
+    let mut interval = tokio::time::interval(SYNC_INTERVAL);
+    loop {
+      interval.tick().await;
+      let mut remote_services = HashMap::new();
+      for peer in self.peers.iter() {
+        match self.fetch_services(peer).await {
+          Ok(services) => {
+            let peer: Arc<str> = peer.as_str().into();
+            for name in services {
+              remote_services.entry(name).or_insert_with(|| peer.clone());
+            }
+          }
+          Err(error) => debug!("failed to fetch services from peer {peer}: {error}"),
+        }
+      }
+      *self.remote_services.write().unwrap() = remote_services;
+    }
+  }
+
+  async fn fetch_services(&self, peer: &str) -> reqwest::Result<Vec<String>> {
+    let url = format!("{peer}/services");
+    let resp = self.request(reqwest::Method::GET, url).send().await?;
+    let services: Vec<ServiceWithStatus> = resp.error_for_status()?.json().await?;
+    let names = (services.into_iter())
+      .filter(|x| matches!(x.status, ServiceStatus::Running))
+      .map(|x| x.service.name().to_owned())
+      .collect();
+    Ok(names)
+  }
+
+  /// Finds a peer running the service, if this request is eligible for
+  /// forwarding.
+  pub fn peer_for(&self, name: &str, req: &Request<Body>) -> Option<Arc<str>> {
+    if self.is_forwarded(req.headers()) {
+      return None;
+    }
+    self.remote_services.read().unwrap().get(name).cloned()
+  }
+
+  /// Proxies the request to a peer, returning the peer's response. Headers
+  /// meaningful only to a single connection are dropped both ways.
+  pub async fn forward(&self, peer: &str, req: Request<Body>) -> Result<Response<Body>> {
+    let (mut parts, body) = req.into_parts();
+    let path_and_query = (parts.uri.path_and_query())
+      .map(|x| x.as_str())
+      .unwrap_or("/");
+    remove_hop_by_hop(&mut parts.headers);
+    parts.headers.remove(HOST);
+    parts.headers.remove(FORWARDED_HEADER);
+    parts.headers.remove(CLIENT_IP_HEADER);
+
+    let mut req = (self.client)
+      .request(parts.method, format!("{peer}{path_and_query}"))
+      .headers(parts.headers)
+      .header(FORWARDED_HEADER, self.forwarded.clone());
+    if let Some(addr) = parts.extensions.get::<ClientAddr>() {
+      req = req.header(CLIENT_IP_HEADER, addr.real_ip.to_string());
+    }
+    let resp = (req.body(reqwest::Body::wrap_stream(body)).send().await)
+      .map_err(|error| (502, "failed to forward request", error.to_string()))?;
+
+    let mut builder = Response::builder().status(resp.status());
+    let headers = builder.headers_mut().unwrap();
+    *headers = resp.headers().clone();
+    remove_hop_by_hop(headers);
+    // The body may have been decoded, changing its length
+    if resp.content_length().is_none() {
+      headers.remove(CONTENT_LENGTH);
+    }
+    Ok(
+      builder
+        .body(Body::wrap_stream(resp.bytes_stream()))
+        .unwrap(),
+    )
+  }
+
+  /// Uploads the stored source of a service to all peers in the background.
+  ///
+  /// Sources in object storage, given by `remote`, are replicated by their
+  /// URLs.
+  pub fn replicate_upload(
+    &self,
+    name: &str,
+    mode: UploadMode,
+    service_path: &Path,
+    remote: Option<String>,
+  ) {
+    for peer in self.peers.iter() {
+      let this = self.clone();
+      let url = format!("{peer}/services/{name}?mode={mode}&force=true");
+      let service_path = service_path.to_owned();
+      let remote = remote.clone();
+      tokio::spawn(async move {
+        let result = async {
+          let form = if let Some(remote) = remote {
+            Form::new().text("remote", remote)
+          } else {
+            let (kind, source_path) = match service_path.join("source.asar") {
+              x if x.exists() => ("multi", x),
+              _ => ("single", service_path.join("source.lua")),
+            };
+            let file = File::open(&source_path).await?;
+            let len = file.metadata().await?.len();
+            Form::new().part(kind, Part::stream_with_length(file, len))
+          };
+          (this.request(reqwest::Method::PUT, url.clone()))
+            .multipart(form)
+            .send()
+            .await?
+            .error_for_status()?;
+          anyhow::Ok(())
+        };
+        if let Err(error) = result.await {
+          warn!("failed to replicate service to {url}: {error}");
+        }
+      });
+    }
+  }
+
+  /// Removes a service from all peers in the background.
+  pub fn replicate_remove(&self, name: &str) {
+    for peer in self.peers.iter() {
+      let request = self.request(reqwest::Method::DELETE, format!("{peer}/services/{name}"));
+      let peer = peer.clone();
+      let name = name.to_owned();
+      tokio::spawn(async move {
+        let result = async { request.send().await?.error_for_status() };
+        if let Err(error) = result.await {
+          warn!("failed to remove service '{name}' from {peer}: {error}");
+        }
+      });
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn headers(forwarded: HeaderValue) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    headers.insert(FORWARDED_HEADER, forwarded);
+    headers.insert(CLIENT_IP_HEADER, HeaderValue::from_static("10.0.0.1"));
+    headers
+  }
+
+  #[test]
+  fn test_forwarded_client_ip() {
+    let token = Uuid::new_v4();
+    let peers = vec!["http://peer".to_owned()];
+    let cluster = Cluster::new(peers.clone(), Some(token)).unwrap();
+    let authentic = headers(forwarded_value(Some(token)));
+    assert_eq!(
+      cluster.forwarded_client_ip(&authentic),
+      "10.0.0.1".parse().ok()
+    );
+
+    // Spoofed by clients
+    let spoofed = headers(HeaderValue::from_static("1"));
+    assert_eq!(cluster.forwarded_client_ip(&spoofed), None);
+    let standalone = Cluster::new(Vec::new(), None).unwrap();
+    assert_eq!(standalone.forwarded_client_ip(&spoofed), None);
+    let standalone = Cluster::new(Vec::new(), Some(token)).unwrap();
+    assert_eq!(standalone.forwarded_client_ip(&authentic), None);
+
+    assert!(Cluster::new(peers, None).is_err());
+  }
+}
@@ -1,9 +1,19 @@
+use super::backup::BackupConfig;
+use super::encryption::StorageKeySource;
+use super::hardening::HardeningConfig;
+use super::hooks::Hook;
+use super::logging::LogTarget;
+use super::reporting::ReportingConfig;
+use abel_core::net::Cidr;
+use abel_core::{HttpClientOptions, S3Credentials};
 use clap::Parser;
 use once_cell::sync::Lazy;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::io;
 use std::net::SocketAddr;
 use std::path::{Path, PathBuf};
+use std::time::Duration;
 use tokio::fs;
 use uuid::Uuid;
 
@@ -40,6 +50,33 @@ pub struct ConfigArgs {
   /// Abel executor pool size [overrides config]
   #[clap(long)]
   pub pool_size: Option<usize>,
+
+  /// Maximum number of loaded services [overrides config]
+  #[clap(long)]
+  pub max_services: Option<usize>,
+
+  /// Maximum number of running services [overrides config]
+  #[clap(long)]
+  pub max_running_services: Option<usize>,
+
+  /// Base URL of a peer node; may be specified multiple times [overrides
+  /// config]
+  #[clap(long = "peer")]
+  pub peers: Vec<String>,
+
+  /// Bind with SO_REUSEPORT for zero-downtime upgrades [overrides config]
+  #[clap(long)]
+  pub reuse_port: bool,
+
+  /// Evaluate saved services on first request instead of at startup
+  /// [overrides config]
+  #[clap(long)]
+  pub lazy_load: bool,
+
+  /// Trusted proxy address range in CIDR notation; may be specified multiple
+  /// times [overrides config]
+  #[clap(long = "trusted-proxy")]
+  pub trusted_proxies: Vec<Cidr>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -47,6 +84,198 @@ pub struct Config {
   pub listen: SocketAddr,
   pub auth_token: Option<Uuid>,
   pub(crate) pool_size: Option<usize>,
+  pub max_services: Option<usize>,
+  pub max_running_services: Option<usize>,
+  /// Allows a new server process to listen on the same address while this one
+  /// is still draining.
+  #[serde(default)]
+  pub reuse_port: bool,
+  /// Base URLs of other nodes in the cluster. Clustering is disabled if empty,
+  /// and requires `auth_token` otherwise.
+  #[serde(default, skip_serializing_if = "Vec::is_empty")]
+  pub peers: Vec<String>,
+  /// Base URL of the node coordinating `abel.lock` and `abel.ratelimit` across
+  /// the cluster, or a `redis://` URL to share them through Redis. Each node
+  /// coordinates its own workers if not set, including the coordinating node
+  /// itself.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub coordinator: Option<String>,
+  /// Restores saved services without evaluating them. Services that were
+  /// running are started on their first request.
+  #[serde(default)]
+  pub lazy_load: bool,
+  /// Rejects updates, start/stop and removal of existing services without an
+  /// `If-Match` header, so that concurrent changes are not overwritten.
+  /// Enabled by default.
+  #[serde(default = "default_require_if_match")]
+  pub require_if_match: bool,
+  /// Where logs are written in addition to the console, e.g. syslog or
+  /// files.
+  #[serde(default, skip_serializing_if = "Vec::is_empty")]
+  pub logging: Vec<LogTarget>,
+  /// Webhooks notified of lifecycle events, in addition to those registered
+  /// through the API.
+  #[serde(default, skip_serializing_if = "Vec::is_empty")]
+  pub webhooks: Vec<Hook>,
+  /// Reporting of services' Lua runtime errors. Services may set their own
+  /// DSN even if not set, reachable only with their `net` permission.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub error_reporting: Option<ReportingConfig>,
+  /// Periodic backups of services' local storage. Disabled if not set.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub backup: Option<BackupConfig>,
+  /// Credentials signing requests for services' sources kept in object
+  /// storage. Requests are sent unsigned if not set.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub object_storage: Option<S3Credentials>,
+  /// Proxies whose `Forwarded` and `X-Forwarded-For` headers are trusted for
+  /// finding client addresses.
+  #[serde(default, skip_serializing_if = "Vec::is_empty")]
+  pub trusted_proxies: Vec<Cidr>,
+  /// Maximum number of service responses cached. Caching is disabled if not
+  /// set.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub response_cache: Option<usize>,
+  #[serde(default, skip_serializing_if = "HttpConfig::is_default")]
+  pub http: HttpConfig,
+  /// Headers added to every response unless already set, e.g.
+  /// `Strict-Transport-Security`. Services' own `headers` take precedence.
+  #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+  pub headers: BTreeMap<String, String>,
+  /// Landlock and seccomp restrictions on the server process, applied at
+  /// startup on Linux. Disabled if not set.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub hardening: Option<HardeningConfig>,
+  /// Programs services may run with the `exec` module, as absolute paths.
+  /// Services only get those they also request, and the module is
+  /// unavailable if empty.
+  #[serde(default, skip_serializing_if = "Vec::is_empty")]
+  pub exec_allowlist: Vec<String>,
+  /// Master key encrypting services' local storage at rest. Storage is not
+  /// encrypted if not set.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub storage_key: Option<StorageKeySource>,
+  #[serde(default, skip_serializing_if = "UploadLimits::is_default")]
+  pub upload: UploadLimits,
+  #[serde(default, skip_serializing_if = "HttpClientConfig::is_default")]
+  pub http_client: HttpClientConfig,
+}
+
+fn default_require_if_match() -> bool {
+  true
+}
+
+/// HTTP protocol tuning. Unset options keep hyper's defaults.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HttpConfig {
+  /// Serves HTTP/2 alongside HTTP/1, enabled by default. Over cleartext,
+  /// clients need prior knowledge to use it. Set to `false` to serve HTTP/1
+  /// only.
+  pub http2: bool,
+  pub http2_max_concurrent_streams: Option<u32>,
+  /// Seconds between HTTP/2 keep-alive pings. Pings are disabled if not set.
+  pub http2_keep_alive_interval: Option<u64>,
+  /// Seconds to wait for a ping to be acknowledged before closing the
+  /// connection.
+  pub http2_keep_alive_timeout: Option<u64>,
+  pub http1_keep_alive: bool,
+  /// Seconds to wait for request headers of HTTP/1 connections.
+  pub http1_header_read_timeout: Option<u64>,
+  /// Maximum size of HTTP/1 request headers in bytes, at least 8192.
+  pub max_header_size: Option<usize>,
+  /// Maximum size of request bodies passed to services in bytes. Unlimited if
+  /// not set.
+  pub max_request_body: Option<u64>,
+  /// Seconds of idleness before sending TCP keep-alive probes. Disabled if not
+  /// set.
+  pub tcp_keep_alive: Option<u64>,
+}
+
+impl Default for HttpConfig {
+  fn default() -> Self {
+    Self {
+      http2: true,
+      http2_max_concurrent_streams: None,
+      http2_keep_alive_interval: None,
+      http2_keep_alive_timeout: None,
+      http1_keep_alive: true,
+      http1_header_read_timeout: None,
+      max_header_size: None,
+      max_request_body: None,
+      tcp_keep_alive: None,
+    }
+  }
+}
+
+impl HttpConfig {
+  fn is_default(&self) -> bool {
+    *self == Self::default()
+  }
+}
+
+/// Connection pooling of services' outbound HTTP requests. Unset options keep
+/// the defaults.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HttpClientConfig {
+  /// Maximum number of idle connections kept for each host.
+  pub pool_max_idle_per_host: Option<usize>,
+  /// Seconds to keep idle connections, defaulting to 90.
+  pub pool_idle_timeout: Option<u64>,
+  /// Seconds to wait for connections to be established, defaulting to 10.
+  pub connect_timeout: Option<u64>,
+}
+
+impl HttpClientConfig {
+  fn is_default(&self) -> bool {
+    *self == Self::default()
+  }
+
+  pub fn options(&self) -> HttpClientOptions {
+    let mut options = HttpClientOptions::default();
+    if let Some(x) = self.pool_max_idle_per_host {
+      options.pool_max_idle_per_host = x;
+    }
+    if let Some(x) = self.pool_idle_timeout {
+      options.pool_idle_timeout = Some(Duration::from_secs(x));
+    }
+    if let Some(x) = self.connect_timeout {
+      options.connect_timeout = Duration::from_secs(x);
+    }
+    options
+  }
+}
+
+/// Limits on uploaded sources. Sizes are in bytes.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct UploadLimits {
+  /// Maximum size of single-file sources.
+  pub max_single_size: u64,
+  /// Maximum size of multi-file source archives and delta uploads.
+  pub max_archive_size: u64,
+  /// Maximum size of each file in multi-file sources.
+  pub max_file_size: u64,
+  /// Maximum number of files in multi-file sources.
+  pub max_files: usize,
+}
+
+impl Default for UploadLimits {
+  fn default() -> Self {
+    Self {
+      max_single_size: 1024u64.pow(2) * 5,
+      max_archive_size: 1024u64.pow(2) * 100,
+      max_file_size: 1024u64.pow(2) * 20,
+      max_files: 10000,
+    }
+  }
+}
+
+impl UploadLimits {
+  fn is_default(&self) -> bool {
+    *self == Self::default()
+  }
 }
 
 impl Default for Config {
@@ -55,6 +284,27 @@ impl Default for Config {
       listen: ([127, 0, 0, 1], 3000).into(),
       auth_token: Some(Uuid::new_v4()),
       pool_size: None,
+      max_services: None,
+      max_running_services: None,
+      reuse_port: false,
+      peers: Vec::new(),
+      lazy_load: false,
+      require_if_match: true,
+      logging: Vec::new(),
+      webhooks: Vec::new(),
+      error_reporting: None,
+      backup: None,
+      object_storage: None,
+      trusted_proxies: Vec::new(),
+      response_cache: None,
+      http: Default::default(),
+      headers: BTreeMap::new(),
+      hardening: None,
+      exec_allowlist: Vec::new(),
+      storage_key: None,
+      upload: Default::default(),
+      http_client: Default::default(),
+      coordinator: None,
     }
   }
 }
@@ -84,6 +334,18 @@ impl Config {
     args.listen.map(|x| self.listen = x);
     args.auth_token.map(|x| self.auth_token = Some(x));
     args.pool_size.map(|x| self.pool_size = Some(x));
+    args.max_services.map(|x| self.max_services = Some(x));
+    args
+      .max_running_services
+      .map(|x| self.max_running_services = Some(x));
+    self.reuse_port |= args.reuse_port;
+    self.lazy_load |= args.lazy_load;
+    if !args.peers.is_empty() {
+      self.peers = args.peers;
+    }
+    if !args.trusted_proxies.is_empty() {
+      self.trusted_proxies = args.trusted_proxies;
+    }
     self
   }
 
@@ -0,0 +1,270 @@
+//! Delta uploads, updating a few files of a service without re-uploading its
+//! whole source.
+
+use super::etag::IfMatch;
+use super::upload::{check_size, log_result, response, upload_local, UploadMode, UploadResponse};
+use super::{Result, ServerState};
+use crate::SourceKind;
+use abel_core::{normalize_path_str, DeployGuard};
+use bytes::{Bytes, BytesMut};
+use futures::future::ready;
+use futures::{stream, Future, TryStreamExt};
+use hive_asar::{pack_dir_into_stream, Archive};
+use hyper::{Body, HeaderMap, Request, Response};
+use log::warn;
+use multer::{Constraints, Multipart, SizeLimit};
+use serde::Deserialize;
+use serde_json::json;
+use std::path::{Path, PathBuf};
+use tokio::fs::{self, File};
+use tokio::io;
+use tokio_util::io::StreamReader;
+use uuid::Uuid;
+
+#[derive(Deserialize)]
+struct PatchQuery {
+  #[serde(default = "default_mode")]
+  mode: UploadMode,
+  #[serde(default)]
+  force: bool,
+}
+
+fn default_mode() -> UploadMode {
+  UploadMode::Cold
+}
+
+/// Applies changed and deleted files to a service's stored source, then
+/// updates the service with it.
+///
+/// Each `file` field carries a changed file, with its path in the source as
+/// its file name. Each `delete` field contains the path of a file to delete.
+pub async fn patch_files(
+  state: &ServerState,
+  name: String,
+  if_match: IfMatch,
+  req: Request<Body>,
+) -> Result<Response<Body>> {
+  let (parts, body) = req.into_parts();
+  let PatchQuery { mode, force } = serde_qs::from_str(parts.uri.query().unwrap_or(""))?;
+  if !matches!(mode, UploadMode::Hot | UploadMode::Cold) {
+    return Err(From::from((
+      "invalid mode",
+      json!({ "msg": "delta uploads only support `hot` and `cold` modes", "mode": mode }),
+    )));
+  }
+
+  let lock = state.abel.lock_deploy(&name, false).await?;
+  if_match.check(state, &name).await?;
+  let asar_path = match stored_source(state, &name).await? {
+    (SourceKind::Multi, path) => path,
+    (SourceKind::Single, _) => {
+      return Err(From::from((
+        "delta upload not supported",
+        json!({ "msg": "upload the whole file of single-file sources", "name": name }),
+      )))
+    }
+  };
+
+  let content_type = (parts.headers.get("content-type"))
+    .ok_or("no Content-Type given")?
+    .to_str()
+    .or(Err("Content-Type is not valid UTF-8"))?;
+  let limits = &state.upload_limits;
+  let constraints = Constraints::new()
+    .allowed_fields(vec!["file", "delete"])
+    .size_limit(
+      SizeLimit::new()
+        .whole_stream(limits.max_archive_size)
+        .for_field("file", limits.max_file_size)
+        .for_field("delete", 4096),
+    );
+  let multipart =
+    Multipart::with_constraints(body, multer::parse_boundary(content_type)?, constraints);
+
+  let resp = repack(state, &lock, &asar_path, mode, force, |dir| async move {
+    apply_changes(&dir, multipart, limits.max_files).await
+  })
+  .await?;
+  finish(state, &name, mode, &parts.headers, resp).await
+}
+
+/// Replaces one file of a service's stored source, then hot-updates the
+/// service with it.
+pub async fn put_file(
+  state: &ServerState,
+  name: String,
+  path: &str,
+  if_match: IfMatch,
+  req: Request<Body>,
+) -> Result<Response<Body>> {
+  const MODE: UploadMode = UploadMode::Hot;
+  let (parts, body) = req.into_parts();
+  let lock = state.abel.lock_deploy(&name, false).await?;
+  if_match.check(state, &name).await?;
+  let (kind, stored_path) = stored_source(state, &name).await?;
+  let file_path = check_path(path)?;
+  let limit = match kind {
+    SourceKind::Single => state.upload_limits.max_single_size,
+    SourceKind::Multi => state.upload_limits.max_file_size,
+  };
+  let content = read_body(body, limit).await?;
+
+  let resp = match kind {
+    SourceKind::Single if file_path != Path::new("main.lua") => {
+      return Err(From::from((
+        "invalid file path",
+        json!({ "msg": "single-file sources only contain `main.lua`", "path": path }),
+      )))
+    }
+    SourceKind::Single => {
+      let stream = stream::once(ready(Ok(content)));
+      upload_local(state, &lock, MODE, false, kind, stream).await?
+    }
+    SourceKind::Multi => {
+      repack(state, &lock, &stored_path, MODE, false, |dir| async move {
+        write_file(&dir.join(file_path), content).await
+      })
+      .await?
+    }
+  };
+  finish(state, &name, MODE, &parts.headers, resp).await
+}
+
+/// Finds the locally stored source of a service.
+async fn stored_source(state: &ServerState, name: &str) -> Result<(SourceKind, PathBuf)> {
+  state.abel.get_service(name)?;
+  let service_path = state.abel_path.join("services").join(name);
+  let metadata = state.metadata.get(name).await?;
+  let asar_path = service_path.join("source.asar");
+  let lua_path = service_path.join("source.lua");
+  if metadata.remote.is_none() && asar_path.exists() {
+    Ok((SourceKind::Multi, asar_path))
+  } else if metadata.remote.is_none() && lua_path.exists() {
+    Ok((SourceKind::Single, lua_path))
+  } else {
+    Err(From::from((
+      "source not stored locally",
+      json!({ "msg": "sources in object storage cannot be edited", "name": name }),
+    )))
+  }
+}
+
+/// Extracts a stored archive, lets `f` change its files and uploads it again.
+async fn repack<'a, F, Fut>(
+  state: &'a ServerState,
+  lock: &DeployGuard,
+  asar_path: &Path,
+  mode: UploadMode,
+  force: bool,
+  f: F,
+) -> Result<UploadResponse<'a>>
+where
+  F: FnOnce(PathBuf) -> Fut,
+  Fut: Future<Output = Result<()>>,
+{
+  let temp_dir = state.abel_path.join(format!("tmp/{}", Uuid::new_v4()));
+  let result = async {
+    Archive::new_from_file(asar_path)
+      .await?
+      .extract(&temp_dir)
+      .await?;
+    f(temp_dir.clone()).await?;
+    let stream = pack_dir_into_stream(&temp_dir).await?;
+    upload_local(state, lock, mode, force, SourceKind::Multi, stream).await
+  }
+  .await;
+  if let Err(error) = fs::remove_dir_all(&temp_dir).await {
+    warn!("failed to remove '{}': {error}", temp_dir.display());
+  }
+  result
+}
+
+async fn finish(
+  state: &ServerState,
+  name: &str,
+  mode: UploadMode,
+  headers: &HeaderMap,
+  resp: UploadResponse<'_>,
+) -> Result<Response<Body>> {
+  log_result(&resp);
+  if !state.cluster.is_forwarded(headers) {
+    let service_path = state.abel_path.join("services").join(name);
+    let cluster = &state.cluster;
+    cluster.replicate_upload(name, mode, &service_path, None);
+  }
+  response(resp).await
+}
+
+async fn read_body(mut body: Body, limit: u64) -> Result<Bytes> {
+  let mut content = BytesMut::new();
+  while let Some(chunk) = body
+    .try_next()
+    .await
+    .map_err(|error| (400, "failed to read request body", error.to_string()))?
+  {
+    content.extend(chunk);
+    check_size("file", content.len() as _, limit)?;
+  }
+  Ok(content.freeze())
+}
+
+async fn apply_changes(dir: &Path, mut multipart: Multipart<'_>, max_files: usize) -> Result<()> {
+  let mut count = 0;
+  while let Some(field) = multipart.next_field().await? {
+    count += 1;
+    if count > max_files {
+      return Err(From::from((
+        413,
+        "payload too large",
+        json!({ "msg": "too many files in delta upload", "limit": max_files }),
+      )));
+    }
+    if field.name() == Some("delete") {
+      let path = field.text().await?;
+      let full_path = dir.join(check_path(path.trim())?);
+      match fs::remove_file(&full_path).await {
+        Err(error) if error.kind() == io::ErrorKind::NotFound => {
+          return Err(From::from((
+            "file not found",
+            json!({ "msg": "cannot delete missing file", "path": path.trim() }),
+          )))
+        }
+        result => result?,
+      }
+    } else {
+      let path = field.file_name().map(str::to_owned).ok_or((
+        "no file name given",
+        "specify the file's path as the file name of `file` fields",
+      ))?;
+      let full_path = dir.join(check_path(&path)?);
+      if let Some(parent) = full_path.parent() {
+        fs::create_dir_all(parent).await?;
+      }
+      let mut reader =
+        StreamReader::new(field.map_err(|e| io::Error::new(io::ErrorKind::Other, e)));
+      io::copy(&mut reader, &mut File::create(full_path).await?).await?;
+    }
+  }
+  Ok(())
+}
+
+async fn write_file(path: &Path, content: impl AsRef<[u8]>) -> Result<()> {
+  if let Some(parent) = path.parent() {
+    fs::create_dir_all(parent).await?;
+  }
+  fs::write(path, content).await?;
+  Ok(())
+}
+
+/// Checks that `path` stays inside the source, normalizing it.
+fn check_path(path: &str) -> Result<PathBuf> {
+  let normalized = normalize_path_str(path);
+  let valid = !normalized.is_empty()
+    && !path.starts_with(['/', '\\'])
+    && !path.split(['/', '\\']).any(|x| x == "..");
+  if valid {
+    Ok(normalized.into())
+  } else {
+    Err(From::from(("invalid file path", json!({ "path": path }))))
+  }
+}
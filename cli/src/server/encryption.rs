@@ -0,0 +1,39 @@
+use anyhow::{anyhow, bail, Context};
+use data_encoding::BASE64;
+use serde::{Deserialize, Serialize};
+use tokio::process::Command;
+
+/// Where the master key encrypting services' local storage comes from.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StorageKeySource {
+  /// Base64-encoded 32-byte key.
+  Key(String),
+  /// Program and arguments printing the Base64-encoded key, e.g. a KMS client
+  /// decrypting it. Run without a shell.
+  Command(Vec<String>),
+}
+
+impl StorageKeySource {
+  pub async fn load(&self) -> anyhow::Result<[u8; 32]> {
+    let encoded = match self {
+      Self::Key(key) => key.clone(),
+      Self::Command(command) => {
+        let (program, args) = (command.split_first()).context("empty storage key command")?;
+        let output = Command::new(program).args(args).output().await?;
+        if !output.status.success() {
+          bail!(
+            "storage key command failed with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim(),
+          );
+        }
+        String::from_utf8(output.stdout)?
+      }
+    };
+    let key = (BASE64.decode(encoded.trim().as_bytes())).context("invalid storage key")?;
+    key
+      .try_into()
+      .map_err(|_| anyhow!("storage key must be 32 bytes"))
+  }
+}
@@ -179,7 +179,8 @@ pub enum ErrorKind {
   Unauthorized,
 
   // Errors when reading multipart body are *mostly* client-side, so they all
-  // currently use 400 Bad Request for simplicity.
+  // currently use 400 Bad Request for simplicity, except for exceeding size
+  // limits.
   //
   // This may change in the future if `multer::Error` proved not suitable to
   // be exposed to untrusted client.
@@ -241,6 +242,9 @@ impl ErrorKind {
     match self {
       ErrorKind::Abel(error) => error.kind().status(),
       ErrorKind::Custom { status, .. } => *status,
+      ErrorKind::Multipart(
+        multer::Error::FieldSizeExceeded { .. } | multer::Error::StreamSizeExceeded { .. },
+      ) => StatusCode::PAYLOAD_TOO_LARGE,
       _ => self.get_str("status").unwrap().parse().unwrap(),
     }
   }
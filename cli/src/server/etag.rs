@@ -0,0 +1,113 @@
+use super::{Result, ServerState};
+use hyper::header::IF_MATCH;
+use hyper::HeaderMap;
+use serde_json::json;
+
+/// Identifies the deployed source of a service and the revision of its
+/// metadata, changing whenever either does.
+pub async fn etag(state: &ServerState, name: &str) -> Result<String> {
+  let version = {
+    let service = state.abel.get_service(name)?;
+    let guard = service.upgrade();
+    (guard.content_hash().map(String::from)).unwrap_or_else(|| guard.uuid().to_simple().to_string())
+  };
+  let revision = state.metadata.get(name).await?.revision;
+  Ok(format!("\"{version}-{revision}\""))
+}
+
+/// `If-Match` precondition of a request modifying a service, so that changes
+/// made since the client last read it are not overwritten.
+///
+/// It is read from the request up front, and checked once the service is
+/// locked for deploying, so that it still holds when the change is made.
+#[derive(Debug, Clone)]
+pub struct IfMatch {
+  value: Option<String>,
+  /// Requests replicated from other nodes are not checked, as revisions
+  /// differ between nodes.
+  forwarded: bool,
+}
+
+impl IfMatch {
+  pub fn of(state: &ServerState, headers: &HeaderMap) -> Result<Self> {
+    let value = match headers.get(IF_MATCH) {
+      Some(x) => Some(x.to_str().map_err(|_| "invalid If-Match header")?.into()),
+      None => None,
+    };
+    Ok(Self {
+      value,
+      forwarded: state.cluster.is_forwarded(headers),
+    })
+  }
+
+  /// Checks the precondition against the service's current ETag. Call this
+  /// with the service's deploy lock held.
+  pub async fn check(&self, state: &ServerState, name: &str) -> Result<()> {
+    if self.forwarded {
+      return Ok(());
+    }
+    let etag = match state.abel.get_service(name) {
+      Ok(_) => Some(etag(state, name).await?),
+      Err(_) => None,
+    };
+    match evaluate(
+      self.value.as_deref(),
+      etag.as_deref(),
+      state.require_if_match,
+    ) {
+      Precondition::Met => Ok(()),
+      Precondition::Required => Err(From::from((
+        428,
+        "precondition required",
+        json!({ "msg": "If-Match header is required", "service": name }),
+      ))),
+      Precondition::Failed => Err(From::from((
+        412,
+        "precondition failed",
+        json!({ "msg": "service was modified", "service": name }),
+      ))),
+    }
+  }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Precondition {
+  Met,
+  Required,
+  Failed,
+}
+
+/// Evaluates `If-Match` against the ETag of the service, or `None` if it does
+/// not exist.
+fn evaluate(if_match: Option<&str>, etag: Option<&str>, require: bool) -> Precondition {
+  match (if_match, etag) {
+    (None, Some(_)) if require => Precondition::Required,
+    (None, _) => Precondition::Met,
+    (Some(_), None) => Precondition::Failed,
+    (Some(x), Some(_)) if x.trim() == "*" => Precondition::Met,
+    (Some(x), Some(etag)) if x.split(',').any(|x| x.trim() == etag) => Precondition::Met,
+    (Some(_), Some(_)) => Precondition::Failed,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::Precondition::*;
+  use super::*;
+  use test_case::test_case;
+
+  const ETAG: &str = "\"abc-1\"";
+
+  #[test_case(None, Some(ETAG), false => Met; "not required")]
+  #[test_case(None, Some(ETAG), true => Required; "required")]
+  #[test_case(None, None, true => Met; "new service")]
+  #[test_case(Some("*"), Some(ETAG), true => Met; "any")]
+  #[test_case(Some("*"), None, true => Failed; "any new service")]
+  #[test_case(Some(ETAG), Some(ETAG), true => Met; "matched")]
+  #[test_case(Some("\"x-0\", \"abc-1\""), Some(ETAG), true => Met; "matched in list")]
+  #[test_case(Some("\"abc-0\""), Some(ETAG), true => Failed; "modified")]
+  #[test_case(Some(ETAG), None, false => Failed; "removed")]
+  fn test_evaluate(if_match: Option<&str>, etag: Option<&str>, require: bool) -> Precondition {
+    evaluate(if_match, etag, require)
+  }
+}
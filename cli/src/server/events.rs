@@ -0,0 +1,73 @@
+use super::{Result, ServerState};
+use abel_core::event::Event;
+use bytes::Bytes;
+use futures::stream;
+use hyper::{Body, Response};
+use serde::Deserialize;
+use std::convert::Infallible;
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::broadcast::Receiver;
+
+/// Interval of comments sent to keep idle connections open.
+const KEEP_ALIVE: Duration = Duration::from_secs(15);
+
+#[derive(Deserialize)]
+struct Filter {
+  /// Only sends events about this service.
+  service: Option<String>,
+  /// Also sends log lines of services.
+  #[serde(default)]
+  logs: bool,
+}
+
+impl Filter {
+  fn matches(&self, event: &Event) -> bool {
+    (self.logs || !event.kind.is_log())
+      && (self.service.as_deref()).map_or(true, |s| event.kind.service() == Some(s))
+  }
+}
+
+/// Streams events as server-sent events, until the client disconnects.
+///
+/// Query parameters: `service` to filter by service, `logs=true` to include
+/// log lines.
+pub fn stream(state: &ServerState, query: &str) -> Result<Response<Body>> {
+  let filter: Filter = serde_qs::from_str(query)?;
+  let events = (state.abel.subscribe(), state.abel.subscribe_activity());
+  let stream = stream::unfold((events, filter), |(mut events, filter)| async move {
+    let chunk = next_chunk(&mut events, &filter).await?;
+    Some((Ok::<_, Infallible>(chunk), (events, filter)))
+  });
+  let resp = Response::builder()
+    .header("content-type", "text/event-stream")
+    .header("cache-control", "no-store")
+    .body(Body::wrap_stream(stream))
+    .unwrap();
+  Ok(resp)
+}
+
+/// Takes from both lifecycle and activity events.
+async fn next_chunk(
+  (lifecycle, activity): &mut (Receiver<Event>, Receiver<Event>),
+  filter: &Filter,
+) -> Option<Bytes> {
+  loop {
+    let recv = async {
+      tokio::select! {
+        x = lifecycle.recv() => x,
+        x = activity.recv() => x,
+      }
+    };
+    let chunk = match tokio::time::timeout(KEEP_ALIVE, recv).await {
+      Err(_) => ": keep-alive\n\n".to_owned(),
+      Ok(Ok(event)) if filter.matches(&event) => {
+        format!("data: {}\n\n", serde_json::to_string(&event).unwrap())
+      }
+      Ok(Ok(_)) => continue,
+      Ok(Err(RecvError::Lagged(n))) => format!(": {n} events skipped\n\n"),
+      Ok(Err(RecvError::Closed)) => return None,
+    };
+    return Some(chunk.into());
+  }
+}
@@ -1,30 +1,49 @@
+use super::audit::{audited, Actor};
 use super::error::ErrorKind::Unauthorized;
-use super::error::{method_not_allowed, ErrorAuthWrapper};
+use super::error::{method_not_allowed, Error, ErrorAuthWrapper};
+use super::etag::IfMatch;
 use super::types::{OwnedServiceWithStatus, ServiceWithStatus};
 use super::upload::upload;
-use super::{authenticate, json_response, Metadata, Result, ServerState};
+use super::{
+  approval, audit, authenticate, backup, bench, browse, canary, capture, coordination, delta, etag,
+  events, hooks, jobs, json_response, layout, maintenance, redirect, suspend, ui, Result,
+  ServerState,
+};
 use crate::server::types::ServiceStatus::{Running, Stopped};
+use abel_core::net::ClientAddr;
+use abel_core::source::Source;
 use abel_core::ErrorKind::{ServiceDropped, ServiceNotFound};
+use abel_core::{add_default_headers, constant_time_eq, DiagnosticLevel};
+use hyper::header::{AUTHORIZATION, ETAG};
 use hyper::{Body, Method, Request, Response, StatusCode};
-use log::{error, info};
+use log::{error, info, warn};
 use owo_colors::OwoColorize;
 use serde::Deserialize;
 use serde_json::json;
 use std::borrow::Cow;
 use std::convert::Infallible;
+use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
+use strum::IntoStaticStr;
+use tokio::io::{self, AsyncReadExt};
 
 pub(crate) async fn handle(
   state: Arc<ServerState>,
-  req: Request<Body>,
+  remote_addr: SocketAddr,
+  mut req: Request<Body>,
 ) -> Result<Response<Body>, Infallible> {
   const GET: &Method = &Method::GET;
-  #[allow(unused)]
   const POST: &Method = &Method::POST;
   const PUT: &Method = &Method::PUT;
   const PATCH: &Method = &Method::PATCH;
   const DELETE: &Method = &Method::DELETE;
 
+  let mut client_addr = ClientAddr::new(remote_addr, req.headers(), &state.trusted_proxies);
+  if let Some(ip) = state.cluster.forwarded_client_ip(req.headers()) {
+    client_addr.real_ip = ip;
+  }
+  req.extensions_mut().insert(client_addr);
+
   let method = req.method();
   let path = req.uri().path();
   let segments = path
@@ -33,9 +52,56 @@ pub(crate) async fn handle(
     .collect::<Box<_>>();
 
   let auth = authenticate(&state, &req);
+  let mut service_headers = None;
 
   let result = match (method, &*segments) {
     (GET, []) => hello_world().await,
+    (GET, ["_ui"]) => Ok(ui::dashboard(&state, &req)),
+    (_, ["audit"]) => match method {
+      _ if !auth => Err(Unauthorized.into()),
+      GET => audit::list(&state, req.uri().query().unwrap_or("")).await,
+      _ => Err(method_not_allowed(&["GET"], method)),
+    },
+    (_, ["events"]) => match method {
+      _ if !auth => Err(Unauthorized.into()),
+      GET => events::stream(&state, req.uri().query().unwrap_or("")),
+      _ => Err(method_not_allowed(&["GET"], method)),
+    },
+    (_, ["hooks", ..]) => match (method, &segments[1..]) {
+      _ if !auth => Err(Unauthorized.into()),
+      (GET, []) => hooks::list(&state).await,
+      (POST, []) => hooks::register(&state, req).await,
+      (_, []) => Err(method_not_allowed(&["GET", "POST"], method)),
+      (DELETE, [id]) => hooks::remove(&state, id).await,
+      (_, [_id]) => Err(method_not_allowed(&["DELETE"], method)),
+      (_, [..]) => Err((404, "path not found", json!({ "path": path })).into()),
+    },
+    (_, ["jobs", id]) => match method {
+      _ if !auth => Err(Unauthorized.into()),
+      GET => jobs::get(&state, id),
+      _ => Err(method_not_allowed(&["GET"], method)),
+    },
+    (_, ["_coordination", op]) => match method {
+      _ if !auth => Err(Unauthorized.into()),
+      POST => coordination::handle(&state, op, req).await,
+      _ => Err(method_not_allowed(&["POST"], method)),
+    },
+    (_, ["_capacity"]) => match method {
+      _ if !auth => Err(Unauthorized.into()),
+      GET => json_response(StatusCode::OK, state.abel.capacity()),
+      _ => Err(method_not_allowed(&["GET"], method)),
+    },
+    (_, ["_sandboxes"]) => match method {
+      _ if !auth => Err(Unauthorized.into()),
+      GET => json_response(StatusCode::OK, state.abel.sandbox_stats().await),
+      _ => Err(method_not_allowed(&["GET"], method)),
+    },
+    // `/metrics` is where Prometheus scrapes by default
+    (_, ["metrics" | "_metrics"]) => match method {
+      _ if !auth => Err(Unauthorized.into()),
+      GET => Ok(metrics(&state)),
+      _ => Err(method_not_allowed(&["GET"], method)),
+    },
 
     // Service management API entry
     (_, ["services", ..]) => match (method, &segments[1..]) {
@@ -43,10 +109,112 @@ pub(crate) async fn handle(
       (GET, []) => list(&state),
       (_, []) => Err(method_not_allowed(&["GET"], method)),
 
-      (GET, [name]) => get(&state, name),
-      (PUT, [name]) => upload(&state, (*name).into(), req).await,
-      (PATCH, [name]) => start_stop(&state, name, req.uri().query().unwrap_or("")).await,
-      (DELETE, [name]) => remove(&state, name).await,
+      (GET, [name]) => get(&state, name).await,
+      (GET, [name, "stats"]) => stats(&state, name),
+      (GET, [name, "profiles"]) => profiles(&state, name),
+      (GET, [name, "errors"]) => errors(&state, name, req.uri().query().unwrap_or("")),
+      (GET, [name, "source"]) => browse::tree(&state, name).await,
+      (GET, [name, "source", path @ ..]) => browse::file(&state, name, &path.join("/")).await,
+      (PUT, [name, "source", path @ ..]) => {
+        let actor = Actor::of(&state, &req);
+        let edit = async {
+          let if_match = IfMatch::of(&state, req.headers())?;
+          delta::put_file(&state, (*name).into(), &path.join("/"), if_match, req).await
+        };
+        audited(&state, actor, "edit_file", name, edit).await
+      }
+      (_, [_name, "source", ..]) => Err(method_not_allowed(&["GET", "PUT"], method)),
+      (POST, [name, "bench"]) => {
+        let actor = Actor::of(&state, &req);
+        let run = bench::bench(&state, name, req);
+        audited(&state, actor, "bench", name, run).await
+      }
+      (_, [_name, "bench"]) => Err(method_not_allowed(&["POST"], method)),
+      (GET, [name, "captures"]) => capture::list(&state, name),
+      (_, [_name, "captures"]) => Err(method_not_allowed(&["GET"], method)),
+      (POST, [name, "replay", id]) => {
+        let actor = Actor::of(&state, &req);
+        let replay = capture::replay(&state, name, id);
+        audited(&state, actor, "replay", name, replay).await
+      }
+      (_, [_name, "replay", _id]) => Err(method_not_allowed(&["POST"], method)),
+      (GET, [name, "redirects"]) => redirect::get(&state, name).await,
+      (PUT, [name, "redirects"]) => {
+        let actor = Actor::of(&state, &req);
+        let update = redirect::update(&state, (*name).into(), req);
+        audited(&state, actor, "update_redirects", name, update).await
+      }
+      (_, [_name, "redirects"]) => Err(method_not_allowed(&["GET", "PUT"], method)),
+      (GET, [name, "maintenance"]) => maintenance::get(&state, name).await,
+      (POST, [name, "maintenance"]) => {
+        let actor = Actor::of(&state, &req);
+        let enable = maintenance::enable(&state, (*name).into(), req);
+        audited(&state, actor, "enable_maintenance", name, enable).await
+      }
+      (DELETE, [name, "maintenance"]) => {
+        let actor = Actor::of(&state, &req);
+        let disable = maintenance::disable(&state, name);
+        audited(&state, actor, "disable_maintenance", name, disable).await
+      }
+      (_, [_name, "maintenance"]) => Err(method_not_allowed(&["GET", "POST", "DELETE"], method)),
+      (POST, [name, "approve"]) => {
+        let approval = approval::approve(&state, name);
+        audited(&state, Actor::of(&state, &req), "approve", name, approval).await
+      }
+      (_, [_name, "approve"]) => Err(method_not_allowed(&["POST"], method)),
+      (GET, [name, "permissions"]) => approval::get_permissions(&state, name).await,
+      (PATCH, [name, "permissions"]) => {
+        let actor = Actor::of(&state, &req);
+        let update = approval::update_permissions(&state, name, req);
+        audited(&state, actor, "update_permissions", name, update).await
+      }
+      (_, [_name, "permissions"]) => Err(method_not_allowed(&["GET", "PATCH"], method)),
+      (GET, [name, "backups"]) => backup::list(&state, name).await,
+      (_, [_name, "backups"]) => Err(method_not_allowed(&["GET"], method)),
+      (POST, [name, "restore-storage"]) => {
+        let query = req.uri().query().unwrap_or("");
+        let actor = Actor::of(&state, &req);
+        let restore = backup::restore(&state, name, query);
+        audited(&state, actor, "restore_storage", name, restore).await
+      }
+      (_, [_name, "restore-storage"]) => Err(method_not_allowed(&["POST"], method)),
+      (PUT, [name]) => {
+        let operation = match state.abel.get_service(name) {
+          Ok(_) => "update",
+          Err(_) => "create",
+        };
+        let actor = Actor::of(&state, &req);
+        let job_actor = actor.clone();
+        let update = async {
+          let if_match = IfMatch::of(&state, req.headers())?;
+          upload(&state, (*name).into(), job_actor, operation, if_match, req).await
+        };
+        audited(&state, actor, operation, name, update).await
+      }
+      (PATCH, [name, "files"]) => {
+        let actor = Actor::of(&state, &req);
+        let patch = async {
+          let if_match = IfMatch::of(&state, req.headers())?;
+          delta::patch_files(&state, (*name).into(), if_match, req).await
+        };
+        audited(&state, actor, "patch_files", name, patch).await
+      }
+      (_, [_name, "files"]) => Err(method_not_allowed(&["PATCH"], method)),
+      (PATCH, [name]) => {
+        let query = req.uri().query().unwrap_or("");
+        match IfMatch::of(&state, req.headers()) {
+          Ok(if_match) => start_stop(&state, Actor::of(&state, &req), name, query, if_match).await,
+          Err(error) => Err(error),
+        }
+      }
+      (DELETE, [name]) => {
+        let forwarded = state.cluster.is_forwarded(req.headers());
+        let removal = async {
+          let if_match = IfMatch::of(&state, req.headers())?;
+          remove(&state, name, if_match, forwarded).await
+        };
+        audited(&state, Actor::of(&state, &req), "delete", name, removal).await
+      }
       (_, [_name]) => Err(method_not_allowed(
         &["GET", "PUT", "PATCH", "DELETE"],
         method,
@@ -59,29 +227,17 @@ pub(crate) async fn handle(
     (_, [service_name, ..]) => {
       let sub_path = "/".to_string() + path[1..].split_once('/').unwrap_or(("", "")).1;
       let service_name: String = (*service_name).into();
-      match state.abel.get_running_service(&service_name) {
-        Ok(service) => {
-          let result = state.abel.run_service(service, sub_path, req).await;
-          match result {
-            Ok(resp) => Ok(resp),
-            // Hide `ServiceDropped` from normal users
-            Err(error) if matches!(error.kind(), ServiceDropped) && !auth => {
-              error!("{error}");
-              Err(From::from(ServiceNotFound {
-                name: service_name.into(),
-              }))
-            }
-            Err(error) => Err(error.into()),
-          }
-        }
-        Err(error) => Err(error.into()),
-      }
+      let result = run_service(&state, auth, service_name.clone(), sub_path, req).await;
+      // Looked up afterwards, so that services woken up by the request count
+      service_headers = (state.abel.get_service(&service_name).ok())
+        .and_then(|x| Some(x.try_upgrade().ok()?.default_headers().clone()));
+      result
     }
 
     _ => Err((404, "path not found", json!({ "path": path })).into()),
   };
 
-  Ok(result.unwrap_or_else(|error| {
+  let mut resp = result.unwrap_or_else(|error| {
     let server_error = error.kind().status().is_server_error();
     let error = ErrorAuthWrapper::new(auth, error);
     if server_error {
@@ -92,13 +248,190 @@ pub(crate) async fn handle(
       }
     }
     error.into()
-  }))
+  });
+  // Error responses of services carry their headers too
+  if let Some(headers) = &service_headers {
+    add_default_headers(resp.headers_mut(), headers);
+  }
+  add_default_headers(resp.headers_mut(), &state.default_headers);
+  Ok(resp)
+}
+
+async fn run_service(
+  state: &Arc<ServerState>,
+  auth: bool,
+  service_name: String,
+  sub_path: String,
+  req: Request<Body>,
+) -> Result<Response<Body>> {
+  // Checked first, so that suspended services are not woken up
+  if let Some(mode) = state.maintenance.get(&service_name, &sub_path) {
+    return Ok(maintenance::respond(state, &service_name, &mode).await);
+  }
+  let real_ip = req.extensions().get::<ClientAddr>().map(|x| x.real_ip);
+  let service = match state.abel.get_running_service(&service_name) {
+    Ok(service) => service,
+    Err(_) if suspend::is_suspended(state, &service_name) => {
+      // Checked before waking, so that denied clients cannot wake it up
+      let denied_ip = real_ip.filter(|ip| {
+        (state.abel.get_service(&service_name).ok())
+          .and_then(|x| Some(x.try_upgrade().ok()?.is_ip_allowed(*ip)))
+          == Some(false)
+      });
+      if let Some(ip) = denied_ip {
+        return Err(deny_ip(state, &service_name, ip).await);
+      }
+      suspend::wake(state, &service_name).await?
+    }
+    Err(error) => match state.cluster.peer_for(&service_name, &req) {
+      Some(peer) => return state.cluster.forward(&peer, req).await,
+      None => return Err(error.into()),
+    },
+  };
+  let (error_page, capture, denied_ip, token_secrets, uuid) = match service.try_upgrade() {
+    Ok(x) => {
+      let error_page = x.error_page().map(|p| (x.source().clone(), p.to_owned()));
+      let denied_ip = real_ip.filter(|ip| !x.is_ip_allowed(*ip));
+      (
+        error_page,
+        x.capture_requests(),
+        denied_ip,
+        x.route_token_secrets(&sub_path),
+        Some(x.uuid()),
+      )
+    }
+    Err(_) => (None, None, None, Vec::new(), None),
+  };
+
+  if let Some(ip) = denied_ip {
+    return Err(deny_ip(state, &service_name, ip).await);
+  }
+  if !check_route_tokens(state, &service_name, &token_secrets, &req).await {
+    return Err(Unauthorized.into());
+  }
+
+  let lookup = match (&state.cache, uuid) {
+    (Some(cache), Some(uuid)) => cache.lookup(uuid, &req),
+    _ => None,
+  };
+  if let (Some(cache), Some(lookup)) = (&state.cache, &lookup) {
+    if let Some(resp) = cache.get(lookup) {
+      return Ok(resp);
+    }
+  }
+
+  let req = match capture {
+    Some(limit) => {
+      (state.captures)
+        .capture(&service_name, limit, &sub_path, req)
+        .await?
+    }
+    None => req,
+  };
+
+  match state.abel.run_service(service, sub_path, req).await {
+    Ok(resp) => match (&state.cache, lookup) {
+      (Some(cache), Some(lookup)) => (cache.store(lookup, resp).await)
+        .map_err(|error| (502, "failed to read service response", error.to_string()).into()),
+      _ => Ok(resp),
+    },
+    // Hide `ServiceDropped` from normal users
+    Err(error) if matches!(error.kind(), ServiceDropped) && !auth => {
+      error!("{error}");
+      Err(From::from(ServiceNotFound {
+        name: service_name.into(),
+      }))
+    }
+    Err(error) => match error_page {
+      Some((source, path)) if error.kind().status().is_server_error() => {
+        let status = error.kind().status();
+        error!("{error}");
+        match serve_error_page(&source, &path, status).await {
+          Ok(resp) => Ok(resp),
+          Err(page_error) => {
+            warn!("failed to serve error page '{path}' of service '{service_name}': {page_error}");
+            Err(error.into())
+          }
+        }
+      }
+      _ => Err(error.into()),
+    },
+  }
+}
+
+/// Records a request rejected by the service's `allow_ips` or `deny_ips`.
+async fn deny_ip(state: &ServerState, service_name: &str, ip: IpAddr) -> Error {
+  audit::record_ip_denied(state, service_name, ip).await;
+  From::from((
+    403,
+    "forbidden",
+    json!({ "msg": "client address not allowed", "service": service_name }),
+  ))
+}
+
+/// Checks the request's bearer token against the service's secrets named
+/// `secrets`, required by route rules. Secrets that are not set deny access.
+async fn check_route_tokens(
+  state: &ServerState,
+  service_name: &str,
+  secrets: &[String],
+  req: &Request<Body>,
+) -> bool {
+  if secrets.is_empty() {
+    return true;
+  }
+  let token =
+    (req.headers().get(AUTHORIZATION)).and_then(|x| x.to_str().ok()?.strip_prefix("Bearer "));
+  let token = match token {
+    Some(token) => token,
+    None => return false,
+  };
+  for secret in secrets {
+    match state.abel.get_secret(service_name, secret).await {
+      Ok(Some(expected)) if constant_time_eq(token.as_bytes(), expected.as_bytes()) => {}
+      Ok(Some(_)) => return false,
+      Ok(None) => {
+        warn!("secret '{secret}' of service '{service_name}' required by its routes is not set");
+        return false;
+      }
+      Err(error) => {
+        warn!("failed to read secrets of service '{service_name}': {error}");
+        return false;
+      }
+    }
+  }
+  true
+}
+
+pub(super) async fn serve_error_page(
+  source: &Source,
+  path: &str,
+  status: StatusCode,
+) -> io::Result<Response<Body>> {
+  let mut file = source.get(path).await?;
+  let mut page = Vec::new();
+  file.read_to_end(&mut page).await?;
+
+  let resp = Response::builder()
+    .status(status)
+    .header("content-type", browse::content_type(path, &page))
+    .body(page.into())
+    .unwrap();
+  Ok(resp)
 }
 
 async fn hello_world() -> Result<Response<Body>> {
   json_response(StatusCode::OK, json!({ "msg": "Hello, world!" }))
 }
 
+/// Metrics of services in Prometheus' text format.
+fn metrics(state: &ServerState) -> Response<Body> {
+  Response::builder()
+    .header("content-type", "text/plain; version=0.0.4")
+    .body(state.abel.render_metrics().into())
+    .unwrap()
+}
+
 fn list(state: &ServerState) -> Result<Response<Body>> {
   let services = state
     .abel
@@ -108,58 +441,131 @@ fn list(state: &ServerState) -> Result<Response<Body>> {
   json_response(StatusCode::OK, services)
 }
 
-fn get(state: &ServerState, name: &str) -> Result<Response<Body>> {
+/// Resource usage of the service's current version.
+fn stats(state: &ServerState, name: &str) -> Result<Response<Body>> {
   let service = state.abel.get_service(name)?;
-  json_response(
-    StatusCode::OK,
-    ServiceWithStatus::from_guard(&service.upgrade()),
-  )
+  let guard = service.upgrade();
+  let metrics = guard.metrics();
+  let body = json!({
+    "name": name,
+    "metrics": metrics.snapshot(),
+    "idle_for": metrics.idle_for().as_secs(),
+  });
+  json_response(StatusCode::OK, body)
 }
 
-async fn start_stop(state: &ServerState, name: &str, query: &str) -> Result<Response<Body>> {
+/// Profiles of recent requests exceeding the service's `profile_threshold`.
+fn profiles(state: &ServerState, name: &str) -> Result<Response<Body>> {
+  state.abel.get_service(name)?;
+  json_response(StatusCode::OK, state.abel.list_profiles(name))
+}
+
+fn errors(state: &ServerState, name: &str, query: &str) -> Result<Response<Body>> {
   #[derive(Deserialize)]
   struct Query {
-    op: Operation,
+    level: Option<DiagnosticLevel>,
   }
 
+  state.abel.get_service(name)?;
+  let Query { level } = serde_qs::from_str(query)?;
+  json_response(StatusCode::OK, state.abel.list_diagnostics(name, level))
+}
+
+async fn get(state: &ServerState, name: &str) -> Result<Response<Body>> {
+  let etag = etag::etag(state, name).await?;
+  let service = state.abel.get_service(name)?;
+  let guard = service.upgrade();
+  let mut info = ServiceWithStatus::from_guard(&guard);
+  info.canary = canary::status(state, name);
+  let mut resp = json_response(StatusCode::OK, info)?;
+  resp.headers_mut().insert(ETAG, etag.try_into().unwrap());
+  Ok(resp)
+}
+
+async fn start_stop(
+  state: &ServerState,
+  actor: Actor,
+  name: &str,
+  query: &str,
+  if_match: IfMatch,
+) -> Result<Response<Body>> {
   #[derive(Deserialize)]
+  struct Query {
+    op: Operation,
+  }
+
+  #[derive(Deserialize, IntoStaticStr)]
+  #[strum(serialize_all = "snake_case")]
   enum Operation {
     #[serde(rename = "start")]
     Start,
     #[serde(rename = "stop")]
     Stop,
+    #[serde(rename = "promote")]
+    Promote,
+    #[serde(rename = "abort")]
+    Abort,
   }
 
   let Query { op } = serde_qs::from_str(query)?;
-  let metadata_path = state
-    .abel_path
-    .join(format!("services/{name}/metadata.json"));
-
-  match op {
-    Operation::Start => {
-      let service = state.abel.start_service(name).await?;
-      Metadata::modify(&metadata_path, |m| m.started = true).await?;
-      json_response(StatusCode::OK, ServiceWithStatus {
-        status: Running,
-        service: Cow::Borrowed(service.upgrade().info()),
-      })
-    }
-    Operation::Stop => {
-      let result = state.abel.stop_service(name).await;
-      Metadata::modify(&metadata_path, |m| m.started = false).await?;
-      result.map_err(From::from).and_then(|x| {
+  let operation: &str = (&op).into();
+  let result = async {
+    let lock = state.abel.lock_deploy(name, false).await?;
+    if_match.check(state, name).await?;
+    match op {
+      Operation::Start => {
+        let service = state.abel.start_service(&lock).await?;
+        state
+          .metadata
+          .modify(name, &mut |m| m.started = true)
+          .await?;
+        let guard = service.upgrade();
         json_response(StatusCode::OK, ServiceWithStatus {
-          status: Stopped,
-          service: Cow::Borrowed(x.info()),
+          status: Running,
+          service: Cow::Borrowed(guard.info()),
+          metrics: guard.metrics().snapshot(),
+          uptime: Some(guard.metrics().uptime().as_secs()),
+          canary: None,
         })
-      })
+      }
+      Operation::Stop => {
+        let result = state.abel.stop_service(&lock).await;
+        state
+          .metadata
+          .modify(name, &mut |m| m.started = false)
+          .await?;
+        result.map_err(From::from).and_then(|x| {
+          json_response(StatusCode::OK, ServiceWithStatus {
+            status: Stopped,
+            service: Cow::Borrowed(x.info()),
+            metrics: x.metrics().snapshot(),
+            uptime: None,
+            canary: None,
+          })
+        })
+      }
+      Operation::Promote => canary::promote(state, &lock).await,
+      Operation::Abort => canary::abort(state, &lock).await,
     }
-  }
+  };
+  audited(state, actor, operation, name, result).await
 }
 
-async fn remove(state: &ServerState, service_name: &str) -> Result<Response<Body>> {
-  let removed = state.abel.remove_service(service_name).await?;
-  tokio::fs::remove_dir_all(state.abel_path.join("services").join(service_name)).await?;
+async fn remove(
+  state: &ServerState,
+  service_name: &str,
+  if_match: IfMatch,
+  forwarded: bool,
+) -> Result<Response<Body>> {
+  let lock = state.abel.lock_deploy(service_name, false).await?;
+  if_match.check(state, service_name).await?;
+  let removed = state.abel.remove_service(&lock).await?;
+  state.captures.remove_service(service_name);
+  state.maintenance.remove_service(service_name);
+  layout::remove(&state.abel_path.join("services"), service_name).await?;
+  if !forwarded {
+    state.cluster.replicate_remove(service_name);
+  }
   info!("Removed service '{}' ({})", removed.name(), removed.uuid());
   json_response(StatusCode::OK, removed.info())
 }
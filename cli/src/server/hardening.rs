@@ -0,0 +1,325 @@
+//! Kernel-level hardening of the server process.
+//!
+//! The Lua sandbox guards against accidents, not escapes through bugs in the
+//! interpreter or native code. Landlock limits the files the process can
+//! touch, and a seccomp filter denies system calls Abel never needs.
+//!
+//! Both only apply to the calling thread and threads it spawns afterwards, so
+//! [`apply`] must be called before any runtime starts.
+
+use super::config::Config;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HardeningConfig {
+  /// Limits filesystem access with landlock to Abel's working directory,
+  /// system directories, `read_paths` and `write_paths`.
+  #[serde(default = "default_enabled")]
+  pub landlock: bool,
+  /// Denies system calls Abel does not need, like `ptrace` and `mount`.
+  #[serde(default = "default_enabled")]
+  pub seccomp: bool,
+  /// Additional paths readable and executable, e.g. programs run by the
+  /// `exec` module outside system directories.
+  #[serde(default, skip_serializing_if = "Vec::is_empty")]
+  pub read_paths: Vec<PathBuf>,
+  /// Additional paths writable.
+  #[serde(default, skip_serializing_if = "Vec::is_empty")]
+  pub write_paths: Vec<PathBuf>,
+}
+
+fn default_enabled() -> bool {
+  true
+}
+
+/// Paths readable and executable by default, for shared libraries, TLS
+/// certificates, DNS configuration and the like.
+const SYSTEM_PATHS: &[&str] = &[
+  "/bin", "/dev", "/etc", "/lib", "/lib64", "/proc", "/sbin", "/sys", "/usr",
+];
+
+/// Applies hardening configured in `<abel_path>/config.json`, if any.
+pub fn apply(abel_path: &Path) -> anyhow::Result<()> {
+  let config: Config = match std::fs::read(abel_path.join("config.json")) {
+    Ok(content) => serde_json::from_slice(&content)?,
+    Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+    Err(error) => return Err(error.into()),
+  };
+  let hardening = match &config.hardening {
+    Some(hardening) => hardening,
+    None => return Ok(()),
+  };
+
+  #[cfg(target_os = "linux")]
+  {
+    if hardening.landlock {
+      let mut write_paths = vec![abel_path.to_path_buf(), "/dev/null".into()];
+      if let Some(dir) = config.backup.as_ref().and_then(|x| x.local_dir()) {
+        std::fs::create_dir_all(dir)?;
+        write_paths.push(dir.into());
+      }
+      // Rotation creates files next to log files
+      for path in config.logging.iter().filter_map(|x| x.output.file_path()) {
+        match path.parent() {
+          Some(dir) if !dir.as_os_str().is_empty() => write_paths.push(dir.into()),
+          _ => write_paths.push(".".into()),
+        }
+      }
+      write_paths.extend(hardening.write_paths.iter().cloned());
+      let read_paths = (SYSTEM_PATHS.iter())
+        .map(PathBuf::from)
+        .chain(hardening.read_paths.iter().cloned())
+        .collect::<Vec<_>>();
+      if linux::landlock(&read_paths, &write_paths)? {
+        info!("Filesystem access restricted with landlock");
+      } else {
+        warn!("Landlock is not supported by the kernel; skipping");
+      }
+    }
+    if hardening.seccomp {
+      if linux::seccomp()? {
+        info!("System calls filtered with seccomp");
+      } else {
+        warn!("Seccomp filtering is not supported on this architecture; skipping");
+      }
+    }
+  }
+
+  #[cfg(not(target_os = "linux"))]
+  if hardening.landlock || hardening.seccomp {
+    warn!("Hardening is only supported on Linux; skipping");
+  }
+
+  Ok(())
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+  use libc::{c_long, c_void, sock_filter, sock_fprog};
+  use std::ffi::CString;
+  use std::io;
+  use std::os::unix::ffi::OsStrExt;
+  use std::path::{Path, PathBuf};
+
+  // Not in `libc` yet
+  const SYS_LANDLOCK_CREATE_RULESET: c_long = 444;
+  const SYS_LANDLOCK_ADD_RULE: c_long = 445;
+  const SYS_LANDLOCK_RESTRICT_SELF: c_long = 446;
+  const LANDLOCK_CREATE_RULESET_VERSION: u32 = 1;
+  const LANDLOCK_RULE_PATH_BENEATH: u32 = 1;
+  const SECCOMP_SET_MODE_FILTER: u32 = 1;
+
+  const ACCESS_EXECUTE: u64 = 1 << 0;
+  const ACCESS_WRITE_FILE: u64 = 1 << 1;
+  const ACCESS_READ_FILE: u64 = 1 << 2;
+  const ACCESS_READ_DIR: u64 = 1 << 3;
+  /// All access rights of landlock ABI 2, up to `LANDLOCK_ACCESS_FS_REFER`.
+  const ACCESS_ALL: u64 = (1 << 14) - 1;
+  const ACCESS_FILE: u64 = ACCESS_EXECUTE | ACCESS_WRITE_FILE | ACCESS_READ_FILE;
+  const ACCESS_READ: u64 = ACCESS_EXECUTE | ACCESS_READ_FILE | ACCESS_READ_DIR;
+
+  #[repr(C)]
+  struct RulesetAttr {
+    handled_access_fs: u64,
+  }
+
+  #[repr(C, packed)]
+  struct PathBeneathAttr {
+    allowed_access: u64,
+    parent_fd: i32,
+  }
+
+  fn check(ret: c_long) -> io::Result<c_long> {
+    if ret < 0 {
+      Err(io::Error::last_os_error())
+    } else {
+      Ok(ret)
+    }
+  }
+
+  fn no_new_privs() -> io::Result<()> {
+    check(unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } as _)?;
+    Ok(())
+  }
+
+  /// Restricts filesystem access to the given paths, returning `false` if
+  /// landlock (ABI 2 or later) is unavailable. Missing paths are skipped.
+  pub fn landlock(read_paths: &[PathBuf], write_paths: &[PathBuf]) -> io::Result<bool> {
+    let abi = unsafe {
+      libc::syscall(
+        SYS_LANDLOCK_CREATE_RULESET,
+        std::ptr::null::<c_void>(),
+        0,
+        LANDLOCK_CREATE_RULESET_VERSION,
+      )
+    };
+    // Renaming files across directories is always denied below ABI 2
+    if abi < 2 {
+      return Ok(false);
+    }
+
+    let attr = RulesetAttr {
+      handled_access_fs: ACCESS_ALL,
+    };
+    let ruleset = check(unsafe {
+      libc::syscall(
+        SYS_LANDLOCK_CREATE_RULESET,
+        &attr as *const RulesetAttr,
+        std::mem::size_of::<RulesetAttr>(),
+        0,
+      )
+    })? as i32;
+
+    let result = (|| {
+      let rules = (read_paths.iter().map(|x| (x, ACCESS_READ)))
+        .chain(write_paths.iter().map(|x| (x, ACCESS_ALL)));
+      for (path, access) in rules {
+        add_rule(ruleset, path, access)?;
+      }
+      no_new_privs()?;
+      check(unsafe { libc::syscall(SYS_LANDLOCK_RESTRICT_SELF, ruleset, 0) })?;
+      Ok(true)
+    })();
+    unsafe { libc::close(ruleset) };
+    result
+  }
+
+  fn add_rule(ruleset: i32, path: &Path, access: u64) -> io::Result<()> {
+    let path_c = CString::new(path.as_os_str().as_bytes())?;
+    let fd = unsafe { libc::open(path_c.as_ptr(), libc::O_PATH | libc::O_CLOEXEC) };
+    if fd < 0 {
+      let error = io::Error::last_os_error();
+      return match error.kind() {
+        io::ErrorKind::NotFound => Ok(()),
+        _ => Err(error),
+      };
+    }
+    let access = if path.is_dir() {
+      access
+    } else {
+      access & ACCESS_FILE
+    };
+    let attr = PathBeneathAttr {
+      allowed_access: access,
+      parent_fd: fd,
+    };
+    let result = check(unsafe {
+      libc::syscall(
+        SYS_LANDLOCK_ADD_RULE,
+        ruleset,
+        LANDLOCK_RULE_PATH_BENEATH,
+        &attr as *const PathBeneathAttr,
+        0,
+      )
+    });
+    unsafe { libc::close(fd) };
+    result.map(drop)
+  }
+
+  #[cfg(target_arch = "x86_64")]
+  const AUDIT_ARCH: u32 = 0xc000_003e;
+  #[cfg(target_arch = "aarch64")]
+  const AUDIT_ARCH: u32 = 0xc000_00b7;
+
+  /// System calls denied with `EPERM`.
+  #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+  const DENIED: &[c_long] = &[
+    libc::SYS_acct,
+    libc::SYS_add_key,
+    libc::SYS_bpf,
+    libc::SYS_clock_settime,
+    libc::SYS_delete_module,
+    libc::SYS_finit_module,
+    libc::SYS_init_module,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_ioperm,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_iopl,
+    libc::SYS_kexec_file_load,
+    libc::SYS_kexec_load,
+    libc::SYS_keyctl,
+    libc::SYS_mount,
+    libc::SYS_name_to_handle_at,
+    libc::SYS_open_by_handle_at,
+    libc::SYS_perf_event_open,
+    libc::SYS_pivot_root,
+    libc::SYS_process_vm_readv,
+    libc::SYS_process_vm_writev,
+    libc::SYS_ptrace,
+    libc::SYS_quotactl,
+    libc::SYS_reboot,
+    libc::SYS_request_key,
+    libc::SYS_setns,
+    libc::SYS_settimeofday,
+    libc::SYS_swapoff,
+    libc::SYS_swapon,
+    libc::SYS_syslog,
+    libc::SYS_umount2,
+    libc::SYS_unshare,
+    libc::SYS_userfaultfd,
+  ];
+
+  #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+  fn stmt(code: u32, k: u32) -> sock_filter {
+    jump(code, k, 0, 0)
+  }
+
+  #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+  fn jump(code: u32, k: u32, jt: u8, jf: u8) -> sock_filter {
+    sock_filter {
+      code: code as _,
+      jt,
+      jf,
+      k,
+    }
+  }
+
+  /// Installs a seccomp filter denying dangerous system calls, returning
+  /// `false` on unsupported architectures.
+  #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+  pub fn seccomp() -> io::Result<bool> {
+    use libc::{
+      BPF_ABS, BPF_JEQ, BPF_JGE, BPF_JMP, BPF_K, BPF_LD, BPF_RET, BPF_W, SECCOMP_RET_ALLOW,
+      SECCOMP_RET_ERRNO, SECCOMP_RET_KILL_PROCESS,
+    };
+
+    let deny = SECCOMP_RET_ERRNO | libc::EPERM as u32;
+    // Offsets of `arch` and `nr` in `struct seccomp_data`
+    let mut filter = vec![
+      stmt(BPF_LD | BPF_W | BPF_ABS, 4),
+      jump(BPF_JMP | BPF_JEQ | BPF_K, AUDIT_ARCH, 1, 0),
+      stmt(BPF_RET | BPF_K, SECCOMP_RET_KILL_PROCESS),
+      stmt(BPF_LD | BPF_W | BPF_ABS, 0),
+      // x32 system calls share the architecture, with this bit set
+      jump(BPF_JMP | BPF_JGE | BPF_K, 0x4000_0000, 0, 1),
+      stmt(BPF_RET | BPF_K, deny),
+    ];
+    for &nr in DENIED {
+      filter.push(jump(BPF_JMP | BPF_JEQ | BPF_K, nr as u32, 0, 1));
+      filter.push(stmt(BPF_RET | BPF_K, deny));
+    }
+    filter.push(stmt(BPF_RET | BPF_K, SECCOMP_RET_ALLOW));
+
+    let prog = sock_fprog {
+      len: filter.len() as _,
+      filter: filter.as_mut_ptr(),
+    };
+    no_new_privs()?;
+    check(unsafe {
+      libc::syscall(
+        libc::SYS_seccomp,
+        SECCOMP_SET_MODE_FILTER,
+        0,
+        &prog as *const sock_fprog,
+      )
+    })?;
+    Ok(true)
+  }
+
+  #[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+  pub fn seccomp() -> io::Result<bool> {
+    Ok(false)
+  }
+}
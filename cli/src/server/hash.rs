@@ -0,0 +1,67 @@
+//! Content hashes of services' sources.
+//!
+//! Identical sources produce identical hashes and UUIDs, so re-deploying
+//! unchanged code can be detected and skipped.
+
+use data_encoding::HEXLOWER;
+use hive_asar::header::Entry;
+use hive_asar::Archive;
+use sha2::{Digest, Sha256};
+use std::path::Path;
+use tokio::io::{self, AsyncReadExt};
+use uuid::Uuid;
+
+pub fn hash_single(code: &[u8]) -> String {
+  format!("sha256:{}", HEXLOWER.encode(&Sha256::digest(code)))
+}
+
+/// Hashes the files in an archive, independent of how it was packed.
+pub async fn hash_archive(path: &Path) -> io::Result<String> {
+  let mut archive = Archive::new_from_file(path).await?;
+  let mut paths = Vec::new();
+  if let Some(Entry::Directory(root)) = archive.get_entry("") {
+    collect_paths(root.files.iter(), String::new(), &mut paths);
+  }
+  paths.sort_unstable();
+
+  let mut hasher = Sha256::new();
+  let mut content = Vec::new();
+  for path in paths {
+    content.clear();
+    archive.get(&path).await?.read_to_end(&mut content).await?;
+    hasher.update((path.len() as u64).to_le_bytes());
+    hasher.update(path.as_bytes());
+    hasher.update((content.len() as u64).to_le_bytes());
+    hasher.update(&content);
+  }
+  Ok(format!("sha256:{}", HEXLOWER.encode(&hasher.finalize())))
+}
+
+fn collect_paths<'a>(
+  files: impl Iterator<Item = (&'a Box<str>, &'a Entry)>,
+  prefix: String,
+  paths: &mut Vec<String>,
+) {
+  for (name, entry) in files {
+    let path = prefix.clone() + name;
+    match entry {
+      Entry::File(_) => paths.push(path),
+      Entry::Directory(dir) => collect_paths(dir.files.iter(), path + "/", paths),
+    }
+  }
+}
+
+/// Derives a service's UUID from its name and content hash.
+pub fn derive_uuid(name: &str, hash: &str) -> Uuid {
+  let digest = Sha256::new()
+    .chain_update(name)
+    .chain_update([0])
+    .chain_update(hash)
+    .finalize();
+  let mut bytes = [0; 16];
+  bytes.copy_from_slice(&digest[..16]);
+  // Version 8 (custom) and RFC 4122 variant
+  bytes[6] = (bytes[6] & 0x0f) | 0x80;
+  bytes[8] = (bytes[8] & 0x3f) | 0x80;
+  Uuid::from_bytes(bytes)
+}
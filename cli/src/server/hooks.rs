@@ -0,0 +1,192 @@
+use super::{json_response, Result, ServerState};
+use abel_core::hmac_sha256;
+use bytes::Bytes;
+use data_encoding::HEXLOWER;
+use hyper::{Body, Request, Response, StatusCode};
+use log::{debug, warn};
+use reqwest::{Client, Url};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::io;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::fs;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::Mutex;
+use uuid::Uuid;
+
+/// Header carrying `sha256=<hex>`, the HMAC-SHA256 of the request body keyed
+/// with the hook's secret.
+pub const SIGNATURE_HEADER: &str = "abel-signature";
+
+const MAX_RETRIES: u32 = 3;
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Webhook receiving lifecycle events as JSON.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Hook {
+  #[serde(default = "Uuid::new_v4")]
+  pub id: Uuid,
+  pub url: String,
+  /// Key for signing deliveries. Unsigned if not set.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub secret: Option<String>,
+}
+
+impl Hook {
+  fn view(&self, configured: bool) -> serde_json::Value {
+    json!({
+      "id": self.id,
+      "url": self.url,
+      "signed": self.secret.is_some(),
+      "configured": configured,
+    })
+  }
+}
+
+/// Webhooks from the server config, and those registered through the API.
+///
+/// Registered hooks are persisted in `hooks.json`.
+pub struct Hooks {
+  path: PathBuf,
+  client: Client,
+  configured: Vec<Hook>,
+  registered: Mutex<Vec<Hook>>,
+}
+
+impl Hooks {
+  pub async fn load(path: PathBuf, configured: Vec<Hook>) -> io::Result<Self> {
+    let registered = match fs::read(&path).await {
+      Ok(content) => serde_json::from_slice(&content)?,
+      Err(error) if error.kind() == io::ErrorKind::NotFound => Vec::new(),
+      Err(error) => return Err(error),
+    };
+    Ok(Self {
+      path,
+      client: Client::new(),
+      configured,
+      registered: Mutex::new(registered),
+    })
+  }
+
+  async fn all(&self) -> Vec<Hook> {
+    let registered = self.registered.lock().await;
+    (self.configured.iter().chain(registered.iter()))
+      .cloned()
+      .collect()
+  }
+
+  async fn save(&self, hooks: &[Hook]) -> io::Result<()> {
+    fs::write(&self.path, serde_json::to_vec_pretty(hooks)?).await
+  }
+}
+
+/// Delivers events to all webhooks. Never returns.
+pub async fn run(state: Arc<ServerState>) {
+  let mut events = state.abel.subscribe();
+  loop {
+    let event = match events.recv().await {
+      Ok(event) => event,
+      Err(RecvError::Lagged(n)) => {
+        warn!("{n} events were not delivered to webhooks");
+        continue;
+      }
+      Err(RecvError::Closed) => return,
+    };
+    let body = Bytes::from(serde_json::to_vec(&event).unwrap());
+    for hook in state.hooks.all().await {
+      tokio::spawn(deliver(state.hooks.client.clone(), hook, body.clone()));
+    }
+  }
+}
+
+/// Posts the event, retrying with exponential backoff on failure.
+async fn deliver(client: Client, hook: Hook, body: Bytes) {
+  let signature = (hook.secret.as_ref()).map(|secret| {
+    format!(
+      "sha256={}",
+      HEXLOWER.encode(&hmac_sha256(secret.as_bytes(), &body))
+    )
+  });
+  let mut delay = RETRY_DELAY;
+  for attempt in 0..=MAX_RETRIES {
+    if attempt > 0 {
+      tokio::time::sleep(delay).await;
+      delay *= 2;
+    }
+    let mut req = (client.post(&hook.url))
+      .header("content-type", "application/json")
+      .timeout(DELIVERY_TIMEOUT)
+      .body(body.clone());
+    if let Some(signature) = &signature {
+      req = req.header(SIGNATURE_HEADER, signature);
+    }
+    match req.send().await.and_then(|x| x.error_for_status()) {
+      Ok(_) => return,
+      Err(error) => debug!("failed to deliver event to webhook {}: {error}", hook.url),
+    }
+  }
+  warn!(
+    "gave up delivering event to webhook {} after {} attempts",
+    hook.url,
+    MAX_RETRIES + 1
+  );
+}
+
+pub async fn list(state: &ServerState) -> Result<Response<Body>> {
+  let hooks = state.hooks.registered.lock().await;
+  let views = (state.hooks.configured.iter())
+    .map(|x| x.view(true))
+    .chain(hooks.iter().map(|x| x.view(false)))
+    .collect::<Vec<_>>();
+  json_response(StatusCode::OK, views)
+}
+
+pub async fn register(state: &ServerState, req: Request<Body>) -> Result<Response<Body>> {
+  #[derive(Deserialize)]
+  struct Registration {
+    url: String,
+    secret: Option<String>,
+  }
+
+  let body = hyper::body::to_bytes(req.into_body())
+    .await
+    .map_err(|error| (400, "failed to read request body", error.to_string()))?;
+  let Registration { url, secret } = serde_json::from_slice(&body)?;
+  match Url::parse(&url) {
+    Ok(x) if matches!(x.scheme(), "http" | "https") => {}
+    _ => return Err(("invalid webhook URL", json!({ "url": url })).into()),
+  }
+
+  let hook = Hook {
+    id: Uuid::new_v4(),
+    url,
+    secret,
+  };
+  let mut hooks = state.hooks.registered.lock().await;
+  hooks.push(hook.clone());
+  if let Err(error) = state.hooks.save(&hooks).await {
+    hooks.pop();
+    return Err(error.into());
+  }
+  json_response(StatusCode::CREATED, hook.view(false))
+}
+
+pub async fn remove(state: &ServerState, id: &str) -> Result<Response<Body>> {
+  let not_found = || (404, "webhook not found", json!({ "id": id }));
+  let id = Uuid::parse_str(id).map_err(|_| not_found())?;
+  if state.hooks.configured.iter().any(|x| x.id == id) {
+    return Err(("webhook is set in server config", json!({ "id": id })).into());
+  }
+
+  let mut hooks = state.hooks.registered.lock().await;
+  let index = (hooks.iter().position(|x| x.id == id)).ok_or_else(not_found)?;
+  let hook = hooks.remove(index);
+  if let Err(error) = state.hooks.save(&hooks).await {
+    hooks.insert(index, hook);
+    return Err(error.into());
+  }
+  json_response(StatusCode::OK, hook.view(false))
+}
@@ -0,0 +1,358 @@
+//! Operations running in the background, such as deploys with `?async=true`.
+//!
+//! Each job is recorded as `<id>.json` under `jobs` as it progresses, so that
+//! its outcome survives restarts. Files a job works on, such as uploaded
+//! sources, are kept beside it as `<id>.source` until it finishes. Jobs
+//! interrupted by a restart are marked failed on startup, and their files are
+//! removed.
+
+use super::error::Error;
+use super::{json_response, Result, ServerState};
+use futures::Future;
+use hyper::{Body, Response, StatusCode};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::{fs, io};
+use uuid::Uuid;
+
+/// Number of recent jobs kept for inspection.
+const MAX_JOBS: usize = 100;
+
+tokio::task_local! {
+  static CURRENT_JOB: Arc<Job>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobPhase {
+  Pending,
+  Extracting,
+  Evaluating,
+  Swapping,
+  Done,
+  Failed,
+}
+
+impl JobPhase {
+  fn is_finished(self) -> bool {
+    matches!(self, Self::Done | Self::Failed)
+  }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JobStatus {
+  id: Uuid,
+  service: String,
+  phase: JobPhase,
+  /// Unix time in seconds.
+  created: u64,
+  /// Unix time in seconds of the last phase change.
+  updated: u64,
+  /// Response body of the operation, once done.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  result: Option<serde_json::Value>,
+  /// Status code of the error, if failed.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  status: Option<u16>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  error: Option<serde_json::Value>,
+}
+
+impl JobStatus {
+  fn fail(&mut self, error: Error) {
+    let (code, body) = error.into_status_and_body();
+    self.phase = JobPhase::Failed;
+    self.status = Some(code.as_u16());
+    self.error = serde_json::to_value(body).ok();
+  }
+}
+
+pub struct Job {
+  path: PathBuf,
+  status: Mutex<JobStatus>,
+}
+
+impl Job {
+  fn set_phase(&self, phase: JobPhase) {
+    let mut status = self.status.lock().unwrap();
+    status.phase = phase;
+    status.updated = now();
+    self.persist(&status);
+  }
+
+  /// Writes the job's record, so that its progress is kept across restarts.
+  ///
+  /// This is called with the status locked, so that records are written in
+  /// the order of changes.
+  fn persist(&self, status: &JobStatus) {
+    let result = serde_json::to_vec(status)
+      .map_err(io::Error::from)
+      .and_then(|data| {
+        let temp_path = self.path.with_extension("json.tmp");
+        std::fs::write(&temp_path, data)?;
+        std::fs::rename(temp_path, &self.path)
+      });
+    if let Err(error) = result {
+      warn!("failed to record job {}: {error}", status.id);
+    }
+  }
+}
+
+/// Removes the file of a job once it finishes, even if it panics.
+struct JobFile(PathBuf);
+
+impl Drop for JobFile {
+  fn drop(&mut self) {
+    if let Err(error) = std::fs::remove_file(&self.0) {
+      if error.kind() != io::ErrorKind::NotFound {
+        warn!("failed to remove '{}': {error}", self.0.display());
+      }
+    }
+  }
+}
+
+/// Recent jobs, oldest first.
+pub struct Jobs {
+  path: PathBuf,
+  jobs: Mutex<VecDeque<Arc<Job>>>,
+}
+
+impl Jobs {
+  /// Loads jobs recorded in `path`, failing those interrupted by a restart.
+  pub async fn load(path: PathBuf) -> io::Result<Self> {
+    fs::create_dir_all(&path).await?;
+    let mut jobs = Vec::new();
+    let mut entries = fs::read_dir(&path).await?;
+    while let Some(entry) = entries.next_entry().await? {
+      let file_path = entry.path();
+      if file_path.extension().map_or(true, |x| x != "json") {
+        // Leftover files of interrupted jobs
+        remove_file(&file_path).await?;
+        continue;
+      }
+      let status = match fs::read(&file_path)
+        .await
+        .map(|x| serde_json::from_slice(&x))
+      {
+        Ok(Ok(status)) => status,
+        _ => {
+          warn!("removing unreadable job record '{}'", file_path.display());
+          remove_file(&file_path).await?;
+          continue;
+        }
+      };
+      let job = Job {
+        path: file_path,
+        status: Mutex::new(status),
+      };
+      {
+        let mut status = job.status.lock().unwrap();
+        if !status.phase.is_finished() {
+          status.fail(Error::from((
+            500,
+            "job interrupted",
+            "server stopped before the job finished",
+          )));
+          status.updated = now();
+          job.persist(&status);
+        }
+      }
+      jobs.push(Arc::new(job));
+    }
+    jobs.sort_by_key(|x| x.status.lock().unwrap().created);
+
+    let jobs = Self {
+      path,
+      jobs: Mutex::new(jobs.into()),
+    };
+    jobs.prune();
+    Ok(jobs)
+  }
+
+  /// Forgets the oldest jobs beyond [`MAX_JOBS`], along with their records.
+  fn prune(&self) {
+    let mut jobs = self.jobs.lock().unwrap();
+    while jobs.len() > MAX_JOBS {
+      let job = jobs.pop_front().unwrap();
+      if let Err(error) = std::fs::remove_file(&job.path) {
+        warn!("failed to remove '{}': {error}", job.path.display());
+      }
+    }
+  }
+
+  /// Runs `f` in the background, returning the job's ID.
+  ///
+  /// If `file` is given, it is moved to the job's directory, and its new path
+  /// is passed to `f`. It is removed once the job finishes.
+  pub fn spawn<F, Fut>(&self, service: &str, file: Option<&Path>, f: F) -> io::Result<Uuid>
+  where
+    F: FnOnce(Option<PathBuf>) -> Fut,
+    Fut: Future<Output = Result<serde_json::Value>> + Send + 'static,
+  {
+    let id = Uuid::new_v4();
+    let file = match file {
+      Some(file) => {
+        let job_file = self.path.join(format!("{id}.source"));
+        std::fs::rename(file, &job_file)?;
+        Some(job_file)
+      }
+      None => None,
+    };
+    let job = Arc::new(Job {
+      path: self.path.join(format!("{id}.json")),
+      status: Mutex::new(JobStatus {
+        id,
+        service: service.into(),
+        phase: JobPhase::Pending,
+        created: now(),
+        updated: now(),
+        result: None,
+        status: None,
+        error: None,
+      }),
+    });
+    job.persist(&job.status.lock().unwrap());
+    self.jobs.lock().unwrap().push_back(job.clone());
+    self.prune();
+
+    let guard = file.clone().map(JobFile);
+    let f = f(file);
+    tokio::spawn(CURRENT_JOB.scope(job.clone(), async move {
+      let _guard = guard;
+      let result = f.await;
+      let mut status = job.status.lock().unwrap();
+      match result {
+        Ok(result) => {
+          status.phase = JobPhase::Done;
+          status.result = Some(result);
+        }
+        Err(error) => {
+          warn!("job {id} of service '{}' failed: {error}", status.service);
+          status.fail(error);
+        }
+      }
+      status.updated = now();
+      job.persist(&status);
+    }));
+    Ok(id)
+  }
+
+  fn get(&self, id: Uuid) -> Option<JobStatus> {
+    (self.jobs.lock().unwrap().iter())
+      .map(|x| x.status.lock().unwrap())
+      .find(|x| x.id == id)
+      .map(|x| x.clone())
+  }
+}
+
+/// Reports progress of the job running the current task, if any.
+pub fn report(phase: JobPhase) {
+  let _ = CURRENT_JOB.try_with(|job| job.set_phase(phase));
+}
+
+/// Returns a function reporting progress of the job running the current task,
+/// for use outside of it, e.g. in hooks run by Abel.
+pub fn reporter() -> impl Fn(JobPhase) + Send + 'static {
+  let job = CURRENT_JOB.try_with(Arc::clone).ok();
+  move |phase| {
+    if let Some(job) = &job {
+      job.set_phase(phase);
+    }
+  }
+}
+
+pub fn get(state: &ServerState, id: &str) -> Result<Response<Body>> {
+  let not_found = || (404, "job not found", json!({ "id": id }));
+  let id = Uuid::parse_str(id).map_err(|_| not_found())?;
+  let status = state.jobs.get(id).ok_or_else(not_found)?;
+  json_response(StatusCode::OK, status)
+}
+
+async fn remove_file(path: &Path) -> io::Result<()> {
+  match fs::remove_file(path).await {
+    Err(error) if error.kind() != io::ErrorKind::NotFound => Err(error),
+    _ => Ok(()),
+  }
+}
+
+fn now() -> u64 {
+  (SystemTime::now().duration_since(UNIX_EPOCH))
+    .map(|x| x.as_secs())
+    .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::time::Duration;
+  use tempfile::TempDir;
+
+  async fn wait(jobs: &Jobs, id: Uuid) -> JobStatus {
+    for _ in 0..100 {
+      match jobs.get(id) {
+        Some(status) if status.phase.is_finished() => return status,
+        _ => tokio::time::sleep(Duration::from_millis(10)).await,
+      }
+    }
+    panic!("job {id} did not finish");
+  }
+
+  #[tokio::test]
+  async fn test_spawn() -> io::Result<()> {
+    let dir = TempDir::new()?;
+    let jobs = Jobs::load(dir.path().into()).await?;
+    let file = dir.path().join("upload");
+    std::fs::write(&file, "source")?;
+
+    let id = jobs.spawn("svc", Some(&file), |file| async move {
+      let file = file.unwrap();
+      report(JobPhase::Evaluating);
+      reporter()(JobPhase::Swapping);
+      Ok(json!(std::fs::read_to_string(file)?))
+    })?;
+    let status = wait(&jobs, id).await;
+    assert_eq!(status.phase, JobPhase::Done);
+    assert_eq!(status.result, Some(json!("source")));
+    assert!(!file.exists());
+    assert!(!dir.path().join(format!("{id}.source")).exists());
+
+    let id = jobs.spawn("svc", None, |_| async { Err("bad".into()) })?;
+    let status = wait(&jobs, id).await;
+    assert_eq!(status.phase, JobPhase::Failed);
+    assert_eq!(status.status, Some(400));
+
+    // Outcomes are kept across restarts
+    let jobs = Jobs::load(dir.path().into()).await?;
+    assert_eq!(jobs.get(id).unwrap().phase, JobPhase::Failed);
+    Ok(())
+  }
+
+  #[tokio::test]
+  async fn test_interrupted() -> io::Result<()> {
+    let dir = TempDir::new()?;
+    let id = Uuid::new_v4();
+    let status = json!({
+      "id": id,
+      "service": "svc",
+      "phase": "evaluating",
+      "created": 0,
+      "updated": 0,
+    });
+    std::fs::write(dir.path().join(format!("{id}.json")), status.to_string())?;
+    std::fs::write(dir.path().join(format!("{id}.source")), "source")?;
+    std::fs::write(dir.path().join("broken.json"), "{")?;
+
+    let jobs = Jobs::load(dir.path().into()).await?;
+    let status = jobs.get(id).unwrap();
+    assert_eq!(status.phase, JobPhase::Failed);
+    assert_eq!(status.status, Some(500));
+    assert!(!dir.path().join(format!("{id}.source")).exists());
+    assert!(!dir.path().join("broken.json").exists());
+    Ok(())
+  }
+}
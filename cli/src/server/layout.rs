@@ -0,0 +1,292 @@
+//! Crash-safe layout of services on disk.
+//!
+//! A service lives in `services/<name>`. Deploys prepare its new directory as
+//! `services/.<name>.new`, write the journal `services/.<name>.journal` once
+//! it is complete, and swap it in with renames, before the new version
+//! replaces the running one. Changes to more than one file of a service, like
+//! promoting its canary, are staged and committed the same way. Removed
+//! services are renamed to `services/.<name>.removed` before being deleted.
+//! Service names never start with a dot, so these never clash with services.
+//!
+//! Updates waiting for approval of their permissions are kept as
+//! `services/.<name>.held`, and swapped in like deploys once approved.
+//!
+//! On startup, `recover` finishes swaps that were journaled and discards the
+//! rest, so a crash never leaves a half-written service behind.
+
+use log::{info, warn};
+use std::path::{Path, PathBuf};
+use tokio::fs::{self, File};
+use tokio::io;
+
+fn sibling(services_path: &Path, name: &str, suffix: &str) -> PathBuf {
+  services_path.join(format!(".{name}.{suffix}"))
+}
+
+/// Creates a directory to prepare a new version of a service in, discarding
+/// leftovers of earlier attempts.
+///
+/// Files in `keep` are copied from the current directory, if present, so that
+/// the new one is complete even before they are updated.
+pub async fn stage(services_path: &Path, name: &str, keep: &[&str]) -> io::Result<PathBuf> {
+  let staging = sibling(services_path, name, "new");
+  if staging.exists() {
+    fs::remove_dir_all(&staging).await?;
+  }
+  fs::create_dir(&staging).await?;
+  for file in keep {
+    let path = services_path.join(name).join(file);
+    if path.exists() {
+      fs::copy(path, staging.join(file)).await?;
+    }
+  }
+  Ok(staging)
+}
+
+/// Stages a copy of the service's current directory, so that several of its
+/// files can be changed at once by committing it.
+pub async fn stage_copy(services_path: &Path, name: &str) -> io::Result<PathBuf> {
+  let staging = stage(services_path, name, &[]).await?;
+  let mut entries = fs::read_dir(services_path.join(name)).await?;
+  while let Some(entry) = entries.next_entry().await? {
+    if entry.file_type().await?.is_file() {
+      fs::copy(entry.path(), staging.join(entry.file_name())).await?;
+    }
+  }
+  Ok(staging)
+}
+
+/// Discards the staged directory of a deploy that did not go through.
+pub async fn discard(services_path: &Path, name: &str) -> io::Result<()> {
+  match fs::remove_dir_all(sibling(services_path, name, "new")).await {
+    Err(error) if error.kind() != io::ErrorKind::NotFound => Err(error),
+    _ => Ok(()),
+  }
+}
+
+/// Replaces a service's directory with the staged one.
+pub async fn commit(services_path: &Path, name: &str) -> io::Result<()> {
+  let staging = sibling(services_path, name, "new");
+  sync_dir(&staging).await?;
+
+  let journal = sibling(services_path, name, "journal");
+  write_atomic(&journal, name.as_bytes()).await?;
+  swap(services_path, name).await?;
+  fs::remove_file(&journal).await?;
+  sync(services_path).await
+}
+
+/// Directory of the service's update held for approval.
+pub fn held_path(services_path: &Path, name: &str) -> PathBuf {
+  sibling(services_path, name, "held")
+}
+
+/// Keeps the staged directory as the service's update held for approval,
+/// replacing the one held before.
+pub async fn hold(services_path: &Path, name: &str) -> io::Result<()> {
+  let staging = sibling(services_path, name, "new");
+  sync_dir(&staging).await?;
+
+  let held = held_path(services_path, name);
+  if held.exists() {
+    fs::remove_dir_all(&held).await?;
+  }
+  fs::rename(&staging, &held).await?;
+  sync(services_path).await
+}
+
+/// Replaces a service's directory with its held update, if any. Returns
+/// whether there was one.
+pub async fn approve_held(services_path: &Path, name: &str) -> io::Result<bool> {
+  let held = held_path(services_path, name);
+  if !held.exists() {
+    return Ok(false);
+  }
+  let staging = sibling(services_path, name, "new");
+  if staging.exists() {
+    fs::remove_dir_all(&staging).await?;
+  }
+
+  // Journaled before moving, so that a crash in between does not discard it
+  let journal = sibling(services_path, name, "journal");
+  write_atomic(&journal, name.as_bytes()).await?;
+  fs::rename(&held, &staging).await?;
+  swap(services_path, name).await?;
+  fs::remove_file(&journal).await?;
+  sync(services_path).await?;
+  Ok(true)
+}
+
+async fn swap(services_path: &Path, name: &str) -> io::Result<()> {
+  let path = services_path.join(name);
+  let staging = sibling(services_path, name, "new");
+  let old = sibling(services_path, name, "old");
+  if staging.exists() {
+    if path.exists() {
+      if old.exists() {
+        fs::remove_dir_all(&old).await?;
+      }
+      fs::rename(&path, &old).await?;
+    }
+    fs::rename(&staging, &path).await?;
+  }
+  if old.exists() {
+    fs::remove_dir_all(&old).await?;
+  }
+  Ok(())
+}
+
+/// Removes a service's directory.
+pub async fn remove(services_path: &Path, name: &str) -> io::Result<()> {
+  let removed = sibling(services_path, name, "removed");
+  if removed.exists() {
+    fs::remove_dir_all(&removed).await?;
+  }
+  fs::rename(services_path.join(name), &removed).await?;
+  sync(services_path).await?;
+  fs::remove_dir_all(&removed).await?;
+  let held = held_path(services_path, name);
+  if held.exists() {
+    fs::remove_dir_all(&held).await?;
+  }
+  Ok(())
+}
+
+/// Finishes or rolls back deploys and removals interrupted by a crash.
+pub async fn recover(services_path: &Path) -> io::Result<()> {
+  let mut names = Vec::new();
+  let mut entries = fs::read_dir(services_path).await?;
+  while let Some(entry) = entries.next_entry().await? {
+    let file_name = entry.file_name().to_string_lossy().into_owned();
+    if file_name.ends_with(".tmp") {
+      fs::remove_file(entry.path()).await?;
+    } else if let Some(rest) = file_name.strip_prefix('.') {
+      if let Some((name, _)) = rest.rsplit_once('.') {
+        if !names.iter().any(|x| x == name) {
+          names.push(name.to_owned());
+        }
+      }
+    }
+  }
+
+  for name in names {
+    let journal = sibling(services_path, &name, "journal");
+    if journal.exists() {
+      info!("Finishing interrupted deploy of service '{name}'");
+      swap(services_path, &name).await?;
+      fs::remove_file(&journal).await?;
+    } else {
+      let staging = sibling(services_path, &name, "new");
+      if staging.exists() {
+        warn!("Discarding incomplete deploy of service '{name}'");
+        fs::remove_dir_all(&staging).await?;
+      }
+      let old = sibling(services_path, &name, "old");
+      if old.exists() && !services_path.join(&name).exists() {
+        fs::rename(&old, services_path.join(&name)).await?;
+      } else if old.exists() {
+        fs::remove_dir_all(&old).await?;
+      }
+    }
+    let removed = sibling(services_path, &name, "removed");
+    if removed.exists() {
+      fs::remove_dir_all(&removed).await?;
+    }
+  }
+  Ok(())
+}
+
+/// Writes a file by renaming a complete temporary one over it, so that it is
+/// never seen half-written.
+pub async fn write_atomic(path: &Path, contents: impl AsRef<[u8]>) -> io::Result<()> {
+  let mut temp_name = path.file_name().unwrap_or_default().to_os_string();
+  temp_name.push(".tmp");
+  let temp_path = path.with_file_name(temp_name);
+  fs::write(&temp_path, contents).await?;
+  File::open(&temp_path).await?.sync_all().await?;
+  fs::rename(&temp_path, path).await
+}
+
+/// Flushes a directory and the files in it to disk.
+async fn sync_dir(path: &Path) -> io::Result<()> {
+  let mut entries = fs::read_dir(path).await?;
+  while let Some(entry) = entries.next_entry().await? {
+    sync(&entry.path()).await?;
+  }
+  sync(path).await
+}
+
+/// Flushes a file or, on Unix, a directory to disk.
+async fn sync(path: &Path) -> io::Result<()> {
+  if cfg!(unix) || path.is_file() {
+    File::open(path).await?.sync_all().await?;
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use tempfile::TempDir;
+
+  fn read(path: impl AsRef<Path>) -> String {
+    std::fs::read_to_string(path).unwrap()
+  }
+
+  #[tokio::test]
+  async fn test_commit() -> io::Result<()> {
+    let dir = TempDir::new()?;
+    let services_path = dir.path();
+    std::fs::create_dir(services_path.join("svc"))?;
+    std::fs::write(services_path.join("svc/source.lua"), "v1")?;
+    std::fs::write(services_path.join("svc/metadata.json"), "{}")?;
+
+    let staging = stage_copy(services_path, "svc").await?;
+    std::fs::write(staging.join("source.lua"), "v2")?;
+    // Untouched until committed
+    assert_eq!(read(services_path.join("svc/source.lua")), "v1");
+    commit(services_path, "svc").await?;
+    assert_eq!(read(services_path.join("svc/source.lua")), "v2");
+    assert_eq!(read(services_path.join("svc/metadata.json")), "{}");
+
+    stage(services_path, "svc", &[]).await?;
+    discard(services_path, "svc").await?;
+    assert_eq!(std::fs::read_dir(services_path)?.count(), 1);
+    Ok(())
+  }
+
+  #[tokio::test]
+  async fn test_recover() -> io::Result<()> {
+    let dir = TempDir::new()?;
+    let services_path = dir.path();
+    let write = |path: &str, content: &str| {
+      let path = services_path.join(path);
+      std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+      std::fs::write(path, content).unwrap();
+    };
+
+    // Journaled after the old directory was moved aside
+    write(".a.old/source.lua", "a1");
+    write(".a.new/source.lua", "a2");
+    write(".a.journal", "a");
+    // Not journaled, so discarded
+    write("b/source.lua", "b1");
+    write(".b.new/source.lua", "b2");
+    // Interrupted after the old directory was moved aside, but not journaled
+    write(".c.old/source.lua", "c1");
+    // Interrupted removal
+    write(".d.removed/source.lua", "d1");
+    write("metadata.json.tmp", "");
+
+    recover(services_path).await?;
+    assert_eq!(read(services_path.join("a/source.lua")), "a2");
+    assert_eq!(read(services_path.join("b/source.lua")), "b1");
+    assert_eq!(read(services_path.join("c/source.lua")), "c1");
+    let mut entries = std::fs::read_dir(services_path)?
+      .map(|x| x.unwrap().file_name().into_string().unwrap())
+      .collect::<Vec<_>>();
+    entries.sort();
+    assert_eq!(entries, ["a", "b", "c"]);
+    Ok(())
+  }
+}
@@ -0,0 +1,100 @@
+use super::config::{Config, HttpConfig};
+use hyper::server::conn::AddrIncoming;
+use hyper::server::Builder;
+use log::info;
+use std::io;
+use std::net::TcpListener;
+use std::time::Duration;
+
+/// Creates the listener of the server.
+///
+/// A socket passed by systemd (socket activation) is preferred over binding a
+/// new one. Otherwise, when `reuse_port` is enabled, the socket is bound with
+/// `SO_REUSEPORT`, so that a new server process can start listening on the same
+/// address while the old one drains its connections after `SIGTERM`.
+pub fn bind(config: &Config) -> io::Result<TcpListener> {
+  #[cfg(unix)]
+  if let Some(listener) = from_systemd()? {
+    info!("Using socket passed by systemd");
+    return Ok(listener);
+  }
+  if config.reuse_port {
+    bind_reuse_port(config)
+  } else {
+    TcpListener::bind(config.listen)
+  }
+}
+
+/// Applies protocol options to the server.
+pub fn tune(mut builder: Builder<AddrIncoming>, http: &HttpConfig) -> Builder<AddrIncoming> {
+  let secs = Duration::from_secs;
+  if !http.http2 {
+    builder = builder.http1_only(true);
+  }
+  builder = builder
+    .http1_keepalive(http.http1_keep_alive)
+    .http2_max_concurrent_streams(http.http2_max_concurrent_streams)
+    .http2_keep_alive_interval(http.http2_keep_alive_interval.map(secs))
+    .tcp_keepalive(http.tcp_keep_alive.map(secs));
+  if let Some(timeout) = http.http2_keep_alive_timeout {
+    builder = builder.http2_keep_alive_timeout(secs(timeout));
+  }
+  if let Some(timeout) = http.http1_header_read_timeout {
+    builder = builder.http1_header_read_timeout(secs(timeout));
+  }
+  if let Some(size) = http.max_header_size {
+    // hyper panics on buffers smaller than this
+    builder = builder.http1_max_buf_size(size.max(8192));
+  }
+  builder
+}
+
+#[cfg(unix)]
+fn bind_reuse_port(config: &Config) -> io::Result<TcpListener> {
+  use tokio::net::TcpSocket;
+
+  let socket = match config.listen {
+    std::net::SocketAddr::V4(_) => TcpSocket::new_v4()?,
+    std::net::SocketAddr::V6(_) => TcpSocket::new_v6()?,
+  };
+  socket.set_reuseaddr(true)?;
+  socket.set_reuseport(true)?;
+  socket.bind(config.listen)?;
+  socket.listen(1024)?.into_std()
+}
+
+#[cfg(windows)]
+fn bind_reuse_port(config: &Config) -> io::Result<TcpListener> {
+  log::warn!("`reuse_port` is not supported on Windows; ignoring");
+  TcpListener::bind(config.listen)
+}
+
+/// Takes the first socket passed with the systemd socket activation protocol.
+///
+/// See `sd_listen_fds(3)`.
+#[cfg(unix)]
+fn from_systemd() -> io::Result<Option<TcpListener>> {
+  use std::os::unix::io::FromRawFd;
+
+  const SD_LISTEN_FDS_START: i32 = 3;
+
+  let pid_matches = (std::env::var("LISTEN_PID").ok())
+    .and_then(|x| x.parse::<u32>().ok())
+    .map(|x| x == std::process::id())
+    .unwrap_or(false);
+  let num_fds = (std::env::var("LISTEN_FDS").ok())
+    .and_then(|x| x.parse::<u32>().ok())
+    .unwrap_or(0);
+  if !pid_matches || num_fds == 0 {
+    return Ok(None);
+  }
+  // Not to be inherited by child processes
+  std::env::remove_var("LISTEN_PID");
+  std::env::remove_var("LISTEN_FDS");
+  std::env::remove_var("LISTEN_FDNAMES");
+
+  // SAFETY: systemd guarantees the file descriptor is open and owned by us.
+  let listener = unsafe { TcpListener::from_raw_fd(SD_LISTEN_FDS_START) };
+  listener.set_nonblocking(true)?;
+  Ok(Some(listener))
+}
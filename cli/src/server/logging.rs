@@ -0,0 +1,452 @@
+//! Server logging to the console, and to syslog, journald or files set in the
+//! server config.
+//!
+//! The console logger is installed at startup and follows `RUST_LOG`. Other
+//! targets are added with [`configure`] once the config is loaded.
+
+use abel_core::civil_from_days;
+use log::{LevelFilter, Log, Metadata, Record};
+use once_cell::sync::OnceCell;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const IDENTIFIER: &str = "abel";
+
+static TARGETS: OnceCell<Vec<Target>> = OnceCell::new();
+
+/// Destination of log records, with its own levels.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LogTarget {
+  #[serde(flatten)]
+  pub output: LogOutput,
+  /// Most verbose level written. Defaults to `info`.
+  #[serde(default = "default_level")]
+  pub level: LogLevel,
+  /// Levels of records whose target starts with a key, overriding `level`.
+  /// The longest matching key applies.
+  #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+  pub modules: BTreeMap<String, LogLevel>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum LogOutput {
+  /// Local syslog daemon, through its Unix socket.
+  Syslog {
+    /// Defaults to `daemon`.
+    #[serde(default = "default_facility")]
+    facility: String,
+    /// Defaults to `/dev/log`.
+    #[serde(default = "default_syslog_socket")]
+    socket: PathBuf,
+  },
+  /// systemd's journal, with the target in the `ABEL_TARGET` field.
+  Journald,
+  /// File rotated to `<path>.1`, `<path>.2` and so on when it grows larger
+  /// than `max_size` bytes, keeping `keep` old files.
+  File {
+    path: PathBuf,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    max_size: Option<u64>,
+    #[serde(default = "default_keep")]
+    keep: usize,
+  },
+}
+
+impl LogOutput {
+  /// File written to, if any.
+  pub fn file_path(&self) -> Option<&Path> {
+    match self {
+      Self::File { path, .. } => Some(path),
+      _ => None,
+    }
+  }
+}
+
+/// Level filter written as in `RUST_LOG`, e.g. `warn` or `off`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LogLevel(pub LevelFilter);
+
+impl Serialize for LogLevel {
+  fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&self.0.as_str().to_ascii_lowercase())
+  }
+}
+
+impl<'de> Deserialize<'de> for LogLevel {
+  fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+    let s = String::deserialize(deserializer)?;
+    s.parse().map(Self).map_err(serde::de::Error::custom)
+  }
+}
+
+fn default_level() -> LogLevel {
+  LogLevel(LevelFilter::Info)
+}
+
+fn default_facility() -> String {
+  "daemon".into()
+}
+
+fn default_syslog_socket() -> PathBuf {
+  "/dev/log".into()
+}
+
+fn default_keep() -> usize {
+  5
+}
+
+/// Installs the console logger.
+pub fn init() {
+  if option_env!("RUST_LOG").is_none() {
+    std::env::set_var("RUST_LOG", "INFO");
+  }
+  let mut builder = pretty_env_logger::formatted_builder();
+  if let Ok(filters) = std::env::var("RUST_LOG") {
+    builder.parse_filters(&filters);
+  }
+  let console = builder.build();
+  log::set_max_level(console.filter());
+  log::set_boxed_logger(Box::new(Logger {
+    console: Box::new(console),
+  }))
+  .unwrap();
+}
+
+/// Starts writing to `targets` in addition to the console. Only the first
+/// call takes effect.
+pub fn configure(targets: &[LogTarget]) -> io::Result<()> {
+  if targets.is_empty() {
+    return Ok(());
+  }
+  let targets = targets
+    .iter()
+    .map(Target::open)
+    .collect::<io::Result<Vec<_>>>()?;
+  let max_level = (targets.iter())
+    .flat_map(|x| x.modules.iter().map(|(_, level)| *level).chain([x.level]))
+    .fold(log::max_level(), LevelFilter::max);
+  if TARGETS.set(targets).is_ok() {
+    log::set_max_level(max_level);
+  }
+  Ok(())
+}
+
+struct Logger {
+  console: Box<dyn Log>,
+}
+
+impl Log for Logger {
+  fn enabled(&self, metadata: &Metadata) -> bool {
+    self.console.enabled(metadata) || targets().any(|x| x.enabled(metadata))
+  }
+
+  fn log(&self, record: &Record) {
+    self.console.log(record);
+    for target in targets().filter(|x| x.enabled(record.metadata())) {
+      // Failing to log cannot be logged
+      let _ = target.write(record);
+    }
+  }
+
+  fn flush(&self) {
+    self.console.flush();
+    for target in targets() {
+      if let Writer::File(file) = &target.writer {
+        let _ = file.lock().unwrap().file.flush();
+      }
+    }
+  }
+}
+
+fn targets() -> impl Iterator<Item = &'static Target> {
+  TARGETS.get().into_iter().flatten()
+}
+
+struct Target {
+  level: LevelFilter,
+  /// Sorted by length, longest first.
+  modules: Vec<(String, LevelFilter)>,
+  writer: Writer,
+}
+
+enum Writer {
+  #[cfg(unix)]
+  Syslog {
+    socket: std::os::unix::net::UnixDatagram,
+    facility: u8,
+  },
+  #[cfg(unix)]
+  Journald(std::os::unix::net::UnixDatagram),
+  File(Mutex<RotatingFile>),
+}
+
+impl Target {
+  fn open(config: &LogTarget) -> io::Result<Self> {
+    let mut modules = (config.modules.iter())
+      .map(|(name, level)| (name.clone(), level.0))
+      .collect::<Vec<_>>();
+    modules.sort_by(|a, b| b.0.len().cmp(&a.0.len()));
+    let writer = match &config.output {
+      #[cfg(unix)]
+      LogOutput::Syslog { facility, socket } => {
+        let facility = syslog_facility(facility).ok_or_else(|| {
+          let msg = format!("unknown syslog facility '{facility}'");
+          io::Error::new(io::ErrorKind::InvalidInput, msg)
+        })?;
+        let datagram = std::os::unix::net::UnixDatagram::unbound()?;
+        datagram.connect(socket)?;
+        Writer::Syslog {
+          socket: datagram,
+          facility,
+        }
+      }
+      #[cfg(unix)]
+      LogOutput::Journald => {
+        let datagram = std::os::unix::net::UnixDatagram::unbound()?;
+        datagram.connect("/run/systemd/journal/socket")?;
+        Writer::Journald(datagram)
+      }
+      #[cfg(not(unix))]
+      LogOutput::Syslog { .. } | LogOutput::Journald => {
+        let msg = "syslog and journald are only supported on Unix";
+        return Err(io::Error::new(io::ErrorKind::Unsupported, msg));
+      }
+      LogOutput::File {
+        path,
+        max_size,
+        keep,
+      } => Writer::File(Mutex::new(RotatingFile::open(
+        path.clone(),
+        *max_size,
+        *keep,
+      )?)),
+    };
+    Ok(Self {
+      level: config.level.0,
+      modules,
+      writer,
+    })
+  }
+
+  fn enabled(&self, metadata: &Metadata) -> bool {
+    let level = (self.modules.iter())
+      .find(|(name, _)| metadata.target().starts_with(&**name))
+      .map(|(_, level)| *level)
+      .unwrap_or(self.level);
+    metadata.level() <= level
+  }
+
+  fn write(&self, record: &Record) -> io::Result<()> {
+    let target = record.target();
+    let message = record.args().to_string();
+    match &self.writer {
+      #[cfg(unix)]
+      Writer::Syslog { socket, facility } => {
+        let priority = facility * 8 + severity(record.level());
+        let pid = std::process::id();
+        let line = format!("<{priority}>{IDENTIFIER}[{pid}]: {target}: {message}");
+        socket.send(line.as_bytes()).map(|_| ())
+      }
+      #[cfg(unix)]
+      Writer::Journald(socket) => {
+        let mut datagram = Vec::new();
+        let priority = severity(record.level()).to_string();
+        for (key, value) in [
+          ("MESSAGE", &*message),
+          ("PRIORITY", &priority),
+          ("SYSLOG_IDENTIFIER", IDENTIFIER),
+          ("ABEL_TARGET", target),
+        ] {
+          journald_field(&mut datagram, key, value);
+        }
+        socket.send(&datagram).map(|_| ())
+      }
+      Writer::File(file) => {
+        let line = format!(
+          "{} {:<5} {target}: {message}\n",
+          format_time(SystemTime::now()),
+          record.level()
+        );
+        file.lock().unwrap().write(line.as_bytes())
+      }
+    }
+  }
+}
+
+#[cfg(unix)]
+fn severity(level: log::Level) -> u8 {
+  use log::Level::*;
+  match level {
+    Error => 3,
+    Warn => 4,
+    Info => 6,
+    Debug | Trace => 7,
+  }
+}
+
+#[cfg(unix)]
+fn syslog_facility(name: &str) -> Option<u8> {
+  const FACILITIES: &[&str] = &[
+    "kern", "user", "mail", "daemon", "auth", "syslog", "lpr", "news", "uucp", "cron", "authpriv",
+    "ftp",
+  ];
+  match FACILITIES.iter().position(|x| *x == name) {
+    Some(i) => Some(i as u8),
+    None => {
+      let n = name.strip_prefix("local")?.parse::<u8>().ok()?;
+      (n < 8).then(|| 16 + n)
+    }
+  }
+}
+
+/// Appends a field in journald's native protocol, using the binary form for
+/// values containing newlines.
+#[cfg(unix)]
+fn journald_field(datagram: &mut Vec<u8>, key: &str, value: &str) {
+  datagram.extend_from_slice(key.as_bytes());
+  if value.contains('\n') {
+    datagram.push(b'\n');
+    datagram.extend_from_slice(&(value.len() as u64).to_le_bytes());
+  } else {
+    datagram.push(b'=');
+  }
+  datagram.extend_from_slice(value.as_bytes());
+  datagram.push(b'\n');
+}
+
+/// Formats as `YYYY-MM-DDTHH:MM:SS.mmmZ`.
+fn format_time(time: SystemTime) -> String {
+  let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+  let secs = since_epoch.as_secs() as i64;
+  let (days, secs_of_day) = (secs.div_euclid(86400), secs.rem_euclid(86400));
+  let (year, month, day) = civil_from_days(days);
+
+  format!(
+    "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}.{:03}Z",
+    secs_of_day / 3600,
+    secs_of_day / 60 % 60,
+    secs_of_day % 60,
+    since_epoch.subsec_millis()
+  )
+}
+
+struct RotatingFile {
+  path: PathBuf,
+  max_size: Option<u64>,
+  keep: usize,
+  file: File,
+  size: u64,
+}
+
+impl RotatingFile {
+  fn open(path: PathBuf, max_size: Option<u64>, keep: usize) -> io::Result<Self> {
+    let file = OpenOptions::new().create(true).append(true).open(&path)?;
+    let size = file.metadata()?.len();
+    Ok(Self {
+      path,
+      max_size,
+      keep,
+      file,
+      size,
+    })
+  }
+
+  fn rotated_path(&self, n: usize) -> PathBuf {
+    let mut path = self.path.clone().into_os_string();
+    path.push(format!(".{n}"));
+    path.into()
+  }
+
+  fn write(&mut self, line: &[u8]) -> io::Result<()> {
+    if let Some(max_size) = self.max_size {
+      if self.size > 0 && self.size + line.len() as u64 > max_size {
+        self.rotate()?;
+      }
+    }
+    self.file.write_all(line)?;
+    self.size += line.len() as u64;
+    Ok(())
+  }
+
+  fn rotate(&mut self) -> io::Result<()> {
+    if self.keep == 0 {
+      self.file.set_len(0)?;
+    } else {
+      for n in (1..self.keep).rev() {
+        let from = self.rotated_path(n);
+        if from.exists() {
+          fs::rename(from, self.rotated_path(n + 1))?;
+        }
+      }
+      fs::rename(&self.path, self.rotated_path(1))?;
+      self.file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&self.path)?;
+    }
+    self.size = 0;
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::time::Duration;
+  use tempfile::TempDir;
+  use test_case::test_case;
+
+  #[test_case(0 => "1970-01-01T00:00:00.000Z"; "epoch")]
+  #[test_case(951_782_400_000 => "2000-02-29T00:00:00.000Z"; "leap day")]
+  #[test_case(1_700_000_000_123 => "2023-11-14T22:13:20.123Z"; "millis")]
+  fn test_format_time(millis: u64) -> String {
+    format_time(UNIX_EPOCH + Duration::from_millis(millis))
+  }
+
+  #[cfg(unix)]
+  #[test_case("kern" => Some(0))]
+  #[test_case("ftp" => Some(11))]
+  #[test_case("local0" => Some(16))]
+  #[test_case("local7" => Some(23))]
+  #[test_case("local8" => None)]
+  #[test_case("Daemon" => None)]
+  fn test_syslog_facility(name: &str) -> Option<u8> {
+    syslog_facility(name)
+  }
+
+  #[test]
+  fn test_rotation() -> io::Result<()> {
+    let dir = TempDir::new()?;
+    let path = dir.path().join("abel.log");
+    let mut file = RotatingFile::open(path.clone(), Some(8), 2)?;
+    for line in ["one\n", "two\n", "three\n", "four\n", "five\n"] {
+      file.write(line.as_bytes())?;
+    }
+    assert_eq!(fs::read_to_string(&path)?, "five\n");
+    assert_eq!(fs::read_to_string(dir.path().join("abel.log.1"))?, "four\n");
+    assert_eq!(
+      fs::read_to_string(dir.path().join("abel.log.2"))?,
+      "three\n"
+    );
+    assert!(!dir.path().join("abel.log.3").exists());
+    Ok(())
+  }
+
+  #[test]
+  fn test_rotation_keep_none() -> io::Result<()> {
+    let dir = TempDir::new()?;
+    let path = dir.path().join("abel.log");
+    let mut file = RotatingFile::open(path.clone(), Some(8), 0)?;
+    file.write(b"one\n")?;
+    file.write(b"three\n")?;
+    assert_eq!(fs::read_to_string(&path)?, "three\n");
+    assert!(!dir.path().join("abel.log.1").exists());
+    Ok(())
+  }
+}
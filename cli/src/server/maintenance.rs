@@ -0,0 +1,166 @@
+use super::handle::serve_error_page;
+use super::{json_response, json_response_raw, Result, ServerState};
+use hyper::header::RETRY_AFTER;
+use hyper::{Body, Request, Response, StatusCode};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Mutex;
+use tokio::{fs, io};
+
+/// Maintenance mode of a service, kept across restarts.
+const STATE_FILE: &str = "maintenance.json";
+
+/// How a service in maintenance responds.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MaintenanceMode {
+  /// Message in the JSON error body.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub message: Option<String>,
+  /// Path to a static page in the source, served in place of the JSON body.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub page: Option<String>,
+  /// Path still routed to the service, e.g. for health checks.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub health_path: Option<String>,
+  /// Seconds clients are told to wait before retrying.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub retry_after: Option<u64>,
+}
+
+/// Services in maintenance. They stay loaded, but requests are answered with
+/// 503 without reaching them.
+#[derive(Default)]
+pub struct Maintenance {
+  services: Mutex<HashMap<String, MaintenanceMode>>,
+}
+
+impl Maintenance {
+  /// Maintenance mode of a service, if `sub_path` is not excluded from it.
+  pub fn get(&self, service_name: &str, sub_path: &str) -> Option<MaintenanceMode> {
+    (self.services.lock().unwrap().get(service_name))
+      .filter(|x| x.health_path.as_deref() != Some(sub_path))
+      .cloned()
+  }
+
+  pub fn mode(&self, service_name: &str) -> Option<MaintenanceMode> {
+    self.services.lock().unwrap().get(service_name).cloned()
+  }
+
+  pub fn remove_service(&self, service_name: &str) {
+    self.services.lock().unwrap().remove(service_name);
+  }
+}
+
+pub async fn get(state: &ServerState, name: &str) -> Result<Response<Body>> {
+  state.abel.get_service(name)?;
+  let mode = state.maintenance.mode(name);
+  json_response(
+    StatusCode::OK,
+    json!({ "service": name, "maintenance": mode }),
+  )
+}
+
+/// Puts a service into maintenance, or updates its maintenance mode.
+pub async fn enable(
+  state: &ServerState,
+  name: String,
+  req: Request<Body>,
+) -> Result<Response<Body>> {
+  state.abel.get_service(&name)?;
+  let body = hyper::body::to_bytes(req.into_body())
+    .await
+    .map_err(|error| (400, "failed to read request body", error.to_string()))?;
+  let mode: MaintenanceMode = if body.is_empty() {
+    Default::default()
+  } else {
+    serde_json::from_slice(&body)
+      .map_err(|error| ("invalid maintenance mode", error.to_string()))?
+  };
+  if matches!(&mode.health_path, Some(path) if !path.starts_with('/')) {
+    return Err("health path must start with '/'".into());
+  }
+
+  (state.maintenance.services.lock().unwrap()).insert(name.clone(), mode.clone());
+  persist(state, &name, &state.abel_path.join("services").join(&name)).await?;
+
+  info!("Put service '{name}' into maintenance");
+  json_response(
+    StatusCode::OK,
+    json!({ "service": name, "maintenance": mode }),
+  )
+}
+
+pub async fn disable(state: &ServerState, name: &str) -> Result<Response<Body>> {
+  state.abel.get_service(name)?;
+  let service_path = state.abel_path.join("services").join(name);
+  match fs::remove_file(service_path.join(STATE_FILE)).await {
+    Err(error) if error.kind() != io::ErrorKind::NotFound => return Err(error.into()),
+    _ => {}
+  }
+  let mode = state.maintenance.services.lock().unwrap().remove(name);
+
+  if mode.is_some() {
+    info!("Took service '{name}' out of maintenance");
+  }
+  json_response(
+    StatusCode::OK,
+    json!({ "service": name, "maintenance": null }),
+  )
+}
+
+/// Saves the maintenance mode of a service, if any, e.g. after its folder is
+/// recreated on update.
+pub async fn persist(state: &ServerState, name: &str, service_path: &Path) -> io::Result<()> {
+  match state.maintenance.mode(name) {
+    Some(mode) => fs::write(service_path.join(STATE_FILE), serde_json::to_string(&mode)?).await,
+    None => Ok(()),
+  }
+}
+
+/// Applies a previously enabled maintenance mode, if any.
+pub async fn restore(state: &ServerState, name: &str, service_path: &Path) -> anyhow::Result<()> {
+  match fs::read(service_path.join(STATE_FILE)).await {
+    Ok(bytes) => {
+      let mode = serde_json::from_slice(&bytes)?;
+      (state.maintenance.services.lock().unwrap()).insert(name.into(), mode);
+      Ok(())
+    }
+    Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(()),
+    Err(error) => Err(error.into()),
+  }
+}
+
+/// Answers a request to a service in maintenance.
+pub async fn respond(state: &ServerState, name: &str, mode: &MaintenanceMode) -> Response<Body> {
+  let source = (state.abel.get_service(name).ok()).map(|x| x.upgrade().source().clone());
+  let page = match (&mode.page, source) {
+    (Some(path), Some(source)) => {
+      match serve_error_page(&source, path, StatusCode::SERVICE_UNAVAILABLE).await {
+        Ok(resp) => Some(resp),
+        Err(error) => {
+          warn!("failed to serve maintenance page '{path}' of service '{name}': {error}");
+          None
+        }
+      }
+    }
+    _ => None,
+  };
+  let mut resp = page.unwrap_or_else(|| {
+    let msg = (mode.message.as_deref()).unwrap_or("service is under maintenance");
+    json_response_raw(
+      StatusCode::SERVICE_UNAVAILABLE,
+      json!({
+        "error": "service under maintenance",
+        "detail": { "msg": msg, "service": name },
+      }),
+    )
+  });
+  if let Some(secs) = mode.retry_after {
+    resp.headers_mut().insert(RETRY_AFTER, secs.into());
+  }
+  resp
+}
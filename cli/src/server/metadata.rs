@@ -1,30 +1,225 @@
-use super::Result;
+use super::layout::write_atomic;
+use abel_core::Permissions;
+use futures::Future;
 use serde::{Deserialize, Serialize};
-use std::path::Path;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
 use tokio::{fs, io};
 use uuid::Uuid;
 
+/// Number of earlier deployments kept in a service's history.
+const MAX_HISTORY: usize = 20;
+
 /// Extra information of a loaded service.
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Metadata {
   pub uuid: Uuid,
   pub started: bool,
+  /// Base URL in object storage, if the source is not stored locally.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub remote: Option<String>,
+  /// Permissions approved for the service. Updates requesting more are held
+  /// until approved again. Whatever requested is allowed if not set.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub granted: Option<Permissions>,
+  /// Whether the service is held until its permissions are approved.
+  /// Otherwise it runs restricted to `granted`.
+  #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+  pub pending_approval: bool,
+  /// Content hash of the source, if computed when uploaded.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub hash: Option<String>,
+  /// Unix time in seconds the source was uploaded.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub deployed_at: Option<u64>,
+  /// Incremented on every change, identifying the metadata in ETags.
+  #[serde(default)]
+  pub revision: u64,
+  /// Who the service belongs to, as given when uploading it.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub owner: Option<String>,
+  /// Unix time in seconds the service was first deployed.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub created_at: Option<u64>,
+  /// Earlier deployments, the most recent last.
+  #[serde(default, skip_serializing_if = "Vec::is_empty")]
+  pub history: Vec<Deployment>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Deployment {
+  pub uuid: Uuid,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub hash: Option<String>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub deployed_at: Option<u64>,
 }
 
 impl Metadata {
-  pub async fn read(path: &Path) -> io::Result<Self> {
-    let metadata_bytes = fs::read(&path).await?;
-    Ok(serde_json::from_slice(&metadata_bytes)?)
+  /// Carries over what outlives a deploy from the metadata of the version
+  /// being replaced, recording it in the history.
+  pub fn succeed(&mut self, previous: Metadata) {
+    self.revision = previous.revision + 1;
+    self.owner = self.owner.take().or(previous.owner);
+    self.created_at = previous.created_at.or(self.created_at);
+    self.history = previous.history;
+    self.history.push(Deployment {
+      uuid: previous.uuid,
+      hash: previous.hash,
+      deployed_at: previous.deployed_at,
+    });
+    if self.history.len() > MAX_HISTORY {
+      self.history.drain(..self.history.len() - MAX_HISTORY);
+    }
+  }
+
+  /// Writes the metadata into a service's directory, e.g. a staged one to be
+  /// committed along with its source.
+  pub async fn write(&self, service_path: &Path) -> io::Result<()> {
+    let path = service_path.join("metadata.json");
+    write_atomic(&path, serde_json::to_vec(self)?).await
+  }
+}
+
+/// Keeps metadata as `metadata.json` in each service's directory, so that it
+/// is swapped along with the service's source.
+///
+/// Modifications of one service's metadata are applied one at a time, so
+/// that concurrent ones are not lost.
+pub struct MetadataStore {
+  services_path: PathBuf,
+  /// Locks of services whose metadata is being changed.
+  locks: Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>,
+}
+
+impl MetadataStore {
+  pub fn new(services_path: impl Into<PathBuf>) -> Self {
+    Self {
+      services_path: services_path.into(),
+      locks: Default::default(),
+    }
   }
 
-  pub async fn write(&self, path: &Path) -> io::Result<()> {
-    fs::write(path, serde_json::to_string(self)?).await
+  pub async fn get(&self, name: &str) -> io::Result<Metadata> {
+    let path = self.services_path.join(name).join("metadata.json");
+    Ok(serde_json::from_slice(&fs::read(path).await?)?)
   }
 
-  pub async fn modify(path: &Path, f: impl FnOnce(&mut Self)) -> Result<()> {
-    let mut metadata: Metadata = serde_json::from_slice(&fs::read(path).await?)?;
-    f(&mut metadata);
-    fs::write(path, serde_json::to_vec(&metadata)?).await?;
+  /// Replaces the metadata of a service as is.
+  pub async fn put(&self, name: &str, metadata: &Metadata) -> io::Result<()> {
+    (self.locked(name, metadata.write(&self.services_path.join(name)))).await
+  }
+
+  /// Modifies the metadata of a service, incrementing its revision.
+  pub async fn modify(
+    &self,
+    name: &str,
+    f: &mut (dyn FnMut(&mut Metadata) + Send),
+  ) -> io::Result<Metadata> {
+    self
+      .locked(name, async {
+        let mut metadata = self.get(name).await?;
+        f(&mut metadata);
+        metadata.revision += 1;
+        metadata.write(&self.services_path.join(name)).await?;
+        Ok(metadata)
+      })
+      .await
+  }
+
+  /// Runs `f` with the service's metadata locked, forgetting the lock once
+  /// nobody else waits for it.
+  async fn locked<T>(&self, name: &str, f: impl Future<Output = T>) -> T {
+    let lock = (self.locks.lock().unwrap())
+      .entry(name.into())
+      .or_default()
+      .clone();
+    let result = {
+      let _guard = lock.lock().await;
+      f.await
+    };
+    drop(lock);
+    let mut locks = self.locks.lock().unwrap();
+    if locks.get(name).map_or(false, |x| Arc::strong_count(x) == 1) {
+      locks.remove(name);
+    }
+    result
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use tempfile::TempDir;
+
+  fn metadata(uuid: Uuid, deployed_at: u64) -> Metadata {
+    Metadata {
+      uuid,
+      started: true,
+      remote: None,
+      granted: None,
+      pending_approval: false,
+      hash: None,
+      deployed_at: Some(deployed_at),
+      revision: 0,
+      owner: None,
+      created_at: Some(deployed_at),
+      history: Vec::new(),
+    }
+  }
+
+  #[test]
+  fn test_succeed() {
+    let mut previous = metadata(Uuid::new_v4(), 1);
+    previous.revision = 3;
+    previous.owner = Some("alice".into());
+    let previous_uuid = previous.uuid;
+
+    let mut current = metadata(Uuid::new_v4(), 2);
+    current.succeed(previous);
+    assert_eq!(current.revision, 4);
+    assert_eq!(current.owner.as_deref(), Some("alice"));
+    assert_eq!(current.created_at, Some(1));
+    assert_eq!(current.deployed_at, Some(2));
+    assert_eq!(current.history.len(), 1);
+    assert_eq!(current.history[0].uuid, previous_uuid);
+
+    for i in 0..MAX_HISTORY as u64 {
+      let mut next = metadata(Uuid::new_v4(), 3 + i);
+      next.succeed(current);
+      current = next;
+    }
+    assert_eq!(current.history.len(), MAX_HISTORY);
+    assert_eq!(current.created_at, Some(1));
+  }
+
+  #[tokio::test]
+  async fn test_modify() -> io::Result<()> {
+    let dir = TempDir::new()?;
+    std::fs::create_dir(dir.path().join("svc"))?;
+    let store = Arc::new(MetadataStore::new(dir.path()));
+    store.put("svc", &metadata(Uuid::new_v4(), 1)).await?;
+
+    // Concurrent modifications are not lost
+    let tasks = (0..10).map(|_| {
+      let store = store.clone();
+      tokio::spawn(async move {
+        (store.modify("svc", &mut |m| m.started = !m.started))
+          .await
+          .unwrap()
+      })
+    });
+    for result in futures::future::join_all(tasks).await {
+      result.unwrap();
+    }
+    let metadata = store.get("svc").await?;
+    assert_eq!(metadata.revision, 10);
+    assert!(metadata.started);
+    assert!(store.locks.lock().unwrap().is_empty());
+
+    let error = store.modify("other", &mut |_| {}).await.unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::NotFound);
     Ok(())
   }
 }
@@ -0,0 +1,79 @@
+//! Middlewares registered to Abel, applied to all service traffic.
+
+use super::upload::check_size;
+use abel_core::Middleware;
+use async_trait::async_trait;
+use futures::StreamExt;
+use hyper::header::CONTENT_LENGTH;
+use hyper::{Body, Request, Response};
+use std::io;
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Limits request bodies passed to services, configured by
+/// `http.max_request_body`.
+///
+/// Requests declaring a larger `Content-Length` are rejected with 413 before
+/// reaching the service. Otherwise reading the body fails once it exceeds the
+/// limit.
+pub struct BodyLimit(pub u64);
+
+#[async_trait]
+impl Middleware for BodyLimit {
+  async fn on_request(&self, _service: &str, req: &mut Request<Body>) -> Option<Response<Body>> {
+    let limit = self.0;
+    let declared =
+      (req.headers().get(CONTENT_LENGTH)).and_then(|x| x.to_str().ok()?.parse::<u64>().ok());
+    if let Err(error) = check_size("request body", declared.unwrap_or(0), limit) {
+      return Some(error.into());
+    }
+
+    let mut read = 0u64;
+    let body = std::mem::take(req.body_mut()).map(move |chunk| -> Result<_, BoxError> {
+      let chunk = chunk?;
+      read += chunk.len() as u64;
+      if read > limit {
+        let error = io::Error::new(io::ErrorKind::Other, "request body exceeds size limit");
+        return Err(error.into());
+      }
+      Ok(chunk)
+    });
+    *req.body_mut() = Body::wrap_stream(body);
+    None
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn request(body: &'static str, content_length: Option<usize>) -> Request<Body> {
+    let mut req = Request::builder();
+    if let Some(len) = content_length {
+      req = req.header(CONTENT_LENGTH, len);
+    }
+    req.body(body.into()).unwrap()
+  }
+
+  #[tokio::test]
+  async fn test_within_limit() {
+    let mut req = request("hello", Some(5));
+    assert!(BodyLimit(5).on_request("test", &mut req).await.is_none());
+    let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+    assert_eq!(body, "hello");
+  }
+
+  #[tokio::test]
+  async fn test_declared_too_large() {
+    let mut req = request("hello", Some(5));
+    let resp = BodyLimit(4).on_request("test", &mut req).await.unwrap();
+    assert_eq!(resp.status(), 413);
+  }
+
+  #[tokio::test]
+  async fn test_streamed_too_large() {
+    let mut req = request("hello", None);
+    assert!(BodyLimit(4).on_request("test", &mut req).await.is_none());
+    assert!(hyper::body::to_bytes(req.into_body()).await.is_err());
+  }
+}
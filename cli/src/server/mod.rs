@@ -1,32 +1,75 @@
 pub mod config;
+pub mod hardening;
+pub mod logging;
 pub mod metadata;
 pub mod types;
 pub mod upload;
 
+mod approval;
+mod audit;
+mod backup;
+mod bench;
+mod browse;
+mod cache;
+mod canary;
+mod capture;
+mod cluster;
+mod coordination;
+mod delta;
+mod encryption;
 mod error;
+mod etag;
+mod events;
 mod handle;
+mod hash;
+mod hooks;
+mod jobs;
+mod layout;
+mod listener;
+mod maintenance;
+mod middleware;
+mod redirect;
+mod reporting;
+mod schedule;
+mod suspend;
+mod ui;
 
 pub use error::JsonError;
 
-use crate::source::{AsarSource, SingleSource};
-use abel_core::service::Service;
+use crate::source::{AsarSource, ObjectSource, SingleSource};
+use abel_core::net::Cidr;
+use abel_core::service::{startup_order, Service, StartupOrder};
 use abel_core::source::Source;
-use abel_core::{Abel, AbelOptions};
-use anyhow::bail;
-use config::{Config, ServerArgs};
+use abel_core::{parse_headers, Abel, AbelOptions, Coordinator, ErrorKind, S3Credentials};
+use anyhow::{anyhow, bail};
+use audit::AuditLog;
+use backup::Backups;
+use cache::ResponseCache;
+use capture::Captures;
+use cluster::Cluster;
+use config::{Config, ServerArgs, UploadLimits};
 use error::Error;
+use futures::{stream, StreamExt};
 use handle::handle;
 use hive_asar::Archive;
+use hooks::Hooks;
+use hyper::server::conn::AddrStream;
 use hyper::service::{make_service_fn, service_fn};
-use hyper::{Body, Request, Response, Server, StatusCode};
+use hyper::{Body, HeaderMap, Request, Response, Server, StatusCode};
+use jobs::Jobs;
 use log::{error, info, warn};
-use metadata::Metadata;
+use maintenance::Maintenance;
+use metadata::{Metadata, MetadataStore};
+use middleware::BodyLimit;
 use owo_colors::OwoColorize;
+use reporting::Reporter;
 use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
 use std::convert::Infallible;
 use std::io;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::Instant;
 use tokio::fs;
 use tokio::io::AsyncReadExt;
 use uuid::Uuid;
@@ -37,20 +80,53 @@ pub struct ServerState {
   pub abel: Abel,
   pub abel_path: PathBuf,
   pub auth_token: Option<Uuid>,
+  pub audit: AuditLog,
+  pub captures: Captures,
+  pub maintenance: Maintenance,
+  pub metadata: MetadataStore,
+  pub cluster: Cluster,
+  pub hooks: Hooks,
+  pub jobs: Jobs,
+  pub reporter: Reporter,
+  pub backups: Option<Backups>,
+  pub object_storage: Option<S3Credentials>,
+  pub trusted_proxies: Vec<Cidr>,
+  pub require_if_match: bool,
+  /// Parsed `headers` of the config.
+  pub default_headers: HeaderMap,
+  pub cache: Option<ResponseCache>,
+  pub upload_limits: UploadLimits,
+  pub coordinator: Arc<dyn Coordinator>,
 }
 
 pub async fn run(config: Config, state: Arc<ServerState>) -> anyhow::Result<()> {
   let state2 = state.clone();
-  let make_svc = make_service_fn(move |_conn| {
+  let make_svc = make_service_fn(move |conn: &AddrStream| {
     let state = state2.clone();
-    async move { Ok::<_, Infallible>(service_fn(move |req| handle(state.clone(), req))) }
+    let remote_addr = conn.remote_addr();
+    async move {
+      Ok::<_, Infallible>(service_fn(move |req| {
+        handle(state.clone(), remote_addr, req)
+      }))
+    }
   });
 
-  let server = Server::bind(&config.listen)
+  let listener = listener::bind(&config)?;
+  let local_addr = listener.local_addr()?;
+  let server = listener::tune(Server::from_tcp(listener)?, &config.http)
     .serve(make_svc)
     .with_graceful_shutdown(shutdown_signal());
 
-  info!("Abel is listening to {}", config.listen.underline());
+  info!("Abel is listening to {}", local_addr.underline());
+
+  if state.cluster.is_enabled() {
+    tokio::spawn(state.cluster.clone().sync());
+  }
+  tokio::spawn(suspend::run(state.clone()));
+  tokio::spawn(schedule::run(state.clone()));
+  tokio::spawn(hooks::run(state.clone()));
+  tokio::spawn(reporting::run(state.clone()));
+  tokio::spawn(backup::run(state.clone()));
 
   if let Err(error) = server.await {
     error!("fatal server error: {}", error);
@@ -61,13 +137,6 @@ pub async fn run(config: Config, state: Arc<ServerState>) -> anyhow::Result<()>
   Ok(())
 }
 
-pub fn init_logger() {
-  if option_env!("RUST_LOG").is_none() {
-    std::env::set_var("RUST_LOG", "INFO");
-  }
-  pretty_env_logger::init();
-}
-
 pub async fn init_state(
   args: ServerArgs,
   init_config: Config,
@@ -76,15 +145,57 @@ pub async fn init_state(
 
   let (local_storage_path, remote_cache_path) = init_paths(&abel_path).await;
   let config = init_config.merge(config);
+  logging::configure(&config.logging)?;
+  let storage_key = match &config.storage_key {
+    Some(source) => {
+      let key = source.load().await?;
+      let count = abel_core::seal_local_storage(local_storage_path.clone(), key).await?;
+      if count > 0 {
+        info!("Encrypted {count} files in local storage");
+      }
+      Some(key)
+    }
+    None => None,
+  };
+
+  let coordinator = coordination::coordinator(config.coordinator.as_deref(), config.auth_token)?;
+
+  let mut abel = Abel::new(AbelOptions {
+    runtime_pool_size: config.pool_size(),
+    local_storage_path,
+    secrets_path: Some(abel_path.join("secrets")),
+    remote_cache_path: Some(remote_cache_path),
+    max_services: config.max_services,
+    max_running_services: config.max_running_services,
+    http_client: config.http_client.options(),
+    coordinator: Some(coordinator.clone()),
+    exec_allowlist: config.exec_allowlist.clone(),
+    storage_key,
+  })?;
+  if let Some(limit) = config.http.max_request_body {
+    abel.add_middleware(BodyLimit(limit));
+  }
 
   let state = Arc::new(ServerState {
-    abel: Abel::new(AbelOptions {
-      runtime_pool_size: config.pool_size(),
-      local_storage_path,
-      remote_cache_path: Some(remote_cache_path),
-    })?,
+    abel,
     abel_path: abel_path.clone(),
     auth_token: config.auth_token,
+    audit: AuditLog::new(abel_path.join("audit.log"), config.auth_token),
+    captures: Default::default(),
+    maintenance: Default::default(),
+    metadata: MetadataStore::new(abel_path.join("services")),
+    cluster: Cluster::new(config.peers.clone(), config.auth_token)?,
+    jobs: Jobs::load(abel_path.join("jobs")).await?,
+    hooks: Hooks::load(abel_path.join("hooks.json"), config.webhooks.clone()).await?,
+    reporter: Reporter::new(config.error_reporting.clone()),
+    backups: config.backup.as_ref().map(Backups::new),
+    object_storage: config.object_storage.clone(),
+    trusted_proxies: config.trusted_proxies.clone(),
+    require_if_match: config.require_if_match,
+    default_headers: parse_headers(&config.headers).map_err(|x| anyhow!("invalid headers: {x}"))?,
+    cache: config.response_cache.map(ResponseCache::new),
+    upload_limits: config.upload.clone(),
+    coordinator,
   });
   Ok((abel_path, config, state))
 }
@@ -107,6 +218,7 @@ async fn init_paths(abel_path: &Path) -> (PathBuf, PathBuf) {
   async {
     create_dir_path(abel_path).await?;
     create_dir_path(abel_path.join("services")).await?;
+    layout::recover(&abel_path.join("services")).await?;
 
     // Creates a fresh temporary folder
     let temp_dir = abel_path.join("tmp");
@@ -126,82 +238,276 @@ async fn init_paths(abel_path: &Path) -> (PathBuf, PathBuf) {
   .expect("failed to create Abel config directory")
 }
 
-pub async fn load_saved_services(state: &ServerState, services_path: &Path) -> anyhow::Result<()> {
+/// Service read from disk, waiting to be loaded.
+struct SavedService {
+  path: PathBuf,
+  metadata: Metadata,
+  source: Source,
+  config: abel_core::Config,
+}
+
+/// Loads services saved on disk, in dependency order.
+///
+/// Services not depending on each other are loaded concurrently, as many at a
+/// time as the size of the runtime pool. If `lazy_load` is enabled, services
+/// are not evaluated until they start; those that were running are started on
+/// their first request.
+pub async fn load_saved_services(
+  state: &ServerState,
+  services_path: &Path,
+  config: &Config,
+) -> anyhow::Result<()> {
+  let started_at = Instant::now();
   let mut services = fs::read_dir(services_path).await?;
+  let mut saved = BTreeMap::new();
 
   while let Some(service_folder) = services.next_entry().await? {
-    if service_folder.file_type().await?.is_dir() {
-      let name = service_folder.file_name().to_string_lossy().into_owned();
-      let result = async {
-        let metadata_path = service_folder.path().join("metadata.json");
-        let mut metadata = Metadata::read(&metadata_path).await?;
-
-        let asar_path = service_folder.path().join("source.asar");
-        let lua_path = service_folder.path().join("source.lua");
-
-        let (source, config) = match (asar_path.exists(), lua_path.exists()) {
-          (true, false) => {
-            let mut archive = Archive::new_from_file(asar_path).await?;
-
-            let config = if let Ok(mut config_file) = archive.get("abel.json").await {
-              let mut config_bytes = Vec::with_capacity(config_file.metadata().size as _);
-              config_file.read_to_end(&mut config_bytes).await?;
-              serde_json::from_slice(&config_bytes)?
-            } else {
-              Default::default()
-            };
-
-            let source = Source::new(AsarSource(archive));
-            (source, config)
-          }
-          (false, true) => {
-            let code = fs::read(lua_path).await?;
-            let source = Source::new(SingleSource::new(code));
-            (source, Default::default())
-          }
-          (true, true) => bail!("both source.asar and source.lua found"),
-          (false, false) => bail!("neither source.asar nor source.lua found"),
-        };
-
-        let (service, error_payload) = if metadata.started {
-          let (service, _, error_payload) = (state.abel)
-            .cold_update_or_create_service(name.clone(), Some(metadata.uuid), source, config)
-            .await?;
-          (service, error_payload)
-        } else {
-          let (service, error_payload) = (state.abel)
-            .preload_service(name.clone(), metadata.uuid, source, config)
-            .await?;
-          (Service::Stopped(service), error_payload)
-        };
-
-        metadata.started = service.is_running();
-        metadata.write(&metadata_path).await?;
-
-        let service = service.upgrade();
-        if !error_payload.is_empty() {
-          warn!(
-            "Loaded service '{}' with error {}",
-            service.name(),
-            format!("({})", service.uuid()).dimmed(),
-          );
-          warn!("error payload: {error_payload:?}");
-        } else {
-          info!(
-            "Loaded service '{}' {}",
-            service.name(),
-            format!("({})", service.uuid()).dimmed()
-          );
+    let name = service_folder.file_name().to_string_lossy().into_owned();
+    if service_folder.file_type().await?.is_dir() && !name.starts_with('.') {
+      match read_saved_service(state, &name, service_folder.path()).await {
+        Ok(service) => {
+          saved.insert(name, service);
+        }
+        Err(error) => {
+          warn!("Error preloading service '{name}': {error}");
+          warn!("maybe check '{}'?", service_folder.path().display());
         }
-
-        anyhow::Ok(())
       }
+    }
+  }
+
+  // Services are loaded after their dependencies, so that they can be started.
+  // Each wave only depends on previous ones.
+  let StartupOrder { order, cyclic } = startup_order(
+    (saved.iter()).map(|(name, service)| (name.as_str(), &service.config.depends_on[..])),
+  );
+  let mut depths = HashMap::new();
+  let mut waves = Vec::<Vec<(String, bool)>>::new();
+  for name in order {
+    let depth = (saved[name].config.depends_on.iter())
+      .filter_map(|x| depths.get(x.as_str()))
+      .map(|x| x + 1)
+      .max()
+      .unwrap_or(0);
+    depths.insert(name, depth);
+    if waves.len() <= depth {
+      waves.resize_with(depth + 1, Vec::new);
+    }
+    waves[depth].push((name.to_owned(), false));
+  }
+  waves.push(cyclic.into_iter().map(|x| (x.to_owned(), true)).collect());
+
+  let count = saved.len();
+  for wave in waves {
+    let wave = (wave.into_iter())
+      .map(|(name, cyclic)| {
+        let service = saved.remove(&name).unwrap();
+        (name, service, cyclic)
+      })
+      .collect::<Vec<_>>();
+    stream::iter(wave)
+      .for_each_concurrent(config.pool_size(), |(name, service, cyclic)| async move {
+        let path = service.path.clone();
+        let result = load_saved_service(state, name.clone(), service, cyclic, config.lazy_load);
+        if let Err(error) = result.await {
+          warn!("Error preloading service '{name}': {error}");
+          warn!("maybe check '{}'?", path.display());
+        }
+        if let Err(error) = restore_held(state, &name).await {
+          warn!("Error restoring update of service '{name}' held for approval: {error}");
+        }
+      })
       .await;
-      if let Err(error) = result {
-        warn!("Error preloading service '{name}': {error}");
-        warn!("maybe check '{}'?", service_folder.path().display());
-      }
+  }
+  info!("Restored {count} services in {:.2?}", started_at.elapsed());
+  Ok(())
+}
+
+async fn read_saved_service(
+  state: &ServerState,
+  name: &str,
+  path: PathBuf,
+) -> anyhow::Result<SavedService> {
+  let metadata = state.metadata.get(name).await?;
+  canary::remove_files(&path).await?;
+  read_service_dir(state, path, metadata).await
+}
+
+async fn read_service_dir(
+  state: &ServerState,
+  path: PathBuf,
+  metadata: Metadata,
+) -> anyhow::Result<SavedService> {
+  let asar_path = path.join("source.asar");
+  let lua_path = path.join("source.lua");
+
+  let (source, config) = match (asar_path.exists(), lua_path.exists()) {
+    _ if metadata.remote.is_some() => {
+      let base = metadata.remote.as_deref().unwrap();
+      let source = ObjectSource::new(base, &state.abel_path, state.object_storage.clone());
+      let config = source.read_config(true).await?;
+      (Source::new(source), config)
+    }
+    (true, false) => {
+      let mut archive = Archive::new_from_file(asar_path).await?;
+
+      let config = if let Ok(mut config_file) = archive.get("abel.json").await {
+        let mut config_bytes = Vec::with_capacity(config_file.metadata().size as _);
+        config_file.read_to_end(&mut config_bytes).await?;
+        abel_core::Config::from_json_lenient(&config_bytes)?
+      } else {
+        Default::default()
+      };
+
+      let source = Source::new(AsarSource(archive));
+      (source, config)
     }
+    (false, true) => {
+      let code = fs::read(lua_path).await?;
+      let source = Source::new(SingleSource::new(code));
+      (source, Default::default())
+    }
+    (true, true) => bail!("both source.asar and source.lua found"),
+    (false, false) => bail!("neither source.asar nor source.lua found"),
+  };
+
+  Ok(SavedService {
+    path,
+    metadata,
+    source,
+    config,
+  })
+}
+
+/// Holds the update of a service saved aside for approval, if any, while the
+/// approved version keeps serving.
+async fn restore_held(state: &ServerState, name: &str) -> anyhow::Result<()> {
+  let path = layout::held_path(&state.abel_path.join("services"), name);
+  if !path.exists() || state.abel.get_service(name).is_err() {
+    return Ok(());
+  }
+  let metadata: Metadata = serde_json::from_slice(&fs::read(path.join("metadata.json")).await?)?;
+  let SavedService {
+    metadata,
+    source,
+    mut config,
+    ..
+  } = read_service_dir(state, path, metadata).await?;
+  config.granted = metadata.granted;
+  config.content_hash = metadata.hash;
+  config.deployed_at = metadata.deployed_at;
+  let lock = state.abel.lock_deploy(name, true).await?;
+  (state.abel)
+    .hold_service(&lock, Some(metadata.uuid), source, config)
+    .await?;
+  info!(
+    "Restored update of service '{name}' held for approval {}",
+    format!("({})", metadata.uuid).dimmed(),
+  );
+  Ok(())
+}
+
+/// Loads a service read from disk, starting it if it was running and its
+/// dependencies are.
+async fn load_saved_service(
+  state: &ServerState,
+  name: String,
+  service: SavedService,
+  cyclic: bool,
+  lazy: bool,
+) -> anyhow::Result<()> {
+  let SavedService {
+    path,
+    mut metadata,
+    source,
+    mut config,
+  } = service;
+  config.granted = metadata.granted.clone();
+  config.content_hash = metadata.hash.clone();
+  config.deployed_at = metadata.deployed_at;
+  maintenance::restore(state, &name, &path).await?;
+
+  let lock = state.abel.lock_deploy(&name, true).await?;
+  let started_at = Instant::now();
+  if metadata.pending_approval {
+    (state.abel)
+      .hold_service(&lock, Some(metadata.uuid), source, config)
+      .await?;
+    redirect::restore(state, &name, &path).await?;
+    info!(
+      "Loaded service '{name}' pending approval {} in {:.2?}",
+      format!("({})", metadata.uuid).dimmed(),
+      started_at.elapsed(),
+    );
+    return Ok(());
+  }
+  if lazy && !cyclic {
+    (state.abel)
+      .preload_service_lazily(&lock, metadata.uuid, source, config, metadata.started)
+      .await?;
+    redirect::restore(state, &name, &path).await?;
+    info!(
+      "Loaded service '{name}' lazily {} in {:.2?}",
+      format!("({})", metadata.uuid).dimmed(),
+      started_at.elapsed(),
+    );
+    return Ok(());
+  }
+
+  let dependency_error: Option<abel_core::Error> = if cyclic {
+    Some(
+      ErrorKind::DependencyCycle {
+        name: name.as_str().into(),
+      }
+      .into(),
+    )
+  } else {
+    (config.depends_on.iter())
+      .find(|x| !matches!(state.abel.get_service(x), Ok(x) if x.is_running()))
+      .map(|x| {
+        ErrorKind::DependencyNotRunning {
+          name: name.as_str().into(),
+          dependency: x.as_str().into(),
+        }
+        .into()
+      })
+  };
+
+  let (service, mut error_payload) = if metadata.started && dependency_error.is_none() {
+    let (service, _, error_payload) = (state.abel)
+      .cold_update_or_create_service(&lock, Some(metadata.uuid), source, config, None)
+      .await?;
+    (service, error_payload)
+  } else {
+    let (service, error_payload) = (state.abel)
+      .preload_service(&lock, metadata.uuid, source, config)
+      .await?;
+    (Service::Stopped(service), error_payload)
+  };
+  if metadata.started || cyclic {
+    error_payload.start = error_payload.start.or(dependency_error);
+  }
+
+  redirect::restore(state, &name, &path).await?;
+  metadata.started = service.is_running();
+  state.metadata.put(&name, &metadata).await?;
+
+  let service = service.upgrade();
+  if !error_payload.is_empty() {
+    warn!(
+      "Loaded service '{}' with error {} in {:.2?}",
+      service.name(),
+      format!("({})", service.uuid()).dimmed(),
+      started_at.elapsed(),
+    );
+    warn!("error payload: {error_payload:?}");
+  } else {
+    info!(
+      "Loaded service '{}' {} in {:.2?}",
+      service.name(),
+      format!("({})", service.uuid()).dimmed(),
+      started_at.elapsed(),
+    );
   }
   Ok(())
 }
@@ -0,0 +1,81 @@
+use super::{json_response, Result, ServerState};
+use abel_core::service::RedirectMap;
+use hyper::{Body, Request, Response, StatusCode};
+use log::info;
+use serde_json::json;
+use std::path::Path;
+use tokio::io::AsyncReadExt;
+use tokio::{fs, io};
+
+/// Redirect map uploaded through the API, overriding the one in the source.
+const OVERRIDE_FILE: &str = "redirects.txt";
+
+pub async fn get(state: &ServerState, name: &str) -> Result<Response<Body>> {
+  let (source, path) = {
+    let service = state.abel.get_service(name)?;
+    let guard = service.upgrade();
+    (
+      guard.source().clone(),
+      guard.redirects().map(ToOwned::to_owned),
+    )
+  };
+  let service_path = state.abel_path.join("services").join(name);
+  let map = match fs::read(service_path.join(OVERRIDE_FILE)).await {
+    Ok(map) => map,
+    Err(error) if error.kind() == io::ErrorKind::NotFound => {
+      let path = path.ok_or((404, "redirect map not found", json!({ "service": name })))?;
+      let mut map = Vec::new();
+      source.get(&path).await?.read_to_end(&mut map).await?;
+      map
+    }
+    Err(error) => return Err(error.into()),
+  };
+  let resp = Response::builder()
+    .header("content-type", "text/plain; charset=utf-8")
+    .body(map.into())
+    .unwrap();
+  Ok(resp)
+}
+
+/// Replaces the redirect map of a service without reloading it.
+pub async fn update(
+  state: &ServerState,
+  name: String,
+  req: Request<Body>,
+) -> Result<Response<Body>> {
+  state.abel.get_service(&name)?;
+  let body = hyper::body::to_bytes(req.into_body())
+    .await
+    .map_err(|error| (400, "failed to read request body", error.to_string()))?;
+  let text = std::str::from_utf8(&body).map_err(|_| "redirect map is not valid UTF-8")?;
+  let map = RedirectMap::parse(text)?;
+  let len = map.len();
+
+  let service_path = state.abel_path.join("services").join(&name);
+  fs::write(service_path.join(OVERRIDE_FILE), text).await?;
+  state.abel.get_service(&name)?.upgrade().set_redirects(map);
+
+  info!("Updated redirects of service '{name}' ({len} rules)");
+  json_response(StatusCode::OK, json!({ "service": name, "rules": len }))
+}
+
+/// Applies a previously uploaded redirect map, if any.
+pub async fn restore(state: &ServerState, name: &str, service_path: &Path) -> anyhow::Result<()> {
+  match fs::read_to_string(service_path.join(OVERRIDE_FILE)).await {
+    Ok(text) => {
+      let map = RedirectMap::parse(&text)?;
+      state.abel.get_service(name)?.upgrade().set_redirects(map);
+      Ok(())
+    }
+    Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(()),
+    Err(error) => Err(error.into()),
+  }
+}
+
+/// Removes the uploaded redirect map, so that the one in the source is used.
+pub async fn remove_override(service_path: &Path) -> io::Result<()> {
+  match fs::remove_file(service_path.join(OVERRIDE_FILE)).await {
+    Err(error) if error.kind() != io::ErrorKind::NotFound => Err(error),
+    _ => Ok(()),
+  }
+}
@@ -0,0 +1,387 @@
+use super::ServerState;
+use abel_core::event::{Event, EventKind};
+use abel_core::net::NetPolicy;
+use abel_core::NetPermission;
+use log::{debug, warn};
+use reqwest::{Client, Url};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::HashMap;
+use std::ops::Range;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast::error::RecvError;
+use uuid::Uuid;
+
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(10);
+const RATE_LIMIT_WINDOW: Duration = Duration::from_secs(60);
+const FILTERED: &str = "[Filtered]";
+
+/// Parts of query parameter names that look like credentials. Values of such
+/// parameters are always scrubbed.
+const SENSITIVE_PARAMS: &[&str] = &["auth", "key", "password", "secret", "session", "token"];
+
+/// Forwarding of services' Lua runtime errors to a Sentry-compatible
+/// endpoint.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReportingConfig {
+  /// DSN receiving errors of services without their own `error_reporting`.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub dsn: Option<String>,
+  /// Maximum number of errors reported per minute for each service. Further
+  /// errors are dropped.
+  #[serde(default = "default_rate_limit")]
+  pub rate_limit: u32,
+  /// Query parameters whose values are scrubbed from reports, in addition to
+  /// those looking like credentials. Scrubbed values are also removed from
+  /// error messages.
+  #[serde(default, skip_serializing_if = "Vec::is_empty")]
+  pub scrub_params: Vec<String>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub environment: Option<String>,
+}
+
+impl Default for ReportingConfig {
+  fn default() -> Self {
+    Self {
+      dsn: None,
+      rate_limit: default_rate_limit(),
+      scrub_params: Vec::new(),
+      environment: None,
+    }
+  }
+}
+
+fn default_rate_limit() -> u32 {
+  10
+}
+
+/// Where to post events, parsed from `<scheme>://<key>@<host>/<project>`.
+#[derive(Debug, PartialEq)]
+struct Dsn {
+  store_url: String,
+  public_key: String,
+  host: String,
+  port: u16,
+}
+
+impl Dsn {
+  fn parse(dsn: &str) -> Option<Self> {
+    let url = Url::parse(dsn).ok()?;
+    let public_key = url.username();
+    let (base, project) = url.path().trim_end_matches('/').rsplit_once('/')?;
+    if public_key.is_empty() || project.is_empty() {
+      return None;
+    }
+    let host = url.host_str()?;
+    let port = url.port().map(|x| format!(":{x}")).unwrap_or_default();
+    Some(Self {
+      store_url: format!("{}://{host}{port}{base}/api/{project}/store/", url.scheme()),
+      public_key: public_key.into(),
+      host: host.into(),
+      port: url.port_or_known_default()?,
+    })
+  }
+
+  /// Client delivering to a service's own DSN, which is only reachable where
+  /// the service may connect itself with its `net` permission. The checked
+  /// address is pinned, so that the name cannot resolve elsewhere later.
+  async fn client_for(&self, service: &str, net: &NetPermission) -> Result<Client, String> {
+    match net {
+      NetPermission::All(true) => Ok(Client::new()),
+      NetPermission::All(false) => Err("service has no net permission".into()),
+      NetPermission::Rules(rules) => {
+        let policy = NetPolicy::new(service, &rules[..]);
+        let host = self.host.trim_start_matches('[').trim_end_matches(']');
+        let addr = (policy.resolve(host, self.port).await).map_err(|error| error.to_string())?;
+        (Client::builder().resolve(host, addr).build()).map_err(|error| error.to_string())
+      }
+    }
+  }
+}
+
+pub struct Reporter {
+  client: Client,
+  config: ReportingConfig,
+  /// Start of the current rate limit window and errors reported in it, for
+  /// each service.
+  windows: Mutex<HashMap<String, (Instant, u32)>>,
+}
+
+impl Reporter {
+  pub fn new(config: Option<ReportingConfig>) -> Self {
+    Self {
+      client: Client::new(),
+      config: config.unwrap_or_default(),
+      windows: Default::default(),
+    }
+  }
+
+  fn allow(&self, service: &str) -> bool {
+    let now = Instant::now();
+    let mut windows = self.windows.lock().unwrap();
+    let (start, count) = windows.entry(service.into()).or_insert((now, 0));
+    if now.duration_since(*start) >= RATE_LIMIT_WINDOW {
+      *start = now;
+      *count = 0;
+    }
+    *count += 1;
+    *count <= self.config.rate_limit
+  }
+
+  fn is_scrubbed(&self, param: &str) -> bool {
+    let param = param.to_ascii_lowercase();
+    SENSITIVE_PARAMS.iter().any(|x| param.contains(x))
+      || (self.config.scrub_params.iter()).any(|x| x.eq_ignore_ascii_case(&param))
+  }
+
+  /// Replaces values of scrubbed parameters, returning the scrubbed query and
+  /// the original values.
+  fn scrub_query(&self, query: &str) -> (String, Vec<String>) {
+    let mut scrubbed = Vec::new();
+    let query = (query.split('&'))
+      .map(|pair| match pair.split_once('=') {
+        Some((name, value)) if self.is_scrubbed(name) => {
+          if !value.is_empty() {
+            scrubbed.push(value.to_owned());
+          }
+          format!("{name}={FILTERED}")
+        }
+        _ => pair.to_owned(),
+      })
+      .collect::<Vec<_>>()
+      .join("&");
+    (query, scrubbed)
+  }
+
+  /// Scrubs credentials from free text such as error messages, which may
+  /// quote URLs, headers or data the service handled: values of `name=value`
+  /// and `name: value` pairs with scrubbed names, bearer tokens, and user info
+  /// in URLs.
+  fn scrub_text(&self, text: &str) -> String {
+    let is_name = |c: char| c.is_ascii_alphanumeric() || c == '_' || c == '-';
+    let is_end =
+      |c: char| c.is_whitespace() || matches!(c, '&' | '"' | '\'' | ',' | ';' | ')' | ']' | '}');
+    let value_end = |start: usize| text[start..].find(is_end).map_or(text.len(), |x| start + x);
+    let mut ranges = Vec::<Range<usize>>::new();
+
+    for (i, _) in text.match_indices(&['=', ':'][..]) {
+      if text[i + 1..].starts_with("//") {
+        continue;
+      }
+      let name = text[..i].trim_end_matches(&['"', '\''][..]);
+      let name_start = (name.char_indices().rev())
+        .find(|(_, c)| !is_name(*c))
+        .map_or(0, |(x, c)| x + c.len_utf8());
+      let name = &name[name_start..];
+      if name.is_empty() || !self.is_scrubbed(name) {
+        continue;
+      }
+      let value = text[i + 1..].trim_start_matches(&[' ', '"', '\''][..]);
+      let start = text.len() - value.len();
+      ranges.push(start..value_end(start));
+    }
+    for (i, _) in text.to_ascii_lowercase().match_indices("bearer ") {
+      let start = i + "bearer ".len();
+      ranges.push(start..value_end(start));
+    }
+    for (i, _) in text.match_indices("://") {
+      let start = i + "://".len();
+      let end =
+        (text[start..].find(|c: char| c == '/' || is_end(c))).map_or(text.len(), |x| start + x);
+      if let Some(at) = text[start..end].rfind('@') {
+        ranges.push(start..start + at);
+      }
+    }
+
+    ranges.sort_by_key(|x| x.start);
+    let mut scrubbed = String::with_capacity(text.len());
+    let mut last = 0;
+    for range in ranges.into_iter().filter(|x| !x.is_empty()) {
+      if range.end <= last {
+        continue;
+      }
+      scrubbed.push_str(&text[last..range.start.max(last)]);
+      scrubbed.push_str(FILTERED);
+      last = range.end;
+    }
+    scrubbed.push_str(&text[last..]);
+    scrubbed
+  }
+
+  /// Builds a Sentry event from a request failure.
+  fn sentry_event(&self, time: u64, kind: &EventKind) -> Option<serde_json::Value> {
+    let (service, uuid, method, path, query, error) = match kind {
+      EventKind::RequestFailed {
+        service,
+        uuid,
+        method,
+        path,
+        query,
+        error,
+      } => (service, uuid, method, path, query, error),
+      _ => return None,
+    };
+    let (query, scrubbed) = match query {
+      Some(query) => {
+        let (query, scrubbed) = self.scrub_query(query);
+        (Some(query), scrubbed)
+      }
+      None => (None, Vec::new()),
+    };
+    let mut error = error.clone();
+    for value in scrubbed {
+      error = error.replace(&value, FILTERED);
+    }
+    let error = self.scrub_text(&error);
+    let path = self.scrub_text(path);
+    let (message, traceback) = match error.split_once("\nstack traceback:") {
+      Some((message, traceback)) => (message, Some(format!("stack traceback:{traceback}"))),
+      None => (&*error, None),
+    };
+    Some(json!({
+      "event_id": Uuid::new_v4().to_simple().to_string(),
+      "timestamp": time as f64 / 1000.,
+      "platform": "other",
+      "level": "error",
+      "logger": "abel",
+      "environment": self.config.environment,
+      "exception": { "values": [{ "type": "LuaError", "value": message }] },
+      "tags": { "service": service, "uuid": uuid },
+      "request": { "method": method, "url": path, "query_string": query },
+      "extra": { "traceback": traceback },
+    }))
+  }
+}
+
+/// Reports request failures to the service's DSN, or the server's. Never
+/// returns.
+pub async fn run(state: Arc<ServerState>) {
+  let mut events = state.abel.subscribe_activity();
+  loop {
+    let Event { time, kind } = match events.recv().await {
+      Ok(event) => event,
+      Err(RecvError::Lagged(n)) => {
+        warn!("{n} events were not checked for error reporting");
+        continue;
+      }
+      Err(RecvError::Closed) => return,
+    };
+    let service = match &kind {
+      EventKind::RequestFailed { service, .. } => service,
+      _ => continue,
+    };
+    // A service's own DSN is subject to its `net` permission like any other
+    // destination it chooses, while the server's is trusted
+    let service_dsn = (state.abel.get_service(service).ok()).and_then(|x| {
+      let x = x.upgrade();
+      let dsn = x.error_reporting()?.to_owned();
+      Some((dsn, x.effective_permissions().net))
+    });
+    let (dsn, net) = match service_dsn {
+      Some((dsn, net)) => (dsn, Some(net)),
+      None => match &state.reporter.config.dsn {
+        Some(dsn) => (dsn.clone(), None),
+        None => continue,
+      },
+    };
+    let dsn = match Dsn::parse(&dsn) {
+      Some(dsn) => dsn,
+      None => {
+        debug!("invalid error reporting DSN of service '{service}'");
+        continue;
+      }
+    };
+    if !state.reporter.allow(service) {
+      continue;
+    }
+    if let Some(body) = state.reporter.sentry_event(time, &kind) {
+      let client = state.reporter.client.clone();
+      let service = service.to_string();
+      tokio::spawn(async move {
+        let client = match net {
+          Some(net) => match dsn.client_for(&service, &net).await {
+            Ok(client) => client,
+            Err(error) => {
+              debug!("not reporting error of service '{service}' to its DSN: {error}");
+              return;
+            }
+          },
+          None => client,
+        };
+        deliver(client, dsn, body).await
+      });
+    }
+  }
+}
+
+async fn deliver(client: Client, dsn: Dsn, body: serde_json::Value) {
+  let auth = format!(
+    "Sentry sentry_version=7, sentry_client=abel/{}, sentry_key={}",
+    env!("CARGO_PKG_VERSION"),
+    dsn.public_key
+  );
+  let result = (client.post(&dsn.store_url))
+    .header("x-sentry-auth", auth)
+    .timeout(DELIVERY_TIMEOUT)
+    .json(&body)
+    .send()
+    .await
+    .and_then(|x| x.error_for_status());
+  if let Err(error) = result {
+    debug!("failed to report error to {}: {error}", dsn.store_url);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use test_case::test_case;
+
+  fn reporter() -> Reporter {
+    Reporter::new(Some(ReportingConfig {
+      scrub_params: vec!["email".into()],
+      ..Default::default()
+    }))
+  }
+
+  #[test]
+  fn test_dsn_parse() {
+    let dsn = Dsn::parse("https://abc@sentry.example.com/42").unwrap();
+    assert_eq!(dsn.store_url, "https://sentry.example.com/api/42/store/");
+    assert_eq!(dsn.public_key, "abc");
+    assert_eq!((&*dsn.host, dsn.port), ("sentry.example.com", 443));
+
+    let dsn = Dsn::parse("http://abc@localhost:9000/sentry/7/").unwrap();
+    assert_eq!(dsn.store_url, "http://localhost:9000/sentry/api/7/store/");
+    assert_eq!(dsn.port, 9000);
+  }
+
+  #[test_case("https://sentry.example.com/42"; "no key")]
+  #[test_case("https://abc@sentry.example.com/"; "no project")]
+  #[test_case("https://abc@sentry.example.com"; "no path")]
+  #[test_case("not a dsn"; "not url")]
+  fn test_dsn_parse_invalid(dsn: &str) {
+    assert_eq!(Dsn::parse(dsn), None);
+  }
+
+  #[test]
+  fn test_scrub_query() {
+    let (query, scrubbed) = reporter().scrub_query("page=2&api_key=s3cr3t&Email=a@b.c&token=");
+    assert_eq!(
+      query,
+      "page=2&api_key=[Filtered]&Email=[Filtered]&token=[Filtered]"
+    );
+    assert_eq!(scrubbed, ["s3cr3t", "a@b.c"]);
+  }
+
+  #[test_case("fetching https://x.com/?a=1&access_token=abc failed" => "fetching https://x.com/?a=1&access_token=[Filtered] failed"; "url")]
+  #[test_case("sent Bearer abc.def upstream" => "sent Bearer [Filtered] upstream"; "bearer")]
+  #[test_case("Authorization: Bearer abc" => "Authorization: [Filtered] [Filtered]"; "header")]
+  #[test_case(r#"{"password": "hunter2", "user": "a"}"# => r#"{"password": "[Filtered]", "user": "a"}"#; "json")]
+  #[test_case("redis://admin:pw@localhost/0" => "redis://[Filtered]@localhost/0"; "user info")]
+  #[test_case("main.lua:12: bad argument #1" => "main.lua:12: bad argument #1"; "unchanged")]
+  fn test_scrub_text(text: &str) -> String {
+    reporter().scrub_text(text)
+  }
+}
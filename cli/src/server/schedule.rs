@@ -0,0 +1,23 @@
+use super::ServerState;
+use log::info;
+use std::sync::Arc;
+use std::time::Duration;
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Fires due `abel.schedule_at` calls, and starts and stops services with
+/// `availability` windows. Never returns.
+pub async fn run(state: Arc<ServerState>) {
+  let mut interval = tokio::time::interval(CHECK_INTERVAL);
+  loop {
+    interval.tick().await;
+    for (name, started) in state.abel.apply_availability().await {
+      if started {
+        info!("Started service '{name}' for its availability window");
+      } else {
+        info!("Stopped service '{name}' outside its availability windows");
+      }
+    }
+    state.abel.run_due_schedules().await;
+  }
+}
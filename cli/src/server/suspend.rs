@@ -0,0 +1,52 @@
+use super::{Result, ServerState};
+use abel_core::service::RunningService;
+use abel_core::ErrorKind::ServiceNotReady;
+use log::info;
+use std::sync::Arc;
+use std::time::Duration;
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Suspends services that have been idle for longer than their
+/// `idle_timeout`. Never returns.
+pub async fn run(state: Arc<ServerState>) {
+  let mut interval = tokio::time::interval(CHECK_INTERVAL);
+  loop {
+    interval.tick().await;
+    for name in state.abel.suspend_idle_services().await {
+      info!("Suspended idle service '{name}'");
+    }
+  }
+}
+
+pub fn is_suspended(state: &ServerState, name: &str) -> bool {
+  matches!(state.abel.get_service(name), Ok(x) if x.upgrade().is_suspended())
+}
+
+/// Starts a suspended service on request, waiting at most its `wake_timeout`.
+///
+/// If the timeout is exceeded, the service keeps starting in the background
+/// and the request fails.
+pub async fn wake(state: &Arc<ServerState>, name: &str) -> Result<RunningService> {
+  let timeout = (state.abel.get_service(name)?.upgrade())
+    .wake_timeout()
+    .map(Duration::from_secs);
+  let task = tokio::spawn({
+    let state = state.clone();
+    let name = name.to_owned();
+    async move {
+      let result = state.abel.wake_service(&name).await;
+      if result.is_ok() {
+        info!("Woke up service '{name}'");
+      }
+      result
+    }
+  });
+  let result = match timeout {
+    Some(timeout) => (tokio::time::timeout(timeout, task).await)
+      .map_err(|_| ServiceNotReady { name: name.into() })?,
+    None => task.await,
+  };
+  let result = result.map_err(|error| (500, "failed to wake service", error.to_string()))?;
+  Ok(result?)
+}
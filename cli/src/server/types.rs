@@ -1,8 +1,9 @@
-use abel_core::service::{Service, ServiceGuard, ServiceInfo};
+use abel_core::service::{MetricsSnapshot, Service, ServiceGuard, ServiceInfo};
 use ouroboros::self_referencing;
 use serde::{Deserialize, Serialize, Serializer};
 use serde_with::skip_serializing_none;
 use std::borrow::Cow;
+use uuid::Uuid;
 
 #[self_referencing]
 pub struct OwnedServiceWithStatus<'a> {
@@ -38,12 +39,29 @@ pub enum ServiceStatus {
   Running,
   #[serde(rename = "stopped")]
   Stopped,
+  #[serde(rename = "pending_approval")]
+  PendingApproval,
 }
 
+#[skip_serializing_none]
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ServiceWithStatus<'a> {
   pub status: ServiceStatus,
   pub service: Cow<'a, ServiceInfo>,
+  #[serde(default)]
+  pub metrics: MetricsSnapshot,
+  /// Seconds since the service started, if running.
+  #[serde(default)]
+  pub uptime: Option<u64>,
+  #[serde(default)]
+  pub canary: Option<CanaryStatus>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CanaryStatus {
+  pub uuid: Uuid,
+  pub weight: u8,
+  pub metrics: MetricsSnapshot,
 }
 
 impl<'a> ServiceWithStatus<'a> {
@@ -53,10 +71,20 @@ impl<'a> ServiceWithStatus<'a> {
       ServiceGuard::Running { service } => Self {
         status: Running,
         service: Cow::Borrowed(service.info()),
+        metrics: service.metrics().snapshot(),
+        uptime: Some(service.metrics().uptime().as_secs()),
+        canary: None,
       },
       ServiceGuard::Stopped { service } => Self {
-        status: Stopped,
+        status: if service.pending_approval() {
+          PendingApproval
+        } else {
+          Stopped
+        },
         service: Cow::Borrowed(service.info()),
+        metrics: service.metrics().snapshot(),
+        uptime: None,
+        canary: None,
       },
     }
   }
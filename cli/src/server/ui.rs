@@ -0,0 +1,46 @@
+use super::{authenticate, ServerState};
+use abel_core::constant_time_eq;
+use hyper::header::HeaderValue;
+use hyper::{Body, Request, Response, StatusCode};
+
+const DASHBOARD_HTML: &str = include_str!("ui/dashboard.html");
+const LOGIN_HTML: &str = include_str!("ui/login.html");
+
+/// Cookie set by the login page. Only used for serving the dashboard page
+/// itself; API requests from the dashboard still carry the `Authorization`
+/// header.
+const UI_TOKEN_COOKIE: &str = "abel_ui_token";
+
+pub fn dashboard(state: &ServerState, req: &Request<Body>) -> Response<Body> {
+  if authenticate(state, req) || authenticate_cookie(state, req) {
+    html_response(StatusCode::OK, DASHBOARD_HTML)
+  } else {
+    html_response(StatusCode::UNAUTHORIZED, LOGIN_HTML)
+  }
+}
+
+fn authenticate_cookie(state: &ServerState, req: &Request<Body>) -> bool {
+  let auth_token = match state.auth_token {
+    Some(x) => x.to_string(),
+    None => return true,
+  };
+  (req.headers())
+    .get_all("cookie")
+    .iter()
+    .filter_map(|x| x.to_str().ok())
+    .flat_map(|x| x.split(';'))
+    .filter_map(|x| x.trim().split_once('='))
+    .any(|(k, v)| k == UI_TOKEN_COOKIE && constant_time_eq(v.as_bytes(), auth_token.as_bytes()))
+}
+
+fn html_response(status: StatusCode, html: &'static str) -> Response<Body> {
+  let mut resp = Response::new(Body::from(html));
+  *resp.status_mut() = status;
+  let headers = resp.headers_mut();
+  headers.insert(
+    "content-type",
+    HeaderValue::from_static("text/html; charset=utf-8"),
+  );
+  headers.insert("cache-control", HeaderValue::from_static("no-store"));
+  resp
+}
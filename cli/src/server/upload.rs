@@ -1,26 +1,38 @@
+use super::audit::{self, Actor};
+use super::config::UploadLimits;
+use super::etag::IfMatch;
+use super::hash::{derive_uuid, hash_archive, hash_single};
+use super::jobs::{self, JobPhase};
 use super::metadata::Metadata;
 use super::types::{HttpUploadResponse, ServiceWithStatus};
-use super::{json_response, Result, ServerState};
-use crate::source::{AsarSource, SingleSource};
+use super::{approval, canary, json_response, layout, maintenance, Result, ServerState};
+use crate::source::{AsarSource, ObjectSource, SingleSource};
 use crate::SourceKind;
-use abel_core::service::{ErrorPayload, Service};
+use abel_core::event::EventKind;
+use abel_core::service::{BeforeSwap, ErrorPayload, Service};
 use abel_core::source::Source;
 use abel_core::ErrorKind::ServiceExists;
-use abel_core::{Config, ServiceImpl};
+use abel_core::{normalize_path_str, Config, DeployGuard, ServiceImpl};
 use bytes::{Bytes, BytesMut};
+use futures::future::ready;
 use futures::{Stream, TryStreamExt};
-use hive_asar::Archive;
+use hive_asar::header::Entry;
+use hive_asar::{Archive, DuplicableFile};
+use hyper::header::LOCATION;
 use hyper::{Body, HeaderMap, Request, Response, StatusCode};
 use log::{info, warn};
 use multer::{Constraints, Multipart, SizeLimit};
 use owo_colors::OwoColorize;
 use serde::{Deserialize, Serialize};
+use serde_json::json;
 use std::borrow::Cow;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
 use strum::{Display, EnumString, IntoStaticStr};
 use tokio::fs::{self, File};
-use tokio::io::{self, AsyncReadExt};
-use tokio_util::io::StreamReader;
+use tokio::io::{self, AsyncReadExt, AsyncWriteExt};
+use tokio_util::io::{ReaderStream, StreamReader};
 use uuid::Uuid;
 
 #[derive(
@@ -51,12 +63,33 @@ pub enum UploadMode {
   #[serde(rename = "load")]
   #[strum(serialize = "load")]
   Load,
+  #[serde(rename = "canary")]
+  #[strum(serialize = "canary")]
+  Canary,
 }
 
 #[derive(Serialize, Deserialize)]
 struct UploadQuery {
   #[serde(default)]
   mode: UploadMode,
+  /// Percentage of traffic routed to the canary, used only in canary mode.
+  #[serde(default = "default_canary_weight")]
+  weight: u8,
+  /// Updates the service even if its source is unchanged.
+  #[serde(default)]
+  force: bool,
+  /// Responds once the source is received, deploying it in the background
+  /// as a job.
+  #[serde(default, rename = "async")]
+  background: bool,
+  /// Sets who the service belongs to. Kept across updates if not given.
+  owner: Option<String>,
+}
+
+pub const DEFAULT_CANARY_WEIGHT: u8 = 10;
+
+fn default_canary_weight() -> u8 {
+  DEFAULT_CANARY_WEIGHT
 }
 
 pub struct UploadResponse<'a> {
@@ -65,55 +98,311 @@ pub struct UploadResponse<'a> {
   pub errors: ErrorPayload,
 }
 
+/// Uploaded source, either streamed or kept in object storage under a base
+/// URL.
+enum Uploaded<S> {
+  Local(SourceKind, S),
+  Remote(String),
+}
+
 pub async fn upload(
-  state: &ServerState,
+  state: &Arc<ServerState>,
   name: String,
+  actor: Actor,
+  operation: &str,
+  if_match: IfMatch,
   req: Request<Body>,
 ) -> Result<Response<Body>> {
   let (parts, body) = req.into_parts();
-  let mut multipart = parse_multipart(&parts.headers, body)?;
+  let mut multipart = parse_multipart(&parts.headers, body, &state.upload_limits)?;
 
-  let UploadQuery { mode } = serde_qs::from_str(parts.uri.query().unwrap_or(""))?;
+  let query: UploadQuery = serde_qs::from_str(parts.uri.query().unwrap_or(""))?;
+  let forwarded = state.cluster.is_forwarded(&parts.headers);
 
   let source_field = multipart.next_field().await?.ok_or((
     "no source uploaded",
-    "specify either `single` or `multi` field in multipart",
+    "specify one of `single`, `multi` or `remote` field in multipart",
   ))?;
 
+  // `None` for sources in object storage
   let kind = match source_field.name() {
-    Some("single") => SourceKind::Single,
-    Some("multi") => SourceKind::Multi,
+    Some("single") => Some(SourceKind::Single),
+    Some("multi") => Some(SourceKind::Multi),
+    Some("remote") => None,
     _ => {
       return Err(From::from((
         "unknown field name",
-        "first field is neither named `single` nor `multi`",
+        "first field is not named `single`, `multi` or `remote`",
       )))
     }
   };
 
-  let source_stream = source_field.map_err(|e| io::Error::new(io::ErrorKind::Other, e));
-  let resp = upload_local(state, name, mode, kind, source_stream).await?;
+  if query.mode == UploadMode::Canary && kind.is_none() {
+    return Err(From::from((
+      "remote canary not supported",
+      "upload the canary's source directly",
+    )));
+  }
+
+  if query.background {
+    // Checked again once the job locks the service, but rejected early here
+    // if it already fails
+    if_match.check(state, &name).await?;
+    let uploaded = match kind {
+      Some(kind) => {
+        let source_stream = source_field.map_err(|e| io::Error::new(io::ErrorKind::Other, e));
+        Uploaded::Local(kind, receive_temp(state, source_stream).await?)
+      }
+      None => Uploaded::Remote(source_field.text().await?),
+    };
+    let file = match &uploaded {
+      Uploaded::Local(_, path) => Some(path.clone()),
+      Uploaded::Remote(_) => None,
+    };
+    let (state2, name2, operation) = (state.clone(), name.clone(), operation.to_owned());
+    let id = state
+      .jobs
+      .spawn(&name, file.as_deref(), move |file| async move {
+        let uploaded = match (uploaded, file) {
+          (Uploaded::Local(kind, _), Some(path)) => Uploaded::Local(kind, path),
+          (uploaded, _) => uploaded,
+        };
+        let deploy =
+          deploy_in_background(&state2, name2.clone(), if_match, query, uploaded, forwarded);
+        audit::audited_job(&state2, actor, &operation, &name2, deploy).await
+      })?;
+    let mut resp = json_response(StatusCode::ACCEPTED, json!({ "job": id, "service": name }))?;
+    let location = format!("/jobs/{id}").try_into().unwrap();
+    resp.headers_mut().insert(LOCATION, location);
+    return Ok(resp);
+  }
 
+  let uploaded = match kind {
+    Some(kind) => Uploaded::Local(
+      kind,
+      source_field.map_err(|e| io::Error::new(io::ErrorKind::Other, e)),
+    ),
+    None => Uploaded::Remote(source_field.text().await?),
+  };
+  let lock = state.abel.lock_deploy(&name, false).await?;
+  if_match.check(state, &name).await?;
+  let resp = deploy(state, &lock, query, uploaded, forwarded).await?;
   response(resp).await
 }
 
-pub async fn upload_local(
+async fn deploy<'a, S>(
+  state: &'a ServerState,
+  lock: &DeployGuard,
+  query: UploadQuery,
+  uploaded: Uploaded<S>,
+  forwarded: bool,
+) -> Result<UploadResponse<'a>>
+where
+  S: Stream<Item = io::Result<Bytes>> + Unpin,
+{
+  let name = lock.name();
+  let UploadQuery {
+    mode,
+    weight,
+    force,
+    owner,
+    ..
+  } = query;
+  let remote = match &uploaded {
+    Uploaded::Local(..) => None,
+    Uploaded::Remote(base) => Some(base.trim().to_owned()),
+  };
+  let resp = match uploaded {
+    Uploaded::Local(kind, source_stream) if mode == UploadMode::Canary => {
+      return upload_canary(state, lock, weight, kind, source_stream).await;
+    }
+    Uploaded::Local(kind, source_stream) => {
+      upload_local(state, lock, mode, force, kind, source_stream).await?
+    }
+    Uploaded::Remote(base) => upload_remote(state, lock, mode, force, base.trim()).await?,
+  };
+  if let Some(owner) = owner {
+    (state.metadata)
+      .modify(name, &mut |m| m.owner = Some(owner.clone()))
+      .await?;
+  }
+  log_result(&resp);
+  if !forwarded {
+    let service_path = state.abel_path.join("services").join(name);
+    state
+      .cluster
+      .replicate_upload(name, mode, &service_path, remote);
+  }
+  Ok(resp)
+}
+
+/// Deploys a source received beforehand, returning the response body.
+///
+/// Unlike direct uploads, this waits for deploys of the same service in
+/// progress instead of failing.
+async fn deploy_in_background(
   state: &ServerState,
   name: String,
+  if_match: IfMatch,
+  query: UploadQuery,
+  uploaded: Uploaded<PathBuf>,
+  forwarded: bool,
+) -> Result<serde_json::Value> {
+  let lock = state.abel.lock_deploy(&name, true).await?;
+  if_match.check(state, &name).await?;
+  let resp = match uploaded {
+    Uploaded::Local(kind, path) => {
+      let stream = ReaderStream::new(File::open(&path).await?);
+      deploy(
+        state,
+        &lock,
+        query,
+        Uploaded::Local(kind, stream),
+        forwarded,
+      )
+      .await?
+    }
+    Uploaded::Remote(base) => {
+      let uploaded = Uploaded::<ReaderStream<File>>::Remote(base);
+      deploy(state, &lock, query, uploaded, forwarded).await?
+    }
+  };
+  Ok(response_body(resp)?)
+}
+
+/// Saves an uploaded source as is, to be deployed later.
+async fn receive_temp(
+  state: &ServerState,
+  mut source_stream: impl Stream<Item = io::Result<Bytes>> + Unpin,
+) -> Result<PathBuf> {
+  let limits = &state.upload_limits;
+  let limit = limits.max_archive_size.max(limits.max_single_size);
+  let temp_path = state.abel_path.join(format!("tmp/{}", Uuid::new_v4()));
+  let result = async {
+    let mut file = File::create(&temp_path).await?;
+    let mut size = 0;
+    while let Some(chunk) = source_stream.try_next().await? {
+      size += chunk.len() as u64;
+      check_size("source", size, limit)?;
+      file.write_all(&chunk).await?;
+    }
+    file.flush().await?;
+    Ok(())
+  };
+  match result.await {
+    Ok(()) => Ok(temp_path),
+    Err(error) => {
+      if temp_path.exists() {
+        fs::remove_file(&temp_path).await?;
+      }
+      Err(error)
+    }
+  }
+}
+
+pub async fn upload_local<'a>(
+  state: &'a ServerState,
+  lock: &DeployGuard,
+  mode: UploadMode,
+  force: bool,
+  kind: SourceKind,
+  source_stream: impl Stream<Item = io::Result<Bytes>> + Unpin,
+) -> Result<UploadResponse<'a>> {
+  let (temp_path, source, config) = read_store_service_temp(state, kind, source_stream).await?;
+  let stored = StoredSource::Local {
+    kind,
+    temp_path: &temp_path,
+  };
+  create_service(state, lock, mode, force, config, source, stored).await
+}
+
+/// Creates or updates a service whose source is kept in object storage under
+/// `base`, instead of being uploaded.
+pub async fn upload_remote<'a>(
+  state: &'a ServerState,
+  lock: &DeployGuard,
   mode: UploadMode,
+  force: bool,
+  base: &str,
+) -> Result<UploadResponse<'a>> {
+  jobs::report(JobPhase::Extracting);
+  let source = ObjectSource::new(base, &state.abel_path, state.object_storage.clone());
+  let config = source.read_config(false).await?;
+  let stored = StoredSource::Remote(base);
+  create_service(
+    state,
+    lock,
+    mode,
+    force,
+    config,
+    Source::new(source),
+    stored,
+  )
+  .await
+}
+
+/// Deploys a new version of a running service as its canary.
+///
+/// The source is stored alongside the current one and only replaces it when
+/// the canary is promoted.
+async fn upload_canary<'a>(
+  state: &'a ServerState,
+  lock: &DeployGuard,
+  weight: u8,
   kind: SourceKind,
   source_stream: impl Stream<Item = io::Result<Bytes>> + Unpin,
-) -> Result<UploadResponse> {
-  let (temp_path, source, config) =
-    read_store_service_temp(&state.abel_path, kind, source_stream).await?;
-  create_service(state, mode, name, config, source, kind, &temp_path).await
+) -> Result<UploadResponse<'a>> {
+  let name = lock.name();
+  let (temp_path, source, mut config) = read_store_service_temp(state, kind, source_stream).await?;
+  config.deployed_at = Some(now());
+  let service_path = state.abel_path.join("services").join(name);
+  if !approval::granted(state, name)
+    .await?
+    .covers(&config.permissions)
+  {
+    return Err(From::from((
+      403,
+      "permissions not approved",
+      json!({ "msg": "deploy the new version normally to request approval", "name": name }),
+    )));
+  }
+  jobs::report(JobPhase::Evaluating);
+  let (new_service, replaced_service) = (state.abel)
+    .deploy_canary(lock, None, source, config, weight, Some(report_swapping()))
+    .await?;
+
+  canary::remove_files(&service_path).await?;
+  match kind {
+    SourceKind::Single => fs::rename(temp_path, service_path.join("canary.lua")).await?,
+    SourceKind::Multi => fs::hard_link(temp_path, service_path.join("canary.asar")).await?,
+  }
+
+  let guard = new_service.try_upgrade()?;
+  info!(
+    "Deployed canary of service '{}' with weight {weight}% {}",
+    guard.name(),
+    format!("({})", guard.uuid()).dimmed(),
+  );
+  drop(guard);
+
+  Ok(UploadResponse {
+    new_service: Service::Running(new_service),
+    replaced_service,
+    errors: Default::default(),
+  })
 }
 
-fn parse_multipart(headers: &HeaderMap, body: Body) -> Result<Multipart<'static>> {
-  let allowed_fields = vec!["single", "multi", "config"];
+fn parse_multipart(
+  headers: &HeaderMap,
+  body: Body,
+  limits: &UploadLimits,
+) -> Result<Multipart<'static>> {
+  let allowed_fields = vec!["single", "multi", "remote", "config"];
   let size_limit = SizeLimit::new()
-    .for_field("single", 1024u64.pow(2) * 5)
-    .for_field("multi", 1024u64.pow(2) * 100)
+    .whole_stream(limits.max_archive_size.max(limits.max_single_size) + 1024u64.pow(2) * 5)
+    .for_field("single", limits.max_single_size)
+    .for_field("remote", 4096)
+    .for_field("multi", limits.max_archive_size)
     .for_field("config", 1024u64.pow(2) * 5);
 
   let content_type = headers
@@ -129,99 +418,299 @@ fn parse_multipart(headers: &HeaderMap, body: Body) -> Result<Multipart<'static>
 }
 
 async fn read_store_service_temp(
-  abel_path: &Path,
+  state: &ServerState,
   kind: SourceKind,
-  mut source_stream: impl Stream<Item = io::Result<Bytes>> + Unpin,
+  source_stream: impl Stream<Item = io::Result<Bytes>> + Unpin,
 ) -> Result<(PathBuf, Source, Config)> {
-  let temp_path = abel_path.join(format!("tmp/{}", Uuid::new_v4()));
+  let temp_path = state.abel_path.join(format!("tmp/{}", Uuid::new_v4()));
+  match store_service_temp(&temp_path, &state.upload_limits, kind, source_stream).await {
+    Ok((source, config)) => Ok((temp_path, source, config)),
+    Err(error) => {
+      if temp_path.exists() {
+        fs::remove_file(&temp_path).await?;
+      }
+      Err(error)
+    }
+  }
+}
 
+async fn store_service_temp(
+  temp_path: &Path,
+  limits: &UploadLimits,
+  kind: SourceKind,
+  mut source_stream: impl Stream<Item = io::Result<Bytes>> + Unpin,
+) -> Result<(Source, Config)> {
+  jobs::report(JobPhase::Extracting);
   let (source, config) = match kind {
     SourceKind::Single => {
       let mut code = BytesMut::new();
       while let Some(chunk) = source_stream.try_next().await? {
         code.extend(chunk);
+        check_size("source", code.len() as _, limits.max_single_size)?;
       }
       fs::write(&temp_path, &code).await?;
 
+      let config = Config {
+        content_hash: Some(hash_single(&code)),
+        ..Default::default()
+      };
       let source = Source::new(SingleSource::new(code));
-      (source, Default::default())
+      (source, config)
     }
     SourceKind::Multi => {
       let mut reader = StreamReader::new(source_stream);
       let mut writer = File::create(&temp_path).await?;
-      io::copy(&mut reader, &mut writer).await?;
+      let size = io::copy(&mut reader, &mut writer).await?;
+      check_size("archive", size, limits.max_archive_size)?;
 
       let mut archive = Archive::new_from_file(&temp_path).await?;
+      check_archive(&archive, limits)?;
 
-      let config = if let Ok(mut config_file) = archive.get("abel.json").await {
-        let mut config_bytes = vec![0; config_file.metadata().size as _];
+      let mut config: Config = if let Ok(mut config_file) = archive.get("abel.json").await {
+        let mut config_bytes = Vec::with_capacity(config_file.metadata().size as _);
         config_file.read_to_end(&mut config_bytes).await?;
-        serde_json::from_slice(&config_bytes)?
+        Config::from_json(&config_bytes)?
       } else {
         Default::default()
       };
+      config.content_hash = Some(hash_archive(&temp_path).await?);
 
       let source = Source::new(AsarSource(archive));
       (source, config)
     }
   };
 
-  Ok((temp_path, source, config))
+  Ok((source, config))
+}
+
+fn now() -> u64 {
+  (SystemTime::now().duration_since(UNIX_EPOCH))
+    .map(|x| x.as_secs())
+    .unwrap_or(0)
+}
+
+pub(super) fn check_size(what: &str, size: u64, limit: u64) -> Result<()> {
+  if size > limit {
+    return Err(From::from((
+      413,
+      "payload too large",
+      json!({ "msg": format!("{what} exceeds size limit"), "limit": limit }),
+    )));
+  }
+  Ok(())
+}
+
+/// Checks the entries of an uploaded archive against upload limits, and that
+/// none of them escapes the archive when extracted.
+fn check_archive(archive: &Archive<DuplicableFile>, limits: &UploadLimits) -> Result<()> {
+  let mut count = 0;
+  let mut dirs = match archive.get_entry("") {
+    Some(Entry::Directory(root)) => vec![(String::new(), root)],
+    _ => Vec::new(),
+  };
+  while let Some((prefix, dir)) = dirs.pop() {
+    for (name, entry) in &dir.files {
+      let path = prefix.clone() + name;
+      if name.is_empty() || name.contains(['/', '\\']) || normalize_path_str(name) != **name {
+        return Err(From::from(("invalid file path", json!({ "path": path }))));
+      }
+      match entry {
+        Entry::File(file) => {
+          count += 1;
+          if count > limits.max_files {
+            return Err(From::from((
+              413,
+              "payload too large",
+              json!({ "msg": "too many files in archive", "limit": limits.max_files }),
+            )));
+          }
+          check_size(&format!("file '{path}'"), file.size, limits.max_file_size)?;
+        }
+        Entry::Directory(dir) => dirs.push((path + "/", dir)),
+      }
+    }
+  }
+  Ok(())
+}
+
+/// Reports the job deploying a service, if any, as swapping once the new
+/// version is evaluated.
+fn report_swapping() -> BeforeSwap {
+  let report = jobs::reporter();
+  Box::new(move || {
+    report(JobPhase::Swapping);
+    Box::pin(ready(Ok(())))
+  })
+}
+
+/// Reports the job deploying a service, if any, as swapping once the new
+/// version is evaluated, and commits the service's staged directory before the
+/// new version replaces the current one.
+fn commit_on_swap(services_path: PathBuf, name: String) -> BeforeSwap {
+  let report = jobs::reporter();
+  Box::new(move || {
+    report(JobPhase::Swapping);
+    Box::pin(async move { layout::commit(&services_path, &name).await })
+  })
+}
+
+/// Where the source of a new service is kept.
+enum StoredSource<'a> {
+  /// Uploaded source, temporarily stored at `temp_path`.
+  Local {
+    kind: SourceKind,
+    temp_path: &'a Path,
+  },
+  Remote(&'a str),
 }
 
 async fn create_service<'a>(
   state: &'a ServerState,
+  lock: &DeployGuard,
   mode: UploadMode,
-  name: String,
-  config: Config,
+  force: bool,
+  mut config: Config,
   source: Source,
-  source_kind: SourceKind,
-  temp_path: &Path,
+  stored: StoredSource<'_>,
 ) -> Result<UploadResponse<'a>> {
-  let (new_service, replaced_service, errors) = match mode {
-    UploadMode::Create if state.abel.get_service(&name).is_ok() => {
-      return Err(ServiceExists { name: name.into() }.into())
+  let name = lock.name();
+  if state.abel.get_canary(name).is_some() {
+    return Err(From::from((
+      "canary in progress",
+      json!({ "msg": "promote or abort the canary first", "name": name }),
+    )));
+  }
+
+  let hash = config.content_hash.clone();
+  if let (Some(hash), false) = (&hash, force) {
+    let unchanged = matches!(mode, UploadMode::Hot | UploadMode::Cold)
+      && matches!(
+        state.abel.get_running_service(name),
+        Ok(service) if service.upgrade().content_hash() == Some(hash),
+      );
+    if unchanged {
+      if let StoredSource::Local { temp_path, .. } = stored {
+        fs::remove_file(temp_path).await?;
+      }
+      return Err(From::from((
+        409,
+        "service unchanged",
+        json!({ "msg": "upload with `force` to update anyway", "name": name, "hash": hash }),
+      )));
     }
-    UploadMode::Hot if state.abel.get_running_service(&name).is_ok() => {
-      let (service, replaced) = (state.abel)
-        .hot_update_service(name, None, source, config)
-        .await?;
-      (
-        Service::Running(service),
-        Some(replaced),
-        Default::default(),
-      )
+  }
+
+  let granted = approval::granted(state, name).await?;
+  let pending = !granted.covers(&config.permissions);
+  config.granted = Some(granted.clone());
+  config.deployed_at = Some(now());
+  let uuid = (hash.as_deref()).map_or_else(Uuid::new_v4, |hash| derive_uuid(name, hash));
+  // Held aside while the approved version keeps serving
+  let aside =
+    pending && matches!(state.abel.get_service(name), Ok(s) if !s.upgrade().pending_approval());
+
+  if mode == UploadMode::Create && state.abel.get_service(name).is_ok() {
+    return Err(ServiceExists { name: name.into() }.into());
+  }
+
+  // The new directory, along with the new metadata, is committed right before
+  // the new version replaces the current one, so that neither is missing or
+  // mismatched after a crash.
+  let services_path = state.abel_path.join("services");
+  let previous = state.metadata.get(name).await.ok();
+  let service_path = layout::stage(&services_path, name, &["metadata.json"]).await?;
+  let remote = match stored {
+    StoredSource::Local {
+      kind: SourceKind::Single,
+      temp_path,
+    } => {
+      fs::rename(temp_path, service_path.join("source.lua")).await?;
+      None
     }
-    UploadMode::Hot | UploadMode::Cold | UploadMode::Create => {
-      (state.abel)
-        .cold_update_or_create_service(name, None, source, config)
-        .await?
+    StoredSource::Local {
+      kind: SourceKind::Multi,
+      temp_path,
+    } => {
+      fs::hard_link(temp_path, service_path.join("source.asar")).await?;
+      None
     }
-    UploadMode::Load => {
-      let (service, replaced, error_payload) = (state.abel)
-        .load_service(name, None, source, config)
-        .await?;
-      (Service::Stopped(service), replaced, error_payload)
+    StoredSource::Remote(base) => Some(base.into()),
+  };
+  maintenance::persist(state, name, &service_path).await?;
+  let mut metadata = Metadata {
+    uuid,
+    started: !pending,
+    remote,
+    granted: Some(granted),
+    pending_approval: pending,
+    hash,
+    deployed_at: config.deployed_at,
+    revision: 0,
+    owner: None,
+    created_at: config.deployed_at,
+    history: Vec::new(),
+  };
+  if let Some(previous) = previous {
+    metadata.succeed(previous);
+  }
+  metadata.write(&service_path).await?;
+
+  jobs::report(JobPhase::Evaluating);
+  let on_swap = || Some(commit_on_swap(services_path.clone(), name.into()));
+  let deployed: Result<_> = async {
+    Ok(match mode {
+      UploadMode::Canary => unreachable!("canaries are deployed with `upload_canary`"),
+      _ if pending => {
+        jobs::report(JobPhase::Swapping);
+        let (service, replaced) = (state.abel)
+          .hold_service(lock, Some(uuid), source, config)
+          .await?;
+        (Service::Stopped(service), replaced, Default::default())
+      }
+      UploadMode::Hot if state.abel.get_running_service(name).is_ok() => {
+        let (service, replaced) = (state.abel)
+          .hot_update_service(lock, Some(uuid), source, config, on_swap())
+          .await?;
+        (
+          Service::Running(service),
+          Some(replaced),
+          Default::default(),
+        )
+      }
+      UploadMode::Hot | UploadMode::Cold | UploadMode::Create => {
+        (state.abel)
+          .cold_update_or_create_service(lock, Some(uuid), source, config, on_swap())
+          .await?
+      }
+      UploadMode::Load => {
+        let (service, replaced, error_payload) = (state.abel)
+          .load_service(lock, Some(uuid), source, config, on_swap())
+          .await?;
+        (Service::Stopped(service), replaced, error_payload)
+      }
+    })
+  }
+  .await;
+  let (new_service, replaced_service, errors) = match deployed {
+    Ok(deployed) => deployed,
+    Err(error) => {
+      layout::discard(&services_path, name).await?;
+      return Err(error);
     }
   };
   let guard = new_service.upgrade();
 
-  let service_path = state.abel_path.join("services").join(guard.name());
-  if service_path.exists() {
-    fs::remove_dir_all(&service_path).await?;
+  // Held versions do not serve, so their directories are kept once loaded
+  if aside {
+    layout::hold(&services_path, name).await?;
+  } else if pending {
+    layout::commit(&services_path, name).await?;
   }
-  fs::create_dir(&service_path).await?;
 
-  let metadata = Metadata {
+  state.abel.publish(EventKind::Deployed {
+    service: guard.name().into(),
     uuid: guard.uuid(),
-    started: true,
-  };
-  metadata.write(&service_path.join("metadata.json")).await?;
-
-  match source_kind {
-    SourceKind::Single => fs::rename(temp_path, service_path.join("source.lua")).await?,
-    SourceKind::Multi => fs::hard_link(temp_path, service_path.join("source.asar")).await?,
-  }
+  });
 
   Ok(UploadResponse {
     new_service,
@@ -251,13 +740,23 @@ pub fn log_result(
       format!("({})", service.uuid()).dimmed(),
     );
   }
+  if service.pending_approval() {
+    info!(
+      "Service '{}' is pending approval of permissions {:?}",
+      service.name(),
+      service.permissions(),
+    );
+  }
   if !errors.is_empty() {
     warn!("errors: {errors:?}");
   }
 }
 
-async fn response(resp: UploadResponse<'_>) -> Result<Response<Body>> {
-  log_result(&resp);
+pub(super) async fn response(resp: UploadResponse<'_>) -> Result<Response<Body>> {
+  json_response(StatusCode::OK, response_body(resp)?)
+}
+
+fn response_body(resp: UploadResponse<'_>) -> serde_json::Result<serde_json::Value> {
   let UploadResponse {
     new_service,
     replaced_service,
@@ -270,5 +769,35 @@ async fn response(resp: UploadResponse<'_>) -> Result<Response<Body>> {
     replaced_service: replaced_service.as_ref().map(|x| Cow::Borrowed(x.info())),
     errors: errors.into(),
   };
-  json_response(StatusCode::OK, body)
+  serde_json::to_value(body)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use hive_asar::pack_dir_into_stream;
+  use tempfile::TempDir;
+
+  #[tokio::test]
+  async fn test_store_multi_config() -> Result<()> {
+    let dir = TempDir::new()?;
+    let source_path = dir.path().join("source");
+    std::fs::create_dir(&source_path)?;
+    std::fs::write(
+      source_path.join("main.lua"),
+      "abel.listen('/', function() end)",
+    )?;
+    std::fs::write(
+      source_path.join("abel.json"),
+      r#"{ "description": "test" }"#,
+    )?;
+
+    let stream = pack_dir_into_stream(&source_path).await?;
+    let temp_path = dir.path().join("source.asar");
+    let limits = UploadLimits::default();
+    let (_, config) = store_service_temp(&temp_path, &limits, SourceKind::Multi, stream).await?;
+    assert_eq!(config.description.as_deref(), Some("test"));
+    assert!(config.content_hash.is_some());
+    Ok(())
+  }
 }
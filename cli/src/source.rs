@@ -1,12 +1,15 @@
-use abel_core::normalize_path_str;
 use abel_core::source::{Metadata, SourceVfs};
+use abel_core::{encode_s3_key, normalize_path_str, S3Credentials};
 use async_trait::async_trait;
 use hive_asar::header::Entry;
 use hive_asar::{Archive, DuplicableFile};
+use hyper::Method;
+use std::collections::HashMap;
 use std::io::Cursor;
-use std::path::PathBuf;
-use std::sync::Arc;
-use tokio::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{self, AsyncReadExt};
 
 pub struct AsarSource(pub(crate) Archive<DuplicableFile>);
 
@@ -31,6 +34,20 @@ impl SourceVfs for AsarSource {
       Entry::File(m) => Ok(Metadata::File { size: m.size }),
     }
   }
+
+  async fn read_dir(&self, path: &str) -> io::Result<Vec<String>> {
+    let entry = (self.0)
+      .get_entry(path)
+      .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "No such file or directory"))?;
+    match entry {
+      Entry::Directory(dir) => {
+        let mut names = dir.files.keys().map(|x| x.to_string()).collect::<Vec<_>>();
+        names.sort();
+        Ok(names)
+      }
+      Entry::File(_) => Err(io::Error::from_raw_os_error(libc::ENOTDIR)),
+    }
+  }
 }
 
 pub struct SingleSource(Arc<[u8]>);
@@ -75,34 +92,168 @@ impl SourceVfs for SingleSource {
       )),
     }
   }
+
+  async fn read_dir(&self, path: &str) -> io::Result<Vec<String>> {
+    match &*normalize_path_str(path) {
+      "" => Ok(vec!["main.lua".into()]),
+      "main.lua" => Err(io::Error::from_raw_os_error(libc::ENOTDIR)),
+      _ => Err(io::Error::new(
+        io::ErrorKind::NotFound,
+        "No such file or directory",
+      )),
+    }
+  }
+}
+
+/// How long a cached object is used before revalidating it.
+const REVALIDATE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Source stored in an HTTP-accessible object storage bucket, such as S3, GCS
+/// or MinIO, under a common prefix.
+///
+/// Requests are signed with AWS Signature Version 4 if credentials are given.
+/// Objects are cached locally and revalidated with their ETags at most once a
+/// minute. If the bucket is unreachable, the cached copy is used as is.
+pub struct ObjectSource {
+  base: String,
+  client: reqwest::Client,
+  credentials: Option<S3Credentials>,
+  cache_path: PathBuf,
+  /// When each cached object was last fetched or revalidated.
+  validated: Mutex<HashMap<String, Instant>>,
 }
 
-pub struct DirSource(pub(crate) PathBuf);
+impl ObjectSource {
+  pub fn new(base: &str, abel_path: &Path, credentials: Option<S3Credentials>) -> Self {
+    Self {
+      base: base.trim_end_matches('/').into(),
+      client: reqwest::Client::new(),
+      credentials,
+      cache_path: abel_path.join("object_cache"),
+      validated: Default::default(),
+    }
+  }
+
+  /// Reads the service's config, leniently if `lenient` is set, e.g. when
+  /// restoring a saved service.
+  pub async fn read_config(&self, lenient: bool) -> io::Result<abel_core::Config> {
+    match self.get("abel.json").await {
+      Ok(mut file) => {
+        let mut config_bytes = Vec::new();
+        file.read_to_end(&mut config_bytes).await?;
+        let config = if lenient {
+          abel_core::Config::from_json_lenient(&config_bytes)
+        } else {
+          abel_core::Config::from_json(&config_bytes)
+        };
+        config.map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))
+      }
+      Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(Default::default()),
+      Err(error) => Err(error),
+    }
+  }
+
+  /// Fetches the object into the local cache, returning the cached file's path.
+  async fn fetch(&self, path: &str) -> io::Result<PathBuf> {
+    use sha2::{Digest, Sha256};
+
+    let url = format!("{}/{}", self.base, encode_s3_key(&normalize_path_str(path)));
+    let key = data_encoding::HEXLOWER.encode(&Sha256::digest(url.as_bytes()));
+    let file_path = self.cache_path.join(&key);
+    let etag_path = self.cache_path.join(format!("{key}.etag"));
+
+    let fresh = (self.validated.lock().unwrap().get(&key))
+      .map_or(false, |x| x.elapsed() < REVALIDATE_INTERVAL);
+    if fresh && file_path.exists() {
+      return Ok(file_path);
+    }
+
+    let etag = tokio::fs::read_to_string(&etag_path).await.ok();
+    let mut req = self.client.get(&url);
+    if let Some(credentials) = &self.credentials {
+      let uri = (url.parse::<hyper::Uri>())
+        .map_err(|error| io::Error::new(io::ErrorKind::InvalidInput, error))?;
+      let headers = (credentials.authorize(&Method::GET, &uri))
+        .map_err(|error| io::Error::new(io::ErrorKind::InvalidInput, error))?;
+      req = req.headers(headers);
+    }
+    if let Some(etag) = &etag {
+      req = req.header(reqwest::header::IF_NONE_MATCH, etag);
+    }
+    let resp = match req.send().await {
+      Ok(resp) => resp,
+      Err(_) if etag.is_some() => return Ok(file_path),
+      Err(error) => return Err(io::Error::new(io::ErrorKind::Other, error)),
+    };
+
+    match resp.status() {
+      reqwest::StatusCode::NOT_MODIFIED if etag.is_some() => {
+        self.validated.lock().unwrap().insert(key, Instant::now());
+        Ok(file_path)
+      }
+      reqwest::StatusCode::NOT_FOUND => Err(io::Error::new(
+        io::ErrorKind::NotFound,
+        "No such file or directory",
+      )),
+      status if status.is_success() => {
+        let new_etag = (resp.headers().get(reqwest::header::ETAG))
+          .and_then(|x| x.to_str().ok())
+          .map(ToOwned::to_owned);
+        let bytes = (resp.bytes().await).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+
+        // Writes to a temporary file first, so that readers never see a
+        // partially written object
+        tokio::fs::create_dir_all(&self.cache_path).await?;
+        let temp_path = self
+          .cache_path
+          .join(format!("{key}.{}", uuid::Uuid::new_v4()));
+        tokio::fs::write(&temp_path, &bytes).await?;
+        tokio::fs::rename(&temp_path, &file_path).await?;
+        match new_etag {
+          Some(etag) => tokio::fs::write(&etag_path, etag).await?,
+          None => match tokio::fs::remove_file(&etag_path).await {
+            Err(error) if error.kind() != io::ErrorKind::NotFound => return Err(error),
+            _ => {}
+          },
+        }
+        self.validated.lock().unwrap().insert(key, Instant::now());
+        Ok(file_path)
+      }
+      status => Err(io::Error::new(
+        io::ErrorKind::Other,
+        format!("object storage responded with {status}"),
+      )),
+    }
+  }
+}
 
 #[async_trait]
-impl SourceVfs for DirSource {
+impl SourceVfs for ObjectSource {
   type File = tokio::fs::File;
 
   async fn get(&self, path: &str) -> io::Result<Self::File> {
-    tokio::fs::File::open(self.0.join(normalize_path_str(path))).await
+    if normalize_path_str(path).is_empty() {
+      return Err(io::Error::from_raw_os_error(libc::EISDIR));
+    }
+    tokio::fs::File::open(self.fetch(path).await?).await
   }
 
   async fn exists(&self, path: &str) -> io::Result<bool> {
-    Ok(
-      tokio::fs::metadata(self.0.join(normalize_path_str(path)))
-        .await
-        .is_ok(),
-    )
+    match self.metadata(path).await {
+      Ok(_) => Ok(true),
+      Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(false),
+      Err(error) => Err(error),
+    }
   }
 
+  /// Object storage has no directories; only the root is treated as one.
   async fn metadata(&self, path: &str) -> io::Result<Metadata> {
-    let metadata = tokio::fs::metadata(self.0.join(normalize_path_str(path))).await?;
-    if metadata.is_file() {
-      Ok(Metadata::File {
-        size: metadata.len(),
-      })
-    } else {
-      Ok(Metadata::Dir)
+    if normalize_path_str(path).is_empty() {
+      return Ok(Metadata::Dir);
     }
+    let metadata = tokio::fs::metadata(self.fetch(path).await?).await?;
+    Ok(Metadata::File {
+      size: metadata.len(),
+    })
   }
 }
@@ -1,8 +1,575 @@
-use serde::Deserialize;
+use crate::net::{Cidr, HostPattern, NetRule};
+use crate::runtime::check_name;
+use crate::service::Availability;
+use crate::version::{Version, VersionReq};
+use crate::ErrorKind::InvalidConfig;
+use crate::Result;
+use bstr::ByteSlice;
+use hyper::header::{HeaderName, HeaderValue, COOKIE};
+use hyper::HeaderMap;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::Path;
 
+/// Longest `request_timeout` in seconds.
+pub const MAX_REQUEST_TIMEOUT: u64 = 3600;
+
+/// Most requests a service may keep with `capture_requests`.
+pub const MAX_CAPTURE_REQUESTS: usize = 100;
+
+/// Service config, read from `abel.json` in the source root.
 #[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct Config {
   #[serde(rename = "name")]
   pub pkg_name: Option<String>,
   pub description: Option<String>,
+  /// Version of the service itself, e.g. `1.2.0`.
+  pub version: Option<Version>,
+  /// Lua API versions the service works with, e.g. `1.0`. Services
+  /// incompatible with [`API_VERSION`] are rejected.
+  ///
+  /// [`API_VERSION`]: crate::API_VERSION
+  pub abel_api_version: Option<VersionReq>,
+  /// Path to a static page in the source, served in place of the JSON error
+  /// body when the service fails with a server error.
+  pub error_page: Option<String>,
+  /// Number of recent requests to keep for debugging and replay, at most
+  /// [`MAX_CAPTURE_REQUESTS`]. Capturing is disabled if not set.
+  pub capture_requests: Option<usize>,
+  /// Milliseconds after which a request is considered slow, keeping a
+  /// profile of its Lua code. Profiling is disabled if not set.
+  pub profile_threshold: Option<u64>,
+  /// Routes requests with the same session key to the same worker, so that
+  /// worker-local state like caches behaves predictably.
+  pub affinity: Option<Affinity>,
+  /// Path to a redirect map in the source. Matching requests are redirected
+  /// without running the service's code.
+  pub redirects: Option<String>,
+  /// Sentry-compatible DSN receiving the service's Lua runtime errors, in
+  /// place of the server's if the host supports error reporting. Reports are
+  /// only sent where the service's `net` permission allows it to connect.
+  pub error_reporting: Option<String>,
+  /// Seconds a request may take before failing, at most
+  /// [`MAX_REQUEST_TIMEOUT`]. Outbound calls made while handling it time out
+  /// no later than that.
+  pub request_timeout: Option<u64>,
+  /// Maximum number of requests handled by the service at the same time.
+  pub max_concurrency: Option<usize>,
+  /// Maximum number of requests waiting when `max_concurrency` is reached.
+  /// Further requests are rejected. Defaults to 0.
+  pub max_queued: Option<usize>,
+  /// Services that must be running before this one starts. At boot, services
+  /// are restored in dependency order.
+  #[serde(default)]
+  pub depends_on: Vec<String>,
+  /// Seconds to wait for the service to call `abel.ready()` after starting.
+  /// If set, no requests are routed to the service until it is ready, and
+  /// starting fails on timeout.
+  pub ready_timeout: Option<u64>,
+  /// Seconds without requests after which the service is suspended (stopped)
+  /// to free resources. A request to a suspended service starts it again.
+  pub idle_timeout: Option<u64>,
+  /// Seconds a request may wait for a suspended service to start.
+  pub wake_timeout: Option<u64>,
+  /// Paths, optionally with queries, requested with `GET` after each update
+  /// and before the new version receives traffic, priming its caches.
+  #[serde(default)]
+  pub warm_up: Vec<String>,
+  /// Cron-like windows during which the service runs. It is started and
+  /// stopped automatically as windows begin and end.
+  pub availability: Option<Availability>,
+  /// Client addresses allowed to access the service. All are allowed if
+  /// empty.
+  #[serde(default)]
+  pub allow_ips: Vec<Cidr>,
+  /// Client addresses denied access, even if allowed by `allow_ips`.
+  #[serde(default)]
+  pub deny_ips: Vec<Cidr>,
+  /// Access rules of paths, keyed by patterns like `/admin/*`, enforced
+  /// before the request reaches the service's code.
+  #[serde(default)]
+  pub routes: BTreeMap<String, RouteRule>,
+  /// Headers added to responses of the service unless it sets them itself,
+  /// including error responses, e.g. `Content-Security-Policy`.
+  #[serde(default)]
+  pub headers: BTreeMap<String, String>,
+  /// Maximum size of a response body in bytes. Larger responses are rejected,
+  /// or aborted if streamed.
+  pub max_response_size: Option<u64>,
+  /// Maximum bytes of response bodies per minute.
+  pub response_quota: Option<u64>,
+  /// Capabilities beyond the default sandbox.
+  #[serde(default)]
+  pub permissions: Permissions,
+  /// Permissions granted by the host, restricting `permissions` if set. Not
+  /// read from the service's config.
+  #[serde(skip)]
+  pub granted: Option<Permissions>,
+  /// Hash of the service's source, computed by the host. Not read from the
+  /// service's config.
+  #[serde(skip)]
+  pub content_hash: Option<String>,
+  /// Unix time in seconds the service was deployed, recorded by the host. Not
+  /// read from the service's config.
+  #[serde(skip)]
+  pub deployed_at: Option<u64>,
+}
+
+impl Config {
+  /// Parses and validates a service's `abel.json`, reporting the offending
+  /// field on error.
+  pub fn from_json(bytes: &[u8]) -> Result<Self> {
+    let value: serde_json::Value =
+      serde_json::from_slice(bytes).map_err(|error| invalid("", error))?;
+    let config = match serde_json::from_value::<Self>(value.clone()) {
+      Ok(config) => config,
+      Err(error) => {
+        // Fields are independent, so parsing them one at a time locates the
+        // offending one.
+        if let serde_json::Value::Object(fields) = value {
+          for (field, value) in fields {
+            let single = serde_json::Map::from_iter([(field.clone(), value)]);
+            if let Err(error) = serde_json::from_value::<Self>(single.into()) {
+              return Err(invalid(&field, error).into());
+            }
+          }
+        }
+        return Err(invalid("", error).into());
+      }
+    };
+    config.validate()?;
+    Ok(config)
+  }
+
+  /// Like [`Config::from_json`], but drops offending fields with a warning
+  /// instead of failing, so that services saved by an older version are
+  /// still restored after the rules get stricter.
+  pub fn from_json_lenient(bytes: &[u8]) -> Result<Self> {
+    let mut value: serde_json::Value =
+      serde_json::from_slice(bytes).map_err(|error| invalid("", error))?;
+    loop {
+      let error = match Self::from_json(value.to_string().as_bytes()) {
+        Ok(config) => return Ok(config),
+        Err(error) => error,
+      };
+      // Nested fields like `permissions.exec` are dropped as a whole
+      let field = match error.kind() {
+        InvalidConfig { field, .. } => field.split('.').next().unwrap_or_default(),
+        _ => return Err(error),
+      };
+      match value.as_object_mut().and_then(|x| x.remove(field)) {
+        Some(_) => warn!("ignoring config field '{field}': {error}"),
+        None => return Err(error),
+      }
+    }
+  }
+
+  /// Checks values that are well-typed but meaningless.
+  pub fn validate(&self) -> Result<()> {
+    let positive = [
+      ("capture_requests", self.capture_requests.map(|x| x as u64)),
+      ("profile_threshold", self.profile_threshold),
+      ("request_timeout", self.request_timeout),
+      ("max_concurrency", self.max_concurrency.map(|x| x as u64)),
+      ("ready_timeout", self.ready_timeout),
+      ("idle_timeout", self.idle_timeout),
+      ("wake_timeout", self.wake_timeout),
+      ("max_response_size", self.max_response_size),
+      ("response_quota", self.response_quota),
+    ];
+    for (field, value) in positive {
+      if value == Some(0) {
+        return Err(invalid(field, "must be positive").into());
+      }
+    }
+    if matches!(self.request_timeout, Some(x) if x > MAX_REQUEST_TIMEOUT) {
+      let reason = format!("must be at most {MAX_REQUEST_TIMEOUT}");
+      return Err(invalid("request_timeout", reason).into());
+    }
+    if matches!(self.capture_requests, Some(x) if x > MAX_CAPTURE_REQUESTS) {
+      let reason = format!("must be at most {MAX_CAPTURE_REQUESTS}");
+      return Err(invalid("capture_requests", reason).into());
+    }
+    for (field, value) in [
+      ("error_page", &self.error_page),
+      ("redirects", &self.redirects),
+      ("error_reporting", &self.error_reporting),
+    ] {
+      if value.as_deref() == Some("") {
+        return Err(invalid(field, "must not be empty").into());
+      }
+    }
+    if self.max_queued.is_some() && self.max_concurrency.is_none() {
+      return Err(invalid("max_queued", "requires max_concurrency").into());
+    }
+    if let Some(name) = self.depends_on.iter().find(|x| check_name(x).is_err()) {
+      let reason = format!("invalid service name '{name}'");
+      return Err(invalid("depends_on", reason).into());
+    }
+    if matches!(&self.availability, Some(x) if x.windows.is_empty()) {
+      return Err(invalid("availability", "no windows").into());
+    }
+    if let Some(path) = self.warm_up.iter().find(|x| !x.starts_with('/')) {
+      let reason = format!("path '{path}' does not start with '/'");
+      return Err(invalid("warm_up", reason).into());
+    }
+    if let Some(pattern) = self.routes.keys().find(|x| !x.starts_with('/')) {
+      let reason = format!("route pattern '{pattern}' does not start with '/'");
+      return Err(invalid("routes", reason).into());
+    }
+    if let Some(pattern) = (self.routes.iter())
+      .find(|(_, x)| x.token_secret.as_deref() == Some(""))
+      .map(|(x, _)| x)
+    {
+      let reason = format!("route pattern '{pattern}': token_secret must not be empty");
+      return Err(invalid("routes", reason).into());
+    }
+    parse_headers(&self.headers).map_err(|reason| invalid("headers", reason))?;
+    let exec = &self.permissions.exec;
+    if let Some(path) = exec.iter().find(|x| !Path::new(x).is_absolute()) {
+      let reason = format!("program path '{path}' is not absolute");
+      return Err(invalid("permissions.exec", reason).into());
+    }
+    Ok(())
+  }
+}
+
+/// Parses headers given as names and values.
+pub fn parse_headers(headers: &BTreeMap<String, String>) -> Result<HeaderMap, String> {
+  let mut map = HeaderMap::with_capacity(headers.len());
+  for (name, value) in headers {
+    let name = HeaderName::from_bytes(name.as_bytes())
+      .map_err(|_| format!("invalid header name '{name}'"))?;
+    let value =
+      HeaderValue::from_str(value).map_err(|_| format!("invalid value of header '{name}'"))?;
+    map.insert(name, value);
+  }
+  Ok(map)
+}
+
+/// Adds headers to a response, except those it already has.
+pub fn add_default_headers(headers: &mut HeaderMap, defaults: &HeaderMap) {
+  for (name, value) in defaults {
+    if !headers.contains_key(name) {
+      headers.insert(name, value.clone());
+    }
+  }
+}
+
+fn invalid(field: &str, reason: impl ToString) -> crate::ErrorKind {
+  InvalidConfig {
+    field: field.into(),
+    reason: reason.to_string(),
+  }
+}
+
+/// Access rule of paths matching a route pattern.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RouteRule {
+  /// Whether requests must carry the route's token, as
+  /// `Authorization: Bearer <token>`.
+  #[serde(default)]
+  pub require_token: bool,
+  /// Name of the service's secret holding the token. Defaults to
+  /// `route_token`.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub token_secret: Option<String>,
+}
+
+impl RouteRule {
+  /// Name of the service's secret holding the token, if one is required.
+  pub fn token_secret(&self) -> Option<&str> {
+    (self.require_token).then(|| self.token_secret.as_deref().unwrap_or("route_token"))
+  }
+}
+
+/// Whether `path` matches a route `pattern`, in which `*` matches any
+/// characters. A trailing `/*` also matches the path without it.
+pub(crate) fn route_matches(pattern: &str, path: &str) -> bool {
+  if let Some(prefix) = pattern.strip_suffix("/*") {
+    if path == prefix {
+      return true;
+    }
+  }
+  match_wildcards(pattern, path).is_some()
+}
+
+/// Matches `path` against a `pattern` in which `*` matches any characters,
+/// returning what each `*` matched.
+pub(crate) fn match_wildcards<'a>(pattern: &str, path: &'a str) -> Option<Vec<&'a str>> {
+  let mut parts = pattern.split('*');
+  let mut rest = path.strip_prefix(parts.next().unwrap_or_default())?;
+  let parts = parts.collect::<Vec<_>>();
+  let (last, middle) = match parts.split_last() {
+    Some(x) => x,
+    None => return rest.is_empty().then(Vec::new),
+  };
+  let mut captures = Vec::with_capacity(parts.len());
+  for part in middle {
+    let i = rest.find(part)?;
+    captures.push(&rest[..i]);
+    rest = &rest[i + part.len()..];
+  }
+  captures.push(rest.strip_suffix(last)?);
+  Some(captures)
+}
+
+/// Capabilities a service must declare to use certain modules.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Permissions {
+  /// Outbound network access.
+  #[serde(default)]
+  pub net: NetPermission,
+  /// Addresses the `socket` module may connect to, as `host:port` patterns.
+  /// The module is unavailable if empty.
+  #[serde(default)]
+  pub socket: Vec<HostPattern>,
+  /// Emails per minute the service may send with the `email` module. The
+  /// module is unavailable if not set.
+  #[serde(default)]
+  pub email: Option<u32>,
+  /// Programs the `exec` module may run, as absolute paths. Only those also
+  /// in the host's allowlist are available, and the module is unavailable if
+  /// none is.
+  #[serde(default)]
+  pub exec: Vec<String>,
+  /// Whether the `oauth` module is available.
+  #[serde(default)]
+  pub oauth: bool,
+}
+
+impl Permissions {
+  pub fn is_default(&self) -> bool {
+    *self == Self::default()
+  }
+
+  /// Whether these permissions include everything in `other`.
+  pub fn covers(&self, other: &Self) -> bool {
+    self.net.covers(&other.net)
+      && other.socket.iter().all(|x| self.socket.contains(x))
+      && match (self.email, other.email) {
+        (_, None) => true,
+        (Some(granted), Some(requested)) => granted >= requested,
+        (None, Some(_)) => false,
+      }
+      && other.exec.iter().all(|x| self.exec.contains(x))
+      && (self.oauth || !other.oauth)
+  }
+
+  /// Requested permissions limited to what is `granted`.
+  pub fn restrict(&self, granted: &Self) -> Self {
+    Self {
+      net: self.net.restrict(&granted.net),
+      socket: (self.socket.iter())
+        .filter(|x| granted.socket.contains(x))
+        .cloned()
+        .collect(),
+      email: match (self.email, granted.email) {
+        (Some(requested), Some(granted)) => Some(requested.min(granted)),
+        _ => None,
+      },
+      exec: (self.exec.iter())
+        .filter(|x| granted.exec.contains(x))
+        .cloned()
+        .collect(),
+      oauth: self.oauth && granted.oauth,
+    }
+  }
+}
+
+/// Outbound network access of a service, applied to every module reaching
+/// out: `http`, `s3`, `oauth`, `dns` and `socket`.
+///
+/// Nothing is reachable without this permission. `true` allows everything. A
+/// list of rules only allows matching destinations, and names matching host
+/// patterns to be looked up with `dns`. Denied access is raised as an HTTP
+/// error with the destination in its detail.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum NetPermission {
+  All(bool),
+  Rules(Vec<NetRule>),
+}
+
+impl Default for NetPermission {
+  fn default() -> Self {
+    Self::All(false)
+  }
+}
+
+impl NetPermission {
+  /// Whether this permission includes everything in `other`.
+  pub fn covers(&self, other: &Self) -> bool {
+    match (self, other) {
+      (_, Self::All(false)) | (Self::All(true), _) => true,
+      (Self::Rules(granted), Self::Rules(requested)) => {
+        requested.iter().all(|x| granted.contains(x))
+      }
+      _ => false,
+    }
+  }
+
+  /// Requested access limited to what is `granted`.
+  ///
+  /// Revoking rules from a service leaves it with no destinations.
+  pub fn restrict(&self, granted: &Self) -> Self {
+    match (self, granted) {
+      (Self::All(false), _) | (_, Self::All(true)) => self.clone(),
+      (Self::All(true), Self::All(false)) => Self::All(false),
+      (Self::All(true), Self::Rules(_)) => granted.clone(),
+      (Self::Rules(_), Self::All(false)) => Self::Rules(Vec::new()),
+      (Self::Rules(requested), Self::Rules(granted)) => Self::Rules(
+        (requested.iter())
+          .filter(|x| granted.contains(x))
+          .cloned()
+          .collect(),
+      ),
+    }
+  }
+}
+
+/// Where to find the session key of a request.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Affinity {
+  Header(String),
+  Cookie(String),
+}
+
+impl Affinity {
+  pub fn session_key<'a>(&self, headers: &'a HeaderMap) -> Option<&'a [u8]> {
+    match self {
+      Self::Header(name) => headers.get(name).map(|x| x.as_bytes()),
+      Self::Cookie(name) => headers
+        .get_all(COOKIE)
+        .iter()
+        .flat_map(|x| x.as_bytes().split(|&b| b == b';'))
+        .filter_map(|x| {
+          let i = x.iter().position(|&b| b == b'=')?;
+          Some((x[..i].trim(), &x[i + 1..]))
+        })
+        .find(|(k, _)| *k == name.as_bytes())
+        .map(|(_, v)| v.trim()),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use test_case::test_case;
+
+  fn permissions(net: bool, email: Option<u32>, exec: &[&str]) -> Permissions {
+    Permissions {
+      net: NetPermission::All(net),
+      email,
+      exec: exec.iter().map(|&x| x.into()).collect(),
+      ..Default::default()
+    }
+  }
+
+  #[test_case(permissions(false, None, &[]), permissions(false, None, &[]) => true; "default")]
+  #[test_case(permissions(true, Some(10), &["/bin/a"]), permissions(false, None, &[]) => true; "fewer")]
+  #[test_case(permissions(false, None, &[]), permissions(true, None, &[]) => false; "net")]
+  #[test_case(permissions(false, Some(10), &[]), permissions(false, Some(10), &[]) => true; "same email rate")]
+  #[test_case(permissions(false, Some(10), &[]), permissions(false, Some(20), &[]) => false; "higher email rate")]
+  #[test_case(permissions(false, None, &["/bin/a"]), permissions(false, None, &["/bin/a", "/bin/b"]) => false; "more programs")]
+  #[test_case(Permissions::default(), Permissions { oauth: true, ..Default::default() } => false; "oauth")]
+  fn test_covers(granted: Permissions, requested: Permissions) -> bool {
+    granted.covers(&requested)
+  }
+
+  #[test_case(br#"{ "name": "a", "max_concurrency": 4 }"# => None; "valid")]
+  #[test_case(b"{" => Some(String::new()); "syntax")]
+  #[test_case(br#"{ "name": "a", "foo": 1 }"# => Some("foo".into()); "unknown field")]
+  #[test_case(br#"{ "idle_timeout": "1s" }"# => Some("idle_timeout".into()); "wrong type")]
+  #[test_case(br#"{ "granted": {} }"# => Some("granted".into()); "host-only field")]
+  #[test_case(br#"{ "max_concurrency": 0 }"# => Some("max_concurrency".into()); "zero")]
+  #[test_case(br#"{ "max_queued": 4 }"# => Some("max_queued".into()); "dependent field")]
+  #[test_case(br#"{ "depends_on": ["A"] }"# => Some("depends_on".into()); "invalid dependency")]
+  #[test_case(br#"{ "version": "1.0" }"# => Some("version".into()); "invalid version")]
+  #[test_case(br#"{ "abel_api_version": ">=1" }"# => Some("abel_api_version".into()); "invalid api version")]
+  #[test_case(br#"{ "availability": { "windows": ["* 9-17 * * 8"] } }"# => Some("availability".into()); "invalid window")]
+  #[test_case(br#"{ "routes": { "admin/*": { "require_token": true } } }"# => Some("routes".into()); "relative route")]
+  #[test_case(br#"{ "routes": { "/admin/*": { "require_token": true, "token_secret": "" } } }"# => Some("routes".into()); "empty token secret")]
+  #[test_case(br#"{ "warm_up": ["/", "index.html"] }"# => Some("warm_up".into()); "relative warm-up path")]
+  #[test_case(br#"{ "headers": { "X-Frame-Options": "DENY\n" } }"# => Some("headers".into()); "invalid header")]
+  #[test_case(br#"{ "request_timeout": 3601 }"# => Some("request_timeout".into()); "timeout too long")]
+  #[test_case(br#"{ "capture_requests": 101 }"# => Some("capture_requests".into()); "too many captures")]
+  fn test_from_json(json: &[u8]) -> Option<String> {
+    match Config::from_json(json).map_err(|x| x.into_parts().0) {
+      Ok(_) => None,
+      Err(InvalidConfig { field, .. }) => Some(field.into()),
+      Err(error) => panic!("unexpected error: {error}"),
+    }
+  }
+
+  #[test]
+  fn test_from_json_lenient() {
+    let json = br#"{
+      "description": "test",
+      "unknown": 1,
+      "request_timeout": 0,
+      "permissions": { "exec": ["true"] }
+    }"#;
+    assert!(Config::from_json(json).is_err());
+    let config = Config::from_json_lenient(json).unwrap();
+    assert_eq!(config.description.as_deref(), Some("test"));
+    assert_eq!(config.request_timeout, None);
+    assert!(config.permissions.exec.is_empty());
+    assert!(Config::from_json_lenient(b"[]").is_err());
+  }
+
+  #[test_case(false, None => None; "not required")]
+  #[test_case(true, None => Some("route_token"); "default secret")]
+  #[test_case(true, Some("admin_token") => Some("admin_token"); "custom secret")]
+  fn test_token_secret(require_token: bool, token_secret: Option<&str>) -> Option<String> {
+    let rule = RouteRule {
+      require_token,
+      token_secret: token_secret.map(String::from),
+    };
+    rule.token_secret().map(String::from)
+  }
+
+  #[test_case("/admin/*", "/admin" => true; "bare prefix")]
+  #[test_case("/admin/*", "/admin/users/1" => true; "nested")]
+  #[test_case("/admin/*", "/administrator" => false; "longer segment")]
+  #[test_case("/api/*/delete", "/api/users/delete" => true; "middle")]
+  #[test_case("/api/*/delete", "/api/users/list" => false; "middle mismatch")]
+  #[test_case("/metrics", "/metrics" => true; "exact")]
+  #[test_case("/metrics", "/metrics/1" => false; "exact mismatch")]
+  fn test_route_matches(pattern: &str, path: &str) -> bool {
+    route_matches(pattern, path)
+  }
+
+  fn rules(rules: &[&str]) -> NetPermission {
+    NetPermission::Rules(rules.iter().map(|x| x.parse().unwrap()).collect())
+  }
+
+  #[test_case(NetPermission::All(true), rules(&["10.0.0.0/8"]) => true; "all")]
+  #[test_case(rules(&["10.0.0.0/8"]), NetPermission::All(false) => true; "none")]
+  #[test_case(rules(&["10.0.0.0/8"]), NetPermission::All(true) => false; "rules and all")]
+  #[test_case(rules(&["10.0.0.0/8", "*.example.com:443"]), rules(&["*.example.com:443"]) => true; "subset")]
+  #[test_case(rules(&["10.0.0.0/8"]), rules(&["*.example.com:443"]) => false; "other rule")]
+  fn test_net_covers(granted: NetPermission, requested: NetPermission) -> bool {
+    granted.covers(&requested)
+  }
+
+  #[test_case(NetPermission::All(false), rules(&["10.0.0.0/8"]) => NetPermission::All(false); "not requested")]
+  #[test_case(NetPermission::All(true), NetPermission::All(false) => NetPermission::All(false); "revoked")]
+  #[test_case(NetPermission::All(true), rules(&["10.0.0.0/8"]) => rules(&["10.0.0.0/8"]); "narrowed")]
+  #[test_case(rules(&["10.0.0.0/8"]), NetPermission::All(false) => rules(&[]); "rules revoked")]
+  #[test_case(rules(&["10.0.0.0/8", "*.example.com:443"]), rules(&["*.example.com:443"]) => rules(&["*.example.com:443"]); "subset")]
+  fn test_net_restrict(requested: NetPermission, granted: NetPermission) -> NetPermission {
+    requested.restrict(&granted)
+  }
+
+  #[test_case(permissions(true, Some(10), &["/bin/a"]), permissions(true, Some(20), &["/bin/a", "/bin/b"]) => true; "covered")]
+  #[test_case(permissions(true, Some(20), &["/bin/a", "/bin/b"]), permissions(false, Some(10), &["/bin/a"]) => false; "narrowed")]
+  fn test_restrict_unchanged(requested: Permissions, granted: Permissions) -> bool {
+    requested.restrict(&granted) == requested
+  }
 }
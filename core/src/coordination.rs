@@ -0,0 +1,198 @@
+use async_trait::async_trait;
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::io;
+use std::time::{Duration, Instant};
+
+/// Backend of locks, rate limits and values shared by services' instances,
+/// exposed as `abel.lock`, `abel.ratelimit` and `abel.idempotent`.
+///
+/// Set with [`AbelOptions::coordinator`](crate::AbelOptions::coordinator).
+/// Defaults to [`LocalCoordinator`], which only coordinates workers of the
+/// same process.
+#[async_trait]
+pub trait Coordinator: Debug + Send + Sync {
+  /// Tries to acquire the lock `name` for `owner` until `ttl` passes.
+  /// Acquiring a lock already held by `owner` extends it.
+  async fn try_lock(&self, name: &str, owner: &str, ttl: Duration) -> io::Result<bool>;
+
+  /// Releases the lock `name`, returning whether it was held by `owner`.
+  async fn unlock(&self, name: &str, owner: &str) -> io::Result<bool>;
+
+  /// Counts a hit of `key`, allowing at most `limit` hits in each `window`.
+  async fn rate_limit(
+    &self,
+    key: &str,
+    limit: u64,
+    window: Duration,
+  ) -> io::Result<RateLimitStatus>;
+
+  /// Gets the value of `key`, if set and not expired.
+  async fn get(&self, key: &str) -> io::Result<Option<Vec<u8>>>;
+
+  /// Sets the value of `key`, expiring after `ttl`.
+  async fn set(&self, key: &str, value: Vec<u8>, ttl: Duration) -> io::Result<()>;
+}
+
+/// Longest time locks, rate limit windows and values are kept. Longer TTLs
+/// are capped, so that expiry times cannot overflow.
+pub const MAX_COORDINATION_TTL: Duration = Duration::from_secs(365 * 86400);
+
+fn expiry(now: Instant, ttl: Duration) -> Instant {
+  now + ttl.min(MAX_COORDINATION_TTL)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimitStatus {
+  pub allowed: bool,
+  pub remaining: u64,
+  /// Time until the current window ends.
+  pub reset: Duration,
+}
+
+/// In-memory [`Coordinator`].
+#[derive(Debug, Default)]
+pub struct LocalCoordinator {
+  locks: Mutex<Expiring<String>>,
+  windows: Mutex<Expiring<u64>>,
+  values: Mutex<Expiring<Vec<u8>>>,
+}
+
+/// Map of entries with expiry time, pruned once it doubles in size.
+#[derive(Debug)]
+struct Expiring<T> {
+  entries: HashMap<String, (T, Instant)>,
+  prune_at: usize,
+}
+
+impl<T> Default for Expiring<T> {
+  fn default() -> Self {
+    Self {
+      entries: HashMap::new(),
+      prune_at: 1024,
+    }
+  }
+}
+
+impl<T> Expiring<T> {
+  fn get_mut(&mut self, key: &str, now: Instant) -> Option<&mut (T, Instant)> {
+    self.entries.get_mut(key).filter(|x| x.1 > now)
+  }
+
+  fn insert(&mut self, key: &str, value: T, expires: Instant) {
+    if self.entries.len() >= self.prune_at {
+      let now = Instant::now();
+      self.entries.retain(|_, x| x.1 > now);
+      self.prune_at = (self.entries.len() * 2).max(1024);
+    }
+    self.entries.insert(key.into(), (value, expires));
+  }
+}
+
+#[async_trait]
+impl Coordinator for LocalCoordinator {
+  async fn try_lock(&self, name: &str, owner: &str, ttl: Duration) -> io::Result<bool> {
+    let now = Instant::now();
+    let mut locks = self.locks.lock();
+    let held_by_others = matches!(locks.get_mut(name, now), Some((x, _)) if x.as_str() != owner);
+    if !held_by_others {
+      locks.insert(name, owner.into(), expiry(now, ttl));
+    }
+    Ok(!held_by_others)
+  }
+
+  async fn unlock(&self, name: &str, owner: &str) -> io::Result<bool> {
+    let mut locks = self.locks.lock();
+    let held = matches!(locks.get_mut(name, Instant::now()), Some((x, _)) if x.as_str() == owner);
+    if held {
+      locks.entries.remove(name);
+    }
+    Ok(held)
+  }
+
+  async fn rate_limit(
+    &self,
+    key: &str,
+    limit: u64,
+    window: Duration,
+  ) -> io::Result<RateLimitStatus> {
+    let now = Instant::now();
+    let mut windows = self.windows.lock();
+    let (count, end) = if let Some((count, end)) = windows.get_mut(key, now) {
+      *count += 1;
+      (*count, *end)
+    } else {
+      let end = expiry(now, window);
+      windows.insert(key, 1, end);
+      (1, end)
+    };
+    Ok(RateLimitStatus {
+      allowed: count <= limit,
+      remaining: limit.saturating_sub(count),
+      reset: end - now,
+    })
+  }
+
+  async fn get(&self, key: &str) -> io::Result<Option<Vec<u8>>> {
+    let mut values = self.values.lock();
+    Ok(values.get_mut(key, Instant::now()).map(|x| x.0.clone()))
+  }
+
+  async fn set(&self, key: &str, value: Vec<u8>, ttl: Duration) -> io::Result<()> {
+    (self.values.lock()).insert(key, value, expiry(Instant::now(), ttl));
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[tokio::test]
+  async fn test_local_lock() -> io::Result<()> {
+    let c = LocalCoordinator::default();
+    let ttl = Duration::from_secs(10);
+    assert!(c.try_lock("a", "x", ttl).await?);
+    assert!(c.try_lock("a", "x", ttl).await?);
+    assert!(!c.try_lock("a", "y", ttl).await?);
+    assert!(!c.unlock("a", "y").await?);
+    assert!(c.unlock("a", "x").await?);
+    assert!(c.try_lock("a", "y", Duration::ZERO).await?);
+    assert!(c.try_lock("a", "x", ttl).await?);
+    Ok(())
+  }
+
+  #[tokio::test]
+  async fn test_local_rate_limit() -> io::Result<()> {
+    let c = LocalCoordinator::default();
+    let window = Duration::from_secs(10);
+    assert_eq!(c.rate_limit("a", 2, window).await?.remaining, 1);
+    assert!(c.rate_limit("a", 2, window).await?.allowed);
+    assert!(!c.rate_limit("a", 2, window).await?.allowed);
+    assert!(c.rate_limit("b", 2, window).await?.allowed);
+    Ok(())
+  }
+
+  #[tokio::test]
+  async fn test_local_values() -> io::Result<()> {
+    let c = LocalCoordinator::default();
+    c.set("a", b"1".to_vec(), Duration::from_secs(10)).await?;
+    c.set("b", b"2".to_vec(), Duration::ZERO).await?;
+    assert_eq!(c.get("a").await?.as_deref(), Some(&b"1"[..]));
+    assert_eq!(c.get("b").await?, None);
+    assert_eq!(c.get("c").await?, None);
+    Ok(())
+  }
+
+  #[tokio::test]
+  async fn test_local_long_ttl() -> io::Result<()> {
+    let c = LocalCoordinator::default();
+    assert!(c.try_lock("a", "x", Duration::MAX).await?);
+    let status = c.rate_limit("a", 1, Duration::MAX).await?;
+    assert_eq!(status.reset, MAX_COORDINATION_TTL);
+    c.set("a", b"1".to_vec(), Duration::MAX).await?;
+    assert!(c.get("a").await?.is_some());
+    Ok(())
+  }
+}
@@ -0,0 +1,904 @@
+//! At-rest encryption of services' local storage.
+//!
+//! Data is encrypted with ChaCha20-Poly1305 under a key derived from the
+//! master key for each service. Small records are sealed whole, while files
+//! are split into chunks sealed separately, so that they can be streamed and
+//! written in place without holding them in memory.
+//!
+//! Each chunk of a file is bound to the service, a random ID of the file, its
+//! index and whether it is the last one, so that chunks cannot be swapped,
+//! reordered or cut off without failing to decrypt.
+
+use futures::ready;
+use log::warn;
+use openssl::error::ErrorStack;
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use openssl::sign::Signer;
+use openssl::symm::{decrypt_aead, encrypt_aead, Cipher};
+use std::fmt::{self, Debug, Formatter};
+use std::future::Future;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeek, AsyncWrite, ReadBuf};
+use tokio::task::{spawn_blocking, JoinError, JoinHandle};
+use uuid::Uuid;
+
+const MAGIC: &[u8; 8] = b"ABELENC1";
+const FILE_MAGIC: &[u8; 8] = b"ABELSTR1";
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+const FILE_ID_LEN: usize = 16;
+
+/// Bytes a sealed record takes in addition to its content.
+pub const OVERHEAD: u64 = (MAGIC.len() + NONCE_LEN + TAG_LEN) as u64;
+
+const HEADER_LEN: u64 = (FILE_MAGIC.len() + FILE_ID_LEN) as u64;
+const CHUNK_OVERHEAD: u64 = (NONCE_LEN + TAG_LEN) as u64;
+/// Content bytes in each chunk of a file, except for the last one.
+const CHUNK_LEN: u64 = 64 * 1024;
+const SEALED_CHUNK_LEN: u64 = CHUNK_LEN + CHUNK_OVERHEAD;
+
+fn to_io_error(error: ErrorStack) -> io::Error {
+  io::Error::new(io::ErrorKind::Other, error)
+}
+
+fn join_error(error: JoinError) -> io::Error {
+  io::Error::new(io::ErrorKind::Other, error)
+}
+
+fn not_encrypted() -> io::Error {
+  io::Error::new(io::ErrorKind::InvalidData, "data is not encrypted")
+}
+
+fn decryption_failed() -> io::Error {
+  io::Error::new(io::ErrorKind::InvalidData, "failed to decrypt data")
+}
+
+/// Key of a service's local storage.
+#[derive(Clone)]
+pub struct StorageKey {
+  key: [u8; 32],
+  service: Box<str>,
+}
+
+impl StorageKey {
+  /// Derives the key of `service` from the master key with HMAC-SHA256.
+  pub fn derive(master: &[u8; 32], service: &str) -> io::Result<Self> {
+    let derive = || {
+      let pkey = PKey::hmac(master)?;
+      let mut signer = Signer::new(MessageDigest::sha256(), &pkey)?;
+      signer.update(b"abel-storage:")?;
+      signer.update(service.as_bytes())?;
+      signer.sign_to_vec()
+    };
+    let mut key = [0; 32];
+    key.copy_from_slice(&derive().map_err(to_io_error)?);
+    Ok(Self {
+      key,
+      service: service.into(),
+    })
+  }
+
+  /// Encrypts `plaintext` into nonce, ciphertext and tag.
+  fn encrypt(&self, aad: &[u8], plaintext: &[u8]) -> io::Result<Vec<u8>> {
+    let mut nonce = [0; NONCE_LEN];
+    let mut tag = [0; TAG_LEN];
+    openssl::rand::rand_bytes(&mut nonce).map_err(to_io_error)?;
+    let cipher = Cipher::chacha20_poly1305();
+    let ciphertext = encrypt_aead(cipher, &self.key, Some(&nonce), aad, plaintext, &mut tag)
+      .map_err(to_io_error)?;
+
+    let mut sealed = Vec::with_capacity(ciphertext.len() + CHUNK_OVERHEAD as usize);
+    sealed.extend_from_slice(&nonce);
+    sealed.extend_from_slice(&ciphertext);
+    sealed.extend_from_slice(&tag);
+    Ok(sealed)
+  }
+
+  fn decrypt(&self, aad: &[u8], sealed: &[u8]) -> io::Result<Vec<u8>> {
+    if (sealed.len() as u64) < CHUNK_OVERHEAD {
+      return Err(decryption_failed());
+    }
+    let (nonce, rest) = sealed.split_at(NONCE_LEN);
+    let (ciphertext, tag) = rest.split_at(rest.len() - TAG_LEN);
+    let cipher = Cipher::chacha20_poly1305();
+    decrypt_aead(cipher, &self.key, Some(nonce), aad, ciphertext, tag)
+      .map_err(|_| decryption_failed())
+  }
+
+  fn chunk_aad(&self, file_id: &[u8], index: u64, last: bool) -> Vec<u8> {
+    let index = index.to_be_bytes();
+    [self.service.as_bytes(), file_id, &index, &[last as u8]].concat()
+  }
+
+  /// Seals a record whole.
+  pub fn seal(&self, plaintext: &[u8]) -> io::Result<Vec<u8>> {
+    let sealed = self.encrypt(self.service.as_bytes(), plaintext)?;
+    Ok([&MAGIC[..], &sealed].concat())
+  }
+
+  /// Opens a record sealed with [`seal`](Self::seal). Anything else is
+  /// rejected, so that plaintext is never mistaken for decrypted data.
+  pub fn open(&self, data: Vec<u8>) -> io::Result<Vec<u8>> {
+    if !is_sealed(&data) {
+      return Err(not_encrypted());
+    }
+    self.decrypt(self.service.as_bytes(), &data[MAGIC.len()..])
+  }
+}
+
+impl Debug for StorageKey {
+  fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+    f.debug_struct("StorageKey")
+      .field("service", &self.service)
+      .finish_non_exhaustive()
+  }
+}
+
+/// Whether `data` starts like a sealed record.
+pub fn is_sealed(data: &[u8]) -> bool {
+  data.len() as u64 >= OVERHEAD && data.starts_with(MAGIC)
+}
+
+/// Number of chunks of a file with `len` bytes of content. Empty files still
+/// have one, so that cutting off all chunks is detected.
+fn chunk_count(len: u64) -> u64 {
+  ((len + CHUNK_LEN - 1) / CHUNK_LEN).max(1)
+}
+
+/// Content length of an encrypted file taking `size` bytes on disk.
+fn content_len_of(size: u64) -> Option<u64> {
+  let rest = size.checked_sub(HEADER_LEN).filter(|&x| x > 0)?;
+  let chunks = (rest + SEALED_CHUNK_LEN - 1) / SEALED_CHUNK_LEN;
+  let last = rest - (chunks - 1) * SEALED_CHUNK_LEN;
+  (last >= CHUNK_OVERHEAD).then(|| rest - chunks * CHUNK_OVERHEAD)
+}
+
+/// Content length of an encrypted file.
+pub async fn content_len(path: &Path, size: u64) -> io::Result<u64> {
+  if size == 0 {
+    return Ok(0);
+  }
+  let mut magic = [0; FILE_MAGIC.len()];
+  let mut file = tokio::fs::File::open(path).await?;
+  (file.read_exact(&mut magic).await).map_err(|_| not_encrypted())?;
+  if &magic != FILE_MAGIC {
+    return Err(not_encrypted());
+  }
+  content_len_of(size).ok_or_else(decryption_failed)
+}
+
+struct Chunk {
+  index: u64,
+  data: Vec<u8>,
+  dirty: bool,
+}
+
+/// Blocking access to an encrypted file, holding at most one decrypted chunk
+/// in memory.
+///
+/// Chunks are encrypted and written back when another one is loaded, and on
+/// flush.
+struct ChunkedFile {
+  file: std::fs::File,
+  key: Arc<StorageKey>,
+  file_id: [u8; FILE_ID_LEN],
+  writable: bool,
+  append: bool,
+  /// Content length, including changes not yet written.
+  len: u64,
+  pos: u64,
+  /// Bytes of the file on disk.
+  size: u64,
+  /// Chunks on disk, and the one of them sealed as the last.
+  stored: u64,
+  stored_last: Option<u64>,
+  chunk: Option<Chunk>,
+}
+
+impl ChunkedFile {
+  /// Opens a file already created or truncated according to the open mode.
+  /// Empty files are treated as new.
+  fn open(
+    mut file: std::fs::File,
+    key: Arc<StorageKey>,
+    writable: bool,
+    append: bool,
+  ) -> io::Result<Self> {
+    let size = file.metadata()?.len();
+    let mut file_id = [0; FILE_ID_LEN];
+    let (len, stored) = if size == 0 {
+      openssl::rand::rand_bytes(&mut file_id).map_err(to_io_error)?;
+      (0, 0)
+    } else {
+      let mut header = [0; HEADER_LEN as usize];
+      file.read_exact(&mut header).map_err(|_| not_encrypted())?;
+      if &header[..FILE_MAGIC.len()] != FILE_MAGIC {
+        return Err(not_encrypted());
+      }
+      file_id.copy_from_slice(&header[FILE_MAGIC.len()..]);
+      let len = content_len_of(size).ok_or_else(decryption_failed)?;
+      (len, chunk_count(len))
+    };
+    Ok(Self {
+      file,
+      key,
+      file_id,
+      writable,
+      append,
+      len,
+      pos: 0,
+      size,
+      stored,
+      stored_last: stored.checked_sub(1),
+      chunk: None,
+    })
+  }
+
+  fn read_chunk(&mut self, index: u64) -> io::Result<Vec<u8>> {
+    let offset = HEADER_LEN + index * SEALED_CHUNK_LEN;
+    let mut sealed = vec![0; (self.size - offset).min(SEALED_CHUNK_LEN) as usize];
+    self.file.seek(SeekFrom::Start(offset))?;
+    self.file.read_exact(&mut sealed)?;
+    let last = self.stored_last == Some(index);
+    let aad = self.key.chunk_aad(&self.file_id, index, last);
+    self.key.decrypt(&aad, &sealed)
+  }
+
+  fn write_chunk(&mut self, index: u64, data: &[u8], last: bool) -> io::Result<()> {
+    if self.size == 0 {
+      self.file.seek(SeekFrom::Start(0))?;
+      self.file.write_all(FILE_MAGIC)?;
+      self.file.write_all(&self.file_id)?;
+      self.size = HEADER_LEN;
+    }
+    if last {
+      // The previous last chunk is now followed by others
+      if let Some(prev) = self.stored_last.filter(|&x| x != index) {
+        let data = self.read_chunk(prev)?;
+        self.stored_last = None;
+        self.write_chunk(prev, &data, false)?;
+      }
+    } else if self.stored_last == Some(index) {
+      self.stored_last = None;
+    }
+
+    let aad = self.key.chunk_aad(&self.file_id, index, last);
+    let sealed = self.key.encrypt(&aad, data)?;
+    let offset = HEADER_LEN + index * SEALED_CHUNK_LEN;
+    self.file.seek(SeekFrom::Start(offset))?;
+    self.file.write_all(&sealed)?;
+    self.size = self.size.max(offset + sealed.len() as u64);
+    self.stored = self.stored.max(index + 1);
+    if last {
+      self.stored_last = Some(index);
+    }
+    Ok(())
+  }
+
+  /// Writes the loaded chunk back if changed, with the content being `len`
+  /// bytes long.
+  fn store(&mut self, len: u64) -> io::Result<()> {
+    if let Some(mut chunk) = self.chunk.take() {
+      let mut result = Ok(());
+      if chunk.dirty {
+        let last = chunk.index == chunk_count(len) - 1;
+        result = self.write_chunk(chunk.index, &chunk.data, last);
+        chunk.dirty = result.is_err();
+      }
+      self.chunk = Some(chunk);
+      result?;
+    }
+    Ok(())
+  }
+
+  /// Loads a chunk, with the content being `len` bytes long once the current
+  /// operation is done.
+  fn load(&mut self, index: u64, len: u64) -> io::Result<&mut Chunk> {
+    if !matches!(&self.chunk, Some(x) if x.index == index) {
+      self.store(len)?;
+      let data = if index < self.stored {
+        self.read_chunk(index)?
+      } else {
+        Vec::new()
+      };
+      self.chunk = Some(Chunk {
+        index,
+        data,
+        dirty: false,
+      });
+    }
+    Ok(self.chunk.as_mut().unwrap())
+  }
+
+  /// Writes to the chunk at the current position, returning bytes written.
+  fn write_in_chunk(&mut self, buf: &[u8]) -> io::Result<usize> {
+    let index = self.pos / CHUNK_LEN;
+    let start = (self.pos % CHUNK_LEN) as usize;
+    let n = buf.len().min(CHUNK_LEN as usize - start);
+    let len = self.len.max(self.pos + n as u64);
+    let chunk = self.load(index, len)?;
+    if chunk.data.len() < start + n {
+      chunk.data.resize(start + n, 0);
+    }
+    chunk.data[start..start + n].copy_from_slice(&buf[..n]);
+    chunk.dirty = true;
+    self.pos += n as u64;
+    self.len = len;
+    Ok(n)
+  }
+}
+
+impl Read for ChunkedFile {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    if self.pos >= self.len || buf.is_empty() {
+      return Ok(0);
+    }
+    let start = (self.pos % CHUNK_LEN) as usize;
+    let chunk = self.load(self.pos / CHUNK_LEN, self.len)?;
+    let n = buf.len().min(chunk.data.len().saturating_sub(start));
+    buf[..n].copy_from_slice(&chunk.data[start..start + n]);
+    self.pos += n as u64;
+    Ok(n)
+  }
+}
+
+impl Write for ChunkedFile {
+  fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    if !self.writable {
+      return Err(io::Error::from_raw_os_error(libc::EBADF));
+    }
+    if buf.is_empty() {
+      return Ok(0);
+    }
+    if self.append {
+      self.pos = self.len;
+    }
+    // Fills the gap after the end with zeros
+    let pos = self.pos;
+    while self.len < pos {
+      self.pos = self.len;
+      let zeros = vec![0; (pos - self.len).min(CHUNK_LEN) as usize];
+      self.write_in_chunk(&zeros)?;
+    }
+    self.pos = pos;
+    self.write_in_chunk(buf)
+  }
+
+  fn flush(&mut self) -> io::Result<()> {
+    if !self.writable {
+      return Ok(());
+    }
+    self.store(self.len)?;
+    if self.stored == 0 {
+      self.write_chunk(0, &[], true)?;
+    }
+    self.file.flush()
+  }
+}
+
+impl Seek for ChunkedFile {
+  fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+    let pos = match pos {
+      SeekFrom::Start(x) => Some(x),
+      SeekFrom::End(x) => self.len.checked_add_signed(x),
+      SeekFrom::Current(x) => self.pos.checked_add_signed(x),
+    };
+    self.pos = pos.ok_or_else(|| {
+      io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "invalid seek to a negative position",
+      )
+    })?;
+    Ok(self.pos)
+  }
+}
+
+impl Drop for ChunkedFile {
+  fn drop(&mut self) {
+    if let Err(error) = self.flush() {
+      warn!("failed to write encrypted file on close: {error}");
+    }
+  }
+}
+
+enum Op {
+  Read(io::Result<Vec<u8>>),
+  Write(io::Result<()>),
+  Flush(io::Result<()>),
+  Seek(io::Result<u64>),
+}
+
+enum State {
+  Idle(Option<Box<ChunkedFile>>),
+  Busy(JoinHandle<(Box<ChunkedFile>, Op)>),
+}
+
+/// File in encrypted local storage.
+///
+/// Like [`tokio::fs::File`], operations run on the blocking thread pool one at
+/// a time, and writes are reported done once started. Their errors are
+/// returned by the next operation.
+pub struct EncryptedFile {
+  state: State,
+  last_error: Option<io::Error>,
+}
+
+impl EncryptedFile {
+  /// Opens a file already created or truncated according to the open mode.
+  pub async fn open(
+    file: std::fs::File,
+    key: Arc<StorageKey>,
+    writable: bool,
+    append: bool,
+  ) -> io::Result<Self> {
+    let inner = spawn_blocking(move || ChunkedFile::open(file, key, writable, append))
+      .await
+      .map_err(join_error)??;
+    Ok(Self {
+      state: State::Idle(Some(Box::new(inner))),
+      last_error: None,
+    })
+  }
+
+  /// Content length, waiting for pending operations.
+  pub async fn len(&mut self) -> io::Result<u64> {
+    futures::future::poll_fn(|cx| self.poll_idle(cx)).await?;
+    Ok(self.inner()?.len)
+  }
+
+  fn inner(&mut self) -> io::Result<&mut ChunkedFile> {
+    match &mut self.state {
+      State::Idle(Some(inner)) => Ok(inner),
+      _ => Err(io::Error::new(
+        io::ErrorKind::Other,
+        "file operation failed",
+      )),
+    }
+  }
+
+  fn spawn(&mut self, f: impl FnOnce(&mut ChunkedFile) -> Op + Send + 'static) -> io::Result<()> {
+    let mut inner = match &mut self.state {
+      State::Idle(inner) => inner.take(),
+      State::Busy(_) => None,
+    }
+    .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "other file operation is pending"))?;
+    self.state = State::Busy(spawn_blocking(move || {
+      let op = f(&mut inner);
+      (inner, op)
+    }));
+    Ok(())
+  }
+
+  /// Waits for the pending operation, if any, returning its result.
+  fn poll_op(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<Option<Op>>> {
+    let handle = match &mut self.state {
+      State::Idle(_) => return Poll::Ready(Ok(None)),
+      State::Busy(handle) => handle,
+    };
+    let result = ready!(Pin::new(handle).poll(cx));
+    Poll::Ready(match result {
+      Ok((inner, op)) => {
+        self.state = State::Idle(Some(inner));
+        Ok(Some(op))
+      }
+      Err(error) => {
+        self.state = State::Idle(None);
+        Err(join_error(error))
+      }
+    })
+  }
+
+  /// Keeps the error of a write for the next operation, dropping results of
+  /// other operations whose callers went away.
+  fn record(&mut self, op: Op) {
+    if let Op::Write(Err(error)) = op {
+      self.last_error = Some(error);
+    }
+  }
+
+  fn poll_idle(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+    while let Some(op) = ready!(self.poll_op(cx))? {
+      self.record(op);
+    }
+    match self.last_error.take() {
+      Some(error) => Poll::Ready(Err(error)),
+      None => Poll::Ready(Ok(())),
+    }
+  }
+}
+
+impl Drop for EncryptedFile {
+  fn drop(&mut self) {
+    // Writes back the loaded chunk off the async thread if possible
+    if let State::Idle(inner @ Some(_)) = &mut self.state {
+      if let Ok(handle) = tokio::runtime::Handle::try_current() {
+        let inner = inner.take();
+        handle.spawn_blocking(move || drop(inner));
+      }
+    }
+  }
+}
+
+impl AsyncRead for EncryptedFile {
+  fn poll_read(
+    self: Pin<&mut Self>,
+    cx: &mut Context<'_>,
+    buf: &mut ReadBuf<'_>,
+  ) -> Poll<io::Result<()>> {
+    let this = self.get_mut();
+    loop {
+      match ready!(this.poll_op(cx))? {
+        Some(Op::Read(result)) => {
+          let data = result?;
+          let n = data.len().min(buf.remaining());
+          buf.put_slice(&data[..n]);
+          // Gives back what a read into a larger buffer took
+          this.inner()?.pos -= (data.len() - n) as u64;
+          return Poll::Ready(Ok(()));
+        }
+        Some(op) => this.record(op),
+        None => {
+          if let Some(error) = this.last_error.take() {
+            return Poll::Ready(Err(error));
+          }
+          let len = buf.remaining().min(CHUNK_LEN as usize);
+          this.spawn(move |inner| {
+            let mut data = vec![0; len];
+            Op::Read(inner.read(&mut data).map(|n| {
+              data.truncate(n);
+              data
+            }))
+          })?;
+        }
+      }
+    }
+  }
+}
+
+impl AsyncWrite for EncryptedFile {
+  fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+    let this = self.get_mut();
+    ready!(this.poll_idle(cx))?;
+    if !this.inner()?.writable {
+      return Poll::Ready(Err(io::Error::from_raw_os_error(libc::EBADF)));
+    }
+    let data = buf[..buf.len().min(CHUNK_LEN as usize)].to_vec();
+    let n = data.len();
+    this.spawn(move |inner| Op::Write(inner.write_all(&data)))?;
+    Poll::Ready(Ok(n))
+  }
+
+  fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+    let this = self.get_mut();
+    loop {
+      match ready!(this.poll_op(cx))? {
+        Some(Op::Flush(result)) => return Poll::Ready(result),
+        Some(op) => this.record(op),
+        None => {
+          if let Some(error) = this.last_error.take() {
+            return Poll::Ready(Err(error));
+          }
+          this.spawn(|inner| Op::Flush(inner.flush()))?;
+        }
+      }
+    }
+  }
+
+  fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+    self.poll_flush(cx)
+  }
+}
+
+impl AsyncSeek for EncryptedFile {
+  fn start_seek(self: Pin<&mut Self>, position: SeekFrom) -> io::Result<()> {
+    (self.get_mut()).spawn(move |inner| Op::Seek(inner.seek(position)))
+  }
+
+  fn poll_complete(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<u64>> {
+    let this = self.get_mut();
+    loop {
+      match ready!(this.poll_op(cx))? {
+        Some(Op::Seek(result)) => return Poll::Ready(result),
+        Some(op) => this.record(op),
+        None => {
+          if let Some(error) = this.last_error.take() {
+            return Poll::Ready(Err(error));
+          }
+          return Poll::Ready(Ok(this.inner()?.pos));
+        }
+      }
+    }
+  }
+}
+
+/// Encrypts what was written to local storage before encryption was enabled:
+/// files of each service, and records of its key-value store, job queue and
+/// scheduled calls under `.kv`, `.queues` and `.schedules`. Files sealed whole
+/// by earlier versions are split into chunks.
+///
+/// Returns the number of files encrypted.
+pub async fn seal_local_storage(path: PathBuf, master: [u8; 32]) -> io::Result<usize> {
+  spawn_blocking(move || seal_local_storage_blocking(&path, &master))
+    .await
+    .map_err(join_error)?
+}
+
+fn seal_local_storage_blocking(root: &Path, master: &[u8; 32]) -> io::Result<usize> {
+  let mut count = 0;
+  for (name, dir) in service_dirs(root)? {
+    let key = Arc::new(StorageKey::derive(master, &name)?);
+    for path in list_files(&dir)? {
+      count += seal_file(&path, &key)? as usize;
+    }
+  }
+  for records in [".kv", ".queues", ".schedules"] {
+    for (name, dir) in service_dirs(&root.join(records))? {
+      let key = StorageKey::derive(master, &name)?;
+      for path in list_files(&dir)? {
+        let data = std::fs::read(&path)?;
+        if !is_sealed(&data) {
+          write_atomic(&path, |file| file.write_all(&key.seal(&data)?))?;
+          count += 1;
+        }
+      }
+    }
+  }
+  Ok(count)
+}
+
+/// Directories named after services, skipping internal ones starting with
+/// `.`.
+fn service_dirs(path: &Path) -> io::Result<Vec<(String, PathBuf)>> {
+  let entries = match std::fs::read_dir(path) {
+    Ok(entries) => entries,
+    Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+    Err(error) => return Err(error),
+  };
+  let mut dirs = Vec::new();
+  for entry in entries {
+    let entry = entry?;
+    if let Some(name) = entry.file_name().to_str() {
+      if !name.starts_with('.') && entry.file_type()?.is_dir() {
+        dirs.push((name.to_owned(), entry.path()));
+      }
+    }
+  }
+  Ok(dirs)
+}
+
+fn list_files(dir: &Path) -> io::Result<Vec<PathBuf>> {
+  let mut files = Vec::new();
+  for entry in std::fs::read_dir(dir)? {
+    let entry = entry?;
+    let file_type = entry.file_type()?;
+    if file_type.is_dir() {
+      files.extend(list_files(&entry.path())?);
+    } else if file_type.is_file() {
+      files.push(entry.path());
+    }
+  }
+  Ok(files)
+}
+
+/// Encrypts a plaintext or whole-sealed file in chunks, returning whether it
+/// was rewritten.
+fn seal_file(path: &Path, key: &Arc<StorageKey>) -> io::Result<bool> {
+  let mut file = std::fs::File::open(path)?;
+  let mut head = Vec::new();
+  (&mut file).take(OVERHEAD).read_to_end(&mut head)?;
+  if head.is_empty() || head.starts_with(FILE_MAGIC) {
+    return Ok(false);
+  }
+  let legacy = if is_sealed(&head) {
+    let mut data = head.clone();
+    file.read_to_end(&mut data)?;
+    key.open(data).ok()
+  } else {
+    None
+  };
+  write_atomic(path, |temp| {
+    let mut sealed = ChunkedFile::open(temp.try_clone()?, key.clone(), true, false)?;
+    match &legacy {
+      Some(content) => sealed.write_all(content)?,
+      None => {
+        file.rewind()?;
+        io::copy(&mut file, &mut sealed)?;
+      }
+    }
+    sealed.flush()
+  })?;
+  Ok(true)
+}
+
+/// Replaces a file with what `f` writes, so that a crash never leaves it
+/// half-written.
+fn write_atomic(
+  path: &Path,
+  f: impl FnOnce(&mut std::fs::File) -> io::Result<()>,
+) -> io::Result<()> {
+  let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+  let temp_path = path.with_file_name(format!(".{file_name}.{}.tmp", Uuid::new_v4()));
+  let result = (|| {
+    let mut temp = std::fs::File::create(&temp_path)?;
+    f(&mut temp)?;
+    temp.sync_all()?;
+    std::fs::rename(&temp_path, path)
+  })();
+  if result.is_err() {
+    let _ = std::fs::remove_file(&temp_path);
+  }
+  result
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use test_case::test_case;
+  use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+
+  fn test_key() -> Arc<StorageKey> {
+    Arc::new(StorageKey::derive(&[1; 32], "test").unwrap())
+  }
+
+  #[test_case(b"" ; "empty")]
+  #[test_case(b"hello world" ; "short")]
+  fn test_seal_open(plaintext: &[u8]) {
+    let key = test_key();
+    let sealed = key.seal(plaintext).unwrap();
+    assert!(is_sealed(&sealed));
+    assert_eq!(sealed.len() as u64, plaintext.len() as u64 + OVERHEAD);
+    assert_eq!(key.open(sealed).unwrap(), plaintext);
+  }
+
+  #[test]
+  fn test_open_other_service() {
+    let sealed = (StorageKey::derive(&[1; 32], "a").unwrap())
+      .seal(b"secret")
+      .unwrap();
+    let other = StorageKey::derive(&[1; 32], "b").unwrap();
+    assert!(other.open(sealed).is_err());
+  }
+
+  #[test]
+  fn test_open_plaintext() {
+    let error = test_key().open(b"plain".to_vec()).unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+  }
+
+  #[test_case(0 ; "empty")]
+  #[test_case(100 ; "short")]
+  #[test_case(CHUNK_LEN ; "one chunk")]
+  #[test_case(CHUNK_LEN * 2 + 100 ; "three chunks")]
+  fn test_content_len(len: u64) {
+    let size = HEADER_LEN + len + chunk_count(len) * CHUNK_OVERHEAD;
+    assert_eq!(content_len_of(size), Some(len));
+  }
+
+  fn content(len: usize) -> Vec<u8> {
+    (0..len).map(|x| (x % 251) as u8).collect()
+  }
+
+  async fn open(path: &Path, key: &Arc<StorageKey>, write: bool) -> io::Result<EncryptedFile> {
+    let file = (std::fs::OpenOptions::new().read(true).write(write))
+      .create(write)
+      .open(path)?;
+    EncryptedFile::open(file, key.clone(), write, false).await
+  }
+
+  #[tokio::test]
+  async fn test_file_roundtrip() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("file");
+    let key = test_key();
+    let data = content(CHUNK_LEN as usize * 2 + 1000);
+
+    let mut file = open(&path, &key, true).await?;
+    file.write_all(&data).await?;
+    file.flush().await?;
+    assert_eq!(file.len().await?, data.len() as u64);
+    drop(file);
+    let size = std::fs::metadata(&path)?.len();
+    assert_eq!(content_len(&path, size).await?, data.len() as u64);
+
+    let mut file = open(&path, &key, false).await?;
+    let mut read = Vec::new();
+    file.read_to_end(&mut read).await?;
+    assert_eq!(read, data);
+
+    file.seek(SeekFrom::Start(CHUNK_LEN - 2)).await?;
+    let mut buf = [0; 4];
+    file.read_exact(&mut buf).await?;
+    assert_eq!(buf, data[CHUNK_LEN as usize - 2..][..4]);
+    Ok(())
+  }
+
+  #[tokio::test]
+  async fn test_file_append_across_chunks() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("file");
+    let key = test_key();
+    let data = content(CHUNK_LEN as usize + 10);
+
+    let mut file = open(&path, &key, true).await?;
+    file.write_all(&data[..CHUNK_LEN as usize]).await?;
+    file.flush().await?;
+    drop(file);
+
+    let file = std::fs::OpenOptions::new()
+      .read(true)
+      .write(true)
+      .open(&path)?;
+    let mut file = EncryptedFile::open(file, key.clone(), true, true).await?;
+    file.write_all(&data[CHUNK_LEN as usize..]).await?;
+    file.flush().await?;
+    drop(file);
+
+    let mut read = Vec::new();
+    open(&path, &key, false)
+      .await?
+      .read_to_end(&mut read)
+      .await?;
+    assert_eq!(read, data);
+    Ok(())
+  }
+
+  #[tokio::test]
+  async fn test_file_rejects_tampering() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("file");
+    let key = test_key();
+
+    let mut file = open(&path, &key, true).await?;
+    file.write_all(&content(CHUNK_LEN as usize + 10)).await?;
+    file.flush().await?;
+    drop(file);
+
+    // Cuts off the last chunk
+    let file = std::fs::OpenOptions::new().write(true).open(&path)?;
+    file.set_len(HEADER_LEN + SEALED_CHUNK_LEN)?;
+    drop(file);
+    let mut read = Vec::new();
+    let result = open(&path, &key, false).await?.read_to_end(&mut read).await;
+    assert!(result.is_err());
+
+    std::fs::write(&path, b"plain")?;
+    assert!(open(&path, &key, false).await.is_err());
+    Ok(())
+  }
+
+  #[tokio::test]
+  async fn test_seal_local_storage() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let key = test_key();
+    std::fs::create_dir_all(dir.path().join("test/sub"))?;
+    std::fs::write(dir.path().join("test/sub/plain"), b"plain")?;
+    std::fs::write(dir.path().join("test/legacy"), key.seal(b"legacy")?)?;
+    std::fs::create_dir_all(dir.path().join(".kv/test"))?;
+    std::fs::write(dir.path().join(".kv/test/record"), b"{}")?;
+    std::fs::create_dir_all(dir.path().join(".queues/test/dead"))?;
+    std::fs::write(dir.path().join(".queues/test/dead/job.json"), b"{}")?;
+
+    assert_eq!(seal_local_storage(dir.path().into(), [1; 32]).await?, 4);
+    assert_eq!(seal_local_storage(dir.path().into(), [1; 32]).await?, 0);
+
+    for (name, expected) in [
+      ("test/sub/plain", &b"plain"[..]),
+      ("test/legacy", b"legacy"),
+    ] {
+      let mut read = Vec::new();
+      let mut file = open(&dir.path().join(name), &key, false).await?;
+      file.read_to_end(&mut read).await?;
+      assert_eq!(read, expected);
+    }
+    for name in [".kv/test/record", ".queues/test/dead/job.json"] {
+      let record = std::fs::read(dir.path().join(name))?;
+      assert_eq!(key.open(record)?, b"{}");
+    }
+    Ok(())
+  }
+}
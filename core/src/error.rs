@@ -86,6 +86,81 @@ pub enum ErrorKind {
   #[strum(props(status = "500", error = "service is dropped"))]
   ServiceDropped,
 
+  #[error("worker dropped the task")]
+  #[strum(props(status = "500", error = "worker dropped"))]
+  WorkerDropped,
+
+  #[error("permissions of service '{name}' are pending approval")]
+  #[strum(props(status = "409", error = "service pending approval"))]
+  ServicePendingApproval { name: ServiceName },
+
+  #[error("service '{name}' is not pending approval")]
+  #[strum(props(status = "409", error = "service not pending approval"))]
+  ServiceNotPendingApproval { name: ServiceName },
+
+  #[error("invalid service config{}: {reason}", fmt_field(field))]
+  #[strum(props(status = "400", error = "invalid config"))]
+  InvalidConfig { field: Box<str>, reason: String },
+
+  #[error("service requires API version {required}, but {current} is provided")]
+  #[strum(props(status = "400", error = "incompatible API version"))]
+  IncompatibleApiVersion { required: String, current: String },
+
+  #[error("too many services (max {max})")]
+  #[strum(props(status = "503", error = "capacity exceeded"))]
+  TooManyServices { max: usize },
+
+  #[error("too many running services (max {max})")]
+  #[strum(props(status = "503", error = "capacity exceeded"))]
+  TooManyRunningServices { max: usize },
+
+  #[error("service '{name}' is overloaded")]
+  #[strum(props(status = "503", error = "service overloaded"))]
+  ServiceOverloaded { name: ServiceName },
+
+  #[error("request to service '{name}' timed out")]
+  #[strum(props(status = "504", error = "request timed out"))]
+  RequestTimeout { name: ServiceName },
+
+  #[error("service '{name}' is not ready")]
+  #[strum(props(status = "503", error = "service not ready"))]
+  ServiceNotReady { name: ServiceName },
+
+  #[error("response of service '{name}' exceeded {limit} bytes")]
+  #[strum(props(status = "500", error = "response too large"))]
+  ResponseTooLarge { name: ServiceName, limit: u64 },
+
+  #[error("service '{name}' exceeded its response quota")]
+  #[strum(props(status = "503", error = "response quota exceeded"))]
+  ResponseQuotaExceeded { name: ServiceName },
+
+  #[error("dependency '{dependency}' of service '{name}' is not running")]
+  #[strum(props(status = "409", error = "dependency not running"))]
+  DependencyNotRunning {
+    name: ServiceName,
+    dependency: Box<str>,
+  },
+
+  #[error("service '{name}' is part of a dependency cycle")]
+  #[strum(props(status = "400", error = "dependency cycle"))]
+  DependencyCycle { name: ServiceName },
+
+  #[error("service '{name}' is being deployed")]
+  #[strum(props(status = "409", error = "deploy in progress"))]
+  DeployInProgress { name: ServiceName },
+
+  #[error("service '{name}' has no canary")]
+  #[strum(props(status = "404", error = "canary not found"))]
+  CanaryNotFound { name: ServiceName },
+
+  #[error("invalid canary weight: {weight} (expected 0 to 100)")]
+  #[strum(props(status = "400", error = "invalid canary weight"))]
+  InvalidCanaryWeight { weight: u8 },
+
+  #[error("invalid redirect map at line {line}: {reason}")]
+  #[strum(props(status = "400", error = "invalid redirect map"))]
+  InvalidRedirectMap { line: usize, reason: Box<str> },
+
   #[error("entry '{entry}' not found")]
   #[strum(props(status = "404", error = "entry not found"))]
   EntryNotFound { entry: Box<str> },
@@ -129,6 +204,14 @@ where
   json!({ "msg": error.to_string() }).serialize(ser)
 }
 
+fn fmt_field(field: &str) -> String {
+  if field.is_empty() {
+    String::new()
+  } else {
+    format!(" field '{field}'")
+  }
+}
+
 impl ErrorKind {
   pub fn status(&self) -> StatusCode {
     match self {
@@ -0,0 +1,134 @@
+use crate::service::ServiceName;
+use serde::Serialize;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::broadcast;
+use uuid::Uuid;
+
+/// Number of events kept for slow subscribers in each channel. Older ones are
+/// dropped.
+const CAPACITY: usize = 1024;
+
+/// Lifecycle event of services or workers.
+#[derive(Debug, Clone, Serialize)]
+pub struct Event {
+  /// Unix time in milliseconds.
+  pub time: u64,
+  #[serde(flatten)]
+  pub kind: EventKind,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+#[non_exhaustive]
+pub enum EventKind {
+  /// A new version of a service was uploaded.
+  Deployed {
+    service: ServiceName,
+    uuid: Uuid,
+  },
+  Started {
+    service: ServiceName,
+    uuid: Uuid,
+  },
+  Stopped {
+    service: ServiceName,
+    uuid: Uuid,
+  },
+  /// A service failed to start.
+  Crashed {
+    service: ServiceName,
+    uuid: Uuid,
+    error: String,
+  },
+  /// A worker panicked. It is restarted on next use.
+  Panicked {
+    worker: String,
+  },
+  /// A service called `print` or `warn`.
+  Log {
+    service: ServiceName,
+    level: LogLevel,
+    message: String,
+  },
+  /// A request failed with a Lua runtime error.
+  RequestFailed {
+    service: ServiceName,
+    uuid: Uuid,
+    method: String,
+    path: String,
+    query: Option<String>,
+    /// Error message followed by Lua traceback, if any.
+    error: String,
+  },
+}
+
+impl EventKind {
+  /// The service this event is about, if any.
+  pub fn service(&self) -> Option<&str> {
+    match self {
+      Self::Deployed { service, .. }
+      | Self::Started { service, .. }
+      | Self::Stopped { service, .. }
+      | Self::Crashed { service, .. }
+      | Self::Log { service, .. }
+      | Self::RequestFailed { service, .. } => Some(service),
+      Self::Panicked { .. } => None,
+    }
+  }
+
+  pub fn is_log(&self) -> bool {
+    matches!(self, Self::Log { .. })
+  }
+
+  /// Whether this event is about the lifecycle of services or workers, rather
+  /// than what happens in them.
+  pub fn is_lifecycle(&self) -> bool {
+    !matches!(self, Self::Log { .. } | Self::RequestFailed { .. })
+  }
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LogLevel {
+  Info,
+  Warn,
+}
+
+/// Lifecycle events, and log lines and request failures, are published to
+/// separate channels, so that chatty services never push lifecycle events out
+/// of slow subscribers' buffers.
+#[derive(Debug, Clone)]
+pub(crate) struct Events {
+  lifecycle: broadcast::Sender<Event>,
+  activity: broadcast::Sender<Event>,
+}
+
+impl Events {
+  pub fn new() -> Self {
+    Self {
+      lifecycle: broadcast::channel(CAPACITY).0,
+      activity: broadcast::channel(CAPACITY).0,
+    }
+  }
+
+  pub fn publish(&self, kind: EventKind) {
+    let time = (SystemTime::now().duration_since(UNIX_EPOCH))
+      .map(|x| x.as_millis() as u64)
+      .unwrap_or(0);
+    let sender = if kind.is_lifecycle() {
+      &self.lifecycle
+    } else {
+      &self.activity
+    };
+    // Fails only when there is no subscriber
+    let _ = sender.send(Event { time, kind });
+  }
+
+  pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+    self.lifecycle.subscribe()
+  }
+
+  pub fn subscribe_activity(&self) -> broadcast::Receiver<Event> {
+    self.activity.subscribe()
+  }
+}
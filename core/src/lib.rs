@@ -1,121 +1,299 @@
+pub mod event;
+pub mod net;
 pub mod service;
 pub mod source;
 
 mod config;
+mod coordination;
+#[cfg(feature = "encryption")]
+mod encryption;
 mod error;
 mod lua;
+mod middleware;
 mod path;
 mod runtime;
 mod task;
+mod version;
 
-pub use config::Config;
+pub use config::{
+  add_default_headers, parse_headers, Affinity, Config, NetPermission, Permissions, RouteRule,
+  MAX_CAPTURE_REQUESTS, MAX_REQUEST_TIMEOUT,
+};
+pub use coordination::{Coordinator, LocalCoordinator, RateLimitStatus, MAX_COORDINATION_TTL};
+#[cfg(feature = "encryption")]
+pub use encryption::seal_local_storage;
 pub use error::{Error, ErrorKind, Result};
 pub use lua::require::{load_create_require, RemoteInterface};
+pub use lua::s3::{encode_key as encode_s3_key, S3Credentials};
+pub use lua::http::HttpClientOptions;
+pub use lua::{civil_from_days, constant_time_eq, hmac_sha256, LuaModuleFn};
+pub use middleware::Middleware;
 pub use mlua;
 pub use mlua::Error as LuaError;
 pub use path::normalize_path_str;
 pub use runtime::check_name;
-pub use service::{RunningService, RunningServiceGuard, ServiceImpl};
+pub use runtime::diagnostics::{Deprecation, Diagnostic, DiagnosticLevel};
+pub use service::{DeployGuard, RunningService, RunningServiceGuard, ServiceImpl};
+pub use task::{Profile, SandboxStats};
+pub use version::{Version, VersionReq, API_VERSION};
 
+use event::{Event, EventKind, Events};
 use hyper::{Body, Request, Response};
+use log::warn;
+use lua::http::{apply_range, RangeRequest};
+use lua::LuaModules;
+use middleware::Middlewares;
+use runtime::diagnostics::Diagnostics;
+use runtime::kv::KvStores;
+use runtime::metrics::CustomMetrics;
+use runtime::queue::JobQueues;
+use runtime::schedule::{Schedules, MAX_ATTEMPTS};
 use runtime::Runtime;
-use service::{ErrorPayload, Service, ServiceName, ServicePool, StoppedService};
+use service::{
+  meter, BeforeSwap, Capacity, ErrorPayload, MetricsSnapshot, Service, ServiceLimits, ServiceName,
+  ServicePool, StoppedService,
+};
 use source::Source;
 use std::path::PathBuf;
+use std::rc::Rc;
 use std::sync::Arc;
-use task::Pool;
+use std::time::{Duration, Instant};
+use task::{Pool, Priority, Profiles};
+use tokio::sync::broadcast;
 use uuid::Uuid;
 
 pub struct Abel {
-  runtime_pool: Pool,
+  runtime_pool: Arc<Pool>,
   service_pool: ServicePool,
+  middlewares: Middlewares,
   state: Arc<AbelState>,
 }
 
 #[derive(Debug)]
 pub struct AbelState {
   pub local_storage_path: PathBuf,
+  pub secrets_path: Option<PathBuf>,
   pub remote: RemoteInterface,
+  pub(crate) events: Events,
+  pub(crate) lua_modules: LuaModules,
+  pub(crate) http_client: HttpClientOptions,
+  pub(crate) coordinator: Arc<dyn Coordinator>,
+  pub(crate) exec_allowlist: Vec<String>,
+  pub(crate) queues: JobQueues,
+  pub(crate) kv: KvStores,
+  pub(crate) schedules: Schedules,
+  pub(crate) metrics: CustomMetrics,
+  pub(crate) profiles: Profiles,
+  pub(crate) diagnostics: Diagnostics,
+  #[cfg(feature = "encryption")]
+  pub(crate) storage_key: Option<[u8; 32]>,
 }
 
 pub struct AbelOptions {
   pub runtime_pool_size: usize,
   pub local_storage_path: PathBuf,
+  /// Directory of services' secrets, stored as `<name>.json` each.
+  pub secrets_path: Option<PathBuf>,
   pub remote_cache_path: Option<PathBuf>,
+  pub max_services: Option<usize>,
+  pub max_running_services: Option<usize>,
+  pub http_client: HttpClientOptions,
+  /// Backend of `abel.lock` and `abel.ratelimit`. Uses [`LocalCoordinator`]
+  /// if not set.
+  pub coordinator: Option<Arc<dyn Coordinator>>,
+  /// Programs services may run with the `exec` module, as absolute paths.
+  /// Services only get those they also request in their `exec` permission.
+  pub exec_allowlist: Vec<String>,
+  /// Master key encrypting services' local storage at rest. Unencrypted
+  /// files are rejected, so those written before should be encrypted with
+  /// [`seal_local_storage`] first.
+  #[cfg(feature = "encryption")]
+  pub storage_key: Option<[u8; 32]>,
 }
 
 impl Abel {
   pub fn new(options: AbelOptions) -> Result<Self> {
+    #[cfg(feature = "encryption")]
+    let master = options.storage_key;
+    #[cfg(not(feature = "encryption"))]
+    let master = None;
     let state = Arc::new(AbelState {
+      queues: JobQueues::new(options.local_storage_path.join(".queues"), master),
+      kv: KvStores::new(options.local_storage_path.join(".kv")),
+      schedules: Schedules::new(options.local_storage_path.join(".schedules"), master),
+      metrics: CustomMetrics::default(),
+      profiles: Profiles::default(),
+      diagnostics: Diagnostics::default(),
       local_storage_path: options.local_storage_path,
+      secrets_path: options.secrets_path,
       remote: RemoteInterface::new(options.remote_cache_path),
+      events: Events::new(),
+      lua_modules: LuaModules::default(),
+      http_client: options.http_client,
+      coordinator: (options.coordinator).unwrap_or_else(|| Arc::new(LocalCoordinator::default())),
+      exec_allowlist: options.exec_allowlist,
+      #[cfg(feature = "encryption")]
+      storage_key: options.storage_key,
     });
     Ok(Self {
-      runtime_pool: Pool::new(options.runtime_pool_size, {
-        let state = state.clone();
-        move || Runtime::new(state.clone())
-      })?,
-      service_pool: ServicePool::new(state.clone()),
+      runtime_pool: Arc::new(Pool::new(
+        options.runtime_pool_size,
+        state.events.clone(),
+        {
+          let state = state.clone();
+          move || Runtime::new(state.clone())
+        },
+      )?),
+      service_pool: ServicePool::new(state.clone(), ServiceLimits {
+        max_services: options.max_services,
+        max_running_services: options.max_running_services,
+      }),
+      middlewares: Middlewares::default(),
       state,
     })
   }
 
+  /// Registers a Rust-backed Lua module, available to services loaded
+  /// afterwards with `require(name)`. Modules of the same name, including
+  /// built-in ones, are replaced.
+  pub fn register_lua_module(&self, name: impl Into<String>, f: LuaModuleFn) {
+    self.state.lua_modules.register(name.into(), f)
+  }
+
+  pub fn add_middleware(&mut self, middleware: impl Middleware + 'static) {
+    self.middlewares.push(Box::new(middleware));
+  }
+
+  /// Locks a service for deploying, so that concurrent updates of the same
+  /// name do not interleave. If `wait` is false, fails with
+  /// `DeployInProgress` when already locked.
+  ///
+  /// The guard is required by every operation that replaces, starts, stops
+  /// or removes the service.
+  pub async fn lock_deploy(&self, name: &str, wait: bool) -> Result<DeployGuard> {
+    self.service_pool.lock_deploy(name, wait).await
+  }
+
   pub async fn load_service(
     &self,
-    name: impl Into<ServiceName>,
+    guard: &DeployGuard,
     uuid: Option<Uuid>,
     source: Source,
     config: Config,
+    before_swap: Option<BeforeSwap>,
   ) -> Result<(StoppedService<'_>, Option<ServiceImpl>, ErrorPayload)> {
     (self.service_pool)
-      .load(&self.runtime_pool, name.into(), uuid, source, config)
+      .load(&self.runtime_pool, guard, uuid, source, config, before_swap)
       .await
   }
 
   pub async fn cold_update_or_create_service(
     &self,
-    name: impl Into<ServiceName>,
+    guard: &DeployGuard,
     uuid: Option<Uuid>,
     source: Source,
     config: Config,
+    before_swap: Option<BeforeSwap>,
   ) -> Result<(Service<'_>, Option<ServiceImpl>, ErrorPayload)> {
     (self.service_pool)
-      .cold_update_or_create(&self.runtime_pool, name.into(), uuid, source, config)
+      .cold_update_or_create(&self.runtime_pool, guard, uuid, source, config, before_swap)
       .await
   }
 
   pub async fn hot_update_service(
     &self,
-    name: impl Into<ServiceName>,
+    guard: &DeployGuard,
     uuid: Option<Uuid>,
     source: Source,
     config: Config,
+    before_swap: Option<BeforeSwap>,
   ) -> Result<(RunningService, ServiceImpl)> {
     (self.service_pool)
-      .hot_update(&self.runtime_pool, name.into(), uuid, source, config)
+      .hot_update(&self.runtime_pool, guard, uuid, source, config, before_swap)
       .await
   }
 
   pub async fn preload_service(
     &self,
-    name: impl Into<ServiceName>,
+    guard: &DeployGuard,
     uuid: Uuid,
     source: Source,
     config: Config,
   ) -> Result<(StoppedService<'_>, ErrorPayload)> {
     let (service, replaced, error_payload) = (self.service_pool)
-      .load(&self.runtime_pool, name.into(), Some(uuid), source, config)
+      .load(&self.runtime_pool, guard, Some(uuid), source, config, None)
       .await?;
     assert!(replaced.is_none());
     Ok((service, error_payload))
   }
 
+  /// Loads a stopped service without evaluating its source until it starts.
+  ///
+  /// If `start_on_request` is set, the service is started by its first
+  /// request, like a suspended one.
+  pub async fn preload_service_lazily(
+    &self,
+    guard: &DeployGuard,
+    uuid: Uuid,
+    source: Source,
+    config: Config,
+    start_on_request: bool,
+  ) -> Result<StoppedService<'_>> {
+    (self.service_pool)
+      .load_lazy(guard, Some(uuid), source, config, start_on_request)
+      .await
+  }
+
+  /// Loads a service as pending approval of its permissions, without
+  /// evaluating its source. An approved version of the service keeps serving
+  /// until this one is approved. Returns the version held before, if any.
+  pub async fn hold_service(
+    &self,
+    guard: &DeployGuard,
+    uuid: Option<Uuid>,
+    source: Source,
+    config: Config,
+  ) -> Result<(StoppedService<'_>, Option<ServiceImpl>)> {
+    (self.service_pool).hold(guard, uuid, source, config).await
+  }
+
+  /// Gets the version of a service held for approval while an approved one
+  /// keeps serving.
+  pub fn get_held_service(&self, name: &str) -> Option<StoppedService<'_>> {
+    self.service_pool.get_held(name)
+  }
+
+  /// Approves the permissions of a held service, so that it can be started.
+  /// Returns the version it replaces, if any, which is stopped.
+  pub async fn approve_service(
+    &self,
+    guard: &DeployGuard,
+  ) -> Result<(StoppedService<'_>, Option<ServiceImpl>)> {
+    self.service_pool.approve(&self.runtime_pool, guard).await
+  }
+
+  /// Replaces the permissions granted to a service, restricting what it
+  /// requested. `None` grants everything requested. Takes effect without
+  /// restarting the service.
+  pub fn grant_permissions(
+    &self,
+    guard: &DeployGuard,
+    granted: Option<Permissions>,
+  ) -> Result<Service<'_>> {
+    self.service_pool.grant(guard, granted)
+  }
+
   pub fn get_service(&self, name: &str) -> Result<Service<'_>> {
     (self.service_pool)
       .get(name)
       .ok_or_else(|| ErrorKind::ServiceNotFound { name: name.into() }.into())
   }
 
+  /// Reads one of a service's secrets.
+  pub async fn get_secret(&self, name: &str, key: &str) -> Result<Option<String>> {
+    Ok(service::load_secrets(&self.state, name).await?.remove(key))
+  }
+
   pub fn get_running_service(&self, name: &str) -> Result<RunningService> {
     (self.service_pool)
       .get_running(name)
@@ -128,28 +306,342 @@ impl Abel {
     path: String,
     req: Request<Body>,
   ) -> Result<Response<Body>> {
-    (self.runtime_pool)
-      .scope(move |rt| async move { Ok(rt.handle_request(service, &path, req).await?.into()) })
+    self.run(service, path, req, true).await
+  }
+
+  /// Runs a synthetic request, e.g. of a benchmark. Unlike [`run_service`],
+  /// it is not recorded in the service's metrics and diagnostics, does not
+  /// keep the service from being suspended, and its failure is not published.
+  ///
+  /// [`run_service`]: Self::run_service
+  pub async fn run_synthetic(
+    &self,
+    service: RunningService,
+    path: String,
+    req: Request<Body>,
+  ) -> Result<Response<Body>> {
+    self.run(service, path, req, false).await
+  }
+
+  async fn run(
+    &self,
+    service: RunningService,
+    path: String,
+    req: Request<Body>,
+    recorded: bool,
+  ) -> Result<Response<Body>> {
+    let guard = service.try_upgrade()?;
+    let metrics = guard.metrics.clone();
+    if let Some(resp) = guard.redirect(&path, req.uri().query()) {
+      if recorded {
+        metrics.record(false);
+      }
+      return Ok(resp);
+    }
+    let limiter = guard.limiter.clone();
+    let readiness = guard.readiness.clone();
+    let output_limits = guard.output_limits.clone();
+    let default_headers = guard.default_headers.clone();
+    let request_timeout = guard.request_timeout().map(Duration::from_secs);
+    let name: ServiceName = guard.name().into();
+    let uuid = guard.uuid();
+    let session_key = (guard.affinity())
+      .and_then(|x| x.session_key(req.headers()))
+      .map(|x| x.to_vec());
+    if recorded {
+      metrics.touch();
+    }
+    drop(guard);
+
+    let method = req.method().to_string();
+    let query = req.uri().query().map(String::from);
+    let path2 = path.clone();
+    let mut req = req;
+    let (called, short_circuited) = self.middlewares.on_request(&name, &mut req).await;
+
+    let name2 = name.clone();
+    // Timeouts too far in the future to be represented are no deadline at all
+    let deadline = request_timeout.and_then(|x| Instant::now().checked_add(x));
+    let run = async {
+      if let Some(resp) = short_circuited {
+        return Ok(resp);
+      }
+      if !readiness.wait().await {
+        return Err(ErrorKind::ServiceNotReady { name: name2 }.into());
+      }
+      // Excess requests are held here, outside of the runtime pool, so that
+      // they don't occupy workers needed by other services
+      let _permit = match &limiter {
+        Some(limiter) => {
+          Some((limiter.acquire().await).ok_or(ErrorKind::ServiceOverloaded { name: name2 })?)
+        }
+        None => None,
+      };
+      let range = RangeRequest::new(&req);
+      let task = move |rt: Rc<Runtime>| async move {
+        let mut resp = rt.handle_request(service, &path, req, deadline).await?;
+        apply_range(&mut resp, &range).await?;
+        Ok(resp.into())
+      };
+      if let Some(key) = session_key {
+        (self.runtime_pool)
+          .scope_on(key, Priority::Interactive, task)
+          .await?
+      } else {
+        (self.runtime_pool)
+          .scope_with(Priority::Interactive, task)
+          .await
+      }
+    };
+    let mut result: Result<Response<Body>> = match deadline {
+      Some(deadline) => (tokio::time::timeout_at(deadline.into(), run).await)
+        .unwrap_or_else(|_| Err(ErrorKind::RequestTimeout { name: name.clone() }.into())),
+      None => run.await,
+    };
+    if let Ok(resp) = &mut result {
+      add_default_headers(resp.headers_mut(), &default_headers);
+      self.middlewares.on_response(called, &name, resp).await;
+    }
+    let result = result.and_then(|resp| meter(name.clone(), resp, metrics.clone(), output_limits));
+    if !recorded {
+      return result;
+    }
+    metrics.record(match &result {
+      Ok(resp) => resp.status().is_server_error(),
+      Err(error) => error.kind().status().is_server_error(),
+    });
+    if let Err(error) = &result {
+      if error.kind().status().is_server_error() {
+        let diagnostics = self.state.diagnostics.get(&name);
+        diagnostics.record_error(error.to_string());
+      }
+      if let ErrorKind::Lua(lua_error) = error.kind() {
+        self.state.events.publish(EventKind::RequestFailed {
+          service: name,
+          uuid,
+          method,
+          path: path2,
+          query,
+          error: lua_error.to_string(),
+        });
+      }
+    }
+    result
+  }
+
+  pub async fn deploy_canary(
+    &self,
+    guard: &DeployGuard,
+    uuid: Option<Uuid>,
+    source: Source,
+    config: Config,
+    weight: u8,
+    before_swap: Option<BeforeSwap>,
+  ) -> Result<(RunningService, Option<ServiceImpl>)> {
+    (self.service_pool)
+      .deploy_canary(
+        &self.runtime_pool,
+        guard,
+        uuid,
+        source,
+        config,
+        weight,
+        before_swap,
+      )
+      .await
+  }
+
+  pub fn get_canary(&self, name: &str) -> Option<(RunningService, u8)> {
+    self.service_pool.get_canary(name)
+  }
+
+  pub async fn promote_canary(
+    &self,
+    guard: &DeployGuard,
+  ) -> Result<(RunningService, Option<ServiceImpl>, ErrorPayload)> {
+    (self.service_pool)
+      .promote_canary(&self.runtime_pool, guard)
+      .await
+  }
+
+  pub async fn abort_canary(&self, guard: &DeployGuard) -> Result<(ServiceImpl, ErrorPayload)> {
+    (self.service_pool)
+      .abort_canary(&self.runtime_pool, guard)
       .await
   }
 
+  /// Cleans up dropped services and expired caches, and collects garbage on
+  /// every worker.
+  pub async fn gc(&self) {
+    self.runtime_pool.cleanup().await
+  }
+
   pub fn list_services(&self) -> impl Iterator<Item = Service<'_>> {
     self.service_pool.list()
   }
 
-  pub async fn stop_service(&self, name: &str) -> Result<StoppedService<'_>> {
-    self.service_pool.stop(&self.runtime_pool, name).await
+  /// Renders request metrics of running services and custom metrics recorded
+  /// through `abel.metrics`, in Prometheus' text format.
+  pub fn render_metrics(&self) -> String {
+    let mut snapshots = (self.service_pool.list())
+      .filter_map(|x| match x {
+        Service::Running(x) => {
+          let guard = x.try_upgrade().ok()?;
+          Some((guard.name().to_string(), guard.metrics().snapshot()))
+        }
+        Service::Stopped(_) => None,
+      })
+      .collect::<Vec<_>>();
+    snapshots.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let mut out = String::new();
+    let counters: [(&str, fn(&MetricsSnapshot) -> u64); 5] = [
+      ("requests", |x| x.requests),
+      ("errors", |x| x.errors),
+      ("bytes_sent", |x| x.bytes_sent),
+      ("aborted_responses", |x| x.aborted_responses),
+      ("cpu_time_microseconds", |x| x.cpu_time_us),
+    ];
+    for (name, f) in counters {
+      out += &format!("# TYPE abel_{name}_total counter\n");
+      for (service, snapshot) in &snapshots {
+        out += &format!(
+          "abel_{name}_total{{service=\"{service}\"}} {}\n",
+          f(snapshot)
+        );
+      }
+    }
+    self.state.metrics.render(&mut out);
+    out
+  }
+
+  /// Profiles of recent slow requests to the service, oldest first.
+  pub fn list_profiles(&self, name: &str) -> Vec<Profile> {
+    self.state.profiles.list(name)
+  }
+
+  /// Recent errors and warnings of a service, optionally of one level.
+  pub fn list_diagnostics(&self, name: &str, level: Option<DiagnosticLevel>) -> Vec<Diagnostic> {
+    self.state.diagnostics.list(name, level)
+  }
+
+  /// Resource usage of each worker's sandbox.
+  pub async fn sandbox_stats(&self) -> Vec<SandboxStats> {
+    self.runtime_pool.stats().await
+  }
+
+  pub fn capacity(&self) -> Capacity {
+    self.service_pool.capacity()
+  }
+
+  pub async fn stop_service(&self, guard: &DeployGuard) -> Result<StoppedService<'_>> {
+    self.service_pool.stop(&self.runtime_pool, guard).await
   }
 
   pub async fn stop_all_services(&self) {
     self.service_pool.stop_all(&self.runtime_pool).await
   }
 
-  pub async fn start_service(&self, name: &str) -> Result<RunningService> {
-    self.service_pool.start(&self.runtime_pool, name).await
+  pub async fn start_service(&self, guard: &DeployGuard) -> Result<RunningService> {
+    self.service_pool.start(&self.runtime_pool, guard).await
+  }
+
+  /// Stops services idle for longer than their `idle_timeout`.
+  pub async fn suspend_idle_services(&self) -> Vec<ServiceName> {
+    self.service_pool.suspend_idle(&self.runtime_pool).await
+  }
+
+  /// Starts a service previously suspended for being idle.
+  pub async fn wake_service(&self, name: &str) -> Result<RunningService> {
+    self.service_pool.wake(&self.runtime_pool, name).await
+  }
+
+  /// Starts and stops services as their `availability` windows begin and
+  /// end.
+  pub async fn apply_availability(&self) -> Vec<(ServiceName, bool)> {
+    self
+      .service_pool
+      .apply_availability(&self.runtime_pool)
+      .await
+  }
+
+  /// Fires due `abel.schedule_at` calls of running services.
+  ///
+  /// Calls are spawned rather than awaited, so that slow callbacks do not hold
+  /// back others. Calls taken are leased, so later invocations do not fire
+  /// them again while they run.
+  pub async fn run_due_schedules(&self) {
+    let services = (self.service_pool.list())
+      .filter_map(|x| match x {
+        Service::Running(x) => Some(x),
+        Service::Stopped(_) => None,
+      })
+      .collect::<Vec<_>>();
+    for service in services {
+      let name = match service.try_upgrade() {
+        Ok(guard) => guard.name().to_string(),
+        Err(_) => continue,
+      };
+      let calls = match self.state.schedules.take_due(&name).await {
+        Ok(calls) => calls,
+        Err(error) => {
+          warn!("failed to read schedules of service '{name}': {error}");
+          continue;
+        }
+      };
+      for call in calls {
+        let pool = self.runtime_pool.clone();
+        let state = self.state.clone();
+        let service = service.clone();
+        let name = name.clone();
+        tokio::spawn(async move {
+          let call2 = call.clone();
+          let result = pool
+            .scope_with(Priority::Scheduled, move |rt: Rc<Runtime>| async move {
+              rt.handle_schedule(service, &call2).await
+            })
+            .await;
+          if let Err(error) = &result {
+            let retry = if call.attempts < MAX_ATTEMPTS {
+              "retrying later"
+            } else {
+              "giving up"
+            };
+            warn!(
+              "scheduled call '{}' of service '{name}' failed ({retry}): {error}",
+              call.id
+            );
+          }
+          let finished = (state.schedules)
+            .finish(&name, &call.id, result.is_ok())
+            .await;
+          if let Err(error) = finished {
+            warn!("failed to update schedules of service '{name}': {error}");
+          }
+        });
+      }
+    }
+  }
+
+  pub async fn remove_service(&self, guard: &DeployGuard) -> Result<ServiceImpl> {
+    (self.service_pool)
+      .remove(&self.runtime_pool, &self.state, guard)
+      .await
+  }
+
+  /// Receives lifecycle events published from now on.
+  pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+    self.state.events.subscribe()
+  }
+
+  /// Receives log lines and request failures published from now on.
+  pub fn subscribe_activity(&self) -> broadcast::Receiver<Event> {
+    self.state.events.subscribe_activity()
   }
 
-  pub async fn remove_service(&self, name: &str) -> Result<ServiceImpl> {
-    self.service_pool.remove(&self.state, name).await
+  /// Publishes an event to subscribers, for events Abel itself does not know
+  /// about, like deployments.
+  pub fn publish(&self, kind: EventKind) {
+    self.state.events.publish(kind)
   }
 }
@@ -1,4 +1,5 @@
-use crate::task::TimeoutError;
+use crate::net::NetDenied;
+use crate::task::{CancelledError, TimeoutError};
 use bstr::ByteSlice;
 use hyper::StatusCode;
 use mlua::Error::*;
@@ -64,27 +65,81 @@ pub fn modify_global_error_handling(lua: &Lua) -> mlua::Result<()> {
 
 fn create_fn_handle_http_error(lua: &Lua) -> mlua::Result<Function> {
   lua.create_function(|lua, custom_error: Table| -> mlua::Result<()> {
-    let result = CustomError {
-      status: custom_error
-        .check_raw_get::<Option<u16>>(lua, "status", "u16")?
-        .unwrap_or(500)
-        .try_into()
-        .map_err(|_| bad_field("status", "invalid status code"))?,
-      error: custom_error
-        .check_raw_get::<Option<mlua::String>>(lua, "error", "string")?
-        .map(|x| mlua::Result::Ok(x.to_str()?.into()).map_err(|error| bad_field("error", error)))
-        .transpose()?
-        .unwrap_or_else(|| "".into()),
-      detail: custom_error
-        .raw_get::<_, mlua::Value>("detail")
-        .and_then(|x| lua.from_value(x))
-        .map_err(|error| bad_field("detail", error))?,
-      source: Some(lua.create_registry_value(custom_error)?),
-    };
-    Err(result.to_lua_err())
+    Err(custom_error_from_table(lua, custom_error)?.to_lua_err())
   })
 }
 
+fn custom_error_from_table<'lua>(
+  lua: &'lua Lua,
+  custom_error: Table<'lua>,
+) -> mlua::Result<CustomError> {
+  Ok(CustomError {
+    status: custom_error
+      .check_raw_get::<Option<u16>>(lua, "status", "u16")?
+      .unwrap_or(500)
+      .try_into()
+      .map_err(|_| bad_field("status", "invalid status code"))?,
+    error: custom_error
+      .check_raw_get::<Option<mlua::String>>(lua, "error", "string")?
+      .map(|x| mlua::Result::Ok(x.to_str()?.into()).map_err(|error| bad_field("error", error)))
+      .transpose()?
+      .unwrap_or_else(|| "".into()),
+    detail: custom_error
+      .raw_get::<_, mlua::Value>("detail")
+      .and_then(|x| lua.from_value(x))
+      .map_err(|error| bad_field("detail", error))?,
+    source: Some(lua.create_registry_value(custom_error)?),
+  })
+}
+
+/// Creates an HTTP error from Rust, same as calling
+/// `error { status = ..., error = ..., detail = ... }` in Lua.
+pub fn http_error<'lua>(
+  lua: &'lua Lua,
+  status: StatusCode,
+  error: &str,
+  detail: mlua::Value<'lua>,
+) -> mlua::Error {
+  let result = (|| {
+    let custom_error = lua.create_table_from([
+      ("status", lua.pack(status.as_u16())?),
+      ("error", lua.pack(error)?),
+      ("detail", detail),
+    ])?;
+    custom_error_from_table(lua, custom_error)
+  })();
+  match result {
+    Ok(custom_error) => custom_error.to_lua_err(),
+    Err(error) => error,
+  }
+}
+
+/// Converts an error of outbound network access. Access denied by the `net`
+/// permission is raised as an HTTP error with the destination in its detail,
+/// so that it can be told apart from network failures.
+pub fn net_error(
+  lua: &Lua,
+  error: impl Into<Box<dyn std::error::Error + Send + Sync>>,
+) -> mlua::Error {
+  let error = error.into();
+  let mut source: Option<&(dyn std::error::Error + 'static)> = Some(&*error);
+  while let Some(x) = source {
+    if let Some(denied) = x.downcast_ref::<NetDenied>() {
+      return match lua.to_value(denied) {
+        Ok(detail) => http_error(
+          lua,
+          StatusCode::INTERNAL_SERVER_ERROR,
+          "net permission denied",
+          detail,
+        ),
+        Err(error) => error,
+      };
+    }
+    source = x.source();
+  }
+  rt_error(error)
+}
+
 fn create_fn_pcall(lua: &Lua) -> mlua::Result<Function> {
   lua.create_async_function(|lua, args: MultiValue| async move {
     let (success, value): (bool, mlua::Value) = lua
@@ -96,7 +151,7 @@ fn create_fn_pcall(lua: &Lua) -> mlua::Result<Function> {
     } else {
       let value = if let mlua::Value::Error(error) = value {
         if let mlua::Error::ExternalError(ext) = resolve_callback_error(&error) {
-          if ext.is::<TimeoutError>() {
+          if ext.is::<TimeoutError>() || ext.is::<CancelledError>() {
             return Err(error);
           }
           ext
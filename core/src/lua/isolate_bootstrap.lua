@@ -3,6 +3,8 @@ local source, remote, create_require = ...
 local local_env = {}
 local internal = {
   paths = {},
+  middlewares = {},
+  schedules = {},
   sealed = false,
 }
 
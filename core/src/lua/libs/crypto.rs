@@ -1,8 +1,12 @@
-use crate::lua::error::{check_string, check_userdata_mut, rt_error, tag_handler};
+use crate::lua::error::{
+  arg_error, check_integer, check_string, check_userdata_mut, rt_error, tag_handler,
+};
 use crate::lua::LuaCacheExt;
-use data_encoding::HEXLOWER;
+use data_encoding::{BASE64, HEXLOWER};
 use digest::Digest;
+use mlua::Value::Nil;
 use mlua::{Function, Lua, MultiValue, UserData};
+use rand::{thread_rng, RngCore};
 use sha2::{Sha224, Sha256, Sha384, Sha512, Sha512_224, Sha512_256};
 
 pub fn create_preload_crypto(lua: &Lua) -> mlua::Result<Function> {
@@ -14,6 +18,8 @@ pub fn create_preload_crypto(lua: &Lua) -> mlua::Result<Function> {
     crypto_table.raw_set("Sha512", create_digest_interface::<Sha512>(lua)?)?;
     crypto_table.raw_set("Sha512_224", create_digest_interface::<Sha512_224>(lua)?)?;
     crypto_table.raw_set("Sha512_256", create_digest_interface::<Sha512_256>(lua)?)?;
+    crypto_table.raw_set("sri", create_fn_sri(lua)?)?;
+    crypto_table.raw_set("nonce", create_fn_nonce(lua)?)?;
     Ok(crypto_table)
   })
 }
@@ -60,3 +66,44 @@ fn create_digest_interface<H: Digest + 'static>(lua: &Lua) -> mlua::Result<Funct
     }
   })
 }
+
+/// Computes Subresource Integrity metadata of `data`, e.g. `sha384-...`.
+fn create_fn_sri(lua: &Lua) -> mlua::Result<Function> {
+  lua.create_cached_function("abel:crypto.sri", |lua, mut args: MultiValue| {
+    let data = check_string(lua, args.pop_front()).map_err(tag_handler(lua, 1, 0))?;
+    let algorithm = match args.pop_front() {
+      None | Some(Nil) => None,
+      x => Some(check_string(lua, x).map_err(tag_handler(lua, 2, 0))?),
+    };
+    let (prefix, hash) = match algorithm.as_ref().map(|x| x.as_bytes()) {
+      Some(b"sha256") => ("sha256", Sha256::digest(data).to_vec()),
+      None | Some(b"sha384") => ("sha384", Sha384::digest(data).to_vec()),
+      Some(b"sha512") => ("sha512", Sha512::digest(data).to_vec()),
+      Some(_) => {
+        return Err(arg_error(
+          lua,
+          2,
+          "algorithm must be one of 'sha256', 'sha384' and 'sha512'",
+          0,
+        ))
+      }
+    };
+    Ok(format!("{prefix}-{}", BASE64.encode(&hash)))
+  })
+}
+
+/// Generates a random Base64 string suitable for CSP nonces.
+fn create_fn_nonce(lua: &Lua) -> mlua::Result<Function> {
+  lua.create_cached_function("abel:crypto.nonce", |lua, mut args: MultiValue| {
+    let len = match args.pop_front() {
+      None | Some(Nil) => 16,
+      x => check_integer(x).map_err(tag_handler(lua, 1, 0))?,
+    };
+    if !(1..=1024).contains(&len) {
+      return Err(arg_error(lua, 1, "length must be between 1 and 1024", 0));
+    }
+    let mut bytes = vec![0; len as _];
+    thread_rng().fill_bytes(&mut bytes);
+    Ok(BASE64.encode(&bytes))
+  })
+}
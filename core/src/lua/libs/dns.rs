@@ -0,0 +1,232 @@
+//! DNS lookups with trust-dns, configured by `/etc/resolv.conf` and
+//! `/etc/hosts`.
+
+use crate::lua::error::{arg_error, check_string, net_error, rt_error, rt_error_fmt, tag_handler};
+use crate::lua::LuaCacheExt;
+use crate::net::NetPolicy;
+use crate::task::TaskContext;
+use mlua::{Function, Lua, MultiValue, Table, ToLua};
+use once_cell::sync::Lazy;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io;
+use tokio::time::timeout;
+use trust_dns_resolver::config::LookupIpStrategy;
+use trust_dns_resolver::error::{ResolveError, ResolveErrorKind};
+use trust_dns_resolver::proto::rr::{Name, RData, RecordType};
+use trust_dns_resolver::{system_conf, TokioAsyncResolver};
+
+/// Shared by all services, so that answers are cached across them.
+static RESOLVER: Lazy<TokioAsyncResolver> = Lazy::new(|| {
+  let (config, mut opts) = system_conf::read_system_conf().unwrap_or_default();
+  opts.ip_strategy = LookupIpStrategy::Ipv4AndIpv6;
+  TokioAsyncResolver::tokio(config, opts).expect("failed to create DNS resolver")
+});
+
+/// Longest time a lookup from Lua may take, capped at the request's deadline.
+const RESOLVE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Record types `dns.resolve` accepts.
+const RECORD_TYPES: &[RecordType] = &[
+  RecordType::A,
+  RecordType::NS,
+  RecordType::CNAME,
+  RecordType::MX,
+  RecordType::TXT,
+  RecordType::AAAA,
+  RecordType::SRV,
+];
+
+#[derive(Debug, PartialEq, Eq)]
+enum Record {
+  A(Ipv4Addr),
+  Aaaa(Ipv6Addr),
+  /// `NS` or `CNAME`
+  Name(String),
+  Mx {
+    preference: u16,
+    exchange: String,
+  },
+  /// Character strings concatenated
+  Txt(Vec<u8>),
+  Srv {
+    priority: u16,
+    weight: u16,
+    port: u16,
+    target: String,
+  },
+}
+
+impl<'lua> ToLua<'lua> for Record {
+  fn to_lua(self, lua: &'lua Lua) -> mlua::Result<mlua::Value<'lua>> {
+    match self {
+      Self::A(ip) => lua.pack(ip.to_string()),
+      Self::Aaaa(ip) => lua.pack(ip.to_string()),
+      Self::Name(name) => lua.pack(name),
+      Self::Txt(text) => lua.pack(lua.create_string(&text)?),
+      Self::Mx {
+        preference,
+        exchange,
+      } => {
+        let table = lua.create_table()?;
+        table.raw_set("preference", preference)?;
+        table.raw_set("exchange", exchange)?;
+        lua.pack(table)
+      }
+      Self::Srv {
+        priority,
+        weight,
+        port,
+        target,
+      } => {
+        let table = lua.create_table()?;
+        table.raw_set("priority", priority)?;
+        table.raw_set("weight", weight)?;
+        table.raw_set("port", port)?;
+        table.raw_set("target", target)?;
+        lua.pack(table)
+      }
+    }
+  }
+}
+
+pub fn create_preload_dns(lua: &Lua) -> mlua::Result<Function> {
+  lua.create_cached_function("abel:preload_dns", |lua, ()| {
+    let dns = lua.create_table()?;
+    dns.raw_set("resolve", create_fn_dns_resolve(lua)?)?;
+    Ok(dns)
+  })
+}
+
+/// `dns` module that only looks up names allowed by `policy`.
+pub fn create_preload_dns_restricted(
+  policy: Arc<NetPolicy>,
+) -> impl FnOnce(&Lua) -> mlua::Result<Function> {
+  |lua| {
+    lua.create_function(move |lua, ()| {
+      let dns = lua.create_table()?;
+      let policy = policy.clone();
+      let resolve =
+        lua.create_async_function(move |lua, args| dns_resolve(lua, args, Some(policy.clone())))?;
+      dns.raw_set("resolve", resolve)?;
+      Ok(dns)
+    })
+  }
+}
+
+fn create_fn_dns_resolve(lua: &Lua) -> mlua::Result<Function> {
+  lua.create_cached_async_function("abel:dns.resolve", |lua, args| dns_resolve(lua, args, None))
+}
+
+/// `dns.resolve(name, type)`, where `type` defaults to `"A"`. Returns an
+/// empty array if the name does not exist.
+async fn dns_resolve<'lua>(
+  lua: &'lua Lua,
+  mut args: MultiValue<'lua>,
+  policy: Option<Arc<NetPolicy>>,
+) -> mlua::Result<Table<'lua>> {
+  let name = check_string(lua, args.pop_front()).map_err(tag_handler(lua, 1, 1))?;
+  let name = (name.to_str()).map_err(|_| arg_error(lua, 1, "invalid domain name", 1))?;
+  let rtype = match args.pop_front() {
+    None | Some(mlua::Value::Nil) => RecordType::A,
+    x => {
+      let rtype = check_string(lua, x).map_err(tag_handler(lua, 2, 1))?;
+      (rtype.to_str().ok())
+        .and_then(|x| x.to_ascii_uppercase().parse().ok())
+        .filter(|x| RECORD_TYPES.contains(x))
+        .ok_or_else(|| arg_error(lua, 2, "unsupported record type", 1))?
+    }
+  };
+  if let Some(policy) = policy {
+    policy
+      .check_name(name)
+      .map_err(|error| net_error(lua, error))?;
+  }
+  let limit = TaskContext::cap_timeout(lua, Some(RESOLVE_TIMEOUT)).unwrap();
+  let records = (timeout(limit, resolve(name, rtype)).await)
+    .map_err(|_| rt_error_fmt!("resolving '{name}' timed out"))?
+    .map_err(|error| rt_error_fmt!("failed to resolve '{name}' ({error})"))?;
+  lua.create_sequence_from(records)
+}
+
+/// Looks up addresses of `host` without blocking a thread, IPv4 first.
+pub(crate) async fn lookup_host(host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+  if let Ok(ip) = host.parse::<IpAddr>() {
+    return Ok(vec![SocketAddr::new(ip, port)]);
+  }
+  let lookup = (RESOLVER.lookup_ip(host).await)
+    .map_err(|error| io::Error::new(io::ErrorKind::Other, error))?;
+  let mut addrs = (lookup.iter())
+    .map(|ip| SocketAddr::new(ip, port))
+    .collect::<Vec<_>>();
+  addrs.sort_by_key(SocketAddr::is_ipv6);
+  Ok(addrs)
+}
+
+/// Looks up records of the given type. A non-existent name yields no records.
+async fn resolve(name: &str, rtype: RecordType) -> Result<Vec<Record>, ResolveError> {
+  match RESOLVER.lookup(name, rtype).await {
+    // CNAME records followed to get the answers are left out
+    Ok(lookup) => Ok(
+      (lookup.iter())
+        .filter(|x| x.to_record_type() == rtype)
+        .filter_map(to_record)
+        .collect(),
+    ),
+    Err(error) if matches!(error.kind(), ResolveErrorKind::NoRecordsFound { .. }) => Ok(Vec::new()),
+    Err(error) => Err(error),
+  }
+}
+
+fn to_record(data: &RData) -> Option<Record> {
+  let record = match data {
+    RData::A(ip) => Record::A(*ip),
+    RData::AAAA(ip) => Record::Aaaa(*ip),
+    RData::NS(name) | RData::CNAME(name) => Record::Name(name_to_string(name)),
+    RData::MX(mx) => Record::Mx {
+      preference: mx.preference(),
+      exchange: name_to_string(mx.exchange()),
+    },
+    RData::TXT(txt) => Record::Txt(txt.txt_data().concat()),
+    RData::SRV(srv) => Record::Srv {
+      priority: srv.priority(),
+      weight: srv.weight(),
+      port: srv.port(),
+      target: name_to_string(srv.target()),
+    },
+    _ => return None,
+  };
+  Some(record)
+}
+
+/// Names without the trailing dot of fully qualified ones.
+fn name_to_string(name: &Name) -> String {
+  let name = name.to_utf8();
+  name.strip_suffix('.').unwrap_or(&name).to_owned()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::str::FromStr;
+  use trust_dns_resolver::proto::rr::rdata::{SRV, TXT};
+
+  #[test]
+  fn test_to_record() {
+    let target = Name::from_str("example.com.").unwrap();
+    let srv = RData::SRV(SRV::new(10, 5, 5060, target));
+    assert_eq!(
+      to_record(&srv),
+      Some(Record::Srv {
+        priority: 10,
+        weight: 5,
+        port: 5060,
+        target: "example.com".into(),
+      })
+    );
+
+    let txt = RData::TXT(TXT::new(vec!["v=spf1 ".into(), "-all".into()]));
+    assert_eq!(to_record(&txt), Some(Record::Txt(b"v=spf1 -all".to_vec())));
+  }
+}
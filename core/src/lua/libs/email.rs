@@ -0,0 +1,328 @@
+//! Sending emails over SMTP with lettre, configured with the service's
+//! secrets:
+//!
+//! - `smtp_host`, `smtp_from`: required
+//! - `smtp_port`: defaults to 465, 587 with STARTTLS, or 25 without TLS
+//! - `smtp_tls`: `starttls` to upgrade plain connections with `STARTTLS`, or
+//!   `false` to connect without TLS, e.g. to a local relay. TLS is implicit
+//!   (SMTPS) by default.
+//! - `smtp_username`, `smtp_password`: credentials, never sent without TLS
+
+use crate::lua::error::{
+  bad_field, check_value, rt_error, rt_error_fmt, tag_handler, TableCheckExt,
+};
+use crate::task::TaskContext;
+use lettre::message::{Mailbox, MultiPart, SinglePart};
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::transport::smtp::AsyncSmtpTransportBuilder;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use mlua::{Function, Lua, Table};
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::time::timeout;
+
+const SEND_TIMEOUT: Duration = Duration::from_secs(30);
+
+type Transport = AsyncSmtpTransport<Tokio1Executor>;
+
+/// How the connection to the SMTP server is secured.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SmtpTls {
+  /// TLS from the start (SMTPS).
+  Implicit,
+  /// Upgraded with `STARTTLS`, which the server must support.
+  StartTls,
+  None,
+}
+
+#[derive(Debug, Clone)]
+pub struct SmtpConfig {
+  host: String,
+  port: u16,
+  tls: SmtpTls,
+  username: Option<String>,
+  password: Option<String>,
+  from: String,
+}
+
+impl SmtpConfig {
+  /// Returns `None` if SMTP is not configured in the secrets.
+  pub fn from_secrets(secrets: &HashMap<String, String>) -> Option<Self> {
+    let host = secrets.get("smtp_host")?.clone();
+    let from = secrets.get("smtp_from")?.clone();
+    let tls = match secrets.get("smtp_tls").map(|x| &**x) {
+      Some("false") => SmtpTls::None,
+      Some("starttls") => SmtpTls::StartTls,
+      _ => SmtpTls::Implicit,
+    };
+    let default_port = match tls {
+      SmtpTls::Implicit => 465,
+      SmtpTls::StartTls => 587,
+      SmtpTls::None => 25,
+    };
+    let port = (secrets.get("smtp_port"))
+      .and_then(|x| x.parse().ok())
+      .unwrap_or(default_port);
+    Some(Self {
+      host,
+      port,
+      tls,
+      username: secrets.get("smtp_username").cloned(),
+      password: secrets.get("smtp_password").cloned(),
+      from,
+    })
+  }
+}
+
+/// Emails sent in the current minute by each service, shared by all workers.
+static SENT: Lazy<Mutex<HashMap<String, (u64, u32)>>> = Lazy::new(Default::default);
+
+/// Returns `false` if the service has sent `limit` emails this minute.
+fn check_rate(service: &str, limit: u32) -> bool {
+  let minute = (SystemTime::now().duration_since(UNIX_EPOCH))
+    .map(|x| x.as_secs() / 60)
+    .unwrap_or(0);
+  let mut sent = SENT.lock();
+  let (window, count) = sent.entry(service.into()).or_insert((minute, 0));
+  if *window != minute {
+    *window = minute;
+    *count = 0;
+  }
+  if *count >= limit {
+    false
+  } else {
+    *count += 1;
+    true
+  }
+}
+
+pub fn create_preload_email(
+  service: String,
+  config: Option<SmtpConfig>,
+  rate_limit: u32,
+) -> impl FnOnce(&Lua) -> mlua::Result<Function> {
+  let context = Arc::new((service, config, rate_limit));
+  |lua| {
+    lua.create_function(move |lua, ()| {
+      let email = lua.create_table()?;
+      email.raw_set("send", create_fn_email_send(lua, context.clone())?)?;
+      Ok(email)
+    })
+  }
+}
+
+/// `email.send { to, cc, bcc, subject, text, html, vars }`
+///
+/// `to`, `cc` and `bcc` are an address or an array of them. Occurrences of
+/// `{{ key }}` in `subject`, `text` and `html` are replaced by values in
+/// `vars`.
+fn create_fn_email_send(
+  lua: &Lua,
+  context: Arc<(String, Option<SmtpConfig>, u32)>,
+) -> mlua::Result<Function> {
+  lua.create_async_function(move |lua, args: mlua::MultiValue| {
+    let context = context.clone();
+    async move {
+      let (service, config, rate_limit) = &*context;
+      let config = config.as_ref().ok_or_else(|| {
+        rt_error("SMTP is not configured (set 'smtp_host' and 'smtp_from' in service secrets)")
+      })?;
+      let table = check_value::<Table>(lua, args.into_iter().next(), "table")
+        .map_err(tag_handler(lua, 1, 1))?;
+      let email = Email::from_table(lua, table)?;
+      if !check_rate(service, *rate_limit) {
+        return Err(rt_error_fmt!(
+          "email rate limit exceeded ({rate_limit} per minute)"
+        ));
+      }
+      let message = email.to_message(&config.from).map_err(rt_error)?;
+      let limit = TaskContext::cap_timeout(lua, Some(SEND_TIMEOUT)).unwrap();
+      (timeout(limit, send(config, message)).await)
+        .map_err(|_| rt_error("sending email timed out"))?
+        .map_err(|error| rt_error_fmt!("failed to send email ({error})"))
+    }
+  })
+}
+
+struct Email {
+  to: Vec<Mailbox>,
+  cc: Vec<Mailbox>,
+  bcc: Vec<Mailbox>,
+  subject: String,
+  text: Option<String>,
+  html: Option<String>,
+}
+
+impl Email {
+  fn from_table(lua: &Lua, table: Table) -> mlua::Result<Self> {
+    let vars = (table.check_raw_get::<Option<Table>>(lua, "vars", "table")?)
+      .map(|vars| {
+        (vars.pairs::<String, mlua::Value>())
+          .map(|x| {
+            let (k, v) = x?;
+            let type_name = v.type_name();
+            let v = (lua.coerce_string(v)?)
+              .ok_or_else(|| bad_field("vars", format!("string expected, got {type_name}")))?;
+            Ok((k, v.to_str()?.to_owned()))
+          })
+          .collect::<mlua::Result<HashMap<_, _>>>()
+      })
+      .transpose()?
+      .unwrap_or_default();
+    let field = |name: &str| -> mlua::Result<Option<String>> {
+      (table.check_raw_get::<Option<String>>(lua, name, "string")?)
+        .map(|x| render(&x, &vars).map_err(|error| bad_field(name, error)))
+        .transpose()
+    };
+    let addresses = |name: &str| -> mlua::Result<Vec<Mailbox>> {
+      let addresses: Vec<String> = match table.raw_get::<_, mlua::Value>(name)? {
+        mlua::Value::Nil => Vec::new(),
+        mlua::Value::Table(t) => t.sequence_values().collect::<mlua::Result<_>>()?,
+        x => vec![lua
+          .unpack(x)
+          .map_err(|_| bad_field(name, "address or array expected"))?],
+      };
+      (addresses.iter())
+        .map(|x| (x.parse()).map_err(|_| bad_field(name, format!("invalid email address '{x}'"))))
+        .collect()
+    };
+
+    let email = Self {
+      to: addresses("to")?,
+      cc: addresses("cc")?,
+      bcc: addresses("bcc")?,
+      subject: field("subject")?.unwrap_or_default(),
+      text: field("text")?,
+      html: field("html")?,
+    };
+    if email.to.is_empty() && email.cc.is_empty() && email.bcc.is_empty() {
+      return Err(rt_error("no recipient"));
+    }
+    if email.subject.contains(['\r', '\n']) {
+      return Err(bad_field("subject", "line breaks not allowed"));
+    }
+    if email.text.is_none() && email.html.is_none() {
+      return Err(rt_error("either 'text' or 'html' is required"));
+    }
+    Ok(email)
+  }
+
+  fn to_message(&self, from: &str) -> Result<Message, String> {
+    let from =
+      (from.parse::<Mailbox>()).map_err(|_| format!("invalid 'smtp_from' address '{from}'"))?;
+    let mut builder = Message::builder().from(from).subject(&self.subject);
+    for x in &self.to {
+      builder = builder.to(x.clone());
+    }
+    for x in &self.cc {
+      builder = builder.cc(x.clone());
+    }
+    // Left out of the sent message, but kept in the envelope
+    for x in &self.bcc {
+      builder = builder.bcc(x.clone());
+    }
+    let message = match (&self.text, &self.html) {
+      (Some(text), Some(html)) => builder.multipart(MultiPart::alternative_plain_html(
+        text.clone(),
+        html.clone(),
+      )),
+      (Some(text), None) => builder.singlepart(SinglePart::plain(text.clone())),
+      (None, Some(html)) => builder.singlepart(SinglePart::html(html.clone())),
+      (None, None) => unreachable!(),
+    };
+    message.map_err(|error| error.to_string())
+  }
+}
+
+/// Replaces `{{ key }}` with values in `vars`.
+fn render(template: &str, vars: &HashMap<String, String>) -> Result<String, String> {
+  let mut result = String::with_capacity(template.len());
+  let mut rest = template;
+  while let Some(start) = rest.find("{{") {
+    result += &rest[..start];
+    let end = (rest[start..].find("}}")).ok_or("unclosed '{{' in template")?;
+    let key = rest[start + 2..start + end].trim();
+    let value = (vars.get(key)).ok_or_else(|| format!("variable '{key}' not found"))?;
+    result += value;
+    rest = &rest[start + end + 2..];
+  }
+  result += rest;
+  Ok(result)
+}
+
+async fn send(config: &SmtpConfig, message: Message) -> Result<(), String> {
+  let credentials = match (&config.username, &config.password) {
+    (Some(_), Some(_)) if config.tls == SmtpTls::None => {
+      return Err("refusing to send credentials without TLS".into())
+    }
+    (Some(username), Some(password)) => Some(Credentials::new(username.clone(), password.clone())),
+    _ => None,
+  };
+  let mut builder = match config.tls {
+    SmtpTls::None => Transport::builder_dangerous(&config.host),
+    tls => tls_transport(&config.host, tls)?,
+  };
+  builder = builder.port(config.port).timeout(Some(SEND_TIMEOUT));
+  if let Some(credentials) = credentials {
+    builder = builder.credentials(credentials);
+  }
+  (builder.build().send(message).await).map_err(|error| error.to_string())?;
+  Ok(())
+}
+
+#[cfg(feature = "tls")]
+fn tls_transport(host: &str, tls: SmtpTls) -> Result<AsyncSmtpTransportBuilder, String> {
+  let builder = match tls {
+    SmtpTls::StartTls => Transport::starttls_relay(host),
+    _ => Transport::relay(host),
+  };
+  builder.map_err(|error| error.to_string())
+}
+
+#[cfg(not(feature = "tls"))]
+fn tls_transport(_host: &str, _tls: SmtpTls) -> Result<AsyncSmtpTransportBuilder, String> {
+  Err("TLS is not supported in this build; set 'smtp_tls' to false".into())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use test_case::test_case;
+
+  #[test_case("Hi {{ name }}!" => Ok("Hi Alice!".into()); "variable")]
+  #[test_case("{{name}}{{name}}" => Ok("AliceAlice".into()); "repeated")]
+  #[test_case("no vars" => Ok("no vars".into()); "plain")]
+  #[test_case("{{ age }}" => Err("variable 'age' not found".into()); "missing")]
+  #[test_case("{{ name" => Err("unclosed '{{' in template".into()); "unclosed")]
+  fn test_render(template: &str) -> Result<String, String> {
+    let vars = HashMap::from([("name".into(), "Alice".into())]);
+    render(template, &vars)
+  }
+
+  #[test]
+  fn test_to_message() {
+    let email = Email {
+      to: vec!["Alice <alice@example.com>".parse().unwrap()],
+      cc: Vec::new(),
+      bcc: vec!["bob@example.com".parse().unwrap()],
+      subject: "Hi".into(),
+      text: Some("Hello".into()),
+      html: None,
+    };
+    let message = email.to_message("Abel <abel@example.com>").unwrap();
+    assert_eq!(message.envelope().to().len(), 2);
+    let formatted = String::from_utf8(message.formatted()).unwrap();
+    assert!(formatted.contains("alice@example.com"));
+    assert!(!formatted.contains("bob@example.com"));
+  }
+
+  #[test_case("a@example.com" => true; "valid")]
+  #[test_case("a@example.com>\r\nRCPT TO:<b@example.com" => false; "injection")]
+  #[test_case("example.com" => false; "no at")]
+  fn test_parse_address(address: &str) -> bool {
+    address.parse::<Mailbox>().is_ok()
+  }
+}
@@ -0,0 +1,166 @@
+//! Running host programs, requested in the `exec` permission and allowed by
+//! the host.
+//!
+//! Programs are executed directly without a shell, with an empty environment
+//! unless `env` is given, and are killed on timeout.
+
+use crate::lua::error::{
+  check_string, check_value, rt_error, rt_error_fmt, tag_handler, TableCheckExt,
+};
+use crate::task::TaskContext;
+use mlua::Value::Nil;
+use mlua::{Function, Lua, MultiValue, Table};
+use std::collections::HashMap;
+use std::path::Path;
+use std::process::Stdio;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::process::Command;
+use tokio::time::timeout;
+
+const DEFAULT_TIMEOUT: f64 = 30.;
+const MAX_TIMEOUT: f64 = 600.;
+/// Maximum size of each of stdout and stderr.
+const MAX_OUTPUT: usize = 4 * 1024 * 1024;
+
+pub fn create_preload_exec(allowed: Arc<[String]>) -> impl FnOnce(&Lua) -> mlua::Result<Function> {
+  |lua| {
+    lua.create_function(move |lua, ()| {
+      let exec = lua.create_table()?;
+      exec.raw_set("run", create_fn_exec_run(lua, allowed.clone())?)?;
+      Ok(exec)
+    })
+  }
+}
+
+async fn read_limited(reader: impl AsyncRead + Unpin) -> io::Result<Vec<u8>> {
+  let mut buf = Vec::new();
+  (reader.take(MAX_OUTPUT as u64 + 1))
+    .read_to_end(&mut buf)
+    .await?;
+  if buf.len() > MAX_OUTPUT {
+    return Err(io::Error::new(
+      io::ErrorKind::Other,
+      format!("output exceeds {MAX_OUTPUT} bytes"),
+    ));
+  }
+  Ok(buf)
+}
+
+/// `exec.run(program, args, { stdin, env, timeout })`, returning
+/// `{ status, stdout, stderr }` after the program exits. `status` is `nil` if
+/// the program is killed by a signal. `timeout` is capped at the request's
+/// deadline.
+fn create_fn_exec_run(lua: &Lua, allowed: Arc<[String]>) -> mlua::Result<Function> {
+  lua.create_async_function(move |lua, mut args: MultiValue| {
+    let allowed = allowed.clone();
+    async move {
+      let program = check_string(lua, args.pop_front()).map_err(tag_handler(lua, 1, 1))?;
+      let program = program.to_str()?.to_owned();
+      if !Path::new(&program).is_absolute() || !allowed.contains(&program) {
+        return Err(rt_error_fmt!("running '{program}' is not permitted"));
+      }
+      let program_args =
+        check_value::<Option<Vec<String>>>(lua, args.pop_front().or(Some(Nil)), "array of strings")
+          .map_err(tag_handler(lua, 2, 1))?
+          .unwrap_or_default();
+      let opts = check_value::<Option<Table>>(lua, args.pop_front().or(Some(Nil)), "table")
+        .map_err(tag_handler(lua, 3, 1))?;
+      let stdin = (opts.as_ref())
+        .map(|x| x.check_raw_get::<Option<mlua::String>>(lua, "stdin", "string"))
+        .transpose()?
+        .flatten()
+        .map(|x| x.as_bytes().to_vec());
+      let env = (opts.as_ref())
+        .map(|x| x.check_raw_get::<Option<HashMap<String, String>>>(lua, "env", "table"))
+        .transpose()?
+        .flatten()
+        .unwrap_or_default();
+      let secs = (opts.as_ref())
+        .map(|x| x.check_raw_get::<Option<f64>>(lua, "timeout", "number"))
+        .transpose()?
+        .flatten()
+        .unwrap_or(DEFAULT_TIMEOUT);
+      if !(secs > 0. && secs <= MAX_TIMEOUT) {
+        return Err(rt_error_fmt!(
+          "timeout must be positive and at most {MAX_TIMEOUT} seconds"
+        ));
+      }
+
+      let mut child = Command::new(&program)
+        .args(program_args)
+        .env_clear()
+        .envs(env)
+        .stdin(if stdin.is_some() {
+          Stdio::piped()
+        } else {
+          Stdio::null()
+        })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|error| rt_error_fmt!("failed to run '{program}' ({error})"))?;
+      let stdin_pipe = child.stdin.take();
+      let stdout = child.stdout.take().unwrap();
+      let stderr = child.stderr.take().unwrap();
+
+      let run = async {
+        let write = async {
+          if let (Some(mut pipe), Some(data)) = (stdin_pipe, stdin) {
+            match pipe.write_all(&data).await {
+              // The program may exit without reading all of its input
+              Err(error) if error.kind() != io::ErrorKind::BrokenPipe => return Err(error),
+              _ => {}
+            }
+          }
+          Ok(())
+        };
+        let (write, stdout, stderr) =
+          tokio::join!(write, read_limited(stdout), read_limited(stderr));
+        write?;
+        let status = child.wait().await?;
+        io::Result::Ok((status, stdout?, stderr?))
+      };
+      // Programs outliving the request would be killed with it anyway
+      let limit = TaskContext::cap_timeout(lua, Some(Duration::from_secs_f64(secs))).unwrap();
+      let (status, stdout, stderr) = (timeout(limit, run).await)
+        .map_err(|_| rt_error_fmt!("'{program}' timed out"))?
+        .map_err(rt_error)?;
+
+      let result = lua.create_table()?;
+      result.raw_set("status", status.code())?;
+      result.raw_set("stdout", lua.create_string(&stdout)?)?;
+      result.raw_set("stderr", lua.create_string(&stderr)?)?;
+      Ok(result)
+    }
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[tokio::test]
+  async fn test_read_limited() {
+    let data = vec![0u8; MAX_OUTPUT];
+    assert_eq!(read_limited(&data[..]).await.unwrap().len(), MAX_OUTPUT);
+    let data = vec![0u8; MAX_OUTPUT + 1];
+    assert!(read_limited(&data[..]).await.is_err());
+  }
+
+  #[cfg(unix)]
+  #[tokio::test]
+  async fn test_exec_deadline() -> mlua::Result<()> {
+    let lua = Lua::new();
+    TaskContext::default().set_current(&lua);
+    TaskContext::set_deadline(&lua, std::time::Instant::now() + Duration::from_millis(100));
+    let run = create_fn_exec_run(&lua, Arc::from(["/bin/sleep".to_string()]))?;
+    let start = std::time::Instant::now();
+    let error = (run.call_async::<_, Table>(("/bin/sleep", vec!["5"])).await).unwrap_err();
+    assert!(error.to_string().contains("timed out"));
+    assert!(start.elapsed() < Duration::from_secs(5));
+    Ok(())
+  }
+}
@@ -1,21 +1,31 @@
 use super::stream::create_table_stream;
+use super::{http_date, mime};
+#[cfg(feature = "encryption")]
+use crate::encryption::{self, EncryptedFile, StorageKey};
 use crate::lua::error::{
-  arg_error, check_integer, check_string, check_truthiness, check_userdata, check_userdata_mut,
-  rt_error, rt_error_fmt, tag_error, tag_handler, UserDataRef, UserDataRefMut,
+  arg_error, bad_field, check_integer, check_string, check_truthiness, check_userdata,
+  check_userdata_mut, check_value, http_error, rt_error, rt_error_fmt, tag_error, tag_handler,
+  TableCheckExt, UserDataRef, UserDataRefMut,
 };
+use crate::lua::http::{LuaBody, LuaResponse};
 use crate::lua::LuaCacheExt;
 use crate::path::normalize_path_str;
 use crate::source::{Metadata, ReadOnlyFile, Source};
 use crate::task::TaskContext;
 use bstr::ByteSlice;
+use hyper::header::{HeaderValue, CONTENT_DISPOSITION, CONTENT_TYPE, LAST_MODIFIED};
+use hyper::{HeaderMap, StatusCode};
 use mlua::Value::Nil;
-use mlua::{AnyUserData, Function, Lua, MultiValue, UserData, UserDataMethods};
+use mlua::{AnyUserData, Function, Lua, MultiValue, Table, UserData, UserDataMethods};
 use pin_project::pin_project;
+use std::cell::RefCell;
 use std::io::SeekFrom;
 use std::path::Path;
 use std::pin::Pin;
+use std::rc::Rc;
 use std::sync::Arc;
 use std::task::{Context, Poll};
+use std::time::UNIX_EPOCH;
 use tempfile::tempfile;
 use tokio::fs::{self, File, OpenOptions};
 use tokio::io::{
@@ -24,15 +34,27 @@ use tokio::io::{
 };
 use tokio::task::spawn_blocking;
 
+/// Placeholder without the `encryption` feature; local storage is never
+/// encrypted.
+#[cfg(not(feature = "encryption"))]
+#[derive(Debug)]
+pub enum StorageKey {}
+
 // Note that "lsp" stands for "local storage path".
+//
+// Files in local storage are encrypted at rest if `key` is set.
 pub fn create_preload_fs(
   source: Source,
   lsp: Arc<Path>,
+  key: Option<Arc<StorageKey>>,
 ) -> impl FnOnce(&Lua) -> mlua::Result<Function> {
   |lua| {
     lua.create_function(move |lua, ()| {
       let fs = lua.create_table()?;
-      fs.raw_set("open", create_fn_fs_open(lua, source.clone(), lsp.clone())?)?;
+      fs.raw_set(
+        "open",
+        create_fn_fs_open(lua, source.clone(), lsp.clone(), key.clone())?,
+      )?;
       fs.raw_set("type", create_fn_fs_type(lua)?)?;
       fs.raw_set("tmpfile", create_fn_fs_tmpfile(lua)?)?;
       fs.raw_set("mkdir", create_fn_fs_mkdir(lua, lsp.clone())?)?;
@@ -40,12 +62,16 @@ pub fn create_preload_fs(
       fs.raw_set("rename", create_fn_fs_rename(lua, lsp.clone())?)?;
       fs.raw_set(
         "metadata",
-        create_fn_fs_metadata(lua, source.clone(), lsp.clone())?,
+        create_fn_fs_metadata(lua, source.clone(), lsp.clone(), key.is_some())?,
       )?;
       fs.raw_set(
         "exists",
         create_fn_fs_exists(lua, source.clone(), lsp.clone())?,
       )?;
+      fs.raw_set(
+        "send_file",
+        create_fn_fs_send_file(lua, source.clone(), lsp.clone(), key.clone())?,
+      )?;
       Ok(fs)
     })
   }
@@ -349,12 +375,16 @@ fn bad_fd() -> io::Error {
 pub enum GenericFile {
   File(#[pin] File),
   ReadOnly(#[pin] ReadOnlyFile),
+  #[cfg(feature = "encryption")]
+  Encrypted(#[pin] EncryptedFile),
 }
 
 impl GenericFile {
   pub async fn len(&mut self) -> io::Result<u64> {
     match self {
       Self::File(f) => Ok(f.metadata().await?.len()),
+      #[cfg(feature = "encryption")]
+      Self::Encrypted(f) => f.len().await,
       _ => {
         let len = self.seek(SeekFrom::End(0)).await?;
         self.rewind().await?;
@@ -373,6 +403,8 @@ impl AsyncRead for GenericFile {
     match self.project() {
       GenericFileProj::File(f) => f.poll_read(cx, buf),
       GenericFileProj::ReadOnly(f) => f.poll_read(cx, buf),
+      #[cfg(feature = "encryption")]
+      GenericFileProj::Encrypted(f) => f.poll_read(cx, buf),
     }
   }
 }
@@ -382,6 +414,8 @@ impl AsyncWrite for GenericFile {
     match self.project() {
       GenericFileProj::File(f) => f.poll_write(cx, buf),
       GenericFileProj::ReadOnly(_) => Poll::Ready(Err(bad_fd())),
+      #[cfg(feature = "encryption")]
+      GenericFileProj::Encrypted(f) => f.poll_write(cx, buf),
     }
   }
 
@@ -389,6 +423,8 @@ impl AsyncWrite for GenericFile {
     match self.project() {
       GenericFileProj::File(f) => f.poll_flush(cx),
       GenericFileProj::ReadOnly(_) => Poll::Ready(Err(bad_fd())),
+      #[cfg(feature = "encryption")]
+      GenericFileProj::Encrypted(f) => f.poll_flush(cx),
     }
   }
 
@@ -396,6 +432,8 @@ impl AsyncWrite for GenericFile {
     match self.project() {
       GenericFileProj::File(f) => f.poll_shutdown(cx),
       GenericFileProj::ReadOnly(_) => Poll::Ready(Err(bad_fd())),
+      #[cfg(feature = "encryption")]
+      GenericFileProj::Encrypted(f) => f.poll_shutdown(cx),
     }
   }
 }
@@ -405,6 +443,8 @@ impl AsyncSeek for GenericFile {
     match self.project() {
       GenericFileProj::File(f) => f.start_seek(position),
       GenericFileProj::ReadOnly(f) => f.start_seek(position),
+      #[cfg(feature = "encryption")]
+      GenericFileProj::Encrypted(f) => f.start_seek(position),
     }
   }
 
@@ -412,15 +452,58 @@ impl AsyncSeek for GenericFile {
     match self.project() {
       GenericFileProj::File(f) => f.poll_complete(cx),
       GenericFileProj::ReadOnly(f) => f.poll_complete(cx),
+      #[cfg(feature = "encryption")]
+      GenericFileProj::Encrypted(f) => f.poll_complete(cx),
+    }
+  }
+}
+
+async fn open_file(
+  source: &Source,
+  lsp: &Path,
+  key: Option<Arc<StorageKey>>,
+  scheme: Scheme,
+  path: &str,
+  mode: OpenMode,
+) -> io::Result<GenericFile> {
+  match scheme {
+    Scheme::Local => {
+      let path = lsp.join(normalize_path_str(path));
+      #[allow(unused_mut)]
+      let mut options = mode.to_open_options();
+      match key {
+        #[cfg(feature = "encryption")]
+        Some(key) => {
+          let writable = mode != OpenMode::Read;
+          let append = matches!(mode, OpenMode::Append | OpenMode::ReadAppend);
+          // Chunks are read back and rewritten in place, which `O_APPEND`
+          // would prevent
+          let file = (options.read(true).write(writable).append(false))
+            .open(&path)
+            .await?;
+          EncryptedFile::open(file.into_std().await, key, writable, append)
+            .await
+            .map(GenericFile::Encrypted)
+        }
+        _ => Ok(GenericFile::File(options.open(&path).await?)),
+      }
     }
+    // For `source:`, the only open mode is "read"
+    Scheme::Source => source.get(path).await.map(GenericFile::ReadOnly),
   }
 }
 
-fn create_fn_fs_open(lua: &Lua, source: Source, lsp: Arc<Path>) -> mlua::Result<Function<'_>> {
+fn create_fn_fs_open(
+  lua: &Lua,
+  source: Source,
+  lsp: Arc<Path>,
+  key: Option<Arc<StorageKey>>,
+) -> mlua::Result<Function<'_>> {
   use OpenMode::*;
   lua.create_async_function(move |lua, mut args: MultiValue| {
     let source = source.clone();
     let lsp = lsp.clone();
+    let key = key.clone();
     async move {
       let path = check_string(lua, args.pop_front()).map_err(tag_handler(lua, 1, 1))?;
       let mode = args
@@ -432,25 +515,9 @@ fn create_fn_fs_open(lua: &Lua, source: Source, lsp: Arc<Path>) -> mlua::Result<
       let (scheme, path) = parse_path(&path)?;
       let mode = OpenMode::from_lua(mode)?;
 
-      let file = match scheme {
-        Scheme::Local => {
-          let path = normalize_path_str(path);
-          mode
-            .to_open_options()
-            .open(lsp.join(path))
-            .await
-            .map(GenericFile::File)
-            .map_err(rt_error)?
-        }
-        Scheme::Source => {
-          // For `source:`, the only open mode is "read"
-          source
-            .get(path)
-            .await
-            .map(GenericFile::ReadOnly)
-            .map_err(rt_error)?
-        }
-      };
+      let file = open_file(&source, &lsp, key, scheme, path, mode)
+        .await
+        .map_err(rt_error)?;
       let (rc, wc) = match mode {
         Read => (8192, 0),
         Write | Append => (0, 8192),
@@ -560,7 +627,13 @@ fn create_fn_fs_rename(lua: &Lua, lsp: Arc<Path>) -> mlua::Result<Function> {
   })
 }
 
-fn create_fn_fs_metadata(lua: &Lua, source: Source, lsp: Arc<Path>) -> mlua::Result<Function> {
+#[cfg_attr(not(feature = "encryption"), allow(unused_variables))]
+fn create_fn_fs_metadata(
+  lua: &Lua,
+  source: Source,
+  lsp: Arc<Path>,
+  encrypted: bool,
+) -> mlua::Result<Function> {
   lua.create_async_function(move |lua, mut args: MultiValue| {
     let source = source.clone();
     let lsp = lsp.clone();
@@ -572,11 +645,17 @@ fn create_fn_fs_metadata(lua: &Lua, source: Source, lsp: Arc<Path>) -> mlua::Res
         Scheme::Local => {
           let path = lsp.join(normalize_path_str(path));
           async {
-            let md = fs::metadata(path).await?;
+            let md = fs::metadata(&path).await?;
             if md.is_dir() {
               Ok(Metadata::Dir)
             } else if md.is_file() {
-              Ok(Metadata::File { size: md.len() })
+              #[allow(unused_mut)]
+              let mut size = md.len();
+              #[cfg(feature = "encryption")]
+              if encrypted {
+                size = encryption::content_len(&path, size).await?;
+              }
+              Ok(Metadata::File { size })
             } else {
               Err(rt_error("the entity is neither a file nor a directory"))
             }
@@ -618,3 +697,117 @@ fn create_fn_fs_exists(lua: &Lua, source: Source, lsp: Arc<Path>) -> mlua::Resul
     }
   })
 }
+
+/// `fs.send_file(path, options)`
+///
+/// Creates a response streaming the file, with `Content-Type` guessed from
+/// its extension and, for local files, `Last-Modified`. Ranges of it are
+/// served if requested.
+///
+/// Options:
+/// - `content_type`: overrides the guessed type
+/// - `attachment`: `true` or a file name, making browsers download the file
+fn create_fn_fs_send_file(
+  lua: &Lua,
+  source: Source,
+  lsp: Arc<Path>,
+  key: Option<Arc<StorageKey>>,
+) -> mlua::Result<Function> {
+  lua.create_async_function(move |lua, mut args: MultiValue| {
+    let source = source.clone();
+    let lsp = lsp.clone();
+    let key = key.clone();
+    async move {
+      let path_str = check_string(lua, args.pop_front()).map_err(tag_handler(lua, 1, 1))?;
+      let options: Option<Table> = check_value(lua, args.pop_front().or(Some(Nil)), "table")
+        .map_err(tag_handler(lua, 2, 1))?;
+      let (scheme, path) = parse_path(&path_str)?;
+
+      let (content_type, attachment) = match &options {
+        Some(options) => (
+          options.check_raw_get::<Option<String>>(lua, "content_type", "string")?,
+          options.check_raw_get::<mlua::Value>(lua, "attachment", "boolean or string")?,
+        ),
+        None => (None, Nil),
+      };
+      let file_name = path.rsplit('/').next().unwrap_or(path);
+      let attachment = match attachment {
+        Nil | mlua::Value::Boolean(false) => None,
+        mlua::Value::Boolean(true) => Some(file_name.to_owned()),
+        mlua::Value::String(name) => Some(name.to_str()?.to_owned()),
+        x => {
+          let error = format!("boolean or string expected, got {}", x.type_name());
+          return Err(bad_field("attachment", error));
+        }
+      };
+
+      let file = match open_file(&source, &lsp, key, scheme, path, OpenMode::Read).await {
+        Ok(file) => file,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => {
+          let detail = lua.pack(path_str.clone())?;
+          return Err(http_error(
+            lua,
+            StatusCode::NOT_FOUND,
+            "file not found",
+            detail,
+          ));
+        }
+        Err(error) => return Err(rt_error(error)),
+      };
+      let last_modified = match scheme {
+        Scheme::Local => (fs::metadata(lsp.join(normalize_path_str(path))).await)
+          .and_then(|x| x.modified())
+          .ok()
+          .and_then(|x| x.duration_since(UNIX_EPOCH).ok())
+          .map(|x| http_date(x.as_secs())),
+        Scheme::Source => None,
+      };
+
+      let mut headers = HeaderMap::new();
+      let content_type = content_type.as_deref().unwrap_or_else(|| mime::guess(path));
+      let content_type = HeaderValue::from_str(content_type)
+        .map_err(|_| bad_field("content_type", "invalid header value"))?;
+      headers.insert(CONTENT_TYPE, content_type);
+      if let Some(x) = last_modified {
+        headers.insert(LAST_MODIFIED, HeaderValue::from_str(&x).unwrap());
+      }
+      if let Some(name) = attachment {
+        headers.insert(CONTENT_DISPOSITION, content_disposition(&name));
+      }
+      Ok(LuaResponse {
+        status: StatusCode::OK,
+        headers: Rc::new(RefCell::new(headers)),
+        body: Some(LuaBody::File(BufStream::with_capacity(8192, 0, file))),
+        cache_ttl: None,
+      })
+    }
+  })
+}
+
+/// `Content-Disposition` of an attachment, with the file name also encoded
+/// as in RFC 5987 if it is not plain ASCII.
+fn content_disposition(name: &str) -> HeaderValue {
+  let mut value = String::from("attachment; filename=\"");
+  for c in name.chars() {
+    match c {
+      '"' | '\\' => {
+        value.push('\\');
+        value.push(c);
+      }
+      ' '..='~' => value.push(c),
+      _ => value.push('_'),
+    }
+  }
+  value.push('"');
+  if !name.bytes().all(|b| (b' '..=b'~').contains(&b)) {
+    value.push_str("; filename*=UTF-8''");
+    for b in name.bytes() {
+      if b.is_ascii_alphanumeric() || b"!#$&+-.^_`|~".contains(&b) {
+        value.push(b as char);
+      } else {
+        value.push_str(&format!("%{b:02X}"));
+      }
+    }
+  }
+  HeaderValue::from_str(&value).unwrap()
+}
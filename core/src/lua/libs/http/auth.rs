@@ -0,0 +1,246 @@
+use super::{LuaBody, LuaRequest, LuaResponse};
+use crate::lua::error::{arg_error, check_string, check_value, rt_error, tag_error, tag_handler};
+use crate::lua::LuaCacheExt;
+use bstr::ByteSlice;
+use data_encoding::BASE64;
+use hyper::header::{HeaderValue, AUTHORIZATION, CONTENT_TYPE, WWW_AUTHENTICATE};
+use hyper::{HeaderMap, StatusCode};
+use mlua::Value::Nil;
+use mlua::{AnyUserData, Function, Lua, MultiValue, TableExt};
+use serde_json::json;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+pub(super) fn create_table_http_auth(lua: &Lua) -> mlua::Result<mlua::Table> {
+  let auth = lua.create_table()?;
+  auth.raw_set("basic", create_fn_http_auth_basic(lua)?)?;
+  auth.raw_set("bearer", create_fn_http_auth_bearer(lua)?)?;
+  Ok(auth)
+}
+
+/// Parses the `Authorization` header of `scheme`, returning the credentials
+/// passed to the verifier: username and password for `Basic`, and the token
+/// for `Bearer`.
+fn parse_authorization(value: &[u8], scheme: &str) -> Option<Vec<Vec<u8>>> {
+  let (s, credentials) = value.split_at(value.find_byte(b' ')?);
+  if !s.eq_ignore_ascii_case(scheme.as_bytes()) {
+    return None;
+  }
+  let credentials = credentials.trim();
+  if scheme == "Basic" {
+    let decoded = BASE64.decode(credentials).ok()?;
+    let colon = decoded.find_byte(b':')?;
+    Some(vec![
+      decoded[..colon].to_vec(),
+      decoded[colon + 1..].to_vec(),
+    ])
+  } else {
+    (!credentials.is_empty()).then(|| vec![credentials.to_vec()])
+  }
+}
+
+fn quote(s: &str) -> String {
+  format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+fn unauthorized(challenge: HeaderValue) -> LuaResponse {
+  let mut headers = HeaderMap::new();
+  headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+  headers.insert(WWW_AUTHENTICATE, challenge);
+  LuaResponse {
+    status: StatusCode::UNAUTHORIZED,
+    headers: Rc::new(RefCell::new(headers)),
+    body: Some(LuaBody::Json(json!({ "error": "unauthorized" }))),
+    cache_ttl: None,
+  }
+}
+
+/// `http.auth.basic(realm, verify)`, where `verify(username, password, req)`
+/// returns whether the credentials are valid.
+fn create_fn_http_auth_basic(lua: &Lua) -> mlua::Result<Function> {
+  lua.create_cached_function("abel:http.auth.basic", |lua, mut args: MultiValue| {
+    let realm = check_string(lua, args.pop_front()).map_err(tag_handler(lua, 1, 1))?;
+    let verify: Function =
+      check_value(lua, args.pop_front(), "function").map_err(tag_handler(lua, 2, 1))?;
+    let params = format!("realm={}, charset=\"UTF-8\"", quote(realm.to_str()?));
+    if HeaderValue::from_str(&params).is_err() {
+      return Err(arg_error(lua, 1, "invalid realm", 1));
+    }
+    create_fn_http_auth_wrap(lua)?.bind(("Basic", params, verify))
+  })
+}
+
+/// `http.auth.bearer(verify)`, where `verify(token, req)` returns whether the
+/// token is valid.
+fn create_fn_http_auth_bearer(lua: &Lua) -> mlua::Result<Function> {
+  lua.create_cached_function("abel:http.auth.bearer", |lua, mut args: MultiValue| {
+    let verify: Function =
+      check_value(lua, args.pop_front(), "function").map_err(tag_handler(lua, 1, 1))?;
+    create_fn_http_auth_wrap(lua)?.bind(("Bearer", "", verify))
+  })
+}
+
+/// Wraps a handler, so that it is only called with valid credentials.
+fn create_fn_http_auth_wrap(lua: &Lua) -> mlua::Result<Function> {
+  lua.create_cached_function("abel:http.auth.wrap", |lua, args: MultiValue| {
+    // scheme, params and verifier are bound
+    let args = args.into_vec();
+    match args.get(3) {
+      Some(mlua::Value::Function(_) | mlua::Value::Table(_)) => {}
+      Some(x) => return Err(tag_error(lua, 1, "function", x.type_name(), 1)),
+      None => return Err(tag_error(lua, 1, "function", "no value", 1)),
+    }
+    create_fn_http_auth_handler(lua)?.bind(MultiValue::from_vec(args))
+  })
+}
+
+fn create_fn_http_auth_handler(lua: &Lua) -> mlua::Result<Function> {
+  type Args<'a> = (
+    mlua::String<'a>,
+    mlua::String<'a>,
+    Function<'a>,
+    mlua::Value<'a>,
+    AnyUserData<'a>,
+  );
+  lua.create_cached_async_function(
+    "abel:http.auth.handler",
+    |lua, (scheme, params, verify, handler, req): Args| async move {
+      let scheme = scheme.to_str()?;
+      let credentials = {
+        let req_ref = req.borrow::<LuaRequest>()?;
+        let headers = req_ref.headers.borrow();
+        (headers.get(AUTHORIZATION)).and_then(|x| parse_authorization(x.as_bytes(), scheme))
+      };
+      let present = credentials.is_some();
+      let authorized = match credentials {
+        Some(credentials) => {
+          let mut args = (credentials.iter())
+            .map(|x| lua.create_string(x).map(mlua::Value::String))
+            .collect::<mlua::Result<Vec<_>>>()?;
+          args.push(mlua::Value::UserData(req.clone()));
+          let result: mlua::Value = verify.call_async(MultiValue::from_vec(args)).await?;
+          !matches!(result, Nil | mlua::Value::Boolean(false))
+        }
+        None => false,
+      };
+
+      if authorized {
+        match handler {
+          mlua::Value::Function(f) => f.call_async(req).await,
+          mlua::Value::Table(t) => t.call_async(req).await,
+          _ => unreachable!(),
+        }
+      } else {
+        let params = match params.to_str()? {
+          "" if scheme == "Bearer" && present => "error=\"invalid_token\"",
+          x => x,
+        };
+        let challenge = if params.is_empty() {
+          HeaderValue::from_str(scheme)
+        } else {
+          HeaderValue::from_str(&format!("{scheme} {params}"))
+        };
+        Ok(unauthorized(challenge.map_err(rt_error)?))
+      }
+    },
+  )
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use hyper::{Body, Request};
+  use test_case::test_case;
+
+  fn strings(x: Option<Vec<Vec<u8>>>) -> Option<Vec<String>> {
+    x.map(|x| {
+      (x.into_iter())
+        .map(|x| String::from_utf8(x).unwrap())
+        .collect()
+    })
+  }
+
+  // "dXNlcjpwYTpzcw==" is "user:pa:ss"
+  #[test_case("Basic dXNlcjpwYTpzcw==", "Basic" => Some(vec!["user".into(), "pa:ss".into()]); "basic")]
+  #[test_case("basic  dXNlcjpwYTpzcw== ", "Basic" => Some(vec!["user".into(), "pa:ss".into()]); "case insensitive")]
+  #[test_case("Basic dXNlcg==", "Basic" => None; "without colon")]
+  #[test_case("Basic !!!", "Basic" => None; "bad base64")]
+  #[test_case("Bearer abc.def", "Bearer" => Some(vec!["abc.def".into()]); "bearer")]
+  #[test_case("Bearer ", "Bearer" => None; "empty token")]
+  #[test_case("Bearer abc", "Basic" => None; "other scheme")]
+  #[test_case("Bearer", "Bearer" => None; "no credentials")]
+  fn test_parse_authorization(value: &str, scheme: &str) -> Option<Vec<String>> {
+    strings(parse_authorization(value.as_bytes(), scheme))
+  }
+
+  #[test_case("abel" => r#""abel""#; "plain")]
+  #[test_case(r#"a "b" \c"# => r#""a \"b\" \\c""#; "escaped")]
+  fn test_quote(s: &str) -> String {
+    quote(s)
+  }
+
+  /// Runs `code` with `http.auth` as its argument, and calls the wrapped
+  /// handler it returns, yielding either the handler's string result or the
+  /// status and challenge of the rejection.
+  async fn call_wrapped(code: &str, authorization: Option<&str>) -> Result<String, (u16, String)> {
+    let lua = Lua::new();
+    let auth = create_table_http_auth(&lua).unwrap();
+    let handler: Function = lua.load(code).call(auth).unwrap();
+
+    let mut req = Request::builder().uri("http://localhost/");
+    if let Some(authorization) = authorization {
+      req = req.header(AUTHORIZATION, authorization);
+    }
+    let req = LuaRequest::new(req.body(Body::empty()).unwrap(), Default::default());
+    let req = lua.create_userdata(req).unwrap();
+
+    match handler.call_async(req).await.unwrap() {
+      mlua::Value::String(s) => Ok(s.to_str().unwrap().into()),
+      mlua::Value::UserData(resp) => {
+        let resp = resp.borrow::<LuaResponse>().unwrap();
+        let headers = resp.headers.borrow();
+        let challenge = headers[WWW_AUTHENTICATE].to_str().unwrap().into();
+        Err((resp.status.as_u16(), challenge))
+      }
+      x => panic!("unexpected value: {x:?}"),
+    }
+  }
+
+  const BASIC: &str = r#"
+    local auth = ...
+    local verify = function(username, password, req)
+      assert(req.uri.path == "/")
+      return username == "user" and password == "pa:ss"
+    end
+    return auth.basic("abel", verify)(function(req) return "ok" end)
+  "#;
+
+  const BEARER: &str = r#"
+    local auth = ...
+    local handler = setmetatable({}, { __call = function(self, req) return "ok" end })
+    return auth.bearer(function(token) return token == "abc" end)(handler)
+  "#;
+
+  #[tokio::test]
+  async fn test_basic() {
+    let challenge = r#"Basic realm="abel", charset="UTF-8""#;
+    let valid = call_wrapped(BASIC, Some("Basic dXNlcjpwYTpzcw==")).await;
+    assert_eq!(valid, Ok("ok".into()));
+    // "dXNlcjp3cm9uZw==" is "user:wrong"
+    let invalid = call_wrapped(BASIC, Some("Basic dXNlcjp3cm9uZw==")).await;
+    assert_eq!(invalid, Err((401, challenge.into())));
+    let missing = call_wrapped(BASIC, None).await;
+    assert_eq!(missing, Err((401, challenge.into())));
+  }
+
+  #[tokio::test]
+  async fn test_bearer() {
+    let valid = call_wrapped(BEARER, Some("Bearer abc")).await;
+    assert_eq!(valid, Ok("ok".into()));
+    let invalid = call_wrapped(BEARER, Some("Bearer xyz")).await;
+    let challenge = r#"Bearer error="invalid_token""#;
+    assert_eq!(invalid, Err((401, challenge.into())));
+    let missing = call_wrapped(BEARER, None).await;
+    assert_eq!(missing, Err((401, "Bearer".into())));
+  }
+}
@@ -1,6 +1,6 @@
 use super::LuaResponse;
 use crate::lua::error::rt_error;
-use crate::lua::fs::LuaFile;
+use crate::lua::fs::{GenericFile, LuaFile};
 use crate::lua::stream::{is_stream, ByteStream};
 use crate::lua::LuaCacheExt;
 use crate::runtime::abel::{abel_spawn, create_fn_spawn, is_in_abel_context};
@@ -10,12 +10,15 @@ use hyper::{Body, HeaderMap, StatusCode};
 use mlua::{AnyUserData, Lua, LuaSerdeExt, ToLua, UserData};
 use std::cell::RefCell;
 use std::rc::Rc;
+use tokio::io::BufStream;
 
 pub enum LuaBody {
   Empty,
   Json(serde_json::Value),
   Bytes(Vec<u8>),
   Stream(Body),
+  /// Opened file, kept seekable so that ranges of it can be served.
+  File(BufStream<GenericFile>),
 }
 
 impl LuaBody {
@@ -33,6 +36,7 @@ impl LuaBody {
       status,
       headers: Rc::new(RefCell::new(headers)),
       body: Some(self),
+      cache_ttl: None,
     }
   }
 
@@ -49,11 +53,9 @@ impl LuaBody {
         .take::<ByteStream>()
         .map(|x| Ok(Self::Stream(Body::wrap_stream(x.0))))?,
       // Optimization for file
-      mlua::Value::UserData(u) if u.is::<LuaFile>() => u.take::<LuaFile>().map(|x| {
-        Ok(Self::Stream(Body::wrap_stream(
-          ByteStream::from_async_read(x.0).0,
-        )))
-      })?,
+      mlua::Value::UserData(u) if u.is::<LuaFile>() => {
+        u.take::<LuaFile>().map(|x| Ok(Self::File(x.0)))?
+      }
       _ if is_stream(lua, value.clone())? => body_from_lua_stream(lua, value).map(Ok)?,
       mlua::Value::UserData(_) => Err("stream expected, got other userdata".into()),
 
@@ -131,6 +133,7 @@ impl From<LuaBody> for Body {
       LuaBody::Json(x) => x.to_string().into(),
       LuaBody::Bytes(x) => x.into(),
       LuaBody::Stream(x) => x,
+      LuaBody::File(x) => Body::wrap_stream(ByteStream::from_async_read(x).0),
     }
   }
 }
@@ -142,6 +145,7 @@ impl<'lua> ToLua<'lua> for LuaBody {
       Self::Json(x) => lua.to_value(&x),
       Self::Bytes(x) => Ok(mlua::Value::String(lua.create_string(&x)?)),
       Self::Stream(x) => lua.pack(ByteStream::from(x)),
+      Self::File(x) => lua.pack(ByteStream::from_async_read(x)),
     }
   }
 }
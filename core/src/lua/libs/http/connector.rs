@@ -0,0 +1,274 @@
+use crate::lua::dns::lookup_host;
+use crate::lua::error::TableCheckExt;
+use crate::net::NetPolicy;
+use crate::task::TaskContext;
+use futures::future::BoxFuture;
+use hyper::client::connect::dns::Name;
+use hyper::client::HttpConnector;
+use hyper::service::Service;
+use hyper::{Body, Client, Request, Response, Uri};
+use mlua::{Lua, Table};
+use std::convert::Infallible;
+use std::future::{ready, Ready};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Connection pool settings of the HTTP client used by services.
+///
+/// Each runtime keeps one pool shared by its services, and services with
+/// restricted `net` permission keep their own.
+#[derive(Debug, Clone)]
+pub struct HttpClientOptions {
+  /// Maximum number of idle connections kept for each host.
+  pub pool_max_idle_per_host: usize,
+  /// How long idle connections are kept. Kept until closed by peers if not
+  /// set.
+  pub pool_idle_timeout: Option<Duration>,
+  pub connect_timeout: Duration,
+}
+
+impl Default for HttpClientOptions {
+  fn default() -> Self {
+    Self {
+      pool_max_idle_per_host: usize::MAX,
+      pool_idle_timeout: Some(Duration::from_secs(90)),
+      connect_timeout: Duration::from_secs(10),
+    }
+  }
+}
+
+/// Delay before trying addresses of the other IP family, as recommended by
+/// RFC 8305.
+const HAPPY_EYEBALLS_TIMEOUT: Duration = Duration::from_millis(250);
+
+/// Resolves names asynchronously and connects with [`HttpConnector`], racing
+/// IPv4 and IPv6 addresses and timing out each attempt. If a [`NetPolicy`]
+/// is set, connects only to addresses it allows, checked right before
+/// connecting.
+#[derive(Debug, Clone)]
+pub(crate) struct NetConnector {
+  policy: Option<Arc<NetPolicy>>,
+  connect_timeout: Duration,
+}
+
+impl Service<Uri> for NetConnector {
+  type Response = TcpStream;
+  type Error = BoxError;
+  type Future = BoxFuture<'static, Result<TcpStream, BoxError>>;
+
+  fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), BoxError>> {
+    Poll::Ready(Ok(()))
+  }
+
+  fn call(&mut self, dst: Uri) -> Self::Future {
+    let this = self.clone();
+    Box::pin(async move {
+      let host = (dst.host())
+        .ok_or("missing host")?
+        .trim_matches(|c| c == '[' || c == ']');
+      let port = dst.port_u16().unwrap_or(match dst.scheme_str() {
+        Some("https") => 443,
+        _ => 80,
+      });
+      let addrs = match &this.policy {
+        Some(policy) => policy.resolve_all(host, port).await?,
+        None => lookup_host(host, port).await?,
+      };
+
+      let mut connector = HttpConnector::new_with_resolver(Resolved(addrs.into()));
+      connector.enforce_http(false);
+      connector.set_nodelay(true);
+      connector.set_connect_timeout(Some(this.connect_timeout));
+      connector.set_happy_eyeballs_timeout(Some(HAPPY_EYEBALLS_TIMEOUT));
+      Ok(connector.call(dst).await?)
+    })
+  }
+}
+
+/// Addresses already resolved and checked, handed to [`HttpConnector`] so
+/// that it connects to nothing else.
+#[derive(Debug, Clone)]
+struct Resolved(Arc<[SocketAddr]>);
+
+impl Service<Name> for Resolved {
+  type Response = std::vec::IntoIter<SocketAddr>;
+  type Error = Infallible;
+  type Future = Ready<Result<Self::Response, Infallible>>;
+
+  fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Infallible>> {
+    Poll::Ready(Ok(()))
+  }
+
+  fn call(&mut self, _name: Name) -> Self::Future {
+    ready(Ok(self.0.to_vec().into_iter()))
+  }
+}
+
+#[cfg(feature = "tls")]
+type NetClientConnector = hyper_tls::HttpsConnector<NetConnector>;
+#[cfg(not(feature = "tls"))]
+type NetClientConnector = NetConnector;
+
+/// Pooled HTTP client, with a separate pool for HTTP/2 with prior knowledge.
+#[derive(Debug, Clone)]
+pub(crate) struct PooledClient {
+  http1: Client<NetClientConnector>,
+  http2: Client<NetClientConnector>,
+}
+
+impl PooledClient {
+  pub fn new(options: &HttpClientOptions, policy: Option<Arc<NetPolicy>>) -> Self {
+    let connector = NetConnector {
+      policy,
+      connect_timeout: options.connect_timeout,
+    };
+    #[cfg(feature = "tls")]
+    let connector = hyper_tls::HttpsConnector::new_with_connector(connector);
+    let mut builder = Client::builder();
+    builder
+      .pool_max_idle_per_host(options.pool_max_idle_per_host)
+      .pool_idle_timeout(options.pool_idle_timeout);
+    Self {
+      http1: builder.build(connector.clone()),
+      http2: builder.http2_only(true).build(connector),
+    }
+  }
+
+  async fn request(
+    &self,
+    req: Request<Body>,
+    options: &RequestOptions,
+  ) -> Result<Response<Body>, BoxError> {
+    let client = if options.http2 {
+      &self.http2
+    } else {
+      &self.http1
+    };
+    match options.timeout {
+      Some(duration) => (timeout(duration, client.request(req)).await)
+        .map_err(|_| "request timed out")?
+        .map_err(Into::into),
+      None => client.request(req).await.map_err(Into::into),
+    }
+  }
+}
+
+/// Per-request options of the HTTP client.
+#[derive(Debug, Default)]
+pub(crate) struct RequestOptions {
+  /// Time limit of receiving the response head.
+  pub timeout: Option<Duration>,
+  /// Uses HTTP/2 with prior knowledge, e.g. for h2c upstreams.
+  pub http2: bool,
+}
+
+impl RequestOptions {
+  pub fn from_table<'lua>(lua: &'lua Lua, table: &Table<'lua>) -> mlua::Result<Self> {
+    let timeout = (table.check_raw_get::<Option<f64>>(lua, "timeout", "number")?)
+      .filter(|x| x.is_finite() && *x > 0.)
+      .map(Duration::from_secs_f64);
+    let http2 = (table.check_raw_get::<Option<bool>>(lua, "http2", "boolean")?).unwrap_or(false);
+    Ok(Self { timeout, http2 })
+  }
+}
+
+/// HTTP client used by services, restricted by the `net` permission if it
+/// lists allowed destinations.
+#[derive(Debug, Clone)]
+pub(crate) enum HttpClient {
+  /// The runtime's shared client.
+  Default,
+  Restricted(PooledClient),
+}
+
+impl HttpClient {
+  pub fn restricted(options: &HttpClientOptions, policy: Arc<NetPolicy>) -> Self {
+    Self::Restricted(PooledClient::new(options, Some(policy)))
+  }
+
+  pub async fn request(
+    &self,
+    lua: &Lua,
+    req: Request<Body>,
+    options: &RequestOptions,
+  ) -> Result<Response<Body>, BoxError> {
+    let options = &RequestOptions {
+      timeout: TaskContext::cap_timeout(lua, options.timeout),
+      http2: options.http2,
+    };
+    match self {
+      Self::Default => shared_client(lua).request(req, options).await,
+      Self::Restricted(client) => client.request(req, options).await,
+    }
+  }
+}
+
+/// Sets the client shared by services in `lua`.
+pub(crate) fn set_shared_client(lua: &Lua, options: &HttpClientOptions) {
+  lua.set_app_data(PooledClient::new(options, None));
+}
+
+fn shared_client(lua: &Lua) -> PooledClient {
+  if let Some(client) = lua.app_data_ref::<PooledClient>() {
+    return client.clone();
+  }
+  let client = PooledClient::new(&Default::default(), None);
+  lua.set_app_data(client.clone());
+  client
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::net::NetDenied;
+  use tokio::net::TcpListener;
+
+  fn connector(policy: Option<Arc<NetPolicy>>) -> NetConnector {
+    NetConnector {
+      policy,
+      connect_timeout: Duration::from_secs(1),
+    }
+  }
+
+  #[tokio::test]
+  async fn test_connect() -> Result<(), BoxError> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let port = listener.local_addr()?.port();
+    let uri: Uri = format!("http://localhost:{port}").parse()?;
+    let stream = connector(None).call(uri).await?;
+    assert_eq!(stream.peer_addr()?.port(), port);
+    assert!(stream.nodelay()?);
+    Ok(())
+  }
+
+  #[tokio::test]
+  async fn test_connect_policy() -> Result<(), BoxError> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let port = listener.local_addr()?.port();
+    let uri: Uri = format!("http://127.0.0.1:{port}").parse()?;
+
+    let policy = Arc::new(NetPolicy::new("test", vec!["127.0.0.1".parse()?]));
+    connector(Some(policy)).call(uri.clone()).await?;
+
+    let policy = Arc::new(NetPolicy::new("test", vec!["10.0.0.0/8".parse()?]));
+    let error = connector(Some(policy)).call(uri).await.unwrap_err();
+    assert!(error.downcast_ref::<NetDenied>().is_some());
+    Ok(())
+  }
+
+  #[tokio::test]
+  async fn test_connect_refused() -> Result<(), BoxError> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let port = listener.local_addr()?.port();
+    drop(listener);
+    let uri: Uri = format!("http://127.0.0.1:{port}").parse()?;
+    assert!(connector(None).call(uri).await.is_err());
+    Ok(())
+  }
+}
@@ -0,0 +1,130 @@
+use crate::lua::error::{arg_error, bad_field, check_value, tag_error, tag_handler, TableCheckExt};
+use crate::lua::LuaCacheExt;
+use hyper::StatusCode;
+use mlua::{Function, Lua, MultiValue, Table};
+
+/// Names of common error statuses, listed in `http.error_kinds`.
+const ERROR_KINDS: &[(&str, u16)] = &[
+  ("bad_request", 400),
+  ("unauthorized", 401),
+  ("forbidden", 403),
+  ("not_found", 404),
+  ("method_not_allowed", 405),
+  ("conflict", 409),
+  ("gone", 410),
+  ("payload_too_large", 413),
+  ("unprocessable_entity", 422),
+  ("too_many_requests", 429),
+  ("internal", 500),
+  ("not_implemented", 501),
+  ("bad_gateway", 502),
+  ("service_unavailable", 503),
+  ("gateway_timeout", 504),
+];
+
+fn kind_status(kind: &[u8]) -> Option<u16> {
+  (ERROR_KINDS.iter())
+    .find(|(name, _)| name.as_bytes() == kind)
+    .map(|(_, status)| *status)
+}
+
+fn status_kind(status: u16) -> Option<&'static str> {
+  (ERROR_KINDS.iter())
+    .find(|(_, x)| *x == status)
+    .map(|(name, _)| *name)
+}
+
+pub(super) fn create_table_http_error_kinds(lua: &Lua) -> mlua::Result<Table> {
+  lua.create_table_from(ERROR_KINDS.iter().copied())
+}
+
+fn error_metatable(lua: &Lua) -> mlua::Result<Table> {
+  lua.create_cached_value("abel:http_error_metatable", || {
+    let tostring = lua.create_function(|_lua, this: Table| {
+      let status = StatusCode::from_u16(this.raw_get("status")?).unwrap_or_default();
+      let message: mlua::String = this.raw_get("message")?;
+      Ok(format!("({status}) {}", message.to_string_lossy()))
+    })?;
+    lua.create_table_from([("__tostring", tostring)])
+  })
+}
+
+/// `http.error { status = ..., kind = ..., message = ..., detail = ... }`
+///
+/// Creates an error object to be raised with `error`, responding with
+/// `status` (or the status of `kind`, see `http.error_kinds`; 500 if neither
+/// is given) and a body containing `message` and `detail`.
+pub(super) fn create_fn_http_error(lua: &Lua) -> mlua::Result<Function> {
+  lua.create_cached_function("abel:http.error", |lua, mut args: MultiValue| {
+    let params: Table =
+      check_value(lua, args.pop_front(), "table").map_err(tag_handler(lua, 1, 0))?;
+
+    let kind_status = (params.check_raw_get::<Option<mlua::String>>(lua, "kind", "string")?)
+      .map(|kind| {
+        kind_status(kind.as_bytes()).ok_or_else(|| {
+          let msg = format!("unknown error kind '{}'", kind.to_string_lossy());
+          bad_field("kind", msg)
+        })
+      })
+      .transpose()?;
+    let status = params.check_raw_get::<Option<u16>>(lua, "status", "integer")?;
+    let status = match (status, kind_status) {
+      (Some(status), Some(kind_status)) if status != kind_status => {
+        return Err(bad_field("status", "conflicts with kind"));
+      }
+      (status, kind_status) => status.or(kind_status).unwrap_or(500),
+    };
+    let status = (StatusCode::from_u16(status).ok())
+      .filter(|x| x.is_client_error() || x.is_server_error())
+      .ok_or_else(|| bad_field("status", "error status (400-599) expected"))?;
+
+    let message = match params.check_raw_get::<Option<mlua::String>>(lua, "message", "string")? {
+      Some(message) => message,
+      None => {
+        let reason = status.canonical_reason().unwrap_or("error");
+        lua.create_string(&reason.to_ascii_lowercase())?
+      }
+    };
+    let detail: mlua::Value = params.raw_get("detail")?;
+
+    let error = lua.create_table()?;
+    error.raw_set("status", status.as_u16())?;
+    error.raw_set("kind", status_kind(status.as_u16()))?;
+    // `error` is read when raised, same as `error { status = ..., error = ... }`
+    error.raw_set("error", message.clone())?;
+    error.raw_set("message", message)?;
+    error.raw_set("detail", detail)?;
+    error.set_metatable(Some(error_metatable(lua)?));
+    Ok(error)
+  })
+}
+
+/// `http.is_error(value, expected?)`
+///
+/// Whether `value`, e.g. the error returned by `pcall`, is an HTTP error. If
+/// `expected` is given, also checks its status, or kind if it is a string.
+pub(super) fn create_fn_http_is_error(lua: &Lua) -> mlua::Result<Function> {
+  lua.create_cached_function("abel:http.is_error", |lua, mut args: MultiValue| {
+    let value = args.pop_front();
+    let expected = args.pop_front();
+    let status = match value {
+      Some(mlua::Value::Table(table)) => match table.raw_get("status")? {
+        mlua::Value::Integer(status) if (400..600).contains(&status) => status,
+        _ => return Ok(false),
+      },
+      _ => return Ok(false),
+    };
+    match expected {
+      None | Some(mlua::Value::Nil) => Ok(true),
+      Some(mlua::Value::Integer(expected)) => Ok(status == expected),
+      Some(mlua::Value::String(kind)) => match kind_status(kind.as_bytes()) {
+        Some(expected) => Ok(status == expected as i64),
+        None => {
+          let msg = format!("unknown error kind '{}'", kind.to_string_lossy());
+          Err(arg_error(lua, 2, &msg, 0))
+        }
+      },
+      Some(other) => Err(tag_error(lua, 2, "integer or string", other.type_name(), 0)),
+    }
+  })
+}
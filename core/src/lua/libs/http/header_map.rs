@@ -1,8 +1,9 @@
-use super::{header_name, header_name_convenient};
+use super::{header_name, header_name_convenient, header_value};
 use crate::lua::error::{arg_error, check_string, check_userdata, rt_error_fmt, tag_handler};
 use crate::lua::LuaCacheExt;
 use hyper::header::HeaderValue;
 use hyper::HeaderMap;
+use mlua::Value::Nil;
 use mlua::{AnyUserData, MultiValue, UserData, UserDataMethods, Variadic};
 use ouroboros::self_referencing;
 use std::cell::{RefCell, RefMut};
@@ -35,6 +36,26 @@ impl UserData for LuaHeaderMap {
         .transpose()
     });
 
+    // Assigning `nil` removes the header
+    methods.add_meta_method(
+      "__newindex",
+      |lua, this, (name, value): (mlua::Value, mlua::Value)| {
+        let name = check_string(lua, Some(name))
+          .map_err(|(_, got)| rt_error_fmt!("cannot index header map with {got}"))?;
+        let name = header_name_convenient(name)?;
+        let mut header_map = this.0.borrow_mut();
+        if let Nil = value {
+          header_map.remove(name);
+        } else {
+          let type_name = value.type_name();
+          let value = (lua.coerce_string(value)?)
+            .ok_or_else(|| rt_error_fmt!("expected string as header value, got {type_name}"))?;
+          header_map.insert(name, header_value(value)?);
+        }
+        Ok(())
+      },
+    );
+
     methods.add_meta_method("__pairs", |lua, this, ()| {
       let iter = LuaHeaderMapIterBuilder {
         inner: this.0.clone(),
@@ -1,19 +1,34 @@
+mod auth;
 mod body;
+mod connector;
+mod error;
 mod header_map;
+mod proxy;
+mod range;
 mod request;
 mod response;
 mod uri;
 
+pub(crate) use body::LuaBody;
+pub use connector::HttpClientOptions;
+pub(crate) use connector::{set_shared_client, HttpClient};
+pub(crate) use range::{apply_range, RangeRequest};
 pub use request::LuaRequest;
 pub use response::LuaResponse;
 pub(crate) use uri::LuaUri;
 
-use crate::lua::error::{arg_error, check_value, rt_error, rt_error_fmt, tag_error, tag_handler};
-use crate::lua::{LuaCacheExt, LuaEither, LUA_HTTP_CLIENT};
+use crate::lua::error::{
+  arg_error, check_value, net_error, rt_error, rt_error_fmt, tag_error, tag_handler,
+};
+use crate::lua::{LuaCacheExt, LuaEither};
+use auth::create_table_http_auth;
 use bstr::ByteSlice;
+use connector::RequestOptions;
+use error::{create_fn_http_error, create_fn_http_is_error, create_table_http_error_kinds};
 use hyper::header::{HeaderName, HeaderValue};
 use hyper::HeaderMap;
 use mlua::{AnyUserData, Function, Lua, MultiValue, Table};
+use proxy::{create_fn_http_proxy, http_proxy};
 use response::create_fn_http_create_response;
 use uri::create_fn_http_create_uri;
 
@@ -21,13 +36,58 @@ pub fn create_preload_http(lua: &Lua) -> mlua::Result<Function> {
   lua.create_cached_function("abel:preload_http", move |lua, ()| {
     let http = lua.create_table()?;
     http.raw_set("request", create_fn_http_request(lua)?)?;
+    http.raw_set("proxy", create_fn_http_proxy(lua)?)?;
     http.raw_set("Response", create_fn_http_create_response(lua)?)?;
     http.raw_set("Uri", create_fn_http_create_uri(lua)?)?;
+    http.raw_set("auth", create_table_http_auth(lua)?)?;
+    http.raw_set("error", create_fn_http_error(lua)?)?;
+    http.raw_set("error_kinds", create_table_http_error_kinds(lua)?)?;
+    http.raw_set("is_error", create_fn_http_is_error(lua)?)?;
     Ok(http)
   })
 }
 
+/// `http` module whose client only connects to destinations allowed by the
+/// `net` permission.
+pub(crate) fn create_preload_http_restricted(
+  client: HttpClient,
+) -> impl FnOnce(&Lua) -> mlua::Result<Function> {
+  |lua| {
+    lua.create_function(move |lua, ()| {
+      let http: Table = create_preload_http(lua)?.call(())?;
+      let client2 = client.clone();
+      let request =
+        lua.create_async_function(move |lua, args| http_request(lua, args, client2.clone()))?;
+      let client2 = client.clone();
+      let proxy =
+        lua.create_async_function(move |lua, args| http_proxy(lua, args, client2.clone()))?;
+      http.raw_set("request", request)?;
+      http.raw_set("proxy", proxy)?;
+      Ok(http)
+    })
+  }
+}
+
+/// `http.request(request, options)`
+///
+/// The response's body is a byte stream read as it arrives, so that large
+/// ones can be relayed, or saved with `body:save_to(file)`, without being
+/// held in memory.
+///
+/// Options:
+/// - `timeout`: seconds to wait for the response head
+/// - `http2`: uses HTTP/2 with prior knowledge
 pub fn create_fn_http_request(lua: &Lua) -> mlua::Result<Function> {
+  lua.create_cached_async_function("abel:http.request", |lua, args| {
+    http_request(lua, args, HttpClient::Default)
+  })
+}
+
+async fn http_request<'lua>(
+  lua: &'lua Lua,
+  mut args: MultiValue<'lua>,
+  client: HttpClient,
+) -> mlua::Result<LuaResponse> {
   fn check_request_first_arg(lua: &Lua, value: Option<mlua::Value>) -> mlua::Result<LuaRequest> {
     use LuaEither::*;
     type RequestMeta<'a> = LuaEither<LuaEither<mlua::String<'a>, Table<'a>>, AnyUserData<'a>>;
@@ -51,17 +111,18 @@ pub fn create_fn_http_request(lua: &Lua) -> mlua::Result<Function> {
     }
   }
 
-  lua.create_cached_async_function(
-    "abel:http.request",
-    move |lua, mut args: MultiValue| async move {
-      let req = check_request_first_arg(lua, args.pop_front())?;
-      LUA_HTTP_CLIENT
-        .request(req.into())
-        .await
-        .map(LuaResponse::from_hyper)
-        .map_err(rt_error)
-    },
-  )
+  let req = check_request_first_arg(lua, args.pop_front())?;
+  let options =
+    check_value::<Option<Table>>(lua, args.pop_front().or(Some(mlua::Value::Nil)), "table")
+      .map_err(tag_handler(lua, 2, 1))?
+      .map(|x| RequestOptions::from_table(lua, &x))
+      .transpose()?
+      .unwrap_or_default();
+  client
+    .request(lua, req.into(), &options)
+    .await
+    .map(LuaResponse::from_hyper)
+    .map_err(|error| net_error(lua, error))
 }
 
 fn check_headers(lua: &Lua, headers_table: Table) -> mlua::Result<HeaderMap> {
@@ -122,3 +183,37 @@ fn header_value(value: mlua::String) -> mlua::Result<HeaderValue> {
   HeaderValue::from_bytes(value)
     .map_err(|_| rt_error_fmt!("invalid header value: {:?}", value.as_bstr()))
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::lua::error::{resolve_callback_error, CustomError};
+  use crate::net::NetPolicy;
+  use std::sync::Arc;
+
+  #[tokio::test]
+  async fn test_net_denied() {
+    let lua = Lua::new();
+    let policy = Arc::new(NetPolicy::new("test", Vec::new()));
+    let client = HttpClient::restricted(&Default::default(), policy);
+    let http: Table = (create_preload_http_restricted(client)(&lua).unwrap())
+      .call(())
+      .unwrap();
+    let request: Function = http.raw_get("request").unwrap();
+
+    let uri = "http://127.0.0.1:1/";
+    let error = request
+      .call_async::<_, LuaResponse>(uri)
+      .await
+      .err()
+      .unwrap();
+    let error = match resolve_callback_error(&error) {
+      mlua::Error::ExternalError(error) => error.downcast_ref::<CustomError>().unwrap().clone(),
+      error => panic!("unexpected error: {error}"),
+    };
+    assert_eq!(error.status, 500);
+    assert_eq!(error.error, "net permission denied");
+    let detail = serde_json::json!({ "host": "127.0.0.1", "port": 1 });
+    assert_eq!(error.detail, detail);
+  }
+}
@@ -0,0 +1,338 @@
+use super::connector::{HttpClient, RequestOptions};
+use super::{check_headers, LuaRequest, LuaResponse, LuaUri};
+use crate::lua::error::{
+  arg_error, check_truthiness, check_value, net_error, rt_error, rt_error_fmt, tag_error,
+  tag_handler, TableCheckExt,
+};
+use crate::lua::LuaCacheExt;
+use crate::net::{remove_hop_by_hop, ClientAddr};
+use crate::service::{Tunnel, MAX_TUNNELS};
+use crate::task::TaskContext;
+use hyper::header::{HeaderValue, CONNECTION, HOST, UPGRADE};
+use hyper::http::uri::{Parts, PathAndQuery};
+use hyper::upgrade::OnUpgrade;
+use hyper::{HeaderMap, StatusCode, Uri};
+use log::{debug, warn};
+use mlua::{Function, Lua, MultiValue, Table};
+use std::future::pending;
+use std::time::Instant;
+use tokio::io::copy_bidirectional;
+
+/// Forwards an incoming request to `upstream`, streaming bodies both ways.
+///
+/// `upstream` is the full URI to forward to. The request's query is kept if
+/// `upstream` has none.
+///
+/// Requests to upgrade the connection, e.g. WebSocket handshakes, are passed
+/// on. If `upstream` switches protocols, both connections are bridged until
+/// either side closes, the request's deadline passes or the service stops. A
+/// service may have up to [`MAX_TUNNELS`] of them at a time.
+///
+/// Options:
+/// - `preserve_host`: keep the request's `Host` instead of the upstream's
+/// - `headers`: headers to set on the forwarded request, replacing existing
+///   ones
+/// - `timeout`, `http2`: same as `http.request`
+pub fn create_fn_http_proxy(lua: &Lua) -> mlua::Result<Function> {
+  lua.create_cached_async_function("abel:http.proxy", |lua, args| {
+    http_proxy(lua, args, HttpClient::Default)
+  })
+}
+
+pub(super) async fn http_proxy<'lua>(
+  lua: &'lua Lua,
+  mut args: MultiValue<'lua>,
+  client: HttpClient,
+) -> mlua::Result<LuaResponse> {
+  let mut req = match args.pop_front() {
+    Some(mlua::Value::UserData(u)) if u.is::<LuaRequest>() => LuaRequest::from_userdata(lua, u)?,
+    Some(mlua::Value::UserData(_)) => {
+      return Err(tag_error(lua, 1, "request", "other userdata", 1))
+    }
+    Some(value) => return Err(tag_error(lua, 1, "request", value.type_name(), 1)),
+    None => return Err(tag_error(lua, 1, "request", "no value", 1)),
+  };
+  let upstream = check_upstream(lua, args.pop_front())?;
+  let opts =
+    check_value::<Option<Table>>(lua, args.pop_front().or(Some(mlua::Value::Nil)), "table")
+      .map_err(tag_handler(lua, 3, 1))?;
+  let (preserve_host, extra_headers, options) = match opts {
+    Some(opts) => {
+      let preserve_host = check_truthiness(Some(opts.raw_get("preserve_host")?));
+      let headers = (opts.check_raw_get::<Option<Table>>(lua, "headers", "table")?)
+        .map(|x| check_headers(lua, x))
+        .transpose()?;
+      (
+        preserve_host,
+        headers,
+        RequestOptions::from_table(lua, &opts)?,
+      )
+    }
+    None => (false, None, Default::default()),
+  };
+
+  let client_addr = req.client_addr;
+  let on_upgrade = req.on_upgrade.take();
+  let mut req = hyper::Request::from(req);
+  let uri = upstream_uri(upstream, req.uri()).map_err(rt_error)?;
+  let original_uri = std::mem::replace(req.uri_mut(), uri);
+  let headers = req.headers_mut();
+  let protocol = upgrade_protocol(headers).filter(|_| on_upgrade.is_some());
+  let tunnel = match &protocol {
+    Some(_) => Some(open_tunnel(lua)?),
+    None => None,
+  };
+  remove_hop_by_hop(headers);
+  if let Some(protocol) = &protocol {
+    set_upgrade(headers, protocol.clone());
+  }
+
+  let original_host = (headers.get(HOST).cloned())
+    .or_else(|| (original_uri.authority()).and_then(|x| x.as_str().parse().ok()));
+  if !preserve_host {
+    let authority = req
+      .uri()
+      .authority()
+      .unwrap()
+      .as_str()
+      .parse()
+      .map_err(rt_error)?;
+    req.headers_mut().insert(HOST, authority);
+  }
+
+  let headers = req.headers_mut();
+  if let Some(addr) = client_addr.map(|x: ClientAddr| x.remote_addr.ip()) {
+    let forwarded_for = (headers.get_all(X_FORWARDED_FOR).iter())
+      .filter_map(|x| x.to_str().ok())
+      .chain([&*addr.to_string()])
+      .collect::<Vec<_>>()
+      .join(", ");
+    headers.insert(X_FORWARDED_FOR, forwarded_for.parse().map_err(rt_error)?);
+  }
+  if let Some(host) = original_host {
+    headers.entry(X_FORWARDED_HOST).or_insert(host);
+  }
+  let proto = original_uri.scheme_str().unwrap_or("http");
+  (headers.entry(X_FORWARDED_PROTO)).or_insert(HeaderValue::from_str(proto).map_err(rt_error)?);
+  if let Some(extra_headers) = extra_headers {
+    headers.extend(extra_headers);
+  }
+
+  let mut resp =
+    (client.request(lua, req, &options).await).map_err(|error| net_error(lua, error))?;
+  let switched = match (on_upgrade, tunnel) {
+    (Some(on_upgrade), Some(tunnel)) if resp.status() == StatusCode::SWITCHING_PROTOCOLS => {
+      let switched_to = upgrade_protocol(resp.headers()).or_else(|| protocol.clone());
+      let deadline = TaskContext::get_current(lua).and_then(|x| x.deadline.get());
+      let upstream = hyper::upgrade::on(&mut resp);
+      tokio::spawn(bridge(on_upgrade, upstream, tunnel, deadline));
+      switched_to
+    }
+    _ => None,
+  };
+  remove_hop_by_hop(resp.headers_mut());
+  if let Some(protocol) = switched {
+    set_upgrade(resp.headers_mut(), protocol);
+  }
+  Ok(LuaResponse::from_hyper(resp))
+}
+
+/// Takes a slot for an upgraded connection of the service handling the
+/// request.
+fn open_tunnel(lua: &Lua) -> mlua::Result<Tunnel> {
+  let tunnels = TaskContext::get_current(lua).and_then(|x| x.tunnels.borrow().clone());
+  let tunnels =
+    tunnels.ok_or_else(|| rt_error("connection upgrades can only be proxied by services"))?;
+  (tunnels.open()).ok_or_else(|| rt_error_fmt!("too many upgraded connections (max {MAX_TUNNELS})"))
+}
+
+/// Copies data between the client's and upstream's upgraded connections,
+/// until either side closes, `deadline` passes or `tunnel` is closed.
+async fn bridge(
+  downstream: OnUpgrade,
+  upstream: OnUpgrade,
+  tunnel: Tunnel,
+  deadline: Option<Instant>,
+) {
+  let copy = async {
+    let (mut downstream, mut upstream) = match tokio::try_join!(downstream, upstream) {
+      Ok(x) => x,
+      Err(error) => {
+        warn!("failed to upgrade proxied connection: {error}");
+        return;
+      }
+    };
+    if let Err(error) = copy_bidirectional(&mut downstream, &mut upstream).await {
+      debug!("proxied upgraded connection closed: {error}");
+    }
+  };
+  let expired = async {
+    match deadline {
+      Some(deadline) => tokio::time::sleep_until(deadline.into()).await,
+      None => pending().await,
+    }
+  };
+  tokio::select! {
+    _ = copy => {}
+    _ = tunnel.closed() => debug!("closed proxied upgraded connection of stopped service"),
+    _ = expired => debug!("closed proxied upgraded connection at request deadline"),
+  }
+}
+
+/// Protocol a request or response upgrades the connection to, if any.
+fn upgrade_protocol(headers: &HeaderMap) -> Option<HeaderValue> {
+  let upgrading = (headers.get_all(CONNECTION).iter())
+    .filter_map(|x| x.to_str().ok())
+    .flat_map(|x| x.split(','))
+    .any(|x| x.trim().eq_ignore_ascii_case("upgrade"));
+  headers.get(UPGRADE).filter(|_| upgrading).cloned()
+}
+
+fn set_upgrade(headers: &mut HeaderMap, protocol: HeaderValue) {
+  headers.insert(CONNECTION, HeaderValue::from_static("upgrade"));
+  headers.insert(UPGRADE, protocol);
+}
+
+const X_FORWARDED_FOR: &str = "x-forwarded-for";
+const X_FORWARDED_HOST: &str = "x-forwarded-host";
+const X_FORWARDED_PROTO: &str = "x-forwarded-proto";
+
+fn check_upstream(lua: &Lua, value: Option<mlua::Value>) -> mlua::Result<Uri> {
+  let uri = match value {
+    Some(mlua::Value::String(s)) => {
+      Uri::try_from(s.as_bytes()).map_err(|error| arg_error(lua, 2, &error.to_string(), 1))?
+    }
+    Some(mlua::Value::UserData(u)) if u.is::<LuaUri>() => u.borrow::<LuaUri>()?.0.clone(),
+    Some(value) => return Err(tag_error(lua, 2, "URI", value.type_name(), 1)),
+    None => return Err(tag_error(lua, 2, "URI", "no value", 1)),
+  };
+  if uri.scheme().is_none() || uri.authority().is_none() {
+    return Err(arg_error(lua, 2, "absolute URI expected", 1));
+  }
+  Ok(uri)
+}
+
+/// Keeps the query of `original` if `upstream` has none.
+fn upstream_uri(upstream: Uri, original: &Uri) -> Result<Uri, hyper::http::Error> {
+  let query = match (upstream.query(), original.query()) {
+    (None, Some(query)) => query,
+    _ => return Ok(upstream),
+  };
+  let mut parts = Parts::from(upstream);
+  let path = (parts.path_and_query.as_ref())
+    .map(|x| x.path())
+    .unwrap_or("/");
+  parts.path_and_query = Some(PathAndQuery::try_from(format!("{path}?{query}"))?);
+  Ok(Uri::from_parts(parts)?)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::service::Tunnels;
+  use hyper::header::HeaderName;
+  use hyper::server::conn::Http;
+  use hyper::service::service_fn;
+  use hyper::upgrade::Upgraded;
+  use hyper::{Body, Client, Request, Response};
+  use std::sync::Arc;
+  use std::time::Duration;
+  use test_case::test_case;
+  use tokio::io::{AsyncReadExt, AsyncWriteExt};
+  use tokio::net::TcpListener;
+  use tokio::sync::oneshot;
+  use tokio::time::timeout;
+
+  #[test_case("http://up/api", "/svc/x?a=1" => "http://up/api?a=1"; "keeps query")]
+  #[test_case("http://up/api?b=2", "/svc/x?a=1" => "http://up/api?b=2"; "upstream query wins")]
+  #[test_case("http://up", "/svc?a=1" => "http://up/?a=1"; "no upstream path")]
+  #[test_case("http://up/api", "/svc" => "http://up/api"; "no query")]
+  fn test_upstream_uri(upstream: &str, original: &str) -> String {
+    let uri = upstream_uri(upstream.parse().unwrap(), &original.parse().unwrap());
+    uri.unwrap().to_string()
+  }
+
+  #[test_case(&[("connection", "keep-alive, Upgrade"), ("upgrade", "websocket")] => Some("websocket".into()); "upgrade")]
+  #[test_case(&[("upgrade", "websocket")] => None; "not listed in connection")]
+  #[test_case(&[("connection", "upgrade")] => None; "no protocol")]
+  fn test_upgrade_protocol(headers: &[(&'static str, &'static str)]) -> Option<String> {
+    let headers = (headers.iter())
+      .map(|&(k, v)| (HeaderName::from_static(k), HeaderValue::from_static(v)))
+      .collect();
+    upgrade_protocol(&headers).map(|x| x.to_str().unwrap().into())
+  }
+
+  /// Opens a local connection upgraded to a test protocol, returning the
+  /// server's and the client's side of it.
+  async fn upgrade() -> (OnUpgrade, OnUpgrade) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (tx, rx) = oneshot::channel();
+    tokio::spawn(async move {
+      let (stream, _) = listener.accept().await.unwrap();
+      let mut tx = Some(tx);
+      let service = service_fn(move |mut req: Request<Body>| {
+        if let Some(tx) = tx.take() {
+          let _ = tx.send(hyper::upgrade::on(&mut req));
+        }
+        async {
+          Response::builder()
+            .status(StatusCode::SWITCHING_PROTOCOLS)
+            .header(CONNECTION, "upgrade")
+            .header(UPGRADE, "test")
+            .body(Body::empty())
+        }
+      });
+      let _ = (Http::new().serve_connection(stream, service))
+        .with_upgrades()
+        .await;
+    });
+    let req = Request::get(format!("http://{addr}/"))
+      .header(CONNECTION, "upgrade")
+      .header(UPGRADE, "test")
+      .body(Body::empty())
+      .unwrap();
+    let resp = Client::new().request(req).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::SWITCHING_PROTOCOLS);
+    (rx.await.unwrap(), hyper::upgrade::on(resp))
+  }
+
+  /// Connects a client to an upstream server through [`bridge`].
+  async fn bridged(tunnel: Tunnel, deadline: Option<Instant>) -> (Upgraded, Upgraded) {
+    let (downstream, client) = upgrade().await;
+    let (server, upstream) = upgrade().await;
+    tokio::spawn(bridge(downstream, upstream, tunnel, deadline));
+    (client.await.unwrap(), server.await.unwrap())
+  }
+
+  #[tokio::test]
+  async fn test_bridge() {
+    let tunnels = Arc::new(Tunnels::default());
+    let (mut client, mut server) = bridged(tunnels.open().unwrap(), None).await;
+    let mut buf = [0; 4];
+    client.write_all(b"ping").await.unwrap();
+    server.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"ping");
+    server.write_all(b"pong").await.unwrap();
+    client.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"pong");
+
+    // Both sides are closed when the service stops
+    tunnels.close_all();
+    let read = timeout(Duration::from_secs(1), client.read(&mut buf)).await;
+    assert_eq!(read.unwrap().unwrap(), 0);
+    let read = timeout(Duration::from_secs(1), server.read(&mut buf)).await;
+    assert_eq!(read.unwrap().unwrap(), 0);
+  }
+
+  #[tokio::test]
+  async fn test_bridge_deadline() {
+    let tunnels = Arc::new(Tunnels::default());
+    let deadline = Instant::now() + Duration::from_millis(50);
+    let (mut client, _server) = bridged(tunnels.open().unwrap(), Some(deadline)).await;
+    let mut buf = [0; 4];
+    let read = timeout(Duration::from_secs(1), client.read(&mut buf)).await;
+    assert_eq!(read.unwrap().unwrap(), 0);
+    assert!(Instant::now() >= deadline);
+  }
+}
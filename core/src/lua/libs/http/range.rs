@@ -0,0 +1,165 @@
+use super::{LuaBody, LuaResponse};
+use crate::lua::stream::ByteStream;
+use hyper::header::{
+  HeaderValue, ACCEPT_RANGES, CONTENT_LENGTH, CONTENT_RANGE, ETAG, IF_RANGE, LAST_MODIFIED, RANGE,
+};
+use hyper::{Body, Method, Request, StatusCode};
+use std::io::SeekFrom;
+use tokio::io::{self, AsyncReadExt, AsyncSeekExt};
+
+/// `Range` and `If-Range` headers of a `GET` request, kept until its response
+/// is known.
+#[derive(Debug, Default)]
+pub struct RangeRequest {
+  range: Option<HeaderValue>,
+  if_range: Option<HeaderValue>,
+}
+
+impl RangeRequest {
+  pub fn new(req: &Request<Body>) -> Self {
+    if req.method() != Method::GET {
+      return Self::default();
+    }
+    Self {
+      range: req.headers().get(RANGE).cloned(),
+      if_range: req.headers().get(IF_RANGE).cloned(),
+    }
+  }
+
+  /// Whether `If-Range`, if any, still matches the response's validators.
+  fn is_fresh(&self, resp: &LuaResponse) -> bool {
+    let if_range = match &self.if_range {
+      Some(x) => x,
+      None => return true,
+    };
+    let headers = resp.headers.borrow();
+    if if_range.as_bytes().starts_with(b"W/") {
+      // Weak validators never match
+      false
+    } else if if_range.as_bytes().starts_with(b"\"") {
+      headers.get(ETAG) == Some(if_range)
+    } else {
+      headers.get(LAST_MODIFIED) == Some(if_range)
+    }
+  }
+}
+
+/// Parses a single byte range against a resource of `size` bytes, returning
+/// the inclusive start and end.
+///
+/// `None` means the header is ignored, e.g. for other units or multiple
+/// ranges; `Some(Err(()))` means it is not satisfiable.
+fn parse_range(header: &[u8], size: u64) -> Option<Result<(u64, u64), ()>> {
+  let spec = std::str::from_utf8(header).ok()?.trim();
+  let spec = spec.strip_prefix("bytes=")?.trim();
+  if spec.contains(',') {
+    return None;
+  }
+  let (start, end) = spec.split_once('-')?;
+  let (start, end) = (start.trim(), end.trim());
+  let range = if start.is_empty() {
+    // Suffix range: the last `end` bytes
+    let len = end.parse::<u64>().ok()?;
+    if len == 0 || size == 0 {
+      return Some(Err(()));
+    }
+    (size.saturating_sub(len), size - 1)
+  } else {
+    let start = start.parse::<u64>().ok()?;
+    let end = if end.is_empty() {
+      u64::MAX
+    } else {
+      end.parse::<u64>().ok()?
+    };
+    if end < start {
+      return None;
+    }
+    if start >= size {
+      return Some(Err(()));
+    }
+    (start, end.min(size - 1))
+  };
+  Some(Ok(range))
+}
+
+/// Serves file bodies of successful responses partially if requested, and
+/// advertises range support otherwise.
+///
+/// The resource is the rest of the file from its current position.
+pub(crate) async fn apply_range(resp: &mut LuaResponse, req: &RangeRequest) -> io::Result<()> {
+  if resp.status != StatusCode::OK {
+    return Ok(());
+  }
+  let mut file = match resp.body.take() {
+    Some(LuaBody::File(file)) => file,
+    body => {
+      resp.body = body;
+      return Ok(());
+    }
+  };
+  let offset = file.seek(SeekFrom::Current(0)).await?;
+  let size = file.get_mut().len().await?.saturating_sub(offset);
+  // `len` may move the underlying file
+  file.seek(SeekFrom::Start(offset)).await?;
+
+  let range = (req.range.as_ref())
+    .filter(|_| req.is_fresh(resp))
+    .and_then(|x| parse_range(x.as_bytes(), size));
+  if let Some(Ok((start, _))) = range {
+    file.seek(SeekFrom::Start(offset + start)).await?;
+  }
+  let mut headers = resp.headers.borrow_mut();
+  headers.insert(ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+  match range {
+    None => {
+      headers.entry(CONTENT_LENGTH).or_insert_with(|| size.into());
+      drop(headers);
+      resp.body = Some(LuaBody::File(file));
+    }
+    Some(Err(())) => {
+      resp.status = StatusCode::RANGE_NOT_SATISFIABLE;
+      let content_range = format!("bytes */{size}");
+      headers.insert(
+        CONTENT_RANGE,
+        HeaderValue::from_str(&content_range).unwrap(),
+      );
+      headers.remove(CONTENT_LENGTH);
+      drop(headers);
+      resp.body = Some(LuaBody::Empty);
+    }
+    Some(Ok((start, end))) => {
+      let len = end - start + 1;
+      resp.status = StatusCode::PARTIAL_CONTENT;
+      let content_range = format!("bytes {start}-{end}/{size}");
+      headers.insert(
+        CONTENT_RANGE,
+        HeaderValue::from_str(&content_range).unwrap(),
+      );
+      headers.insert(CONTENT_LENGTH, len.into());
+      drop(headers);
+      let stream = ByteStream::from_async_read(file.take(len));
+      resp.body = Some(LuaBody::Stream(Body::wrap_stream(stream.0)));
+    }
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use test_case::test_case;
+
+  #[test_case("bytes=0-99" => Some(Ok((0, 99))); "start and end")]
+  #[test_case("bytes=100-" => Some(Ok((100, 999))); "open end")]
+  #[test_case("bytes=900-2000" => Some(Ok((900, 999))); "end past size")]
+  #[test_case("bytes=-100" => Some(Ok((900, 999))); "suffix")]
+  #[test_case("bytes=-2000" => Some(Ok((0, 999))); "suffix past size")]
+  #[test_case("bytes=1000-" => Some(Err(())); "start past size")]
+  #[test_case("bytes=-0" => Some(Err(())); "empty suffix")]
+  #[test_case("bytes=0-1,5-6" => None; "multiple ranges")]
+  #[test_case("bytes=5-1" => None; "reversed")]
+  #[test_case("items=0-1" => None; "other unit")]
+  fn test_parse_range(header: &str) -> Option<Result<(u64, u64), ()>> {
+    parse_range(header.as_bytes(), 1000)
+  }
+}
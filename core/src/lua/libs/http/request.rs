@@ -3,9 +3,11 @@ use super::header_map::LuaHeaderMap;
 use super::uri::LuaUri;
 use crate::lua::error::{bad_field, rt_error_fmt, TableCheckExt};
 use crate::lua::http::check_headers;
+use crate::net::ClientAddr;
 use crate::path::Params;
 use crate::task::close_value;
 use hyper::http::request::Parts;
+use hyper::upgrade::OnUpgrade;
 use hyper::{Body, HeaderMap, Method, Request, Uri};
 use mlua::{AnyUserData, Lua, Table, UserData};
 use std::cell::RefCell;
@@ -19,16 +21,22 @@ pub struct LuaRequest {
   pub(crate) body: Option<LuaBody>,
   /// Only used in Abel core
   pub(crate) params: Option<Params>,
+  /// Only set for incoming requests
+  pub(crate) client_addr: Option<ClientAddr>,
+  /// The client's connection once upgraded, if the request asks for it
+  pub(crate) on_upgrade: Option<OnUpgrade>,
 }
 
 impl LuaRequest {
   #[rustfmt::skip]
   pub fn new(req: Request<Body>, params: Params) -> Self {
-    let (Parts { method, uri, headers, .. }, body) = req.into_parts();
+    let (Parts { method, uri, headers, mut extensions, .. }, body) = req.into_parts();
     let headers = Rc::new(RefCell::new(headers));
     let body = Some(body.into());
     let params = Some(params);
-    Self { method, uri, headers, body, params }
+    let client_addr = extensions.get::<ClientAddr>().copied();
+    let on_upgrade = extensions.remove::<OnUpgrade>();
+    Self { method, uri, headers, body, params, client_addr, on_upgrade }
   }
 
   pub fn from_table<'lua>(lua: &'lua Lua, table: Table<'lua>) -> mlua::Result<LuaRequest> {
@@ -86,6 +94,8 @@ impl Default for LuaRequest {
       headers: Default::default(),
       body: Some(LuaBody::Empty),
       params: None,
+      client_addr: None,
+      on_upgrade: None,
     }
   }
 }
@@ -130,6 +140,18 @@ impl UserData for LuaRequest {
     fields.add_field_method_get("headers", |_lua, this| {
       Ok(LuaHeaderMap(this.headers.clone()))
     });
+
+    // Set by `session.wrap`
+    fields.add_field_function_get("session", |_lua, this| {
+      this.get_named_user_value::<_, mlua::Value>("session")
+    });
+
+    fields.add_field_method_get("remote_addr", |lua, this| {
+      lua.pack(this.client_addr.map(|x| x.remote_addr.to_string()))
+    });
+    fields.add_field_method_get("real_ip", |lua, this| {
+      lua.pack(this.client_addr.map(|x| x.real_ip.to_string()))
+    });
   }
 
   fn add_methods<'lua, M: mlua::UserDataMethods<'lua, Self>>(methods: &mut M) {
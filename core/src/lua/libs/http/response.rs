@@ -3,17 +3,21 @@ use super::check_headers;
 use super::header_map::LuaHeaderMap;
 use crate::lua::error::{bad_field, check_value, rt_error_fmt, tag_handler, TableCheckExt};
 use crate::lua::LuaCacheExt;
+use crate::net::CacheTtl;
 use hyper::http::{HeaderMap, StatusCode};
 use hyper::{Body, Response};
 use mlua::{FromLua, Function, Lua, MultiValue, Table, UserData, UserDataFields};
 use std::cell::RefCell;
 use std::rc::Rc;
+use std::time::Duration;
 
 #[derive(Default)]
 pub struct LuaResponse {
   pub status: StatusCode,
   pub headers: Rc<RefCell<HeaderMap>>,
   pub body: Option<LuaBody>,
+  /// Seconds the response may be cached by the server.
+  pub cache_ttl: Option<u64>,
 }
 
 impl LuaResponse {
@@ -23,6 +27,7 @@ impl LuaResponse {
       status: parts.status,
       headers: Rc::new(RefCell::new(parts.headers)),
       body: Some(body.into()),
+      cache_ttl: None,
     }
   }
 }
@@ -43,7 +48,8 @@ impl UserData for LuaResponse {
     });
     fields.add_field_method_get("headers", |_lua, this| {
       Ok(LuaHeaderMap(this.headers.clone()))
-    })
+    });
+    fields.add_field_method_get("cache_ttl", |_lua, this| Ok(this.cache_ttl));
   }
 }
 
@@ -89,6 +95,9 @@ impl From<LuaResponse> for Response<Body> {
 
     let mut builder = Response::builder().status(x.status);
     *builder.headers_mut().unwrap() = headers;
+    if let Some(ttl) = x.cache_ttl {
+      builder = builder.extension(CacheTtl(Duration::from_secs(ttl)));
+    }
     builder.body(x.body.unwrap().into()).unwrap()
   }
 }
@@ -113,6 +122,8 @@ pub fn create_fn_http_create_response(lua: &Lua) -> mlua::Result<Function> {
       response.headers.borrow_mut().extend(check_headers(lua, t)?)
     }
 
+    response.cache_ttl = params.check_raw_get(lua, "cache_ttl", "non-negative integer")?;
+
     Ok(response)
   })
 }
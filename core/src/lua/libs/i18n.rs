@@ -0,0 +1,440 @@
+use super::http::LuaRequest;
+use crate::lua::error::{
+  check_string, check_userdata, check_value, rt_error, rt_error_fmt, tag_error, tag_handler,
+  TableCheckExt,
+};
+use crate::source::Source;
+use hyper::header::ACCEPT_LANGUAGE;
+use mlua::Value::Nil;
+use mlua::{Function, Lua, MultiValue, Table, UserData, UserDataMethods};
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap};
+use std::rc::Rc;
+
+/// Maximum depth of message references when formatting.
+const MAX_DEPTH: usize = 16;
+
+pub fn create_preload_i18n(source: Source) -> impl FnOnce(&Lua) -> mlua::Result<Function> {
+  |lua| {
+    lua.create_function(move |lua, ()| {
+      let i18n = lua.create_table()?;
+      i18n.raw_set("load", create_fn_i18n_load(lua, source.clone())?)?;
+      Ok(i18n)
+    })
+  }
+}
+
+/// Loads every `<locale>.ftl` in a directory of the service source
+/// (`locales` by default) into a catalog.
+///
+/// Options:
+/// - `fallback`: locale used when none of the requested ones has a message
+fn create_fn_i18n_load(lua: &Lua, source: Source) -> mlua::Result<Function> {
+  lua.create_async_function(move |lua, mut args: MultiValue| {
+    let source = source.clone();
+    async move {
+      let dir = match args.pop_front() {
+        None | Some(Nil) => "locales".into(),
+        x => {
+          let dir = check_string(lua, x).map_err(tag_handler(lua, 1, 1))?;
+          dir.to_str()?.trim_matches('/').to_owned()
+        }
+      };
+      let opts = check_value::<Option<Table>>(lua, args.pop_front().or(Some(Nil)), "table")
+        .map_err(tag_handler(lua, 2, 1))?;
+      let fallback = (opts.map(|x| x.check_raw_get::<Option<String>>(lua, "fallback", "string")))
+        .transpose()?
+        .flatten();
+
+      let mut locales = BTreeMap::new();
+      for name in source.read_dir(&dir).await.map_err(rt_error)? {
+        let locale = match name.strip_suffix(".ftl") {
+          Some(locale) if !locale.is_empty() => locale,
+          _ => continue,
+        };
+        let path = format!("{dir}/{name}");
+        let bytes = source.get_bytes(&path).await.map_err(rt_error)?;
+        let text =
+          std::str::from_utf8(&bytes).map_err(|_| rt_error_fmt!("{path}: invalid UTF-8"))?;
+        let bundle = parse_ftl(text).map_err(|error| rt_error_fmt!("{path}:{error}"))?;
+        locales.insert(locale.to_owned(), bundle);
+      }
+      if let Some(fallback) = &fallback {
+        if !locales.contains_key(fallback) {
+          return Err(rt_error_fmt!("fallback locale '{fallback}' not found"));
+        }
+      }
+      Ok(LuaCatalog(Rc::new(Catalog { locales, fallback })))
+    }
+  })
+}
+
+#[derive(Debug, PartialEq)]
+enum Element {
+  Text(String),
+  Variable(String),
+  Reference(String),
+}
+
+type Pattern = Vec<Element>;
+
+/// Messages of a locale. Terms are stored with their leading `-`, and
+/// attributes as `message.attribute`.
+type Bundle = HashMap<String, Pattern>;
+
+struct Catalog {
+  locales: BTreeMap<String, Bundle>,
+  fallback: Option<String>,
+}
+
+impl Catalog {
+  /// Picks available locales in order of preference of an `Accept-Language`
+  /// value, followed by the fallback locale.
+  ///
+  /// Exact matches come first, then locales of the same language, e.g. `en`
+  /// and `en-GB` for `en-US`.
+  fn negotiate(&self, accept_language: &str) -> Vec<&str> {
+    let mut requested = (accept_language.split(','))
+      .filter_map(|x| {
+        let mut parts = x.split(';');
+        let tag = parts.next()?.trim();
+        let q = parts
+          .find_map(|x| x.trim().strip_prefix("q="))
+          .map(|x| x.parse::<f32>().unwrap_or(0.))
+          .unwrap_or(1.);
+        (!tag.is_empty() && tag != "*" && q > 0.).then(|| (tag, q))
+      })
+      .collect::<Vec<_>>();
+    requested.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(Ordering::Equal));
+
+    fn language(tag: &str) -> &str {
+      tag.split('-').next().unwrap_or(tag)
+    }
+
+    let mut result = Vec::new();
+    for (tag, _) in requested {
+      let exact = (self.locales.keys()).filter(|x| x.eq_ignore_ascii_case(tag));
+      let same_language =
+        (self.locales.keys()).filter(|x| language(x).eq_ignore_ascii_case(language(tag)));
+      for x in exact.chain(same_language) {
+        if !result.contains(&&**x) {
+          result.push(&**x);
+        }
+      }
+    }
+    if let Some(fallback) = &self.fallback {
+      if !result.contains(&&**fallback) {
+        result.push(fallback);
+      }
+    }
+    result
+  }
+
+  /// Formats a message using the first locale that has it.
+  fn format(&self, locales: &[&str], key: &str, args: &HashMap<String, String>) -> Option<String> {
+    let bundle = (locales.iter())
+      .filter_map(|x| self.locales.get(*x))
+      .find(|x| x.contains_key(key))?;
+    let mut result = String::new();
+    format_pattern(bundle, &bundle[key], args, 0, &mut result);
+    Some(result)
+  }
+}
+
+fn format_pattern(
+  bundle: &Bundle,
+  pattern: &Pattern,
+  args: &HashMap<String, String>,
+  depth: usize,
+  result: &mut String,
+) {
+  for element in pattern {
+    match element {
+      Element::Text(x) => result.push_str(x),
+      Element::Variable(x) => match args.get(x) {
+        Some(value) => result.push_str(value),
+        None => {
+          result.push_str("{$");
+          result.push_str(x);
+          result.push('}');
+        }
+      },
+      Element::Reference(x) => match bundle.get(x) {
+        Some(pattern) if depth < MAX_DEPTH => {
+          format_pattern(bundle, pattern, args, depth + 1, result)
+        }
+        _ => {
+          result.push('{');
+          result.push_str(x);
+          result.push('}');
+        }
+      },
+    }
+  }
+}
+
+fn is_identifier(s: &str) -> bool {
+  let mut chars = s.chars();
+  (chars.next()).map_or(false, |x| x.is_ascii_alphabetic())
+    && chars.all(|x| x.is_ascii_alphanumeric() || x == '_' || x == '-')
+}
+
+/// Parses a subset of Fluent: messages, terms, attributes, multiline values
+/// and placeables of variables, message or term references and string
+/// literals. Selectors and functions are not supported.
+fn parse_ftl(text: &str) -> Result<Bundle, String> {
+  let mut bundle = Bundle::new();
+  // (line number, key, value)
+  let mut current: Option<(usize, String, String)> = None;
+  let mut parent = String::new();
+
+  let mut finish = |current: &mut Option<(usize, String, String)>| {
+    if let Some((lineno, key, value)) = current.take() {
+      let pattern = parse_pattern(&value).map_err(|error| format!("{lineno}: {error}"))?;
+      bundle.insert(key, pattern);
+    }
+    Ok::<_, String>(())
+  };
+
+  for (i, line) in text.lines().enumerate() {
+    let lineno = i + 1;
+    let trimmed = line.trim();
+    if trimmed.is_empty() {
+      continue;
+    }
+    if line.starts_with(char::is_whitespace) && !parent.is_empty() {
+      if let Some(attr) = trimmed.strip_prefix('.') {
+        finish(&mut current)?;
+        let (name, value) =
+          (attr.split_once('=')).ok_or_else(|| format!("{lineno}: expected attribute"))?;
+        let name = name.trim();
+        if !is_identifier(name) {
+          return Err(format!("{lineno}: invalid attribute name '{name}'"));
+        }
+        current = Some((lineno, format!("{parent}.{name}"), value.trim().into()));
+      } else if let Some((_, _, value)) = &mut current {
+        if !value.is_empty() {
+          value.push('\n');
+        }
+        value.push_str(trimmed);
+      }
+      continue;
+    }
+
+    finish(&mut current)?;
+    parent.clear();
+    if line.starts_with('#') {
+      continue;
+    }
+    let (key, value) =
+      (line.split_once('=')).ok_or_else(|| format!("{lineno}: expected message"))?;
+    let key = key.trim();
+    if !is_identifier(key.strip_prefix('-').unwrap_or(key)) {
+      return Err(format!("{lineno}: invalid message identifier '{key}'"));
+    }
+    parent.push_str(key);
+    current = Some((lineno, key.into(), value.trim().into()));
+  }
+  finish(&mut current)?;
+  Ok(bundle)
+}
+
+fn parse_pattern(mut s: &str) -> Result<Pattern, String> {
+  let mut pattern = Vec::new();
+  loop {
+    let i = match s.find(|c| c == '{' || c == '}') {
+      Some(i) if s.as_bytes()[i] == b'}' => return Err("unbalanced '}'".into()),
+      Some(i) => i,
+      None => {
+        if !s.is_empty() {
+          pattern.push(Element::Text(s.into()));
+        }
+        return Ok(pattern);
+      }
+    };
+    if i > 0 {
+      pattern.push(Element::Text(s[..i].into()));
+    }
+    let rest = s[i + 1..].trim_start();
+
+    if let Some(literal) = rest.strip_prefix('"') {
+      let end = literal.find('"').ok_or("unclosed string literal")?;
+      pattern.push(Element::Text(literal[..end].into()));
+      s = (literal[end + 1..].trim_start())
+        .strip_prefix('}')
+        .ok_or("unclosed placeable")?;
+      continue;
+    }
+
+    let end = rest.find('}').ok_or("unclosed placeable")?;
+    let expr = rest[..end].trim();
+    let element = if let Some(x) = expr.strip_prefix('$') {
+      is_identifier(x).then(|| Element::Variable(x.into()))
+    } else {
+      let (id, attr) = match expr.split_once('.') {
+        Some((id, attr)) => (id, Some(attr)),
+        None => (expr, None),
+      };
+      let valid_id = is_identifier(id.strip_prefix('-').unwrap_or(id));
+      (valid_id && attr.map_or(true, is_identifier)).then(|| Element::Reference(expr.into()))
+    };
+    pattern.push(element.ok_or_else(|| format!("unsupported expression '{expr}'"))?);
+    s = &rest[end + 1..];
+  }
+}
+
+fn check_args(
+  lua: &Lua,
+  value: Option<mlua::Value>,
+  pos: usize,
+) -> mlua::Result<HashMap<String, String>> {
+  let table = check_value::<Option<Table>>(lua, value.or(Some(Nil)), "table")
+    .map_err(tag_handler(lua, pos, 1))?;
+  let mut args = HashMap::new();
+  if let Some(table) = table {
+    for kv in table.pairs::<mlua::String, mlua::Value>() {
+      let (k, v) = kv?;
+      let type_name = v.type_name();
+      let v = (lua.coerce_string(v)?).ok_or_else(|| {
+        rt_error_fmt!("invalid argument '{}' (a {type_name})", k.to_string_lossy())
+      })?;
+      args.insert(k.to_str()?.into(), v.to_str()?.into());
+    }
+  }
+  Ok(args)
+}
+
+/// Accepts either a request, whose `Accept-Language` header is used, or the
+/// header value itself.
+fn check_accept_language(
+  lua: &Lua,
+  value: Option<mlua::Value>,
+  pos: usize,
+) -> mlua::Result<String> {
+  match value {
+    Some(mlua::Value::UserData(u)) if u.is::<LuaRequest>() => {
+      let req = u.borrow::<LuaRequest>()?;
+      let headers = req.headers.borrow();
+      let value = headers.get(ACCEPT_LANGUAGE).and_then(|x| x.to_str().ok());
+      Ok(value.unwrap_or_default().into())
+    }
+    None | Some(Nil) => Ok(String::new()),
+    Some(mlua::Value::String(s)) => Ok(s.to_str()?.into()),
+    Some(x) => Err(tag_error(lua, pos, "request or string", x.type_name(), 1)),
+  }
+}
+
+/// Loaded message catalog.
+///
+/// `negotiate(req)` returns the locales to use, most preferred first.
+/// `translator(req)` returns a function `t(key, args)`, which formats a
+/// message in those locales, or returns `key` itself if none has it.
+pub struct LuaCatalog(Rc<Catalog>);
+
+impl UserData for LuaCatalog {
+  fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+    methods.add_function("locales", |lua, mut args: MultiValue| {
+      let this =
+        check_userdata::<Self>(args.pop_front(), "catalog").map_err(tag_handler(lua, 1, 1))?;
+      let locales = this.with_borrowed(|x| x.0.locales.keys().cloned().collect::<Vec<_>>());
+      lua.create_sequence_from(locales)
+    });
+
+    methods.add_function("negotiate", |lua, mut args: MultiValue| {
+      let this =
+        check_userdata::<Self>(args.pop_front(), "catalog").map_err(tag_handler(lua, 1, 1))?;
+      let accept_language = check_accept_language(lua, args.pop_front(), 2)?;
+      let catalog = this.with_borrowed(|x| x.0.clone());
+      let locales = catalog.negotiate(&accept_language);
+      lua.create_sequence_from(locales)
+    });
+
+    methods.add_function("translator", |lua, mut args: MultiValue| {
+      let this =
+        check_userdata::<Self>(args.pop_front(), "catalog").map_err(tag_handler(lua, 1, 1))?;
+      let accept_language = check_accept_language(lua, args.pop_front(), 2)?;
+      let catalog = this.with_borrowed(|x| x.0.clone());
+      let locales = (catalog.negotiate(&accept_language).into_iter())
+        .map(String::from)
+        .collect::<Vec<_>>();
+      lua.create_function(move |lua, mut args: MultiValue| {
+        let key = check_string(lua, args.pop_front()).map_err(tag_handler(lua, 1, 1))?;
+        let key = key.to_str()?;
+        let fmt_args = check_args(lua, args.pop_front(), 2)?;
+        let locales = locales.iter().map(|x| &**x).collect::<Vec<_>>();
+        Ok(
+          catalog
+            .format(&locales, key, &fmt_args)
+            .unwrap_or_else(|| key.into()),
+        )
+      })
+    });
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use test_case::test_case;
+
+  const FTL: &str = r#"
+# Comment
+-brand = Abel
+hello = Hello, { $name }!
+about = About { -brand }
+multiline =
+    First line
+    second line
+login = Log in
+    .title = Log in to { -brand }
+braces = { "{" }literal{ "}" }
+"#;
+
+  fn format(key: &str, args: &[(&str, &str)]) -> String {
+    let bundle = parse_ftl(FTL).unwrap();
+    let args = (args.iter())
+      .map(|(k, v)| (k.to_string(), v.to_string()))
+      .collect();
+    let mut result = String::new();
+    format_pattern(&bundle, &bundle[key], &args, 0, &mut result);
+    result
+  }
+
+  #[test_case("hello", &[("name", "world")] => "Hello, world!"; "variable")]
+  #[test_case("hello", &[] => "Hello, {$name}!"; "missing variable")]
+  #[test_case("about", &[] => "About Abel"; "term")]
+  #[test_case("multiline", &[] => "First line\nsecond line"; "multiline")]
+  #[test_case("login", &[] => "Log in"; "message with attribute")]
+  #[test_case("login.title", &[] => "Log in to Abel"; "attribute")]
+  #[test_case("braces", &[] => "{literal}"; "string literal")]
+  fn test_format(key: &str, args: &[(&str, &str)]) -> String {
+    format(key, args)
+  }
+
+  #[test_case("a = {"; "unclosed")]
+  #[test_case("a = }"; "unbalanced")]
+  #[test_case("a = { $x ->\n  *[other] x\n}"; "selector")]
+  #[test_case("1a = x"; "invalid identifier")]
+  #[test_case("just text"; "not a message")]
+  fn test_parse_error(text: &str) {
+    assert!(parse_ftl(text).is_err());
+  }
+
+  #[test_case("fr-CH, fr;q=0.9, en;q=0.8, *;q=0.5" => vec!["fr", "en", "en-US"]; "preference")]
+  #[test_case("en-us, en-gb" => vec!["en-US", "en"]; "exact first")]
+  #[test_case("de;q=0, zh" => vec!["zh-Hans", "en"]; "excluded")]
+  #[test_case("" => vec!["en"]; "fallback only")]
+  fn test_negotiate(accept_language: &str) -> Vec<String> {
+    let locales = ["en", "en-US", "fr", "zh-Hans"]
+      .into_iter()
+      .map(|x| (x.to_owned(), Bundle::new()))
+      .collect();
+    let catalog = Catalog {
+      locales,
+      fallback: Some("en".into()),
+    };
+    (catalog.negotiate(accept_language).into_iter())
+      .map(String::from)
+      .collect()
+  }
+}
@@ -1,17 +1,24 @@
 use crate::lua::error::{
-  arg_error, check_string, check_truthiness, check_value, rt_error, tag_handler,
+  arg_error, check_string, check_truthiness, check_value, rt_error, rt_error_fmt, tag_handler,
 };
+use crate::lua::stream::create_table_stream;
 use crate::lua::LuaCacheExt;
-use mlua::{Function, Lua, LuaSerdeExt, MultiValue, Table};
+use mlua::{Function, Lua, LuaSerdeExt, MultiValue, Table, Value};
+use serde::ser::SerializeMap;
+use serde::{Serialize, Serializer};
 
 pub fn create_preload_json(lua: &Lua) -> mlua::Result<Function> {
   lua.create_cached_function("abel:preload_json", |lua, ()| {
     let json_table = lua.create_table()?;
     json_table.raw_set("parse", create_fn_json_parse(lua)?)?;
     json_table.raw_set("stringify", create_fn_json_stringify(lua)?)?;
+    json_table.raw_set("canonicalize", create_fn_json_canonicalize(lua)?)?;
     json_table.raw_set("array", create_fn_json_array(lua)?)?;
     json_table.raw_set("undo_array", create_fn_json_undo_array(lua)?)?;
     json_table.raw_set("array_metatable", lua.array_metatable())?;
+    let ndjson = create_table_ndjson(lua)?;
+    json_table.raw_set("ndjson_reader", ndjson.raw_get::<_, Function>("reader")?)?;
+    json_table.raw_set("ndjson_writer", ndjson.raw_get::<_, Function>("writer")?)?;
     Ok(json_table)
   })
 }
@@ -30,16 +37,41 @@ fn create_fn_json_stringify(lua: &Lua) -> mlua::Result<Function> {
     let value = args
       .pop_front()
       .ok_or_else(|| arg_error(lua, 1, "value expected", 0))?;
-    let pretty = check_truthiness(args.pop_front());
-    let result = if pretty {
-      serde_json::to_string_pretty(&value)
-    } else {
-      serde_json::to_string(&value)
+    // The second argument is either `pretty` itself or a table of options
+    let (pretty, sort_keys) = match args.pop_front() {
+      Some(Value::Table(options)) => (
+        check_truthiness(Some(options.raw_get("pretty")?)),
+        check_truthiness(Some(options.raw_get("sort_keys")?)),
+      ),
+      pretty => (check_truthiness(pretty), false),
+    };
+    let result = match (pretty, sort_keys) {
+      (false, false) => serde_json::to_string(&value),
+      (true, false) => serde_json::to_string_pretty(&value),
+      (pretty, true) => serde_json::to_value(&value).and_then(|x| {
+        if pretty {
+          serde_json::to_string_pretty(&SortedKeys(&x))
+        } else {
+          serde_json::to_string(&SortedKeys(&x))
+        }
+      }),
     };
     result.map_err(rt_error)
   })
 }
 
+fn create_fn_json_canonicalize(lua: &Lua) -> mlua::Result<Function> {
+  lua.create_cached_function("abel:json.canonicalize", |lua, mut args: MultiValue| {
+    let value = args
+      .pop_front()
+      .ok_or_else(|| arg_error(lua, 1, "value expected", 0))?;
+    let value = serde_json::to_value(&value).map_err(rt_error)?;
+    let mut buf = String::new();
+    write_canonical(&mut buf, &value)?;
+    Ok(buf)
+  })
+}
+
 fn create_fn_json_array(lua: &Lua) -> mlua::Result<Function> {
   lua.create_cached_function("abel:json.array", |lua, mut args: MultiValue| {
     let table: Table =
@@ -63,3 +95,144 @@ fn create_fn_json_undo_array(lua: &Lua) -> mlua::Result<Function> {
     Ok(table)
   })
 }
+
+fn create_table_ndjson(lua: &Lua) -> mlua::Result<Table> {
+  lua.create_cached_value("abel:ndjson_module", || {
+    lua
+      .load(include_str!("ndjson.lua"))
+      .set_name("@[ndjson]")?
+      .call((
+        create_fn_json_parse(lua)?,
+        create_fn_json_stringify(lua)?,
+        create_table_stream(lua)?,
+      ))
+  })
+}
+
+/// Serializes a JSON value with object keys in lexicographic order.
+struct SortedKeys<'a>(&'a serde_json::Value);
+
+impl Serialize for SortedKeys<'_> {
+  fn serialize<S: Serializer>(&self, ser: S) -> Result<S::Ok, S::Error> {
+    match self.0 {
+      serde_json::Value::Array(array) => ser.collect_seq(array.iter().map(SortedKeys)),
+      serde_json::Value::Object(object) => {
+        let mut entries = object.iter().collect::<Vec<_>>();
+        entries.sort_unstable_by(|(a, _), (b, _)| a.cmp(b));
+        let mut map = ser.serialize_map(Some(entries.len()))?;
+        for (k, v) in entries {
+          map.serialize_entry(k, &SortedKeys(v))?;
+        }
+        map.end()
+      }
+      x => x.serialize(ser),
+    }
+  }
+}
+
+/// Writes JSON value in the canonical form defined by RFC 8785 (JSON
+/// Canonicalization Scheme).
+fn write_canonical(buf: &mut String, value: &serde_json::Value) -> mlua::Result<()> {
+  use serde_json::Value::*;
+  match value {
+    Null => buf.push_str("null"),
+    Bool(b) => buf.push_str(if *b { "true" } else { "false" }),
+    Number(n) => {
+      // All numbers are treated as IEEE 754 doubles, as in ECMAScript
+      let n = n.as_f64().unwrap();
+      write_es_number(buf, n)?;
+    }
+    // serde_json escapes strings the same way as ECMAScript's `JSON.stringify`
+    String(s) => buf.push_str(&serde_json::to_string(s).map_err(rt_error)?),
+    Array(array) => {
+      buf.push('[');
+      for (i, x) in array.iter().enumerate() {
+        if i > 0 {
+          buf.push(',');
+        }
+        write_canonical(buf, x)?;
+      }
+      buf.push(']');
+    }
+    Object(object) => {
+      // Keys are sorted by their UTF-16 code units
+      let mut entries = object.iter().collect::<Vec<_>>();
+      entries.sort_unstable_by(|(a, _), (b, _)| a.encode_utf16().cmp(b.encode_utf16()));
+      buf.push('{');
+      for (i, (k, v)) in entries.into_iter().enumerate() {
+        if i > 0 {
+          buf.push(',');
+        }
+        buf.push_str(&serde_json::to_string(k).map_err(rt_error)?);
+        buf.push(':');
+        write_canonical(buf, v)?;
+      }
+      buf.push('}');
+    }
+  }
+  Ok(())
+}
+
+/// Writes a number the way ECMAScript's `Number.prototype.toString` does.
+fn write_es_number(buf: &mut String, n: f64) -> mlua::Result<()> {
+  if !n.is_finite() {
+    return Err(rt_error_fmt!("cannot canonicalize non-finite number {n}"));
+  }
+  if n == 0.0 {
+    buf.push('0');
+    return Ok(());
+  }
+  if n < 0.0 {
+    buf.push('-');
+  }
+
+  // Shortest round-trip representation, e.g. `1.2345e2`
+  let sci = format!("{:e}", n.abs());
+  let (mantissa, exp) = sci.split_once('e').unwrap();
+  let digits = mantissa.replace('.', "");
+  let k = digits.len() as i32;
+  let n = exp.parse::<i32>().unwrap() + 1;
+
+  if k <= n && n <= 21 {
+    buf.push_str(&digits);
+    buf.extend(std::iter::repeat('0').take((n - k) as _));
+  } else if 0 < n && n <= 21 {
+    let (int, frac) = digits.split_at(n as _);
+    buf.extend([int, ".", frac]);
+  } else if -6 < n && n <= 0 {
+    buf.push_str("0.");
+    buf.extend(std::iter::repeat('0').take(-n as _));
+    buf.push_str(&digits);
+  } else {
+    let (first, rest) = digits.split_at(1);
+    buf.push_str(first);
+    if !rest.is_empty() {
+      buf.extend([".", rest]);
+    }
+    let e = n - 1;
+    buf.push_str(&format!("e{}{}", if e < 0 { '-' } else { '+' }, e.abs()));
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use test_case::test_case;
+
+  #[test_case(1.0 => "1"; "integer")]
+  #[test_case(-1.5 => "-1.5"; "negative")]
+  #[test_case(-0.0 => "0"; "negative zero")]
+  #[test_case(123.456 => "123.456"; "fraction")]
+  #[test_case(1e20 => "100000000000000000000"; "large integer")]
+  #[test_case(1e21 => "1e+21"; "large exponent")]
+  #[test_case(0.000001 => "0.000001"; "small fraction")]
+  #[test_case(1e-7 => "1e-7"; "small exponent")]
+  #[test_case(5e-324 => "5e-324"; "min subnormal")]
+  #[test_case(f64::MAX => "1.7976931348623157e+308"; "max")]
+  fn test_es_number(n: f64) -> String {
+    let mut buf = String::new();
+    write_es_number(&mut buf, n).unwrap();
+    buf
+  }
+}
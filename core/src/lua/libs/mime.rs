@@ -0,0 +1,101 @@
+//! Media types of files, guessed from their extensions.
+
+/// Common extensions, sorted for binary search.
+const TYPES: &[(&str, &str)] = &[
+  ("7z", "application/x-7z-compressed"),
+  ("aac", "audio/aac"),
+  ("avif", "image/avif"),
+  ("bin", "application/octet-stream"),
+  ("bmp", "image/bmp"),
+  ("css", "text/css; charset=utf-8"),
+  ("csv", "text/csv; charset=utf-8"),
+  ("doc", "application/msword"),
+  (
+    "docx",
+    "application/vnd.openxmlformats-officedocument.wordprocessingml.document",
+  ),
+  ("epub", "application/epub+zip"),
+  ("flac", "audio/flac"),
+  ("gif", "image/gif"),
+  ("gz", "application/gzip"),
+  ("htm", "text/html; charset=utf-8"),
+  ("html", "text/html; charset=utf-8"),
+  ("ico", "image/vnd.microsoft.icon"),
+  ("ics", "text/calendar; charset=utf-8"),
+  ("jpeg", "image/jpeg"),
+  ("jpg", "image/jpeg"),
+  ("js", "text/javascript; charset=utf-8"),
+  ("json", "application/json"),
+  ("lua", "text/x-lua; charset=utf-8"),
+  ("m4a", "audio/mp4"),
+  ("md", "text/markdown; charset=utf-8"),
+  ("mjs", "text/javascript; charset=utf-8"),
+  ("mkv", "video/x-matroska"),
+  ("mov", "video/quicktime"),
+  ("mp3", "audio/mpeg"),
+  ("mp4", "video/mp4"),
+  ("oga", "audio/ogg"),
+  ("ogg", "audio/ogg"),
+  ("ogv", "video/ogg"),
+  ("otf", "font/otf"),
+  ("pdf", "application/pdf"),
+  ("png", "image/png"),
+  ("svg", "image/svg+xml"),
+  ("tar", "application/x-tar"),
+  ("tif", "image/tiff"),
+  ("tiff", "image/tiff"),
+  ("toml", "application/toml"),
+  ("ttf", "font/ttf"),
+  ("txt", "text/plain; charset=utf-8"),
+  ("wasm", "application/wasm"),
+  ("wav", "audio/wav"),
+  ("weba", "audio/webm"),
+  ("webm", "video/webm"),
+  ("webmanifest", "application/manifest+json"),
+  ("webp", "image/webp"),
+  ("woff", "font/woff"),
+  ("woff2", "font/woff2"),
+  ("xls", "application/vnd.ms-excel"),
+  (
+    "xlsx",
+    "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet",
+  ),
+  ("xml", "application/xml"),
+  ("yaml", "application/yaml"),
+  ("yml", "application/yaml"),
+  ("zip", "application/zip"),
+];
+
+/// Guesses the media type of a file from its name, falling back to
+/// `application/octet-stream`.
+pub(crate) fn guess(path: &str) -> &'static str {
+  let name = path.rsplit('/').next().unwrap_or(path);
+  let ext = match name.rsplit_once('.') {
+    Some((stem, ext)) if !stem.is_empty() => ext.to_ascii_lowercase(),
+    _ => return "application/octet-stream",
+  };
+  match TYPES.binary_search_by(|(x, _)| x.cmp(&&*ext)) {
+    Ok(i) => TYPES[i].1,
+    Err(_) => "application/octet-stream",
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use test_case::test_case;
+
+  #[test]
+  fn test_sorted() {
+    assert!(TYPES.windows(2).all(|x| x[0].0 < x[1].0));
+  }
+
+  #[test_case("index.html" => "text/html; charset=utf-8"; "html")]
+  #[test_case("assets/Photo.JPG" => "image/jpeg"; "uppercase")]
+  #[test_case("archive.tar.gz" => "application/gzip"; "last extension")]
+  #[test_case("dir.d/README" => "application/octet-stream"; "no extension")]
+  #[test_case(".env" => "application/octet-stream"; "dotfile")]
+  fn test_guess(path: &str) -> &'static str {
+    guess(path)
+  }
+}
@@ -1,7 +1,93 @@
+#[cfg(feature = "crypto")]
 pub mod crypto;
+pub mod dns;
+pub mod email;
+pub mod exec;
 pub mod fs;
 pub mod http;
+pub mod i18n;
 pub mod json;
 pub mod lua_std;
+mod mime;
+#[cfg(feature = "oauth")]
+pub mod oauth;
 pub mod rand;
+pub mod regex;
+pub mod s3;
+pub mod schema;
+pub mod session;
+pub mod socket;
 pub mod stream;
+pub mod strings;
+#[cfg(feature = "unicode")]
+pub mod unicode;
+#[cfg(feature = "validate")]
+pub mod validate;
+#[cfg(feature = "wasm")]
+pub mod wasm;
+
+use sha2::{Digest, Sha256};
+
+/// HMAC-SHA256 as in RFC 2104.
+pub fn hmac_sha256(key: &[u8], message: &[u8]) -> Vec<u8> {
+  const BLOCK_SIZE: usize = 64;
+  let mut block = [0; BLOCK_SIZE];
+  if key.len() > BLOCK_SIZE {
+    block[..32].copy_from_slice(&Sha256::digest(key));
+  } else {
+    block[..key.len()].copy_from_slice(key);
+  }
+  let pad = |x: u8| block.map(|b| b ^ x);
+  let inner = (Sha256::new().chain_update(pad(0x36)))
+    .chain_update(message)
+    .finalize();
+  (Sha256::new().chain_update(pad(0x5c)))
+    .chain_update(inner)
+    .finalize()
+    .to_vec()
+}
+
+/// Compares two byte strings in constant time, regardless of where they
+/// differ.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+  let diff = (a.iter().zip(b)).fold(0, |acc, (x, y)| acc | (x ^ y));
+  a.len() == b.len() && diff == 0
+}
+
+/// Civil date (year, month, day) of days since the Unix epoch, by Howard
+/// Hinnant.
+pub fn civil_from_days(days: i64) -> (i64, i64, i64) {
+  let z = days + 719468;
+  let era = z.div_euclid(146097);
+  let doe = z.rem_euclid(146097);
+  let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+  let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+  let mp = (5 * doy + 2) / 153;
+  let day = doy - (153 * mp + 2) / 5 + 1;
+  let month = if mp < 10 { mp + 3 } else { mp - 9 };
+  let year = yoe + era * 400 + (month <= 2) as i64;
+  (year, month, day)
+}
+
+/// Civil date (year, month, day) of Unix time.
+pub(crate) fn civil_date(time: u64) -> (i64, i64, i64) {
+  civil_from_days((time / 86400) as i64)
+}
+
+/// Formats Unix time as an HTTP date, e.g. `Thu, 01 Jan 1970 00:00:00 GMT`.
+pub(crate) fn http_date(time: u64) -> String {
+  const WEEKDAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+  const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+  ];
+  let (year, month, day) = civil_date(time);
+  let secs = time % 86400;
+  format!(
+    "{}, {day:02} {} {year:04} {:02}:{:02}:{:02} GMT",
+    WEEKDAYS[(time / 86400 % 7) as usize],
+    MONTHS[month as usize - 1],
+    secs / 3600,
+    secs / 60 % 60,
+    secs % 60
+  )
+}
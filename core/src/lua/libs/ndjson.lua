@@ -0,0 +1,81 @@
+local json_parse, json_stringify, stream = ...
+local ndjson = {}
+
+local function has_field(value, field)
+  local type_value = type(value)
+  if type_value ~= "table" and type_value ~= "userdata" then
+    return false
+  end
+  local success, result = pcall(function() return value[field] ~= nil end)
+  return success and result
+end
+
+-- Reads a byte stream line by line, yielding one parsed JSON value per line.
+-- Blank lines are skipped.
+function ndjson.reader(st)
+  if not has_field(st, "read") then
+    error("stream expected, got " .. type(st), 2)
+  end
+
+  local buf = ""
+  local eof = false
+  return setmetatable({
+    read = function(_)
+      while true do
+        local pos = string.find(buf, "\n", 1, true)
+        if pos then
+          local line = string.sub(buf, 1, pos - 1)
+          buf = string.sub(buf, pos + 1)
+          if string.find(line, "%S") then
+            return json_parse(line)
+          end
+        elseif eof then
+          local line = buf
+          buf = ""
+          if string.find(line, "%S") then
+            return json_parse(line)
+          end
+          return nil
+        else
+          local bytes = st:read()
+          if bytes then
+            buf = buf .. bytes
+          else
+            eof = true
+          end
+        end
+      end
+    end
+  }, { __index = stream })
+end
+
+-- Turns a stream (or iterator function) of values into a byte stream of
+-- NDJSON lines, suitable as response body; or wraps a sink so that each
+-- written value is encoded as one line.
+function ndjson.writer(target)
+  if type(target) == "function" then
+    target = stream.from_iter(target)
+  end
+
+  if has_field(target, "read") then
+    return setmetatable({
+      read = function(_)
+        local item = target:read()
+        if item ~= nil then
+          return json_stringify(item) .. "\n"
+        end
+      end
+    }, { __index = stream })
+  elseif has_field(target, "write") then
+    return setmetatable({
+      write = function(self, item)
+        target:write(json_stringify(item) .. "\n")
+        return self
+      end
+    }, { __index = target })
+  else
+    error("stream, sink or function expected, got " .. type(target), 2)
+  end
+end
+
+return ndjson
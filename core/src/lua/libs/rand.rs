@@ -1,8 +1,13 @@
-use crate::lua::error::{arg_error, check_integer, check_userdata_mut, tag_handler};
+use crate::lua::error::{arg_error, check_integer, check_userdata_mut, check_value, tag_handler};
 use crate::lua::LuaCacheExt;
-use mlua::{Function, Lua, MultiValue, UserData};
+use mlua::Value::Nil;
+use mlua::{Function, Lua, MultiValue, Table, UserData};
+use rand::seq::SliceRandom;
 use rand::{thread_rng, Rng, RngCore};
 
+/// Maximum number of bytes `rand.bytes` generates at once.
+const MAX_BYTES: usize = 1 << 20;
+
 struct LuaRng(Box<dyn RngCore>);
 
 impl UserData for LuaRng {
@@ -37,6 +42,74 @@ pub fn create_preload_rand(lua: &Lua) -> mlua::Result<Function> {
         lua.create_userdata(LuaRng(Box::new(thread_rng())))
       })?,
     )?;
+    rand_table.raw_set("int", create_fn_int(lua)?)?;
+    rand_table.raw_set("float", create_fn_float(lua)?)?;
+    rand_table.raw_set("bytes", create_fn_bytes(lua)?)?;
+    rand_table.raw_set("choice", create_fn_choice(lua)?)?;
+    rand_table.raw_set("shuffle", create_fn_shuffle(lua)?)?;
     Ok(rand_table)
   })
 }
+
+// The following functions use the thread-local generator, which is
+// cryptographically secure and periodically reseeded from the OS, unlike
+// `math.random`.
+
+/// Generates a random integer in `[low, high]`.
+fn create_fn_int(lua: &Lua) -> mlua::Result<Function> {
+  lua.create_cached_function("abel:rand.int", |lua, mut args: MultiValue| {
+    let low = check_integer(args.pop_front()).map_err(tag_handler(lua, 1, 0))?;
+    let high = check_integer(args.pop_front()).map_err(tag_handler(lua, 2, 0))?;
+    if low > high {
+      return Err(arg_error(lua, 2, "range is empty", 0));
+    }
+    Ok(thread_rng().gen_range(low..=high))
+  })
+}
+
+/// Generates a random float in `[0, 1)`.
+fn create_fn_float(lua: &Lua) -> mlua::Result<Function> {
+  lua.create_cached_function("abel:rand.float", |_lua, ()| Ok(thread_rng().gen::<f64>()))
+}
+
+/// Generates a string of `n` random bytes.
+fn create_fn_bytes(lua: &Lua) -> mlua::Result<Function> {
+  lua.create_cached_function("abel:rand.bytes", |lua, mut args: MultiValue| {
+    let len = check_integer(args.pop_front()).map_err(tag_handler(lua, 1, 0))?;
+    let len = usize::try_from(len)
+      .ok()
+      .filter(|&x| x <= MAX_BYTES)
+      .ok_or_else(|| arg_error(lua, 1, "length out of range", 0))?;
+    let mut bytes = vec![0; len];
+    thread_rng().fill_bytes(&mut bytes);
+    lua.create_string(&bytes)
+  })
+}
+
+/// Returns a random element of an array, or `nil` if it is empty.
+fn create_fn_choice(lua: &Lua) -> mlua::Result<Function> {
+  lua.create_cached_function("abel:rand.choice", |lua, mut args: MultiValue| {
+    let table: Table =
+      check_value(lua, args.pop_front(), "table").map_err(tag_handler(lua, 1, 0))?;
+    let len = table.raw_len();
+    if len == 0 {
+      return Ok(Nil);
+    }
+    table.raw_get(thread_rng().gen_range(1..=len))
+  })
+}
+
+/// Shuffles an array in place, and returns it.
+fn create_fn_shuffle(lua: &Lua) -> mlua::Result<Function> {
+  lua.create_cached_function("abel:rand.shuffle", |lua, mut args: MultiValue| {
+    let table: Table =
+      check_value(lua, args.pop_front(), "table").map_err(tag_handler(lua, 1, 0))?;
+    let mut values =
+      (table.clone().raw_sequence_values::<mlua::Value>()).collect::<mlua::Result<Vec<_>>>()?;
+    values.shuffle(&mut thread_rng());
+    for (i, value) in values.into_iter().enumerate() {
+      table.raw_set(i + 1, value)?;
+    }
+    Ok(table)
+  })
+}
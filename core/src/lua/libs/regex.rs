@@ -0,0 +1,208 @@
+use crate::lua::error::{
+  arg_error, check_integer, check_string, rt_error_fmt, tag_error, tag_handler,
+};
+use crate::lua::LuaCacheExt;
+use clru::CLruCache;
+use mlua::Value::Nil;
+use mlua::{Function, Lua, MultiValue};
+use nonzero_ext::nonzero;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use regex::bytes::{Captures, Regex, RegexBuilder};
+
+/// Maximum length of a pattern in bytes.
+const MAX_PATTERN_LEN: usize = 4096;
+
+/// Maximum size of a compiled pattern, and of its lazy DFA's cache.
+const SIZE_LIMIT: usize = 1 << 20;
+
+/// Compiled patterns shared by every service, keyed by pattern source.
+static CACHE: Lazy<Mutex<CLruCache<Vec<u8>, Regex>>> =
+  Lazy::new(|| Mutex::new(CLruCache::new(nonzero!(256usize))));
+
+pub fn create_preload_regex(lua: &Lua) -> mlua::Result<Function> {
+  lua.create_cached_function("abel:preload_regex", |lua, ()| {
+    let regex = lua.create_table()?;
+    regex.raw_set("match", create_fn_match(lua)?)?;
+    regex.raw_set("find_all", create_fn_find_all(lua)?)?;
+    regex.raw_set("replace", create_fn_replace(lua)?)?;
+    regex.raw_set("split", create_fn_split(lua)?)?;
+    regex.raw_set("escape", create_fn_escape(lua)?)?;
+    Ok(regex)
+  })
+}
+
+/// Compiles a pattern, or gets it from the cache.
+pub(crate) fn compile(pattern: &[u8]) -> Result<Regex, String> {
+  if pattern.len() > MAX_PATTERN_LEN {
+    return Err("pattern too long".into());
+  }
+  if let Some(regex) = CACHE.lock().get(pattern) {
+    return Ok(regex.clone());
+  }
+  let regex = std::str::from_utf8(pattern)
+    .map_err(|_| "pattern is not valid UTF-8".to_owned())
+    .and_then(|x| {
+      (RegexBuilder::new(x).size_limit(SIZE_LIMIT))
+        .dfa_size_limit(SIZE_LIMIT)
+        .build()
+        .map_err(|error| error.to_string())
+    })?;
+  CACHE.lock().put(pattern.to_vec(), regex.clone());
+  Ok(regex)
+}
+
+fn check_regex(lua: &Lua, value: Option<mlua::Value>, pos: usize) -> mlua::Result<Regex> {
+  let pattern = check_string(lua, value).map_err(tag_handler(lua, pos, 0))?;
+  compile(pattern.as_bytes()).map_err(|error| arg_error(lua, pos, &error, 0))
+}
+
+fn check_limit(lua: &Lua, value: Option<mlua::Value>, pos: usize) -> mlua::Result<Option<usize>> {
+  match value {
+    None | Some(Nil) => Ok(None),
+    x => {
+      let limit = check_integer(x).map_err(tag_handler(lua, pos, 0))?;
+      let limit = limit
+        .try_into()
+        .map_err(|_| arg_error(lua, pos, "limit must be non-negative", 0))?;
+      Ok(Some(limit))
+    }
+  }
+}
+
+/// Converts captures to values in the way `string.match` does: the whole
+/// match if the pattern has no groups, otherwise each group, with `nil` for
+/// those that did not participate.
+fn captures_to_values<'lua>(lua: &'lua Lua, caps: &Captures) -> mlua::Result<MultiValue<'lua>> {
+  let groups = if caps.len() == 1 {
+    caps.iter().collect::<Vec<_>>()
+  } else {
+    caps.iter().skip(1).collect()
+  };
+  (groups.into_iter())
+    .map(|x| match x {
+      Some(x) => lua.create_string(x.as_bytes()).map(mlua::Value::String),
+      None => Ok(Nil),
+    })
+    .collect()
+}
+
+/// Returns captures of the first match, or `nil` if there is none.
+fn create_fn_match(lua: &Lua) -> mlua::Result<Function> {
+  lua.create_cached_function("abel:regex.match", |lua, mut args: MultiValue| {
+    let s = check_string(lua, args.pop_front()).map_err(tag_handler(lua, 1, 0))?;
+    let regex = check_regex(lua, args.pop_front(), 2)?;
+    match regex.captures(s.as_bytes()) {
+      Some(caps) => captures_to_values(lua, &caps),
+      None => Ok(MultiValue::from_vec(vec![Nil])),
+    }
+  })
+}
+
+/// Returns an array of all non-overlapping matches. Each item is the matched
+/// string if the pattern has no groups, or an array of groups otherwise.
+fn create_fn_find_all(lua: &Lua) -> mlua::Result<Function> {
+  lua.create_cached_function("abel:regex.find_all", |lua, mut args: MultiValue| {
+    let s = check_string(lua, args.pop_front()).map_err(tag_handler(lua, 1, 0))?;
+    let regex = check_regex(lua, args.pop_front(), 2)?;
+    let limit = check_limit(lua, args.pop_front(), 3)?.unwrap_or(usize::MAX);
+    let result = lua.create_table()?;
+    if regex.captures_len() == 1 {
+      for (i, m) in regex.find_iter(s.as_bytes()).take(limit).enumerate() {
+        result.raw_set(i + 1, lua.create_string(m.as_bytes())?)?;
+      }
+    } else {
+      for (i, caps) in regex.captures_iter(s.as_bytes()).take(limit).enumerate() {
+        let groups = lua.create_table()?;
+        for (j, value) in captures_to_values(lua, &caps)?.into_iter().enumerate() {
+          groups.raw_set(j + 1, value)?;
+        }
+        result.raw_set(i + 1, groups)?;
+      }
+    }
+    Ok(result)
+  })
+}
+
+enum Replacement<'lua> {
+  Template(mlua::String<'lua>),
+  Function(Function<'lua>),
+}
+
+/// Replaces at most `limit` matches (all if `nil`), returning the result and
+/// number of replacements made.
+///
+/// The replacement is either a string, where `$1` and `${name}` refer to
+/// groups, or a function called with the captures, whose result replaces the
+/// match unless it is `nil` or `false`.
+fn create_fn_replace(lua: &Lua) -> mlua::Result<Function> {
+  lua.create_cached_function("abel:regex.replace", |lua, mut args: MultiValue| {
+    let s = check_string(lua, args.pop_front()).map_err(tag_handler(lua, 1, 0))?;
+    let regex = check_regex(lua, args.pop_front(), 2)?;
+    let repl = match args.pop_front() {
+      Some(mlua::Value::String(x)) => Replacement::Template(x),
+      Some(mlua::Value::Function(f)) => Replacement::Function(f),
+      x => {
+        let type_name = x.map(|x| x.type_name()).unwrap_or("no value");
+        return Err(tag_error(lua, 3, "string or function", type_name, 0));
+      }
+    };
+    let limit = check_limit(lua, args.pop_front(), 4)?.unwrap_or(usize::MAX);
+
+    let s = s.as_bytes();
+    let mut result = Vec::with_capacity(s.len());
+    let mut last = 0;
+    let mut count = 0;
+    for caps in regex.captures_iter(s).take(limit) {
+      let m = caps.get(0).unwrap();
+      result.extend_from_slice(&s[last..m.start()]);
+      match &repl {
+        Replacement::Template(t) => caps.expand(t.as_bytes(), &mut result),
+        Replacement::Function(f) => {
+          match f.call::<_, mlua::Value>(captures_to_values(lua, &caps)?)? {
+            Nil | mlua::Value::Boolean(false) => result.extend_from_slice(m.as_bytes()),
+            x => {
+              let type_name = x.type_name();
+              let x = (lua.coerce_string(x)?)
+                .ok_or_else(|| rt_error_fmt!("invalid replacement value (a {type_name})"))?;
+              result.extend_from_slice(x.as_bytes());
+            }
+          }
+        }
+      }
+      last = m.end();
+      count += 1;
+    }
+    result.extend_from_slice(&s[last..]);
+    Ok((lua.create_string(&result)?, count))
+  })
+}
+
+/// Splits a string by matches, into at most `limit` parts if given.
+fn create_fn_split(lua: &Lua) -> mlua::Result<Function> {
+  lua.create_cached_function("abel:regex.split", |lua, mut args: MultiValue| {
+    let s = check_string(lua, args.pop_front()).map_err(tag_handler(lua, 1, 0))?;
+    let regex = check_regex(lua, args.pop_front(), 2)?;
+    let parts = match check_limit(lua, args.pop_front(), 3)? {
+      Some(0) => return Err(arg_error(lua, 3, "limit must be positive", 0)),
+      Some(limit) => regex.splitn(s.as_bytes(), limit).collect::<Vec<_>>(),
+      None => regex.split(s.as_bytes()).collect(),
+    };
+    let result = lua.create_table_with_capacity(parts.len() as _, 0)?;
+    for (i, part) in parts.into_iter().enumerate() {
+      result.raw_set(i + 1, lua.create_string(part)?)?;
+    }
+    Ok(result)
+  })
+}
+
+/// Escapes all special characters in a string, so it matches literally.
+fn create_fn_escape(lua: &Lua) -> mlua::Result<Function> {
+  lua.create_cached_function("abel:regex.escape", |lua, mut args: MultiValue| {
+    let s = check_string(lua, args.pop_front()).map_err(tag_handler(lua, 1, 0))?;
+    let s = s
+      .to_str()
+      .map_err(|_| arg_error(lua, 1, "string is not valid UTF-8", 0))?;
+    Ok(regex::escape(s))
+  })
+}
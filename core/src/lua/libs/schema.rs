@@ -0,0 +1,368 @@
+use super::regex::compile as compile_regex;
+use crate::lua::error::{check_value, http_error, rt_error_fmt, tag_handler, TableCheckExt};
+use crate::lua::LuaCacheExt;
+use bstr::ByteSlice;
+use hyper::StatusCode;
+use mlua::Value::Nil;
+use mlua::{Function, Lua, MultiValue, Table};
+
+/// Maximum nesting depth of schemas.
+const MAX_DEPTH: usize = 64;
+
+pub fn create_preload_schema(lua: &Lua) -> mlua::Result<Function> {
+  lua.create_cached_function("abel:preload_schema", |lua, ()| {
+    let schema = lua.create_table()?;
+    schema.raw_set("validate", create_fn_validate(lua)?)?;
+    schema.raw_set("check", create_fn_check(lua)?)?;
+    Ok(schema)
+  })
+}
+
+/// Validates a value against a schema, returning `true`, or `false` and an
+/// array of field errors.
+fn create_fn_validate(lua: &Lua) -> mlua::Result<Function> {
+  lua.create_cached_function("abel:schema.validate", |lua, mut args: MultiValue| {
+    let value = args.pop_front().unwrap_or(Nil);
+    let spec: Table =
+      check_value(lua, args.pop_front(), "table").map_err(tag_handler(lua, 2, 0))?;
+    let errors = validate(lua, &value, &spec)?;
+    if errors.is_empty() {
+      Ok((true, Nil))
+    } else {
+      Ok((false, errors_to_lua(lua, errors)?))
+    }
+  })
+}
+
+/// Same as `validate`, but returns the value if valid, and otherwise throws
+/// a 422 error with the field errors as `detail.errors`.
+fn create_fn_check(lua: &Lua) -> mlua::Result<Function> {
+  lua.create_cached_function("abel:schema.check", |lua, mut args: MultiValue| {
+    let value = args.pop_front().unwrap_or(Nil);
+    let spec: Table =
+      check_value(lua, args.pop_front(), "table").map_err(tag_handler(lua, 2, 0))?;
+    let errors = validate(lua, &value, &spec)?;
+    if errors.is_empty() {
+      Ok(value)
+    } else {
+      let detail = lua.create_table_from([("errors", errors_to_lua(lua, errors)?)])?;
+      Err(http_error(
+        lua,
+        StatusCode::UNPROCESSABLE_ENTITY,
+        "validation failed",
+        mlua::Value::Table(detail),
+      ))
+    }
+  })
+}
+
+#[derive(Debug, PartialEq)]
+struct FieldError {
+  field: String,
+  message: String,
+}
+
+fn errors_to_lua(lua: &Lua, errors: Vec<FieldError>) -> mlua::Result<mlua::Value> {
+  let result = lua.create_table_with_capacity(errors.len() as _, 0)?;
+  for (i, error) in errors.into_iter().enumerate() {
+    let error = lua.create_table_from([("field", error.field), ("message", error.message)])?;
+    result.raw_set(i + 1, error)?;
+  }
+  Ok(mlua::Value::Table(result))
+}
+
+fn validate<'lua>(
+  lua: &'lua Lua,
+  value: &mlua::Value<'lua>,
+  spec: &Table<'lua>,
+) -> mlua::Result<Vec<FieldError>> {
+  let mut validator = Validator {
+    lua,
+    errors: Vec::new(),
+  };
+  validator.validate(value, spec, "", 0)?;
+  Ok(validator.errors)
+}
+
+fn join_field(field: &str, name: &str) -> String {
+  if field.is_empty() {
+    name.into()
+  } else {
+    format!("{field}.{name}")
+  }
+}
+
+fn type_name(value: &mlua::Value) -> mlua::Result<&'static str> {
+  use mlua::Value::*;
+  let result = match value {
+    Nil => "null",
+    Boolean(_) => "boolean",
+    Integer(_) => "integer",
+    Number(_) => "number",
+    String(_) => "string",
+    Table(t) if is_array(t)? => "array",
+    Table(_) => "object",
+    _ => value.type_name(),
+  };
+  Ok(result)
+}
+
+/// Checks if a table is a sequence, i.e. only has keys from 1 to its length.
+fn is_array(table: &Table) -> mlua::Result<bool> {
+  let mut count = 0;
+  for kv in table.clone().pairs::<mlua::Value, mlua::Value>() {
+    kv?;
+    count += 1;
+  }
+  Ok(count == table.raw_len())
+}
+
+fn matches_type(value: &mlua::Value, ty: &[u8]) -> mlua::Result<bool> {
+  use mlua::Value::*;
+  let result = match (ty, value) {
+    (b"null", Nil)
+    | (b"boolean", Boolean(_))
+    | (b"string", String(_))
+    | (b"integer", Integer(_))
+    | (b"number", Integer(_) | Number(_)) => true,
+    (b"integer", Number(x)) => x.is_finite() && x.fract() == 0.,
+    // Empty tables are both arrays and objects
+    (b"array", Table(t)) => is_array(t)?,
+    (b"object", Table(t)) => t.raw_len() == 0 || !is_array(t)?,
+    _ => false,
+  };
+  Ok(result)
+}
+
+/// Validates values against a subset of JSON Schema written in Lua tables.
+///
+/// Supported keywords are `type`, `enum`, `const`, `minLength`, `maxLength`,
+/// `pattern`, `minimum`, `maximum`, `exclusiveMinimum`, `exclusiveMaximum`,
+/// `items`, `minItems`, `maxItems`, `properties`, `required` and
+/// `additionalProperties`. String lengths are counted in characters.
+struct Validator<'lua> {
+  lua: &'lua Lua,
+  errors: Vec<FieldError>,
+}
+
+impl<'lua> Validator<'lua> {
+  fn error(&mut self, field: &str, message: impl Into<String>) {
+    self.errors.push(FieldError {
+      field: field.into(),
+      message: message.into(),
+    });
+  }
+
+  fn validate(
+    &mut self,
+    value: &mlua::Value<'lua>,
+    spec: &Table<'lua>,
+    field: &str,
+    depth: usize,
+  ) -> mlua::Result<()> {
+    let lua = self.lua;
+    if depth > MAX_DEPTH {
+      return Err(rt_error_fmt!("schema nested too deep"));
+    }
+
+    match spec.raw_get::<_, mlua::Value>("type")? {
+      Nil => {}
+      mlua::Value::String(ty) => {
+        if !matches_type(value, ty.as_bytes())? {
+          let expected = ty.to_string_lossy();
+          let message = format!("expected {expected}, got {}", type_name(value)?);
+          self.error(field, message);
+          return Ok(());
+        }
+      }
+      mlua::Value::Table(types) => {
+        let mut matched = false;
+        let mut expected = Vec::new();
+        for ty in types.raw_sequence_values::<mlua::String>() {
+          let ty = ty?;
+          matched = matched || matches_type(value, ty.as_bytes())?;
+          expected.push(ty.to_string_lossy().into_owned());
+        }
+        if !matched {
+          let expected = expected.join(" or ");
+          let message = format!("expected {expected}, got {}", type_name(value)?);
+          self.error(field, message);
+          return Ok(());
+        }
+      }
+      _ => return Err(rt_error_fmt!("bad field 'type' (string or table expected)")),
+    }
+
+    if let Some(values) = spec.check_raw_get::<Option<Table>>(lua, "enum", "table")? {
+      let mut found = false;
+      for x in values.raw_sequence_values::<mlua::Value>() {
+        found = found || x? == *value;
+      }
+      if !found {
+        self.error(field, "not one of the allowed values");
+      }
+    }
+    match spec.raw_get::<_, mlua::Value>("const")? {
+      Nil => {}
+      x if x == *value => {}
+      _ => self.error(field, "not the allowed value"),
+    }
+
+    match value {
+      mlua::Value::String(s) => self.validate_string(s, spec, field)?,
+      mlua::Value::Integer(x) => self.validate_number(*x as f64, spec, field)?,
+      mlua::Value::Number(x) => self.validate_number(*x, spec, field)?,
+      mlua::Value::Table(t) => {
+        self.validate_array(t, spec, field, depth)?;
+        self.validate_object(t, spec, field, depth)?;
+      }
+      _ => {}
+    }
+    Ok(())
+  }
+
+  fn validate_string(
+    &mut self,
+    s: &mlua::String<'lua>,
+    spec: &Table<'lua>,
+    field: &str,
+  ) -> mlua::Result<()> {
+    let lua = self.lua;
+    let s = s.as_bytes();
+    let len = s.chars().count();
+    if let Some(min) = spec.check_raw_get::<Option<usize>>(lua, "minLength", "integer")? {
+      if len < min {
+        self.error(field, format!("must be at least {min} characters long"));
+      }
+    }
+    if let Some(max) = spec.check_raw_get::<Option<usize>>(lua, "maxLength", "integer")? {
+      if len > max {
+        self.error(field, format!("must be at most {max} characters long"));
+      }
+    }
+    if let Some(pattern) = spec.check_raw_get::<Option<mlua::String>>(lua, "pattern", "string")? {
+      let regex = compile_regex(pattern.as_bytes())
+        .map_err(|error| rt_error_fmt!("bad field 'pattern' ({error})"))?;
+      if !regex.is_match(s) {
+        let pattern = pattern.to_string_lossy();
+        self.error(field, format!("must match pattern '{pattern}'"));
+      }
+    }
+    Ok(())
+  }
+
+  fn validate_number(&mut self, x: f64, spec: &Table<'lua>, field: &str) -> mlua::Result<()> {
+    let lua = self.lua;
+    if let Some(min) = spec.check_raw_get::<Option<f64>>(lua, "minimum", "number")? {
+      if x < min {
+        self.error(field, format!("must be at least {min}"));
+      }
+    }
+    if let Some(max) = spec.check_raw_get::<Option<f64>>(lua, "maximum", "number")? {
+      if x > max {
+        self.error(field, format!("must be at most {max}"));
+      }
+    }
+    if let Some(min) = spec.check_raw_get::<Option<f64>>(lua, "exclusiveMinimum", "number")? {
+      if x <= min {
+        self.error(field, format!("must be greater than {min}"));
+      }
+    }
+    if let Some(max) = spec.check_raw_get::<Option<f64>>(lua, "exclusiveMaximum", "number")? {
+      if x >= max {
+        self.error(field, format!("must be less than {max}"));
+      }
+    }
+    Ok(())
+  }
+
+  fn validate_array(
+    &mut self,
+    t: &Table<'lua>,
+    spec: &Table<'lua>,
+    field: &str,
+    depth: usize,
+  ) -> mlua::Result<()> {
+    let lua = self.lua;
+    let len = t.raw_len();
+    if let Some(min) = spec.check_raw_get::<Option<i64>>(lua, "minItems", "integer")? {
+      if len < min {
+        self.error(field, format!("must have at least {min} items"));
+      }
+    }
+    if let Some(max) = spec.check_raw_get::<Option<i64>>(lua, "maxItems", "integer")? {
+      if len > max {
+        self.error(field, format!("must have at most {max} items"));
+      }
+    }
+    if let Some(items) = spec.check_raw_get::<Option<Table>>(lua, "items", "table")? {
+      for i in 1..=len {
+        let item = t.raw_get(i)?;
+        self.validate(&item, &items, &format!("{field}[{i}]"), depth + 1)?;
+      }
+    }
+    Ok(())
+  }
+
+  fn validate_object(
+    &mut self,
+    t: &Table<'lua>,
+    spec: &Table<'lua>,
+    field: &str,
+    depth: usize,
+  ) -> mlua::Result<()> {
+    let lua = self.lua;
+    let properties = spec.check_raw_get::<Option<Table>>(lua, "properties", "table")?;
+    if let Some(required) = spec.check_raw_get::<Option<Table>>(lua, "required", "table")? {
+      for name in required.raw_sequence_values::<mlua::String>() {
+        let name = name?;
+        if t.raw_get::<_, mlua::Value>(name.clone())? == Nil {
+          self.error(&join_field(field, &name.to_string_lossy()), "is required");
+        }
+      }
+    }
+    if let Some(properties) = &properties {
+      for kv in properties.clone().pairs::<mlua::String, Table>() {
+        let (name, sub_spec) = kv?;
+        let value = t.raw_get::<_, mlua::Value>(name.clone())?;
+        if value != Nil {
+          let field = join_field(field, &name.to_string_lossy());
+          self.validate(&value, &sub_spec, &field, depth + 1)?;
+        }
+      }
+    }
+    let additional = match spec.raw_get::<_, mlua::Value>("additionalProperties")? {
+      Nil | mlua::Value::Boolean(true) => return Ok(()),
+      mlua::Value::Boolean(false) => None,
+      mlua::Value::Table(x) => Some(x),
+      _ => {
+        return Err(rt_error_fmt!(
+          "bad field 'additionalProperties' (boolean or table expected)"
+        ))
+      }
+    };
+    for kv in t.clone().pairs::<mlua::Value, mlua::Value>() {
+      let (key, value) = kv?;
+      let is_known = match (&properties, &key) {
+        (Some(properties), mlua::Value::String(_)) => {
+          properties.raw_get::<_, mlua::Value>(key.clone())? != Nil
+        }
+        _ => false,
+      };
+      if is_known {
+        continue;
+      }
+      let name = match &key {
+        mlua::Value::String(x) => x.to_string_lossy().into_owned(),
+        mlua::Value::Integer(x) => x.to_string(),
+        _ => format!("<{}>", key.type_name()),
+      };
+      let field = join_field(field, &name);
+      match &additional {
+        Some(additional) => self.validate(&value, additional, &field, depth + 1)?,
+        None => self.error(&field, "unknown field"),
+      }
+    }
+    Ok(())
+  }
+}
@@ -0,0 +1,316 @@
+//! Signed cookie sessions.
+//!
+//! Session data is serialized as JSON and stored in the cookie itself along
+//! with its expiry time, signed with HMAC-SHA256 using the `session_secret`
+//! service secret. The data is not encrypted, so clients can read, but not
+//! modify it.
+
+use super::{constant_time_eq, hmac_sha256};
+use crate::lua::error::{
+  check_string, check_userdata, check_userdata_mut, check_value, rt_error, rt_error_fmt, tag_error,
+  tag_handler, TableCheckExt,
+};
+use crate::lua::http::{LuaRequest, LuaResponse};
+use data_encoding::BASE64URL_NOPAD;
+use hyper::header::{HeaderValue, COOKIE, SET_COOKIE};
+use mlua::Value::Nil;
+use mlua::{AnyUserData, Function, Lua, LuaSerdeExt, MultiValue, Table, TableExt, UserData};
+use serde::{Deserialize, Serialize};
+use serde_json::Map;
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Maximum size of a cookie most browsers accept.
+const MAX_COOKIE_SIZE: usize = 4096;
+
+pub fn create_preload_session(
+  secrets: &HashMap<String, String>,
+) -> impl FnOnce(&Lua) -> mlua::Result<Function> {
+  let secret = secrets.get("session_secret").cloned();
+  |lua| {
+    lua.create_function(move |lua, ()| {
+      let session = lua.create_table()?;
+      session.raw_set("wrap", create_fn_session_wrap(lua, secret.clone())?)?;
+      Ok(session)
+    })
+  }
+}
+
+struct SessionConfig {
+  secret: String,
+  name: String,
+  max_age: u64,
+  path: String,
+  secure: bool,
+  same_site: String,
+}
+
+impl SessionConfig {
+  fn from_lua(lua: &Lua, secret: String, opts: Option<Table>) -> mlua::Result<Self> {
+    let get_string = |key: &str, default: &str| {
+      (opts.as_ref())
+        .map(|x| x.check_raw_get::<Option<String>>(lua, key, "string"))
+        .transpose()
+        .map(|x| x.flatten().unwrap_or_else(|| default.into()))
+    };
+    let name = get_string("name", "session")?;
+    let path = get_string("path", "/")?;
+    let same_site = get_string("same_site", "Lax")?;
+    if !["Strict", "Lax", "None"].contains(&&*same_site) {
+      return Err(rt_error(
+        "bad field 'same_site' (must be one of 'Strict', 'Lax' and 'None')",
+      ));
+    }
+    let max_age = (opts.as_ref())
+      .map(|x| x.check_raw_get::<Option<u64>>(lua, "max_age", "non-negative integer"))
+      .transpose()?
+      .flatten()
+      .unwrap_or(86400);
+    let secure = (opts.as_ref())
+      .map(|x| x.check_raw_get::<Option<bool>>(lua, "secure", "boolean"))
+      .transpose()?
+      .flatten()
+      .unwrap_or(false);
+    // `SameSite=None` requires `Secure`
+    let secure = secure || same_site == "None";
+    Ok(Self {
+      secret,
+      name,
+      max_age,
+      path,
+      secure,
+      same_site,
+    })
+  }
+
+  fn set_cookie(&self, value: &str, max_age: u64) -> String {
+    let mut cookie = format!(
+      "{}={value}; Path={}; Max-Age={max_age}; HttpOnly; SameSite={}",
+      self.name, self.path, self.same_site
+    );
+    if self.secure {
+      cookie += "; Secure";
+    }
+    cookie
+  }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Payload {
+  /// Expiry time in seconds since Unix epoch.
+  #[serde(rename = "e")]
+  expires: u64,
+  #[serde(rename = "d")]
+  data: Map<String, serde_json::Value>,
+}
+
+fn now() -> u64 {
+  (SystemTime::now().duration_since(UNIX_EPOCH))
+    .map(|x| x.as_secs())
+    .unwrap_or(0)
+}
+
+fn encode(secret: &str, payload: &Payload) -> String {
+  let payload = BASE64URL_NOPAD.encode(&serde_json::to_vec(payload).unwrap());
+  let signature = hmac_sha256(secret.as_bytes(), payload.as_bytes());
+  format!("{payload}.{}", BASE64URL_NOPAD.encode(&signature))
+}
+
+/// Verifies and decodes a cookie value, returning `None` if it is invalid or
+/// expired.
+fn decode(secret: &str, value: &str, now: u64) -> Option<Payload> {
+  let (payload, signature) = value.split_once('.')?;
+  let signature = BASE64URL_NOPAD.decode(signature.as_bytes()).ok()?;
+  let expected = hmac_sha256(secret.as_bytes(), payload.as_bytes());
+  if !constant_time_eq(&signature, &expected) {
+    return None;
+  }
+  let payload = BASE64URL_NOPAD.decode(payload.as_bytes()).ok()?;
+  let payload = serde_json::from_slice::<Payload>(&payload).ok()?;
+  (payload.expires > now).then(|| payload)
+}
+
+fn get_cookie<'a>(
+  headers: impl IntoIterator<Item = &'a HeaderValue>,
+  name: &str,
+) -> Option<String> {
+  (headers.into_iter())
+    .filter_map(|x| x.to_str().ok())
+    .flat_map(|x| x.split(';'))
+    .filter_map(|x| x.trim().split_once('='))
+    .find(|(k, _)| *k == name)
+    .map(|(_, v)| v.to_owned())
+}
+
+/// Wraps a handler, so that `req.session` is available in it, and changes
+/// to the session are sent back as `Set-Cookie`.
+///
+/// Options:
+/// - `name`: cookie name, `session` by default
+/// - `max_age`: seconds until the session expires, 1 day by default
+/// - `path`: cookie path, `/` by default
+/// - `secure`: only send the cookie over HTTPS
+/// - `same_site`: one of `Strict`, `Lax` (default) and `None`
+fn create_fn_session_wrap(lua: &Lua, secret: Option<String>) -> mlua::Result<Function> {
+  lua.create_function(move |lua, mut args: MultiValue| {
+    let handler = match args.pop_front() {
+      Some(x @ (mlua::Value::Function(_) | mlua::Value::Table(_))) => x,
+      Some(x) => return Err(tag_error(lua, 1, "function", x.type_name(), 1)),
+      None => return Err(tag_error(lua, 1, "function", "no value", 1)),
+    };
+    let opts = check_value::<Option<Table>>(lua, args.pop_front().or(Some(Nil)), "table")
+      .map_err(tag_handler(lua, 2, 1))?;
+    let secret = (secret.clone()).ok_or_else(|| rt_error("session secret not configured"))?;
+    let config = Rc::new(SessionConfig::from_lua(lua, secret, opts)?);
+
+    let f = lua.create_async_function(move |lua, (handler, req): (mlua::Value, AnyUserData)| {
+      let config = config.clone();
+      async move {
+        let cookie = {
+          let req_ref = req.borrow::<LuaRequest>()?;
+          let headers = req_ref.headers.borrow();
+          get_cookie(headers.get_all(COOKIE), &config.name)
+        };
+        let data = (cookie.and_then(|x| decode(&config.secret, &x, now())))
+          .map(|x| x.data)
+          .unwrap_or_default();
+        let session = lua.create_userdata(LuaSession {
+          data,
+          state: SessionState::Unchanged,
+        })?;
+        req.set_named_user_value("session", session.clone())?;
+
+        let resp: LuaResponse = match handler {
+          mlua::Value::Function(f) => f.call_async(req).await?,
+          mlua::Value::Table(t) => t.call_async(req).await?,
+          _ => unreachable!(),
+        };
+
+        let session = session.borrow::<LuaSession>()?;
+        let cookie = match session.state {
+          SessionState::Unchanged => return Ok(resp),
+          SessionState::Modified => {
+            let payload = Payload {
+              expires: now() + config.max_age,
+              data: session.data.clone(),
+            };
+            let cookie = config.set_cookie(&encode(&config.secret, &payload), config.max_age);
+            if cookie.len() > MAX_COOKIE_SIZE {
+              return Err(rt_error_fmt!("session too large ({} bytes)", cookie.len()));
+            }
+            cookie
+          }
+          SessionState::Destroyed => config.set_cookie("", 0),
+        };
+        let cookie = HeaderValue::from_str(&cookie).map_err(rt_error)?;
+        resp.headers.borrow_mut().append(SET_COOKIE, cookie);
+        Ok(resp)
+      }
+    })?;
+    f.bind(handler)
+  })
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum SessionState {
+  Unchanged,
+  Modified,
+  Destroyed,
+}
+
+/// Session of the current request, available as `req.session`.
+///
+/// Values are converted to and from JSON.
+pub struct LuaSession {
+  data: Map<String, serde_json::Value>,
+  state: SessionState,
+}
+
+impl UserData for LuaSession {
+  fn add_methods<'lua, M: mlua::UserDataMethods<'lua, Self>>(methods: &mut M) {
+    methods.add_function("get", |lua, mut args: MultiValue| {
+      let this =
+        check_userdata::<Self>(args.pop_front(), "session").map_err(tag_handler(lua, 1, 1))?;
+      let key = check_string(lua, args.pop_front()).map_err(tag_handler(lua, 2, 1))?;
+      let key = key.to_str()?;
+      this.with_borrowed(|x| match x.data.get(key) {
+        Some(value) => lua.to_value(value),
+        None => Ok(Nil),
+      })
+    });
+
+    methods.add_function("set", |lua, mut args: MultiValue| {
+      let mut this =
+        check_userdata_mut::<Self>(args.pop_front(), "session").map_err(tag_handler(lua, 1, 1))?;
+      let key = check_string(lua, args.pop_front()).map_err(tag_handler(lua, 2, 1))?;
+      let key = key.to_str()?.to_owned();
+      let value = match args.pop_front().unwrap_or(Nil) {
+        Nil => None,
+        x => Some(lua.from_value::<serde_json::Value>(x)?),
+      };
+      this.with_borrowed_mut(|x| {
+        match value {
+          Some(value) => x.data.insert(key, value),
+          None => x.data.remove(&key),
+        };
+        x.state = SessionState::Modified;
+      });
+      Ok(())
+    });
+
+    methods.add_function("destroy", |lua, mut args: MultiValue| {
+      let mut this =
+        check_userdata_mut::<Self>(args.pop_front(), "session").map_err(tag_handler(lua, 1, 1))?;
+      this.with_borrowed_mut(|x| {
+        x.data.clear();
+        x.state = SessionState::Destroyed;
+      });
+      Ok(())
+    });
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use test_case::test_case;
+
+  fn payload(expires: u64) -> Payload {
+    let mut data = Map::new();
+    data.insert("user".into(), "abel".into());
+    Payload { expires, data }
+  }
+
+  #[test]
+  fn test_encode_decode() {
+    let cookie = encode("secret", &payload(100));
+    let decoded = decode("secret", &cookie, 50).unwrap();
+    assert_eq!(decoded.data["user"], "abel");
+  }
+
+  #[test_case("other", 50; "wrong secret")]
+  #[test_case("secret", 100; "expired")]
+  fn test_decode_invalid(secret: &str, now: u64) {
+    let cookie = encode("secret", &payload(100));
+    assert!(decode(secret, &cookie, now).is_none());
+  }
+
+  #[test]
+  fn test_decode_tampered() {
+    let cookie = encode("secret", &payload(100));
+    let (_, signature) = cookie.split_once('.').unwrap();
+    let other = BASE64URL_NOPAD.encode(br#"{"e":100,"d":{"user":"admin"}}"#);
+    assert!(decode("secret", &format!("{other}.{signature}"), 50).is_none());
+  }
+
+  #[test_case(&["a=1; session=x.y; b=2"] => Some("x.y".into()); "among others")]
+  #[test_case(&["a=1", "session=z"] => Some("z".into()); "multiple headers")]
+  #[test_case(&["sessions=1"] => None; "different name")]
+  fn test_get_cookie(headers: &[&str]) -> Option<String> {
+    let headers = (headers.iter())
+      .map(|x| HeaderValue::from_str(x).unwrap())
+      .collect::<Vec<_>>();
+    get_cookie(&headers, "session")
+  }
+}
@@ -0,0 +1,222 @@
+use crate::lua::error::{
+  check_integer, check_string, check_userdata_mut, net_error, rt_error, rt_error_fmt, tag_error,
+  tag_handler, UserDataRefMut,
+};
+use crate::net::{HostPattern, NetDenied, NetPolicy};
+use crate::task::TaskContext;
+use mlua::Value::Nil;
+use mlua::{AnyUserData, Function, Lua, MultiValue, UserData, UserDataMethods};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::time::timeout;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const READ_SIZE: usize = 8192;
+/// Largest buffer allocated for a single `read`, whatever length is asked for.
+const MAX_READ_SIZE: usize = 64 * 1024;
+const MAX_DATAGRAM_SIZE: usize = 65507;
+
+/// Creates the `socket` module. Connections must match `allowed`, and
+/// `policy` as well if the `net` permission has rules.
+pub fn create_preload_socket(
+  allowed: Arc<[HostPattern]>,
+  policy: Option<Arc<NetPolicy>>,
+) -> impl FnOnce(&Lua) -> mlua::Result<Function> {
+  |lua| {
+    lua.create_function(move |lua, ()| {
+      let socket = lua.create_table()?;
+      let connect = create_fn_socket_connect(lua, allowed.clone(), policy.clone())?;
+      let udp = create_fn_socket_udp(lua, allowed.clone(), policy.clone())?;
+      socket.raw_set("connect", connect)?;
+      socket.raw_set("udp", udp)?;
+      Ok(socket)
+    })
+  }
+}
+
+/// Checks arguments `host` and `port` against the service's allowed patterns.
+fn check_address(
+  lua: &Lua,
+  args: &mut MultiValue,
+  allowed: &[HostPattern],
+) -> mlua::Result<(String, u16)> {
+  let host = check_string(lua, args.pop_front()).map_err(tag_handler(lua, 1, 1))?;
+  let host = host.to_str().map_err(rt_error)?.to_owned();
+  let port = check_integer(args.pop_front()).map_err(tag_handler(lua, 2, 1))?;
+  let port = u16::try_from(port).map_err(|_| rt_error_fmt!("invalid port: {port}"))?;
+  if !allowed.iter().any(|x| x.matches(&host, port)) {
+    let denied = NetDenied {
+      host: host.into(),
+      port: Some(port),
+    };
+    return Err(net_error(lua, denied));
+  }
+  Ok((host, port))
+}
+
+fn create_fn_socket_connect(
+  lua: &Lua,
+  allowed: Arc<[HostPattern]>,
+  policy: Option<Arc<NetPolicy>>,
+) -> mlua::Result<Function> {
+  lua.create_async_function(move |lua, mut args: MultiValue| {
+    let allowed = allowed.clone();
+    let policy = policy.clone();
+    async move {
+      let (host, port) = check_address(lua, &mut args, &allowed)?;
+      let addr = match &policy {
+        Some(policy) => {
+          Some((policy.resolve(&host, port).await).map_err(|error| net_error(lua, error))?)
+        }
+        None => None,
+      };
+      let connect = async {
+        match addr {
+          Some(addr) => TcpStream::connect(addr).await,
+          None => TcpStream::connect((&*host, port)).await,
+        }
+      };
+      let limit = TaskContext::cap_timeout(lua, Some(CONNECT_TIMEOUT)).unwrap();
+      let stream = (timeout(limit, connect).await)
+        .map_err(|_| rt_error_fmt!("connecting to {host}:{port} timed out"))?
+        .map_err(|error| rt_error_fmt!("failed to connect to {host}:{port} ({error})"))?;
+      Ok(LuaTcpSocket(stream))
+    }
+  })
+}
+
+fn create_fn_socket_udp(
+  lua: &Lua,
+  allowed: Arc<[HostPattern]>,
+  policy: Option<Arc<NetPolicy>>,
+) -> mlua::Result<Function> {
+  lua.create_async_function(move |lua, mut args: MultiValue| {
+    let allowed = allowed.clone();
+    let policy = policy.clone();
+    async move {
+      let (host, port) = check_address(lua, &mut args, &allowed)?;
+      let addr = match &policy {
+        Some(policy) => {
+          (policy.resolve(&host, port).await).map_err(|error| net_error(lua, error))?
+        }
+        None => (tokio::net::lookup_host((&*host, port)).await)
+          .map_err(|error| rt_error_fmt!("failed to resolve {host} ({error})"))?
+          .next()
+          .ok_or_else(|| rt_error_fmt!("failed to resolve {host}"))?,
+      };
+      let local = if addr.is_ipv4() {
+        "0.0.0.0:0"
+      } else {
+        "[::]:0"
+      };
+      let socket = UdpSocket::bind(local).await.map_err(rt_error)?;
+      socket.connect(addr).await.map_err(rt_error)?;
+      Ok(LuaUdpSocket(socket))
+    }
+  })
+}
+
+/// Checks that every argument from `pos` on is a string, and concatenates
+/// them.
+fn check_data(lua: &Lua, args: MultiValue, pos: usize) -> mlua::Result<Vec<u8>> {
+  let mut data = Vec::new();
+  for (i, x) in args.into_iter().enumerate() {
+    let type_name = x.type_name();
+    let x = (lua.coerce_string(x).ok().flatten())
+      .ok_or_else(|| tag_error(lua, i + pos, "string", type_name, 1))?;
+    data.extend_from_slice(x.as_bytes());
+  }
+  Ok(data)
+}
+
+/// Connected TCP socket.
+///
+/// `read(n)` returns at most `n` bytes (8 KiB by default, 64 KiB at most) as
+/// soon as any is available, or `nil` when the peer has closed the connection.
+pub struct LuaTcpSocket(TcpStream);
+
+impl UserData for LuaTcpSocket {
+  fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+    fn check_self<'lua>(
+      lua: &'lua Lua,
+      value: Option<mlua::Value<'lua>>,
+    ) -> mlua::Result<UserDataRefMut<'lua, LuaTcpSocket>> {
+      check_userdata_mut(value, "TCP socket").map_err(tag_handler(lua, 1, 1))
+    }
+
+    async fn close(_lua: &Lua, this: AnyUserData<'_>) -> mlua::Result<()> {
+      if let Ok(mut this) = this.take::<LuaTcpSocket>() {
+        this.0.shutdown().await.map_err(rt_error)?;
+      }
+      Ok(())
+    }
+
+    methods.add_async_meta_function("__close", close);
+    methods.add_async_function("close", close);
+
+    methods.add_async_function("read", |lua, mut args: MultiValue| async move {
+      let mut this = check_self(lua, args.pop_front())?;
+      let len = match args.pop_front() {
+        None | Some(Nil) => READ_SIZE,
+        x => check_integer(x)
+          .map_err(tag_handler(lua, 2, 1))?
+          .try_into()
+          .map_err(|_| rt_error("length must be non-negative"))?,
+      };
+      let mut buf = vec![0; len.min(MAX_READ_SIZE)];
+      let len = (this.with_borrowed_mut(|x| x.0.read(&mut buf)).await).map_err(rt_error)?;
+      if len == 0 && !buf.is_empty() {
+        Ok(Nil)
+      } else {
+        Ok(mlua::Value::String(lua.create_string(&buf[..len])?))
+      }
+    });
+
+    methods.add_async_function("write", |lua, mut args: MultiValue| async move {
+      let mut this = check_self(lua, args.pop_front())?;
+      let data = check_data(lua, args, 2)?;
+      (this.with_borrowed_mut(|x| x.0.write_all(&data)).await).map_err(rt_error)?;
+      Ok(this.into_any())
+    });
+  }
+}
+
+/// Connected UDP socket.
+///
+/// `recv()` waits for the next datagram and returns it.
+pub struct LuaUdpSocket(UdpSocket);
+
+impl UserData for LuaUdpSocket {
+  fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+    fn check_self<'lua>(
+      lua: &'lua Lua,
+      value: Option<mlua::Value<'lua>>,
+    ) -> mlua::Result<UserDataRefMut<'lua, LuaUdpSocket>> {
+      check_userdata_mut(value, "UDP socket").map_err(tag_handler(lua, 1, 1))
+    }
+
+    fn close(_lua: &Lua, this: AnyUserData) -> mlua::Result<()> {
+      let _ = this.take::<LuaUdpSocket>();
+      Ok(())
+    }
+
+    methods.add_meta_function("__close", close);
+    methods.add_function("close", close);
+
+    methods.add_async_function("send", |lua, mut args: MultiValue| async move {
+      let mut this = check_self(lua, args.pop_front())?;
+      let data = check_data(lua, args, 2)?;
+      let len = (this.with_borrowed_mut(|x| x.0.send(&data)).await).map_err(rt_error)?;
+      Ok(len)
+    });
+
+    methods.add_async_function("recv", |lua, mut args: MultiValue| async move {
+      let mut this = check_self(lua, args.pop_front())?;
+      let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+      let len = (this.with_borrowed_mut(|x| x.0.recv(&mut buf)).await).map_err(rt_error)?;
+      lua.create_string(&buf[..len])
+    });
+  }
+}
@@ -1,5 +1,6 @@
 use super::json::create_fn_json_parse;
 use crate::lua::error::{check_userdata_mut, rt_error, tag_handler};
+use crate::lua::fs::LuaFile;
 use crate::lua::LuaCacheExt;
 use futures::stream::BoxStream;
 use futures::{StreamExt, TryStreamExt};
@@ -7,7 +8,7 @@ use hyper::body::Bytes;
 use hyper::Body;
 use mlua::Value::Nil;
 use mlua::{AnyUserData, Lua, MultiValue, UserData, UserDataFields, UserDataMethods};
-use tokio::io::AsyncRead;
+use tokio::io::{AsyncRead, AsyncWriteExt};
 use tokio_util::io::ReaderStream;
 
 /// - Stream: `stream<T>:read() -> T?`
@@ -78,5 +79,47 @@ impl UserData for ByteStream {
       };
       Ok(value)
     });
+
+    // Writes the rest of the stream to a sink, returning the number of bytes
+    // written. Files are written directly, without a round trip through Lua
+    // for each chunk.
+    methods.add_async_function("save_to", |lua, mut args: MultiValue| async move {
+      let this = args.pop_front();
+      let sink = args.pop_front().unwrap_or(Nil);
+      if !matches!(&sink, mlua::Value::UserData(u) if u.is::<LuaFile>()) {
+        let save: mlua::Function = lua.create_cached_value("abel:stream_save_to", || {
+          const SRC: &str = r#"
+            local st, sink = ...
+            local type_sink = type(sink)
+            if type_sink ~= "table" and type_sink ~= "userdata" or not sink.write then
+              error("bad argument #2 to 'save_to' (sink expected, got " .. type_sink .. ")", 0)
+            end
+            local len = 0
+            while true do
+              local bytes = st:read()
+              if not bytes then break end
+              sink:write(bytes)
+              len = len + #bytes
+            end
+            return len
+          "#;
+          lua.load(SRC).into_function()
+        })?;
+        check_userdata_mut::<Self>(this.clone(), "byte stream").map_err(tag_handler(lua, 1, 1))?;
+        return save.call_async::<_, u64>((this, sink)).await;
+      }
+
+      let mut this =
+        check_userdata_mut::<Self>(this, "byte stream").map_err(tag_handler(lua, 1, 1))?;
+      let mut file =
+        check_userdata_mut::<LuaFile>(Some(sink), "file").map_err(tag_handler(lua, 2, 1))?;
+      let mut len = 0;
+      while let Some(bytes) = this.with_borrowed_mut(|x| x.0.try_next()).await? {
+        (file.with_borrowed_mut(|x| x.0.write_all(&bytes)).await).map_err(rt_error)?;
+        len += bytes.len() as u64;
+      }
+      (file.with_borrowed_mut(|x| x.0.flush()).await).map_err(rt_error)?;
+      Ok(len)
+    });
   }
 }
@@ -0,0 +1,242 @@
+use crate::lua::error::{arg_error, check_integer, check_string, check_value, tag_handler};
+use crate::lua::LuaCacheExt;
+use bstr::ByteSlice;
+use caseless::default_caseless_match;
+use mlua::Value::Nil;
+use mlua::{Function, Lua, MultiValue, Table};
+
+// Functions here treat strings as UTF-8, but never fail on invalid sequences:
+// each invalid byte counts as a single character and is kept as is.
+//
+// Case mapping, normalization and grapheme clusters are left to `unicode`,
+// which requires valid UTF-8.
+
+pub fn create_preload_strings(lua: &Lua) -> mlua::Result<Function> {
+  lua.create_cached_function("abel:preload_strings", |lua, ()| {
+    let strings = lua.create_table()?;
+    strings.raw_set("len", create_fn_len(lua)?)?;
+    strings.raw_set("sub", create_fn_sub(lua)?)?;
+    strings.raw_set("equal_fold", create_fn_equal_fold(lua)?)?;
+    let trim = create_fn_trim(lua, "abel:strings.trim", Trim::Both)?;
+    let trim_start = create_fn_trim(lua, "abel:strings.trim_start", Trim::Start)?;
+    let trim_end = create_fn_trim(lua, "abel:strings.trim_end", Trim::End)?;
+    strings.raw_set("trim", trim)?;
+    strings.raw_set("trim_start", trim_start)?;
+    strings.raw_set("trim_end", trim_end)?;
+    strings.raw_set("split", create_fn_split(lua)?)?;
+    strings.raw_set("levenshtein", create_fn_levenshtein(lua)?)?;
+    strings.raw_set("chars", create_fn_chars(lua)?)?;
+    strings.raw_set("codepoints", create_fn_codepoints(lua)?)?;
+    strings.raw_set("from_codepoints", create_fn_from_codepoints(lua)?)?;
+    strings.raw_set("bytes", create_fn_bytes(lua)?)?;
+    strings.raw_set("from_bytes", create_fn_from_bytes(lua)?)?;
+    Ok(strings)
+  })
+}
+
+/// Returns the number of characters.
+fn create_fn_len(lua: &Lua) -> mlua::Result<Function> {
+  lua.create_cached_function("abel:strings.len", |lua, mut args: MultiValue| {
+    let s = check_string(lua, args.pop_front()).map_err(tag_handler(lua, 1, 0))?;
+    Ok(s.as_bytes().chars().count())
+  })
+}
+
+/// Same as `string.sub`, but indices are of characters instead of bytes.
+fn create_fn_sub(lua: &Lua) -> mlua::Result<Function> {
+  lua.create_cached_function("abel:strings.sub", |lua, mut args: MultiValue| {
+    let s = check_string(lua, args.pop_front()).map_err(tag_handler(lua, 1, 0))?;
+    let i = match args.pop_front() {
+      None | Some(Nil) => 1,
+      x => check_integer(x).map_err(tag_handler(lua, 2, 0))?,
+    };
+    let j = match args.pop_front() {
+      None | Some(Nil) => -1,
+      x => check_integer(x).map_err(tag_handler(lua, 3, 0))?,
+    };
+
+    let bytes = s.as_bytes();
+    let indices = bytes.char_indices().collect::<Vec<_>>();
+    let len = indices.len() as i64;
+    let i = match i {
+      i if i < 0 => (len + i + 1).max(1),
+      0 => 1,
+      i => i,
+    };
+    let j = match j {
+      j if j < 0 => len + j + 1,
+      j => j.min(len),
+    };
+    if i > j {
+      lua.create_string("")
+    } else {
+      let start = indices[(i - 1) as usize].0;
+      let end = indices[(j - 1) as usize].1;
+      lua.create_string(&bytes[start..end])
+    }
+  })
+}
+
+/// Checks if two strings are equal under Unicode default case folding, e.g.
+/// "Straße" and "STRASSE".
+fn create_fn_equal_fold(lua: &Lua) -> mlua::Result<Function> {
+  lua.create_cached_function("abel:strings.equal_fold", |lua, mut args: MultiValue| {
+    let a = check_string(lua, args.pop_front()).map_err(tag_handler(lua, 1, 0))?;
+    let b = check_string(lua, args.pop_front()).map_err(tag_handler(lua, 2, 0))?;
+    Ok(default_caseless_match(
+      a.as_bytes().chars(),
+      b.as_bytes().chars(),
+    ))
+  })
+}
+
+#[derive(Clone, Copy)]
+enum Trim {
+  Both,
+  Start,
+  End,
+}
+
+/// Removes leading and/or trailing whitespace, or any of the characters in
+/// the second argument if present.
+fn create_fn_trim<'lua>(lua: &'lua Lua, key: &str, trim: Trim) -> mlua::Result<Function<'lua>> {
+  lua.create_cached_function(key, move |lua, mut args: MultiValue| {
+    let s = check_string(lua, args.pop_front()).map_err(tag_handler(lua, 1, 0))?;
+    let s = s.as_bytes();
+    let chars = match args.pop_front() {
+      None | Some(Nil) => None,
+      x => {
+        let chars = check_string(lua, x).map_err(tag_handler(lua, 2, 0))?;
+        Some(chars.as_bytes().chars().collect::<Vec<_>>())
+      }
+    };
+    let result = match (chars, trim) {
+      (None, Trim::Both) => s.trim(),
+      (None, Trim::Start) => s.trim_start(),
+      (None, Trim::End) => s.trim_end(),
+      (Some(chars), Trim::Both) => s.trim_with(|c| chars.contains(&c)),
+      (Some(chars), Trim::Start) => s.trim_start_with(|c| chars.contains(&c)),
+      (Some(chars), Trim::End) => s.trim_end_with(|c| chars.contains(&c)),
+    };
+    lua.create_string(result)
+  })
+}
+
+/// Splits a string by a literal separator, into at most `limit` parts if
+/// given.
+fn create_fn_split(lua: &Lua) -> mlua::Result<Function> {
+  lua.create_cached_function("abel:strings.split", |lua, mut args: MultiValue| {
+    let s = check_string(lua, args.pop_front()).map_err(tag_handler(lua, 1, 0))?;
+    let sep = check_string(lua, args.pop_front()).map_err(tag_handler(lua, 2, 0))?;
+    if sep.as_bytes().is_empty() {
+      return Err(arg_error(lua, 2, "separator must not be empty", 0));
+    }
+    let parts = match args.pop_front() {
+      None | Some(Nil) => s.as_bytes().split_str(sep.as_bytes()).collect::<Vec<_>>(),
+      x => {
+        let limit = check_integer(x).map_err(tag_handler(lua, 3, 0))?;
+        let limit = usize::try_from(limit)
+          .ok()
+          .filter(|&x| x > 0)
+          .ok_or_else(|| arg_error(lua, 3, "limit must be positive", 0))?;
+        s.as_bytes().splitn_str(limit, sep.as_bytes()).collect()
+      }
+    };
+    let result = lua.create_table_with_capacity(parts.len() as _, 0)?;
+    for (i, part) in parts.into_iter().enumerate() {
+      result.raw_set(i + 1, lua.create_string(part)?)?;
+    }
+    Ok(result)
+  })
+}
+
+/// Edit distance between two strings, counted in characters.
+fn levenshtein(a: &[u8], b: &[u8]) -> usize {
+  let b = b.chars().collect::<Vec<_>>();
+  let mut row = (0..=b.len()).collect::<Vec<_>>();
+  for (i, ca) in a.chars().enumerate() {
+    let mut diagonal = row[0];
+    row[0] = i + 1;
+    for (j, &cb) in b.iter().enumerate() {
+      let substitution = diagonal + usize::from(ca != cb);
+      diagonal = row[j + 1];
+      row[j + 1] = substitution.min(row[j] + 1).min(diagonal + 1);
+    }
+  }
+  row[b.len()]
+}
+
+fn create_fn_levenshtein(lua: &Lua) -> mlua::Result<Function> {
+  lua.create_cached_function("abel:strings.levenshtein", |lua, mut args: MultiValue| {
+    let a = check_string(lua, args.pop_front()).map_err(tag_handler(lua, 1, 0))?;
+    let b = check_string(lua, args.pop_front()).map_err(tag_handler(lua, 2, 0))?;
+    Ok(levenshtein(a.as_bytes(), b.as_bytes()))
+  })
+}
+
+/// Splits a string into an array of characters.
+fn create_fn_chars(lua: &Lua) -> mlua::Result<Function> {
+  lua.create_cached_function("abel:strings.chars", |lua, mut args: MultiValue| {
+    let s = check_string(lua, args.pop_front()).map_err(tag_handler(lua, 1, 0))?;
+    let s = s.as_bytes();
+    let result = lua.create_table()?;
+    for (i, (start, end, _)) in s.char_indices().enumerate() {
+      result.raw_set(i + 1, lua.create_string(&s[start..end])?)?;
+    }
+    Ok(result)
+  })
+}
+
+/// Returns an array of code points. Invalid bytes become U+FFFD.
+fn create_fn_codepoints(lua: &Lua) -> mlua::Result<Function> {
+  lua.create_cached_function("abel:strings.codepoints", |lua, mut args: MultiValue| {
+    let s = check_string(lua, args.pop_front()).map_err(tag_handler(lua, 1, 0))?;
+    lua.create_sequence_from(s.as_bytes().chars().map(u32::from))
+  })
+}
+
+fn create_fn_from_codepoints(lua: &Lua) -> mlua::Result<Function> {
+  lua.create_cached_function(
+    "abel:strings.from_codepoints",
+    |lua, mut args: MultiValue| {
+      let t: Table = check_value(lua, args.pop_front(), "table").map_err(tag_handler(lua, 1, 0))?;
+      let mut result = String::new();
+      for x in t.raw_sequence_values::<u32>() {
+        let c = char::from_u32(x?).ok_or_else(|| arg_error(lua, 1, "invalid code point", 0))?;
+        result.push(c);
+      }
+      Ok(result)
+    },
+  )
+}
+
+/// Returns an array of bytes.
+fn create_fn_bytes(lua: &Lua) -> mlua::Result<Function> {
+  lua.create_cached_function("abel:strings.bytes", |lua, mut args: MultiValue| {
+    let s = check_string(lua, args.pop_front()).map_err(tag_handler(lua, 1, 0))?;
+    lua.create_sequence_from(s.as_bytes().iter().copied())
+  })
+}
+
+fn create_fn_from_bytes(lua: &Lua) -> mlua::Result<Function> {
+  lua.create_cached_function("abel:strings.from_bytes", |lua, mut args: MultiValue| {
+    let t: Table = check_value(lua, args.pop_front(), "table").map_err(tag_handler(lua, 1, 0))?;
+    let result = (t.raw_sequence_values::<u8>()).collect::<mlua::Result<Vec<_>>>()?;
+    lua.create_string(&result)
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use test_case::test_case;
+
+  #[test_case("", "" => 0; "empty")]
+  #[test_case("kitten", "sitting" => 3; "ascii")]
+  #[test_case("flaw", "lawn" => 2; "insert and delete")]
+  #[test_case("héllo", "hello" => 1; "counts characters")]
+  #[test_case("abc", "" => 3; "to empty")]
+  fn test_levenshtein(a: &str, b: &str) -> usize {
+    levenshtein(a.as_bytes(), b.as_bytes())
+  }
+}
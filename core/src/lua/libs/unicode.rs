@@ -0,0 +1,140 @@
+use crate::lua::error::{arg_error, check_integer, check_string, tag_handler};
+use crate::lua::LuaCacheExt;
+use mlua::Value::Nil;
+use mlua::{Function, Lua, MultiValue};
+use unicode_normalization::UnicodeNormalization;
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
+
+pub fn create_preload_unicode(lua: &Lua) -> mlua::Result<Function> {
+  lua.create_cached_function("abel:preload_unicode", |lua, ()| {
+    let unicode = lua.create_table()?;
+    unicode.raw_set("len", create_fn_len(lua)?)?;
+    unicode.raw_set("sub", create_fn_sub(lua)?)?;
+    unicode.raw_set("graphemes", create_fn_graphemes(lua)?)?;
+    unicode.raw_set("width", create_fn_width(lua)?)?;
+    unicode.raw_set("lower", create_fn_lower(lua)?)?;
+    unicode.raw_set("upper", create_fn_upper(lua)?)?;
+    unicode.raw_set("casefold", create_fn_casefold(lua)?)?;
+    unicode.raw_set("normalize", create_fn_normalize(lua)?)?;
+    Ok(unicode)
+  })
+}
+
+fn check_utf8<'lua>(
+  lua: &'lua Lua,
+  value: Option<mlua::Value<'lua>>,
+  pos: usize,
+) -> mlua::Result<mlua::String<'lua>> {
+  let s = check_string(lua, value).map_err(tag_handler(lua, pos, 0))?;
+  if std::str::from_utf8(s.as_bytes()).is_err() {
+    return Err(arg_error(lua, pos, "invalid UTF-8", 0));
+  }
+  Ok(s)
+}
+
+fn create_fn_len(lua: &Lua) -> mlua::Result<Function> {
+  lua.create_cached_function("abel:unicode.len", |lua, mut args: MultiValue| {
+    let s = check_utf8(lua, args.pop_front(), 1)?;
+    let s = s.to_str()?;
+    Ok(s.graphemes(true).count())
+  })
+}
+
+fn create_fn_graphemes(lua: &Lua) -> mlua::Result<Function> {
+  lua.create_cached_function("abel:unicode.graphemes", |lua, mut args: MultiValue| {
+    let s = check_utf8(lua, args.pop_front(), 1)?;
+    let s = s.to_str()?;
+    lua.create_sequence_from(s.graphemes(true))
+  })
+}
+
+fn create_fn_width(lua: &Lua) -> mlua::Result<Function> {
+  lua.create_cached_function("abel:unicode.width", |lua, mut args: MultiValue| {
+    let s = check_utf8(lua, args.pop_front(), 1)?;
+    let s = s.to_str()?;
+    Ok(s.width())
+  })
+}
+
+fn create_fn_lower(lua: &Lua) -> mlua::Result<Function> {
+  lua.create_cached_function("abel:unicode.lower", |lua, mut args: MultiValue| {
+    let s = check_utf8(lua, args.pop_front(), 1)?;
+    let s = s.to_str()?;
+    Ok(s.to_lowercase())
+  })
+}
+
+fn create_fn_upper(lua: &Lua) -> mlua::Result<Function> {
+  lua.create_cached_function("abel:unicode.upper", |lua, mut args: MultiValue| {
+    let s = check_utf8(lua, args.pop_front(), 1)?;
+    let s = s.to_str()?;
+    Ok(s.to_uppercase())
+  })
+}
+
+fn create_fn_casefold(lua: &Lua) -> mlua::Result<Function> {
+  lua.create_cached_function("abel:unicode.casefold", |lua, mut args: MultiValue| {
+    let s = check_utf8(lua, args.pop_front(), 1)?;
+    let s = s.to_str()?;
+    Ok(caseless::default_case_fold_str(s))
+  })
+}
+
+fn create_fn_sub(lua: &Lua) -> mlua::Result<Function> {
+  lua.create_cached_function("abel:unicode.sub", |lua, mut args: MultiValue| {
+    let s = check_utf8(lua, args.pop_front(), 1)?;
+    let i = match args.pop_front() {
+      None | Some(Nil) => 1,
+      x => check_integer(x).map_err(tag_handler(lua, 2, 0))?,
+    };
+    let j = match args.pop_front() {
+      None | Some(Nil) => -1,
+      x => check_integer(x).map_err(tag_handler(lua, 3, 0))?,
+    };
+
+    // Same index semantics as `string.sub`, but on grapheme clusters
+    let graphemes = s.to_str()?.graphemes(true).collect::<Vec<_>>();
+    let len = graphemes.len() as i64;
+    let i = match i {
+      i if i < 0 => (len + i + 1).max(1),
+      0 => 1,
+      i => i,
+    };
+    let j = match j {
+      j if j < 0 => len + j + 1,
+      j => j.min(len),
+    };
+    if i > j {
+      Ok(String::new())
+    } else {
+      Ok(graphemes[(i - 1) as usize..j as usize].concat())
+    }
+  })
+}
+
+fn create_fn_normalize(lua: &Lua) -> mlua::Result<Function> {
+  lua.create_cached_function("abel:unicode.normalize", |lua, mut args: MultiValue| {
+    let s = check_utf8(lua, args.pop_front(), 1)?;
+    let s = s.to_str()?;
+    let form = match args.pop_front() {
+      None | Some(Nil) => None,
+      x => Some(check_string(lua, x).map_err(tag_handler(lua, 2, 0))?),
+    };
+    let result = match form.as_ref().map(|x| x.as_bytes()) {
+      None | Some(b"NFC") => s.nfc().collect::<String>(),
+      Some(b"NFD") => s.nfd().collect(),
+      Some(b"NFKC") => s.nfkc().collect(),
+      Some(b"NFKD") => s.nfkd().collect(),
+      Some(_) => {
+        return Err(arg_error(
+          lua,
+          2,
+          "normalization form must be one of 'NFC', 'NFD', 'NFKC' and 'NFKD'",
+          0,
+        ))
+      }
+    };
+    Ok(result)
+  })
+}
@@ -0,0 +1,233 @@
+use crate::lua::error::{check_string, check_value, tag_handler};
+use crate::lua::LuaCacheExt;
+use mlua::Value::Nil;
+use mlua::{Function, Lua, MultiValue, Table};
+use once_cell::sync::Lazy;
+use regex::Regex;
+
+pub fn create_preload_validate(lua: &Lua) -> mlua::Result<Function> {
+  lua.create_cached_function("abel:preload_validate", |lua, ()| {
+    let validate = lua.create_table()?;
+    validate.raw_set("email", create_fn_email(lua)?)?;
+    validate.raw_set("phone", create_fn_phone(lua)?)?;
+    validate.raw_set("url", create_fn_url(lua)?)?;
+    validate.raw_set("iban", create_fn_iban(lua)?)?;
+    validate.raw_set("card", create_fn_card(lua)?)?;
+    Ok(validate)
+  })
+}
+
+fn create_fn_email(lua: &Lua) -> mlua::Result<Function> {
+  lua.create_cached_function("abel:validate.email", |lua, mut args: MultiValue| {
+    let s = check_string(lua, args.pop_front()).map_err(tag_handler(lua, 1, 0))?;
+    Ok(s.to_str().map(is_valid_email).unwrap_or(false))
+  })
+}
+
+/// Parses a phone number in international format, returning it in E.164 form,
+/// i.e. `+` followed by up to 15 digits.
+fn create_fn_phone(lua: &Lua) -> mlua::Result<Function> {
+  lua.create_cached_function("abel:validate.phone", |lua, mut args: MultiValue| {
+    let s = check_string(lua, args.pop_front()).map_err(tag_handler(lua, 1, 0))?;
+    Ok(s.to_str().ok().and_then(parse_e164))
+  })
+}
+
+/// Checks if a string is an absolute URL, optionally with one of the given
+/// schemes.
+fn create_fn_url(lua: &Lua) -> mlua::Result<Function> {
+  lua.create_cached_function("abel:validate.url", |lua, mut args: MultiValue| {
+    let s = check_string(lua, args.pop_front()).map_err(tag_handler(lua, 1, 0))?;
+    let schemes = match args.pop_front() {
+      None | Some(Nil) => None,
+      x => Some(check_value::<Table>(lua, x, "table").map_err(tag_handler(lua, 2, 0))?),
+    };
+    let url = match s.to_str().ok().and_then(|x| url::Url::parse(x).ok()) {
+      Some(url) => url,
+      None => return Ok(false),
+    };
+    if let Some(schemes) = schemes {
+      for scheme in schemes.raw_sequence_values::<mlua::String>() {
+        if scheme?.as_bytes() == url.scheme().as_bytes() {
+          return Ok(true);
+        }
+      }
+      Ok(false)
+    } else {
+      Ok(true)
+    }
+  })
+}
+
+fn create_fn_iban(lua: &Lua) -> mlua::Result<Function> {
+  lua.create_cached_function("abel:validate.iban", |lua, mut args: MultiValue| {
+    let s = check_string(lua, args.pop_front()).map_err(tag_handler(lua, 1, 0))?;
+    Ok(s.to_str().map(is_valid_iban).unwrap_or(false))
+  })
+}
+
+/// Checks a payment card number with the Luhn algorithm.
+fn create_fn_card(lua: &Lua) -> mlua::Result<Function> {
+  lua.create_cached_function("abel:validate.card", |lua, mut args: MultiValue| {
+    let s = check_string(lua, args.pop_front()).map_err(tag_handler(lua, 1, 0))?;
+    Ok(s.to_str().map(is_valid_card).unwrap_or(false))
+  })
+}
+
+/// Valid email address as defined by the WHATWG HTML standard.
+fn is_valid_email(s: &str) -> bool {
+  static EMAIL_REGEX: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(concat!(
+      r"^[a-zA-Z0-9.!#$%&'*+/=?^_`{|}~-]+@",
+      r"[a-zA-Z0-9](?:[a-zA-Z0-9-]{0,61}[a-zA-Z0-9])?",
+      r"(?:\.[a-zA-Z0-9](?:[a-zA-Z0-9-]{0,61}[a-zA-Z0-9])?)*$",
+    ))
+    .unwrap()
+  });
+  EMAIL_REGEX.is_match(s)
+}
+
+fn parse_e164(s: &str) -> Option<String> {
+  let s = s.trim();
+  let s = (s.strip_prefix('+'))
+    .or_else(|| s.strip_prefix("00"))?
+    .trim_start();
+
+  let mut digits = String::with_capacity(16);
+  digits.push('+');
+  for c in s.chars() {
+    match c {
+      '0'..='9' => digits.push(c),
+      ' ' | '-' | '.' | '(' | ')' => {}
+      _ => return None,
+    }
+  }
+  // Country codes never start with 0; at least a few subscriber digits follow
+  let len = digits.len() - 1;
+  (!digits[1..].starts_with('0') && (7..=15).contains(&len)).then_some(digits)
+}
+
+fn is_valid_iban(s: &str) -> bool {
+  let iban = s
+    .chars()
+    .filter(|c| !c.is_ascii_whitespace())
+    .map(|c| c.to_ascii_uppercase())
+    .collect::<String>();
+  if !iban.chars().all(|c| c.is_ascii_alphanumeric()) || iban.len() < 4 {
+    return false;
+  }
+  let (country, rest) = iban.split_at(2);
+  if !country.chars().all(|c| c.is_ascii_alphabetic())
+    || !rest[..2].chars().all(|c| c.is_ascii_digit())
+  {
+    return false;
+  }
+  match iban_length(country) {
+    Some(len) if len == iban.len() => {}
+    _ => return false,
+  }
+
+  // Move the first four characters to the end, convert letters to numbers
+  // (A = 10, ..., Z = 35), and check if the result mod 97 is 1
+  let remainder = (iban[4..].chars())
+    .chain(iban[..4].chars())
+    .fold(0u32, |acc, c| {
+      let n = c.to_digit(36).unwrap();
+      if n < 10 {
+        (acc * 10 + n) % 97
+      } else {
+        (acc * 100 + n) % 97
+      }
+    });
+  remainder == 1
+}
+
+/// IBAN lengths of countries in the SWIFT IBAN registry.
+fn iban_length(country: &str) -> Option<usize> {
+  let len = match country {
+    "NO" => 15,
+    "BE" => 16,
+    "DK" | "FI" | "FO" | "GL" | "NL" => 18,
+    "MK" | "SI" => 19,
+    "AT" | "BA" | "EE" | "KZ" | "LT" | "LU" | "XK" => 20,
+    "CH" | "CR" | "HR" | "LI" | "LV" => 21,
+    "BG" | "BH" | "DE" | "GB" | "GE" | "IE" | "ME" | "RS" | "VA" => 22,
+    "AE" | "GI" | "IL" | "IQ" | "TL" => 23,
+    "AD" | "CZ" | "ES" | "MD" | "PK" | "RO" | "SA" | "SE" | "SK" | "TN" | "VG" => 24,
+    "LY" | "PT" | "ST" => 25,
+    "IS" | "TR" => 26,
+    "FR" | "GR" | "IT" | "MC" | "MR" | "SM" => 27,
+    "AL" | "AZ" | "BY" | "CY" | "DO" | "GT" | "HU" | "LB" | "PL" | "SV" => 28,
+    "BR" | "EG" | "PS" | "QA" | "UA" => 29,
+    "JO" | "KW" | "MU" => 30,
+    "MT" | "SC" => 31,
+    "LC" => 32,
+    "RU" => 33,
+    _ => return None,
+  };
+  Some(len)
+}
+
+fn is_valid_card(s: &str) -> bool {
+  let mut digits = Vec::with_capacity(19);
+  for c in s.chars() {
+    match c {
+      '0'..='9' => digits.push(c as u32 - '0' as u32),
+      ' ' | '-' => {}
+      _ => return false,
+    }
+  }
+  if !(12..=19).contains(&digits.len()) {
+    return false;
+  }
+  let sum = (digits.iter().rev().enumerate())
+    .map(|(i, &d)| match (i % 2, d * 2) {
+      (0, _) => d,
+      (_, d) if d > 9 => d - 9,
+      (_, d) => d,
+    })
+    .sum::<u32>();
+  sum % 10 == 0
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use test_case::test_case;
+
+  #[test_case("user@example.com" => true; "simple")]
+  #[test_case("first.last+tag@sub.example.co" => true; "dots and plus")]
+  #[test_case("user@localhost" => true; "single label")]
+  #[test_case("user@-example.com" => false; "leading hyphen")]
+  #[test_case("user@@example.com" => false; "double at")]
+  #[test_case("\"quoted\"@example.com" => false; "quoted local part")]
+  fn test_email(s: &str) -> bool {
+    is_valid_email(s)
+  }
+
+  #[test_case("+1 (415) 555-2671" => Some("+14155552671".into()); "formatted")]
+  #[test_case("0044 20 7946 0958" => Some("+442079460958".into()); "double zero prefix")]
+  #[test_case("415 555 2671" => None; "no country code")]
+  #[test_case("+0 123 4567" => None; "leading zero")]
+  #[test_case("+1234567890123456" => None; "too long")]
+  fn test_phone(s: &str) -> Option<String> {
+    parse_e164(s)
+  }
+
+  #[test_case("GB82 WEST 1234 5698 7654 32" => true; "gb")]
+  #[test_case("de89370400440532013000" => true; "lowercase")]
+  #[test_case("GB82 WEST 1234 5698 7654 33" => false; "bad checksum")]
+  #[test_case("GB82 WEST 1234 5698 7654" => false; "bad length")]
+  #[test_case("ZZ82 WEST 1234 5698 7654 32" => false; "unknown country")]
+  fn test_iban(s: &str) -> bool {
+    is_valid_iban(s)
+  }
+
+  #[test_case("4111 1111 1111 1111" => true; "visa")]
+  #[test_case("5500-0000-0000-0004" => true; "mastercard")]
+  #[test_case("4111 1111 1111 1112" => false; "bad checksum")]
+  #[test_case("4111" => false; "too short")]
+  fn test_card(s: &str) -> bool {
+    is_valid_card(s)
+  }
+}
@@ -0,0 +1,343 @@
+//! Running WebAssembly modules shipped in the service source.
+//!
+//! Modules must not import anything. Each instance has its own linear memory,
+//! limited by `memory` (16 MiB by default), and each call may consume at most
+//! `fuel` units (roughly one per instruction) before it traps.
+//!
+//! Calls run on a blocking thread, so they don't stall the runtime, and trap
+//! after [`MAX_CALL_TIME`] regardless of the fuel left.
+
+use crate::lua::error::{
+  arg_error, check_integer, check_string, check_userdata, check_value, rt_error, rt_error_fmt,
+  tag_handler, TableCheckExt,
+};
+use crate::source::Source;
+use clru::CLruCache;
+use mlua::Value::Nil;
+use mlua::{Function, Lua, MultiValue, Table, UserData};
+use once_cell::sync::Lazy;
+use parking_lot::{Mutex, MutexGuard};
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use tokio::task::spawn_blocking;
+use wasmtime::{
+  Config, Engine, Instance, Module, Store, StoreLimits, StoreLimitsBuilder, Val, ValType,
+};
+
+const DEFAULT_FUEL: u64 = 100_000_000;
+const MAX_FUEL: u64 = 10_000_000_000;
+const DEFAULT_MEMORY: usize = 16 * 1024 * 1024;
+const MAX_MEMORY: usize = 256 * 1024 * 1024;
+
+/// Interval of the engine's epoch, i.e. the granularity of call timeouts.
+const EPOCH_TICK: Duration = Duration::from_millis(10);
+/// Maximum wall-clock time of a single call.
+const MAX_CALL_TIME: Duration = Duration::from_secs(10);
+
+static ENGINE: Lazy<Engine> = Lazy::new(|| {
+  let mut config = Config::new();
+  config.consume_fuel(true).epoch_interruption(true);
+  let engine = Engine::new(&config).expect("failed to create WASM engine");
+  let ticker = engine.clone();
+  (thread::Builder::new().name("abel-wasm-epoch".into()))
+    .spawn(move || loop {
+      thread::sleep(EPOCH_TICK);
+      ticker.increment_epoch();
+    })
+    .expect("failed to spawn WASM epoch thread");
+  engine
+});
+
+/// Compiled modules, keyed by SHA-256 of their bytes.
+static MODULES: Lazy<Mutex<CLruCache<[u8; 32], Module>>> =
+  Lazy::new(|| Mutex::new(CLruCache::new(nonzero_ext::nonzero!(32usize))));
+
+fn compile(bytes: &[u8]) -> anyhow::Result<Module> {
+  let hash: [u8; 32] = Sha256::digest(bytes).into();
+  if let Some(module) = MODULES.lock().get(&hash) {
+    return Ok(module.clone());
+  }
+  let module = Module::new(&ENGINE, bytes)?;
+  MODULES.lock().put(hash, module.clone());
+  Ok(module)
+}
+
+pub fn create_preload_wasm(source: Source) -> impl FnOnce(&Lua) -> mlua::Result<Function> {
+  |lua| {
+    lua.create_function(move |lua, ()| {
+      let wasm = lua.create_table()?;
+      wasm.raw_set("load", create_fn_wasm_load(lua, source.clone())?)?;
+      Ok(wasm)
+    })
+  }
+}
+
+/// `wasm.load(path, { fuel, memory })`, instantiating a module in the service
+/// source.
+fn create_fn_wasm_load(lua: &Lua, source: Source) -> mlua::Result<Function> {
+  lua.create_async_function(move |lua, mut args: MultiValue| {
+    let source = source.clone();
+    async move {
+      let path = check_string(lua, args.pop_front()).map_err(tag_handler(lua, 1, 1))?;
+      let path = path.to_str()?;
+      let opts = check_value::<Option<Table>>(lua, args.pop_front().or(Some(Nil)), "table")
+        .map_err(tag_handler(lua, 2, 1))?;
+      let fuel = (opts.as_ref())
+        .map(|x| x.check_raw_get::<Option<u64>>(lua, "fuel", "positive integer"))
+        .transpose()?
+        .flatten()
+        .unwrap_or(DEFAULT_FUEL);
+      if fuel > MAX_FUEL {
+        return Err(rt_error_fmt!("fuel exceeds maximum ({MAX_FUEL})"));
+      }
+      let memory = (opts.as_ref())
+        .map(|x| x.check_raw_get::<Option<usize>>(lua, "memory", "positive integer"))
+        .transpose()?
+        .flatten()
+        .unwrap_or(DEFAULT_MEMORY);
+      if memory > MAX_MEMORY {
+        return Err(rt_error_fmt!(
+          "memory limit exceeds maximum ({MAX_MEMORY} bytes)"
+        ));
+      }
+
+      let bytes = (source.get_bytes(path).await)
+        .map_err(|error| rt_error_fmt!("failed to read '{path}': {error}"))?;
+      let module =
+        compile(&bytes).map_err(|error| rt_error_fmt!("invalid WASM module: {error}"))?;
+      let instance =
+        WasmInstance::new(&module, fuel, memory).map_err(|error| rt_error_fmt!("{error:#}"))?;
+      Ok(LuaWasmInstance(Arc::new(Mutex::new(instance))))
+    }
+  })
+}
+
+/// Instance of a WASM module.
+///
+/// - `call(name, ...)`: calls an exported function with numbers as arguments
+/// - `read(ptr, len)`: reads bytes from the exported memory
+/// - `write(ptr, data)`: writes bytes to the exported memory
+///
+/// The instance is locked while a call runs on the blocking thread.
+pub struct LuaWasmInstance(Arc<Mutex<WasmInstance>>);
+
+impl LuaWasmInstance {
+  fn lock(&self) -> mlua::Result<MutexGuard<WasmInstance>> {
+    (self.0.try_lock()).ok_or_else(|| rt_error("WASM instance is busy"))
+  }
+}
+
+struct WasmInstance {
+  store: Store<StoreLimits>,
+  instance: Instance,
+  fuel: u64,
+  /// Epoch ticks a single call may take.
+  ticks: u64,
+}
+
+impl WasmInstance {
+  fn new(module: &Module, fuel: u64, memory: usize) -> anyhow::Result<Self> {
+    if module.imports().next().is_some() {
+      anyhow::bail!("WASM modules with imports are not supported");
+    }
+    let limits = StoreLimitsBuilder::new()
+      .memory_size(memory)
+      .instances(1)
+      .build();
+    let mut store = Store::new(&ENGINE, limits);
+    store.limiter(|x| x);
+    let ticks = (MAX_CALL_TIME.as_millis() / EPOCH_TICK.as_millis()) as u64;
+    store.add_fuel(fuel)?;
+    store.set_epoch_deadline(ticks);
+    let instance = Instance::new(&mut store, module, &[])?;
+    Ok(Self {
+      store,
+      instance,
+      fuel,
+      ticks,
+    })
+  }
+
+  fn call(&mut self, name: &str, params: &[Val]) -> anyhow::Result<Vec<Val>> {
+    let func = (self.instance.get_func(&mut self.store, name))
+      .ok_or_else(|| anyhow::anyhow!("function '{name}' is not exported"))?;
+    let ty = func.ty(&self.store);
+    let mut results = ty.results().map(|_| Val::I32(0)).collect::<Vec<_>>();
+    // Refill fuel for every call
+    let remaining = self.store.consume_fuel(0)?;
+    self.store.add_fuel(self.fuel.saturating_sub(remaining))?;
+    self.store.set_epoch_deadline(self.ticks);
+    func.call(&mut self.store, params, &mut results)?;
+    Ok(results)
+  }
+
+  fn param_types(&mut self, name: &str) -> Option<Vec<ValType>> {
+    let func = self.instance.get_func(&mut self.store, name)?;
+    Some(func.ty(&self.store).params().collect())
+  }
+
+  fn memory(&mut self) -> anyhow::Result<&mut [u8]> {
+    let memory = (self.instance.get_memory(&mut self.store, "memory"))
+      .ok_or_else(|| anyhow::anyhow!("memory is not exported"))?;
+    Ok(memory.data_mut(&mut self.store))
+  }
+}
+
+fn check_range(lua: &Lua, ptr: i64, len: usize, memory_len: usize) -> mlua::Result<usize> {
+  let ptr = usize::try_from(ptr).map_err(|_| arg_error(lua, 2, "negative pointer", 1))?;
+  match ptr.checked_add(len) {
+    Some(end) if end <= memory_len => Ok(ptr),
+    _ => Err(rt_error("out of bounds memory access")),
+  }
+}
+
+impl UserData for LuaWasmInstance {
+  fn add_methods<'lua, M: mlua::UserDataMethods<'lua, Self>>(methods: &mut M) {
+    methods.add_async_function("call", |lua, mut args: MultiValue| async move {
+      let this = check_userdata::<Self>(args.pop_front(), "WASM instance")
+        .map_err(tag_handler(lua, 1, 1))?;
+      let name = check_string(lua, args.pop_front()).map_err(tag_handler(lua, 2, 1))?;
+      let name = name.to_str()?.to_owned();
+      let inner = this.with_borrowed(|x| x.0.clone());
+      drop(this);
+
+      let types = (inner.try_lock())
+        .ok_or_else(|| rt_error("WASM instance is busy"))?
+        .param_types(&name)
+        .ok_or_else(|| rt_error_fmt!("function '{name}' is not exported"))?;
+      if args.len() != types.len() {
+        return Err(rt_error_fmt!(
+          "function '{name}' expects {} arguments, got {}",
+          types.len(),
+          args.len()
+        ));
+      }
+      let params = (types.into_iter().zip(args).enumerate())
+        .map(|(i, (ty, x))| {
+          let pos = i + 3;
+          let val = match ty {
+            ValType::I32 => {
+              let x = check_integer(Some(x)).map_err(tag_handler(lua, pos, 1))?;
+              // Accept both signed and unsigned 32-bit integers
+              if x < i64::from(i32::MIN) || x > i64::from(u32::MAX) {
+                return Err(arg_error(lua, pos, "out of range for i32", 1));
+              }
+              Val::I32(x as i32)
+            }
+            ValType::I64 => Val::I64(check_integer(Some(x)).map_err(tag_handler(lua, pos, 1))?),
+            ValType::F32 => {
+              let x: f64 = check_value(lua, Some(x), "number").map_err(tag_handler(lua, pos, 1))?;
+              Val::F32((x as f32).to_bits())
+            }
+            ValType::F64 => {
+              let x: f64 = check_value(lua, Some(x), "number").map_err(tag_handler(lua, pos, 1))?;
+              Val::F64(x.to_bits())
+            }
+            _ => return Err(rt_error_fmt!("unsupported parameter type: {ty}")),
+          };
+          Ok(val)
+        })
+        .collect::<mlua::Result<Vec<_>>>()?;
+
+      let results = spawn_blocking(move || inner.lock().call(&name, &params))
+        .await
+        .map_err(|x| rt_error_fmt!("background task failed: {x}"))?
+        .map_err(|error| rt_error_fmt!("{error:#}"))?;
+      (results.into_iter())
+        .map(|x| match x {
+          Val::I32(x) => Ok(mlua::Value::Integer(x.into())),
+          Val::I64(x) => Ok(mlua::Value::Integer(x)),
+          Val::F32(x) => Ok(mlua::Value::Number(f32::from_bits(x).into())),
+          Val::F64(x) => Ok(mlua::Value::Number(f64::from_bits(x))),
+          x => Err(rt_error_fmt!("unsupported result type: {}", x.ty())),
+        })
+        .collect::<mlua::Result<MultiValue>>()
+    });
+
+    methods.add_function("read", |lua, mut args: MultiValue| {
+      let this = check_userdata::<Self>(args.pop_front(), "WASM instance")
+        .map_err(tag_handler(lua, 1, 1))?;
+      let ptr = check_integer(args.pop_front()).map_err(tag_handler(lua, 2, 1))?;
+      let len = check_integer(args.pop_front()).map_err(tag_handler(lua, 3, 1))?;
+      let len = usize::try_from(len).map_err(|_| arg_error(lua, 3, "negative length", 1))?;
+      this.with_borrowed(|this| {
+        let mut this = this.lock()?;
+        let memory = this.memory().map_err(rt_error)?;
+        let ptr = check_range(lua, ptr, len, memory.len())?;
+        lua.create_string(&memory[ptr..ptr + len])
+      })
+    });
+
+    methods.add_function("write", |lua, mut args: MultiValue| {
+      let this = check_userdata::<Self>(args.pop_front(), "WASM instance")
+        .map_err(tag_handler(lua, 1, 1))?;
+      let ptr = check_integer(args.pop_front()).map_err(tag_handler(lua, 2, 1))?;
+      let data = check_string(lua, args.pop_front()).map_err(tag_handler(lua, 3, 1))?;
+      let data = data.as_bytes();
+      this.with_borrowed(|this| {
+        let mut this = this.lock()?;
+        let memory = this.memory().map_err(rt_error)?;
+        let ptr = check_range(lua, ptr, data.len(), memory.len())?;
+        memory[ptr..ptr + data.len()].copy_from_slice(data);
+        Ok(())
+      })
+    });
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const WAT: &str = r#"
+    (module
+      (memory (export "memory") 1)
+      (func (export "add") (param i32 i32) (result i32)
+        local.get 0
+        local.get 1
+        i32.add)
+      (func (export "spin")
+        (loop br 0)))
+  "#;
+
+  fn instance() -> WasmInstance {
+    let module = compile(WAT.as_bytes()).unwrap();
+    WasmInstance::new(&module, 10_000, DEFAULT_MEMORY).unwrap()
+  }
+
+  #[test]
+  fn test_call() {
+    let mut instance = instance();
+    let results = instance.call("add", &[Val::I32(1), Val::I32(2)]).unwrap();
+    assert_eq!(results.len(), 1);
+    assert_eq!(results[0].i32(), Some(3));
+  }
+
+  #[test]
+  fn test_fuel_refilled() {
+    let mut instance = instance();
+    assert!(instance.call("spin", &[]).is_err());
+    // Fuel is refilled, so later calls still work
+    assert!(instance.call("add", &[Val::I32(1), Val::I32(2)]).is_ok());
+  }
+
+  #[test]
+  fn test_memory_limit() {
+    let module = compile(WAT.as_bytes()).unwrap();
+    // The module needs one page (64 KiB) of memory
+    assert!(WasmInstance::new(&module, 10_000, 1024).is_err());
+  }
+
+  #[test]
+  fn test_epoch_deadline() {
+    let module = compile(WAT.as_bytes()).unwrap();
+    // Practically unlimited fuel, so that only the deadline stops it
+    let mut instance = WasmInstance::new(&module, MAX_FUEL, DEFAULT_MEMORY).unwrap();
+    instance.ticks = 10;
+    let started_at = std::time::Instant::now();
+    assert!(instance.call("spin", &[]).is_err());
+    assert!(started_at.elapsed() < Duration::from_secs(5));
+  }
+}
@@ -8,20 +8,32 @@ mod libs;
 #[cfg(test)]
 mod tests;
 
-pub use libs::{fs, http, json, lua_std, rand, stream};
+#[cfg(feature = "oauth")]
+pub use libs::oauth;
+pub use libs::{
+  civil_from_days, constant_time_eq, dns, email, exec, fs, hmac_sha256, http, json, lua_std, rand,
+  s3, session, socket, stream,
+};
 
 use crate::{Error, ErrorKind};
 use error::{resolve_callback_error, CustomError};
 use futures::Future;
 use hyper::client::HttpConnector;
 use hyper::Client;
-use hyper_tls::HttpsConnector;
 use mlua::{ExternalError, FromLua, FromLuaMulti, Function, Lua, Table, ToLua, ToLuaMulti};
 use once_cell::sync::Lazy;
+use parking_lot::RwLock;
+use std::fmt::{self, Debug, Formatter};
 use std::sync::Arc;
 
-static LUA_HTTP_CLIENT: Lazy<Client<HttpsConnector<HttpConnector>>> =
-  Lazy::new(|| Client::builder().build(HttpsConnector::new()));
+#[cfg(feature = "tls")]
+type HttpClientConnector = hyper_tls::HttpsConnector<HttpConnector>;
+/// Without `tls`, only plain HTTP is supported.
+#[cfg(not(feature = "tls"))]
+type HttpClientConnector = HttpConnector;
+
+static LUA_HTTP_CLIENT: Lazy<Client<HttpClientConnector>> =
+  Lazy::new(|| Client::builder().build(HttpClientConnector::new()));
 
 pub trait LuaTableExt<'a> {
   fn raw_get_path<T: FromLua<'a>>(&self, base: &str, path: &[&str]) -> mlua::Result<T>;
@@ -143,6 +155,39 @@ impl LuaCacheExt for Lua {
   }
 }
 
+/// Creates the table of a Rust-backed Lua module.
+pub type LuaModuleFn = for<'lua> fn(&'lua Lua) -> mlua::Result<Table<'lua>>;
+
+/// Lua modules registered by the host, available in every service.
+#[derive(Default)]
+pub(crate) struct LuaModules(RwLock<Vec<(String, LuaModuleFn)>>);
+
+impl LuaModules {
+  pub fn register(&self, name: String, f: LuaModuleFn) {
+    let mut modules = self.0.write();
+    modules.retain(|x| x.0 != name);
+    modules.push((name, f));
+  }
+
+  pub fn get(&self) -> Vec<(String, LuaModuleFn)> {
+    self.0.read().clone()
+  }
+}
+
+impl Debug for LuaModules {
+  fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+    f.debug_list()
+      .entries(self.0.read().iter().map(|x| &x.0))
+      .finish()
+  }
+}
+
+pub(crate) fn create_preload_lua_module(
+  f: LuaModuleFn,
+) -> impl FnOnce(&Lua) -> mlua::Result<Function> {
+  move |lua| lua.create_function(move |lua, ()| f(lua))
+}
+
 pub fn sanitize_error(error: mlua::Error) -> Error {
   fn extract_custom_error(
     error: &Arc<dyn std::error::Error + Send + Sync + 'static>,
@@ -3,7 +3,18 @@ use super::global_env::modify_global_env;
 use super::http::create_preload_http;
 use super::isolate::{Isolate, IsolateBuilder};
 use super::json::create_preload_json;
+#[cfg(feature = "crypto")]
 use super::libs::crypto::create_preload_crypto;
+use super::libs::i18n::create_preload_i18n;
+use super::libs::regex::create_preload_regex;
+use super::libs::schema::create_preload_schema;
+use super::libs::strings::create_preload_strings;
+#[cfg(feature = "unicode")]
+use super::libs::unicode::create_preload_unicode;
+#[cfg(feature = "validate")]
+use super::libs::validate::create_preload_validate;
+#[cfg(feature = "wasm")]
+use super::libs::wasm::create_preload_wasm;
 use super::lua_std::{
   create_preload_coroutine, create_preload_math, create_preload_os, create_preload_string,
   create_preload_table, create_preload_utf8, side_effect_global_whitelist,
@@ -44,7 +55,7 @@ impl Sandbox {
     lsp: impl Into<PathBuf>,
   ) -> mlua::Result<IsolateBuilder> {
     let lsp: Arc<Path> = lsp.into().into();
-    self
+    let builder = self
       .isolate_builder(source.clone())?
       .add_side_effect(side_effect_global_whitelist)?
       // Lua std, modified
@@ -55,15 +66,29 @@ impl Sandbox {
       .add_lib("os", create_preload_os)?
       .add_lib("utf8", create_preload_utf8)?
       // Abel std (?)
-      .add_lib("fs", create_preload_fs(source, lsp))?
+      .add_lib("fs", create_preload_fs(source.clone(), lsp, None))?
       .add_lib("http", create_preload_http)?
+      .add_lib("i18n", create_preload_i18n(source.clone()))?
       .add_lib("json", create_preload_json)?
       .add_lib("rand", create_preload_rand)?
-      .add_lib("crypto", create_preload_crypto)?
+      .add_lib("regex", create_preload_regex)?
+      .add_lib("schema", create_preload_schema)?
       .add_lib("stream", create_preload_stream)?
-      .add_lua_lib("testing", include_str!("libs/testing.lua"))?
-      // ...and load some of then into local env
-      .load_libs(["math", "string", "table", "coroutine", "os", "utf8"])
+      .add_lib("strings", create_preload_strings)?
+      .add_lua_lib("testing", include_str!("libs/testing.lua"))?;
+
+    // Optional libs
+    #[cfg(feature = "crypto")]
+    let builder = builder.add_lib("crypto", create_preload_crypto)?;
+    #[cfg(feature = "unicode")]
+    let builder = builder.add_lib("unicode", create_preload_unicode)?;
+    #[cfg(feature = "validate")]
+    let builder = builder.add_lib("validate", create_preload_validate)?;
+    #[cfg(feature = "wasm")]
+    let builder = builder.add_lib("wasm", create_preload_wasm(source))?;
+
+    // ...and load some of then into local env
+    builder.load_libs(["math", "string", "table", "coroutine", "os", "utf8"])
   }
 
   pub async fn run_isolate<'lua, A: ToLuaMulti<'lua>, R: FromLuaMulti<'lua>>(
@@ -1,35 +1,18 @@
 use super::error::resolve_callback_error;
 use super::require::RemoteInterface;
 use super::sandbox::Sandbox;
-use crate::source::{Metadata, Source, SourceVfs};
-use async_trait::async_trait;
-use std::io::Cursor;
+use super::{create_preload_lua_module, LuaModules};
+use crate::source::{MemorySource, Source};
+use mlua::{Lua, Table};
 use tempfile::TempDir;
-use tokio::io;
 
-pub struct EmptySource;
-
-#[async_trait]
-impl SourceVfs for EmptySource {
-  type File = Cursor<Vec<u8>>;
-
-  async fn get(&self, _path: &str) -> io::Result<Self::File> {
-    Err(io::Error::new(
-      io::ErrorKind::NotFound,
-      "No such file or directory",
-    ))
-  }
-
-  async fn exists(&self, _path: &str) -> io::Result<bool> {
-    Ok(false)
-  }
-
-  async fn metadata(&self, _path: &str) -> io::Result<Metadata> {
-    Err(io::Error::new(
-      io::ErrorKind::NotFound,
-      "No such file or directory",
-    ))
-  }
+fn test_source() -> Source {
+  Source::new(MemorySource::from_files([
+    ("main.lua", ""),
+    ("data/hello.txt", "Hello, world!"),
+    ("locales/en.ftl", "hello = Hello, { $name }!\nbye = Bye!"),
+    ("locales/zh-Hans.ftl", "hello = 你好，{ $name }！"),
+  ]))
 }
 
 macro_rules! run_lua_test {
@@ -42,7 +25,7 @@ macro_rules! run_lua_test {
       let sandbox = Sandbox::new(RemoteInterface::new(None))?;
       let local_storage = TempDir::new()?;
       let isolate = sandbox
-        .isolate_builder_with_stdlib(Source::new(EmptySource), local_storage.path())?
+        .isolate_builder_with_stdlib(test_source(), local_storage.path())?
         .build()?;
       sandbox
         .run_isolate_ext::<_, _, ()>(&isolate, $code, $test_name, ())
@@ -94,6 +77,83 @@ lua_tests! {
     t.assert_eq(assert(json.stringify(json.undo_array(table))), '{}')
   "#
 
+  test_json_canonical r#"
+    local json = require "json"
+    local t = require "testing"
+
+    local value = { c = 1, a = { z = true, y = json.array { 3, 2 } }, b = "x" }
+    t.assert_eq(
+      assert(json.stringify(value, { sort_keys = true })),
+      '{"a":{"y":[3,2],"z":true},"b":"x","c":1}'
+    )
+
+    -- Keys sorted by UTF-16 code units, numbers formatted as in ECMAScript
+    local value = {
+      ["\u{FB33}"] = 1.0,
+      ["\u{1F600}"] = 1e21,
+      ["\r"] = 0.000001,
+      ["1"] = "\u{7F}\n",
+    }
+    t.assert_eq(
+      assert(json.canonicalize(value)),
+      '{"\\r":0.000001,"1":"\u{7F}\\n","\u{1F600}":1e+21,"\u{FB33}":1}'
+    )
+  "#
+
+  #[cfg(feature = "crypto")]
+  test_crypto_sri r#"
+    local crypto = require "crypto"
+    local t = require "testing"
+
+    t.assert_eq(
+      crypto.sri "alert('Hello, world.');",
+      "sha384-H8BRh8j48O9oYatfu5AZzq6A9RINhZO5H16dQZngK7T62em8MUt1FLm52t+eX6xO"
+    )
+    t.assert_eq(#crypto.nonce(), 24)
+    t.assert(crypto.nonce() ~= crypto.nonce())
+  "#
+
+  #[cfg(feature = "unicode")]
+  test_unicode r#"
+    local unicode = require "unicode"
+    local t = require "testing"
+
+    local s = "e\u{301}🇨🇳한국어"
+    t.assert_eq(#s, 20)
+    t.assert_eq(unicode.len(s), 5)
+    t.assert_eq(unicode.sub(s, 2, 3), "🇨🇳한")
+    t.assert_eq(unicode.sub(s, -2), "국어")
+    t.assert_eq(unicode.graphemes(s)[1], "e\u{301}")
+    t.assert_eq(unicode.width "한국어", 6)
+
+    t.assert_eq(unicode.normalize "e\u{301}", "\u{E9}")
+    t.assert_eq(unicode.normalize("\u{E9}", "NFD"), "e\u{301}")
+    t.assert_eq(unicode.normalize("ﬁ", "NFKC"), "fi")
+    t.assert_eq(unicode.upper "straße", "STRASSE")
+    t.assert_eq(unicode.casefold "Straße", "strasse")
+  "#
+
+  test_ndjson r#"
+    local json = require "json"
+    local stream = require "stream"
+    local t = require "testing"
+
+    local chunks = { '{"a":1}\n{"b"', ':2}\r\n\n', '[1,2]' }
+    local i = 0
+    local source = { read = function() i = i + 1; return chunks[i] end }
+
+    local reader = json.ndjson_reader(source)
+    t.assert_eq(reader:read().a, 1)
+    t.assert_eq(reader:read().b, 2)
+    t.assert_eq(reader:read()[2], 2)
+    t.assert_eq(reader:read(), nil)
+
+    local values = { { a = 1 }, json.array { 1 } }
+    local j = 0
+    local writer = json.ndjson_writer(function() j = j + 1; return values[j] end)
+    t.assert_eq(stream.read_all(writer), '{"a":1}\n[1]\n')
+  "#
+
   test_http_uri r#"
     local http = require "http"
     local t = require "testing"
@@ -118,6 +178,80 @@ lua_tests! {
     t.assert_eq(query.baz, " ")
   "#
 
+  test_http_response_cache_ttl r#"
+    local http = require "http"
+    local t = require "testing"
+
+    t.assert_eq(http.Response({ body = "hi", cache_ttl = 30 }).cache_ttl, 30)
+    t.assert_eq(http.Response({ body = "hi" }).cache_ttl, nil)
+    t.assert_false(pcall(http.Response, { cache_ttl = "forever" }))
+  "#
+
+  test_http_header_map_set r#"
+    local http = require "http"
+    local t = require "testing"
+
+    local resp = http.Response { body = "hi", headers = { x_a = "1" } }
+    resp.headers.x_b = 2
+    t.assert_eq(resp.headers.x_b, "2")
+    resp.headers.x_a = nil
+    t.assert_eq(resp.headers.x_a, nil)
+    t.assert_false(pcall(function() resp.headers.x_c = {} end))
+  "#
+
+  test_http_proxy_args r#"
+    local http = require "http"
+    local t = require "testing"
+
+    t.assert_false(pcall(http.proxy, "http://example.com", "http://example.com"))
+    t.assert_false(pcall(http.proxy, nil, "http://example.com"))
+  "#
+
+  test_http_request_options r#"
+    local http = require "http"
+    local t = require "testing"
+
+    t.assert_false(pcall(http.request, "http://127.0.0.1:1", "fast"))
+    t.assert_false(pcall(http.request, "http://127.0.0.1:1", { timeout = "1s" }))
+  "#
+
+  test_http_auth_args r#"
+    local http = require "http"
+    local t = require "testing"
+
+    local verify = function() return true end
+    t.assert_eq(type(http.auth.basic("admin", verify)), "function")
+    t.assert_eq(type(http.auth.bearer(verify)(function() end)), "function")
+    t.assert_false(pcall(http.auth.basic, "admin"))
+    t.assert_false(pcall(http.auth.basic, "a\nb", verify))
+    t.assert_false(pcall(http.auth.bearer(verify), 1))
+  "#
+
+  test_http_error r#"
+    local http = require "http"
+    local t = require "testing"
+
+    local e = http.error { status = 404, message = "no such user", detail = { id = 1 } }
+    t.assert_eq(e.kind, "not_found")
+    t.assert_eq(tostring(e), "(404 Not Found) no such user")
+    t.assert_eq(http.error({ kind = "conflict" }).status, 409)
+    t.assert_eq(http.error({}).message, "internal server error")
+    t.assert_false(pcall(http.error, { status = 200 }))
+    t.assert_false(pcall(http.error, { kind = "teapot" }))
+    t.assert_false(pcall(http.error, { kind = "gone", status = 404 }))
+
+    local ok, err = pcall(error, e)
+    t.assert_false(ok)
+    t.assert_eq(err, e)
+    t.assert(http.is_error(err))
+    t.assert(http.is_error(err, 404))
+    t.assert(http.is_error(err, http.error_kinds.not_found))
+    t.assert(http.is_error(err, "not_found"))
+    t.assert_false(http.is_error(err, "gone"))
+    t.assert_false(http.is_error("not found"))
+    t.assert_false(pcall(http.is_error, err, "teapot"))
+  "#
+
   test_rand r#"
     local rand = require "rand"
     local rng = rand.ThreadRng
@@ -126,5 +260,250 @@ lua_tests! {
     t.assert_eq(type(rng:random()), "number")
     t.assert(math.tointeger(rng:gen_range(1, 5)))
     t.assert_false(pcall(rng.gen_range, rng, 1, -1))
+
+    local n = rand.int(1, 6)
+    t.assert(math.tointeger(n) and n >= 1 and n <= 6)
+    t.assert_eq(rand.int(3, 3), 3)
+    t.assert_false(pcall(rand.int, 2, 1))
+    local x = rand.float()
+    t.assert(x >= 0 and x < 1)
+    t.assert_eq(#rand.bytes(16), 16)
+    t.assert_ne(rand.bytes(16), rand.bytes(16))
+    t.assert_eq(rand.choice {}, nil)
+    t.assert_eq(rand.choice { "a" }, "a")
+
+    local array = { 1, 2, 3, 4, 5 }
+    t.assert_eq(rand.shuffle(array), array)
+    local sum = 0
+    for _, v in ipairs(array) do sum = sum + v end
+    t.assert_eq(#array, 5)
+    t.assert_eq(sum, 15)
+  "#
+
+  test_regex r#"
+    local regex = require "regex"
+    local t = require "testing"
+
+    t.assert_eq(regex.match("foo123", [[\d+]]), "123")
+    local k, v = regex.match("key=value", [[(\w+)=(\w+)]])
+    t.assert_eq(k, "key")
+    t.assert_eq(v, "value")
+    t.assert_eq(regex.match("foo", [[\d+]]), nil)
+
+    local all = regex.find_all("a1b22c333", [[\d+]])
+    t.assert_eq(#all, 3)
+    t.assert_eq(all[3], "333")
+    t.assert_eq(regex.find_all("a=1,b=2", [[(\w)=(\d)]])[2][1], "b")
+
+    t.assert_eq(regex.replace("a1b2", [[(\d)]], "<$1>"), "a<1>b<2>")
+    local s, n = regex.replace("a1b2", [[\d]], function(x) return x * 2 end, 1)
+    t.assert_eq(s, "a2b2")
+    t.assert_eq(n, 1)
+
+    local parts = regex.split("a, b,c", [[,\s*]])
+    t.assert_eq(#parts, 3)
+    t.assert_eq(parts[2], "b")
+    t.assert_eq(#regex.split("a,b,c", ",", 2), 2)
+    t.assert_eq(regex.escape "a.b", [[a\.b]])
+
+    t.assert_false(pcall(regex.match, "", "("))
+    t.assert_false(pcall(regex.match, "", string.rep("a", 5000)))
+  "#
+
+  test_schema r#"
+    local schema = require "schema"
+    local t = require "testing"
+
+    local spec = {
+      type = "object",
+      properties = {
+        name = { type = "string", minLength = 1, maxLength = 8 },
+        age = { type = "integer", minimum = 0 },
+        email = { type = "string", pattern = "^[^@]+@[^@]+$" },
+        tags = { type = "array", items = { type = "string" }, maxItems = 2 },
+        role = { enum = { "admin", "user" } },
+      },
+      required = { "name", "age" },
+      additionalProperties = false,
+    }
+
+    t.assert(schema.validate({ name = "Abel", age = 3, tags = {} }, spec))
+    t.assert(schema.validate({ name = "Abel", age = 3.0 }, spec))
+
+    local ok, errors = schema.validate({ age = -1.5, tags = { "a", 1 }, role = "root", x = 1 }, spec)
+    t.assert_false(ok)
+    local messages = {}
+    for _, e in ipairs(errors) do
+      messages[e.field] = e.message
+    end
+    t.assert_eq(messages.name, "is required")
+    t.assert_eq(messages.age, "expected integer, got number")
+    t.assert_eq(messages["tags[2]"], "expected string, got integer")
+    t.assert_eq(messages.role, "not one of the allowed values")
+    t.assert_eq(messages.x, "unknown field")
+
+    t.assert_false(schema.validate("x", { type = { "integer", "null" } }))
+    t.assert(schema.validate(nil, { type = { "integer", "null" } }))
+    t.assert_false(pcall(schema.validate, "x", { pattern = "(" }))
+
+    local value = { name = "Abel", age = 3 }
+    t.assert_eq(schema.check(value, spec), value)
+    local ok, err = pcall(schema.check, { name = "" }, spec)
+    t.assert_false(ok)
+    t.assert_eq(err.status, 422)
+    t.assert_eq(#err.detail.errors, 2)
+  "#
+
+  test_strings r#"
+    local strings = require "strings"
+    local t = require "testing"
+
+    t.assert_eq(strings.len "héllo", 5)
+    t.assert_eq(strings.len "\xff\xfe", 2)
+    t.assert_eq(strings.sub("héllo", 2, 3), "él")
+    t.assert_eq(strings.sub("héllo", -2), "lo")
+    t.assert_eq(strings.sub("héllo", 4, 2), "")
+    t.assert(strings.equal_fold("Straße", "STRASSE"))
+    t.assert(strings.equal_fold("ǅ", "ǆ"))
+    t.assert_false(strings.equal_fold("a", "b"))
+
+    t.assert_eq(strings.trim "  a b \n", "a b")
+    t.assert_eq(strings.trim_start "  a ", "a ")
+    t.assert_eq(strings.trim_end("xxaxx", "x"), "xxa")
+
+    local parts = strings.split("a,b,,c", ",")
+    t.assert_eq(#parts, 4)
+    t.assert_eq(parts[3], "")
+    parts = strings.split("a::b::c", "::", 2)
+    t.assert_eq(#parts, 2)
+    t.assert_eq(parts[2], "b::c")
+    t.assert_false(pcall(strings.split, "a", ""))
+
+    t.assert_eq(strings.levenshtein("kitten", "sitting"), 3)
+    t.assert_eq(strings.chars("né")[2], "é")
+    t.assert_eq(strings.codepoints("né")[2], 0xe9)
+    t.assert_eq(strings.from_codepoints { 0x6e, 0xe9 }, "né")
+    t.assert_eq(strings.bytes("AB")[2], 66)
+    t.assert_eq(strings.from_bytes { 65, 66 }, "AB")
+    t.assert_false(pcall(strings.from_bytes, { 256 }))
   "#
+
+  test_i18n r#"
+    local i18n = require "i18n"
+    local t = require "testing"
+
+    local catalog = i18n.load(nil, { fallback = "en" })
+    t.assert_eq(#catalog:locales(), 2)
+    t.assert_eq(catalog:negotiate("zh-CN, en;q=0.5")[1], "zh-Hans")
+
+    local tr = catalog:translator "zh-CN,zh;q=0.9"
+    t.assert_eq(tr("hello", { name = "Abel" }), "你好，Abel！")
+    t.assert_eq(tr "bye", "Bye!")
+    t.assert_eq(tr "missing", "missing")
+
+    t.assert_false(pcall(i18n.load, "nonexistent"))
+    t.assert_false(pcall(i18n.load, nil, { fallback = "fr" }))
+  "#
+
+  test_fs_source r#"
+    local fs = require "fs"
+    local t = require "testing"
+
+    local file = assert(fs.open("source:data/hello.txt"))
+    t.assert_eq(file:read "a", "Hello, world!")
+    t.assert_eq(fs.metadata("source:data").kind, "dir")
+    t.assert(fs.exists "source:main.lua")
+    t.assert_false(fs.exists "source:data/nonexistent.txt")
+  "#
+
+  test_fs_send_file r#"
+    local fs = require "fs"
+    local t = require "testing"
+
+    local res = fs.send_file "source:data/hello.txt"
+    t.assert_eq(res.status, 200)
+    t.assert_eq(res.headers.content_type, "text/plain; charset=utf-8")
+    t.assert_eq(res.headers.last_modified, nil)
+    t.assert_eq(res.body:read(), "Hello, world!")
+
+    local file = assert(fs.open("report.csv", "w"))
+    file:write "a,b\n"
+    file:close()
+    local res = fs.send_file("report.csv", { attachment = "Report 2022.csv" })
+    t.assert_eq(res.headers.content_type, "text/csv; charset=utf-8")
+    t.assert_eq(res.headers.content_disposition, [[attachment; filename="Report 2022.csv"]])
+    t.assert(res.headers.last_modified:match "GMT$")
+
+    local res = fs.send_file("report.csv", { content_type = "text/plain", attachment = true })
+    t.assert_eq(res.headers.content_type, "text/plain")
+    t.assert_eq(res.headers.content_disposition, [[attachment; filename="report.csv"]])
+
+    t.assert_false(pcall(fs.send_file, "source:data/nonexistent.txt"))
+  "#
+
+  test_stream_save_to r#"
+    local fs = require "fs"
+    local t = require "testing"
+
+    local file = assert(fs.open("copy.txt", "w"))
+    t.assert_eq(fs.send_file("source:data/hello.txt").body:save_to(file), 13)
+    file:close()
+    t.assert_eq(assert(fs.open "copy.txt"):read "a", "Hello, world!")
+
+    local chunks = {}
+    local sink = { write = function(_, x) table.insert(chunks, x) end }
+    t.assert_eq(fs.send_file("source:data/hello.txt").body:save_to(sink), 13)
+    t.assert_eq(table.concat(chunks), "Hello, world!")
+
+    t.assert_false(pcall(fs.send_file("source:data/hello.txt").body.save_to, nil, sink))
+  "#
+}
+
+fn native_module_v1(lua: &Lua) -> mlua::Result<Table> {
+  let module = lua.create_table()?;
+  module.raw_set("version", 1)?;
+  Ok(module)
+}
+
+fn native_module_v2(lua: &Lua) -> mlua::Result<Table> {
+  let module = lua.create_table()?;
+  module.raw_set("version", 2)?;
+  let add = lua.create_function(|_, (a, b): (i64, i64)| Ok(a + b))?;
+  module.raw_set("add", add)?;
+  Ok(module)
+}
+
+#[tokio::test]
+async fn test_lua_modules() -> mlua::Result<()> {
+  let modules = LuaModules::default();
+  modules.register("native".into(), native_module_v1);
+  modules.register("json".into(), native_module_v1);
+  modules.register("native".into(), native_module_v2);
+  let names: Vec<_> = modules.get().into_iter().map(|x| x.0).collect();
+  assert_eq!(names, ["json", "native"]);
+
+  let sandbox = Sandbox::new(RemoteInterface::new(None))?;
+  let local_storage = TempDir::new()?;
+  let mut builder = sandbox.isolate_builder_with_stdlib(test_source(), local_storage.path())?;
+  for (name, f) in modules.get() {
+    builder = builder.add_lib(&name, create_preload_lua_module(f))?;
+  }
+  let isolate = builder.build()?;
+
+  let code = r#"
+    local t = require "testing"
+    local native = require "native"
+    t.assert_eq(native.version, 2)
+    t.assert_eq(native.add(1, 2), 3)
+    t.assert_eq(require "native", native)
+    -- built-in modules of the same name are replaced
+    t.assert_eq((require "json").version, 1)
+  "#;
+  let result = sandbox
+    .run_isolate_ext::<_, _, ()>(&isolate, code, "test_lua_modules", ())
+    .await;
+  if let Err(error) = result {
+    panic!("{}", error_to_string(&error))
+  }
+  Ok(())
 }
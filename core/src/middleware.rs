@@ -0,0 +1,129 @@
+use async_trait::async_trait;
+use hyper::{Body, Request, Response};
+
+/// Intercepts requests to all services, before they enter the sandbox.
+///
+/// Register with [`Abel::add_middleware`](crate::Abel::add_middleware).
+/// Middlewares are called in order of registration on requests, and in
+/// reverse order on responses.
+#[async_trait]
+pub trait Middleware: Send + Sync {
+  /// Called with the service's name and the request. Returning a response
+  /// short-circuits the request, so that the service is not called.
+  async fn on_request(&self, _service: &str, _req: &mut Request<Body>) -> Option<Response<Body>> {
+    None
+  }
+
+  /// Called with responses of requests this middleware has seen, including
+  /// short-circuited ones. Errors are not passed to middlewares.
+  async fn on_response(&self, _service: &str, _resp: &mut Response<Body>) {}
+}
+
+/// Registered middlewares, applied in order.
+#[derive(Default)]
+pub(crate) struct Middlewares(Vec<Box<dyn Middleware>>);
+
+impl Middlewares {
+  pub fn push(&mut self, middleware: Box<dyn Middleware>) {
+    self.0.push(middleware);
+  }
+
+  /// Passes the request through middlewares, returning how many were called
+  /// and the response if one of them short-circuited.
+  pub async fn on_request(
+    &self,
+    service: &str,
+    req: &mut Request<Body>,
+  ) -> (usize, Option<Response<Body>>) {
+    for (i, middleware) in self.0.iter().enumerate() {
+      if let Some(resp) = middleware.on_request(service, req).await {
+        return (i + 1, Some(resp));
+      }
+    }
+    (self.0.len(), None)
+  }
+
+  /// Passes the response through the first `called` middlewares in reverse.
+  pub async fn on_response(&self, called: usize, service: &str, resp: &mut Response<Body>) {
+    for middleware in self.0[..called].iter().rev() {
+      middleware.on_response(service, resp).await;
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::sync::{Arc, Mutex};
+
+  struct Recorder {
+    id: usize,
+    short_circuit: bool,
+    log: Arc<Mutex<Vec<String>>>,
+  }
+
+  #[async_trait]
+  impl Middleware for Recorder {
+    async fn on_request(&self, service: &str, req: &mut Request<Body>) -> Option<Response<Body>> {
+      let (id, mut log) = (self.id, self.log.lock().unwrap());
+      log.push(format!("request {id} {service}"));
+      req.headers_mut().insert("x-seen", id.into());
+      (self.short_circuit).then(|| Response::builder().status(403).body(Body::empty()).unwrap())
+    }
+
+    async fn on_response(&self, service: &str, resp: &mut Response<Body>) {
+      let (id, mut log) = (self.id, self.log.lock().unwrap());
+      log.push(format!("response {id} {service}"));
+      resp.headers_mut().append("x-seen", id.into());
+    }
+  }
+
+  async fn run(short_circuit: &[bool]) -> (Vec<String>, Request<Body>, Response<Body>) {
+    let log = Arc::new(Mutex::new(Vec::new()));
+    let mut middlewares = Middlewares::default();
+    for (id, &short_circuit) in short_circuit.iter().enumerate() {
+      let recorder = Recorder {
+        id,
+        short_circuit,
+        log: log.clone(),
+      };
+      middlewares.push(Box::new(recorder));
+    }
+
+    let mut req = Request::new(Body::empty());
+    let (called, resp) = middlewares.on_request("test", &mut req).await;
+    let mut resp = resp.unwrap_or_else(|| Response::new(Body::empty()));
+    middlewares.on_response(called, "test", &mut resp).await;
+    let log = log.lock().unwrap().clone();
+    (log, req, resp)
+  }
+
+  #[tokio::test]
+  async fn test_order() {
+    let (log, req, resp) = run(&[false, false]).await;
+    let expected = [
+      "request 0 test",
+      "request 1 test",
+      "response 1 test",
+      "response 0 test",
+    ];
+    assert_eq!(log, expected);
+    assert_eq!(req.headers()["x-seen"], "1");
+    assert_eq!(resp.status(), 200);
+    let seen: Vec<_> = resp.headers().get_all("x-seen").iter().collect();
+    assert_eq!(seen, ["1", "0"]);
+  }
+
+  #[tokio::test]
+  async fn test_short_circuit() {
+    let (log, _req, resp) = run(&[false, true, false]).await;
+    let expected = [
+      "request 0 test",
+      "request 1 test",
+      "response 1 test",
+      "response 0 test",
+    ];
+    assert_eq!(log, expected);
+    assert_eq!(resp.status(), 403);
+  }
+}
@@ -0,0 +1,529 @@
+use crate::lua::dns::lookup_host;
+use hyper::header::{
+  HeaderName, CONNECTION, PROXY_AUTHENTICATE, PROXY_AUTHORIZATION, TE, TRAILER, TRANSFER_ENCODING,
+  UPGRADE,
+};
+use hyper::HeaderMap;
+use log::warn;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt::{self, Display, Formatter};
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::time::Duration;
+
+/// IP address range in CIDR notation, e.g. `10.0.0.0/8` or `::1/128`.
+///
+/// A bare address matches only itself.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+  addr: IpAddr,
+  prefix: u8,
+}
+
+impl Cidr {
+  pub fn contains(&self, ip: IpAddr) -> bool {
+    // IPv4-mapped IPv6 addresses are treated as IPv4
+    let ip = match ip {
+      IpAddr::V6(x) => x.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+      _ => ip,
+    };
+    match (self.addr, ip) {
+      (IpAddr::V4(net), IpAddr::V4(ip)) => {
+        let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+        u32::from(net) & mask == u32::from(ip) & mask
+      }
+      (IpAddr::V6(net), IpAddr::V6(ip)) => {
+        let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+        u128::from(net) & mask == u128::from(ip) & mask
+      }
+      _ => false,
+    }
+  }
+}
+
+impl FromStr for Cidr {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let invalid = || format!("invalid CIDR '{s}'");
+    let (addr, prefix) = match s.split_once('/') {
+      Some((addr, prefix)) => (addr, Some(prefix)),
+      None => (s, None),
+    };
+    let addr = IpAddr::from_str(addr).map_err(|_| invalid())?;
+    let max = if addr.is_ipv4() { 32 } else { 128 };
+    let prefix = match prefix {
+      Some(prefix) => prefix.parse().map_err(|_| invalid())?,
+      None => max,
+    };
+    if prefix > max {
+      return Err(invalid());
+    }
+    Ok(Self { addr, prefix })
+  }
+}
+
+impl Display for Cidr {
+  fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+    write!(f, "{}/{}", self.addr, self.prefix)
+  }
+}
+
+impl Serialize for Cidr {
+  fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(self)
+  }
+}
+
+impl<'de> Deserialize<'de> for Cidr {
+  fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+    let s = String::deserialize(deserializer)?;
+    s.parse().map_err(serde::de::Error::custom)
+  }
+}
+
+/// Pattern of `host:port` a service may open sockets to.
+///
+/// The host may start with `*.` to match its subdomains, or be `*` to match
+/// any host. The port may be `*` to match any port. IPv6 hosts are written in
+/// brackets.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HostPattern {
+  host: String,
+  port: Option<u16>,
+}
+
+impl HostPattern {
+  pub fn matches(&self, host: &str, port: u16) -> bool {
+    self.matches_host(host) && self.port.map_or(true, |x| x == port)
+  }
+
+  /// Matches only the host, regardless of port.
+  pub fn matches_host(&self, host: &str) -> bool {
+    let host = host.trim_end_matches('.');
+    match self.host.strip_prefix('*') {
+      Some("") => true,
+      Some(suffix) => host.to_ascii_lowercase().ends_with(suffix),
+      None => host.eq_ignore_ascii_case(&self.host),
+    }
+  }
+}
+
+impl FromStr for HostPattern {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let invalid = || format!("invalid host pattern '{s}'");
+    let (host, port) = s.rsplit_once(':').ok_or_else(invalid)?;
+    let host = (host.strip_prefix('['))
+      .and_then(|x| x.strip_suffix(']'))
+      .unwrap_or(host);
+    let wildcard = host.strip_prefix("*.").unwrap_or(host);
+    if host.is_empty() || (wildcard.contains('*') && host != "*") {
+      return Err(invalid());
+    }
+    let port = match port {
+      "*" => None,
+      port => Some(port.parse().map_err(|_| invalid())?),
+    };
+    let host = host.trim_end_matches('.').to_ascii_lowercase();
+    Ok(Self { host, port })
+  }
+}
+
+impl Display for HostPattern {
+  fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+    if self.host.contains(':') {
+      write!(f, "[{}]", self.host)?;
+    } else {
+      write!(f, "{}", self.host)?;
+    }
+    match self.port {
+      Some(port) => write!(f, ":{port}"),
+      None => write!(f, ":*"),
+    }
+  }
+}
+
+impl Serialize for HostPattern {
+  fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(self)
+  }
+}
+
+impl<'de> Deserialize<'de> for HostPattern {
+  fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+    let s = String::deserialize(deserializer)?;
+    s.parse().map_err(serde::de::Error::custom)
+  }
+}
+
+/// Destination a service may connect to with the `net` permission.
+///
+/// Either a [`HostPattern`] matching the host name and port, or a [`Cidr`]
+/// matching resolved addresses on any port.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NetRule {
+  Host(HostPattern),
+  Cidr(Cidr),
+}
+
+impl NetRule {
+  /// Checks a connection to `host`, resolved to `addr`.
+  pub fn matches(&self, host: &str, addr: SocketAddr) -> bool {
+    match self {
+      Self::Host(pattern) => pattern.matches(host, addr.port()),
+      Self::Cidr(cidr) => cidr.contains(addr.ip()),
+    }
+  }
+}
+
+impl FromStr for NetRule {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    if s.contains('/') || s.parse::<IpAddr>().is_ok() {
+      s.parse().map(Self::Cidr)
+    } else {
+      s.parse().map(Self::Host)
+    }
+  }
+}
+
+impl Display for NetRule {
+  fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+    match self {
+      Self::Host(pattern) => pattern.fmt(f),
+      Self::Cidr(cidr) => cidr.fmt(f),
+    }
+  }
+}
+
+impl Serialize for NetRule {
+  fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(self)
+  }
+}
+
+impl<'de> Deserialize<'de> for NetRule {
+  fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+    let s = String::deserialize(deserializer)?;
+    s.parse().map_err(serde::de::Error::custom)
+  }
+}
+
+/// Outbound access denied by a [`NetPolicy`], reported to services as an HTTP
+/// error with the destination in its detail.
+#[derive(Debug, Clone, Serialize)]
+pub struct NetDenied {
+  pub host: Box<str>,
+  /// Not set when looking up a name.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub port: Option<u16>,
+}
+
+impl Display for NetDenied {
+  fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+    match self.port {
+      Some(port) => write!(f, "connecting to {}:{port} is not permitted", self.host),
+      None => write!(f, "looking up '{}' is not permitted", self.host),
+    }
+  }
+}
+
+impl std::error::Error for NetDenied {}
+
+/// Outbound connections allowed for a service, enforced at connect time.
+/// Violations are logged.
+///
+/// A policy without rules denies everything, e.g. for services without the
+/// `net` permission.
+#[derive(Debug)]
+pub struct NetPolicy {
+  service: Box<str>,
+  rules: Box<[NetRule]>,
+}
+
+impl NetPolicy {
+  pub fn new(service: impl Into<Box<str>>, rules: impl Into<Box<[NetRule]>>) -> Self {
+    Self {
+      service: service.into(),
+      rules: rules.into(),
+    }
+  }
+
+  /// Checks if `name` may be looked up, i.e. matches a host pattern.
+  pub fn check_name(&self, name: &str) -> Result<(), NetDenied> {
+    let allowed =
+      (self.rules.iter()).any(|x| matches!(x, NetRule::Host(p) if p.matches_host(name)));
+    if allowed {
+      Ok(())
+    } else {
+      warn!("service '{}' denied looking up '{name}'", self.service);
+      Err(NetDenied {
+        host: name.into(),
+        port: None,
+      })
+    }
+  }
+
+  /// Resolves `host:port`, returning the addresses the service may connect
+  /// to. Denied connections fail with [`NetDenied`].
+  pub async fn resolve_all(
+    &self,
+    host: &str,
+    port: u16,
+  ) -> Result<Vec<SocketAddr>, Box<dyn std::error::Error + Send + Sync>> {
+    if self.rules.is_empty() {
+      return Err(self.deny(host, port).into());
+    }
+    let addrs = (lookup_host(host, port).await)
+      .map_err(|error| format!("failed to resolve {host} ({error})"))?;
+    if addrs.is_empty() {
+      return Err(format!("failed to resolve {host}").into());
+    }
+    let allowed = (addrs.into_iter())
+      .filter(|addr| self.rules.iter().any(|x| x.matches(host, *addr)))
+      .collect::<Vec<_>>();
+    if allowed.is_empty() {
+      return Err(self.deny(host, port).into());
+    }
+    Ok(allowed)
+  }
+
+  /// Like [`resolve_all`](Self::resolve_all), returning the first address.
+  pub async fn resolve(
+    &self,
+    host: &str,
+    port: u16,
+  ) -> Result<SocketAddr, Box<dyn std::error::Error + Send + Sync>> {
+    Ok(self.resolve_all(host, port).await?[0])
+  }
+
+  fn deny(&self, host: &str, port: u16) -> NetDenied {
+    warn!(
+      "service '{}' denied connecting to {host}:{port}",
+      self.service
+    );
+    NetDenied {
+      host: host.into(),
+      port: Some(port),
+    }
+  }
+}
+
+/// Address of the client, inserted into request extensions by the server.
+#[derive(Debug, Clone, Copy)]
+pub struct ClientAddr {
+  /// Address of the peer connected to the server.
+  pub remote_addr: SocketAddr,
+  /// Address of the client as reported by trusted proxies, or the peer's
+  /// address if it is not a trusted proxy.
+  pub real_ip: IpAddr,
+}
+
+impl ClientAddr {
+  pub fn new(remote_addr: SocketAddr, headers: &HeaderMap, trusted_proxies: &[Cidr]) -> Self {
+    Self {
+      remote_addr,
+      real_ip: real_ip(remote_addr.ip(), headers, trusted_proxies),
+    }
+  }
+}
+
+/// How long a response may be cached by the server, inserted into response
+/// extensions. Set by services with the `cache_ttl` field of responses.
+#[derive(Debug, Clone, Copy)]
+pub struct CacheTtl(pub Duration);
+
+/// Removes headers meaningful only to a single connection (RFC 7230, section
+/// 6.1).
+pub fn remove_hop_by_hop(headers: &mut HeaderMap) {
+  let listed = (headers.get_all(CONNECTION).iter())
+    .filter_map(|x| x.to_str().ok())
+    .flat_map(|x| x.split(','))
+    .filter_map(|x| HeaderName::from_bytes(x.trim().as_bytes()).ok())
+    .collect::<Vec<_>>();
+  for name in listed {
+    headers.remove(name);
+  }
+  for name in [
+    CONNECTION,
+    PROXY_AUTHENTICATE,
+    PROXY_AUTHORIZATION,
+    TE,
+    TRAILER,
+    TRANSFER_ENCODING,
+    UPGRADE,
+  ] {
+    headers.remove(name);
+  }
+  headers.remove("keep-alive");
+}
+
+/// Finds the client address by walking the proxy chain in `Forwarded` or
+/// `X-Forwarded-For` from the nearest hop, stopping at the first address that
+/// is not a trusted proxy.
+fn real_ip(remote_ip: IpAddr, headers: &HeaderMap, trusted_proxies: &[Cidr]) -> IpAddr {
+  let trusted = |ip: IpAddr| trusted_proxies.iter().any(|x| x.contains(ip));
+  if !trusted(remote_ip) {
+    return remote_ip;
+  }
+
+  let forwarded = forwarded_for(headers, "forwarded", parse_forwarded_element);
+  let chain = match forwarded {
+    Some(chain) => chain,
+    None => match forwarded_for(headers, "x-forwarded-for", |x| Some(x.trim())) {
+      Some(chain) => chain,
+      None => return remote_ip,
+    },
+  };
+
+  let mut result = remote_ip;
+  for hop in chain.iter().rev() {
+    match hop.and_then(parse_node) {
+      Some(ip) => {
+        result = ip;
+        if !trusted(ip) {
+          break;
+        }
+      }
+      // Unknown or obfuscated hops cannot be trusted further
+      None => break,
+    }
+  }
+  result
+}
+
+/// Collects nodes from all occurrences of a header, in order. Returns `None`
+/// if the header is absent.
+fn forwarded_for<'a>(
+  headers: &'a HeaderMap,
+  name: &str,
+  f: impl Fn(&'a str) -> Option<&'a str>,
+) -> Option<Vec<Option<&'a str>>> {
+  let values = headers.get_all(name).iter().collect::<Vec<_>>();
+  if values.is_empty() {
+    return None;
+  }
+  let chain = (values.into_iter())
+    .flat_map(|x| x.to_str().unwrap_or("").split(','))
+    .map(f)
+    .collect();
+  Some(chain)
+}
+
+/// Extracts the `for` parameter of a `Forwarded` element (RFC 7239).
+fn parse_forwarded_element(element: &str) -> Option<&str> {
+  (element.split(';'))
+    .filter_map(|x| x.trim().split_once('='))
+    .find(|(k, _)| k.eq_ignore_ascii_case("for"))
+    .map(|(_, v)| v.trim_matches('"'))
+}
+
+/// Parses a node, which may come with a port, and IPv6 ones in brackets.
+fn parse_node(node: &str) -> Option<IpAddr> {
+  if let Ok(ip) = node.parse() {
+    return Some(ip);
+  }
+  if let Ok(addr) = node.parse::<SocketAddr>() {
+    return Some(addr.ip());
+  }
+  node
+    .strip_prefix('[')
+    .and_then(|x| x.strip_suffix(']'))
+    .and_then(|x| x.parse().ok())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use test_case::test_case;
+
+  #[test_case("10.0.0.0/8", "10.1.2.3" => true; "v4 inside")]
+  #[test_case("10.0.0.0/8", "11.1.2.3" => false; "v4 outside")]
+  #[test_case("0.0.0.0/0", "1.1.1.1" => true; "v4 any")]
+  #[test_case("127.0.0.1", "127.0.0.1" => true; "bare address")]
+  #[test_case("fd00::/8", "fd12::1" => true; "v6 inside")]
+  #[test_case("10.0.0.0/8", "::ffff:10.0.0.1" => true; "v4 mapped")]
+  #[test_case("10.0.0.0/8", "fd00::1" => false; "mismatched family")]
+  fn test_cidr_contains(cidr: &str, ip: &str) -> bool {
+    cidr.parse::<Cidr>().unwrap().contains(ip.parse().unwrap())
+  }
+
+  #[test_case("10.0.0.0/33"; "prefix too long")]
+  #[test_case("10.0.0/8"; "bad address")]
+  #[test_case("10.0.0.0/x"; "bad prefix")]
+  fn test_cidr_invalid(cidr: &str) {
+    assert!(cidr.parse::<Cidr>().is_err());
+  }
+
+  #[test_case("smtp.example.com:587", "SMTP.example.com", 587 => true; "exact")]
+  #[test_case("smtp.example.com:587", "smtp.example.com", 25 => false; "other port")]
+  #[test_case("*.example.com:*", "a.b.example.com", 1 => true; "subdomain")]
+  #[test_case("*.example.com:*", "example.com", 1 => false; "not subdomain")]
+  #[test_case("*.example.com:*", "badexample.com", 1 => false; "suffix only")]
+  #[test_case("*:6379", "10.0.0.1", 6379 => true; "any host")]
+  #[test_case("[::1]:53", "::1", 53 => true; "v6")]
+  fn test_host_pattern_matches(pattern: &str, host: &str, port: u16) -> bool {
+    pattern.parse::<HostPattern>().unwrap().matches(host, port)
+  }
+
+  #[test_case("example.com"; "no port")]
+  #[test_case("a.*.com:80"; "inner wildcard")]
+  #[test_case(":80"; "no host")]
+  #[test_case("example.com:http"; "bad port")]
+  fn test_host_pattern_invalid(pattern: &str) {
+    assert!(pattern.parse::<HostPattern>().is_err());
+  }
+
+  #[test_case("*.example.com:443", "a.example.com", "1.1.1.1:443" => true; "host")]
+  #[test_case("*.example.com:443", "a.example.com", "1.1.1.1:80" => false; "host other port")]
+  #[test_case("10.0.0.0/8", "internal", "10.0.0.1:8080" => true; "cidr any port")]
+  #[test_case("10.0.0.0/8", "internal", "192.168.0.1:80" => false; "cidr outside")]
+  #[test_case("10.0.0.1", "internal", "10.0.0.1:80" => true; "bare address")]
+  fn test_net_rule_matches(rule: &str, host: &str, addr: &str) -> bool {
+    let rule = rule.parse::<NetRule>().unwrap();
+    rule.matches(host, addr.parse().unwrap())
+  }
+
+  #[tokio::test]
+  async fn test_net_policy_deny() {
+    let policy = NetPolicy::new("test", Vec::new());
+    let error = policy.resolve("localhost", 80).await.unwrap_err();
+    let denied = error.downcast_ref::<NetDenied>().unwrap();
+    assert_eq!((&*denied.host, denied.port), ("localhost", Some(80)));
+
+    let policy = NetPolicy::new("test", vec!["127.0.0.0/8".parse().unwrap()]);
+    assert!(policy.check_name("localhost").is_err());
+    let addr = policy.resolve("127.0.0.1", 80).await.unwrap();
+    assert_eq!(addr, "127.0.0.1:80".parse().unwrap());
+  }
+
+  #[test_case("192.0.2.1", &[] => "192.0.2.1"; "no headers")]
+  #[test_case("10.0.0.1", &[("x-forwarded-for", "203.0.113.9")] => "203.0.113.9"; "trusted peer")]
+  #[test_case("192.0.2.1", &[("x-forwarded-for", "203.0.113.9")] => "192.0.2.1"; "untrusted peer")]
+  #[test_case("10.0.0.1", &[("x-forwarded-for", "6.6.6.6, 203.0.113.9, 10.0.0.2")] => "203.0.113.9"; "spoofed chain")]
+  #[test_case("10.0.0.1", &[("forwarded", "for=\"[2001:db8::1]:4711\";proto=https")] => "2001:db8::1"; "forwarded v6")]
+  #[test_case("10.0.0.1", &[("forwarded", "for=unknown, for=10.0.0.3")] => "10.0.0.3"; "unknown hop")]
+  fn test_real_ip(remote_ip: &str, headers: &[(&'static str, &'static str)]) -> String {
+    let mut map = HeaderMap::new();
+    for (k, v) in headers {
+      map.append(*k, v.parse().unwrap());
+    }
+    let trusted = ["10.0.0.0/8".parse().unwrap()];
+    real_ip(remote_ip.parse().unwrap(), &map, &trusted).to_string()
+  }
+
+  #[test]
+  fn test_remove_hop_by_hop() {
+    let mut headers = HeaderMap::new();
+    headers.insert(CONNECTION, "keep-alive, x-secret".parse().unwrap());
+    headers.insert("keep-alive", "timeout=5".parse().unwrap());
+    headers.insert("x-secret", "1".parse().unwrap());
+    headers.insert("x-kept", "1".parse().unwrap());
+    remove_hop_by_hop(&mut headers);
+    assert_eq!(headers.len(), 1);
+    assert!(headers.contains_key("x-kept"));
+  }
+}
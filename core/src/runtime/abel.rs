@@ -1,26 +1,83 @@
+use super::cache::create_table_cache;
+use super::id::{create_fn_ulid, create_fn_uuid};
+use super::idempotency::create_fn_idempotent;
+use super::kv::{create_table_kv, KvStore};
+use super::lock::{create_fn_lock, create_fn_ratelimit};
+use super::metrics::{create_table_metrics, SharedMetrics};
+use super::queue::{create_table_queue, SharedQueue};
+use super::retry::{create_fn_breaker, create_fn_retry};
+use super::schedule::{
+  create_fn_cancel_schedule, create_fn_on_schedule, create_fn_schedule_at, SharedSchedule,
+};
 use crate::lua::error::{
-  arg_error, check_integer, check_userdata_mut, check_value, rt_error, tag_error, tag_handler,
+  arg_error, check_integer, check_userdata_mut, check_value, rt_error, rt_error_fmt, tag_error,
+  tag_handler,
 };
+use crate::lua::http::LuaResponse;
 use crate::lua::LuaCacheExt;
+use crate::service::Readiness;
 use crate::task::{LocalTask, TaskContext};
+use crate::Coordinator;
 use futures::future::BoxFuture;
 use futures::{Future, FutureExt};
 use mlua::Value::Nil;
-use mlua::{Function, Lua, MultiValue, RegistryKey, Table, UserData};
+use mlua::{AnyUserData, Function, Lua, MultiValue, RegistryKey, Table, TableExt, UserData};
+use std::sync::Arc;
 use std::time::Duration;
 use tokio::sync::oneshot::error::RecvError;
 
-pub fn side_effect_abel(lua: &Lua, local_env: Table, internal: Table) -> mlua::Result<()> {
-  use mlua::Value::Function as Func;
-  let abel = lua.create_table_from([
-    ("listen", Func(create_fn_listen(lua, internal)?)),
-    ("spawn", Func(create_fn_spawn(lua)?)),
-    ("await_all", Func(create_fn_await_all(lua)?)),
-    ("sleep", Func(create_fn_sleep(lua)?)),
-    ("current_worker", lua.pack(std::thread::current().name())?),
-  ])?;
-  local_env.raw_set("abel", abel.clone())?;
-  Ok(())
+pub fn side_effect_abel(
+  name: &str,
+  readiness: Arc<Readiness>,
+  coordinator: Arc<dyn Coordinator>,
+  queue: SharedQueue,
+  kv: KvStore,
+  schedule: SharedSchedule,
+  metrics: SharedMetrics,
+) -> impl FnOnce(&Lua, Table, Table) -> mlua::Result<()> + '_ {
+  use mlua::Value::{Function as Func, Table as Tbl};
+  move |lua, local_env, internal| {
+    let abel = lua.create_table_from([
+      ("listen", Func(create_fn_listen(lua, internal.clone())?)),
+      ("use", Func(create_fn_use(lua, internal.clone())?)),
+      ("on_schedule", Func(create_fn_on_schedule(lua, internal)?)),
+      ("spawn", Func(create_fn_spawn(lua)?)),
+      ("await_all", Func(create_fn_await_all(lua)?)),
+      ("sleep", Func(create_fn_sleep(lua)?)),
+      ("ready", Func(create_fn_ready(lua, readiness)?)),
+      ("cache", Tbl(create_table_cache(lua, name)?)),
+      ("metrics", Tbl(create_table_metrics(lua, metrics)?)),
+      ("queue", Tbl(create_table_queue(lua, queue)?)),
+      ("kv", Tbl(create_table_kv(lua, kv.clone())?)),
+      (
+        "schedule_at",
+        Func(create_fn_schedule_at(lua, schedule.clone())?),
+      ),
+      (
+        "cancel_schedule",
+        Func(create_fn_cancel_schedule(lua, schedule)?),
+      ),
+      ("uuid", Func(create_fn_uuid(lua)?)),
+      ("ulid", Func(create_fn_ulid(lua)?)),
+      ("retry", Func(create_fn_retry(lua)?)),
+      ("breaker", Func(create_fn_breaker(lua)?)),
+      (
+        "lock",
+        Func(create_fn_lock(lua, name, coordinator.clone())?),
+      ),
+      (
+        "ratelimit",
+        Func(create_fn_ratelimit(lua, name, coordinator.clone())?),
+      ),
+      (
+        "idempotent",
+        Func(create_fn_idempotent(lua, name, coordinator, kv)?),
+      ),
+      ("current_worker", lua.pack(std::thread::current().name())?),
+    ])?;
+    local_env.raw_set("abel", abel.clone())?;
+    Ok(())
+  }
 }
 
 pub fn is_in_abel_context(lua: &Lua) -> bool {
@@ -54,6 +111,69 @@ fn create_fn_listen<'a>(lua: &'a Lua, internal: Table<'a>) -> mlua::Result<Funct
   f.bind(internal)
 }
 
+/// Registers a middleware `f(req, next)` that runs before every handler, in
+/// order of registration. `next()` calls the rest of the chain and returns the
+/// response; not calling it short-circuits the request.
+fn create_fn_use<'a>(lua: &'a Lua, internal: Table<'a>) -> mlua::Result<Function<'a>> {
+  const SRC: &str = r#"
+    local internal, f = ...
+    assert(
+      not internal.sealed,
+      "cannot call `use` from places other than the top level of `main.lua`"
+    )
+    local type_f = type(f)
+    if type_f ~= "function" then
+      if type_f == "table" then
+        local mt = getmetatable(f)
+        if type(mt) == "table" and type(mt.__call) == "function" then
+          goto ok
+        end
+      end
+      error "middleware must either be a function or a callable table"
+    end
+
+    ::ok::
+    table.insert(internal.middlewares, f)
+  "#;
+  let f = lua.create_cached_value("abel:abel.use::meta", || {
+    lua.load(SRC).set_name("@[abel.use]")?.into_function()
+  })?;
+  f.bind(internal)
+}
+
+/// Calls the `i`-th middleware with `next` bound to the rest of the chain, or
+/// the handler if all middlewares have been called.
+pub(super) fn create_fn_dispatch(lua: &Lua) -> mlua::Result<Function> {
+  type Args<'a> = (Table<'a>, usize, mlua::Value<'a>, AnyUserData<'a>);
+  lua.create_cached_async_function(
+    "abel:dispatch",
+    |lua, (middlewares, i, handler, req): Args| async move {
+      let (f, args) = match middlewares.raw_get::<_, mlua::Value>(i)? {
+        Nil => (
+          handler,
+          MultiValue::from_vec(vec![mlua::Value::UserData(req)]),
+        ),
+        f => {
+          let next = (create_fn_dispatch(lua)?).bind((middlewares, i + 1, handler, req.clone()))?;
+          let args = vec![mlua::Value::UserData(req), mlua::Value::Function(next)];
+          (f, MultiValue::from_vec(args))
+        }
+      };
+      let resp: LuaResponse = match f {
+        mlua::Value::Function(f) => f.call_async(args).await?,
+        mlua::Value::Table(t) => t.call_async(args).await?,
+        _ => {
+          return Err(rt_error_fmt!(
+            "attempt to call a(n) {} value",
+            f.type_name()
+          ))
+        }
+      };
+      Ok(resp)
+    },
+  )
+}
+
 pub struct LuaPromise {
   inner: BoxFuture<'static, Result<Box<mlua::Result<RegistryKey>>, RecvError>>,
 }
@@ -153,3 +273,12 @@ fn create_fn_sleep(lua: &Lua) -> mlua::Result<Function> {
     Ok(())
   })
 }
+
+/// Marks the service as ready to receive requests. See `ready_timeout` in the
+/// service config.
+fn create_fn_ready(lua: &Lua, readiness: Arc<Readiness>) -> mlua::Result<Function> {
+  lua.create_function(move |_lua, ()| {
+    readiness.set_ready();
+    Ok(())
+  })
+}
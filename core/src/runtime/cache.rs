@@ -0,0 +1,317 @@
+use crate::lua::error::{arg_error, bad_field, check_string, check_value, tag_handler};
+use clru::CLruCache;
+use mlua::Value::Nil;
+use mlua::{Function, Lua, MultiValue, RegistryKey, Table};
+use nonzero_ext::nonzero;
+use std::collections::HashMap;
+use std::num::NonZeroUsize;
+use std::time::{Duration, Instant};
+
+const DEFAULT_MAX_ENTRIES: NonZeroUsize = nonzero!(1024usize);
+const DEFAULT_MAX_BYTES: usize = 16 * 1024 * 1024;
+
+/// Worker-local caches of services, keyed by service name.
+#[derive(Default)]
+pub struct ServiceCaches(HashMap<String, ServiceCache>);
+
+impl ServiceCaches {
+  fn get_mut(&mut self, name: &str) -> &mut ServiceCache {
+    if !self.0.contains_key(name) {
+      self.0.insert(name.into(), ServiceCache::default());
+    }
+    self.0.get_mut(name).unwrap()
+  }
+
+  /// Removes expired entries and caches of services no longer present,
+  /// returning the number of entries removed.
+  pub fn cleanup(&mut self, mut is_present: impl FnMut(&str) -> bool) -> usize {
+    let mut count = 0;
+    self.0.retain(|name, cache| {
+      if is_present(name) {
+        count += cache.remove_expired();
+        true
+      } else {
+        count += cache.entries.len();
+        false
+      }
+    });
+    count
+  }
+}
+
+struct ServiceCache {
+  entries: CLruCache<Vec<u8>, Entry>,
+  max_entries: usize,
+  bytes: usize,
+  max_bytes: usize,
+  default_ttl: Option<Duration>,
+}
+
+struct Entry {
+  value: RegistryKey,
+  size: usize,
+  expires_at: Option<Instant>,
+}
+
+impl Default for ServiceCache {
+  fn default() -> Self {
+    Self::new(DEFAULT_MAX_ENTRIES, DEFAULT_MAX_BYTES, None)
+  }
+}
+
+impl ServiceCache {
+  fn new(max_entries: NonZeroUsize, max_bytes: usize, default_ttl: Option<Duration>) -> Self {
+    Self {
+      entries: CLruCache::new(max_entries),
+      max_entries: max_entries.get(),
+      bytes: 0,
+      max_bytes,
+      default_ttl,
+    }
+  }
+
+  fn get(&mut self, key: &[u8]) -> Option<&RegistryKey> {
+    let expired = (self.entries.peek(key))
+      .and_then(|x| x.expires_at)
+      .map(|x| x <= Instant::now())
+      .unwrap_or(false);
+    if expired {
+      self.remove(key);
+      None
+    } else {
+      self.entries.get(key).map(|x| &x.value)
+    }
+  }
+
+  fn insert(&mut self, key: Vec<u8>, value: RegistryKey, size: usize, ttl: Option<Duration>) {
+    self.remove(&key);
+    if size > self.max_bytes {
+      return;
+    }
+    while self.entries.len() >= self.max_entries || self.bytes + size > self.max_bytes {
+      match self.entries.pop_back() {
+        Some((_, entry)) => self.bytes -= entry.size,
+        None => break,
+      }
+    }
+    let expires_at = ttl.or(self.default_ttl).map(|x| Instant::now() + x);
+    self.bytes += size;
+    self.entries.put(key, Entry {
+      value,
+      size,
+      expires_at,
+    });
+  }
+
+  fn remove(&mut self, key: &[u8]) -> bool {
+    if let Some(entry) = self.entries.pop(key) {
+      self.bytes -= entry.size;
+      true
+    } else {
+      false
+    }
+  }
+
+  fn remove_expired(&mut self) -> usize {
+    let now = Instant::now();
+    let mut removed = 0;
+    let mut bytes = self.bytes;
+    self.entries.retain(|_, entry| {
+      let r = entry.expires_at.map(|x| x > now).unwrap_or(true);
+      if !r {
+        removed += 1;
+        bytes -= entry.size;
+      }
+      r
+    });
+    self.bytes = bytes;
+    removed
+  }
+}
+
+fn with_cache<R>(lua: &Lua, name: &str, f: impl FnOnce(&mut ServiceCache) -> R) -> R {
+  if lua.app_data_ref::<ServiceCaches>().is_none() {
+    lua.set_app_data(ServiceCaches::default());
+  }
+  let mut caches = lua.app_data_mut::<ServiceCaches>().unwrap();
+  f(caches.get_mut(name))
+}
+
+/// Creates `abel.cache` for a service.
+pub fn create_table_cache<'lua>(lua: &'lua Lua, name: &str) -> mlua::Result<Table<'lua>> {
+  let cache = lua.create_table()?;
+  cache.raw_set("get", create_fn_get(lua, name.into())?)?;
+  cache.raw_set("set", create_fn_set(lua, name.into())?)?;
+  cache.raw_set("delete", create_fn_delete(lua, name.into())?)?;
+  cache.raw_set("clear", create_fn_clear(lua, name.into())?)?;
+  cache.raw_set("configure", create_fn_configure(lua, name.into())?)?;
+  cache.raw_set("memo", create_fn_memo(lua, name.into())?)?;
+  Ok(cache)
+}
+
+fn create_fn_get(lua: &Lua, name: String) -> mlua::Result<Function> {
+  lua.create_function(move |lua, mut args: MultiValue| {
+    let key = check_string(lua, args.pop_front()).map_err(tag_handler(lua, 1, 0))?;
+    get(lua, &name, key.as_bytes())
+  })
+}
+
+fn create_fn_set(lua: &Lua, name: String) -> mlua::Result<Function> {
+  lua.create_function(move |lua, mut args: MultiValue| {
+    let key = check_string(lua, args.pop_front()).map_err(tag_handler(lua, 1, 0))?;
+    let value = args.pop_front().unwrap_or(Nil);
+    let ttl = check_ttl(lua, args.pop_front(), 3, 0)?;
+    set(lua, &name, key.as_bytes(), value, ttl)
+  })
+}
+
+fn create_fn_delete(lua: &Lua, name: String) -> mlua::Result<Function> {
+  lua.create_function(move |lua, mut args: MultiValue| {
+    let key = check_string(lua, args.pop_front()).map_err(tag_handler(lua, 1, 0))?;
+    Ok(with_cache(lua, &name, |c| c.remove(key.as_bytes())))
+  })
+}
+
+fn create_fn_clear(lua: &Lua, name: String) -> mlua::Result<Function> {
+  lua.create_function(move |lua, ()| {
+    with_cache(lua, &name, |c| {
+      c.entries.clear();
+      c.bytes = 0;
+    });
+    Ok(())
+  })
+}
+
+fn create_fn_configure(lua: &Lua, name: String) -> mlua::Result<Function> {
+  lua.create_function(move |lua, mut args: MultiValue| {
+    let options: Table =
+      check_value(lua, args.pop_front(), "table").map_err(tag_handler(lua, 1, 0))?;
+    let max_entries = (options.raw_get::<_, Option<usize>>("max_entries"))
+      .map_err(|_| bad_field("max_entries", "positive integer expected"))?
+      .map(|x| NonZeroUsize::new(x).ok_or_else(|| bad_field("max_entries", "must be positive")))
+      .transpose()?
+      .unwrap_or(DEFAULT_MAX_ENTRIES);
+    let max_bytes = (options.raw_get::<_, Option<usize>>("max_bytes"))
+      .map_err(|_| bad_field("max_bytes", "non-negative integer expected"))?
+      .unwrap_or(DEFAULT_MAX_BYTES);
+    let ttl = (options.raw_get::<_, Option<f64>>("ttl"))
+      .map_err(|_| bad_field("ttl", "number expected"))?
+      .map(|x| secs_to_duration(x).ok_or_else(|| bad_field("ttl", "invalid TTL")))
+      .transpose()?;
+    // Reconfiguring drops all existing entries
+    with_cache(lua, &name, |c| {
+      *c = ServiceCache::new(max_entries, max_bytes, ttl)
+    });
+    Ok(())
+  })
+}
+
+fn create_fn_memo(lua: &Lua, name: String) -> mlua::Result<Function> {
+  lua.create_async_function(move |lua, mut args: MultiValue| {
+    let name = name.clone();
+    async move {
+      let key = check_string(lua, args.pop_front()).map_err(tag_handler(lua, 1, 1))?;
+      let ttl = check_ttl(lua, args.pop_front(), 2, 1)?;
+      let f: Function =
+        check_value(lua, args.pop_front(), "function").map_err(tag_handler(lua, 3, 1))?;
+
+      let cached = get(lua, &name, key.as_bytes())?;
+      if cached != Nil {
+        return Ok(cached);
+      }
+      let value: mlua::Value = f.call_async(()).await?;
+      set(lua, &name, key.as_bytes(), value.clone(), ttl)?;
+      Ok(value)
+    }
+  })
+}
+
+fn get<'lua>(lua: &'lua Lua, name: &str, key: &[u8]) -> mlua::Result<mlua::Value<'lua>> {
+  with_cache(lua, name, |c| {
+    c.get(key).map(|x| lua.registry_value(x)).unwrap_or(Ok(Nil))
+  })
+}
+
+fn set(
+  lua: &Lua,
+  name: &str,
+  key: &[u8],
+  value: mlua::Value,
+  ttl: Option<Duration>,
+) -> mlua::Result<()> {
+  if value == Nil {
+    with_cache(lua, name, |c| c.remove(key));
+    return Ok(());
+  }
+  let size = key.len() + estimate_size(&value, 0);
+  let value = lua.create_registry_value(value)?;
+  with_cache(lua, name, |c| c.insert(key.into(), value, size, ttl));
+  Ok(())
+}
+
+fn check_ttl(
+  lua: &Lua,
+  value: Option<mlua::Value>,
+  pos: usize,
+  level: usize,
+) -> mlua::Result<Option<Duration>> {
+  match value {
+    None | Some(Nil) => Ok(None),
+    value => {
+      let secs: f64 = check_value(lua, value, "number").map_err(tag_handler(lua, pos, level))?;
+      secs_to_duration(secs)
+        .map(Some)
+        .ok_or_else(|| arg_error(lua, pos, "invalid TTL", level))
+    }
+  }
+}
+
+fn secs_to_duration(secs: f64) -> Option<Duration> {
+  (secs.is_finite() && secs >= 0.0).then(|| Duration::from_secs_f64(secs))
+}
+
+/// Roughly estimates memory used by a Lua value.
+fn estimate_size(value: &mlua::Value, depth: usize) -> usize {
+  const MAX_DEPTH: usize = 8;
+  match value {
+    mlua::Value::String(s) => 24 + s.as_bytes().len(),
+    mlua::Value::Table(t) if depth < MAX_DEPTH => {
+      let fields = (t.clone().pairs::<mlua::Value, mlua::Value>())
+        .filter_map(Result::ok)
+        .map(|(k, v)| estimate_size(&k, depth + 1) + estimate_size(&v, depth + 1))
+        .sum::<usize>();
+      56 + fields
+    }
+    _ => 16,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_service_cache_eviction() -> mlua::Result<()> {
+    let lua = Lua::new();
+    let mut cache = ServiceCache::new(nonzero!(2usize), 100, None);
+    let insert = |cache: &mut ServiceCache, key: &str, size| -> mlua::Result<()> {
+      cache.insert(key.into(), lua.create_registry_value(key)?, size, None);
+      Ok(())
+    };
+
+    insert(&mut cache, "a", 10)?;
+    insert(&mut cache, "b", 10)?;
+    assert!(cache.get(b"a").is_some());
+    // Evicts least recently used "b" by entry count
+    insert(&mut cache, "c", 10)?;
+    assert!(cache.get(b"b").is_none());
+    // Evicts both "a" and "c" by size
+    insert(&mut cache, "d", 95)?;
+    assert_eq!(cache.entries.len(), 1);
+    assert_eq!(cache.bytes, 95);
+    // Too large to be cached at all
+    insert(&mut cache, "e", 101)?;
+    assert!(cache.get(b"e").is_none());
+    Ok(())
+  }
+}
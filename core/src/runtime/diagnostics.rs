@@ -0,0 +1,200 @@
+//! Errors and warnings of services, kept for inspection.
+
+use crate::task::TaskContext;
+use bstr::ByteSlice;
+use dashmap::DashMap;
+use log::warn;
+use mlua::Lua;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+/// Number of recent diagnostics kept for each service.
+const MAX_DIAGNOSTICS: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DiagnosticLevel {
+  Error,
+  Warning,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Diagnostic {
+  /// Time of the latest occurrence.
+  pub time: SystemTime,
+  pub level: DiagnosticLevel,
+  pub message: String,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub deprecation: Option<Deprecation>,
+  /// Occurrences, counted for deprecations only.
+  pub count: u64,
+}
+
+/// Use of a standard library API that is going to be removed.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Deprecation {
+  pub api: String,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub replacement: Option<String>,
+  /// Lua code using the API, as `source:line`.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub location: Option<String>,
+}
+
+#[derive(Debug)]
+pub(crate) struct ServiceDiagnostics {
+  name: String,
+  entries: Mutex<VecDeque<Diagnostic>>,
+}
+
+impl ServiceDiagnostics {
+  pub fn record_error(&self, message: String) {
+    push(&mut self.entries.lock(), Diagnostic {
+      time: SystemTime::now(),
+      level: DiagnosticLevel::Error,
+      message,
+      deprecation: None,
+      count: 1,
+    });
+  }
+
+  /// Records use of a deprecated API, logging it the first time it is seen
+  /// at a location.
+  fn record_deprecation(&self, deprecation: Deprecation) {
+    let mut entries = self.entries.lock();
+    let existing = (entries.iter_mut()).find(|x| x.deprecation.as_ref() == Some(&deprecation));
+    if let Some(entry) = existing {
+      entry.time = SystemTime::now();
+      entry.count += 1;
+      return;
+    }
+
+    let mut message = format!("{} is deprecated", deprecation.api);
+    if let Some(replacement) = &deprecation.replacement {
+      message += &format!("; use {replacement} instead");
+    }
+    if let Some(location) = &deprecation.location {
+      message += &format!(" (at {location})");
+    }
+    warn!(target: &format!("service '{}'", self.name), "{message}");
+    push(&mut entries, Diagnostic {
+      time: SystemTime::now(),
+      level: DiagnosticLevel::Warning,
+      message,
+      deprecation: Some(deprecation),
+      count: 1,
+    });
+  }
+
+  pub fn list(&self, level: Option<DiagnosticLevel>) -> Vec<Diagnostic> {
+    (self.entries.lock().iter())
+      .filter(|x| level.map(|l| x.level == l).unwrap_or(true))
+      .cloned()
+      .collect()
+  }
+}
+
+fn push(entries: &mut VecDeque<Diagnostic>, diagnostic: Diagnostic) {
+  entries.push_back(diagnostic);
+  while entries.len() > MAX_DIAGNOSTICS {
+    entries.pop_front();
+  }
+}
+
+/// Diagnostics of all services.
+#[derive(Debug, Default)]
+pub(crate) struct Diagnostics {
+  services: DashMap<String, Arc<ServiceDiagnostics>>,
+}
+
+impl Diagnostics {
+  pub fn get(&self, service_name: &str) -> Arc<ServiceDiagnostics> {
+    (self.services.entry(service_name.into()))
+      .or_insert_with(|| {
+        Arc::new(ServiceDiagnostics {
+          name: service_name.into(),
+          entries: Default::default(),
+        })
+      })
+      .clone()
+  }
+
+  pub fn list(&self, service_name: &str, level: Option<DiagnosticLevel>) -> Vec<Diagnostic> {
+    (self.services.get(service_name))
+      .map(|x| x.list(level))
+      .unwrap_or_default()
+  }
+
+  pub fn remove(&self, service_name: &str) {
+    self.services.remove(service_name);
+  }
+}
+
+/// Warns that the standard library API `api` is deprecated, attributing it
+/// to the service of the current task and the Lua code calling it.
+///
+/// Should be called directly from the deprecated function.
+#[allow(dead_code)] // no API is deprecated at the moment
+pub(crate) fn deprecated(lua: &Lua, api: &str, replacement: Option<&str>) {
+  let diagnostics = TaskContext::get_current(lua).and_then(|x| x.diagnostics.borrow().clone());
+  let diagnostics = match diagnostics {
+    Some(diagnostics) => diagnostics,
+    None => return,
+  };
+  let location = lua.inspect_stack(1).and_then(|debug| {
+    let src = debug.source().short_src?.as_bstr().to_string();
+    match debug.curr_line() {
+      line if line > 0 => Some(format!("{src}:{line}")),
+      _ => Some(src),
+    }
+  });
+  diagnostics.record_deprecation(Deprecation {
+    api: api.into(),
+    replacement: replacement.map(Into::into),
+    location,
+  });
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_deprecated() -> mlua::Result<()> {
+    let lua = Lua::new();
+    let diagnostics = Diagnostics::default();
+    let context = TaskContext::default();
+    *context.diagnostics.borrow_mut() = Some(diagnostics.get("a"));
+    context.set_current(&lua);
+
+    let f = lua.create_function(|lua, ()| {
+      deprecated(lua, "old", Some("new"));
+      Ok(())
+    })?;
+    lua.globals().raw_set("old", f)?;
+    lua
+      .load("for _ = 1, 3 do old() end\nold()")
+      .set_name("=main")?
+      .exec()?;
+    diagnostics.get("a").record_error("oops".into());
+
+    let warnings = diagnostics.list("a", Some(DiagnosticLevel::Warning));
+    let summary = (warnings.iter())
+      .map(|x| (x.deprecation.clone().unwrap().location.unwrap(), x.count))
+      .collect::<Vec<_>>();
+    assert_eq!(summary, [
+      ("main:1".to_string(), 3),
+      ("main:2".to_string(), 1)
+    ]);
+    assert_eq!(
+      warnings[0].message,
+      "old is deprecated; use new instead (at main:1)"
+    );
+    assert_eq!(diagnostics.list("a", None).len(), 3);
+    assert_eq!(diagnostics.list("b", None).len(), 0);
+    Ok(())
+  }
+}
@@ -0,0 +1,82 @@
+use crate::lua::error::{arg_error, check_integer, tag_handler};
+use crate::lua::LuaCacheExt;
+use mlua::Value::Nil;
+use mlua::{Function, Lua, MultiValue};
+use rand::{thread_rng, RngCore};
+use std::time::{SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
+
+const CROCKFORD_BASE32: &[u8; 32] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+
+pub(super) fn unix_millis() -> u64 {
+  (SystemTime::now().duration_since(UNIX_EPOCH))
+    .map(|x| x.as_millis() as u64)
+    .unwrap_or(0)
+}
+
+/// UUID version 7, i.e. 48-bit Unix timestamp in milliseconds followed by
+/// random bits.
+fn uuid_v7(millis: u64, mut bytes: [u8; 16]) -> Uuid {
+  bytes[..6].copy_from_slice(&millis.to_be_bytes()[2..]);
+  bytes[6] = (bytes[6] & 0x0f) | 0x70;
+  bytes[8] = (bytes[8] & 0x3f) | 0x80;
+  Uuid::from_bytes(bytes)
+}
+
+/// ULID, i.e. 48-bit Unix timestamp in milliseconds followed by 80 random
+/// bits, in Crockford's base32.
+pub(super) fn ulid(millis: u64, random: u128) -> String {
+  let value = ((millis as u128) << 80) | (random & ((1 << 80) - 1));
+  (0..26)
+    .rev()
+    .map(|i| CROCKFORD_BASE32[(value >> (i * 5)) as usize & 31] as char)
+    .collect()
+}
+
+/// Generates a random UUID of version 4 (default) or 7, in hyphenated form.
+pub(super) fn create_fn_uuid(lua: &Lua) -> mlua::Result<Function> {
+  lua.create_cached_function("abel:abel.uuid", |lua, mut args: MultiValue| {
+    let version = match args.pop_front() {
+      None | Some(Nil) => 4,
+      x => check_integer(x).map_err(tag_handler(lua, 1, 1))?,
+    };
+    let uuid = match version {
+      4 => Uuid::new_v4(),
+      7 => {
+        let mut bytes = [0; 16];
+        thread_rng().fill_bytes(&mut bytes);
+        uuid_v7(unix_millis(), bytes)
+      }
+      _ => return Err(arg_error(lua, 1, "UUID version must be 4 or 7", 1)),
+    };
+    Ok(uuid.to_hyphenated().to_string())
+  })
+}
+
+/// Generates a ULID, lexicographically sortable by creation time.
+pub(super) fn create_fn_ulid(lua: &Lua) -> mlua::Result<Function> {
+  lua.create_cached_function("abel:abel.ulid", |_lua, ()| {
+    let mut random = [0; 16];
+    thread_rng().fill_bytes(&mut random);
+    Ok(ulid(unix_millis(), u128::from_be_bytes(random)))
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use test_case::test_case;
+
+  #[test_case(0, 0 => "00000000000000000000000000"; "zero")]
+  #[test_case(1469922850259, 0xffff => "01ARZ3NDEK0000000000001ZZZ"; "timestamp")]
+  #[test_case(0, u128::MAX => "0000000000ZZZZZZZZZZZZZZZZ"; "random is truncated")]
+  fn test_ulid(millis: u64, random: u128) -> String {
+    ulid(millis, random)
+  }
+
+  #[test]
+  fn test_uuid_v7() {
+    let uuid = uuid_v7(0x0123_4567_89ab, [0xff; 16]);
+    assert_eq!(uuid.to_string(), "01234567-89ab-7fff-bfff-ffffffffffff");
+  }
+}
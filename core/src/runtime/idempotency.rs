@@ -0,0 +1,309 @@
+//! `abel.idempotent`, replaying stored responses to retried requests.
+//!
+//! Responses are stored in the service's key-value store, while keys of
+//! running requests are locked through the coordinator.
+
+use super::kv::KvStore;
+use super::lock::check_millis;
+use crate::lua::error::{check_value, http_error, rt_error, tag_handler};
+use crate::lua::http::{LuaBody, LuaResponse};
+use crate::Coordinator;
+use data_encoding::{BASE64, HEXLOWER};
+use futures::{stream, StreamExt};
+use hyper::body::Bytes;
+use hyper::header::{HeaderName, HeaderValue};
+use hyper::{Body, HeaderMap, StatusCode};
+use log::warn;
+use mlua::{Function, Lua, MultiValue};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::Duration;
+use uuid::Uuid;
+
+/// Marks responses replayed from a previous request.
+const REPLAYED_HEADER: &str = "idempotent-replayed";
+
+/// Responses with larger bodies are sent without being stored.
+const MAX_STORED_BODY: usize = 1024 * 1024;
+
+/// How long a key stays locked at most, in case the node running its request
+/// crashes. Requests running longer may run again if retried.
+const KEY_LEASE: Duration = Duration::from_secs(10 * 60);
+
+#[derive(Serialize, Deserialize)]
+struct StoredResponse {
+  status: u16,
+  headers: Vec<(String, String)>,
+  /// Base64-encoded.
+  body: String,
+}
+
+impl StoredResponse {
+  fn into_response(self) -> mlua::Result<LuaResponse> {
+    let status = StatusCode::from_u16(self.status).map_err(rt_error)?;
+    let mut headers = HeaderMap::new();
+    for (name, value) in self.headers {
+      if let (Ok(name), Ok(value)) = (
+        HeaderName::from_bytes(name.as_bytes()),
+        HeaderValue::from_bytes(value.as_bytes()),
+      ) {
+        headers.append(name, value);
+      }
+    }
+    headers.insert(REPLAYED_HEADER, HeaderValue::from_static("true"));
+    let body = BASE64.decode(self.body.as_bytes()).map_err(rt_error)?;
+    Ok(LuaResponse {
+      status,
+      headers: Rc::new(RefCell::new(headers)),
+      body: Some(if body.is_empty() {
+        LuaBody::Empty
+      } else {
+        LuaBody::Bytes(body)
+      }),
+      cache_ttl: None,
+    })
+  }
+}
+
+/// Reads the body of `resp` into memory, so that it can be both stored and
+/// sent. Returns `None` without reading further if the body is larger than
+/// [`MAX_STORED_BODY`].
+async fn buffer_body(resp: &mut LuaResponse) -> mlua::Result<Option<Vec<u8>>> {
+  let body = match resp.body.take() {
+    None | Some(LuaBody::Empty) => Vec::new(),
+    Some(LuaBody::Json(x)) => x.to_string().into_bytes(),
+    Some(LuaBody::Bytes(x)) => x,
+    Some(x) => {
+      let mut rest = Body::from(x);
+      let mut body = Vec::new();
+      while let Some(chunk) = rest.next().await {
+        let chunk = chunk.map_err(rt_error)?;
+        if body.len() + chunk.len() > MAX_STORED_BODY {
+          let read = stream::iter([Ok(Bytes::from(body)), Ok(chunk)]);
+          resp.body = Some(LuaBody::Stream(Body::wrap_stream(read.chain(rest))));
+          return Ok(None);
+        }
+        body.extend_from_slice(&chunk);
+      }
+      body
+    }
+  };
+  let stored = (body.len() <= MAX_STORED_BODY).then(|| body.clone());
+  resp.body = Some(if body.is_empty() {
+    LuaBody::Empty
+  } else {
+    LuaBody::Bytes(body)
+  });
+  Ok(stored)
+}
+
+/// Releases a locked key when dropped, including when the request is
+/// cancelled.
+struct KeyGuard {
+  coordinator: Arc<dyn Coordinator>,
+  key: String,
+  owner: String,
+}
+
+impl Drop for KeyGuard {
+  fn drop(&mut self) {
+    let coordinator = self.coordinator.clone();
+    let key = std::mem::take(&mut self.key);
+    let owner = std::mem::take(&mut self.owner);
+    tokio::spawn(async move {
+      if let Err(error) = coordinator.unlock(&key, &owner).await {
+        warn!("failed to release idempotency key '{key}': {error}");
+      }
+    });
+  }
+}
+
+async fn get_stored(store: &KvStore, key: &str) -> mlua::Result<Option<LuaResponse>> {
+  match store.get(key).await.map_err(rt_error)? {
+    Some(stored) => {
+      let stored: StoredResponse = serde_json::from_value(stored).map_err(rt_error)?;
+      stored.into_response().map(Some)
+    }
+    None => Ok(None),
+  }
+}
+
+/// `abel.idempotent(key, ttl, f, ...)`
+///
+/// Calls `f(...)` once for each `key`, usually the request's
+/// `Idempotency-Key` header, storing its response for `ttl` milliseconds and
+/// replaying it to later calls with the same key. Server errors and bodies
+/// larger than 1 MiB are not stored, so that retries run `f` again. Calls with
+/// the same key while `f` is running fail with 409 Conflict. If `key` is
+/// `nil`, `f` is always called.
+pub(super) fn create_fn_idempotent<'lua>(
+  lua: &'lua Lua,
+  service_name: &str,
+  coordinator: Arc<dyn Coordinator>,
+  store: KvStore,
+) -> mlua::Result<Function<'lua>> {
+  // Service names cannot contain `/`, so this does not collide with
+  // `abel.lock`'s names.
+  let prefix: Arc<str> = format!("{service_name}/idempotency:").into();
+  let store = store.internal("idempotency");
+  lua.create_async_function(move |lua, mut args: MultiValue| {
+    let prefix = prefix.clone();
+    let coordinator = coordinator.clone();
+    let store = store.clone();
+    async move {
+      let key: Option<mlua::String> =
+        check_value(lua, args.pop_front(), "string").map_err(tag_handler(lua, 1, 1))?;
+      let ttl = check_millis(lua, args.pop_front(), 2)?;
+      let f: Function =
+        check_value(lua, args.pop_front(), "function").map_err(tag_handler(lua, 3, 1))?;
+      let key = match key {
+        Some(key) => key.to_str()?.to_owned(),
+        None => return f.call_async::<_, LuaResponse>(args).await,
+      };
+
+      // Hashed, as keys come from clients and may be of any length
+      let store_key = HEXLOWER.encode(&Sha256::digest(key.as_bytes()));
+      if let Some(resp) = get_stored(&store, &store_key).await? {
+        return Ok(resp);
+      }
+
+      let full_key = format!("{prefix}{key}");
+      let owner = Uuid::new_v4().to_string();
+      let locked = coordinator.try_lock(&full_key, &owner, ttl.min(KEY_LEASE));
+      if !locked.await.map_err(rt_error)? {
+        let detail = lua.create_table_from([("key", key)])?;
+        return Err(http_error(
+          lua,
+          StatusCode::CONFLICT,
+          "request in progress",
+          mlua::Value::Table(detail),
+        ));
+      }
+      let _guard = KeyGuard {
+        coordinator,
+        key: full_key,
+        owner,
+      };
+
+      // Another request may have finished between the lookup and locking
+      if let Some(resp) = get_stored(&store, &store_key).await? {
+        return Ok(resp);
+      }
+
+      let mut resp: LuaResponse = f.call_async(args).await?;
+      if !resp.status.is_server_error() {
+        if let Some(body) = buffer_body(&mut resp).await? {
+          let headers = (resp.headers.borrow().iter())
+            .map(|(k, v)| (k.to_string(), String::from_utf8_lossy(v.as_bytes()).into()))
+            .collect();
+          let stored = StoredResponse {
+            status: resp.status.as_u16(),
+            headers,
+            body: BASE64.encode(&body),
+          };
+          let stored = serde_json::to_value(&stored).map_err(rt_error)?;
+          let ttl = Some(ttl.as_millis() as u64);
+          (store.set(&store_key, stored, ttl).await).map_err(rt_error)?;
+        }
+      }
+      Ok(resp)
+    }
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::runtime::kv::KvStores;
+  use crate::LocalCoordinator;
+
+  #[test]
+  fn test_stored_response() -> mlua::Result<()> {
+    let stored = StoredResponse {
+      status: 201,
+      headers: vec![("x-a".into(), "1".into()), ("bad\nname".into(), "2".into())],
+      body: BASE64.encode(b"hello"),
+    };
+    let resp = stored.into_response()?;
+    assert_eq!(resp.status, StatusCode::CREATED);
+    let headers = resp.headers.borrow();
+    assert_eq!(headers.len(), 2);
+    assert_eq!(headers[REPLAYED_HEADER], "true");
+    assert!(matches!(resp.body, Some(LuaBody::Bytes(ref x)) if x == b"hello"));
+    Ok(())
+  }
+
+  #[tokio::test]
+  async fn test_buffer_body() -> mlua::Result<()> {
+    let mut resp = LuaBody::Stream("hello".into()).into_default_response();
+    assert_eq!(
+      buffer_body(&mut resp).await?.as_deref(),
+      Some(&b"hello"[..])
+    );
+    assert!(matches!(resp.body, Some(LuaBody::Bytes(ref x)) if x == b"hello"));
+
+    let large = vec![b'a'; MAX_STORED_BODY + 1];
+    let mut resp = LuaBody::Stream(large.clone().into()).into_default_response();
+    assert_eq!(buffer_body(&mut resp).await?, None);
+    let body = Body::from(resp.body.unwrap());
+    assert_eq!(hyper::body::to_bytes(body).await.unwrap(), large);
+    Ok(())
+  }
+
+  #[tokio::test]
+  async fn test_idempotent() -> mlua::Result<()> {
+    let lua = Lua::new();
+    let dir = tempfile::tempdir()?;
+    let store = KvStores::new(dir.path().into()).get("test", None);
+    let coordinator = Arc::new(LocalCoordinator::default());
+    let idempotent = create_fn_idempotent(&lua, "test", coordinator, store)?;
+    let f: Function = lua
+      .load("local n = 0; return function(x) n = n + 1; return x .. n end")
+      .eval()?;
+
+    let body = |resp: &LuaResponse| match &resp.body {
+      Some(LuaBody::Bytes(x)) => String::from_utf8_lossy(x).into_owned(),
+      _ => panic!("expected bytes"),
+    };
+    let mut call = |key: Option<&'static str>| {
+      let args = (key, 1000, f.clone(), "a");
+      idempotent.call_async::<_, LuaResponse>(args)
+    };
+
+    let first = call(Some("k")).await?;
+    assert!(!first.headers.borrow().contains_key(REPLAYED_HEADER));
+    let replayed = call(Some("k")).await?;
+    assert_eq!(replayed.headers.borrow()[REPLAYED_HEADER], "true");
+    assert_eq!(body(&replayed), "a1");
+    assert_eq!(body(&call(None).await?), "a2");
+    assert_eq!(body(&call(Some("other")).await?), "a3");
+    Ok(())
+  }
+
+  #[tokio::test]
+  async fn test_idempotent_cancelled() -> mlua::Result<()> {
+    let lua = Lua::new();
+    let dir = tempfile::tempdir()?;
+    let store = KvStores::new(dir.path().into()).get("test", None);
+    let coordinator = Arc::new(LocalCoordinator::default());
+    let idempotent = create_fn_idempotent(&lua, "test", coordinator, store)?;
+    let pending =
+      lua.create_async_function(|_, ()| futures::future::pending::<mlua::Result<()>>())?;
+
+    let call = idempotent.call_async::<_, LuaResponse>(("k", 1000, pending));
+    let timeout = tokio::time::timeout(Duration::from_millis(10), call);
+    assert!(timeout.await.is_err());
+    // Lets the guard release the key
+    tokio::time::sleep(Duration::from_millis(10)).await;
+
+    let f = lua.create_function(|_, ()| Ok("done"))?;
+    let resp = idempotent
+      .call_async::<_, LuaResponse>(("k", 1000, f))
+      .await?;
+    assert_eq!(resp.status, StatusCode::OK);
+    Ok(())
+  }
+}
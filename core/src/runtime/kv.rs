@@ -0,0 +1,225 @@
+//! Durable key-value stores of services, exposed as `abel.kv`.
+//!
+//! Each entry is stored as a JSON file under `<local storage>/.kv/<service>`,
+//! named after its hex-encoded key. Entries are sealed with the service's
+//! storage key if local storage is encrypted. Abel keeps its own entries for
+//! the service in dot-prefixed subdirectories, which never collide with keys.
+
+use super::id::unix_millis;
+use super::queue::check_optional;
+use super::records::remove_file_if_exists;
+#[cfg(feature = "encryption")]
+use crate::encryption::StorageKey;
+use crate::lua::error::{arg_error, check_string, rt_error, tag_handler};
+#[cfg(not(feature = "encryption"))]
+use crate::lua::fs::StorageKey;
+use data_encoding::HEXLOWER;
+use mlua::Value::Nil;
+use mlua::{Function, Lua, LuaSerdeExt, MultiValue, Table};
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::fs;
+use uuid::Uuid;
+
+/// Keys are part of file names, which are limited to 255 bytes.
+const MAX_KEY_LEN: usize = 100;
+
+#[derive(Serialize, Deserialize)]
+struct Entry {
+  value: serde_json::Value,
+  /// Unix milliseconds after which the entry is gone.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  expires_at: Option<u64>,
+}
+
+/// Key-value store of one service.
+#[derive(Debug, Clone)]
+pub(crate) struct KvStore {
+  path: PathBuf,
+  key: Option<Arc<StorageKey>>,
+}
+
+impl KvStore {
+  /// Store of entries Abel keeps for the service, such as responses of
+  /// `abel.idempotent`.
+  pub fn internal(&self, name: &str) -> KvStore {
+    KvStore {
+      path: self.path.join(format!(".{name}")),
+      key: self.key.clone(),
+    }
+  }
+
+  fn entry_path(&self, key: &str) -> PathBuf {
+    self.path.join(HEXLOWER.encode(key.as_bytes()))
+  }
+
+  fn seal(&self, data: Vec<u8>) -> io::Result<Vec<u8>> {
+    match &self.key {
+      #[cfg(feature = "encryption")]
+      Some(key) => key.seal(&data),
+      _ => Ok(data),
+    }
+  }
+
+  fn open(&self, data: Vec<u8>) -> io::Result<Vec<u8>> {
+    match &self.key {
+      #[cfg(feature = "encryption")]
+      Some(key) => key.open(data),
+      _ => Ok(data),
+    }
+  }
+
+  pub async fn get(&self, key: &str) -> io::Result<Option<serde_json::Value>> {
+    let data = match fs::read(self.entry_path(key)).await {
+      Ok(data) => data,
+      Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(None),
+      Err(error) => return Err(error),
+    };
+    let entry: Entry = serde_json::from_slice(&self.open(data)?)?;
+    if entry.expires_at.map_or(false, |x| x <= unix_millis()) {
+      return Ok(None);
+    }
+    Ok(Some(entry.value))
+  }
+
+  /// Stores `value` for `ttl` milliseconds, or forever if not set.
+  pub async fn set(&self, key: &str, value: serde_json::Value, ttl: Option<u64>) -> io::Result<()> {
+    let entry = Entry {
+      value,
+      expires_at: ttl.map(|x| unix_millis().saturating_add(x)),
+    };
+    let data = self.seal(serde_json::to_vec(&entry)?)?;
+
+    // Written atomically, so that a crash never leaves it half-written
+    fs::create_dir_all(&self.path).await?;
+    let temp_path = self.path.join(format!(".{}.tmp", Uuid::new_v4()));
+    fs::write(&temp_path, data).await?;
+    fs::rename(temp_path, self.entry_path(key)).await
+  }
+
+  pub async fn delete(&self, key: &str) -> io::Result<()> {
+    remove_file_if_exists(&self.entry_path(key)).await
+  }
+}
+
+/// Key-value stores of all services.
+#[derive(Debug)]
+pub(crate) struct KvStores {
+  path: PathBuf,
+}
+
+impl KvStores {
+  pub fn new(path: PathBuf) -> Self {
+    Self { path }
+  }
+
+  pub(super) fn get(&self, service_name: &str, key: Option<Arc<StorageKey>>) -> KvStore {
+    KvStore {
+      path: self.path.join(service_name),
+      key,
+    }
+  }
+
+  /// Deletes all entries of a removed service.
+  pub async fn remove(&self, service_name: &str) -> io::Result<()> {
+    match fs::remove_dir_all(self.path.join(service_name)).await {
+      Err(error) if error.kind() != io::ErrorKind::NotFound => Err(error),
+      _ => Ok(()),
+    }
+  }
+}
+
+/// Creates `abel.kv` for a service.
+///
+/// - `get(key)`: returns the stored value, or `nil` if absent or expired.
+/// - `set(key, value[, ttl])`: stores a JSON-serializable value, expiring after
+///   `ttl` milliseconds if given.
+/// - `delete(key)`: removes a value.
+///
+/// Keys are strings of at most 100 bytes.
+pub(super) fn create_table_kv(lua: &Lua, store: KvStore) -> mlua::Result<Table> {
+  let table = lua.create_table()?;
+  table.raw_set("get", create_fn_get(lua, store.clone())?)?;
+  table.raw_set("set", create_fn_set(lua, store.clone())?)?;
+  table.raw_set("delete", create_fn_delete(lua, store)?)?;
+  Ok(table)
+}
+
+fn check_key(lua: &Lua, value: Option<mlua::Value>) -> mlua::Result<String> {
+  let key = check_string(lua, value).map_err(tag_handler(lua, 1, 1))?;
+  let key = key.to_str()?;
+  if key.is_empty() || key.len() > MAX_KEY_LEN {
+    return Err(arg_error(lua, 1, "key must be 1 to 100 bytes long", 1));
+  }
+  Ok(key.into())
+}
+
+fn create_fn_get(lua: &Lua, store: KvStore) -> mlua::Result<Function> {
+  lua.create_async_function(move |lua, mut args: MultiValue| {
+    let store = store.clone();
+    async move {
+      let key = check_key(lua, args.pop_front())?;
+      match store.get(&key).await.map_err(rt_error)? {
+        Some(value) => lua.to_value(&value),
+        None => Ok(Nil),
+      }
+    }
+  })
+}
+
+fn create_fn_set(lua: &Lua, store: KvStore) -> mlua::Result<Function> {
+  lua.create_async_function(move |lua, mut args: MultiValue| {
+    let store = store.clone();
+    async move {
+      let key = check_key(lua, args.pop_front())?;
+      let value: serde_json::Value = lua.from_value(args.pop_front().unwrap_or(Nil))?;
+      let ttl = check_optional(lua, args.pop_front(), 3, "non-negative integer")?;
+      store.set(&key, value, ttl).await.map_err(rt_error)
+    }
+  })
+}
+
+fn create_fn_delete(lua: &Lua, store: KvStore) -> mlua::Result<Function> {
+  lua.create_async_function(move |lua, mut args: MultiValue| {
+    let store = store.clone();
+    async move {
+      let key = check_key(lua, args.pop_front())?;
+      store.delete(&key).await.map_err(rt_error)
+    }
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use serde_json::json;
+
+  #[tokio::test]
+  async fn test_kv() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let store = KvStores::new(dir.path().into()).get("test", None);
+    assert_eq!(store.get("a").await?, None);
+    store.set("a", json!({ "x": 1 }), None).await?;
+    assert_eq!(store.get("a").await?, Some(json!({ "x": 1 })));
+    store.set("b", json!(2), Some(0)).await?;
+    assert_eq!(store.get("b").await?, None);
+    store.delete("a").await?;
+    assert_eq!(store.get("a").await?, None);
+    Ok(())
+  }
+
+  #[cfg(feature = "encryption")]
+  #[tokio::test]
+  async fn test_kv_encrypted() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let key = Arc::new(StorageKey::derive(&[1; 32], "test")?);
+    let store = KvStores::new(dir.path().into()).get("test", Some(key));
+    store.set("a", json!("secret"), None).await?;
+    let raw = std::fs::read(store.entry_path("a"))?;
+    assert!(!String::from_utf8_lossy(&raw).contains("secret"));
+    assert_eq!(store.get("a").await?, Some(json!("secret")));
+    Ok(())
+  }
+}
@@ -0,0 +1,143 @@
+//! `abel.lock` and `abel.ratelimit`, coordinated by the runtime's
+//! [`Coordinator`] across services' instances.
+
+use crate::lua::error::{
+  arg_error, check_integer, check_string, check_userdata, rt_error, tag_handler,
+};
+use crate::Coordinator;
+use mlua::Value::Nil;
+use mlua::{Function, Lua, MultiValue, UserData, UserDataMethods};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+const LOCK_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+pub(super) fn check_millis(
+  lua: &Lua,
+  value: Option<mlua::Value>,
+  pos: usize,
+) -> mlua::Result<Duration> {
+  let ms = check_integer(value).map_err(tag_handler(lua, pos, 1))?;
+  (ms.try_into())
+    .map(Duration::from_millis)
+    .map_err(|_| arg_error(lua, pos, "duration must be non-negative", 1))
+}
+
+/// Lock held by a service, released when calling `release`, when closed as a
+/// to-be-closed variable, or when its TTL passes.
+#[derive(Clone)]
+struct LuaLock {
+  coordinator: Arc<dyn Coordinator>,
+  name: Arc<str>,
+  owner: Arc<str>,
+}
+
+impl UserData for LuaLock {
+  fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+    async fn release(lua: &Lua, mut args: MultiValue<'_>) -> mlua::Result<bool> {
+      let this = check_userdata::<LuaLock>(args.pop_front(), "lock")
+        .map_err(tag_handler(lua, 1, 1))?;
+      let this = this.borrow_borrowed().clone();
+      (this.coordinator.unlock(&this.name, &this.owner))
+        .await
+        .map_err(rt_error)
+    }
+
+    methods.add_async_meta_function("__close", release);
+    methods.add_async_function("release", release);
+
+    // Extends the lock to expire `ttl` milliseconds later, returning `false`
+    // if it has already been taken by others.
+    methods.add_async_function("extend", |lua, mut args: MultiValue| async move {
+      let this = check_userdata::<Self>(args.pop_front(), "lock")
+        .map_err(tag_handler(lua, 1, 1))?;
+      let this = this.borrow_borrowed().clone();
+      let ttl = check_millis(lua, args.pop_front(), 2)?;
+      (this.coordinator.try_lock(&this.name, &this.owner, ttl))
+        .await
+        .map_err(rt_error)
+    });
+  }
+}
+
+/// `abel.lock(name, ttl[, wait])`
+///
+/// Acquires the lock `name` for `ttl` milliseconds, waiting at most `wait`
+/// milliseconds (0 by default) for others to release it. Returns the lock, or
+/// `nil` if it is still held by others.
+pub(super) fn create_fn_lock<'lua>(
+  lua: &'lua Lua,
+  service_name: &str,
+  coordinator: Arc<dyn Coordinator>,
+) -> mlua::Result<Function<'lua>> {
+  let prefix: Arc<str> = format!("{service_name}:").into();
+  lua.create_async_function(move |lua, mut args: MultiValue| {
+    let prefix = prefix.clone();
+    let coordinator = coordinator.clone();
+    async move {
+      let name = check_string(lua, args.pop_front()).map_err(tag_handler(lua, 1, 1))?;
+      let ttl = check_millis(lua, args.pop_front(), 2)?;
+      let wait = match args.pop_front() {
+        None | Some(Nil) => Duration::ZERO,
+        x => check_millis(lua, x, 3)?,
+      };
+
+      let name: Arc<str> = format!("{prefix}{}", name.to_str()?).into();
+      let owner: Arc<str> = Uuid::new_v4().to_string().into();
+      // Waits until the task's deadline if `wait` is too long to add up
+      let deadline = Instant::now().checked_add(wait);
+      loop {
+        if (coordinator.try_lock(&name, &owner, ttl).await).map_err(rt_error)? {
+          let lock = LuaLock {
+            coordinator,
+            name,
+            owner,
+          };
+          return Ok(mlua::Value::UserData(lua.create_userdata(lock)?));
+        }
+        let now = Instant::now();
+        if deadline.map_or(false, |x| now >= x) {
+          return Ok(Nil);
+        }
+        let remaining = deadline.map_or(LOCK_POLL_INTERVAL, |x| x - now);
+        tokio::time::sleep(LOCK_POLL_INTERVAL.min(remaining)).await;
+      }
+    }
+  })
+}
+
+/// `abel.ratelimit(key, limit, window)`
+///
+/// Counts a hit of `key`, allowing at most `limit` hits in each fixed window
+/// of `window` milliseconds. Returns whether this hit is allowed, the number
+/// of hits remaining, and milliseconds until the window resets.
+pub(super) fn create_fn_ratelimit<'lua>(
+  lua: &'lua Lua,
+  service_name: &str,
+  coordinator: Arc<dyn Coordinator>,
+) -> mlua::Result<Function<'lua>> {
+  let prefix: Arc<str> = format!("{service_name}:").into();
+  lua.create_async_function(move |lua, mut args: MultiValue| {
+    let prefix = prefix.clone();
+    let coordinator = coordinator.clone();
+    async move {
+      let key = check_string(lua, args.pop_front()).map_err(tag_handler(lua, 1, 1))?;
+      let limit = check_integer(args.pop_front()).map_err(tag_handler(lua, 2, 1))?;
+      let limit =
+        (limit.try_into()).map_err(|_| arg_error(lua, 2, "limit must be non-negative", 1))?;
+      let window = check_millis(lua, args.pop_front(), 3)?;
+      if window.is_zero() {
+        return Err(arg_error(lua, 3, "window must be positive", 1));
+      }
+
+      let key = format!("{prefix}{}", key.to_str()?);
+      let status = (coordinator.rate_limit(&key, limit, window).await).map_err(rt_error)?;
+      Ok((
+        status.allowed,
+        status.remaining,
+        status.reset.as_millis() as u64,
+      ))
+    }
+  })
+}
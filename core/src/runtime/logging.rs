@@ -1,15 +1,36 @@
+use crate::event::{EventKind, Events, LogLevel};
+use crate::service::ServiceName;
 use log::{info, warn};
 use mlua::{Function, Lua, MultiValue, Table};
 
-pub fn side_effect_log(name: &str) -> impl FnOnce(&Lua, Table, Table) -> mlua::Result<()> + '_ {
-  |lua, env, _| {
+/// Sets `print` and `warn`, which write to the log and publish log events.
+pub fn side_effect_log(
+  name: &str,
+  events: Events,
+) -> impl FnOnce(&Lua, Table, Table) -> mlua::Result<()> + '_ {
+  move |lua, env, _| {
+    let service = ServiceName::from(name);
+    let publish = move |level, message: &str| {
+      events.publish(EventKind::Log {
+        service: service.clone(),
+        level,
+        message: message.into(),
+      })
+    };
+    let publish2 = publish.clone();
     env.raw_set(
       "print",
-      create_fn_log(lua, name, |t, s| info!(target: t, "{s}"))?,
+      create_fn_log(lua, name, move |t, s| {
+        info!(target: t, "{s}");
+        publish(LogLevel::Info, s);
+      })?,
     )?;
     env.raw_set(
       "warn",
-      create_fn_log(lua, name, |t, s| warn!(target: t, "{s}"))?,
+      create_fn_log(lua, name, move |t, s| {
+        warn!(target: t, "{s}");
+        publish2(LogLevel::Warn, s);
+      })?,
     )
   }
 }
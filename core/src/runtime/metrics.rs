@@ -0,0 +1,498 @@
+//! Custom metrics recorded by services through `abel.metrics`, rendered in
+//! Prometheus' text format.
+//!
+//! Metrics are named `<service>_<name>`, with `-` in service names replaced by
+//! `_` and a `_` prepended if they start with a digit. Names are registered
+//! across services, so that no two services render the same metric name.
+
+use super::queue::check_optional;
+use crate::lua::error::{
+  arg_error, check_string, check_userdata, check_value, rt_error, tag_handler,
+};
+use dashmap::DashMap;
+use mlua::{Function, Lua, MultiValue, Table, UserData, UserDataMethods};
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use regex::Regex;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Arc;
+use strum::{Display, IntoStaticStr};
+
+const DEFAULT_BUCKETS: &[f64] = &[0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1., 2.5, 5., 10.];
+/// Series a service may record, across all of its metrics.
+const MAX_SERIES: usize = 1000;
+/// Prefix of Abel's own metrics.
+const RESERVED_PREFIX: &str = "abel_";
+
+static NAME_REGEX: Lazy<Regex> = Lazy::new(|| Regex::new("^[a-zA-Z_:][a-zA-Z0-9_:]*$").unwrap());
+static LABEL_REGEX: Lazy<Regex> = Lazy::new(|| Regex::new("^[a-zA-Z_][a-zA-Z0-9_]*$").unwrap());
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display, IntoStaticStr)]
+#[strum(serialize_all = "snake_case")]
+enum MetricKind {
+  Counter,
+  Gauge,
+  Histogram,
+}
+
+#[derive(Debug, Default)]
+struct Series {
+  /// Value of counters and gauges, or sum of histograms' observations.
+  value: f64,
+  count: u64,
+  buckets: Vec<u64>,
+}
+
+#[derive(Debug)]
+struct Family {
+  kind: MetricKind,
+  buckets: Vec<f64>,
+  /// Keyed by rendered labels, e.g. `a="1",b="2"`.
+  series: BTreeMap<String, Series>,
+}
+
+impl Family {
+  fn series(&mut self, labels: &str) -> &mut Series {
+    let len = self.buckets.len();
+    (self.series.entry(labels.into())).or_insert_with(|| Series {
+      buckets: vec![0; len],
+      ..Default::default()
+    })
+  }
+}
+
+/// Full metric names of all services, mapped to the service owning each.
+type Names = Arc<DashMap<String, String>>;
+
+#[derive(Debug)]
+pub(super) struct ServiceMetrics {
+  service_name: String,
+  families: BTreeMap<String, Family>,
+  names: Names,
+}
+
+impl ServiceMetrics {
+  fn series_len(&self) -> usize {
+    self.families.values().map(|x| x.series.len()).sum()
+  }
+
+  /// Claims the full name of a new metric, failing if it is reserved or taken
+  /// by another service.
+  fn claim(&self, name: &str) -> Result<(), String> {
+    let full_name = format!("{}_{name}", prefix(&self.service_name));
+    if full_name.starts_with(RESERVED_PREFIX) {
+      return Err(format!("metric name '{full_name}' is reserved"));
+    }
+    let owner = self.names.entry(full_name.clone());
+    let owner = owner.or_insert_with(|| self.service_name.clone());
+    if *owner != self.service_name {
+      return Err(format!(
+        "metric name '{full_name}' is taken by service '{}'",
+        *owner
+      ));
+    }
+    Ok(())
+  }
+}
+
+pub(super) type SharedMetrics = Arc<Mutex<ServiceMetrics>>;
+
+/// Custom metrics of all services, shared by workers.
+#[derive(Debug, Default)]
+pub(crate) struct CustomMetrics {
+  services: DashMap<String, SharedMetrics>,
+  names: Names,
+}
+
+impl CustomMetrics {
+  pub(super) fn get(&self, service_name: &str) -> SharedMetrics {
+    (self.services.entry(service_name.into()))
+      .or_insert_with(|| {
+        Arc::new(Mutex::new(ServiceMetrics {
+          service_name: service_name.into(),
+          families: BTreeMap::new(),
+          names: self.names.clone(),
+        }))
+      })
+      .clone()
+  }
+
+  /// Deletes all metrics of a removed service.
+  pub fn remove(&self, service_name: &str) {
+    self.services.remove(service_name);
+    self.names.retain(|_, owner| owner != service_name);
+  }
+
+  /// Writes all metrics, named `<service>_<name>` with a `service` label.
+  pub fn render(&self, out: &mut String) {
+    let mut services = (self.services.iter())
+      .map(|x| (x.key().clone(), x.value().clone()))
+      .collect::<Vec<_>>();
+    services.sort_by(|a, b| a.0.cmp(&b.0));
+    for (service_name, metrics) in services {
+      let prefix = prefix(&service_name);
+      let service_label = format!("service=\"{}\"", escape(&service_name));
+      for (name, family) in &metrics.lock().families {
+        let name = format!("{prefix}_{name}");
+        let kind: &str = family.kind.into();
+        let _ = writeln!(out, "# TYPE {name} {kind}");
+        for (labels, series) in &family.series {
+          let labels = if labels.is_empty() {
+            service_label.clone()
+          } else {
+            format!("{service_label},{labels}")
+          };
+          if family.kind != MetricKind::Histogram {
+            let _ = writeln!(out, "{name}{{{labels}}} {}", series.value);
+            continue;
+          }
+          let mut cumulative = 0;
+          for (le, count) in family.buckets.iter().zip(&series.buckets) {
+            cumulative += count;
+            let _ = writeln!(out, "{name}_bucket{{{labels},le=\"{le}\"}} {cumulative}");
+          }
+          let _ = writeln!(
+            out,
+            "{name}_bucket{{{labels},le=\"+Inf\"}} {}",
+            series.count
+          );
+          let _ = writeln!(out, "{name}_sum{{{labels}}} {}", series.value);
+          let _ = writeln!(out, "{name}_count{{{labels}}} {}", series.count);
+        }
+      }
+    }
+  }
+}
+
+/// Prefix of a service's metric names, valid as the start of one.
+fn prefix(service_name: &str) -> String {
+  let prefix = service_name.replace(|c: char| !c.is_ascii_alphanumeric(), "_");
+  if prefix.starts_with(|c: char| c.is_ascii_digit()) {
+    format!("_{prefix}")
+  } else {
+    prefix
+  }
+}
+
+fn escape(value: &str) -> String {
+  (value.replace('\\', "\\\\"))
+    .replace('"', "\\\"")
+    .replace('\n', "\\n")
+}
+
+/// One series of a metric, returned by `abel.metrics.*`.
+#[derive(Clone)]
+struct LuaMetric {
+  metrics: SharedMetrics,
+  kind: MetricKind,
+  name: Arc<str>,
+  labels: Arc<str>,
+}
+
+impl LuaMetric {
+  fn update(
+    &self,
+    lua: &Lua,
+    method: &str,
+    f: impl FnOnce(&[f64], &mut Series),
+  ) -> mlua::Result<()> {
+    let allowed = match method {
+      "inc" => self.kind != MetricKind::Histogram,
+      "dec" | "set" => self.kind == MetricKind::Gauge,
+      _ => self.kind == MetricKind::Histogram,
+    };
+    if !allowed {
+      let msg = format!("cannot call '{method}' on a {}", self.kind);
+      return Err(arg_error(lua, 1, &msg, 1));
+    }
+    let mut metrics = self.metrics.lock();
+    let family = metrics.families.get_mut(&*self.name).unwrap();
+    let buckets = family.buckets.clone();
+    f(&buckets, family.series(&self.labels));
+    Ok(())
+  }
+}
+
+impl UserData for LuaMetric {
+  fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+    fn check_amount(lua: &Lua, value: Option<mlua::Value>) -> mlua::Result<f64> {
+      let amount: Option<f64> = check_optional(lua, value, 2, "number")?;
+      Ok(amount.unwrap_or(1.))
+    }
+
+    methods.add_function("inc", |lua, mut args: MultiValue| {
+      let this =
+        check_userdata::<Self>(args.pop_front(), "metric").map_err(tag_handler(lua, 1, 1))?;
+      let this = this.borrow_borrowed();
+      let amount = check_amount(lua, args.pop_front())?;
+      if this.kind == MetricKind::Counter && amount < 0. {
+        return Err(arg_error(lua, 2, "counter cannot decrease", 1));
+      }
+      this.update(lua, "inc", |_, x| x.value += amount)
+    });
+
+    methods.add_function("dec", |lua, mut args: MultiValue| {
+      let this =
+        check_userdata::<Self>(args.pop_front(), "metric").map_err(tag_handler(lua, 1, 1))?;
+      let this = this.borrow_borrowed();
+      let amount = check_amount(lua, args.pop_front())?;
+      this.update(lua, "dec", |_, x| x.value -= amount)
+    });
+
+    methods.add_function("set", |lua, mut args: MultiValue| {
+      let this =
+        check_userdata::<Self>(args.pop_front(), "metric").map_err(tag_handler(lua, 1, 1))?;
+      let this = this.borrow_borrowed();
+      let value: f64 =
+        check_value(lua, args.pop_front(), "number").map_err(tag_handler(lua, 2, 1))?;
+      this.update(lua, "set", |_, x| x.value = value)
+    });
+
+    methods.add_function("observe", |lua, mut args: MultiValue| {
+      let this =
+        check_userdata::<Self>(args.pop_front(), "metric").map_err(tag_handler(lua, 1, 1))?;
+      let this = this.borrow_borrowed();
+      let value: f64 =
+        check_value(lua, args.pop_front(), "number").map_err(tag_handler(lua, 2, 1))?;
+      this.update(lua, "observe", |buckets, x| {
+        if let Some(i) = buckets.iter().position(|&le| value <= le) {
+          x.buckets[i] += 1;
+        }
+        x.value += value;
+        x.count += 1;
+      })
+    });
+  }
+}
+
+fn render_labels(lua: &Lua, labels: Option<Table>) -> mlua::Result<String> {
+  let mut sorted = BTreeMap::new();
+  if let Some(labels) = labels {
+    for pair in labels.pairs::<mlua::Value, mlua::Value>() {
+      let (k, v) = pair?;
+      let k = match k {
+        mlua::Value::String(k) if LABEL_REGEX.is_match(k.to_str()?) && k != "service" => k,
+        _ => return Err(arg_error(lua, 2, "invalid label name", 1)),
+      };
+      let v = match v {
+        mlua::Value::String(_) | mlua::Value::Integer(_) | mlua::Value::Number(_) => {
+          lua.coerce_string(v)?.unwrap()
+        }
+        mlua::Value::Boolean(b) => lua.create_string(&b.to_string())?,
+        _ => {
+          return Err(arg_error(
+            lua,
+            2,
+            "label value must be a string or number",
+            1,
+          ))
+        }
+      };
+      sorted.insert(k.to_str()?.to_string(), escape(v.to_str()?));
+    }
+  }
+  let labels = sorted
+    .iter()
+    .map(|(k, v)| format!("{k}=\"{v}\""))
+    .collect::<Vec<_>>();
+  Ok(labels.join(","))
+}
+
+fn create_fn_metric(lua: &Lua, metrics: SharedMetrics, kind: MetricKind) -> mlua::Result<Function> {
+  lua.create_function(move |lua, mut args: MultiValue| {
+    let name = check_string(lua, args.pop_front()).map_err(tag_handler(lua, 1, 1))?;
+    let name = name.to_str()?;
+    if !NAME_REGEX.is_match(name) {
+      return Err(arg_error(lua, 1, "invalid metric name", 1));
+    }
+    let labels = check_optional(lua, args.pop_front(), 2, "table")?;
+    let labels = render_labels(lua, labels)?;
+    let buckets = match kind {
+      MetricKind::Histogram => {
+        let buckets: Option<Vec<f64>> = check_optional(lua, args.pop_front(), 3, "table")?;
+        let mut buckets = buckets.unwrap_or_else(|| DEFAULT_BUCKETS.into());
+        if buckets.iter().any(|x| x.is_nan()) {
+          return Err(arg_error(lua, 3, "bucket cannot be NaN", 1));
+        }
+        buckets.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        buckets.dedup();
+        buckets
+      }
+      _ => Vec::new(),
+    };
+
+    let mut guard = metrics.lock();
+    match guard.families.get(name) {
+      Some(family) if family.kind != kind => {
+        let msg = format!("metric '{name}' is already a {}", family.kind);
+        return Err(arg_error(lua, 1, &msg, 1));
+      }
+      Some(family) if family.series.contains_key(&labels) => {}
+      _ if guard.series_len() >= MAX_SERIES => {
+        let msg = format!("too many metric series (max {MAX_SERIES})");
+        return Err(rt_error(msg));
+      }
+      Some(_) => {}
+      None => guard
+        .claim(name)
+        .map_err(|msg| arg_error(lua, 1, &msg, 1))?,
+    }
+    let family = (guard.families.entry(name.into())).or_insert_with(|| Family {
+      kind,
+      buckets,
+      series: BTreeMap::new(),
+    });
+    family.series(&labels);
+    drop(guard);
+
+    Ok(LuaMetric {
+      metrics: metrics.clone(),
+      kind,
+      name: name.into(),
+      labels: labels.into(),
+    })
+  })
+}
+
+/// `abel.metrics.{counter, gauge, histogram}(name[, labels])`
+///
+/// Returns a series of the metric `name` with `labels`, registering it if not
+/// present. Histograms optionally take upper bounds of buckets as the third
+/// argument, used only when first registered. A service may record at most
+/// 1000 series.
+pub(super) fn create_table_metrics(lua: &Lua, metrics: SharedMetrics) -> mlua::Result<Table> {
+  let table = lua.create_table()?;
+  let counter = create_fn_metric(lua, metrics.clone(), MetricKind::Counter)?;
+  table.raw_set("counter", counter)?;
+  let gauge = create_fn_metric(lua, metrics.clone(), MetricKind::Gauge)?;
+  table.raw_set("gauge", gauge)?;
+  let histogram = create_fn_metric(lua, metrics, MetricKind::Histogram)?;
+  table.raw_set("histogram", histogram)?;
+  Ok(table)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_render() -> mlua::Result<()> {
+    let lua = Lua::new();
+    let metrics = CustomMetrics::default();
+    let table = create_table_metrics(&lua, metrics.get("a-b"))?;
+    lua.globals().raw_set("metrics", table)?;
+    lua
+      .load(
+        r#"
+          metrics.counter("orders", { region = "eu" }):inc(2)
+          metrics.gauge("queued"):set(3)
+          local h = metrics.histogram("latency", nil, { 1, 0.1 })
+          h:observe(0.5)
+          h:observe(5)
+          assert(not pcall(metrics.gauge, "orders"))
+          assert(not pcall(metrics.counter("x").inc, metrics.counter("x"), -1))
+        "#,
+      )
+      .exec()?;
+
+    let mut out = String::new();
+    metrics.render(&mut out);
+    let expected = r#"# TYPE a_b_latency histogram
+a_b_latency_bucket{service="a-b",le="0.1"} 0
+a_b_latency_bucket{service="a-b",le="1"} 1
+a_b_latency_bucket{service="a-b",le="+Inf"} 2
+a_b_latency_sum{service="a-b"} 5.5
+a_b_latency_count{service="a-b"} 2
+# TYPE a_b_orders counter
+a_b_orders{service="a-b",region="eu"} 2
+# TYPE a_b_queued gauge
+a_b_queued{service="a-b"} 3
+# TYPE a_b_x counter
+a_b_x{service="a-b"} 0
+"#;
+    assert_eq!(out, expected);
+    Ok(())
+  }
+
+  #[test]
+  fn test_names() -> mlua::Result<()> {
+    let lua = Lua::new();
+    let metrics = CustomMetrics::default();
+    let a = create_table_metrics(&lua, metrics.get("a"))?;
+    let a_b = create_table_metrics(&lua, metrics.get("a-b"))?;
+    let digit = create_table_metrics(&lua, metrics.get("1a"))?;
+    let counter = |t: &Table, name: &str| {
+      t.get::<_, Function>("counter")?
+        .call::<_, mlua::Value>(name)
+    };
+
+    counter(&a_b, "x")?;
+    assert!(counter(&a, "b_x").is_err());
+    counter(&digit, "x")?;
+    metrics.remove("a-b");
+    counter(&a, "b_x")?;
+
+    let mut out = String::new();
+    metrics.render(&mut out);
+    assert!(out.contains("# TYPE _1a_x counter\n"));
+    assert!(out.contains("# TYPE a_b_x counter\n"));
+    Ok(())
+  }
+
+  #[test]
+  fn test_max_series() -> mlua::Result<()> {
+    let lua = Lua::new();
+    let metrics = CustomMetrics::default();
+    let table = create_table_metrics(&lua, metrics.get("a"))?;
+    lua.globals().raw_set("metrics", table)?;
+    lua
+      .load(
+        r#"
+          for i = 1, 1000 do
+            metrics.counter("x", { i = i })
+          end
+          metrics.counter("x", { i = 1 }):inc()
+          assert(not pcall(metrics.counter, "x", { i = 1001 }))
+          assert(not pcall(metrics.gauge, "y"))
+        "#,
+      )
+      .exec()
+  }
+
+  #[test]
+  fn test_abel_metrics() -> mlua::Result<()> {
+    use super::super::abel::side_effect_abel;
+    use super::super::kv::KvStores;
+    use super::super::queue::JobQueues;
+    use super::super::schedule::Schedules;
+    use crate::service::Readiness;
+    use crate::LocalCoordinator;
+
+    let dir = tempfile::TempDir::new()?;
+    let metrics = CustomMetrics::default();
+    let lua = Lua::new();
+    let env = lua.create_table()?;
+    side_effect_abel(
+      "svc",
+      Arc::new(Readiness::new(None)),
+      Arc::new(LocalCoordinator::default()),
+      JobQueues::new(dir.path().join("queue"), None).get("svc")?,
+      KvStores::new(dir.path().join("kv")).get("svc", None),
+      Schedules::new(dir.path().join("schedule"), None).get("svc")?,
+      metrics.get("svc"),
+    )(&lua, env.clone(), lua.create_table()?)?;
+    lua
+      .load(r#"abel.metrics.counter("hits"):inc()"#)
+      .set_environment(env)?
+      .exec()?;
+
+    let mut out = String::new();
+    metrics.render(&mut out);
+    assert_eq!(
+      out,
+      "# TYPE svc_hits counter\nsvc_hits{service=\"svc\"} 1\n"
+    );
+    Ok(())
+  }
+}
@@ -1,34 +1,69 @@
 pub(super) mod abel;
+pub(super) mod diagnostics;
+pub(super) mod kv;
+pub(super) mod metrics;
+pub(super) mod queue;
 
+mod cache;
+mod id;
+mod idempotency;
+mod lock;
 mod logging;
+mod records;
+mod retry;
+pub(super) mod schedule;
 
+use crate::config::{NetPermission, Permissions};
+#[cfg(feature = "encryption")]
+use crate::encryption::StorageKey;
+use crate::lua::dns::{create_preload_dns, create_preload_dns_restricted};
+use crate::lua::email::{create_preload_email, SmtpConfig};
 use crate::lua::error::rt_error_fmt;
-use crate::lua::http::{LuaRequest, LuaResponse};
+use crate::lua::exec::create_preload_exec;
+#[cfg(feature = "encryption")]
+use crate::lua::fs::create_preload_fs;
+#[cfg(not(feature = "encryption"))]
+use crate::lua::fs::StorageKey;
+use crate::lua::http::{
+  create_preload_http_restricted, set_shared_client, HttpClient, LuaRequest, LuaResponse,
+};
 use crate::lua::isolate::Isolate;
+#[cfg(feature = "oauth")]
+use crate::lua::oauth::create_preload_oauth;
+use crate::lua::s3::{create_preload_s3, S3Config};
 use crate::lua::sandbox::Sandbox;
-use crate::lua::{sanitize_error, LuaTableExt};
+use crate::lua::session::create_preload_session;
+use crate::lua::socket::create_preload_socket;
+use crate::lua::{create_preload_lua_module, sanitize_error, LuaTableExt};
+use crate::net::NetPolicy;
 use crate::path::PathMatcher;
-use crate::service::{get_local_storage_path, RunningService};
+use crate::service::{get_local_storage_path, load_secrets, Readiness, RunningService};
 use crate::source::Source;
 use crate::task::TaskContext;
 use crate::ErrorKind::*;
 use crate::{AbelState, Result};
-use abel::side_effect_abel;
+use abel::{create_fn_dispatch, side_effect_abel};
+use cache::ServiceCaches;
 use clru::CLruCache;
 use hyper::{Body, Request};
-use log::{debug, info};
+use log::{debug, info, warn};
 use logging::side_effect_log;
-use mlua::{self, FromLuaMulti, Function, Table, TableExt, ToLuaMulti};
+use mlua::{self, FromLuaMulti, Function, LuaSerdeExt, Table, TableExt, ToLuaMulti};
 use nonzero_ext::nonzero;
 use once_cell::sync::Lazy;
 use regex::Regex;
+use schedule::ScheduledCall;
 use std::cell::{Ref, RefCell};
 use std::ops::{Deref, DerefMut};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
 
 pub struct Runtime {
   sandbox: Sandbox,
-  loaded: RefCell<CLruCache<Box<str>, LoadedService>>,
+  /// Loaded services keyed by UUID, so that multiple versions of one service
+  /// (e.g. a canary) can be cached at the same time.
+  loaded: RefCell<CLruCache<Uuid, LoadedService>>,
   state: Arc<AbelState>,
 }
 
@@ -36,12 +71,16 @@ pub struct Runtime {
 struct LoadedService {
   service: RunningService,
   isolate: Isolate,
+  /// Permissions the isolate was created with. It is recreated once they
+  /// are changed.
+  permissions: Permissions,
 }
 
 impl Runtime {
   pub fn new(state: Arc<AbelState>) -> mlua::Result<Self> {
     let loaded = RefCell::new(CLruCache::new(nonzero!(16usize)));
     let sandbox = Sandbox::new(state.remote.clone())?;
+    set_shared_client(sandbox.lua(), &state.http_client);
     Ok(Self {
       sandbox,
       loaded,
@@ -67,6 +106,7 @@ impl Runtime {
     service: RunningService,
     path: &str,
     req: Request<Body>,
+    deadline: Option<Instant>,
   ) -> Result<LuaResponse> {
     let guard = service.try_upgrade()?;
     let (params, matcher) = guard
@@ -77,6 +117,13 @@ impl Runtime {
         service: guard.name.clone(),
         path: path.into(),
       })?;
+    TaskContext::set_metrics(self.lua(), guard.metrics.clone());
+    TaskContext::set_diagnostics(self.lua(), self.state.diagnostics.get(&guard.name));
+    TaskContext::set_tunnels(self.lua(), guard.tunnels.clone());
+    if let Some(deadline) = deadline {
+      TaskContext::set_deadline(self.lua(), deadline);
+    }
+    let slow_after = guard.profile_threshold.map(Duration::from_millis);
 
     // `loaded` is a mapped, immutable, checked-at-runtime borrow from
     // `self.loaded`. Dropping it early here prevents `self.loaded` being borrowed
@@ -91,8 +138,8 @@ impl Runtime {
       .sequence_values::<Table>()
     {
       let f = f?;
-      let path = f.raw_get::<u8, String>(1)?;
-      if path == matcher.as_str() {
+      let pattern = f.raw_get::<u8, String>(1)?;
+      if pattern == matcher.as_str() {
         let handler = f.raw_get::<u8, mlua::Value>(2)?;
 
         // Request object in handler should be ephemeral, otherwise graceful shutdown
@@ -100,8 +147,33 @@ impl Runtime {
         let req = self.lua().create_userdata(LuaRequest::new(req, params))?;
         TaskContext::register(self.lua(), req.clone())?;
 
-        let resp = self.call_extract_error(handler, req).await?;
-        return Ok(resp);
+        if slow_after.is_some() {
+          TaskContext::start_profiling(self.lua());
+          // The denser hook for sampling is installed on the next poll
+          tokio::task::yield_now().await;
+        }
+        let start = Instant::now();
+        let middlewares = internal.raw_get_path::<Table>("<internal>", &["middlewares"])?;
+        let result = if middlewares.raw_len() == 0 {
+          self.call_extract_error(handler, req).await
+        } else {
+          let dispatch = mlua::Value::Function(create_fn_dispatch(self.lua())?);
+          (self.call_extract_error(dispatch, (middlewares, 1, handler, req))).await
+        };
+
+        let elapsed = start.elapsed();
+        let profiler = TaskContext::stop_profiling(self.lua());
+        if let (Some(profiler), Some(slow_after)) = (profiler, slow_after) {
+          if elapsed >= slow_after {
+            let ms = elapsed.as_millis();
+            warn!(
+              "slow request to service '{}' at '{path}' ({ms} ms)",
+              guard.name
+            );
+            (self.state.profiles).record(&guard.name, path, elapsed, profiler);
+          }
+        }
+        return result;
       }
     }
     unreachable!("path matched but no handler found")
@@ -112,9 +184,13 @@ impl Runtime {
     &self,
     name: &str,
     source: Source,
+    readiness: Arc<Readiness>,
+    permissions: Permissions,
   ) -> Result<(Vec<PathMatcher>, Isolate)> {
     check_name(name)?;
-    let (isolate, internal) = self.run_source(name, source).await?;
+    let (isolate, internal) = (self)
+      .run_source(name, source, readiness, permissions)
+      .await?;
 
     let mut paths = Vec::new();
     for f in internal
@@ -131,19 +207,23 @@ impl Runtime {
 
   pub(crate) async fn create_service(
     &self,
-    name: &str,
     service: RunningService,
     isolate: Isolate,
     hot_update: bool,
   ) -> Result<()> {
-    if service.is_dropped() {
-      return Err(ServiceDropped.into());
-    }
+    let service_guard = service.try_upgrade()?;
+    let uuid = service_guard.uuid;
     let loaded = LoadedService {
       service: service.clone(),
       isolate,
+      permissions: service_guard.effective_permissions(),
     };
-    self.loaded.borrow_mut().put(name.into(), loaded);
+    drop(service_guard);
+    // Services updated with identical source keep their UUID
+    let replaced = self.loaded.borrow_mut().put(uuid, loaded);
+    if let Some(replaced) = replaced {
+      self.remove_isolate(replaced.isolate)?;
+    }
     if !hot_update {
       self.run_start(service).await?;
     }
@@ -165,6 +245,9 @@ impl Runtime {
   }
 
   pub(crate) async fn run_stop(&self, service: RunningService) -> Result<()> {
+    if let Ok(guard) = service.try_upgrade() {
+      guard.tunnels.close_all();
+    }
     let stop_fn: Option<Function> = {
       let loaded = self.load_service(service).await?;
       self
@@ -178,12 +261,124 @@ impl Runtime {
     Ok(())
   }
 
-  async fn run_source<'a>(&'a self, name: &str, source: Source) -> Result<(Isolate, Table<'a>)> {
+  /// Calls the `abel.on_schedule` handler for a due scheduled call.
+  pub(crate) async fn handle_schedule(
+    &self,
+    service: RunningService,
+    call: &ScheduledCall,
+  ) -> Result<()> {
+    {
+      let guard = service.try_upgrade()?;
+      TaskContext::set_metrics(self.lua(), guard.metrics.clone());
+      TaskContext::set_diagnostics(self.lua(), self.state.diagnostics.get(&guard.name));
+    }
+    let handler: mlua::Value = {
+      let loaded = self.load_service(service).await?;
+      self
+        .get_internal(&loaded.isolate)?
+        .raw_get_path("<internal>", &["schedules", &call.name])?
+    };
+    if let mlua::Value::Nil = handler {
+      let msg = rt_error_fmt!("no handler registered for schedule '{}'", call.name);
+      return Err(msg.into());
+    }
+    let lua = self.lua();
+    let info = lua.create_table_from([
+      ("id", lua.pack(&*call.id)?),
+      ("at", lua.pack(call.at as f64 / 1000.)?),
+      ("attempts", lua.pack(call.attempts)?),
+    ])?;
+    (self)
+      .call_extract_error(handler, (lua.to_value(&call.payload)?, info))
+      .await
+  }
+
+  async fn run_source<'a>(
+    &'a self,
+    name: &str,
+    source: Source,
+    readiness: Arc<Readiness>,
+    permissions: Permissions,
+  ) -> Result<(Isolate, Table<'a>)> {
+    TaskContext::set_diagnostics(self.lua(), self.state.diagnostics.get(name));
     let local_storage_path = get_local_storage_path(&self.state, name);
-    let isolate = self
-      .isolate_builder_with_stdlib(source.clone(), local_storage_path)?
-      .add_side_effect(side_effect_abel)?
-      .add_side_effect(side_effect_log(name))?
+    let secrets = load_secrets(&self.state, name).await?;
+    #[cfg(feature = "encryption")]
+    let storage_key = match &self.state.storage_key {
+      Some(master) => Some(Arc::new(StorageKey::derive(master, name)?)),
+      None => None,
+    };
+    #[cfg(not(feature = "encryption"))]
+    let storage_key: Option<Arc<StorageKey>> = None;
+
+    // Every module reaching out goes through the same policy-checked client.
+    // Without the `net` permission, nothing is reachable.
+    let (http_client, net_policy) = match &permissions.net {
+      NetPermission::All(true) => (HttpClient::Default, None),
+      NetPermission::All(false) => {
+        let policy = Arc::new(NetPolicy::new(name, Vec::new()));
+        let client = HttpClient::restricted(&self.state.http_client, policy);
+        (client, None)
+      }
+      NetPermission::Rules(rules) => {
+        let policy = Arc::new(NetPolicy::new(name, &rules[..]));
+        let client = HttpClient::restricted(&self.state.http_client, policy.clone());
+        (client, Some(policy))
+      }
+    };
+
+    let s3 = create_preload_s3(S3Config::from_secrets(&secrets), http_client.clone());
+    let mut builder = self
+      .isolate_builder_with_stdlib(source.clone(), local_storage_path.clone())?
+      .add_lib("s3", s3)?
+      .add_lib("session", create_preload_session(&secrets))?;
+    #[cfg(feature = "oauth")]
+    if permissions.oauth {
+      let oauth = create_preload_oauth(&secrets, http_client.clone());
+      builder = builder.add_lib("oauth", oauth)?;
+    }
+    #[cfg(feature = "encryption")]
+    if let Some(key) = &storage_key {
+      let fs = create_preload_fs(source.clone(), local_storage_path.into(), Some(key.clone()));
+      builder = builder.add_lib("fs", fs)?;
+    }
+    if let Some(policy) = &net_policy {
+      builder = builder.add_lib("dns", create_preload_dns_restricted(policy.clone()))?;
+    } else if permissions.net == NetPermission::All(true) {
+      builder = builder.add_lib("dns", create_preload_dns)?;
+    }
+    if let HttpClient::Restricted(_) = http_client {
+      builder = builder.add_lib("http", create_preload_http_restricted(http_client))?;
+    }
+    if !permissions.socket.is_empty() {
+      let socket = create_preload_socket(permissions.socket.into(), net_policy);
+      builder = builder.add_lib("socket", socket)?;
+    }
+    if let Some(rate_limit) = permissions.email {
+      let smtp = SmtpConfig::from_secrets(&secrets);
+      builder = builder.add_lib("email", create_preload_email(name.into(), smtp, rate_limit))?;
+    }
+    // Programs must be allowed by the host as well
+    let exec = (permissions.exec.into_iter())
+      .filter(|x| self.state.exec_allowlist.contains(x))
+      .collect::<Arc<[_]>>();
+    if !exec.is_empty() {
+      builder = builder.add_lib("exec", create_preload_exec(exec))?;
+    }
+    for (module, f) in self.state.lua_modules.get() {
+      builder = builder.add_lib(&module, create_preload_lua_module(f))?;
+    }
+    let isolate = builder
+      .add_side_effect(side_effect_abel(
+        name,
+        readiness,
+        self.state.coordinator.clone(),
+        self.state.queues.get(name)?,
+        self.state.kv.get(name, storage_key),
+        self.state.schedules.get(name)?,
+        self.state.metrics.get(name),
+      ))?
+      .add_side_effect(side_effect_log(name, self.state.events.clone()))?
       .build()?;
     self.run_isolate(&isolate, "main.lua", ()).await?;
 
@@ -196,18 +391,23 @@ impl Runtime {
   async fn load_service(&self, service: RunningService) -> Result<Ref<'_, LoadedService>> {
     let service_guard = service.try_upgrade()?;
     let name = &*service_guard.name;
+    let uuid = service_guard.uuid;
+    let permissions = service_guard.effective_permissions();
     {
       let mut self_loaded = self.loaded.borrow_mut();
-      if let Some(loaded) = self_loaded.pop(name) {
-        if !loaded.service.is_dropped() && loaded.service.ptr_eq(&service) {
+      if let Some(loaded) = self_loaded.pop(&uuid) {
+        if !loaded.service.is_dropped()
+          && loaded.service.ptr_eq(&service)
+          && loaded.permissions == permissions
+        {
           debug!(
             "service '{name}' cache hit on '{}'",
             std::thread::current().name().unwrap_or("<unnamed>")
           );
-          self_loaded.put(name.into(), loaded);
+          self_loaded.put(uuid, loaded);
           drop(self_loaded);
-          self.loaded.borrow_mut().get(name);
-          return Ok(Ref::map(self.loaded.borrow(), |x| x.peek(name).unwrap()));
+          self.loaded.borrow_mut().get(&uuid);
+          return Ok(Ref::map(self.loaded.borrow(), |x| x.peek(&uuid).unwrap()));
         } else {
           self.remove_isolate(loaded.isolate)?;
         }
@@ -218,17 +418,26 @@ impl Runtime {
       );
     }
     let source = service_guard.source();
-    let (isolate, _) = self.run_source(name, source.clone()).await?;
+    let readiness = service_guard.readiness.clone();
+    let (isolate, _) = (self)
+      .run_source(name, source.clone(), readiness, permissions.clone())
+      .await?;
 
     let loaded = LoadedService {
       service: service.clone(),
       isolate,
+      permissions,
     };
     let mut self_loaded = self.loaded.borrow_mut();
-    self_loaded.put(name.into(), loaded);
+    self_loaded.put(uuid, loaded);
     drop(self_loaded);
-    self.loaded.borrow_mut().get(name);
-    Ok(Ref::map(self.loaded.borrow(), |x| x.peek(name).unwrap()))
+    self.loaded.borrow_mut().get(&uuid);
+    Ok(Ref::map(self.loaded.borrow(), |x| x.peek(&uuid).unwrap()))
+  }
+
+  /// Number of service instances loaded in this runtime.
+  pub fn loaded_count(&self) -> usize {
+    self.loaded.borrow().len()
   }
 
   pub fn cleanup(&self) {
@@ -240,9 +449,31 @@ impl Runtime {
       }
       r
     });
+
+    let cached_count = self
+      .lua()
+      .app_data_mut::<ServiceCaches>()
+      .map(|mut caches| {
+        let loaded = self.loaded.borrow();
+        caches.cleanup(|name| {
+          loaded.iter().any(|(_, x)| {
+            x.service
+              .try_upgrade()
+              .map(|x| &*x.name == name)
+              .unwrap_or(false)
+          })
+        })
+      });
+
+    if count > 0 || cached_count.unwrap_or(0) > 0 {
+      self.expire_registry_values();
+    }
     if count > 0 {
       info!("successfully cleaned {count} dropped services");
     }
+    if let Err(error) = self.lua().gc_collect() {
+      warn!("garbage collection failed: {error}");
+    }
   }
 }
 
@@ -0,0 +1,501 @@
+//! Durable job queues of services, exposed as `abel.queue`.
+//!
+//! Each job is stored as a record under `<local storage>/.queues/<service>`,
+//! so that pending jobs survive restarts. Popped jobs become visible again
+//! after their visibility timeout unless acknowledged with the receipt of that
+//! pop, and are moved to the `dead` subdirectory after failing `max_attempts`
+//! times.
+
+use super::id::{ulid, unix_millis};
+use super::records::{Record, RecordStore, Records};
+use super::retry::is_timeout;
+use crate::lua::error::{
+  check_string, check_value, resolve_callback_error, rt_error, tag_handler, TableCheckExt,
+};
+use dashmap::DashMap;
+use mlua::Value::Nil;
+use mlua::{FromLua, Function, Lua, LuaSerdeExt, MultiValue, Table};
+use rand::{thread_rng, Rng};
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use uuid::Uuid;
+
+const DEFAULT_MAX_ATTEMPTS: u32 = 5;
+const DEFAULT_VISIBILITY_TIMEOUT: u64 = 30000;
+const RETRY_BASE_DELAY: u64 = 1000;
+const RETRY_MAX_DELAY: u64 = 3_600_000;
+
+/// Jobs a service may keep, pending and dead ones together.
+const MAX_JOBS: usize = 10_000;
+/// Bytes of a job's JSON payload. Along with [`MAX_JOBS`], this bounds the
+/// disk space a service's queue takes.
+const MAX_PAYLOAD_LEN: usize = 64 * 1024;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Job {
+  id: String,
+  payload: serde_json::Value,
+  attempts: u32,
+  max_attempts: u32,
+  /// Unix milliseconds after which the job may be popped.
+  visible_at: u64,
+  /// Token of the latest pop, required to acknowledge the job.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  receipt: Option<String>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  last_error: Option<String>,
+}
+
+impl Record for Job {
+  fn id(&self) -> &str {
+    &self.id
+  }
+}
+
+impl Job {
+  /// Receipt handle given to Lua, as `<id>:<token>`.
+  fn receipt_handle(&self) -> Option<String> {
+    (self.receipt.as_ref()).map(|token| format!("{}:{token}", self.id))
+  }
+
+  fn to_lua<'lua>(&self, lua: &'lua Lua) -> mlua::Result<Table<'lua>> {
+    lua.create_table_from([
+      ("id", lua.pack(&*self.id)?),
+      ("receipt", lua.pack(self.receipt_handle())?),
+      ("payload", lua.to_value(&self.payload)?),
+      ("attempts", lua.pack(self.attempts)?),
+      ("max_attempts", lua.pack(self.max_attempts)?),
+      ("last_error", lua.pack(self.last_error.as_deref())?),
+    ])
+  }
+}
+
+/// Outcome of a failed job.
+#[derive(Debug, PartialEq, Eq)]
+enum Failure {
+  Retry,
+  Dead,
+}
+
+/// Jobs of one service, loaded from disk on first use.
+#[derive(Debug)]
+pub(super) struct JobQueue {
+  jobs: Records<Job>,
+  dead: Records<Job>,
+}
+
+impl JobQueue {
+  async fn len(&mut self) -> io::Result<usize> {
+    let pending = self.jobs.load().await?.1.len();
+    Ok(pending + self.dead.load().await?.1.len())
+  }
+
+  async fn push(
+    &mut self,
+    payload: serde_json::Value,
+    delay: u64,
+    max_attempts: u32,
+  ) -> mlua::Result<String> {
+    if self.len().await.map_err(rt_error)? >= MAX_JOBS {
+      return Err(rt_error(format!("queue is full ({MAX_JOBS} jobs)")));
+    }
+    let payload_len = serde_json::to_vec(&payload).map_err(rt_error)?.len();
+    if payload_len > MAX_PAYLOAD_LEN {
+      let msg = format!("job payload exceeds {MAX_PAYLOAD_LEN} bytes");
+      return Err(rt_error(msg));
+    }
+    let now = unix_millis();
+    let job = Job {
+      id: ulid(now, thread_rng().gen()),
+      payload,
+      attempts: 0,
+      max_attempts,
+      visible_at: now.saturating_add(delay),
+      receipt: None,
+      last_error: None,
+    };
+    let id = job.id.clone();
+    self.jobs.insert(job).await.map_err(rt_error)?;
+    Ok(id)
+  }
+
+  /// Takes the oldest visible job, hiding it for `visibility_timeout`
+  /// milliseconds. The job gets a new receipt, so that whoever popped it
+  /// before can no longer acknowledge it.
+  async fn pop(&mut self, visibility_timeout: u64) -> io::Result<Option<Job>> {
+    let now = unix_millis();
+    let (dir, jobs) = self.jobs.load().await?;
+    let job = match jobs.values_mut().find(|x| x.visible_at <= now) {
+      Some(job) => job,
+      None => return Ok(None),
+    };
+    job.attempts += 1;
+    job.visible_at = now.saturating_add(visibility_timeout);
+    job.receipt = Some(Uuid::new_v4().to_simple().to_string());
+    dir.write(job).await?;
+    Ok(Some(job.clone()))
+  }
+
+  /// Finds the job popped with `receipt`.
+  async fn popped(&mut self, receipt: &str) -> io::Result<Option<&mut Job>> {
+    let (id, token) = match receipt.split_once(':') {
+      Some(x) => x,
+      None => return Ok(None),
+    };
+    let (_, jobs) = self.jobs.load().await?;
+    Ok((jobs.get_mut(id)).filter(|job| job.receipt.as_deref() == Some(token)))
+  }
+
+  /// Removes a finished job, returning whether `receipt` is still valid.
+  async fn ack(&mut self, receipt: &str) -> io::Result<bool> {
+    let id = match self.popped(receipt).await? {
+      Some(job) => job.id.clone(),
+      None => return Ok(false),
+    };
+    self.jobs.remove(&id).await?;
+    Ok(true)
+  }
+
+  /// Makes a failed job visible again after a backoff, or moves it to the
+  /// dead-letter queue if it has used up its attempts. Returns `None` if
+  /// `receipt` is no longer valid.
+  async fn nack(
+    &mut self,
+    receipt: &str,
+    error: String,
+    delay: Option<u64>,
+  ) -> io::Result<Option<Failure>> {
+    let job = match self.popped(receipt).await? {
+      Some(job) => job,
+      None => return Ok(None),
+    };
+    job.last_error = Some(error);
+    job.receipt = None;
+    if job.attempts >= job.max_attempts {
+      let job = job.clone();
+      self.dead.insert(job.clone()).await?;
+      self.jobs.remove(&job.id).await?;
+      Ok(Some(Failure::Dead))
+    } else {
+      let delay = delay.unwrap_or_else(|| {
+        RETRY_BASE_DELAY
+          .saturating_mul(1 << (job.attempts - 1).min(31))
+          .min(RETRY_MAX_DELAY)
+      });
+      job.visible_at = unix_millis().saturating_add(delay);
+      let job = job.clone();
+      self.jobs.insert(job).await?;
+      Ok(Some(Failure::Retry))
+    }
+  }
+
+  async fn dead(&mut self) -> io::Result<Vec<Job>> {
+    Ok(self.dead.load().await?.1.values().cloned().collect())
+  }
+
+  /// Moves a dead job back to the queue with its attempts reset.
+  async fn requeue(&mut self, id: &str) -> io::Result<bool> {
+    let mut job = match self.dead.load().await?.1.get(id) {
+      Some(job) => job.clone(),
+      None => return Ok(false),
+    };
+    job.attempts = 0;
+    job.visible_at = unix_millis();
+    self.jobs.insert(job).await?;
+    self.dead.remove(id).await?;
+    Ok(true)
+  }
+}
+
+pub(super) type SharedQueue = Arc<Mutex<JobQueue>>;
+
+/// Job queues of all services, shared by workers.
+#[derive(Debug)]
+pub(crate) struct JobQueues {
+  store: RecordStore,
+  queues: DashMap<String, SharedQueue>,
+}
+
+impl JobQueues {
+  /// Jobs are sealed if `master` is set, the master key encrypting local
+  /// storage.
+  pub fn new(path: PathBuf, master: Option<[u8; 32]>) -> Self {
+    Self {
+      store: RecordStore::new(path, master),
+      queues: DashMap::new(),
+    }
+  }
+
+  pub(super) fn get(&self, service_name: &str) -> io::Result<SharedQueue> {
+    if let Some(queue) = self.queues.get(service_name) {
+      return Ok(queue.clone());
+    }
+    let dir = self.store.get(service_name)?;
+    let queue = (self.queues.entry(service_name.into())).or_insert_with(|| {
+      Arc::new(Mutex::new(JobQueue {
+        jobs: Records::new(dir.clone()),
+        dead: Records::new(dir.join("dead")),
+      }))
+    });
+    Ok(queue.clone())
+  }
+
+  /// Deletes all jobs of a removed service.
+  pub async fn remove(&self, service_name: &str) -> io::Result<()> {
+    self.queues.remove(service_name);
+    self.store.remove(service_name).await
+  }
+}
+
+/// Creates `abel.queue` for a service.
+///
+/// - `push(payload[, opts])`: stores a JSON-serializable job, returning its ID.
+///   Options are `delay` in milliseconds and `max_attempts` (default 5). A
+///   service keeps at most 10000 jobs with payloads up to 64 KiB.
+/// - `pop([visibility_timeout])`: takes the oldest visible job, hiding it for
+///   `visibility_timeout` milliseconds (default 30000). The job's `receipt`
+///   acknowledges this pop only.
+/// - `ack(receipt)`: removes a finished job, returning `false` if it has been
+///   popped again since.
+/// - `nack(receipt[, error[, delay]])`: retries a failed job after `delay`
+///   milliseconds, exponential backoff by default. Returns `"retry"`, or
+///   `"dead"` if it has been moved to the dead-letter queue.
+/// - `work(handler[, opts])`: calls `handler(payload, job)` for each visible
+///   job until none is left, acknowledging or retrying them. Returns the number
+///   of jobs processed.
+/// - `dead()`, `requeue(id)`: lists and retries dead jobs.
+pub(super) fn create_table_queue(lua: &Lua, queue: SharedQueue) -> mlua::Result<Table> {
+  let table = lua.create_table()?;
+  table.raw_set("push", create_fn_push(lua, queue.clone())?)?;
+  table.raw_set("pop", create_fn_pop(lua, queue.clone())?)?;
+  table.raw_set("ack", create_fn_ack(lua, queue.clone())?)?;
+  table.raw_set("nack", create_fn_nack(lua, queue.clone())?)?;
+  table.raw_set("work", create_fn_work(lua, queue.clone())?)?;
+  table.raw_set("dead", create_fn_dead(lua, queue.clone())?)?;
+  table.raw_set("requeue", create_fn_requeue(lua, queue)?)?;
+  Ok(table)
+}
+
+/// Checks an optional argument, which may also be absent.
+pub(super) fn check_optional<'lua, T: FromLua<'lua>>(
+  lua: &'lua Lua,
+  value: Option<mlua::Value<'lua>>,
+  pos: usize,
+  expected: &'static str,
+) -> mlua::Result<Option<T>> {
+  check_value(lua, Some(value.unwrap_or(Nil)), expected).map_err(tag_handler(lua, pos, 1))
+}
+
+fn create_fn_push(lua: &Lua, queue: SharedQueue) -> mlua::Result<Function> {
+  lua.create_async_function(move |lua, mut args: MultiValue| {
+    let queue = queue.clone();
+    async move {
+      let payload: serde_json::Value = lua.from_value(args.pop_front().unwrap_or(Nil))?;
+      let opts: Option<Table> = check_optional(lua, args.pop_front(), 2, "table")?;
+      let (delay, max_attempts) = match opts {
+        Some(opts) => (
+          opts.check_raw_get::<Option<u64>>(lua, "delay", "non-negative integer")?,
+          opts.check_raw_get::<Option<u32>>(lua, "max_attempts", "positive integer")?,
+        ),
+        None => (None, None),
+      };
+      let max_attempts = max_attempts.unwrap_or(DEFAULT_MAX_ATTEMPTS).max(1);
+      (queue.lock().await)
+        .push(payload, delay.unwrap_or(0), max_attempts)
+        .await
+    }
+  })
+}
+
+fn create_fn_pop(lua: &Lua, queue: SharedQueue) -> mlua::Result<Function> {
+  lua.create_async_function(move |lua, mut args: MultiValue| {
+    let queue = queue.clone();
+    async move {
+      let visibility_timeout = check_optional(lua, args.pop_front(), 1, "non-negative integer")?
+        .unwrap_or(DEFAULT_VISIBILITY_TIMEOUT);
+      let job = (queue.lock().await.pop(visibility_timeout).await).map_err(rt_error)?;
+      job.map(|x| x.to_lua(lua)).transpose()
+    }
+  })
+}
+
+fn create_fn_ack(lua: &Lua, queue: SharedQueue) -> mlua::Result<Function> {
+  lua.create_async_function(move |lua, mut args: MultiValue| {
+    let queue = queue.clone();
+    async move {
+      let receipt = check_string(lua, args.pop_front()).map_err(tag_handler(lua, 1, 1))?;
+      (queue.lock().await.ack(receipt.to_str()?).await).map_err(rt_error)
+    }
+  })
+}
+
+fn create_fn_nack(lua: &Lua, queue: SharedQueue) -> mlua::Result<Function> {
+  lua.create_async_function(move |lua, mut args: MultiValue| {
+    let queue = queue.clone();
+    async move {
+      let receipt = check_string(lua, args.pop_front()).map_err(tag_handler(lua, 1, 1))?;
+      let error: Option<String> = check_optional(lua, args.pop_front(), 2, "string")?;
+      let delay = check_optional(lua, args.pop_front(), 3, "non-negative integer")?;
+      let error = error.unwrap_or_else(|| "job failed".into());
+      let failure = (queue
+        .lock()
+        .await
+        .nack(receipt.to_str()?, error, delay)
+        .await)
+        .map_err(rt_error)?;
+      Ok(failure.map(|x| match x {
+        Failure::Retry => "retry",
+        Failure::Dead => "dead",
+      }))
+    }
+  })
+}
+
+fn create_fn_work(lua: &Lua, queue: SharedQueue) -> mlua::Result<Function> {
+  lua.create_async_function(move |lua, mut args: MultiValue| {
+    let queue = queue.clone();
+    async move {
+      let handler: Function =
+        check_value(lua, args.pop_front(), "function").map_err(tag_handler(lua, 1, 1))?;
+      let opts: Option<Table> = check_optional(lua, args.pop_front(), 2, "table")?;
+      let visibility_timeout = match &opts {
+        Some(opts) => {
+          (opts.check_raw_get::<Option<u64>>(lua, "visibility_timeout", "non-negative integer")?)
+            .unwrap_or(DEFAULT_VISIBILITY_TIMEOUT)
+        }
+        None => DEFAULT_VISIBILITY_TIMEOUT,
+      };
+
+      let mut count = 0u64;
+      loop {
+        // The lock is not held while running the handler, so that it can
+        // push more jobs.
+        let job = (queue.lock().await.pop(visibility_timeout).await).map_err(rt_error)?;
+        let job = match job {
+          Some(job) => job,
+          None => return Ok(count),
+        };
+        let result =
+          (handler.call_async::<_, ()>((lua.to_value(&job.payload)?, job.to_lua(lua)?))).await;
+        let receipt = job.receipt_handle().unwrap_or_default();
+        let mut guard = queue.lock().await;
+        match result {
+          Ok(()) => {
+            guard.ack(&receipt).await.map_err(rt_error)?;
+          }
+          Err(error) => {
+            let msg = resolve_callback_error(&error).to_string();
+            guard.nack(&receipt, msg, None).await.map_err(rt_error)?;
+            if is_timeout(&error) {
+              return Err(error);
+            }
+          }
+        }
+        count += 1;
+      }
+    }
+  })
+}
+
+fn create_fn_dead(lua: &Lua, queue: SharedQueue) -> mlua::Result<Function> {
+  lua.create_async_function(move |lua, ()| {
+    let queue = queue.clone();
+    async move {
+      let jobs = (queue.lock().await.dead().await).map_err(rt_error)?;
+      let jobs = (jobs.iter())
+        .map(|x| x.to_lua(lua))
+        .collect::<mlua::Result<Vec<_>>>()?;
+      lua.create_sequence_from(jobs)
+    }
+  })
+}
+
+fn create_fn_requeue(lua: &Lua, queue: SharedQueue) -> mlua::Result<Function> {
+  lua.create_async_function(move |lua, mut args: MultiValue| {
+    let queue = queue.clone();
+    async move {
+      let id = check_string(lua, args.pop_front()).map_err(tag_handler(lua, 1, 1))?;
+      (queue.lock().await.requeue(id.to_str()?).await).map_err(rt_error)
+    }
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use serde_json::json;
+  use tempfile::TempDir;
+
+  #[tokio::test]
+  async fn test_job_queue() -> mlua::Result<()> {
+    let dir = TempDir::new()?;
+    let queues = JobQueues::new(dir.path().into(), None);
+    let queue = queues.get("test")?;
+    let mut queue = queue.lock().await;
+    let id = queue.push(json!({ "n": 1 }), 0, 2).await?;
+    queue.push(json!(2), u64::MAX, 1).await?;
+
+    let job = queue.pop(60000).await?.unwrap();
+    assert_eq!((&*job.id, job.attempts), (&*id, 1));
+    assert!(queue.pop(60000).await?.is_none());
+    let receipt = job.receipt_handle().unwrap();
+    assert_eq!(
+      queue.nack(&receipt, "oops".into(), Some(0)).await?,
+      Some(Failure::Retry)
+    );
+    assert!(!queue.ack(&receipt).await?);
+    drop(queue);
+
+    // Reloaded from disk
+    let queues = JobQueues::new(dir.path().into(), None);
+    let queue = queues.get("test")?;
+    let mut queue = queue.lock().await;
+    let job = queue.pop(60000).await?.unwrap();
+    assert_eq!((&*job.id, job.attempts), (&*id, 2));
+    assert_eq!(job.last_error.as_deref(), Some("oops"));
+    let receipt = job.receipt_handle().unwrap();
+    assert_eq!(
+      queue.nack(&receipt, "oops".into(), None).await?,
+      Some(Failure::Dead)
+    );
+    assert_eq!(queue.dead().await?.len(), 1);
+
+    assert!(queue.requeue(&id).await?);
+    assert!(queue.dead().await?.is_empty());
+    let receipt = queue.pop(60000).await?.unwrap().receipt_handle().unwrap();
+    assert!(queue.ack(&receipt).await?);
+    assert!(!queue.ack(&receipt).await?);
+    Ok(())
+  }
+
+  #[tokio::test]
+  async fn test_stale_receipt() -> mlua::Result<()> {
+    let dir = TempDir::new()?;
+    let queues = JobQueues::new(dir.path().into(), None);
+    let queue = queues.get("test")?;
+    let mut queue = queue.lock().await;
+    queue.push(json!(1), 0, 5).await?;
+
+    // Popped again after the visibility timeout passes
+    let first = queue.pop(0).await?.unwrap().receipt_handle().unwrap();
+    let second = queue.pop(60000).await?.unwrap().receipt_handle().unwrap();
+    assert!(!queue.ack(&first).await?);
+    assert_eq!(queue.nack(&first, "oops".into(), None).await?, None);
+    assert!(queue.ack(&second).await?);
+    Ok(())
+  }
+
+  #[tokio::test]
+  async fn test_limits() -> mlua::Result<()> {
+    let dir = TempDir::new()?;
+    let queues = JobQueues::new(dir.path().into(), None);
+    let queue = queues.get("test")?;
+    let mut queue = queue.lock().await;
+    let large = json!("a".repeat(MAX_PAYLOAD_LEN));
+    assert!(queue.push(large, 0, 1).await.is_err());
+    Ok(())
+  }
+}
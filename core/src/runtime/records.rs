@@ -0,0 +1,281 @@
+//! Records of services persisted one file each, backing job queues and
+//! scheduled calls.
+//!
+//! Records are stored as `<id>.json` under `<dir>/<service>`, sealed with the
+//! service's storage key if local storage is encrypted. They are written
+//! atomically and flushed to disk, so that a crash neither leaves them
+//! half-written nor loses them.
+
+#[cfg(feature = "encryption")]
+use crate::encryption::StorageKey;
+#[cfg(not(feature = "encryption"))]
+use crate::lua::fs::StorageKey;
+use log::error;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::fs::{self, File};
+use tokio::io::AsyncWriteExt;
+
+pub(super) trait Record: Serialize + DeserializeOwned {
+  fn id(&self) -> &str;
+}
+
+/// Directory of records of one service.
+#[derive(Debug, Clone)]
+pub(super) struct RecordDir {
+  path: PathBuf,
+  key: Option<Arc<StorageKey>>,
+}
+
+impl RecordDir {
+  pub fn new(path: PathBuf, key: Option<Arc<StorageKey>>) -> Self {
+    Self { path, key }
+  }
+
+  /// Subdirectory sealed with the same key.
+  pub fn join(&self, name: &str) -> Self {
+    Self {
+      path: self.path.join(name),
+      key: self.key.clone(),
+    }
+  }
+
+  fn record_path(&self, id: &str) -> PathBuf {
+    self.path.join(format!("{id}.json"))
+  }
+
+  fn decode<T: Record>(&self, data: Vec<u8>) -> io::Result<T> {
+    let data = match &self.key {
+      #[cfg(feature = "encryption")]
+      Some(key) => key.open(data)?,
+      _ => data,
+    };
+    Ok(serde_json::from_slice(&data)?)
+  }
+
+  fn encode<T: Record>(&self, record: &T) -> io::Result<Vec<u8>> {
+    let data = serde_json::to_vec(record)?;
+    match &self.key {
+      #[cfg(feature = "encryption")]
+      Some(key) => key.seal(&data),
+      _ => Ok(data),
+    }
+  }
+
+  pub async fn read<T: Record>(&self, id: &str) -> io::Result<Option<T>> {
+    match fs::read(self.record_path(id)).await {
+      Ok(data) => self.decode(data).map(Some),
+      Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(None),
+      Err(error) => Err(error),
+    }
+  }
+
+  /// Reads all records.
+  ///
+  /// Records that cannot be read are renamed to `<id>.json.corrupt`, so that
+  /// they are kept for inspection without blocking the others.
+  pub async fn read_all<T: Record>(&self) -> io::Result<BTreeMap<String, T>> {
+    let mut records = BTreeMap::new();
+    let mut entries = match fs::read_dir(&self.path).await {
+      Ok(entries) => entries,
+      Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(records),
+      Err(error) => return Err(error),
+    };
+    while let Some(entry) = entries.next_entry().await? {
+      let path = entry.path();
+      if path.extension().map_or(true, |x| x != "json") {
+        continue;
+      }
+      match self.decode::<T>(fs::read(&path).await?) {
+        Ok(record) => {
+          records.insert(record.id().into(), record);
+        }
+        Err(error) => {
+          let mut corrupt_path = path.clone().into_os_string();
+          corrupt_path.push(".corrupt");
+          fs::rename(&path, &corrupt_path).await?;
+          error!(
+            "moved unreadable record '{}' aside: {error}",
+            path.display()
+          );
+        }
+      }
+    }
+    Ok(records)
+  }
+
+  pub async fn write<T: Record>(&self, record: &T) -> io::Result<()> {
+    fs::create_dir_all(&self.path).await?;
+    let temp_path = self.path.join(format!("{}.json.tmp", record.id()));
+    let mut file = File::create(&temp_path).await?;
+    file.write_all(&self.encode(record)?).await?;
+    file.sync_all().await?;
+    drop(file);
+    fs::rename(temp_path, self.record_path(record.id())).await?;
+    sync_dir(&self.path).await
+  }
+
+  pub async fn remove(&self, id: &str) -> io::Result<()> {
+    remove_file_if_exists(&self.record_path(id)).await
+  }
+}
+
+/// Flushes a directory's entries, including renames in it, to disk on Unix.
+async fn sync_dir(path: &Path) -> io::Result<()> {
+  if cfg!(unix) {
+    File::open(path).await?.sync_all().await?;
+  }
+  Ok(())
+}
+
+pub(super) async fn remove_file_if_exists(path: &Path) -> io::Result<()> {
+  match fs::remove_file(path).await {
+    Err(error) if error.kind() != io::ErrorKind::NotFound => Err(error),
+    _ => Ok(()),
+  }
+}
+
+/// Records of one service, loaded from disk on first use.
+#[derive(Debug)]
+pub(super) struct Records<T> {
+  dir: RecordDir,
+  records: Option<BTreeMap<String, T>>,
+}
+
+impl<T: Record> Records<T> {
+  pub fn new(dir: RecordDir) -> Self {
+    Self { dir, records: None }
+  }
+
+  /// Returns the directory to write changed records to, along with the
+  /// records.
+  pub async fn load(&mut self) -> io::Result<(&RecordDir, &mut BTreeMap<String, T>)> {
+    if self.records.is_none() {
+      self.records = Some(self.dir.read_all().await?);
+    }
+    Ok((&self.dir, self.records.as_mut().unwrap()))
+  }
+
+  pub async fn insert(&mut self, record: T) -> io::Result<()> {
+    self.dir.write(&record).await?;
+    let (_, records) = self.load().await?;
+    records.insert(record.id().into(), record);
+    Ok(())
+  }
+
+  pub async fn remove(&mut self, id: &str) -> io::Result<Option<T>> {
+    let (dir, records) = self.load().await?;
+    let removed = records.remove(id);
+    if removed.is_some() {
+      dir.remove(id).await?;
+    }
+    Ok(removed)
+  }
+}
+
+/// Directories of records of all services.
+#[derive(Debug)]
+pub(super) struct RecordStore {
+  path: PathBuf,
+  /// Master key encrypting local storage.
+  #[cfg_attr(not(feature = "encryption"), allow(dead_code))]
+  master: Option<[u8; 32]>,
+}
+
+impl RecordStore {
+  pub fn new(path: PathBuf, master: Option<[u8; 32]>) -> Self {
+    Self { path, master }
+  }
+
+  pub fn get(&self, service_name: &str) -> io::Result<RecordDir> {
+    #[cfg(feature = "encryption")]
+    let key = match &self.master {
+      Some(master) => Some(Arc::new(StorageKey::derive(master, service_name)?)),
+      None => None,
+    };
+    #[cfg(not(feature = "encryption"))]
+    let key = None;
+    Ok(RecordDir::new(self.path.join(service_name), key))
+  }
+
+  /// Deletes all records of a removed service.
+  pub async fn remove(&self, service_name: &str) -> io::Result<()> {
+    match fs::remove_dir_all(self.path.join(service_name)).await {
+      Err(error) if error.kind() != io::ErrorKind::NotFound => Err(error),
+      _ => Ok(()),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use serde::Deserialize;
+  use tempfile::TempDir;
+
+  #[derive(Debug, PartialEq, Serialize, Deserialize)]
+  struct TestRecord {
+    id: String,
+    value: u32,
+  }
+
+  impl Record for TestRecord {
+    fn id(&self) -> &str {
+      &self.id
+    }
+  }
+
+  fn record(id: &str, value: u32) -> TestRecord {
+    TestRecord {
+      id: id.into(),
+      value,
+    }
+  }
+
+  #[tokio::test]
+  async fn test_records() -> io::Result<()> {
+    let dir = TempDir::new()?;
+    let store = RecordStore::new(dir.path().into(), None);
+    let mut records = Records::new(store.get("test")?);
+    records.insert(record("a", 1)).await?;
+    records.insert(record("b", 2)).await?;
+    assert_eq!(records.remove("a").await?, Some(record("a", 1)));
+    assert_eq!(records.remove("a").await?, None);
+
+    let mut records = Records::<TestRecord>::new(store.get("test")?);
+    let (_, loaded) = records.load().await?;
+    assert_eq!(loaded.len(), 1);
+    assert_eq!(loaded["b"], record("b", 2));
+    Ok(())
+  }
+
+  #[tokio::test]
+  async fn test_corrupt_record() -> io::Result<()> {
+    let dir = TempDir::new()?;
+    let records = RecordStore::new(dir.path().into(), None).get("test")?;
+    records.write(&record("a", 1)).await?;
+    std::fs::write(dir.path().join("test/b.json"), b"{")?;
+
+    let loaded = records.read_all::<TestRecord>().await?;
+    assert_eq!(loaded.keys().collect::<Vec<_>>(), ["a"]);
+    assert!(dir.path().join("test/b.json.corrupt").exists());
+    Ok(())
+  }
+
+  #[cfg(feature = "encryption")]
+  #[tokio::test]
+  async fn test_records_encrypted() -> io::Result<()> {
+    let dir = TempDir::new()?;
+    let store = RecordStore::new(dir.path().into(), Some([1; 32]));
+    let records = store.get("test")?;
+    records.write(&record("a", 12345)).await?;
+    let raw = std::fs::read(dir.path().join("test/a.json"))?;
+    assert!(!String::from_utf8_lossy(&raw).contains("12345"));
+    assert_eq!(records.read("a").await?, Some(record("a", 12345)));
+    Ok(())
+  }
+}
@@ -0,0 +1,326 @@
+//! Resilient outbound calls: `abel.retry` and `abel.breaker`.
+//!
+//! Both treat errors and HTTP responses with status 429 or 5xx as failures.
+
+use crate::lua::error::{
+  check_value, http_error, resolve_callback_error, tag_error, tag_handler, TableCheckExt,
+};
+use crate::lua::http::LuaResponse;
+use crate::lua::LuaCacheExt;
+use crate::task::TimeoutError;
+use hyper::{StatusCode, Uri};
+use mlua::{Function, Lua, MultiValue, Table};
+use rand::Rng;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+fn is_failure(result: &mlua::Result<MultiValue>) -> bool {
+  match result {
+    Ok(values) => match values.iter().next() {
+      Some(mlua::Value::UserData(u)) => (u.borrow::<LuaResponse>())
+        .map(|x| x.status == StatusCode::TOO_MANY_REQUESTS || x.status.is_server_error())
+        .unwrap_or(false),
+      _ => false,
+    },
+    Err(_) => true,
+  }
+}
+
+pub(super) fn is_timeout(error: &mlua::Error) -> bool {
+  matches!(
+    resolve_callback_error(error),
+    mlua::Error::ExternalError(ext) if ext.is::<TimeoutError>(),
+  )
+}
+
+fn check_millis(lua: &Lua, opts: &Table, field: &str) -> mlua::Result<Option<Duration>> {
+  (opts.check_raw_get::<Option<u64>>(lua, field, "non-negative integer")?)
+    .map(|x| Ok(Duration::from_millis(x)))
+    .transpose()
+}
+
+struct RetryOptions<'lua> {
+  attempts: u32,
+  base_delay: Duration,
+  max_delay: Duration,
+  jitter: bool,
+  retry_on: Option<Function<'lua>>,
+}
+
+impl<'lua> RetryOptions<'lua> {
+  fn from_table(lua: &'lua Lua, opts: Option<Table<'lua>>) -> mlua::Result<Self> {
+    let opts = match opts {
+      Some(opts) => opts,
+      None => lua.create_table()?,
+    };
+    Ok(Self {
+      attempts: (opts.check_raw_get::<Option<u32>>(lua, "attempts", "positive integer")?)
+        .unwrap_or(3)
+        .max(1),
+      base_delay: check_millis(lua, &opts, "base_delay")?.unwrap_or(Duration::from_millis(100)),
+      max_delay: check_millis(lua, &opts, "max_delay")?.unwrap_or(Duration::from_secs(10)),
+      jitter: (opts.check_raw_get::<Option<bool>>(lua, "jitter", "boolean")?).unwrap_or(true),
+      retry_on: opts.check_raw_get(lua, "retry_on", "function")?,
+    })
+  }
+
+  /// Exponential backoff, randomized between half and all of the delay if
+  /// `jitter` is set.
+  fn delay(&self, attempt: u32) -> Duration {
+    let delay = (self.base_delay)
+      .saturating_mul(2u32.saturating_pow(attempt - 1))
+      .min(self.max_delay);
+    if self.jitter {
+      delay.mul_f64(rand::thread_rng().gen_range(0.5..=1.0))
+    } else {
+      delay
+    }
+  }
+}
+
+/// `abel.retry(opts, f, ...)`
+///
+/// Calls `f(...)` until it succeeds or `attempts` is reached, returning its
+/// last result.
+///
+/// Options:
+/// - `attempts`: maximum number of calls, defaulting to 3
+/// - `base_delay`, `max_delay`: milliseconds to wait before the first retry,
+///   doubled each time up to `max_delay`
+/// - `jitter`: randomizes delays, defaulting to `true`
+/// - `retry_on(err_or_resp)`: decides whether a failure should be retried
+pub(super) fn create_fn_retry(lua: &Lua) -> mlua::Result<Function> {
+  lua.create_cached_async_function("abel:abel.retry", |lua, mut args: MultiValue| async move {
+    let opts: Option<Table> =
+      check_value(lua, args.pop_front(), "table").map_err(tag_handler(lua, 1, 1))?;
+    let f: Function =
+      check_value(lua, args.pop_front(), "function").map_err(tag_handler(lua, 2, 1))?;
+    let opts = RetryOptions::from_table(lua, opts)?;
+
+    let mut attempt = 1;
+    loop {
+      let result = f.call_async::<_, MultiValue>(args.clone()).await;
+      match &result {
+        Err(error) if is_timeout(error) => return result,
+        _ if attempt >= opts.attempts || !is_failure(&result) => return result,
+        _ => {}
+      }
+      if let Some(retry_on) = &opts.retry_on {
+        let value = match &result {
+          Ok(values) => values.iter().next().cloned().unwrap_or(mlua::Value::Nil),
+          Err(error) => mlua::Value::Error(error.clone()),
+        };
+        if !retry_on.call_async::<_, bool>(value).await? {
+          return result;
+        }
+      }
+      tokio::time::sleep(opts.delay(attempt)).await;
+      attempt += 1;
+    }
+  })
+}
+
+/// Longest time a circuit stays open, so that the reopening time cannot
+/// overflow.
+const MAX_COOLDOWN: Duration = Duration::from_secs(86400);
+
+#[derive(Debug, Default)]
+struct Circuit {
+  failures: u32,
+  open_until: Option<Instant>,
+  /// Whether a trial call is in progress after the circuit opened.
+  trial: bool,
+}
+
+impl Circuit {
+  /// Lets a call through unless the circuit is open, starting a trial call
+  /// once the cooldown is over. Otherwise returns how long until calls may
+  /// go through.
+  fn admit(&mut self, now: Instant) -> Result<(), Duration> {
+    if let Some(until) = self.open_until {
+      if now < until || self.trial {
+        return Err(until.saturating_duration_since(now));
+      }
+      self.trial = true;
+    }
+    Ok(())
+  }
+
+  fn record(&mut self, failed: bool, threshold: u32, cooldown: Duration, now: Instant) {
+    self.trial = false;
+    if !failed {
+      *self = Self::default();
+      return;
+    }
+    self.failures += 1;
+    if self.failures >= threshold || self.open_until.is_some() {
+      self.open_until = Some(now + cooldown.min(MAX_COOLDOWN));
+    }
+  }
+}
+
+type Circuits = Rc<RefCell<HashMap<String, Circuit>>>;
+
+/// Ends a trial call even if it is cancelled, so that the circuit does not
+/// stay open forever.
+struct TrialGuard {
+  circuits: Circuits,
+  key: String,
+}
+
+impl Drop for TrialGuard {
+  fn drop(&mut self) {
+    if let Some(circuit) = self.circuits.borrow_mut().get_mut(&self.key) {
+      circuit.trial = false;
+    }
+  }
+}
+
+/// `abel.breaker(key_or_opts, f, ...)`
+///
+/// Calls `f(...)` unless the circuit of `key` is open. The circuit opens after
+/// `threshold` consecutive failures, rejecting calls with a 503 error for
+/// `cooldown` milliseconds (at most a day), after which one trial call decides
+/// whether it closes again. URIs as keys are keyed by host.
+///
+/// Circuits are kept separately by each worker.
+pub(super) fn create_fn_breaker(lua: &Lua) -> mlua::Result<Function> {
+  let circuits = Circuits::default();
+  lua.create_async_function(move |lua, args| breaker(lua, args, circuits.clone()))
+}
+
+async fn breaker<'lua>(
+  lua: &'lua Lua,
+  mut args: MultiValue<'lua>,
+  circuits: Circuits,
+) -> mlua::Result<MultiValue<'lua>> {
+  let (key, threshold, cooldown) = match args.pop_front() {
+    Some(mlua::Value::String(key)) => (key.to_str()?.to_owned(), 5, Duration::from_secs(30)),
+    Some(mlua::Value::Table(opts)) => {
+      let key: mlua::String = opts.check_raw_get(lua, "key", "string")?;
+      let threshold = (opts.check_raw_get::<Option<u32>>(lua, "threshold", "positive integer")?)
+        .unwrap_or(5)
+        .max(1);
+      let cooldown = check_millis(lua, &opts, "cooldown")?.unwrap_or(Duration::from_secs(30));
+      (key.to_str()?.to_owned(), threshold, cooldown)
+    }
+    Some(value) => return Err(tag_error(lua, 1, "string or table", value.type_name(), 1)),
+    None => return Err(tag_error(lua, 1, "string or table", "no value", 1)),
+  };
+  let f: Function =
+    check_value(lua, args.pop_front(), "function").map_err(tag_handler(lua, 2, 1))?;
+  let key = match key.parse::<Uri>() {
+    Ok(uri) if uri.scheme().is_some() => uri.host().map(Into::into).unwrap_or(key),
+    _ => key,
+  };
+
+  let trial = {
+    let mut circuits = circuits.borrow_mut();
+    let circuit = circuits.entry(key.clone()).or_default();
+    if let Err(retry_after) = circuit.admit(Instant::now()) {
+      let detail = lua.create_table_from([
+        ("key", lua.pack(&*key)?),
+        ("retry_after", lua.pack(retry_after.as_secs_f64())?),
+      ])?;
+      return Err(http_error(
+        lua,
+        StatusCode::SERVICE_UNAVAILABLE,
+        "circuit open",
+        mlua::Value::Table(detail),
+      ));
+    }
+    circuit.trial
+  };
+  let _guard = trial.then(|| TrialGuard {
+    circuits: circuits.clone(),
+    key: key.clone(),
+  });
+
+  let result = f.call_async::<_, MultiValue>(args).await;
+  if !matches!(&result, Err(error) if is_timeout(error)) {
+    let mut circuits = circuits.borrow_mut();
+    let circuit = circuits.entry(key).or_default();
+    circuit.record(is_failure(&result), threshold, cooldown, Instant::now());
+  }
+  result
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use test_case::test_case;
+
+  #[test_case(1 => 100; "first retry")]
+  #[test_case(3 => 400; "doubled")]
+  #[test_case(10 => 1000; "capped")]
+  #[test_case(100 => 1000; "overflow")]
+  fn test_retry_delay(attempt: u32) -> u128 {
+    let opts = RetryOptions {
+      attempts: 3,
+      base_delay: Duration::from_millis(100),
+      max_delay: Duration::from_secs(1),
+      jitter: false,
+      retry_on: None,
+    };
+    opts.delay(attempt).as_millis()
+  }
+
+  #[test]
+  fn test_circuit_opens_and_closes() {
+    let cooldown = Duration::from_secs(10);
+    let now = Instant::now();
+    let mut circuit = Circuit::default();
+    circuit.record(true, 2, cooldown, now);
+    assert!(circuit.admit(now).is_ok());
+    circuit.record(true, 2, cooldown, now);
+    assert_eq!(circuit.admit(now), Err(cooldown));
+
+    // One trial call after the cooldown
+    let later = now + cooldown;
+    assert!(circuit.admit(later).is_ok());
+    assert!(circuit.trial);
+    assert!(circuit.admit(later).is_err());
+    circuit.record(false, 2, cooldown, later);
+    assert!(circuit.admit(later).is_ok());
+    assert_eq!(circuit.failures, 0);
+  }
+
+  #[test]
+  fn test_circuit_failed_trial() {
+    let cooldown = Duration::from_secs(10);
+    let now = Instant::now();
+    let mut circuit = Circuit::default();
+    circuit.record(true, 1, cooldown, now);
+    let later = now + cooldown;
+    assert!(circuit.admit(later).is_ok());
+    circuit.record(true, 1, cooldown, later);
+    assert_eq!(circuit.admit(later), Err(cooldown));
+  }
+
+  #[test]
+  fn test_circuit_cancelled_trial() {
+    let now = Instant::now();
+    let circuits = Circuits::default();
+    let mut circuit = Circuit::default();
+    circuit.record(true, 1, Duration::ZERO, now);
+    assert!(circuit.admit(now).is_ok());
+    circuits.borrow_mut().insert("a".into(), circuit);
+
+    drop(TrialGuard {
+      circuits: circuits.clone(),
+      key: "a".into(),
+    });
+    let mut circuits = circuits.borrow_mut();
+    assert!(circuits.get_mut("a").unwrap().admit(now).is_ok());
+  }
+
+  #[test]
+  fn test_circuit_long_cooldown() {
+    let now = Instant::now();
+    let mut circuit = Circuit::default();
+    circuit.record(true, 1, Duration::MAX, now);
+    assert_eq!(circuit.admit(now), Err(MAX_COOLDOWN));
+  }
+}
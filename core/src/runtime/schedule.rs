@@ -0,0 +1,241 @@
+//! Persisted one-off callbacks, exposed as `abel.schedule_at`.
+//!
+//! Scheduled calls are stored as records under
+//! `<local storage>/.schedules/<service>`, and fired by
+//! [`Abel::run_due_schedules`](crate::Abel::run_due_schedules) once due and
+//! the service is running, so they survive restarts. A call stays stored until
+//! its callback succeeds or fails [`MAX_ATTEMPTS`] times.
+
+use super::id::{ulid, unix_millis};
+use super::records::{Record, RecordStore, Records};
+use crate::lua::error::{check_string, check_value, rt_error, tag_handler};
+use crate::lua::LuaCacheExt;
+use dashmap::DashMap;
+use mlua::Value::Nil;
+use mlua::{Function, Lua, LuaSerdeExt, MultiValue};
+use rand::{thread_rng, Rng};
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+pub(crate) const MAX_ATTEMPTS: u32 = 3;
+/// Milliseconds a fired call is held back from firing again, in case the
+/// server stops before it finishes.
+const LEASE: u64 = 300_000;
+const RETRY_DELAY: u64 = 60_000;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct ScheduledCall {
+  pub id: String,
+  /// Unix milliseconds.
+  pub at: u64,
+  /// Name of the callback registered with `abel.on_schedule`.
+  pub name: String,
+  pub payload: serde_json::Value,
+  #[serde(default)]
+  pub attempts: u32,
+}
+
+impl Record for ScheduledCall {
+  fn id(&self) -> &str {
+    &self.id
+  }
+}
+
+/// Scheduled calls of one service, loaded from disk on first use.
+#[derive(Debug)]
+pub(super) struct ServiceSchedule {
+  calls: Records<ScheduledCall>,
+}
+
+impl ServiceSchedule {
+  async fn add(&mut self, at: u64, name: String, payload: serde_json::Value) -> io::Result<String> {
+    let call = ScheduledCall {
+      id: ulid(unix_millis(), thread_rng().gen()),
+      at,
+      name,
+      payload,
+      attempts: 0,
+    };
+    let id = call.id.clone();
+    self.calls.insert(call).await?;
+    Ok(id)
+  }
+
+  async fn cancel(&mut self, id: &str) -> io::Result<bool> {
+    Ok(self.calls.remove(id).await?.is_some())
+  }
+
+  /// Takes due calls, leasing them so they are not taken again meanwhile.
+  async fn take_due(&mut self) -> io::Result<Vec<ScheduledCall>> {
+    let now = unix_millis();
+    let (dir, calls) = self.calls.load().await?;
+    let mut due = Vec::new();
+    for call in calls.values_mut().filter(|x| x.at <= now) {
+      due.push(call.clone());
+      call.at = now.saturating_add(LEASE);
+      call.attempts += 1;
+      dir.write(call).await?;
+    }
+    Ok(due)
+  }
+
+  /// Removes a call, or retries it later if it failed and has attempts left.
+  async fn finish(&mut self, id: &str, success: bool) -> io::Result<()> {
+    let (dir, calls) = self.calls.load().await?;
+    let call = match calls.get_mut(id) {
+      Some(call) => call,
+      None => return Ok(()),
+    };
+    if !success && call.attempts < MAX_ATTEMPTS {
+      call.at = unix_millis().saturating_add(RETRY_DELAY * call.attempts as u64);
+      dir.write(call).await
+    } else {
+      self.cancel(id).await.map(|_| ())
+    }
+  }
+}
+
+pub(super) type SharedSchedule = Arc<Mutex<ServiceSchedule>>;
+
+/// Scheduled calls of all services, shared by workers.
+#[derive(Debug)]
+pub(crate) struct Schedules {
+  store: RecordStore,
+  services: DashMap<String, SharedSchedule>,
+}
+
+impl Schedules {
+  /// Calls are sealed if `master` is set, the master key encrypting local
+  /// storage.
+  pub fn new(path: PathBuf, master: Option<[u8; 32]>) -> Self {
+    Self {
+      store: RecordStore::new(path, master),
+      services: DashMap::new(),
+    }
+  }
+
+  pub(super) fn get(&self, service_name: &str) -> io::Result<SharedSchedule> {
+    if let Some(schedule) = self.services.get(service_name) {
+      return Ok(schedule.clone());
+    }
+    let dir = self.store.get(service_name)?;
+    let schedule = (self.services.entry(service_name.into())).or_insert_with(|| {
+      Arc::new(Mutex::new(ServiceSchedule {
+        calls: Records::new(dir),
+      }))
+    });
+    Ok(schedule.clone())
+  }
+
+  pub async fn take_due(&self, service_name: &str) -> io::Result<Vec<ScheduledCall>> {
+    self.get(service_name)?.lock().await.take_due().await
+  }
+
+  pub async fn finish(&self, service_name: &str, id: &str, success: bool) -> io::Result<()> {
+    (self.get(service_name)?.lock().await)
+      .finish(id, success)
+      .await
+  }
+
+  /// Deletes all scheduled calls of a removed service.
+  pub async fn remove(&self, service_name: &str) -> io::Result<()> {
+    self.services.remove(service_name);
+    self.store.remove(service_name).await
+  }
+}
+
+/// Registers `handler(payload, call)` at the top level of `main.lua` for
+/// calls scheduled with `name`.
+pub(super) fn create_fn_on_schedule<'a>(
+  lua: &'a Lua,
+  internal: mlua::Table<'a>,
+) -> mlua::Result<Function<'a>> {
+  const SRC: &str = r#"
+    local internal, name, handler = ...
+    assert(
+      not internal.sealed,
+      "cannot call `on_schedule` from places other than the top level of `main.lua`"
+    )
+    assert(type(name) == "string", "schedule name must be a string")
+    assert(type(handler) == "function", "schedule handler must be a function")
+    internal.schedules[name] = handler
+  "#;
+  let f = lua.create_cached_value("abel:abel.on_schedule::meta", || {
+    lua
+      .load(SRC)
+      .set_name("@[abel.on_schedule]")?
+      .into_function()
+  })?;
+  f.bind(internal)
+}
+
+/// `abel.schedule_at(timestamp, name, payload)`
+///
+/// Schedules the handler registered as `name` to be called with `payload` at
+/// `timestamp` in Unix seconds, returning the call's ID.
+pub(super) fn create_fn_schedule_at(lua: &Lua, schedule: SharedSchedule) -> mlua::Result<Function> {
+  lua.create_async_function(move |lua, mut args: MultiValue| {
+    let schedule = schedule.clone();
+    async move {
+      let timestamp: f64 =
+        check_value(lua, args.pop_front(), "number").map_err(tag_handler(lua, 1, 1))?;
+      let name = check_string(lua, args.pop_front()).map_err(tag_handler(lua, 2, 1))?;
+      let payload: serde_json::Value = lua.from_value(args.pop_front().unwrap_or(Nil))?;
+      let at = (timestamp.max(0.) * 1000.) as u64;
+      (schedule.lock().await)
+        .add(at, name.to_str()?.into(), payload)
+        .await
+        .map_err(rt_error)
+    }
+  })
+}
+
+/// `abel.cancel_schedule(id)`
+///
+/// Cancels a scheduled call, returning whether it was pending.
+pub(super) fn create_fn_cancel_schedule(
+  lua: &Lua,
+  schedule: SharedSchedule,
+) -> mlua::Result<Function> {
+  lua.create_async_function(move |lua, mut args: MultiValue| {
+    let schedule = schedule.clone();
+    async move {
+      let id = check_string(lua, args.pop_front()).map_err(tag_handler(lua, 1, 1))?;
+      (schedule.lock().await.cancel(id.to_str()?).await).map_err(rt_error)
+    }
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use serde_json::json;
+  use tempfile::TempDir;
+
+  #[tokio::test]
+  async fn test_schedule() -> io::Result<()> {
+    let dir = TempDir::new()?;
+    let schedules = Schedules::new(dir.path().into(), None);
+    let schedule = schedules.get("a")?;
+    let id = schedule.lock().await.add(0, "f".into(), json!(1)).await?;
+    let later = (schedule.lock().await)
+      .add(unix_millis() + 60000, "f".into(), json!(2))
+      .await?;
+
+    // Reloaded from disk
+    let schedules = Schedules::new(dir.path().into(), None);
+    let due = schedules.take_due("a").await?;
+    assert_eq!(due.len(), 1);
+    assert_eq!((&*due[0].id, due[0].attempts), (&*id, 1));
+    assert!(schedules.take_due("a").await?.is_empty());
+
+    schedules.finish("a", &id, false).await?;
+    schedules.finish("a", &id, true).await?;
+    assert!(!schedules.get("a")?.lock().await.cancel(&id).await?);
+    assert!(schedules.get("a")?.lock().await.cancel(&later).await?);
+    Ok(())
+  }
+}
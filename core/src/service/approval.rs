@@ -0,0 +1,240 @@
+use super::create::new_service_impl;
+use super::{DeployGuard, Service, ServiceImpl, ServicePool, ServiceState, StoppedService};
+use crate::source::Source;
+use crate::task::Pool;
+use crate::ErrorKind::{ServiceExists, ServiceNotFound, ServiceNotPendingApproval, ServiceStopped};
+use crate::{Config, Permissions, Result};
+use dashmap::mapref::entry::Entry;
+use log::warn;
+use uuid::Uuid;
+
+impl ServicePool {
+  /// Holds a version of a service whose permissions are not approved yet.
+  /// Its source is not evaluated, and it cannot start until approved.
+  ///
+  /// If an approved version of the service exists, it keeps serving, and is
+  /// only replaced when the held one is approved. Returns the version held
+  /// before, if any.
+  pub async fn hold(
+    &self,
+    guard: &DeployGuard,
+    uuid: Option<Uuid>,
+    source: Source,
+    config: Config,
+  ) -> Result<(StoppedService<'_>, Option<ServiceImpl>)> {
+    let name = guard.owned_name();
+    let mut service_impl = new_service_impl(name.clone(), uuid, source, config).await?;
+    service_impl.info.pending_approval = true;
+
+    let approved = (self.services.get(&*name)).map(|x| !x.info().pending_approval);
+    if approved == Some(true) {
+      let replaced = self.held.insert(name.clone(), service_impl);
+      let held = (self.held.get(&*name)).ok_or(ServiceNotFound { name })?;
+      return Ok((StoppedService::from_held(held), replaced));
+    }
+
+    // Nothing is serving, so it takes the place of the service
+    self.check_total(&name)?;
+    let replaced = (self.services)
+      .remove_if(&*name, |_, x| x.info().pending_approval)
+      .map(|(_name, service)| service.into_impl());
+    match self.services.entry(name.clone()) {
+      Entry::Vacant(entry) => {
+        let service = entry.insert(ServiceState::Stopped(service_impl));
+        Ok((StoppedService::from_ref(service.downgrade()), replaced))
+      }
+      Entry::Occupied(_) => Err(ServiceExists { name }.into()),
+    }
+  }
+
+  /// Gets the version of a service held for approval aside the existing one.
+  pub fn get_held(&self, name: &str) -> Option<StoppedService<'_>> {
+    self.held.get(name).map(StoppedService::from_held)
+  }
+
+  /// Approves the permissions of a held service, so that it can start. It
+  /// is granted everything it requested.
+  ///
+  /// A version held aside replaces the existing one, which is stopped first
+  /// and returned.
+  pub async fn approve(
+    &self,
+    rt_pool: &Pool,
+    guard: &DeployGuard,
+  ) -> Result<(StoppedService<'_>, Option<ServiceImpl>)> {
+    let name = guard.name();
+    if let Some((name, mut held)) = self.held.remove(name) {
+      held.info.pending_approval = false;
+      held.set_granted(Some(held.info.permissions.clone()));
+      match self.stop(rt_pool, guard).await {
+        Ok(_) => {}
+        Err(error) if matches!(error.kind(), ServiceStopped { .. } | ServiceNotFound { .. }) => {}
+        Err(error) => warn!("Lua error when stopping service '{name}': {error}"),
+      }
+      let replaced = (self.services)
+        .remove(&*name)
+        .map(|(_name, service)| service.into_impl());
+      return match self.services.entry(name.clone()) {
+        Entry::Vacant(entry) => {
+          let service = entry.insert(ServiceState::Stopped(held));
+          Ok((StoppedService::from_ref(service.downgrade()), replaced))
+        }
+        Entry::Occupied(_) => Err(ServiceExists { name }.into()),
+      };
+    }
+
+    let mut service = (self.services.get_mut(name)).ok_or(ServiceNotFound { name: name.into() })?;
+    match service.value_mut() {
+      ServiceState::Stopped(x) if x.info.pending_approval => {
+        x.info.pending_approval = false;
+        x.set_granted(Some(x.info.permissions.clone()));
+      }
+      _ => return Err(ServiceNotPendingApproval { name: name.into() }.into()),
+    }
+    Ok((StoppedService::from_ref(service.downgrade()), None))
+  }
+
+  /// Replaces the permissions granted to a service, restricting what it
+  /// requested. `None` grants everything requested.
+  ///
+  /// The service is updated in place, so handles to a running one stay
+  /// valid. It keeps running; its isolates are recreated with the new
+  /// permissions as they are next used, without calling `start` again.
+  pub fn grant(&self, guard: &DeployGuard, granted: Option<Permissions>) -> Result<Service<'_>> {
+    let name = guard.name();
+    let service = (self.services.get(name)).ok_or(ServiceNotFound { name: name.into() })?;
+    service.as_impl().set_granted(granted);
+    if let ServiceState::Running(x) = service.value() {
+      return Ok(Service::Running(x.downgrade()));
+    }
+    Ok(Service::Stopped(StoppedService::from_ref(service)))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::source::{MemorySource, Source};
+  use crate::{Abel, AbelOptions, Config, NetPermission, Permissions};
+  use hyper::{Body, Request};
+  use tempfile::TempDir;
+
+  fn abel(local_storage: &TempDir) -> Abel {
+    Abel::new(AbelOptions {
+      runtime_pool_size: 1,
+      local_storage_path: local_storage.path().into(),
+      secrets_path: None,
+      remote_cache_path: None,
+      max_services: None,
+      max_running_services: None,
+      http_client: Default::default(),
+      coordinator: None,
+      exec_allowlist: Vec::new(),
+      #[cfg(feature = "encryption")]
+      storage_key: None,
+    })
+    .unwrap()
+  }
+
+  fn source(version: &str) -> Source {
+    let code = format!(r#"abel.listen("/", function() return "{version}" end)"#);
+    Source::new(MemorySource::from_files([("main.lua", code)]))
+  }
+
+  /// Config requesting more than granted.
+  fn unapproved() -> Config {
+    Config {
+      permissions: Permissions {
+        exec: vec!["/bin/true".into()],
+        ..Default::default()
+      },
+      granted: Some(Permissions::default()),
+      ..Default::default()
+    }
+  }
+
+  async fn call(abel: &Abel, name: &str) -> String {
+    let service = abel.get_running_service(name).unwrap();
+    let req = Request::get(format!("http://localhost/{name}"))
+      .body(Body::empty())
+      .unwrap();
+    let resp = abel.run_service(service, "/".into(), req).await.unwrap();
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    String::from_utf8(body.to_vec()).unwrap()
+  }
+
+  #[tokio::test]
+  async fn test_hold_update() {
+    let local_storage = TempDir::new().unwrap();
+    let abel = abel(&local_storage);
+    let guard = abel.lock_deploy("svc", false).await.unwrap();
+    (abel.cold_update_or_create_service(&guard, None, source("v1"), Default::default(), None))
+      .await
+      .unwrap();
+
+    let (held, replaced) = (abel.hold_service(&guard, None, source("v2"), unapproved()))
+      .await
+      .unwrap();
+    assert!(held.pending_approval());
+    assert!(replaced.is_none());
+    drop(held);
+    // The approved version keeps serving until the new one is approved
+    assert_eq!(call(&abel, "svc").await, "v1");
+    assert!(abel.get_held_service("svc").is_some());
+
+    let (approved, replaced) = abel.approve_service(&guard).await.unwrap();
+    assert!(!approved.pending_approval());
+    assert_eq!(approved.effective_permissions().exec, ["/bin/true"]);
+    drop(approved);
+    assert!(replaced.is_some());
+    assert!(abel.get_held_service("svc").is_none());
+    abel.start_service(&guard).await.unwrap();
+    assert_eq!(call(&abel, "svc").await, "v2");
+  }
+
+  #[tokio::test]
+  async fn test_hold_new() {
+    let local_storage = TempDir::new().unwrap();
+    let abel = abel(&local_storage);
+    let guard = abel.lock_deploy("svc", false).await.unwrap();
+    let (held, _) = (abel.hold_service(&guard, None, source("v1"), unapproved()))
+      .await
+      .unwrap();
+    drop(held);
+    assert!(abel.get_held_service("svc").is_none());
+    assert!(abel.start_service(&guard).await.is_err());
+
+    let (approved, replaced) = abel.approve_service(&guard).await.unwrap();
+    drop(approved);
+    assert!(replaced.is_none());
+    abel.start_service(&guard).await.unwrap();
+    assert_eq!(call(&abel, "svc").await, "v1");
+    assert!(abel.approve_service(&guard).await.is_err());
+  }
+
+  #[tokio::test]
+  async fn test_grant_in_place() {
+    let local_storage = TempDir::new().unwrap();
+    let abel = abel(&local_storage);
+    let guard = abel.lock_deploy("svc", false).await.unwrap();
+    let code = r#"abel.listen("/", function() return tostring((pcall(require, "dns"))) end)"#;
+    let source = Source::new(MemorySource::from_files([("main.lua", code)]));
+    let config = Config {
+      permissions: Permissions {
+        net: NetPermission::All(true),
+        ..Default::default()
+      },
+      ..Default::default()
+    };
+    (abel.cold_update_or_create_service(&guard, None, source, config, None))
+      .await
+      .unwrap();
+    let service = abel.get_running_service("svc").unwrap();
+    assert_eq!(call(&abel, "svc").await, "true");
+
+    (abel.grant_permissions(&guard, Some(Permissions::default()))).unwrap();
+    // Handles to the running service stay valid
+    let net = service.upgrade().effective_permissions().net;
+    assert_eq!(net, NetPermission::All(false));
+    assert_eq!(call(&abel, "svc").await, "false");
+  }
+}
@@ -0,0 +1,328 @@
+use super::{ServiceName, ServicePool, ServiceState};
+use crate::lua::civil_from_days;
+use crate::task::Pool;
+use crate::ErrorKind::{ServiceNotFound, ServiceStopped};
+use log::warn;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt::{self, Display, Formatter};
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Times a service is available. It is started when entering one of the
+/// windows, and stopped when leaving all of them.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Availability {
+  pub windows: Vec<CronWindow>,
+  /// Offset of the time zone windows are written in. Defaults to UTC.
+  #[serde(default)]
+  pub utc_offset: UtcOffset,
+}
+
+impl Availability {
+  pub fn contains(&self, time: SystemTime) -> bool {
+    let secs = match time.duration_since(UNIX_EPOCH) {
+      Ok(x) => x.as_secs() as i64,
+      Err(_) => return false,
+    };
+    let minutes = secs.div_euclid(60) + self.utc_offset.0 as i64;
+    let time = CivilTime::from_minutes(minutes);
+    self.windows.iter().any(|x| x.contains(&time))
+  }
+}
+
+/// Minutes matching a cron expression of five fields: minute, hour, day of
+/// month, month and day of week, e.g. `* 9-17 * * 1-5` for 9:00 to 17:59 on
+/// weekdays.
+///
+/// Fields are `*`, numbers, ranges `a-b`, steps `*/n` and `a-b/n`, or lists of
+/// them separated by commas. Day of week is 0 to 7, both 0 and 7 being
+/// Sunday. As in cron, if both day fields are restricted, matching either is
+/// enough.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CronWindow {
+  expr: Box<str>,
+  minutes: u64,
+  hours: u64,
+  days: u64,
+  months: u64,
+  weekdays: u64,
+  any_day: bool,
+  any_weekday: bool,
+}
+
+impl CronWindow {
+  fn contains(&self, time: &CivilTime) -> bool {
+    let bit = |set: u64, x: u32| set & (1 << x) != 0;
+    let day = bit(self.days, time.day);
+    let weekday = bit(self.weekdays, time.weekday);
+    let day_matches = match (self.any_day, self.any_weekday) {
+      (true, true) => true,
+      (true, false) => weekday,
+      (false, true) => day,
+      (false, false) => day || weekday,
+    };
+    bit(self.minutes, time.minute)
+      && bit(self.hours, time.hour)
+      && bit(self.months, time.month)
+      && day_matches
+  }
+}
+
+fn parse_field(field: &str, min: u32, max: u32) -> Option<u64> {
+  let mut set = 0;
+  for part in field.split(',') {
+    let (range, step) = match part.split_once('/') {
+      Some((range, step)) => (range, step.parse::<u32>().ok().filter(|&x| x > 0)?),
+      None => (part, 1),
+    };
+    let (start, end) = match range.split_once('-') {
+      _ if range == "*" => (min, max),
+      Some((start, end)) => (start.parse().ok()?, end.parse().ok()?),
+      None => {
+        let start = range.parse().ok()?;
+        (start, if step > 1 { max } else { start })
+      }
+    };
+    if start < min || end > max || start > end {
+      return None;
+    }
+    for x in (start..=end).step_by(step as usize) {
+      set |= 1 << x;
+    }
+  }
+  Some(set)
+}
+
+impl FromStr for CronWindow {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let invalid = || format!("invalid cron expression '{s}'");
+    let fields = s.split_whitespace().collect::<Vec<_>>();
+    if fields.len() != 5 {
+      return Err(invalid());
+    }
+    let parse = |i: usize, min, max| parse_field(fields[i], min, max).ok_or_else(invalid);
+    let mut weekdays = parse(4, 0, 7)?;
+    // 7 is also Sunday
+    if weekdays & (1 << 7) != 0 {
+      weekdays = (weekdays | 1) & !(1 << 7);
+    }
+    Ok(Self {
+      expr: fields.join(" ").into(),
+      minutes: parse(0, 0, 59)?,
+      hours: parse(1, 0, 23)?,
+      days: parse(2, 1, 31)?,
+      months: parse(3, 1, 12)?,
+      weekdays,
+      any_day: fields[2] == "*",
+      any_weekday: fields[4] == "*",
+    })
+  }
+}
+
+impl Display for CronWindow {
+  fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+    f.write_str(&self.expr)
+  }
+}
+
+/// Time zone offset written as `+08:00` or `-05:30`, stored in minutes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct UtcOffset(i32);
+
+impl FromStr for UtcOffset {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let invalid = || format!("invalid UTC offset '{s}'");
+    let (sign, rest) = match s.as_bytes().first() {
+      Some(b'+') => (1, &s[1..]),
+      Some(b'-') => (-1, &s[1..]),
+      _ => return Err(invalid()),
+    };
+    let (hours, minutes) = rest.split_once(':').ok_or_else(invalid)?;
+    if hours.len() != 2 || minutes.len() != 2 {
+      return Err(invalid());
+    }
+    let hours: i32 = hours.parse().map_err(|_| invalid())?;
+    let minutes: i32 = minutes.parse().map_err(|_| invalid())?;
+    if hours > 23 || minutes > 59 {
+      return Err(invalid());
+    }
+    Ok(Self(sign * (hours * 60 + minutes)))
+  }
+}
+
+impl Display for UtcOffset {
+  fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+    let sign = if self.0 < 0 { '-' } else { '+' };
+    let minutes = self.0.abs();
+    write!(f, "{sign}{:02}:{:02}", minutes / 60, minutes % 60)
+  }
+}
+
+impl Serialize for CronWindow {
+  fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(self)
+  }
+}
+
+impl<'de> Deserialize<'de> for CronWindow {
+  fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+    let s = String::deserialize(deserializer)?;
+    s.parse().map_err(serde::de::Error::custom)
+  }
+}
+
+impl Serialize for UtcOffset {
+  fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(self)
+  }
+}
+
+impl<'de> Deserialize<'de> for UtcOffset {
+  fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+    let s = String::deserialize(deserializer)?;
+    s.parse().map_err(serde::de::Error::custom)
+  }
+}
+
+/// Calendar fields of a time, as matched by cron expressions.
+#[derive(Debug, PartialEq, Eq)]
+struct CivilTime {
+  minute: u32,
+  hour: u32,
+  day: u32,
+  month: u32,
+  weekday: u32,
+}
+
+impl CivilTime {
+  fn from_minutes(minutes: i64) -> Self {
+    let days = minutes.div_euclid(24 * 60);
+    let minute_of_day = minutes.rem_euclid(24 * 60) as u32;
+    let (_, month, day) = civil_from_days(days);
+    Self {
+      minute: minute_of_day % 60,
+      hour: minute_of_day / 60,
+      day: day as u32,
+      month: month as u32,
+      // 1970-01-01 is a Thursday
+      weekday: (days + 4).rem_euclid(7) as u32,
+    }
+  }
+}
+
+impl ServicePool {
+  /// Starts services entering their availability windows, and stops those
+  /// leaving them, returning their names and whether they were started.
+  ///
+  /// Only transitions are acted on, so services started or stopped manually
+  /// stay so until the next one. Services are checked once when loaded,
+  /// though. Failed transitions are retried on the next call.
+  pub async fn apply_availability(&self, rt_pool: &Pool) -> Vec<(ServiceName, bool)> {
+    let now = SystemTime::now();
+    let transitions = (self.services.iter())
+      .filter_map(|x| {
+        let service = x.value().as_impl();
+        let available = service.availability.as_ref()?.contains(now);
+        let mut last = service.available.lock();
+        if *last == Some(available) {
+          return None;
+        }
+        let running = matches!(x.value(), ServiceState::Running(_));
+        if running == available {
+          *last = Some(available);
+          return None;
+        }
+        Some((x.key().clone(), available))
+      })
+      .collect::<Vec<_>>();
+
+    let mut applied = Vec::with_capacity(transitions.len());
+    for (name, available) in transitions {
+      // Services being deployed are retried on the next call
+      let guard = match self.lock_deploy(&name, false).await {
+        Ok(guard) => guard,
+        Err(_) => continue,
+      };
+      let result = if available {
+        self.start(rt_pool, &guard).await.map(|_| ())
+      } else {
+        self.stop(rt_pool, &guard).await.map(|_| ())
+      };
+      match result {
+        Ok(()) => applied.push((name.clone(), available)),
+        Err(error) if matches!(error.kind(), ServiceStopped { .. } | ServiceNotFound { .. }) => {}
+        Err(error) => {
+          let action = if available { "starting" } else { "stopping" };
+          warn!("error {action} service '{name}' for its availability: {error}");
+          continue;
+        }
+      }
+      if let Some(x) = self.services.get(&name) {
+        *x.value().as_impl().available.lock() = Some(available);
+      }
+    }
+    applied
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::time::Duration;
+  use test_case::test_case;
+
+  fn at(secs: u64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_secs(secs)
+  }
+
+  // 2022-10-03 is a Monday
+  const MONDAY_9AM: u64 = 1664787600;
+
+  #[test_case(0 => CivilTime { minute: 0, hour: 0, day: 1, month: 1, weekday: 4 }; "epoch")]
+  #[test_case(MONDAY_9AM / 60 => CivilTime { minute: 0, hour: 9, day: 3, month: 10, weekday: 1 }; "monday")]
+  #[test_case(951825600 / 60 => CivilTime { minute: 0, hour: 12, day: 29, month: 2, weekday: 2 }; "leap day")]
+  #[test_case(-1 => CivilTime { minute: 59, hour: 23, day: 31, month: 12, weekday: 3 }; "before epoch")]
+  fn test_civil_time(minutes: i64) -> CivilTime {
+    CivilTime::from_minutes(minutes)
+  }
+
+  #[test_case("* 9-17 * * 1-5" => true; "working hours")]
+  #[test_case("* 9-17 * * 6,0" => false; "weekend")]
+  #[test_case("* 9-17 * * 7" => false; "sunday as 7")]
+  #[test_case("*/15 9 * * *" => true; "step")]
+  #[test_case("1-59 9 * * *" => false; "minute range")]
+  #[test_case("* * 3 * 0" => true; "either day field")]
+  #[test_case("* * * 1-9 *" => false; "month")]
+  fn test_contains(window: &str) -> bool {
+    let availability = Availability {
+      windows: vec![window.parse().unwrap()],
+      utc_offset: UtcOffset::default(),
+    };
+    availability.contains(at(MONDAY_9AM))
+  }
+
+  #[test_case("+00:00" => true; "utc")]
+  #[test_case("+08:00" => false; "ahead")]
+  #[test_case("-01:00" => false; "behind")]
+  fn test_utc_offset(offset: &str) -> bool {
+    let availability = Availability {
+      windows: vec!["* 9 * * *".parse().unwrap()],
+      utc_offset: offset.parse().unwrap(),
+    };
+    availability.contains(at(MONDAY_9AM))
+  }
+
+  #[test_case("* * * *"; "too few fields")]
+  #[test_case("60 * * * *"; "out of range")]
+  #[test_case("5-1 * * * *"; "reversed range")]
+  #[test_case("*/0 * * * *"; "zero step")]
+  #[test_case("* * * * mon"; "names")]
+  fn test_invalid(window: &str) {
+    assert!(window.parse::<CronWindow>().is_err());
+  }
+}
@@ -0,0 +1,144 @@
+use super::create::{before_swap, prepare_service, BeforeSwap};
+use super::readiness::wait_ready;
+use super::{DeployGuard, ErrorPayload, RunningService, ServiceImpl, ServicePool, ServiceState};
+use crate::source::Source;
+use crate::task::Pool;
+use crate::ErrorKind::{CanaryNotFound, InvalidCanaryWeight, ServiceNotFound, ServiceStopped};
+use crate::{Config, Result};
+use rand::{thread_rng, Rng};
+use std::sync::Arc;
+use uuid::Uuid;
+
+/// A second version of a running service receiving a share of its traffic.
+pub(super) struct Canary {
+  service: Arc<ServiceImpl>,
+  /// Percentage of requests routed to the canary, from 0 to 100.
+  weight: u8,
+}
+
+impl ServicePool {
+  pub(super) fn route_canary(&self, name: &str) -> Option<RunningService> {
+    let canary = self.canaries.get(name)?;
+    (thread_rng().gen_range(0..100) < canary.weight).then(|| canary.service.downgrade())
+  }
+
+  pub fn get_canary(&self, name: &str) -> Option<(RunningService, u8)> {
+    (self.canaries.get(name)).map(|x| (x.service.downgrade(), x.weight))
+  }
+
+  /// Loads and starts a new version of a running service alongside the
+  /// current one, returning the replaced canary if there is one.
+  pub async fn deploy_canary(
+    &self,
+    rt_pool: &Pool,
+    guard: &DeployGuard,
+    uuid: Option<Uuid>,
+    source: Source,
+    config: Config,
+    weight: u8,
+    on_swap: Option<BeforeSwap>,
+  ) -> Result<(RunningService, Option<ServiceImpl>)> {
+    let name = guard.owned_name();
+    if weight > 100 {
+      return Err(InvalidCanaryWeight { weight }.into());
+    }
+    match self.get(&name) {
+      Some(x) if x.is_stopped() => return Err(ServiceStopped { name }.into()),
+      None => return Err(ServiceNotFound { name }.into()),
+      _ => {}
+    }
+
+    let name2 = name.clone();
+    let service_impl = rt_pool
+      .scope(move |rt| async move {
+        let (service_impl, isolate) = prepare_service(&rt, name2, uuid, source, config).await?;
+        if let Err(error) = before_swap(on_swap).await {
+          rt.remove_isolate(isolate)?;
+          return Err(error.into());
+        }
+        let service_impl = Arc::new(service_impl);
+        rt.create_service(service_impl.downgrade(), isolate, false)
+          .await?;
+        Ok::<_, crate::Error>(service_impl)
+      })
+      .await?;
+    wait_ready(rt_pool, service_impl.downgrade()).await?;
+
+    let service = service_impl.downgrade();
+    let replaced = (self.canaries)
+      .insert(name, Canary {
+        service: service_impl,
+        weight,
+      })
+      .map(|x| ServiceState::Running(x.service).into_impl());
+    Ok((service, replaced))
+  }
+
+  /// Replaces the current version with its canary. The old version is stopped
+  /// and returned.
+  pub async fn promote_canary(
+    &self,
+    rt_pool: &Pool,
+    guard: &DeployGuard,
+  ) -> Result<(RunningService, Option<ServiceImpl>, ErrorPayload)> {
+    let name = guard.name();
+    let (name, canary) = (self.canaries)
+      .remove(name)
+      .ok_or_else(|| CanaryNotFound { name: name.into() })?;
+
+    let mut error_payload = ErrorPayload::default();
+    let services = self.services.clone();
+    let name2 = name.clone();
+    let result = rt_pool
+      .scope(move |rt| async move { Self::scope_stop(services, &rt, &name2).await })
+      .await;
+    match result {
+      Ok(_) => {}
+      Err(error) if matches!(error.kind(), ServiceStopped { .. } | ServiceNotFound { .. }) => {}
+      Err(error) => error_payload.stop = Some(error),
+    }
+
+    let service = canary.service.downgrade();
+    let replaced = (self.services)
+      .insert(name, ServiceState::Running(canary.service))
+      .map(ServiceState::into_impl);
+    Ok((service, replaced, error_payload))
+  }
+
+  /// Stops and discards the canary of a service, if there is one.
+  ///
+  /// Used when the current version stops or is removed, as a canary only runs
+  /// alongside it.
+  pub(super) async fn stop_canary(&self, rt_pool: &Pool, name: &str) -> Result<()> {
+    if let Some((_name, canary)) = self.canaries.remove(name) {
+      let x = canary.service.downgrade();
+      (rt_pool.scope(move |rt| async move { rt.run_stop(x).await })).await?;
+    }
+    Ok(())
+  }
+
+  /// Stops and removes the canary of a service.
+  pub async fn abort_canary(
+    &self,
+    rt_pool: &Pool,
+    guard: &DeployGuard,
+  ) -> Result<(ServiceImpl, ErrorPayload)> {
+    let name = guard.name();
+    let (_name, canary) = (self.canaries)
+      .remove(name)
+      .ok_or_else(|| CanaryNotFound { name: name.into() })?;
+
+    let mut error_payload = ErrorPayload::default();
+    let x = canary.service.downgrade();
+    let result = rt_pool
+      .scope(move |rt| async move { rt.run_stop(x).await })
+      .await;
+    if let Err(error) = result {
+      error_payload.stop = Some(error);
+    }
+    Ok((
+      ServiceState::Running(canary.service).into_impl(),
+      error_payload,
+    ))
+  }
+}
@@ -0,0 +1,34 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tokio::sync::{Semaphore, SemaphorePermit};
+
+/// Limits simultaneous in-flight requests of a service.
+#[derive(Debug)]
+pub struct ConcurrencyLimiter {
+  semaphore: Semaphore,
+  queued: AtomicUsize,
+  max_queued: usize,
+}
+
+impl ConcurrencyLimiter {
+  pub fn new(max_concurrency: usize, max_queued: usize) -> Self {
+    Self {
+      semaphore: Semaphore::new(max_concurrency),
+      queued: AtomicUsize::new(0),
+      max_queued,
+    }
+  }
+
+  /// Waits for a free slot, or returns `None` if the queue is already full.
+  pub async fn acquire(&self) -> Option<SemaphorePermit<'_>> {
+    if let Ok(permit) = self.semaphore.try_acquire() {
+      return Some(permit);
+    }
+    if self.queued.fetch_add(1, Ordering::AcqRel) >= self.max_queued {
+      self.queued.fetch_sub(1, Ordering::AcqRel);
+      return None;
+    }
+    let permit = self.semaphore.acquire().await.ok();
+    self.queued.fetch_sub(1, Ordering::AcqRel);
+    permit
+  }
+}
@@ -1,14 +1,27 @@
+use super::concurrency::ConcurrencyLimiter;
+use super::output::OutputLimits;
+use super::readiness::{wait_ready, Readiness};
+use super::warm_up::warm_up;
 use super::{
-  get_local_storage_path, RunningService, Service, ServiceImpl, ServiceInfo, ServiceName,
-  ServicePool, ServiceState, StoppedService,
+  get_local_storage_path, DeployGuard, RedirectMap, RunningService, Service, ServiceImpl,
+  ServiceInfo, ServiceName, ServicePool, ServiceState, StoppedService,
 };
+use crate::event::EventKind;
 use crate::lua::isolate::Isolate;
-use crate::runtime::Runtime;
+use crate::runtime::{check_name, Runtime};
 use crate::source::Source;
 use crate::task::Pool;
-use crate::ErrorKind::{self, ServiceNotFound, ServiceStopped};
-use crate::{Config, Error, Result};
+use crate::ErrorKind::{
+  self, IncompatibleApiVersion, InvalidConfig, ServiceNotFound, ServiceStopped,
+};
+use crate::{parse_headers, Config, Error, Result, API_VERSION};
+use futures::future::BoxFuture;
+use parking_lot::RwLock;
+use replace_with::replace_with_or_abort;
+use std::io;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
+use std::time::Duration;
 use uuid::Uuid;
 
 /// Contains non-critical errors when loading, creating or updating services.
@@ -29,40 +42,207 @@ impl ErrorPayload {
   }
 }
 
-async fn prepare_service(
-  rt: &Runtime,
+/// Runs once a new version of a service is evaluated, right before it replaces
+/// the current one. The update is aborted if it fails.
+pub type BeforeSwap = Box<dyn FnOnce() -> BoxFuture<'static, io::Result<()>> + Send>;
+
+pub(super) async fn before_swap(f: Option<BeforeSwap>) -> io::Result<()> {
+  match f {
+    Some(f) => f().await,
+    None => Ok(()),
+  }
+}
+
+/// Creates a service without evaluating its source. Its paths are unknown
+/// until [`evaluate`] is called.
+pub(super) async fn new_service_impl(
   name: ServiceName,
   uuid: Option<Uuid>,
   source: Source,
   config: Config,
-) -> Result<(ServiceImpl, Isolate)> {
+) -> Result<ServiceImpl> {
+  check_name(&name)?;
   let Config {
     pkg_name,
     description,
+    version,
+    abel_api_version,
+    error_page,
+    capture_requests,
+    profile_threshold,
+    affinity,
+    redirects,
+    error_reporting,
+    request_timeout,
+    max_concurrency,
+    max_queued,
+    depends_on,
+    ready_timeout,
+    idle_timeout,
+    wake_timeout,
+    warm_up,
+    availability,
+    allow_ips,
+    deny_ips,
+    routes,
+    headers,
+    max_response_size,
+    response_quota,
+    permissions,
+    granted,
+    content_hash,
+    deployed_at,
   } = config;
-  let (paths, isolate) = rt.prepare_service(&name, source.clone()).await?;
+  if let Some(req) = &abel_api_version {
+    if !req.matches(&API_VERSION) {
+      return Err(
+        IncompatibleApiVersion {
+          required: req.to_string(),
+          current: API_VERSION.to_string(),
+        }
+        .into(),
+      );
+    }
+  }
+  let default_headers = parse_headers(&headers).map_err(|reason| InvalidConfig {
+    field: "headers".into(),
+    reason,
+  })?;
+  let redirect_map = match &redirects {
+    Some(path) => RedirectMap::load(&source, path).await?,
+    None => RedirectMap::default(),
+  };
   let service_impl = ServiceImpl {
     info: ServiceInfo {
       name,
       pkg_name,
       description,
-      paths,
+      version,
+      abel_api_version,
+      error_page,
+      capture_requests,
+      profile_threshold,
+      affinity,
+      redirects,
+      error_reporting,
+      request_timeout,
+      max_concurrency,
+      max_queued,
+      depends_on,
+      ready_timeout,
+      idle_timeout,
+      wake_timeout,
+      warm_up,
+      availability,
+      allow_ips,
+      deny_ips,
+      routes,
+      headers,
+      max_response_size,
+      response_quota,
+      permissions,
+      pending_approval: false,
+      content_hash,
+      deployed_at,
+      paths: Vec::new(),
       uuid: uuid.unwrap_or_else(Uuid::new_v4),
     },
     source,
+    metrics: Default::default(),
+    limiter: max_concurrency.map(|x| Arc::new(ConcurrencyLimiter::new(x, max_queued.unwrap_or(0)))),
+    redirects: Arc::new(RwLock::new(redirect_map)),
+    output_limits: Arc::new(OutputLimits::new(max_response_size, response_quota)),
+    readiness: Arc::new(Readiness::new(ready_timeout.map(Duration::from_secs))),
+    granted: Arc::new(RwLock::new(granted)),
+    default_headers: Arc::new(default_headers),
+    tunnels: Default::default(),
+    suspended: Default::default(),
+    available: Default::default(),
+    lazy: true,
   };
+  Ok(service_impl)
+}
+
+pub(super) async fn prepare_service(
+  rt: &Runtime,
+  name: ServiceName,
+  uuid: Option<Uuid>,
+  source: Source,
+  config: Config,
+) -> Result<(ServiceImpl, Isolate)> {
+  let mut service_impl = new_service_impl(name, uuid, source, config).await?;
+  let (paths, isolate) = (rt)
+    .prepare_service(
+      &service_impl.name,
+      service_impl.source.clone(),
+      service_impl.readiness.clone(),
+      service_impl.effective_permissions(),
+    )
+    .await?;
+  service_impl.info.paths = paths;
+  service_impl.lazy = false;
   Ok((service_impl, isolate))
 }
 
+/// Evaluates the source of a lazily loaded service, so that it can be started.
+pub(super) async fn evaluate(rt_pool: &Pool, service_impl: &mut ServiceImpl) -> Result<()> {
+  if !service_impl.lazy {
+    return Ok(());
+  }
+  let name = service_impl.name.clone();
+  let source = service_impl.source.clone();
+  let readiness = service_impl.readiness.clone();
+  let permissions = service_impl.effective_permissions();
+  let paths = rt_pool
+    .scope(move |rt| async move {
+      let (paths, isolate) = (rt)
+        .prepare_service(&name, source, readiness, permissions)
+        .await?;
+      rt.remove_isolate(isolate)?;
+      Ok::<_, crate::Error>(paths)
+    })
+    .await?;
+  service_impl.info.paths = paths;
+  service_impl.lazy = false;
+  Ok(())
+}
+
 impl ServicePool {
+  /// Loads a service as stopped without evaluating its source, which is
+  /// deferred until it starts. If `suspended`, it is started on request.
+  pub async fn load_lazy(
+    &self,
+    guard: &DeployGuard,
+    uuid: Option<Uuid>,
+    source: Source,
+    config: Config,
+    suspended: bool,
+  ) -> Result<StoppedService<'_>> {
+    let name = guard.owned_name();
+    self.check_total(&name)?;
+    if self.services.contains_key(&*name) {
+      return Err(ErrorKind::ServiceExists { name }.into());
+    }
+    let service_impl = new_service_impl(name.clone(), uuid, source, config).await?;
+    service_impl.suspended.store(suspended, Ordering::Release);
+    self
+      .services
+      .insert(name.clone(), ServiceState::Stopped(service_impl));
+    let service = self.services.get(&*name).unwrap();
+    Ok(StoppedService::from_ref(service))
+  }
+
   pub async fn load(
     &self,
     rt_pool: &Pool,
-    name: ServiceName,
+    guard: &DeployGuard,
     uuid: Option<Uuid>,
     source: Source,
     config: Config,
+    on_swap: Option<BeforeSwap>,
   ) -> Result<(StoppedService<'_>, Option<ServiceImpl>, ErrorPayload)> {
+    let name = guard.owned_name();
+    self.check_total(&name)?;
     let services = self.services.clone();
     let name2 = name.clone();
     let (service_impl, error_payload) = rt_pool
@@ -72,6 +252,7 @@ impl ServicePool {
         let (service_impl, isolate) =
           prepare_service(&rt, name2.clone(), uuid, source, config).await?;
         rt.remove_isolate(isolate)?;
+        before_swap(on_swap).await?;
 
         match Self::scope_stop(services, &rt, &*name2).await {
           Ok(_) => {}
@@ -97,15 +278,20 @@ impl ServicePool {
   pub async fn cold_update_or_create(
     &self,
     rt_pool: &Pool,
-    name: ServiceName,
+    guard: &DeployGuard,
     uuid: Option<Uuid>,
     source: Source,
     config: Config,
+    on_swap: Option<BeforeSwap>,
   ) -> Result<(Service<'_>, Option<ServiceImpl>, ErrorPayload)> {
+    let name = guard.owned_name();
+    self.check_total(&name)?;
+    self.check_running(&name)?;
+    self.check_dependencies(&name, &config.depends_on)?;
     let services = self.services.clone();
     let state = self.state.clone();
     let name2 = name.clone();
-    let (service_state, error_payload) = rt_pool
+    let (mut service_state, mut error_payload) = rt_pool
       .scope(move |rt| async move {
         let mut error_payload = ErrorPayload::default();
 
@@ -115,6 +301,10 @@ impl ServicePool {
         }
         let (service_impl, isolate) =
           prepare_service(&rt, name2.clone(), uuid, source, config).await?;
+        if let Err(error) = before_swap(on_swap).await {
+          rt.remove_isolate(isolate)?;
+          return Err(error.into());
+        }
 
         match Self::scope_stop(services, &rt, &*name2).await {
           Ok(_) => {}
@@ -124,7 +314,7 @@ impl ServicePool {
 
         let service_impl = Arc::new(service_impl);
         let result = rt
-          .create_service(&service_impl.name, service_impl.downgrade(), isolate, false)
+          .create_service(service_impl.downgrade(), isolate, false)
           .await;
         let state = ServiceState::Running(service_impl);
         let state = match result {
@@ -141,6 +331,29 @@ impl ServicePool {
       })
       .await?;
 
+    if let ServiceState::Running(service_impl) = &service_state {
+      if let Err(error) = wait_ready(rt_pool, service_impl.downgrade()).await {
+        error_payload.start = Some(error);
+        replace_with_or_abort(&mut service_state, |x| ServiceState::Stopped(x.into_impl()));
+      } else {
+        warm_up(rt_pool, service_impl.downgrade()).await;
+      }
+    }
+
+    let uuid = service_state.info().uuid;
+    let event = match &error_payload.start {
+      Some(error) => EventKind::Crashed {
+        service: name.clone(),
+        uuid,
+        error: error.to_string(),
+      },
+      None => EventKind::Started {
+        service: name.clone(),
+        uuid,
+      },
+    };
+    self.state.events.publish(event);
+
     match service_state {
       ServiceState::Running(service_impl) => {
         let service = service_impl.downgrade();
@@ -171,27 +384,37 @@ impl ServicePool {
   pub async fn hot_update(
     &self,
     rt_pool: &Pool,
-    name: ServiceName,
+    guard: &DeployGuard,
     uuid: Option<Uuid>,
     source: Source,
     config: Config,
+    on_swap: Option<BeforeSwap>,
   ) -> Result<(RunningService, ServiceImpl)> {
+    let name = guard.owned_name();
     match self.get(&*name) {
       Some(x) if x.is_stopped() => return Err(ErrorKind::ServiceStopped { name }.into()),
       None => return Err(ErrorKind::ServiceNotFound { name }.into()),
       _ => {}
     }
+    self.check_dependencies(&name, &config.depends_on)?;
 
     let name2 = name.clone();
     let service_impl = rt_pool
       .scope(move |rt| async move {
         let (service_impl, isolate) = prepare_service(&rt, name2, uuid, source, config).await?;
+        if let Err(error) = before_swap(on_swap).await {
+          rt.remove_isolate(isolate)?;
+          return Err(error.into());
+        }
         let service_impl = Arc::new(service_impl);
-        rt.create_service(&service_impl.name, service_impl.downgrade(), isolate, true)
+        rt.create_service(service_impl.downgrade(), isolate, true)
           .await?;
         Ok::<_, crate::Error>(service_impl)
       })
       .await?;
+    // `start` is not called on hot update, so the service is ready already
+    service_impl.readiness.set_ready();
+    warm_up(rt_pool, service_impl.downgrade()).await;
 
     let service = service_impl.downgrade();
     let replaced = (self.services)
@@ -0,0 +1,90 @@
+use std::collections::HashMap;
+
+/// Order in which services should be started, as computed by
+/// [`startup_order`].
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct StartupOrder<'a> {
+  /// Services whose dependencies all come before them.
+  pub order: Vec<&'a str>,
+  /// Services in, or depending on, a dependency cycle.
+  pub cyclic: Vec<&'a str>,
+}
+
+/// Sorts services so that each one comes after its dependencies.
+///
+/// Dependencies not in the list are ignored here; whether they are running is
+/// checked when the service starts.
+pub fn startup_order<'a>(
+  services: impl IntoIterator<Item = (&'a str, &'a [String])>,
+) -> StartupOrder<'a> {
+  #[derive(Clone, Copy, PartialEq, Eq)]
+  enum Mark {
+    Visiting,
+    Done,
+    Cyclic,
+  }
+
+  fn visit<'a>(
+    name: &'a str,
+    deps: &HashMap<&'a str, &'a [String]>,
+    marks: &mut HashMap<&'a str, Mark>,
+    result: &mut StartupOrder<'a>,
+  ) -> bool {
+    match marks.get(name) {
+      Some(Mark::Done) => return true,
+      Some(Mark::Visiting | Mark::Cyclic) => return false,
+      None => {}
+    }
+    marks.insert(name, Mark::Visiting);
+    let mut ok = true;
+    for dep in deps[name].iter() {
+      if let Some((&dep, _)) = deps.get_key_value(dep.as_str()) {
+        ok &= visit(dep, deps, marks, result);
+      }
+    }
+    if ok {
+      marks.insert(name, Mark::Done);
+      result.order.push(name);
+    } else {
+      marks.insert(name, Mark::Cyclic);
+      result.cyclic.push(name);
+    }
+    ok
+  }
+
+  let services = services.into_iter().collect::<Vec<_>>();
+  let deps = services.iter().copied().collect::<HashMap<_, _>>();
+  let mut marks = HashMap::new();
+  let mut result = StartupOrder::default();
+  for (name, _) in services {
+    visit(name, &deps, &mut marks, &mut result);
+  }
+  result
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use test_case::test_case;
+
+  #[test_case(&[("a", "b"), ("b", "c"), ("c", "")] => "c b a / "; "chain")]
+  #[test_case(&[("a", "x"), ("b", "")] => "a b / "; "unknown dependency")]
+  #[test_case(&[("a", "b"), ("b", "a"), ("c", "")] => "c / b a"; "cycle")]
+  #[test_case(&[("a", "b c"), ("b", "b"), ("c", "")] => "c / b a"; "depends on cycle")]
+  fn test_startup_order(services: &[(&str, &str)]) -> String {
+    let services = (services.iter())
+      .map(|(name, deps)| {
+        (
+          *name,
+          deps
+            .split_whitespace()
+            .map(String::from)
+            .collect::<Vec<_>>(),
+        )
+      })
+      .collect::<Vec<_>>();
+    let StartupOrder { order, cyclic } =
+      startup_order(services.iter().map(|(name, deps)| (*name, &deps[..])));
+    format!("{} / {}", order.join(" "), cyclic.join(" "))
+  }
+}
@@ -1,13 +1,26 @@
-use super::ServiceName;
-use crate::path::PathMatcher;
+use super::availability::Availability;
+use super::concurrency::ConcurrencyLimiter;
+use super::output::OutputLimits;
+use super::readiness::Readiness;
+use super::tunnels::Tunnels;
+use super::{RedirectMap, ServiceMetrics, ServiceName};
+use crate::config::{route_matches, Affinity, Permissions, RouteRule};
+use crate::net::Cidr;
+use crate::path::{normalize_path_str, PathMatcher};
 use crate::source::Source;
+use crate::version::{Version, VersionReq};
 use crate::ErrorKind::ServiceDropped;
 use crate::Result;
 use dashmap::mapref::multiple::RefMulti;
 use dashmap::mapref::one::Ref;
+use hyper::{Body, HeaderMap, Response};
+use parking_lot::{Mutex, RwLock};
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::marker::PhantomData;
+use std::net::IpAddr;
 use std::ops::Deref;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Weak};
 use uuid::Uuid;
 
@@ -17,6 +30,20 @@ pub(super) enum ServiceState {
 }
 
 impl ServiceState {
+  pub fn info(&self) -> &ServiceInfo {
+    match self {
+      Self::Running(x) => &x.info,
+      Self::Stopped(x) => &x.info,
+    }
+  }
+
+  pub fn as_impl(&self) -> &ServiceImpl {
+    match self {
+      Self::Running(x) => x,
+      Self::Stopped(x) => x,
+    }
+  }
+
   pub fn into_impl(self) -> ServiceImpl {
     match self {
       Self::Running(x) => Arc::try_unwrap(x).unwrap_or_else(|arc| arc.as_ref().clone()),
@@ -29,6 +56,28 @@ impl ServiceState {
 pub struct ServiceImpl {
   pub(crate) info: ServiceInfo,
   pub(crate) source: Source,
+  pub(crate) metrics: Arc<ServiceMetrics>,
+  pub(crate) limiter: Option<Arc<ConcurrencyLimiter>>,
+  pub(crate) redirects: Arc<RwLock<RedirectMap>>,
+  pub(crate) output_limits: Arc<OutputLimits>,
+  pub(crate) readiness: Arc<Readiness>,
+  /// Permissions granted by the host, restricting `permissions` if set.
+  /// Shared with the running service, so that granting takes effect without
+  /// replacing it.
+  pub(crate) granted: Arc<RwLock<Option<Permissions>>>,
+  /// Parsed `headers`.
+  pub(crate) default_headers: Arc<HeaderMap>,
+  /// Upgraded connections proxied by the service, closed when it stops.
+  pub(crate) tunnels: Arc<Tunnels>,
+  /// Whether the service was stopped for being idle, and should be started
+  /// again on request.
+  pub(crate) suspended: Arc<AtomicBool>,
+  /// Whether the service was within its `availability` when last checked.
+  pub(crate) available: Arc<Mutex<Option<bool>>>,
+  /// Whether the source is not evaluated yet, see [`ServicePool::load_lazy`].
+  ///
+  /// [`ServicePool::load_lazy`]: super::ServicePool::load_lazy
+  pub(crate) lazy: bool,
 }
 
 impl ServiceImpl {
@@ -45,6 +94,44 @@ impl ServiceImpl {
   pub fn source(&self) -> &Source {
     &self.source
   }
+
+  pub fn metrics(&self) -> &ServiceMetrics {
+    &self.metrics
+  }
+
+  pub fn is_suspended(&self) -> bool {
+    self.suspended.load(Ordering::Acquire)
+  }
+
+  pub fn granted(&self) -> Option<Permissions> {
+    self.granted.read().clone()
+  }
+
+  pub(crate) fn set_granted(&self, granted: Option<Permissions>) {
+    *self.granted.write() = granted;
+  }
+
+  /// Permissions the service actually runs with.
+  pub fn effective_permissions(&self) -> Permissions {
+    match &*self.granted.read() {
+      Some(granted) => self.permissions.restrict(granted),
+      None => self.permissions.clone(),
+    }
+  }
+
+  /// Headers added to the service's responses, parsed from `headers`.
+  pub fn default_headers(&self) -> &HeaderMap {
+    &self.default_headers
+  }
+
+  /// Replaces the redirect map of the service, taking effect immediately.
+  pub fn set_redirects(&self, redirects: RedirectMap) {
+    *self.redirects.write() = redirects;
+  }
+
+  pub(crate) fn redirect(&self, path: &str, query: Option<&str>) -> Option<Response<Body>> {
+    self.redirects.read().respond(path, query)
+  }
 }
 
 impl Deref for ServiceImpl {
@@ -60,6 +147,61 @@ pub struct ServiceInfo {
   pub(crate) name: ServiceName,
   pub(crate) pkg_name: Option<String>,
   pub(crate) description: Option<String>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub(crate) version: Option<Version>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub(crate) abel_api_version: Option<VersionReq>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub(crate) error_page: Option<String>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub(crate) capture_requests: Option<usize>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub(crate) profile_threshold: Option<u64>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub(crate) affinity: Option<Affinity>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub(crate) redirects: Option<String>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub(crate) error_reporting: Option<String>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub(crate) request_timeout: Option<u64>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub(crate) max_concurrency: Option<usize>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub(crate) max_queued: Option<usize>,
+  #[serde(default, skip_serializing_if = "Vec::is_empty")]
+  pub(crate) depends_on: Vec<String>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub(crate) ready_timeout: Option<u64>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub(crate) idle_timeout: Option<u64>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub(crate) wake_timeout: Option<u64>,
+  #[serde(default, skip_serializing_if = "Vec::is_empty")]
+  pub(crate) warm_up: Vec<String>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub(crate) availability: Option<Availability>,
+  #[serde(default, skip_serializing_if = "Vec::is_empty")]
+  pub(crate) allow_ips: Vec<Cidr>,
+  #[serde(default, skip_serializing_if = "Vec::is_empty")]
+  pub(crate) deny_ips: Vec<Cidr>,
+  #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+  pub(crate) routes: BTreeMap<String, RouteRule>,
+  #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+  pub(crate) headers: BTreeMap<String, String>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub(crate) max_response_size: Option<u64>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub(crate) response_quota: Option<u64>,
+  #[serde(default, skip_serializing_if = "Permissions::is_default")]
+  pub(crate) permissions: Permissions,
+  /// Whether `permissions` are not approved yet. Such services cannot start.
+  #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+  pub(crate) pending_approval: bool,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub(crate) content_hash: Option<String>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub(crate) deployed_at: Option<u64>,
   pub(crate) paths: Vec<PathMatcher>,
   pub(crate) uuid: Uuid,
 }
@@ -69,10 +211,58 @@ impl ServiceInfo {
   pub fn name(&self) -> &str { &self.name }
   pub fn pkg_name(&self) -> Option<&str> { self.pkg_name.as_deref() }
   pub fn description(&self) -> Option<&str> { self.description.as_deref() }
+  pub fn version(&self) -> Option<&Version> { self.version.as_ref() }
+  pub fn abel_api_version(&self) -> Option<&VersionReq> { self.abel_api_version.as_ref() }
+  pub fn error_page(&self) -> Option<&str> { self.error_page.as_deref() }
+  pub fn capture_requests(&self) -> Option<usize> { self.capture_requests }
+  pub fn profile_threshold(&self) -> Option<u64> { self.profile_threshold }
+  pub fn affinity(&self) -> Option<&Affinity> { self.affinity.as_ref() }
+  pub fn redirects(&self) -> Option<&str> { self.redirects.as_deref() }
+  pub fn error_reporting(&self) -> Option<&str> { self.error_reporting.as_deref() }
+  pub fn request_timeout(&self) -> Option<u64> { self.request_timeout }
+  pub fn max_concurrency(&self) -> Option<usize> { self.max_concurrency }
+  pub fn max_queued(&self) -> Option<usize> { self.max_queued }
+  pub fn depends_on(&self) -> &[String] { &self.depends_on }
+  pub fn ready_timeout(&self) -> Option<u64> { self.ready_timeout }
+  pub fn idle_timeout(&self) -> Option<u64> { self.idle_timeout }
+  pub fn wake_timeout(&self) -> Option<u64> { self.wake_timeout }
+  pub fn warm_up(&self) -> &[String] { &self.warm_up }
+  pub fn availability(&self) -> Option<&Availability> { self.availability.as_ref() }
+  pub fn allow_ips(&self) -> &[Cidr] { &self.allow_ips }
+  pub fn deny_ips(&self) -> &[Cidr] { &self.deny_ips }
+  pub fn routes(&self) -> &BTreeMap<String, RouteRule> { &self.routes }
+  pub fn headers(&self) -> &BTreeMap<String, String> { &self.headers }
+  pub fn max_response_size(&self) -> Option<u64> { self.max_response_size }
+  pub fn response_quota(&self) -> Option<u64> { self.response_quota }
+  pub fn permissions(&self) -> &Permissions { &self.permissions }
+  pub fn pending_approval(&self) -> bool { self.pending_approval }
+  pub fn content_hash(&self) -> Option<&str> { self.content_hash.as_deref() }
+  pub fn deployed_at(&self) -> Option<u64> { self.deployed_at }
   pub fn paths(&self) -> &[PathMatcher] { &self.paths }
   pub fn uuid(&self) -> Uuid { self.uuid }
 }
 
+impl ServiceInfo {
+  /// Checks the client address against `allow_ips` and `deny_ips`.
+  pub fn is_ip_allowed(&self, ip: IpAddr) -> bool {
+    !self.deny_ips.iter().any(|x| x.contains(ip))
+      && (self.allow_ips.is_empty() || self.allow_ips.iter().any(|x| x.contains(ip)))
+  }
+
+  /// Names of the service's secrets holding the tokens required by route
+  /// rules matching `path`.
+  ///
+  /// `path` is normalized first, so that e.g. `//admin` cannot bypass a
+  /// rule for `/admin`.
+  pub fn route_token_secrets(&self, path: &str) -> Vec<String> {
+    let path = format!("/{}", normalize_path_str(path));
+    (self.routes.iter())
+      .filter(|(pattern, _)| route_matches(pattern, &path))
+      .filter_map(|(_, rule)| rule.token_secret().map(String::from))
+      .collect()
+  }
+}
+
 pub enum Service<'a> {
   Running(RunningService),
   Stopped(StoppedService<'a>),
@@ -171,6 +361,7 @@ pub struct StoppedService<'a>(StoppedServiceInner<'a>);
 enum StoppedServiceInner<'a> {
   Ref(Ref<'a, ServiceName, ServiceState>),
   RefMulti(RefMulti<'a, ServiceName, ServiceState>),
+  Held(Ref<'a, ServiceName, ServiceImpl>),
 }
 
 impl<'a> StoppedService<'a> {
@@ -183,6 +374,10 @@ impl<'a> StoppedService<'a> {
     assert!(matches!(x.value(), ServiceState::Stopped(_)));
     Self(StoppedServiceInner::RefMulti(x))
   }
+
+  pub(super) fn from_held(x: Ref<'a, ServiceName, ServiceImpl>) -> Self {
+    Self(StoppedServiceInner::Held(x))
+  }
 }
 
 impl Deref for StoppedService<'_> {
@@ -204,6 +399,7 @@ impl Deref for StoppedService<'_> {
           unreachable!()
         }
       }
+      StoppedServiceInner::Held(x) => x.value(),
     }
   }
 }
@@ -0,0 +1,135 @@
+use super::{ServiceName, ServicePool};
+use crate::ErrorKind::DeployInProgress;
+use crate::Result;
+use dashmap::DashMap;
+use std::sync::Arc;
+use tokio::sync::{Mutex, OwnedMutexGuard};
+
+pub(super) type DeployLocks = DashMap<ServiceName, Arc<Mutex<()>>>;
+
+/// Lock of a service for deploying, required by operations that replace,
+/// start, stop or remove it. Released when dropped.
+pub struct DeployGuard {
+  name: ServiceName,
+  guard: Option<OwnedMutexGuard<()>>,
+  locks: Arc<DeployLocks>,
+}
+
+impl DeployGuard {
+  pub fn name(&self) -> &str {
+    &self.name
+  }
+
+  pub(super) fn owned_name(&self) -> ServiceName {
+    self.name.clone()
+  }
+}
+
+impl Drop for DeployGuard {
+  fn drop(&mut self) {
+    drop(self.guard.take());
+    // Forgets the lock if nobody else holds or waits for it
+    (self.locks).remove_if(&self.name, |_, x| Arc::strong_count(x) == 1);
+  }
+}
+
+impl ServicePool {
+  /// Locks a service for deploying, so that changes to the same name happen
+  /// one at a time.
+  ///
+  /// If `wait` is false, fails with `DeployInProgress` instead of waiting for
+  /// the deploy in progress.
+  pub async fn lock_deploy(&self, name: &str, wait: bool) -> Result<DeployGuard> {
+    let lock = self.deploying.entry(name.into()).or_default().clone();
+    let guard = if wait {
+      lock.lock_owned().await
+    } else {
+      (lock.try_lock_owned()).map_err(|_| DeployInProgress { name: name.into() })?
+    };
+    Ok(DeployGuard {
+      name: name.into(),
+      guard: Some(guard),
+      locks: self.deploying.clone(),
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::source::{MemorySource, Source};
+  use crate::{Abel, AbelOptions};
+  use std::sync::atomic::{AtomicBool, Ordering};
+  use std::sync::Arc;
+  use std::time::Duration;
+  use tempfile::TempDir;
+
+  fn abel(local_storage: &TempDir) -> Abel {
+    Abel::new(AbelOptions {
+      runtime_pool_size: 1,
+      local_storage_path: local_storage.path().into(),
+      secrets_path: None,
+      remote_cache_path: None,
+      max_services: None,
+      max_running_services: None,
+      http_client: Default::default(),
+      coordinator: None,
+      exec_allowlist: Vec::new(),
+      #[cfg(feature = "encryption")]
+      storage_key: None,
+    })
+    .unwrap()
+  }
+
+  fn source(version: &str) -> Source {
+    let code = format!(r#"abel.listen("/", function() return "{version}" end)"#);
+    Source::new(MemorySource::from_files([("main.lua", code)]))
+  }
+
+  #[tokio::test]
+  async fn test_lock_deploy() {
+    let local_storage = TempDir::new().unwrap();
+    let abel = abel(&local_storage);
+    let guard = abel.lock_deploy("svc", false).await.unwrap();
+    assert!(abel.lock_deploy("svc", false).await.is_err());
+    assert!(abel.lock_deploy("other", false).await.is_ok());
+    drop(guard);
+    assert!(abel.lock_deploy("svc", false).await.is_ok());
+    assert!(abel.service_pool.deploying.is_empty());
+  }
+
+  #[tokio::test]
+  async fn test_lock_deploy_concurrent() {
+    let local_storage = TempDir::new().unwrap();
+    let abel = Arc::new(abel(&local_storage));
+    let guard = abel.lock_deploy("svc", false).await.unwrap();
+    (abel.cold_update_or_create_service(&guard, None, source("v1"), Default::default(), None))
+      .await
+      .unwrap();
+
+    // Waits for the deploy in progress, instead of interleaving with it
+    let locked = Arc::new(AtomicBool::new(false));
+    let waiting = tokio::spawn({
+      let abel = abel.clone();
+      let locked = locked.clone();
+      async move {
+        let guard = abel.lock_deploy("svc", true).await.unwrap();
+        locked.store(true, Ordering::Release);
+        let uuid = abel.get_running_service("svc").unwrap().upgrade().uuid();
+        abel.stop_service(&guard).await.unwrap();
+        uuid
+      }
+    });
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    let (service, _, _) =
+      (abel.cold_update_or_create_service(&guard, None, source("v2"), Default::default(), None))
+        .await
+        .unwrap();
+    let uuid = service.upgrade().uuid();
+    assert!(!locked.load(Ordering::Acquire));
+    drop(guard);
+
+    assert_eq!(waiting.await.unwrap(), uuid);
+    assert!(abel.get_running_service("svc").is_err());
+    assert!(abel.service_pool.deploying.is_empty());
+  }
+}
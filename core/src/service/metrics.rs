@@ -0,0 +1,204 @@
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Request counters of a single service version.
+#[derive(Debug)]
+pub struct ServiceMetrics {
+  requests: AtomicU64,
+  errors: AtomicU64,
+  bytes_sent: AtomicU64,
+  aborted_responses: AtomicU64,
+  cpu_time_us: AtomicU64,
+  peak_memory: AtomicU64,
+  /// Unix time of the last request or start, in seconds.
+  last_active: AtomicU64,
+  /// Unix time of the last start, in seconds.
+  started_at: AtomicU64,
+  /// Requests and server errors in each of the last 60 seconds, indexed by
+  /// second.
+  recent: Mutex<[RecentBucket; 60]>,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct RecentBucket {
+  second: u64,
+  requests: u64,
+  errors: u64,
+}
+
+impl Default for ServiceMetrics {
+  fn default() -> Self {
+    Self {
+      requests: AtomicU64::new(0),
+      errors: AtomicU64::new(0),
+      bytes_sent: AtomicU64::new(0),
+      aborted_responses: AtomicU64::new(0),
+      cpu_time_us: AtomicU64::new(0),
+      peak_memory: AtomicU64::new(0),
+      last_active: AtomicU64::new(now()),
+      started_at: AtomicU64::new(now()),
+      recent: Mutex::new([Default::default(); 60]),
+    }
+  }
+}
+
+fn now() -> u64 {
+  (SystemTime::now().duration_since(UNIX_EPOCH))
+    .map(|x| x.as_secs())
+    .unwrap_or(0)
+}
+
+impl ServiceMetrics {
+  pub(crate) fn record(&self, server_error: bool) {
+    self.requests.fetch_add(1, Ordering::Relaxed);
+    if server_error {
+      self.errors.fetch_add(1, Ordering::Relaxed);
+    }
+    self.touch();
+    self.record_recent(now(), server_error);
+  }
+
+  fn record_recent(&self, now: u64, server_error: bool) {
+    let mut recent = self.recent.lock();
+    let bucket = &mut recent[(now % 60) as usize];
+    if bucket.second != now {
+      *bucket = RecentBucket {
+        second: now,
+        ..Default::default()
+      };
+    }
+    bucket.requests += 1;
+    if server_error {
+      bucket.errors += 1;
+    }
+  }
+
+  pub(crate) fn record_bytes(&self, bytes: u64) {
+    self.bytes_sent.fetch_add(bytes, Ordering::Relaxed);
+  }
+
+  pub(crate) fn record_aborted(&self) {
+    self.aborted_responses.fetch_add(1, Ordering::Relaxed);
+  }
+
+  pub(crate) fn record_cpu_time(&self, time: Duration) {
+    (self.cpu_time_us).fetch_add(time.as_micros() as u64, Ordering::Relaxed);
+  }
+
+  pub(crate) fn record_memory(&self, bytes: u64) {
+    self.peak_memory.fetch_max(bytes, Ordering::Relaxed);
+  }
+
+  pub(crate) fn touch(&self) {
+    self.last_active.store(now(), Ordering::Relaxed);
+  }
+
+  pub(crate) fn mark_started(&self) {
+    self.started_at.store(now(), Ordering::Relaxed);
+    self.touch();
+  }
+
+  /// Time since the last start, meaningful only if the service is running.
+  pub fn uptime(&self) -> Duration {
+    Duration::from_secs(now().saturating_sub(self.started_at.load(Ordering::Relaxed)))
+  }
+
+  /// Time since the last request or start.
+  pub fn idle_for(&self) -> Duration {
+    Duration::from_secs(now().saturating_sub(self.last_active.load(Ordering::Relaxed)))
+  }
+
+  /// Requests and server errors in the 60 seconds up to `now`. Buckets of
+  /// earlier seconds are left out, even if not yet overwritten.
+  fn recent(&self, now: u64) -> (u64, u64) {
+    (self.recent.lock().iter())
+      .filter(|x| now.saturating_sub(x.second) < 60)
+      .fold((0, 0), |(r, e), x| (r + x.requests, e + x.errors))
+  }
+
+  pub fn snapshot(&self) -> MetricsSnapshot {
+    let (requests_last_minute, errors_last_minute) = self.recent(now());
+    MetricsSnapshot {
+      requests: self.requests.load(Ordering::Relaxed),
+      errors: self.errors.load(Ordering::Relaxed),
+      bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+      aborted_responses: self.aborted_responses.load(Ordering::Relaxed),
+      cpu_time_us: self.cpu_time_us.load(Ordering::Relaxed),
+      peak_memory: self.peak_memory.load(Ordering::Relaxed),
+      requests_last_minute,
+      errors_last_minute,
+      error_rate: if requests_last_minute > 0 {
+        errors_last_minute as f64 / requests_last_minute as f64
+      } else {
+        0.
+      },
+    }
+  }
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct MetricsSnapshot {
+  pub requests: u64,
+  /// Requests that resulted in server errors.
+  pub errors: u64,
+  /// Bytes of response bodies.
+  #[serde(default)]
+  pub bytes_sent: u64,
+  /// Responses rejected or aborted for exceeding size limits.
+  #[serde(default)]
+  pub aborted_responses: u64,
+  /// Time spent running the service's tasks, in microseconds.
+  #[serde(default)]
+  pub cpu_time_us: u64,
+  /// Most Lua memory held by a single task, in bytes, counting what it
+  /// allocates and frees while running.
+  #[serde(default)]
+  pub peak_memory: u64,
+  /// Requests in the last 60 seconds, counted in one bucket per second.
+  #[serde(default)]
+  pub requests_last_minute: u64,
+  /// Requests in the last minute that resulted in server errors.
+  #[serde(default)]
+  pub errors_last_minute: u64,
+  /// Ratio of `errors_last_minute` to `requests_last_minute`.
+  #[serde(default)]
+  pub error_rate: f64,
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_usage() {
+    let metrics = ServiceMetrics::default();
+    metrics.record_cpu_time(Duration::from_micros(1500));
+    metrics.record_cpu_time(Duration::from_micros(500));
+    metrics.record_memory(4096);
+    metrics.record_memory(1024);
+    let snapshot = metrics.snapshot();
+    assert_eq!(snapshot.cpu_time_us, 2000);
+    assert_eq!(snapshot.peak_memory, 4096);
+  }
+
+  #[test]
+  fn test_recent() {
+    let metrics = ServiceMetrics::default();
+    let t = 1_000_000;
+    metrics.record_recent(t, false);
+    metrics.record_recent(t, true);
+    metrics.record_recent(t + 1, false);
+    metrics.record_recent(t + 30, true);
+    assert_eq!(metrics.recent(t + 30), (4, 2));
+    assert_eq!(metrics.recent(t + 59), (4, 2));
+    // Buckets older than a minute are left out before being reused
+    assert_eq!(metrics.recent(t + 60), (2, 1));
+    assert_eq!(metrics.recent(t + 120), (0, 0));
+
+    // Reusing a bucket drops its old counts
+    metrics.record_recent(t + 60, false);
+    assert_eq!(metrics.recent(t + 60), (3, 1));
+  }
+}
@@ -1,36 +1,155 @@
+mod approval;
+mod availability;
+mod canary;
+mod concurrency;
 mod create;
+mod depends;
 mod impls;
+mod lock;
+mod metrics;
+mod output;
+mod readiness;
+mod redirect;
+mod suspend;
+mod tunnels;
+mod warm_up;
 
-pub use create::ErrorPayload;
+pub use availability::{Availability, CronWindow, UtcOffset};
+pub use create::{BeforeSwap, ErrorPayload};
+pub use depends::{startup_order, StartupOrder};
 pub use impls::*;
+pub use lock::DeployGuard;
+pub use metrics::{MetricsSnapshot, ServiceMetrics};
+pub(crate) use output::meter;
+pub(crate) use readiness::Readiness;
+pub use redirect::RedirectMap;
+pub(crate) use tunnels::{Tunnel, Tunnels, MAX_TUNNELS};
 
+use crate::event::EventKind;
 use crate::runtime::Runtime;
 use crate::task::Pool;
 use crate::ErrorKind::*;
 use crate::{AbelState, Result};
+use canary::Canary;
+use create::evaluate;
 use dashmap::DashMap;
+use lock::DeployLocks;
 use log::warn;
+use readiness::wait_ready;
 use replace_with::{replace_with_or_abort, replace_with_or_abort_and_return};
+use serde::Serialize;
 use smallstr::SmallString;
+use std::collections::HashMap;
 use std::path::PathBuf;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
 
 pub type ServiceName = SmallString<[u8; 16]>;
 type Services = DashMap<ServiceName, ServiceState>;
 
+/// Limits on the number of services, checked when services are created or
+/// started.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ServiceLimits {
+  pub max_services: Option<usize>,
+  pub max_running_services: Option<usize>,
+}
+
+/// Current number of services and their limits.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct Capacity {
+  pub services: usize,
+  pub running_services: usize,
+  pub max_services: Option<usize>,
+  pub max_running_services: Option<usize>,
+}
+
 pub struct ServicePool {
   services: Arc<Services>,
+  canaries: DashMap<ServiceName, Canary>,
+  /// New versions of existing services held until their permissions are
+  /// approved, while the existing ones keep serving.
+  held: DashMap<ServiceName, ServiceImpl>,
+  /// Locks held while waking suspended services.
+  waking: DashMap<ServiceName, Arc<tokio::sync::Mutex<()>>>,
+  /// Locks held while deploying services, shared with their guards.
+  deploying: Arc<DeployLocks>,
+  limits: ServiceLimits,
   state: Arc<AbelState>,
 }
 
 impl ServicePool {
-  pub fn new(state: Arc<AbelState>) -> Self {
+  pub fn new(state: Arc<AbelState>, limits: ServiceLimits) -> Self {
     Self {
       services: Default::default(),
+      canaries: Default::default(),
+      held: Default::default(),
+      waking: Default::default(),
+      deploying: Default::default(),
+      limits,
       state,
     }
   }
 
+  pub fn capacity(&self) -> Capacity {
+    let running_services = (self.services.iter())
+      .filter(|x| matches!(x.value(), ServiceState::Running(_)))
+      .count();
+    Capacity {
+      services: self.services.len(),
+      running_services,
+      max_services: self.limits.max_services,
+      max_running_services: self.limits.max_running_services,
+    }
+  }
+
+  /// Checks if a service could be added without exceeding the limits.
+  ///
+  /// This is best-effort: concurrent creations may slightly overshoot.
+  fn check_total(&self, name: &str) -> Result<()> {
+    match self.limits.max_services {
+      Some(max) if !self.services.contains_key(name) && self.services.len() >= max => {
+        Err(TooManyServices { max }.into())
+      }
+      _ => Ok(()),
+    }
+  }
+
+  /// Checks if a service could be started without exceeding the limits.
+  fn check_running(&self, name: &str) -> Result<()> {
+    let max = match self.limits.max_running_services {
+      Some(max) => max,
+      None => return Ok(()),
+    };
+    let running = (self.services.get(name))
+      .map(|x| matches!(x.value(), ServiceState::Running(_)))
+      .unwrap_or(false);
+    if !running && self.capacity().running_services >= max {
+      Err(TooManyRunningServices { max }.into())
+    } else {
+      Ok(())
+    }
+  }
+
+  /// Checks if all dependencies of a service are running.
+  fn check_dependencies(&self, name: &str, depends_on: &[String]) -> Result<()> {
+    for dependency in depends_on {
+      let running = (self.services.get(dependency.as_str()))
+        .map(|x| matches!(x.value(), ServiceState::Running(_)))
+        .unwrap_or(false);
+      if !running {
+        return Err(
+          DependencyNotRunning {
+            name: name.into(),
+            dependency: dependency.as_str().into(),
+          }
+          .into(),
+        );
+      }
+    }
+    Ok(())
+  }
+
   pub fn get(&self, name: &str) -> Option<Service<'_>> {
     self.services.get(name).map(|x| match x.value() {
       ServiceState::Running(x) => Service::Running(x.downgrade()),
@@ -38,10 +157,12 @@ impl ServicePool {
     })
   }
 
+  /// Gets a running service, routing to its canary (if any) according to the
+  /// canary's weight.
   pub fn get_running(&self, name: &str) -> Option<RunningService> {
     let x = self.services.get(name);
     if let Some(ServiceState::Running(x)) = x.as_deref() {
-      Some(x.downgrade())
+      Some(self.route_canary(name).unwrap_or_else(|| x.downgrade()))
     } else {
       None
     }
@@ -54,10 +175,12 @@ impl ServicePool {
     })
   }
 
-  pub async fn stop(&self, rt_pool: &Pool, name: &str) -> Result<StoppedService<'_>> {
+  pub async fn stop(&self, rt_pool: &Pool, guard: &DeployGuard) -> Result<StoppedService<'_>> {
+    let name = guard.name();
     if let Some(mut service) = self.services.get_mut(name) {
       let state = service.value_mut();
       if let ServiceState::Running(service2) = state {
+        let uuid = service2.uuid;
         let x = service2.downgrade();
         let result = rt_pool
           .scope(|rt| async move {
@@ -66,6 +189,13 @@ impl ServicePool {
           })
           .await;
         replace_with_or_abort(state, |x| ServiceState::Stopped(x.into_impl()));
+        (self.state.events).publish(EventKind::Stopped {
+          service: name.into(),
+          uuid,
+        });
+        if let Err(error) = self.stop_canary(rt_pool, name).await {
+          warn!("Lua error when stopping canary of service '{name}': {error}");
+        }
         result.map(|_| StoppedService::from_ref(service.downgrade()))
       } else {
         Err(ServiceStopped { name: name.into() }.into())
@@ -95,6 +225,7 @@ impl ServicePool {
     for mut service in self.services.iter_mut() {
       let state = service.value_mut();
       if let ServiceState::Running(service2) = state {
+        let uuid = service2.uuid;
         let x = service2.downgrade();
         let result = rt_pool
           .scope(|rt| async move {
@@ -103,6 +234,10 @@ impl ServicePool {
           })
           .await;
         replace_with_or_abort(state, |x| ServiceState::Stopped(x.into_impl()));
+        (self.state.events).publish(EventKind::Stopped {
+          service: service.key().clone(),
+          uuid,
+        });
         if let Err(error) = result {
           warn!(
             "Lua error when stopping service '{}': {error}",
@@ -111,19 +246,48 @@ impl ServicePool {
         }
       }
     }
+    let canaries = (self.canaries.iter())
+      .map(|x| x.key().clone())
+      .collect::<Vec<_>>();
+    for name in canaries {
+      if let Err(error) = self.stop_canary(rt_pool, &name).await {
+        warn!("Lua error when stopping canary of service '{name}': {error}");
+      }
+    }
   }
 
-  pub async fn start(&self, rt_pool: &Pool, name: &str) -> Result<RunningService> {
+  pub async fn start(&self, rt_pool: &Pool, guard: &DeployGuard) -> Result<RunningService> {
+    let name = guard.name();
+    let depends_on = (self.services.get(name)).map(|x| x.info().depends_on.clone());
+    if let Some(depends_on) = depends_on {
+      self.check_running(name)?;
+      self.check_dependencies(name, &depends_on)?;
+    }
     if let Some(mut service) = self.services.get_mut(name) {
+      if let ServiceState::Stopped(service_impl) = service.value_mut() {
+        if service_impl.pending_approval {
+          return Err(ServicePendingApproval { name: name.into() }.into());
+        }
+        evaluate(rt_pool, service_impl).await?;
+      }
       if let state @ ServiceState::Stopped(_) = service.value_mut() {
         let running = replace_with_or_abort_and_return(state, |x| {
           if let ServiceState::Stopped(s) = x {
+            s.readiness.reset();
+            s.suspended.store(false, Ordering::Release);
+            s.metrics.mark_started();
             let s = Arc::new(s);
             (s.downgrade(), ServiceState::Running(s))
           } else {
             unreachable!()
           }
         });
+        let uuid = running.upgrade().uuid;
+        let crashed = |error: &crate::Error| EventKind::Crashed {
+          service: name.into(),
+          uuid,
+          error: error.to_string(),
+        };
         let running2 = running.clone();
         let result = rt_pool
           .scope(move |rt| async move {
@@ -131,13 +295,29 @@ impl ServicePool {
             Ok::<_, crate::Error>(())
           })
           .await;
-        match result {
-          Ok(_) => Ok(running),
-          Err(error) => {
-            replace_with_or_abort(state, |x| ServiceState::Stopped(x.into_impl()));
-            Err(error)
+        if let Err(error) = result {
+          replace_with_or_abort(state, |x| ServiceState::Stopped(x.into_impl()));
+          self.state.events.publish(crashed(&error));
+          return Err(error);
+        }
+        // Not holding the entry while waiting, so that requests can reach the
+        // readiness gate
+        drop(service);
+        if let Err(error) = wait_ready(rt_pool, running.clone()).await {
+          if let Some(mut service) = self.services.get_mut(name) {
+            let state = service.value_mut();
+            if matches!(state, ServiceState::Running(x) if running.ptr_eq(&x.downgrade())) {
+              replace_with_or_abort(state, |x| ServiceState::Stopped(x.into_impl()));
+            }
           }
+          self.state.events.publish(crashed(&error));
+          return Err(error);
         }
+        (self.state.events).publish(EventKind::Started {
+          service: name.into(),
+          uuid,
+        });
+        Ok(running)
       } else {
         Err(ServiceRunning { name: name.into() }.into())
       }
@@ -146,11 +326,28 @@ impl ServicePool {
     }
   }
 
-  pub async fn remove(&self, state: &AbelState, name: &str) -> Result<ServiceImpl> {
+  pub async fn remove(
+    &self,
+    rt_pool: &Pool,
+    state: &AbelState,
+    guard: &DeployGuard,
+  ) -> Result<ServiceImpl> {
+    let name = guard.name();
     if let Some((name2, old_service)) = self.services.remove(name) {
       if let ServiceState::Stopped(x) = old_service {
+        if let Err(error) = self.stop_canary(rt_pool, name).await {
+          warn!("Lua error when stopping canary of service '{name}': {error}");
+        }
+        self.waking.remove(name);
+        self.held.remove(name);
         let local_storage_path = get_local_storage_path(state, name);
         tokio::fs::remove_dir_all(local_storage_path).await?;
+        state.queues.remove(name).await?;
+        state.kv.remove(name).await?;
+        state.schedules.remove(name).await?;
+        state.metrics.remove(name);
+        state.profiles.remove(name);
+        state.diagnostics.remove(name);
         Ok(x)
       } else {
         assert!(self.services.insert(name2, old_service).is_none());
@@ -165,3 +362,17 @@ impl ServicePool {
 pub(crate) fn get_local_storage_path(state: &AbelState, name: &str) -> PathBuf {
   state.local_storage_path.join(name)
 }
+
+/// Reads the service's secrets, a JSON object of strings. Empty if the
+/// service has none.
+pub(crate) async fn load_secrets(state: &AbelState, name: &str) -> Result<HashMap<String, String>> {
+  let path = match &state.secrets_path {
+    Some(path) => path.join(format!("{name}.json")),
+    None => return Ok(HashMap::new()),
+  };
+  match tokio::fs::read(path).await {
+    Ok(content) => Ok(serde_json::from_slice(&content).map_err(std::io::Error::from)?),
+    Err(error) if error.kind() == std::io::ErrorKind::NotFound => Ok(HashMap::new()),
+    Err(error) => Err(error.into()),
+  }
+}
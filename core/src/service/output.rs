@@ -0,0 +1,126 @@
+use super::{ServiceMetrics, ServiceName};
+use crate::ErrorKind::{ResponseQuotaExceeded, ResponseTooLarge};
+use crate::Result;
+use futures::StreamExt;
+use hyper::body::HttpBody;
+use hyper::{Body, Response};
+use log::warn;
+use std::error::Error;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Limits on bytes produced by a service's responses.
+#[derive(Debug)]
+pub(crate) struct OutputLimits {
+  /// Maximum size of a single response body.
+  max_response_size: Option<u64>,
+  quota: Option<ByteQuota>,
+}
+
+impl OutputLimits {
+  pub fn new(max_response_size: Option<u64>, response_quota: Option<u64>) -> Self {
+    Self {
+      max_response_size,
+      quota: response_quota.map(ByteQuota::new),
+    }
+  }
+}
+
+/// Bytes allowed per minute, counted in fixed one-minute windows.
+#[derive(Debug)]
+struct ByteQuota {
+  limit: u64,
+  /// Unix time in minutes of the current window.
+  window: AtomicU64,
+  used: AtomicU64,
+}
+
+impl ByteQuota {
+  fn new(limit: u64) -> Self {
+    Self {
+      limit,
+      window: AtomicU64::new(0),
+      used: AtomicU64::new(0),
+    }
+  }
+
+  fn sync_window(&self) {
+    let minute = (SystemTime::now().duration_since(UNIX_EPOCH))
+      .map(|x| x.as_secs() / 60)
+      .unwrap_or(0);
+    // Racing resets may forgive a few bytes, which is fine for a quota
+    if self.window.swap(minute, Ordering::Relaxed) != minute {
+      self.used.store(0, Ordering::Relaxed);
+    }
+  }
+
+  fn is_exhausted(&self) -> bool {
+    self.sync_window();
+    self.used.load(Ordering::Relaxed) >= self.limit
+  }
+
+  /// Returns `false` if the quota is exceeded.
+  fn consume(&self, bytes: u64) -> bool {
+    self.sync_window();
+    self.used.fetch_add(bytes, Ordering::Relaxed) + bytes <= self.limit
+  }
+}
+
+/// Counts bytes of the response body, enforcing the service's limits.
+///
+/// Bodies of known size are checked before being sent. Streamed bodies are
+/// aborted as soon as a limit is exceeded.
+pub(crate) fn meter(
+  name: ServiceName,
+  resp: Response<Body>,
+  metrics: Arc<ServiceMetrics>,
+  limits: Arc<OutputLimits>,
+) -> Result<Response<Body>> {
+  if let Some(quota) = &limits.quota {
+    if quota.is_exhausted() {
+      return Err(ResponseQuotaExceeded { name }.into());
+    }
+  }
+
+  if let Some(size) = resp.body().size_hint().exact() {
+    metrics.record_bytes(size);
+    if let Some(limit) = limits.max_response_size.filter(|x| size > *x) {
+      warn!("response of service '{name}' exceeded {limit} bytes");
+      metrics.record_aborted();
+      return Err(ResponseTooLarge { name, limit }.into());
+    }
+    if let Some(quota) = &limits.quota {
+      if !quota.consume(size) {
+        warn!("service '{name}' exceeded its response quota");
+        metrics.record_aborted();
+        return Err(ResponseQuotaExceeded { name }.into());
+      }
+    }
+    return Ok(resp);
+  }
+
+  let (parts, body) = resp.into_parts();
+  let mut size = 0;
+  let body = body.map(move |chunk| -> Result<_, Box<dyn Error + Send + Sync>> {
+    let chunk = chunk?;
+    let len = chunk.len() as u64;
+    size += len;
+    metrics.record_bytes(len);
+    let error = if let Some(limit) = limits.max_response_size.filter(|x| size > *x) {
+      warn!("response of service '{name}' exceeded {limit} bytes, aborting");
+      ResponseTooLarge {
+        name: name.clone(),
+        limit,
+      }
+    } else if !(limits.quota.as_ref()).map_or(true, |x| x.consume(len)) {
+      warn!("service '{name}' exceeded its response quota, aborting");
+      ResponseQuotaExceeded { name: name.clone() }
+    } else {
+      return Ok(chunk);
+    };
+    metrics.record_aborted();
+    Err(crate::Error::from(error).into())
+  });
+  Ok(Response::from_parts(parts, Body::wrap_stream(body)))
+}
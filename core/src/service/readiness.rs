@@ -0,0 +1,81 @@
+use super::RunningService;
+use crate::task::Pool;
+use crate::ErrorKind::ServiceNotReady;
+use crate::Result;
+use log::warn;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use tokio::sync::Notify;
+
+/// Whether a started service is ready to receive traffic.
+///
+/// Services with `ready_timeout` set are not ready until they call
+/// `abel.ready()`; others are always ready.
+#[derive(Debug)]
+pub(crate) struct Readiness {
+  ready: AtomicBool,
+  notify: Notify,
+  timeout: Option<Duration>,
+}
+
+impl Readiness {
+  pub fn new(timeout: Option<Duration>) -> Self {
+    Self {
+      ready: AtomicBool::new(timeout.is_none()),
+      notify: Notify::new(),
+      timeout,
+    }
+  }
+
+  pub fn is_ready(&self) -> bool {
+    self.ready.load(Ordering::Acquire)
+  }
+
+  pub fn set_ready(&self) {
+    self.ready.store(true, Ordering::Release);
+    self.notify.notify_waiters();
+  }
+
+  /// Marks the service as not ready, before it is started again.
+  pub fn reset(&self) {
+    self.ready.store(self.timeout.is_none(), Ordering::Release);
+  }
+
+  /// Waits until the service is ready, returning `false` on timeout.
+  pub async fn wait(&self) -> bool {
+    let timeout = match self.timeout {
+      _ if self.is_ready() => return true,
+      Some(timeout) => timeout,
+      None => return true,
+    };
+    let wait = async {
+      loop {
+        let notified = self.notify.notified();
+        if self.is_ready() {
+          break;
+        }
+        notified.await;
+      }
+    };
+    tokio::time::timeout(timeout, wait).await.is_ok()
+  }
+}
+
+/// Waits for a newly started service to become ready, stopping it if it does
+/// not in time.
+pub(super) async fn wait_ready(rt_pool: &Pool, service: RunningService) -> Result<()> {
+  let (readiness, name) = {
+    let guard = service.try_upgrade()?;
+    (guard.readiness.clone(), guard.name.clone())
+  };
+  if readiness.wait().await {
+    return Ok(());
+  }
+  let result = rt_pool
+    .scope(move |rt| async move { rt.run_stop(service).await })
+    .await;
+  if let Err(error) = result {
+    warn!("Lua error when stopping service '{name}': {error}");
+  }
+  Err(ServiceNotReady { name }.into())
+}
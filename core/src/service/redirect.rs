@@ -0,0 +1,221 @@
+use crate::config::match_wildcards;
+use crate::source::Source;
+use crate::ErrorKind::{EntryNotFound, InvalidRedirectMap};
+use crate::Result;
+use hyper::header::{HeaderValue, LOCATION};
+use hyper::{Body, Response, StatusCode};
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use tokio::io::{self, AsyncReadExt};
+
+/// Redirects served directly by Abel, without running any Lua code.
+///
+/// A redirect map is a text file with one rule per line, consisting of the
+/// source path, the target and an optional status code (301 by default):
+///
+/// ```text
+/// # Comments start with '#'
+/// /old-page   /new-page
+/// /go/docs    https://example.com/docs   302
+/// /legacy/*   /v2/*                      308
+/// /u/*/p/*    /posts/$2?user=$1
+/// ```
+///
+/// A `*` in the source matches any characters, and `$1`, `$2`, ... in the
+/// target are replaced by what the source's `*`s matched, in order. A `*` at
+/// the end of the target is replaced by what the last one matched, e.g. the
+/// rest of the path. Exact rules take precedence over wildcard ones, and
+/// longer wildcard rules over shorter ones.
+#[derive(Debug, Default)]
+pub struct RedirectMap {
+  exact: HashMap<String, Redirect>,
+  wildcards: Vec<(String, Redirect)>,
+}
+
+#[derive(Debug)]
+struct Redirect {
+  to: String,
+  status: StatusCode,
+}
+
+impl RedirectMap {
+  pub fn parse(s: &str) -> Result<Self> {
+    let mut map = Self::default();
+    for (i, line) in s.lines().enumerate() {
+      let line = line.trim();
+      if line.is_empty() || line.starts_with('#') {
+        continue;
+      }
+      let error = |reason: &str| InvalidRedirectMap {
+        line: i + 1,
+        reason: reason.into(),
+      };
+
+      let mut fields = line.split_ascii_whitespace();
+      let (from, to) = match (fields.next(), fields.next()) {
+        (Some(from), Some(to)) => (from, to),
+        _ => return Err(error("expected source and target").into()),
+      };
+      let status = match fields.next() {
+        Some(status) => (status.parse().ok())
+          .and_then(|x| StatusCode::from_u16(x).ok())
+          .filter(StatusCode::is_redirection)
+          .ok_or_else(|| error("invalid redirect status"))?,
+        None => StatusCode::MOVED_PERMANENTLY,
+      };
+      if fields.next().is_some() {
+        return Err(error("too many fields").into());
+      }
+      if !from.starts_with('/') {
+        return Err(error("source must start with '/'").into());
+      }
+      if HeaderValue::from_str(to).is_err() {
+        return Err(error("invalid target").into());
+      }
+      let wildcards = from.matches('*').count();
+      if capture_refs(to).any(|n| n == 0 || n > wildcards) {
+        return Err(error("invalid capture reference").into());
+      }
+
+      let redirect = Redirect {
+        to: to.into(),
+        status,
+      };
+      if wildcards > 0 {
+        if map.wildcards.iter().any(|(x, _)| x == from) {
+          return Err(error("duplicate source").into());
+        }
+        map.wildcards.push((from.into(), redirect));
+      } else {
+        match map.exact.entry(from.into()) {
+          Entry::Occupied(_) => return Err(error("duplicate source").into()),
+          Entry::Vacant(entry) => entry.insert(redirect),
+        };
+      }
+    }
+    map.wildcards.sort_by(|a, b| b.0.len().cmp(&a.0.len()));
+    Ok(map)
+  }
+
+  /// Reads and parses a redirect map from the service's source.
+  pub(crate) async fn load(source: &Source, path: &str) -> Result<Self> {
+    let mut file = match source.get(path).await {
+      Ok(file) => file,
+      Err(error) if error.kind() == io::ErrorKind::NotFound => {
+        return Err(EntryNotFound { entry: path.into() }.into())
+      }
+      Err(error) => return Err(error.into()),
+    };
+    let mut s = String::new();
+    file.read_to_string(&mut s).await?;
+    Self::parse(&s)
+  }
+
+  pub fn len(&self) -> usize {
+    self.exact.len() + self.wildcards.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.len() == 0
+  }
+
+  fn lookup(&self, path: &str) -> Option<(String, StatusCode)> {
+    if let Some(Redirect { to, status }) = self.exact.get(path) {
+      return Some((to.clone(), *status));
+    }
+    (self.wildcards.iter()).find_map(|(pattern, Redirect { to, status })| {
+      let captures = match_wildcards(pattern, path)?;
+      Some((substitute(to, &captures), *status))
+    })
+  }
+
+  /// Generates the redirect response for the path, if any rule matches.
+  ///
+  /// The query string is carried over unless the target already has one.
+  pub fn respond(&self, path: &str, query: Option<&str>) -> Option<Response<Body>> {
+    let (mut location, status) = self.lookup(path)?;
+    if let Some(query) = query.filter(|_| !location.contains('?')) {
+      location.push('?');
+      location.push_str(query);
+    }
+    Response::builder()
+      .status(status)
+      .header(LOCATION, location)
+      .body(Body::empty())
+      .ok()
+  }
+}
+
+/// Capture numbers referred to by `$1`, `$2`, ... in a target.
+fn capture_refs(to: &str) -> impl Iterator<Item = usize> + '_ {
+  (to.split('$').skip(1))
+    .filter_map(|x| x.chars().next()?.to_digit(10))
+    .map(|n| n as usize)
+}
+
+/// Replaces capture references in `to`, and a trailing `*` with the last
+/// capture.
+fn substitute(to: &str, captures: &[&str]) -> String {
+  let (to, rest) = match to.strip_suffix('*') {
+    Some(to) => (to, captures.last().copied()),
+    None => (to, None),
+  };
+  let mut result = String::with_capacity(to.len());
+  let mut chars = to.chars().peekable();
+  while let Some(c) = chars.next() {
+    let capture = (chars.peek().and_then(|x| x.to_digit(10)))
+      .filter(|_| c == '$')
+      .and_then(|n| captures.get((n as usize).checked_sub(1)?));
+    match capture {
+      Some(capture) => {
+        result.push_str(capture);
+        chars.next();
+      }
+      None => result.push(c),
+    }
+  }
+  result.push_str(rest.unwrap_or_default());
+  result
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use test_case::test_case;
+
+  const MAP: &str = "
+    # comment
+    /old        /new
+    /go/docs    https://example.com/docs  302
+    /legacy/*   /v2/*                     308
+    /legacy/a/* /a
+    /u/*/p/*    /posts/$2?user=$1
+    /*.php      /$1 302
+  ";
+
+  #[test_case("/old" => Some(("/new".into(), 301)); "exact")]
+  #[test_case("/go/docs" => Some(("https://example.com/docs".into(), 302)); "external")]
+  #[test_case("/legacy/x/y" => Some(("/v2/x/y".into(), 308)); "wildcard")]
+  #[test_case("/legacy/a/b" => Some(("/a".into(), 301)); "longest prefix")]
+  #[test_case("/u/1/p/2" => Some(("/posts/2?user=1".into(), 301)); "captures")]
+  #[test_case("/a/b.php" => Some(("/a/b".into(), 302)); "inner wildcard")]
+  #[test_case("/old/" => None; "no match")]
+  fn test_lookup(path: &str) -> Option<(String, u16)> {
+    let map = RedirectMap::parse(MAP).unwrap();
+    map.lookup(path).map(|(to, status)| (to, status.as_u16()))
+  }
+
+  #[test_case("/a" => 1; "missing target")]
+  #[test_case("/a /b 200" => 1; "not redirection")]
+  #[test_case("/a /b 301 x" => 1; "too many fields")]
+  #[test_case("a /b" => 1; "relative source")]
+  #[test_case("/a /b\n\n/a /c" => 3; "duplicate")]
+  #[test_case("/a/* /$2" => 1; "unmatched capture")]
+  #[test_case("/a /$1" => 1; "capture of exact source")]
+  fn test_parse_error(s: &str) -> usize {
+    match RedirectMap::parse(s).unwrap_err().into_parts().0 {
+      InvalidRedirectMap { line, .. } => line,
+      kind => panic!("unexpected error: {kind}"),
+    }
+  }
+}
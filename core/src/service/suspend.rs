@@ -0,0 +1,102 @@
+use super::{RunningService, ServiceName, ServicePool, ServiceState};
+use crate::task::Pool;
+use crate::ErrorKind::{DependencyCycle, ServiceNotFound, ServiceStopped};
+use crate::Result;
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use log::warn;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+impl ServicePool {
+  /// Stops running services that have received no requests for longer than
+  /// their `idle_timeout`, returning their names.
+  ///
+  /// Services with a canary are never suspended.
+  pub async fn suspend_idle(&self, rt_pool: &Pool) -> Vec<ServiceName> {
+    let idle = (self.services.iter())
+      .filter_map(|x| match x.value() {
+        ServiceState::Running(service) if !self.canaries.contains_key(x.key()) => {
+          let timeout = Duration::from_secs(service.idle_timeout?);
+          (service.metrics.idle_for() >= timeout)
+            .then(|| (x.key().clone(), service.suspended.clone()))
+        }
+        _ => None,
+      })
+      .collect::<Vec<_>>();
+
+    let mut suspended = Vec::with_capacity(idle.len());
+    for (name, flag) in idle {
+      // Services being deployed are left alone until the next call
+      let guard = match self.lock_deploy(&name, false).await {
+        Ok(guard) => guard,
+        Err(_) => continue,
+      };
+      flag.store(true, Ordering::Release);
+      match self.stop(rt_pool, &guard).await {
+        Ok(_) => suspended.push(name),
+        Err(error) if matches!(error.kind(), ServiceStopped { .. } | ServiceNotFound { .. }) => {
+          flag.store(false, Ordering::Release);
+        }
+        Err(error) => {
+          warn!("Lua error when suspending service '{name}': {error}");
+          suspended.push(name);
+        }
+      }
+    }
+    suspended
+  }
+
+  /// Starts a suspended service again, along with its suspended
+  /// dependencies. Concurrent calls for the same service share one start.
+  pub async fn wake(&self, rt_pool: &Pool, name: &str) -> Result<RunningService> {
+    self.wake_inner(rt_pool, name, &mut Vec::new()).await
+  }
+
+  fn wake_inner<'a>(
+    &'a self,
+    rt_pool: &'a Pool,
+    name: &'a str,
+    waking: &'a mut Vec<ServiceName>,
+  ) -> BoxFuture<'a, Result<RunningService>> {
+    async move {
+      if waking.iter().any(|x| x.as_str() == name) {
+        return Err(DependencyCycle { name: name.into() }.into());
+      }
+      let lock = self.waking.entry(name.into()).or_default().clone();
+      let _guard = lock.lock().await;
+      if let Some(service) = self.get_running(name) {
+        return Ok(service);
+      }
+      let depends_on = match self.services.get(name).as_deref() {
+        Some(ServiceState::Stopped(x)) if x.is_suspended() => x.depends_on.clone(),
+        Some(_) => return Err(ServiceStopped { name: name.into() }.into()),
+        None => return Err(ServiceNotFound { name: name.into() }.into()),
+      };
+
+      waking.push(name.into());
+      for dependency in &depends_on {
+        if self.is_suspended(dependency) {
+          self.wake_inner(rt_pool, dependency, waking).await?;
+        }
+      }
+      waking.pop();
+      let guard = self.lock_deploy(name, true).await?;
+      // Started or replaced while waiting for the lock
+      if let Some(service) = self.get_running(name) {
+        return Ok(service);
+      } else if !self.is_suspended(name) {
+        return Err(ServiceStopped { name: name.into() }.into());
+      }
+      self.start(rt_pool, &guard).await
+    }
+    .boxed()
+  }
+
+  fn is_suspended(&self, name: &str) -> bool {
+    matches!(
+      self.services.get(name).as_deref(),
+      Some(ServiceState::Stopped(x)) if x.is_suspended(),
+    )
+  }
+}
@@ -0,0 +1,88 @@
+use parking_lot::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio_util::sync::CancellationToken;
+
+/// Most upgraded connections a service may proxy at the same time.
+pub const MAX_TUNNELS: usize = 256;
+
+/// Upgraded connections a service passes through `http.proxy`, e.g.
+/// WebSockets, which outlive the requests opening them.
+#[derive(Debug, Default)]
+pub struct Tunnels {
+  count: AtomicUsize,
+  closing: Mutex<CancellationToken>,
+}
+
+impl Tunnels {
+  /// Takes a slot for a new tunnel, or returns `None` if [`MAX_TUNNELS`] are
+  /// already open.
+  pub fn open(self: &Arc<Self>) -> Option<Tunnel> {
+    if self.count.fetch_add(1, Ordering::AcqRel) >= MAX_TUNNELS {
+      self.count.fetch_sub(1, Ordering::AcqRel);
+      return None;
+    }
+    Some(Tunnel {
+      closing: self.closing.lock().clone(),
+      tunnels: self.clone(),
+    })
+  }
+
+  /// Closes every open tunnel, e.g. when the service stops. Tunnels opened
+  /// afterwards are not affected, as the service may be started again.
+  pub fn close_all(&self) {
+    std::mem::take(&mut *self.closing.lock()).cancel();
+  }
+}
+
+/// Slot of an open tunnel, freed when dropped.
+#[derive(Debug)]
+pub struct Tunnel {
+  closing: CancellationToken,
+  tunnels: Arc<Tunnels>,
+}
+
+impl Tunnel {
+  /// Completes when the tunnel should be closed.
+  pub async fn closed(&self) {
+    self.closing.cancelled().await
+  }
+}
+
+impl Drop for Tunnel {
+  fn drop(&mut self) {
+    self.tunnels.count.fetch_sub(1, Ordering::AcqRel);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::time::Duration;
+  use tokio::time::timeout;
+
+  #[tokio::test]
+  async fn test_tunnels() {
+    let tunnels = Arc::new(Tunnels::default());
+    let mut open = (0..MAX_TUNNELS)
+      .map(|_| tunnels.open().unwrap())
+      .collect::<Vec<_>>();
+    assert!(tunnels.open().is_none());
+    open.pop();
+    assert_eq!(tunnels.count.load(Ordering::Acquire), MAX_TUNNELS - 1);
+
+    tunnels.close_all();
+    for tunnel in &open {
+      timeout(Duration::from_secs(1), tunnel.closed())
+        .await
+        .unwrap();
+    }
+    // Tunnels opened later, e.g. after restarting, stay open
+    let tunnel = tunnels.open().unwrap();
+    assert!(timeout(Duration::from_millis(10), tunnel.closed())
+      .await
+      .is_err());
+    drop((open, tunnel));
+    assert_eq!(tunnels.count.load(Ordering::Acquire), 0);
+  }
+}
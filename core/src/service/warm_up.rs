@@ -0,0 +1,72 @@
+use super::RunningService;
+use crate::task::{Pool, Priority};
+use futures::TryStreamExt;
+use hyper::{Body, Request, Response};
+use log::{info, warn};
+use std::time::{Duration, Instant};
+
+/// Longest a warm-up request may take, even if the service's `request_timeout`
+/// is longer or not set.
+const MAX_WARM_UP_TIME: Duration = Duration::from_secs(30);
+
+/// Sends the `warm_up` requests of a newly started service to every runtime,
+/// before it takes traffic.
+///
+/// Failures are only logged, as the service is already running.
+pub(super) async fn warm_up(rt_pool: &Pool, service: RunningService) {
+  let (name, paths, timeout) = match service.try_upgrade() {
+    Ok(guard) if !guard.warm_up.is_empty() => {
+      let timeout = (guard.request_timeout.map(Duration::from_secs))
+        .map_or(MAX_WARM_UP_TIME, |x| x.min(MAX_WARM_UP_TIME));
+      (guard.name.clone(), guard.warm_up.clone(), timeout)
+    }
+    _ => return,
+  };
+  let started_at = Instant::now();
+  for path in paths {
+    let sub_path = path
+      .split_once('?')
+      .map(|x| x.0)
+      .unwrap_or(&path)
+      .to_owned();
+    for i in 0..rt_pool.size() {
+      let req = match Request::get(format!("/{name}{path}")).body(Body::empty()) {
+        Ok(req) => req,
+        Err(error) => {
+          warn!("invalid warm-up path '{path}' of service '{name}': {error}");
+          break;
+        }
+      };
+      let service = service.clone();
+      let sub_path = sub_path.clone();
+      let deadline = Instant::now() + timeout;
+      let run = rt_pool.scope_at(i, Priority::Background, move |rt| async move {
+        let resp: Response<Body> = rt
+          .handle_request(service, &sub_path, req, Some(deadline))
+          .await?
+          .into();
+        let status = resp.status();
+        // Streamed responses run until their bodies are read
+        let mut body = resp.into_body();
+        while let Ok(Some(_)) = body.try_next().await {}
+        Ok::<_, crate::Error>(status)
+      });
+      let result = match tokio::time::timeout_at(deadline.into(), run).await {
+        Ok(Ok(result)) => result,
+        Ok(Err(error)) => Err(error),
+        Err(_) => {
+          warn!("warm-up request to '{path}' of service '{name}' timed out after {timeout:?}");
+          continue;
+        }
+      };
+      match result {
+        Ok(status) if status.is_server_error() => {
+          warn!("warm-up request to '{path}' of service '{name}' failed with {status}")
+        }
+        Ok(_) => {}
+        Err(error) => warn!("warm-up request to '{path}' of service '{name}' failed: {error}"),
+      }
+    }
+  }
+  info!("Warmed up service '{name}' in {:.2?}", started_at.elapsed());
+}
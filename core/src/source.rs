@@ -1,21 +1,41 @@
+use crate::path::normalize_path_str;
 use crate::ErrorKind::EntryNotFound;
 use crate::Result;
 use async_trait::async_trait;
 use mlua::{ExternalResult, Function, Lua, Table, UserData};
+use std::collections::BTreeMap;
 use std::fmt::Debug;
-use std::io::SeekFrom;
+use std::io::{Cursor, SeekFrom};
 use std::ops::Deref;
+use std::path::PathBuf;
 use std::pin::Pin;
 use std::sync::Arc;
 use tokio::io::ErrorKind::NotFound;
 use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt};
 
+/// Provides files of a service's source.
+///
+/// Implement this to serve services from places other than the local file
+/// system, e.g. databases or version control systems, and wrap it in
+/// [`Source`]. Paths are relative to the source root, with `""` being the root
+/// itself.
 #[async_trait]
 pub trait SourceVfs {
   type File: AsyncRead + AsyncSeek;
   async fn get(&self, path: &str) -> io::Result<Self::File>;
   async fn exists(&self, path: &str) -> io::Result<bool>;
   async fn metadata(&self, path: &str) -> io::Result<Metadata>;
+
+  /// Lists names of the entries directly inside a directory.
+  ///
+  /// Sources unable to enumerate their contents may keep the default
+  /// implementation, which always fails.
+  async fn read_dir(&self, _path: &str) -> io::Result<Vec<String>> {
+    Err(io::Error::new(
+      io::ErrorKind::Unsupported,
+      "listing directories is not supported by this source",
+    ))
+  }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -54,6 +74,10 @@ where
   async fn metadata(&self, path: &str) -> io::Result<Metadata> {
     self.0.metadata(path).await
   }
+
+  async fn read_dir(&self, path: &str) -> io::Result<Vec<String>> {
+    self.0.read_dir(path).await
+  }
 }
 
 #[derive(Clone)]
@@ -68,7 +92,7 @@ impl Source {
     Self(Arc::new(SourceInner(vfs)) as _)
   }
 
-  async fn get_bytes(&self, path: &str) -> io::Result<Vec<u8>> {
+  pub(crate) async fn get_bytes(&self, path: &str) -> io::Result<Vec<u8>> {
     let mut file = self.get(path).await?;
     let len = file.seek(SeekFrom::End(0)).await?;
     file.rewind().await?;
@@ -113,6 +137,153 @@ impl Debug for Source {
   }
 }
 
+/// Source in a directory of the local file system.
+pub struct DirSource(PathBuf);
+
+impl DirSource {
+  pub fn new(path: impl Into<PathBuf>) -> Self {
+    Self(path.into())
+  }
+}
+
+#[async_trait]
+impl SourceVfs for DirSource {
+  type File = tokio::fs::File;
+
+  async fn get(&self, path: &str) -> io::Result<Self::File> {
+    tokio::fs::File::open(self.0.join(normalize_path_str(path))).await
+  }
+
+  async fn exists(&self, path: &str) -> io::Result<bool> {
+    Ok(
+      tokio::fs::metadata(self.0.join(normalize_path_str(path)))
+        .await
+        .is_ok(),
+    )
+  }
+
+  async fn metadata(&self, path: &str) -> io::Result<Metadata> {
+    let metadata = tokio::fs::metadata(self.0.join(normalize_path_str(path))).await?;
+    if metadata.is_file() {
+      Ok(Metadata::File {
+        size: metadata.len(),
+      })
+    } else {
+      Ok(Metadata::Dir)
+    }
+  }
+
+  async fn read_dir(&self, path: &str) -> io::Result<Vec<String>> {
+    let mut dir = tokio::fs::read_dir(self.0.join(normalize_path_str(path))).await?;
+    let mut names = Vec::new();
+    while let Some(entry) = dir.next_entry().await? {
+      names.push(entry.file_name().to_string_lossy().into_owned());
+    }
+    names.sort();
+    Ok(names)
+  }
+}
+
+/// Source kept entirely in memory, for embedding and testing.
+///
+/// ```
+/// use abel_core::source::{MemorySource, Source};
+///
+/// let source = Source::new(MemorySource::from_files([(
+///   "main.lua",
+///   r#"abel.listen("/", function(req) return "Hello, world!" end)"#,
+/// )]));
+/// ```
+#[derive(Debug, Default, Clone)]
+pub struct MemorySource {
+  files: BTreeMap<String, Arc<[u8]>>,
+}
+
+impl MemorySource {
+  pub fn from_files<P, C>(files: impl IntoIterator<Item = (P, C)>) -> Self
+  where
+    P: AsRef<str>,
+    C: AsRef<[u8]>,
+  {
+    let files = (files.into_iter())
+      .map(|(path, content)| {
+        let path = normalize_path_str(path.as_ref());
+        (path, Arc::from(content.as_ref()))
+      })
+      .collect();
+    Self { files }
+  }
+
+  fn is_dir(&self, path: &str) -> bool {
+    path.is_empty() || self.entries_in(path).next().is_some()
+  }
+
+  /// Iterates over paths relative to the directory, including ones in its
+  /// subdirectories.
+  fn entries_in<'a>(&'a self, dir: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+    let prefix = if dir.is_empty() {
+      String::new()
+    } else {
+      format!("{dir}/")
+    };
+    (self.files.range(prefix.clone()..))
+      .map(|(path, _)| path.as_str())
+      .take_while(move |path| path.starts_with(&prefix))
+      .map(move |path| &path[dir.len() + !dir.is_empty() as usize..])
+  }
+}
+
+#[async_trait]
+impl SourceVfs for MemorySource {
+  type File = Cursor<Arc<[u8]>>;
+
+  async fn get(&self, path: &str) -> io::Result<Self::File> {
+    let path = normalize_path_str(path);
+    match self.files.get(&path) {
+      Some(content) => Ok(Cursor::new(content.clone())),
+      None if self.is_dir(&path) => Err(io::Error::from_raw_os_error(libc::EISDIR)),
+      None => Err(io::Error::new(
+        io::ErrorKind::NotFound,
+        "No such file or directory",
+      )),
+    }
+  }
+
+  async fn exists(&self, path: &str) -> io::Result<bool> {
+    let path = normalize_path_str(path);
+    Ok(self.files.contains_key(&path) || self.is_dir(&path))
+  }
+
+  async fn metadata(&self, path: &str) -> io::Result<Metadata> {
+    let path = normalize_path_str(path);
+    match self.files.get(&path) {
+      Some(content) => Ok(Metadata::File {
+        size: content.len() as _,
+      }),
+      None if self.is_dir(&path) => Ok(Metadata::Dir),
+      None => Err(io::Error::new(
+        io::ErrorKind::NotFound,
+        "No such file or directory",
+      )),
+    }
+  }
+
+  async fn read_dir(&self, path: &str) -> io::Result<Vec<String>> {
+    let path = normalize_path_str(path);
+    if !self.is_dir(&path) {
+      return Err(io::Error::new(
+        io::ErrorKind::NotFound,
+        "No such file or directory",
+      ));
+    }
+    let mut names = (self.entries_in(&path))
+      .map(|x| x.split_once('/').map(|x| x.0).unwrap_or(x).to_owned())
+      .collect::<Vec<_>>();
+    names.dedup();
+    Ok(names)
+  }
+}
+
 #[derive(Debug, Clone)]
 pub struct SourceUserData(pub Source);
 
@@ -1,14 +1,28 @@
+use super::Profiler;
+use crate::runtime::diagnostics::ServiceDiagnostics;
+use crate::service::{ServiceMetrics, Tunnels};
 use mlua::{Function, Lua, RegistryKey, Table, ToLua};
 use parking_lot::Mutex;
-use std::cell::Ref;
+use std::cell::{Cell, Ref, RefCell};
 use std::rc::Rc;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 #[derive(Debug, Clone, Default)]
 pub struct TaskContext {
   pub close_table: Option<Rc<RegistryKey>>,
   pub cpu_time: Arc<Mutex<Duration>>,
+  /// Usage of the task and tasks it spawns is recorded to the service's
+  /// metrics, once it is known.
+  pub metrics: Rc<RefCell<Option<Arc<ServiceMetrics>>>>,
+  /// Where deprecated APIs used by the task are reported.
+  pub diagnostics: Rc<RefCell<Option<Arc<ServiceDiagnostics>>>>,
+  pub profiler: Rc<RefCell<Option<Profiler>>>,
+  /// Deadline of the request being handled, capping outbound calls' timeouts.
+  pub deadline: Rc<Cell<Option<Instant>>>,
+  /// Where connections upgraded by the task are tracked.
+  pub tunnels: Rc<RefCell<Option<Arc<Tunnels>>>>,
+  pub cancelled: Rc<Cell<bool>>,
 }
 
 impl TaskContext {
@@ -32,6 +46,53 @@ impl TaskContext {
     lua.remove_app_data::<Self>()
   }
 
+  /// Attributes usage of the current task to a service.
+  pub fn set_metrics(lua: &Lua, metrics: Arc<ServiceMetrics>) {
+    if let Some(ctx) = Self::get_current(lua) {
+      *ctx.metrics.borrow_mut() = Some(metrics);
+    }
+  }
+
+  pub fn set_tunnels(lua: &Lua, tunnels: Arc<Tunnels>) {
+    if let Some(ctx) = Self::get_current(lua) {
+      *ctx.tunnels.borrow_mut() = Some(tunnels);
+    }
+  }
+
+  pub fn set_diagnostics(lua: &Lua, diagnostics: Arc<ServiceDiagnostics>) {
+    if let Some(ctx) = Self::get_current(lua) {
+      *ctx.diagnostics.borrow_mut() = Some(diagnostics);
+    }
+  }
+
+  /// Starts sampling call stacks of the current task and tasks it spawns.
+  pub fn start_profiling(lua: &Lua) {
+    if let Some(ctx) = Self::get_current(lua) {
+      *ctx.profiler.borrow_mut() = Some(Profiler::default());
+    }
+  }
+
+  pub fn stop_profiling(lua: &Lua) -> Option<Profiler> {
+    Self::get_current(lua).and_then(|x| x.profiler.borrow_mut().take())
+  }
+
+  pub fn set_deadline(lua: &Lua, deadline: Instant) {
+    if let Some(ctx) = Self::get_current(lua) {
+      ctx.deadline.set(Some(deadline));
+    }
+  }
+
+  /// Caps `timeout` at the time left until the current request's deadline.
+  pub fn cap_timeout(lua: &Lua, timeout: Option<Duration>) -> Option<Duration> {
+    let remaining = (Self::get_current(lua))
+      .and_then(|x| x.deadline.get())
+      .map(|x| x.saturating_duration_since(Instant::now()));
+    match (timeout, remaining) {
+      (Some(timeout), Some(remaining)) => Some(timeout.min(remaining)),
+      (timeout, remaining) => timeout.or(remaining),
+    }
+  }
+
   pub fn try_close(&mut self, lua: &Lua) -> mlua::Result<()> {
     if let Some(context) = self.close_table.take().and_then(|x| Rc::try_unwrap(x).ok()) {
       let context_table: Table = lua.registry_value(&context)?;
@@ -71,3 +132,30 @@ pub fn close_value(v: mlua::Value) -> mlua::Result<()> {
   }
   Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_cap_timeout() {
+    let lua = Lua::new();
+    let timeout = Some(Duration::from_secs(10));
+    assert_eq!(TaskContext::cap_timeout(&lua, timeout), timeout);
+
+    TaskContext::default().set_current(&lua);
+    assert_eq!(TaskContext::cap_timeout(&lua, timeout), timeout);
+    assert_eq!(TaskContext::cap_timeout(&lua, None), None);
+
+    TaskContext::set_deadline(&lua, Instant::now() + Duration::from_secs(1));
+    let capped = TaskContext::cap_timeout(&lua, timeout).unwrap();
+    assert!(capped <= Duration::from_secs(1));
+    assert!(TaskContext::cap_timeout(&lua, None).unwrap() <= Duration::from_secs(1));
+
+    TaskContext::set_deadline(&lua, Instant::now() - Duration::from_millis(1));
+    assert_eq!(
+      TaskContext::cap_timeout(&lua, timeout),
+      Some(Duration::ZERO)
+    );
+  }
+}
@@ -1,5 +1,6 @@
 use super::task_future::TaskFuture;
-use super::{LocalTask, Task};
+use super::{LocalTask, Priority, Task};
+use crate::event::{EventKind, Events};
 use crate::runtime::Runtime;
 use futures::future::select;
 use futures::future::Either::*;
@@ -7,15 +8,17 @@ use futures::stream::FuturesUnordered;
 use futures::task::{waker, ArcWake};
 use futures::{pin_mut, Stream};
 use log::{error, trace};
+use mlua::Lua;
+use serde::Serialize;
 use std::pin::Pin;
 use std::rc::Rc;
 use std::sync::atomic::Ordering::Release;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::task::{Context, Poll, Waker};
 use std::time::Duration;
 use tokio::runtime::Handle;
-use tokio::sync::{mpsc, oneshot};
+use tokio::sync::{mpsc, oneshot, Notify};
 use tokio::time::Instant;
 
 struct MyWaker(mpsc::UnboundedSender<()>);
@@ -26,34 +29,153 @@ impl ArcWake for MyWaker {
   }
 }
 
-struct PanicNotifier(Arc<AtomicBool>);
+struct PanicNotifier(Arc<AtomicBool>, Events);
 
 impl Drop for PanicNotifier {
   fn drop(&mut self) {
     if std::thread::panicking() {
-      self.0.store(true, Release)
+      self.0.store(true, Release);
+      let worker = std::thread::current().name().unwrap_or_default().into();
+      self.1.publish(EventKind::Panicked { worker });
     }
   }
 }
 
+/// How often the executor checks whether it should clean up.
+const CHECK_INTERVAL: Duration = Duration::from_secs(10);
+/// Clean up after being idle for this long.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+/// Clean up anyway after this long, if there has been any activity.
+const MAX_INTERVAL: Duration = Duration::from_secs(600);
+/// Clean up when Lua memory usage grows by this many bytes since last time.
+const MEMORY_GROWTH_THRESHOLD: usize = 64 * 1024 * 1024;
+
+/// Capacity of each task channel.
+const QUEUE_SIZE: usize = 16;
+
+/// Counters updated by the executor's thread.
+#[derive(Debug, Default)]
+struct Counters {
+  used_memory: AtomicUsize,
+  gc_count: AtomicU64,
+  loaded_services: AtomicUsize,
+  running_tasks: AtomicUsize,
+}
+
+impl Counters {
+  /// Counts completed garbage collection cycles with an object that is
+  /// finalized, and replaced by a new one, once every cycle.
+  fn watch_gc(self: &Arc<Self>, lua: &Lua) -> mlua::Result<()> {
+    let this = self.clone();
+    let metatable = lua.create_table()?;
+    let finalizer = lua.create_function(move |lua, _: mlua::Value| {
+      this.gc_count.fetch_add(1, Ordering::Relaxed);
+      this.watch_gc(lua)
+    })?;
+    metatable.raw_set("__gc", finalizer)?;
+    let object = lua.create_table()?;
+    object.set_metatable(Some(metatable));
+    Ok(())
+  }
+
+  fn update(&self, rt: &Runtime, running_tasks: usize) {
+    (self.used_memory).store(rt.lua().used_memory(), Ordering::Relaxed);
+    (self.loaded_services).store(rt.loaded_count(), Ordering::Relaxed);
+    (self.running_tasks).store(running_tasks, Ordering::Relaxed);
+  }
+}
+
+/// Resource usage of an executor's Lua sandbox.
+#[derive(Debug, Clone, Serialize)]
+pub struct SandboxStats {
+  pub worker: String,
+  pub panicked: bool,
+  /// Bytes of memory used by Lua.
+  pub used_memory: usize,
+  /// Number of completed garbage collection cycles, incremental or full.
+  pub gc_count: u64,
+  /// Number of service instances loaded in the sandbox.
+  pub loaded_services: usize,
+  pub running_tasks: usize,
+  /// Tasks sent to the executor but not yet taken.
+  pub queued_tasks: usize,
+}
+
+/// Decides when to run `Runtime::cleanup`.
+struct CleanupTrigger {
+  last_cleanup: Instant,
+  last_active: Instant,
+  dirty: bool,
+  baseline_memory: usize,
+}
+
+impl CleanupTrigger {
+  fn new(used_memory: usize) -> Self {
+    let now = Instant::now();
+    Self {
+      last_cleanup: now,
+      last_active: now,
+      dirty: false,
+      baseline_memory: used_memory,
+    }
+  }
+
+  fn active(&mut self) {
+    self.last_active = Instant::now();
+    self.dirty = true;
+  }
+
+  fn is_due(&self, used_memory: usize, idle: bool) -> bool {
+    if used_memory >= self.baseline_memory + MEMORY_GROWTH_THRESHOLD {
+      trace!("memory pressure: {used_memory} bytes used");
+      return true;
+    }
+    self.dirty
+      && ((idle && self.last_active.elapsed() >= IDLE_TIMEOUT)
+        || self.last_cleanup.elapsed() >= MAX_INTERVAL)
+  }
+
+  fn reset(&mut self, used_memory: usize) {
+    self.last_cleanup = Instant::now();
+    self.dirty = false;
+    self.baseline_memory = used_memory;
+  }
+}
+
 pub struct Executor {
+  name: String,
   panicked: Arc<AtomicBool>,
-  task_tx: mpsc::Sender<Task>,
+  counters: Arc<Counters>,
+  /// One channel for each priority, indexed by `Priority as usize`.
+  task_txs: Vec<mpsc::Sender<Task>>,
+  cleanup_notify: Arc<Notify>,
   _stop_tx: oneshot::Sender<()>,
 }
 
 impl Executor {
-  pub fn new(f: impl FnOnce() -> mlua::Result<Runtime> + Send + 'static, name: String) -> Self {
+  pub fn new(
+    f: impl FnOnce() -> mlua::Result<Runtime> + Send + 'static,
+    name: String,
+    events: Events,
+  ) -> Self {
     let panicked = Arc::new(AtomicBool::new(false));
-    let panic_notifier = PanicNotifier(panicked.clone());
-    let (task_tx, mut task_rx) = mpsc::channel::<Task>(16);
+    let panic_notifier = PanicNotifier(panicked.clone(), events);
+    let (task_txs, mut task_rxs): (Vec<_>, Vec<_>) = (0..Priority::COUNT)
+      .map(|_| mpsc::channel::<Task>(QUEUE_SIZE))
+      .unzip();
     let (_stop_tx, mut stop_rx) = oneshot::channel();
+    let cleanup_notify = Arc::new(Notify::new());
+    let cleanup_notify2 = cleanup_notify.clone();
+    let counters = Arc::new(Counters::default());
+    let counters2 = counters.clone();
 
     let handle = Handle::current();
     std::thread::Builder::new()
-      .name(name)
+      .name(name.clone())
       .spawn(move || {
         let _panic_notifier = panic_notifier;
+        let cleanup_notify = cleanup_notify2;
+        let counters = counters2;
 
         handle.block_on(async move {
           let rt = Rc::new(f().unwrap());
@@ -62,9 +184,13 @@ impl Executor {
           let waker = waker(Arc::new(MyWaker(waker_tx)));
 
           rt.lua().set_app_data(Vec::<LocalTask>::new());
+          if let Err(error) = counters.watch_gc(rt.lua()) {
+            error!("failed to count garbage collections: {error}");
+          }
 
-          let dur = Duration::from_secs(600);
-          let mut clean_interval = tokio::time::interval_at(Instant::now() + dur, dur);
+          let mut check_interval =
+            tokio::time::interval_at(Instant::now() + CHECK_INTERVAL, CHECK_INTERVAL);
+          let mut trigger = CleanupTrigger::new(rt.lua().used_memory());
 
           loop {
             {
@@ -81,11 +207,16 @@ impl Executor {
 
             let stop_rx_mut = Pin::new(&mut stop_rx);
             let waker_recv = waker_rx.recv();
-            let clean = clean_interval.tick();
+            let clean = async {
+              tokio::select! {
+                _ = check_interval.tick() => false,
+                _ = cleanup_notify.notified() => true,
+              }
+            };
             pin_mut!(waker_recv, clean);
 
             // SAFETY: `new_task_recv` is never moved
-            let mut new_task_recv_ = task_rx.recv();
+            let mut new_task_recv_ = recv_prioritized(&mut task_rxs);
             let new_task_recv = unsafe { Pin::new_unchecked(&mut new_task_recv_) };
 
             let select = select(
@@ -97,40 +228,96 @@ impl Executor {
                 trace!("{} stopping", std::thread::current().name().unwrap());
                 break;
               }
-              Left((Right(_), _)) => waker_poll(&waker, &mut tasks),
-              Right((Left(_), _)) => rt.cleanup(),
-              Right((Right((Some(msg), _)), _)) => {
+              Left((Right(_), _)) => {
+                trigger.active();
+                waker_poll(&waker, &mut tasks);
+              }
+              Right((Left((forced, _)), _)) => {
+                if forced || trigger.is_due(rt.lua().used_memory(), tasks.is_empty()) {
+                  rt.cleanup();
+                  trigger.reset(rt.lua().used_memory());
+                }
+              }
+              Right((Right((Some((level, msg)), _)), _)) => {
                 drop(new_task_recv_);
+                trigger.active();
                 if let Some(task) = msg.take(rt.lua()).unwrap() {
                   tasks.push(TaskFuture::from_local_task(rt.clone(), task));
-                  while let Ok(task) = task_rx.try_recv() {
-                    if let Some(task) = task.take(rt.lua()).unwrap() {
-                      tasks.push(TaskFuture::from_local_task(rt.clone(), task));
+                  // Lower-priority tasks stay queued until no task of this
+                  // priority or higher is waiting
+                  for task_rx in &mut task_rxs[..=level] {
+                    while let Ok(task) = task_rx.try_recv() {
+                      if let Some(task) = task.take(rt.lua()).unwrap() {
+                        tasks.push(TaskFuture::from_local_task(rt.clone(), task));
+                      }
                     }
                   }
                   waker_poll(&waker, &mut tasks);
                 }
               }
             }
+            counters.update(&rt, tasks.len());
           }
         })
       })
       .unwrap();
 
     Self {
+      name,
       panicked,
-      task_tx,
+      counters,
+      task_txs,
+      cleanup_notify,
       _stop_tx,
     }
   }
 
-  pub async fn send(&self, task: impl Into<Task>) -> Result<(), mpsc::error::SendError<Task>> {
-    self.task_tx.send(task.into()).await
+  /// Asks the executor to clean up as soon as possible.
+  pub fn cleanup(&self) {
+    self.cleanup_notify.notify_one();
+  }
+
+  pub async fn send(
+    &self,
+    task: impl Into<Task>,
+    priority: Priority,
+  ) -> Result<(), mpsc::error::SendError<Task>> {
+    self.task_txs[priority as usize].send(task.into()).await
   }
 
   pub fn is_panicked(&self) -> bool {
     self.panicked.load(Ordering::Acquire)
   }
+
+  pub fn stats(&self) -> SandboxStats {
+    let counters = &self.counters;
+    SandboxStats {
+      worker: self.name.clone(),
+      panicked: self.is_panicked(),
+      used_memory: counters.used_memory.load(Ordering::Relaxed),
+      gc_count: counters.gc_count.load(Ordering::Relaxed),
+      loaded_services: counters.loaded_services.load(Ordering::Relaxed),
+      running_tasks: counters.running_tasks.load(Ordering::Relaxed),
+      queued_tasks: (self.task_txs.iter())
+        .map(|x| QUEUE_SIZE - x.capacity())
+        .sum(),
+    }
+  }
+}
+
+/// Receives a task along with its priority as index, preferring ones with
+/// higher priority. Returns `None` when all channels are closed.
+async fn recv_prioritized(task_rxs: &mut [mpsc::Receiver<Task>]) -> Option<(usize, Task)> {
+  match task_rxs {
+    [interactive, background, scheduled] => tokio::select! {
+      biased;
+      Some(task) = interactive.recv() => Some((Priority::Interactive as usize, task)),
+      Some(task) = background.recv() => Some((Priority::Background as usize, task)),
+      Some(task) = scheduled.recv() => Some((Priority::Scheduled as usize, task)),
+      else => None,
+    },
+    _ => unreachable!(),
+  }
 }
 
 fn waker_poll(waker: &Waker, tasks: &mut FuturesUnordered<TaskFuture>) {
@@ -144,3 +331,20 @@ fn waker_poll(waker: &Waker, tasks: &mut FuturesUnordered<TaskFuture>) {
     }
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_watch_gc() -> mlua::Result<()> {
+    let lua = Lua::new();
+    let counters = Arc::new(Counters::default());
+    counters.watch_gc(&lua)?;
+    for i in 1..=3 {
+      lua.gc_collect()?;
+      assert_eq!(counters.gc_count.load(Ordering::Relaxed), i);
+    }
+    Ok(())
+  }
+}
@@ -1,12 +1,15 @@
 mod context;
 mod executor;
 mod pool;
+mod profile;
 mod task_future;
 
 pub use context::{close_value, TaskContext};
-pub use executor::Executor;
+pub use executor::{Executor, SandboxStats};
 pub use pool::Pool;
-pub use task_future::TimeoutError;
+pub use profile::Profile;
+pub(crate) use profile::{Profiler, Profiles};
+pub use task_future::{CancelledError, TimeoutError};
 
 use crate::runtime::Runtime;
 use futures::future::LocalBoxFuture;
@@ -23,6 +26,21 @@ use tokio::sync::oneshot::error::RecvError;
 type AnyBox = Box<dyn Any + Send>;
 type TaskFn = Box<(dyn FnOnce(Rc<Runtime>) -> LocalBoxFuture<'static, AnyBox> + Send + 'static)>;
 
+/// Priority class of a task. Executors take tasks of higher priority first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+  /// Handling HTTP requests
+  Interactive,
+  /// Service management and other background jobs
+  Background,
+  /// Periodic tasks
+  Scheduled,
+}
+
+impl Priority {
+  pub(crate) const COUNT: usize = 3;
+}
+
 pub struct SharedTask(Arc<Mutex<Option<OwnedTask>>>);
 
 impl SharedTask {
@@ -100,6 +118,7 @@ impl OwnedTask {
       task_fn,
       tx,
       context,
+      cancellable: true,
     };
     Ok(task)
   }
@@ -109,6 +128,10 @@ pub struct LocalTask {
   task_fn: TaskFn,
   tx: oneshot::Sender<AnyBox>,
   context: TaskContext,
+  /// Whether the task is cancelled once its result is no longer awaited.
+  /// Tasks spawned from Lua may be left running in the background, but are
+  /// cancelled along with the task spawning them.
+  cancellable: bool,
 }
 
 impl LocalTask {
@@ -130,6 +153,7 @@ impl LocalTask {
       task_fn,
       tx,
       context,
+      cancellable: false,
     };
     let rx = rx.map_ok(|x| x.downcast::<Fut::Output>().unwrap());
     (task, rx)
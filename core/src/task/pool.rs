@@ -1,8 +1,12 @@
+use crate::event::Events;
 use crate::runtime::Runtime;
-use crate::task::{Executor, SharedTask};
+use crate::task::{Executor, Priority, SandboxStats, SharedTask};
+use crate::ErrorKind::WorkerDropped;
 use crate::Result;
 use futures::Future;
 use log::error;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
 use std::rc::Rc;
 use std::sync::Arc;
 use tokio::sync::RwLock;
@@ -10,11 +14,13 @@ use tokio::sync::RwLock;
 pub struct Pool {
   executors: Vec<RwLock<Executor>>,
   f: Arc<dyn Fn() -> mlua::Result<Runtime> + Send + Sync>,
+  events: Events,
 }
 
 impl Pool {
   pub fn new(
     size: usize,
+    events: Events,
     f: impl Fn() -> mlua::Result<Runtime> + Send + Sync + 'static,
   ) -> Result<Self> {
     let f = Arc::new(f);
@@ -24,38 +30,123 @@ impl Pool {
         Ok(RwLock::new(Executor::new(
           move || f(),
           format!("abel-worker-{i}"),
+          events.clone(),
         )))
       })
       .collect::<Result<_>>()?;
 
-    Ok(Self { executors, f })
+    Ok(Self {
+      executors,
+      f,
+      events,
+    })
   }
 
+  /// Runs a background task on any of the executors.
   pub async fn scope<'a, F, Fut, R>(&self, task_fn: F) -> R
+  where
+    F: FnOnce(Rc<Runtime>) -> Fut + Send + 'static,
+    Fut: Future<Output = R> + 'a,
+    R: Send + 'static,
+  {
+    self.scope_with(Priority::Background, task_fn).await
+  }
+
+  pub async fn scope_with<'a, F, Fut, R>(&self, priority: Priority, task_fn: F) -> R
+  where
+    F: FnOnce(Rc<Runtime>) -> Fut + Send + 'static,
+    Fut: Future<Output = R> + 'a,
+    R: Send + 'static,
+  {
+    let (task, rx) = SharedTask::new(Default::default(), task_fn);
+    for i in 0..self.executors.len() {
+      self.send(i, task.clone(), priority).await;
+    }
+    *rx.await.unwrap()
+  }
+
+  /// Like [`Pool::scope_with`], but always runs tasks with the same key on the
+  /// same executor.
+  ///
+  /// Fails with `WorkerDropped` if that executor drops the task, e.g. when it
+  /// panics, since no other executor would pick it up.
+  pub async fn scope_on<'a, F, Fut, R>(
+    &self,
+    key: impl Hash,
+    priority: Priority,
+    task_fn: F,
+  ) -> Result<R>
+  where
+    F: FnOnce(Rc<Runtime>) -> Fut + Send + 'static,
+    Fut: Future<Output = R> + 'a,
+    R: Send + 'static,
+  {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    let i = jump_consistent_hash(hasher.finish(), self.executors.len());
+    self.scope_at(i, priority, task_fn).await
+  }
+
+  /// Number of executors, each with its own runtime.
+  pub fn size(&self) -> usize {
+    self.executors.len()
+  }
+
+  /// Like [`Pool::scope_on`], but runs the task on the `i`-th executor, e.g.
+  /// to reach every runtime in turn.
+  pub async fn scope_at<'a, F, Fut, R>(&self, i: usize, priority: Priority, task_fn: F) -> Result<R>
   where
     F: FnOnce(Rc<Runtime>) -> Fut + Send + 'static,
     Fut: Future<Output = R> + 'a,
     R: Send + 'static,
   {
     let (task, rx) = SharedTask::new(Default::default(), task_fn);
+    self.send(i, task, priority).await;
+    let result = rx.await.map_err(|_| WorkerDropped)?;
+    Ok(*result)
+  }
 
-    for (i, e) in self.executors.iter().enumerate() {
-      let rl = e.read().await;
-      let result = if rl.is_panicked() {
-        drop(rl);
-        let mut wl = e.write().await;
-        // let state = self.state.clone();
-        let f = self.f.clone();
-        *wl = Executor::new(move || f(), format!("abel-worker-{i}"));
-        wl.send(task.clone()).await
-      } else {
-        rl.send(task.clone()).await
-      };
-      if result.is_err() {
-        error!("task send failed");
-      }
+  /// Asks every executor to clean up.
+  pub async fn cleanup(&self) {
+    for e in &self.executors {
+      e.read().await.cleanup();
     }
+  }
 
-    *rx.await.unwrap()
+  pub async fn stats(&self) -> Vec<SandboxStats> {
+    let mut stats = Vec::with_capacity(self.executors.len());
+    for e in &self.executors {
+      stats.push(e.read().await.stats());
+    }
+    stats
+  }
+
+  async fn send(&self, i: usize, task: SharedTask, priority: Priority) {
+    let e = &self.executors[i];
+    let rl = e.read().await;
+    let result = if rl.is_panicked() {
+      drop(rl);
+      let mut wl = e.write().await;
+      let f = self.f.clone();
+      *wl = Executor::new(move || f(), format!("abel-worker-{i}"), self.events.clone());
+      wl.send(task, priority).await
+    } else {
+      rl.send(task, priority).await
+    };
+    if result.is_err() {
+      error!("task send failed");
+    }
+  }
+}
+
+/// Jump consistent hash (Lamping & Veach), mapping `key` to a bucket in
+/// `0..buckets`.
+fn jump_consistent_hash(mut key: u64, buckets: usize) -> usize {
+  let (mut b, mut j) = (-1i64, 0i64);
+  while j < buckets as i64 {
+    b = j;
+    key = key.wrapping_mul(2862933555777941757).wrapping_add(1);
+    j = ((b + 1) as f64 * ((1u64 << 31) as f64 / ((key >> 33) + 1) as f64)) as i64;
   }
+  b as usize
 }
@@ -0,0 +1,136 @@
+//! Sampling profiler of Lua code, enabled for requests of services with
+//! `profile_threshold` set.
+
+use bstr::ByteSlice;
+use mlua::{Debug, DebugSource, Lua};
+use parking_lot::Mutex;
+use serde::Serialize;
+use std::borrow::Cow;
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, SystemTime};
+
+/// Deepest call stack recorded in a sample.
+const MAX_DEPTH: usize = 64;
+/// Number of recent profiles kept for each service.
+const MAX_PROFILES: usize = 16;
+
+/// Time spent in each call stack, sampled on the CPU time hook.
+#[derive(Debug, Default)]
+pub struct Profiler {
+  stacks: HashMap<String, Duration>,
+}
+
+impl Profiler {
+  /// Attributes `weight` to the current call stack.
+  pub(crate) fn sample(&mut self, lua: &Lua, weight: Duration) {
+    let mut frames = (0..MAX_DEPTH)
+      .map_while(|level| lua.inspect_stack(level))
+      .map(|x| frame_name(&x))
+      .collect::<Vec<_>>();
+    if frames.is_empty() {
+      return;
+    }
+    frames.reverse();
+    *self.stacks.entry(frames.join(";")).or_default() += weight;
+  }
+}
+
+fn frame_name(debug: &Debug) -> String {
+  let name = (debug.names().name)
+    .map(String::from_utf8_lossy)
+    .unwrap_or(Cow::Borrowed("?"));
+  let DebugSource {
+    short_src,
+    line_defined,
+    ..
+  } = debug.source();
+  let name = match short_src {
+    Some(src) if line_defined > 0 => format!("{name} ({}:{line_defined})", src.as_bstr()),
+    Some(src) => format!("{name} ({})", src.as_bstr()),
+    None => name.into_owned(),
+  };
+  // `;` separates frames in folded stacks
+  name.replace(';', ",")
+}
+
+/// Report of a slow request, with call stacks in folded format.
+#[derive(Debug, Clone, Serialize)]
+pub struct Profile {
+  pub time: SystemTime,
+  pub path: String,
+  pub duration_ms: u64,
+  /// Call stacks from outermost to innermost frame separated by `;`, and
+  /// microseconds spent in them, most expensive first.
+  pub stacks: Vec<(String, u64)>,
+}
+
+/// Recent profiles of all services.
+#[derive(Debug, Default)]
+pub(crate) struct Profiles {
+  services: Mutex<HashMap<String, VecDeque<Profile>>>,
+}
+
+impl Profiles {
+  pub fn record(&self, service_name: &str, path: &str, duration: Duration, profiler: Profiler) {
+    let mut stacks = (profiler.stacks.into_iter())
+      .map(|(stack, time)| (stack, time.as_micros() as u64))
+      .collect::<Vec<_>>();
+    stacks.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    let profile = Profile {
+      time: SystemTime::now(),
+      path: path.into(),
+      duration_ms: duration.as_millis() as u64,
+      stacks,
+    };
+
+    let mut services = self.services.lock();
+    let profiles = services.entry(service_name.into()).or_default();
+    profiles.push_back(profile);
+    while profiles.len() > MAX_PROFILES {
+      profiles.pop_front();
+    }
+  }
+
+  pub fn list(&self, service_name: &str) -> Vec<Profile> {
+    (self.services.lock())
+      .get(service_name)
+      .map(|x| x.iter().cloned().collect())
+      .unwrap_or_default()
+  }
+
+  pub fn remove(&self, service_name: &str) {
+    self.services.lock().remove(service_name);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_sample() -> mlua::Result<()> {
+    let lua = Lua::new();
+    let profiler = std::rc::Rc::new(std::cell::RefCell::new(Profiler::default()));
+    let f = lua.create_function({
+      let profiler = profiler.clone();
+      move |lua, ()| {
+        profiler.borrow_mut().sample(lua, Duration::from_micros(5));
+        Ok(())
+      }
+    })?;
+    lua.globals().raw_set("sample", f)?;
+    lua
+      .load("local function inner() sample() end\nfunction outer() inner() end\nouter()")
+      .set_name("=main")?
+      .exec()?;
+
+    let profiles = Profiles::default();
+    profiles.record("a", "/", Duration::from_millis(1), profiler.take());
+    let profile = &profiles.list("a")[0];
+    assert_eq!(profile.stacks.len(), 1);
+    let (stack, micros) = &profile.stacks[0];
+    assert!(stack.ends_with("outer (main:2);inner (main:1);sample ([C])"));
+    assert_eq!(*micros, 5);
+    Ok(())
+  }
+}
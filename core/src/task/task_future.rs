@@ -13,13 +13,41 @@ use std::time::{Duration, Instant};
 use thiserror::Error;
 use tokio::sync::oneshot;
 
+/// Instructions between checks of CPU time and cancellation.
+const HOOK_INTERVAL: u32 = 1048576;
+/// Instructions between samples while profiling, frequent enough to sample
+/// meaningfully.
+const PROFILE_HOOK_INTERVAL: u32 = 16384;
+
 #[pin_project]
 pub struct TaskFuture {
   rt: Rc<Runtime>,
   context: TaskContext,
   #[pin]
   task: LocalBoxFuture<'static, AnyBox>,
-  tx: Option<oneshot::Sender<AnyBox>>,
+  tx: Rc<RefCell<Option<oneshot::Sender<AnyBox>>>>,
+  cancellable: bool,
+  memory: TaskMemory,
+}
+
+/// Lua memory held by one task.
+///
+/// The Lua state is shared by concurrent tasks, so only changes while this
+/// task runs are counted. Other tasks' garbage collected meanwhile lowers it.
+#[derive(Debug, Default)]
+struct TaskMemory {
+  held: i64,
+  peak: u64,
+}
+
+impl TaskMemory {
+  /// Records memory in use before and after a poll, returning the most held
+  /// after any poll so far.
+  fn record(&mut self, before: usize, after: usize) -> u64 {
+    self.held += after as i64 - before as i64;
+    self.peak = self.peak.max(self.held.max(0) as u64);
+    self.peak
+  }
 }
 
 impl TaskFuture {
@@ -28,19 +56,22 @@ impl TaskFuture {
     task_fn: impl FnOnce(Rc<Runtime>) -> LocalBoxFuture<'static, AnyBox>,
     tx: oneshot::Sender<AnyBox>,
     context: TaskContext,
+    cancellable: bool,
   ) -> Self {
     Self {
       rt: rt.clone(),
       context,
       task: task_fn(rt),
-      tx: Some(tx),
+      tx: Rc::new(RefCell::new(Some(tx))),
+      cancellable,
+      memory: TaskMemory::default(),
     }
   }
 
   pub fn from_local_task(rt: Rc<Runtime>, task: LocalTask) -> Self {
     #[rustfmt::skip]
-    let LocalTask { task_fn, tx, context } = task;
-    Self::new(rt, task_fn, tx, context)
+    let LocalTask { task_fn, tx, context, cancellable } = task;
+    Self::new(rt, task_fn, tx, context, cancellable)
   }
 }
 
@@ -51,17 +82,50 @@ impl Future for TaskFuture {
     let this = self.project();
     let lua = this.rt.lua();
 
+    // Cancel if the result is no longer awaited, e.g. the client disconnected
+    if *this.cancellable {
+      if let Some(tx) = &mut *this.tx.borrow_mut() {
+        if tx.poll_closed(cx).is_ready() {
+          this.context.cancelled.set(true);
+        }
+      }
+    }
+    if this.context.cancelled.get() {
+      this.tx.borrow_mut().take();
+      this.context.try_close(lua)?;
+      return Poll::Ready(Ok(()));
+    }
+
     this.context.set_current(lua);
 
-    let hook_triggers = HookTriggers::every_nth_instruction(1048576);
+    let hook_interval = if this.context.profiler.borrow().is_some() {
+      PROFILE_HOOK_INTERVAL
+    } else {
+      HOOK_INTERVAL
+    };
+    let hook_triggers = HookTriggers::every_nth_instruction(hook_interval);
     lua.set_hook(hook_triggers, {
       let t1 = RefCell::new(Instant::now());
       let cpu_time = this.context.cpu_time.clone();
-      move |_lua, _| {
+      let profiler = this.context.profiler.clone();
+      let cancellable = *this.cancellable;
+      let tx = this.tx.clone();
+      let cancelled = this.context.cancelled.clone();
+      move |lua, _| {
+        if cancellable && (tx.borrow().as_ref()).map_or(false, |x| x.is_closed()) {
+          cancelled.set(true);
+        }
+        if cancelled.get() {
+          return Err(CancelledError(()).to_lua_err());
+        }
+
         let mut cpu_time = cpu_time.lock();
         let t2 = Instant::now();
         let dur = t2.duration_since(*t1.borrow());
         *cpu_time += dur;
+        if let Some(profiler) = &mut *profiler.borrow_mut() {
+          profiler.sample(lua, dur);
+        }
 
         if *cpu_time >= Duration::from_secs(1) {
           Err(TimeoutError(()).to_lua_err())
@@ -72,15 +136,22 @@ impl Future for TaskFuture {
       }
     })?;
 
+    let memory = lua.used_memory();
+    let start = Instant::now();
     let poll = this.task.poll(cx);
     lua.remove_hook();
+    let peak_memory = this.memory.record(memory, lua.used_memory());
+    if let Some(metrics) = &*this.context.metrics.borrow() {
+      metrics.record_cpu_time(start.elapsed());
+      metrics.record_memory(peak_memory);
+    }
     let x = TaskContext::remove_current(lua);
     assert_eq!(x.as_ref(), Some(&*this.context));
     drop(x);
 
     match poll {
       Poll::Ready(result) => {
-        if let Some(tx) = this.tx.take() {
+        if let Some(tx) = this.tx.borrow_mut().take() {
           let _ = tx.send(result);
           this.context.try_close(lua)?;
         }
@@ -94,3 +165,84 @@ impl Future for TaskFuture {
 #[derive(Debug, Error)]
 #[error("timeout")]
 pub struct TimeoutError(pub(crate) ());
+
+#[derive(Debug, Error)]
+#[error("cancelled")]
+pub struct CancelledError(pub(crate) ());
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::source::{MemorySource, Source};
+  use crate::{Abel, AbelOptions};
+  use hyper::{Body, Request};
+  use tempfile::TempDir;
+
+  #[test]
+  fn test_task_memory() {
+    let mut memory = TaskMemory::default();
+    assert_eq!(memory.record(1000, 1500), 500);
+    // Another task allocated in between
+    assert_eq!(memory.record(3000, 3200), 700);
+    assert_eq!(memory.record(3200, 2000), 700);
+    assert_eq!(memory.record(2000, 2100), 700);
+    assert_eq!(memory.record(2100, 1000), 700);
+  }
+
+  async fn call(abel: &Abel, path: &str) -> crate::Result<hyper::Response<Body>> {
+    let service = abel.get_running_service("svc")?;
+    let req = Request::get(format!("http://localhost/svc{path}"))
+      .body(Body::empty())
+      .unwrap();
+    abel.run_service(service, path.into(), req).await
+  }
+
+  #[tokio::test]
+  async fn test_cancel_disconnected() {
+    let local_storage = TempDir::new().unwrap();
+    let abel = Abel::new(AbelOptions {
+      runtime_pool_size: 1,
+      local_storage_path: local_storage.path().into(),
+      secrets_path: None,
+      remote_cache_path: None,
+      max_services: None,
+      max_running_services: None,
+      http_client: Default::default(),
+      coordinator: None,
+      exec_allowlist: Vec::new(),
+      #[cfg(feature = "encryption")]
+      storage_key: None,
+    })
+    .unwrap();
+    let code = r#"
+      local started, caught
+      abel.listen("/status", function()
+        return tostring(started) .. " " .. tostring(caught)
+      end)
+      abel.listen("/", function()
+        started = true
+        while true do
+          local ok, error = pcall(function()
+            while true do end
+          end)
+          caught = error
+        end
+      end)
+    "#;
+    let source = Source::new(MemorySource::from_files([("main.lua", code)]));
+    let guard = abel.lock_deploy("svc", false).await.unwrap();
+    (abel.cold_update_or_create_service(&guard, None, source, Default::default(), None))
+      .await
+      .unwrap();
+
+    // The client disconnects while the request is still running
+    let request = tokio::time::timeout(Duration::from_millis(200), call(&abel, "/"));
+    assert!(request.await.is_err());
+
+    // Cancellation is not caught by `pcall`, which would otherwise keep the
+    // loop going until it runs out of CPU time
+    let resp = call(&abel, "/status").await.unwrap();
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    assert_eq!(body, "true nil");
+  }
+}
@@ -0,0 +1,191 @@
+//! Versions of services, and of the Lua API they target.
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt::{self, Display, Formatter};
+use std::str::FromStr;
+
+/// Version of the Lua API provided to services. Additions bump the minor
+/// version, and breaking changes the major version.
+pub const API_VERSION: Version = Version::new(1, 0, 0);
+
+/// Semantic version `major.minor.patch`, optionally followed by pre-release
+/// and build metadata, which are kept but not compared.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Version {
+  pub major: u64,
+  pub minor: u64,
+  pub patch: u64,
+  suffix: Option<Box<str>>,
+}
+
+impl Version {
+  pub const fn new(major: u64, minor: u64, patch: u64) -> Self {
+    Self {
+      major,
+      minor,
+      patch,
+      suffix: None,
+    }
+  }
+}
+
+fn parse_number(s: &str) -> Result<u64, String> {
+  if s.is_empty() || !s.bytes().all(|x| x.is_ascii_digit()) || (s.len() > 1 && s.starts_with('0')) {
+    return Err(format!("invalid version number '{s}'"));
+  }
+  s.parse()
+    .map_err(|_| format!("version number '{s}' is too large"))
+}
+
+impl FromStr for Version {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let i = s.find(|c| c == '-' || c == '+').unwrap_or(s.len());
+    let (numbers, suffix) = s.split_at(i);
+    let numbers = numbers.split('.').collect::<Vec<_>>();
+    match numbers[..] {
+      [major, minor, patch] => Ok(Self {
+        major: parse_number(major)?,
+        minor: parse_number(minor)?,
+        patch: parse_number(patch)?,
+        suffix: (!suffix.is_empty()).then(|| suffix.into()),
+      }),
+      _ => Err(format!("'{s}' is not in the form 'major.minor.patch'")),
+    }
+  }
+}
+
+impl Display for Version {
+  fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+    let Self {
+      major,
+      minor,
+      patch,
+      suffix,
+    } = self;
+    write!(
+      f,
+      "{major}.{minor}.{patch}{}",
+      suffix.as_deref().unwrap_or("")
+    )
+  }
+}
+
+/// Compatible API versions, written as a version with optional `^` prefix and
+/// minor or patch parts, e.g. `1`, `^1.2`.
+///
+/// Like Cargo's default requirements, `1.2` allows 1.2.0 up to but excluding
+/// 2.0.0, and `0.2` allows 0.2.0 up to but excluding 0.3.0.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VersionReq {
+  major: u64,
+  minor: Option<u64>,
+  patch: Option<u64>,
+}
+
+impl VersionReq {
+  pub fn matches(&self, version: &Version) -> bool {
+    let Self {
+      major,
+      minor,
+      patch,
+    } = *self;
+    let min = (major, minor.unwrap_or(0), patch.unwrap_or(0));
+    let v = (version.major, version.minor, version.patch);
+    let compatible = match (major, minor) {
+      (0, Some(minor)) => v.0 == 0 && v.1 == minor,
+      _ => v.0 == major,
+    };
+    compatible && v >= min
+  }
+}
+
+impl FromStr for VersionReq {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let numbers = s.strip_prefix('^').unwrap_or(s).split('.');
+    let numbers = numbers.map(parse_number).collect::<Result<Vec<_>, _>>()?;
+    match numbers[..] {
+      [major] => Ok(Self {
+        major,
+        minor: None,
+        patch: None,
+      }),
+      [major, minor] => Ok(Self {
+        major,
+        minor: Some(minor),
+        patch: None,
+      }),
+      [major, minor, patch] => Ok(Self {
+        major,
+        minor: Some(minor),
+        patch: Some(patch),
+      }),
+      _ => Err(format!("invalid version requirement '{s}'")),
+    }
+  }
+}
+
+impl Display for VersionReq {
+  fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+    write!(f, "^{}", self.major)?;
+    if let Some(minor) = self.minor {
+      write!(f, ".{minor}")?;
+    }
+    if let Some(patch) = self.patch {
+      write!(f, ".{patch}")?;
+    }
+    Ok(())
+  }
+}
+
+macro_rules! impl_serde_str {
+  ($($t:ty),*) => {$(
+    impl Serialize for $t {
+      fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+      }
+    }
+
+    impl<'de> Deserialize<'de> for $t {
+      fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+      }
+    }
+  )*};
+}
+
+impl_serde_str!(Version, VersionReq);
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use test_case::test_case;
+
+  #[test_case("1.2.3" => Ok((1, 2, 3)))]
+  #[test_case("0.1.0-beta.1+build" => Ok((0, 1, 0)))]
+  #[test_case("1.2" => matches Err(_))]
+  #[test_case("1.02.3" => matches Err(_))]
+  #[test_case("1.x.3" => matches Err(_))]
+  fn test_parse_version(s: &str) -> Result<(u64, u64, u64), String> {
+    let v: Version = s.parse()?;
+    assert_eq!(v.to_string(), s);
+    Ok((v.major, v.minor, v.patch))
+  }
+
+  #[test_case("1", "1.0.0" => true)]
+  #[test_case("1", "2.0.0" => false)]
+  #[test_case("^1.2", "1.1.9" => false)]
+  #[test_case("1.2", "1.5.0" => true)]
+  #[test_case("1.2.3", "1.2.2" => false)]
+  #[test_case("0.2", "0.2.5" => true)]
+  #[test_case("0.2", "0.3.0" => false)]
+  #[test_case("0", "0.9.0" => true)]
+  fn test_matches(req: &str, version: &str) -> bool {
+    let req: VersionReq = req.parse().unwrap();
+    req.matches(&version.parse().unwrap())
+  }
+}